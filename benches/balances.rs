@@ -0,0 +1,53 @@
+//! Balance maintenance and holder ranking: `cargo bench --bench balances`
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use eth_indexer::repository::BalanceRepository;
+use eth_indexer::testutil::{
+    fresh_database, populate_balances, remove_database, synthetic_transfers, temp_database_path,
+    token_address,
+};
+
+const APPLIED_TRANSFERS: u64 = 1_000;
+const APPLIED_HOLDERS: u64 = 500;
+const RANKED_HOLDERS: u64 = 1_000_000;
+
+/// 1k finalized transfers over 500 addresses, half of them mints
+fn apply_transfers(c: &mut Criterion) {
+    let transfers = synthetic_transfers(0..APPLIED_TRANSFERS, APPLIED_HOLDERS);
+    let path = temp_database_path("bench-apply");
+
+    let mut group = c.benchmark_group("apply_transfers");
+    group.throughput(Throughput::Elements(APPLIED_TRANSFERS));
+    group.bench_function("1k_transfers_500_addresses", |b| {
+        b.iter_batched(
+            || fresh_database(&path).unwrap(),
+            |db| {
+                BalanceRepository::new(&db.conn, &token_address())
+                    .apply_transfers(&transfers)
+                    .unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+    remove_database(&path);
+}
+
+fn get_top_holders(c: &mut Criterion) {
+    let path = temp_database_path("bench-top-holders");
+    let db = fresh_database(&path).unwrap();
+    populate_balances(&db, RANKED_HOLDERS).unwrap();
+    let repo = BalanceRepository::new(&db.conn, &token_address());
+
+    let mut group = c.benchmark_group("get_top_holders_1m");
+    for limit in [10, 100, 1_000] {
+        group.bench_with_input(BenchmarkId::from_parameter(limit), &limit, |b, &limit| {
+            b.iter(|| repo.get_top_holders(limit).unwrap())
+        });
+    }
+    group.finish();
+    drop(db);
+    remove_database(&path);
+}
+
+criterion_group!(benches, apply_transfers, get_top_holders);
+criterion_main!(benches);
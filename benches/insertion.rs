@@ -0,0 +1,84 @@
+//! Transfer insertion throughput: `cargo bench --bench insertion`
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use eth_indexer::repository::TransferRepository;
+use eth_indexer::testutil::{
+    fresh_database, remove_database, synthetic_transfers, temp_database_path, token_address,
+};
+
+const CROSSOVER_TRANSFERS: u64 = 1_000_000;
+const HOLDERS: u64 = 5_000;
+
+fn insert_batch(c: &mut Criterion) {
+    let path = temp_database_path("bench-insertion");
+
+    let mut group = c.benchmark_group("insert_batch");
+    group.sample_size(10);
+    for (name, count) in [("10k_transfers", 10_000), ("100k_transfers", 100_000)] {
+        let transfers = synthetic_transfers(0..count, HOLDERS);
+        group.throughput(Throughput::Elements(count));
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || fresh_database(&path).unwrap(),
+                |db| {
+                    let repo = TransferRepository::new(&db.conn, &token_address());
+                    for batch in transfers.chunks(10_000) {
+                        repo.insert_batch(batch).unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+    remove_database(&path);
+}
+
+/// Per-row against multi-row statements on 1M transfers, at the batch sizes
+/// the insertion worker sees, to place MULTI_ROW_INSERT_THRESHOLD
+fn multi_row_crossover(c: &mut Criterion) {
+    let transfers = synthetic_transfers(0..CROSSOVER_TRANSFERS, HOLDERS);
+    let path = temp_database_path("bench-crossover");
+
+    let mut group = c.benchmark_group("insert_1m");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(CROSSOVER_TRANSFERS));
+    for batch_size in [10, 100, 1_000, 10_000] {
+        group.bench_with_input(
+            BenchmarkId::new("per_row", batch_size),
+            &batch_size,
+            |b, &batch_size| {
+                b.iter_batched(
+                    || fresh_database(&path).unwrap(),
+                    |db| {
+                        let repo = TransferRepository::new(&db.conn, &token_address());
+                        for batch in transfers.chunks(batch_size) {
+                            repo.insert_batch(batch).unwrap();
+                        }
+                    },
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("multi_row", batch_size),
+            &batch_size,
+            |b, &batch_size| {
+                b.iter_batched(
+                    || fresh_database(&path).unwrap(),
+                    |db| {
+                        let repo = TransferRepository::new(&db.conn, &token_address());
+                        for batch in transfers.chunks(batch_size) {
+                            repo.insert_batch_multi_row(batch).unwrap();
+                        }
+                    },
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+    group.finish();
+    remove_database(&path);
+}
+
+criterion_group!(benches, insert_batch, multi_row_crossover);
+criterion_main!(benches);
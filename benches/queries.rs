@@ -0,0 +1,34 @@
+//! Transfer listing: `cargo bench --bench queries`
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use eth_indexer::repository::TransferRepository;
+use eth_indexer::testutil::{
+    cached_database, populate_transfers, temp_database_path, token_address,
+};
+
+const TRANSFERS: u64 = 10_000_000;
+const HOLDERS: u64 = 100_000;
+const PAGE: usize = 100;
+
+/// One page of `query_transfers` at increasing offsets into 10M transfers.
+/// Building the table takes about half an hour, so it is kept in the temp
+/// directory for the next run.
+fn query_transfers_pagination(c: &mut Criterion) {
+    let path = temp_database_path("bench-queries");
+    let db = cached_database(&path, |db| populate_transfers(db, TRANSFERS, HOLDERS)).unwrap();
+    let repo = TransferRepository::new(&db.conn, &token_address());
+
+    let mut group = c.benchmark_group("query_transfers_10m");
+    group.sample_size(10);
+    for offset in [0, 10_000, 1_000_000, 9_000_000] {
+        group.bench_with_input(BenchmarkId::new("offset", offset), &offset, |b, &offset| {
+            b.iter(|| {
+                repo.query_transfers(None, None, None, false, PAGE, offset)
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, query_transfers_pagination);
+criterion_main!(benches);
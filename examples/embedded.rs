@@ -0,0 +1,42 @@
+//! Index a token into a local database, then look up a holder's balance.
+//!
+//! ```bash
+//! cargo run --example embedded -- <RPC_URL> <CONTRACT> <HOLDER> [START_BLOCK]
+//! ```
+use alloy_primitives::Address;
+use anyhow::{Context, Result};
+use eth_indexer::Indexer;
+use eth_indexer::logging::init_logging;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let usage = "usage: embedded <RPC_URL> <CONTRACT> <HOLDER> [START_BLOCK]";
+    let rpc_url = args.next().context(usage)?;
+    let contract: Address = args.next().context(usage)?.parse()?;
+    let holder: Address = args.next().context(usage)?.parse()?;
+
+    let mut builder = Indexer::builder()
+        .rpc_url(rpc_url)
+        .contract(contract)
+        .database("sqlite:embedded.db");
+    if let Some(start_block) = args.next() {
+        builder = builder.start_block(start_block.parse()?);
+    }
+    let indexer = builder.build()?;
+    init_logging(indexer.config().log_format, &indexer.config().log_filter)?;
+
+    let mut handle = indexer.start().await?;
+    let head = handle.wait_caught_up().await?;
+    handle.shutdown().await?;
+
+    let readers = indexer.read_pool();
+    let reader = readers.get().await?;
+    let balance = indexer
+        .balances(&reader)
+        .get_balance(&holder, true)?
+        .balance;
+    println!("Indexed up to block {head}, finalized balance of {holder}: {balance}");
+
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+
+/// Number of recent batches used to estimate how many logs a block holds
+const DENSITY_WINDOW: usize = 20;
+
+/// The most the batch size may grow by after a single successful request
+const GROWTH_FACTOR: f64 = 1.25;
+
+/// Picks the block span of the next `eth_getLogs` request so each request
+/// returns roughly `target_logs` logs.
+///
+/// Log density is estimated over the last few batches. The span shrinks right
+/// away when a request hit the provider's result limit or the chain got busier,
+/// and only grows by `GROWTH_FACTOR` per batch so a quiet stretch doesn't make
+/// the next busy one trip the limit.
+pub struct BatchSizer {
+    current: u64,
+    min: u64,
+    max: u64,
+    target_logs: u64,
+    /// (blocks, logs) of the most recent batches
+    window: VecDeque<(u64, u64)>,
+}
+
+impl BatchSizer {
+    pub fn new(initial: u64, min: u64, max: u64, target_logs: u64) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        Self {
+            current: initial.clamp(min, max),
+            min,
+            max,
+            target_logs: target_logs.max(1),
+            window: VecDeque::with_capacity(DENSITY_WINDOW),
+        }
+    }
+
+    /// Block span to use for the next request
+    pub fn current(&self) -> u64 {
+        self.current
+    }
+
+    /// Record a completed batch of `blocks` blocks that returned `logs` logs
+    /// and had to be split `splits` times to fit the provider's result limit
+    pub fn record(&mut self, blocks: u64, logs: u64, splits: u32) {
+        if self.window.len() == DENSITY_WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back((blocks, logs));
+
+        if splits > 0 {
+            // The provider's limit is below our target, don't wait for the
+            // density estimate to catch up
+            let shrunk = (blocks / (u64::from(splits) + 1)).min(self.current / 2);
+            self.current = shrunk.clamp(self.min, self.max);
+            return;
+        }
+
+        let (window_blocks, window_logs) = self
+            .window
+            .iter()
+            .fold((0u64, 0u64), |(b, l), &(blocks, logs)| {
+                (b + blocks, l + logs)
+            });
+
+        let target_span = if window_logs == 0 {
+            self.max
+        } else {
+            (self.target_logs as u128 * window_blocks as u128 / window_logs as u128) as u64
+        }
+        .clamp(self.min, self.max);
+
+        if target_span < self.current {
+            self.current = target_span;
+        } else {
+            let grown = ((self.current as f64 * GROWTH_FACTOR) as u64).max(self.current + 1);
+            self.current = target_span.min(grown);
+        }
+    }
+}
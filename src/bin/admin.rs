@@ -0,0 +1,130 @@
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use eth_indexer::config::Config;
+use eth_indexer::dump::{export_transfers, import_transfers};
+use eth_indexer::logging::init_logging;
+use eth_indexer::repository::{Database, TransferRepository};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(name = "admin")]
+#[command(about = "Maintenance tasks on an indexed database", long_about = None)]
+struct Cli {
+    /// TOML config file, overridden by environment variables
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Write transfers to a JSON Lines dump, gzip compressed if the path ends in .gz
+    ExportTransfers {
+        #[arg(long, num_args = 2, value_names = ["START", "END"])]
+        block_range: Option<Vec<u64>>,
+
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Insert the transfers of a dump written by export-transfers
+    ImportTransfers { input: PathBuf },
+    /// Delete the transfers above a block and move the cursors back to it, so
+    /// the indexer re-fetches what follows. Stop the indexer first.
+    Rollback {
+        #[arg(long, value_name = "N")]
+        to_block: u64,
+    },
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let config = Config::load(cli.config.as_deref())?;
+    init_logging(config.log_format, &config.log_filter)?;
+
+    let db = Database::with_options(&config.database_url, config.sqlite_options())?;
+    let token_address = &config.erc20_contract_address;
+
+    match cli.command {
+        Commands::ExportTransfers {
+            block_range,
+            output,
+        } => {
+            let range = block_range.map(|v| if v.len() >= 2 { (v[0], v[1]) } else { (0, 0) });
+            let summary = export_transfers(&db.conn, token_address, range, &output)?;
+            println!(
+                "Exported {} transfers from blocks {}-{} to {}",
+                summary.transfers,
+                summary.from_block,
+                summary.to_block,
+                output.display()
+            );
+        }
+        Commands::ImportTransfers { input } => {
+            let summary = import_transfers(&db.conn, token_address, &input)?;
+            println!("Read {} transfers from {}", summary.rows, input.display());
+            println!("Inserted: {}", summary.inserted);
+            println!("Duplicates skipped: {}", summary.duplicates);
+            println!(
+                "Skipped for another token address: {}",
+                summary.token_mismatches
+            );
+            if summary.cursor_advanced {
+                println!(
+                    "Last processed block advanced to {}",
+                    summary.last_processed_block
+                );
+            } else {
+                println!(
+                    "Last processed block left at {} (dump covers blocks {}-{})",
+                    summary.last_processed_block, summary.from_block, summary.to_block
+                );
+            }
+        }
+        Commands::Rollback { to_block } => {
+            let summary = TransferRepository::new(&db.conn, token_address).rollback_to(to_block)?;
+            let or_none = |block: Option<u64>| block.map_or("none".to_string(), |b| b.to_string());
+            println!("Rolled back to block {to_block}");
+            println!(
+                "Transfers deleted: {} in {} blocks",
+                summary.transfers_deleted, summary.blocks_deleted
+            );
+            println!(
+                "Finalized transfers taken out of the balances: {}",
+                summary.finalized_reverted
+            );
+            println!(
+                "Last processed block: {} -> {}",
+                or_none(summary.previous_last_processed_block),
+                or_none(
+                    summary
+                        .previous_last_processed_block
+                        .map(|b| b.min(to_block))
+                )
+            );
+            println!(
+                "Last finalized block: {} -> {}",
+                or_none(summary.previous_last_finalized_block),
+                or_none(
+                    summary
+                        .previous_last_finalized_block
+                        .map(|b| b.min(to_block))
+                )
+            );
+            if !summary.unscanned_below.is_empty() {
+                let gaps: Vec<_> = summary
+                    .unscanned_below
+                    .iter()
+                    .map(|(from, to)| format!("{from}-{to}"))
+                    .collect();
+                println!(
+                    "Warning: blocks {} below the new cursor were never scanned and won't be re-fetched; queue them with `query coverage --repair`",
+                    gaps.join(", ")
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use anyhow::{Result, bail};
+use clap::Parser;
+use eth_indexer::repository::BalanceRepository;
+use eth_indexer::testutil::{
+    DataGenerator, TEST_TOKEN_SYMBOL, populate_with, remove_database, token_address,
+};
+use std::path::PathBuf;
+use std::time::Instant;
+
+#[derive(Parser)]
+#[command(name = "gen-testdata")]
+#[command(
+    about = "Create a database of synthetic finalized transfers for manual testing",
+    long_about = None
+)]
+struct Cli {
+    /// Database file to create
+    path: PathBuf,
+
+    /// Transfers to generate
+    #[arg(long, default_value_t = 100_000)]
+    transfers: u64,
+
+    /// Distinct addresses taking part
+    #[arg(long, default_value_t = 10_000)]
+    holders: u64,
+
+    /// Zipf exponent of address popularity, 0 for uniform
+    #[arg(long, default_value_t = 1.0)]
+    zipf: f64,
+
+    /// Smallest transfer value, in base units
+    #[arg(long, default_value_t = 1)]
+    min_value: u64,
+
+    /// Largest transfer value, in base units
+    #[arg(long, default_value_t = 1_000_000_000_000)]
+    max_value: u64,
+
+    #[arg(long, default_value_t = 10)]
+    transfers_per_block: u64,
+
+    #[arg(long, default_value_t = 1)]
+    start_block: u64,
+
+    /// Same seed and settings, same database
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Replace the file if it exists
+    #[arg(long)]
+    force: bool,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    if cli.path.exists() {
+        if !cli.force {
+            bail!("{} exists, pass --force to replace it", cli.path.display());
+        }
+        remove_database(&cli.path);
+    }
+
+    let generator = DataGenerator::new(cli.seed)
+        .with_holders(cli.holders, cli.zipf)
+        .with_value_range(cli.min_value, cli.max_value)
+        .with_transfers_per_block(cli.transfers_per_block)
+        .with_start_block(cli.start_block);
+
+    println!(
+        "Generating {} transfers between {} holders into {}",
+        cli.transfers,
+        cli.holders,
+        cli.path.display()
+    );
+    let started = Instant::now();
+    let db = populate_with(&cli.path, generator, cli.transfers)?;
+
+    let balances = BalanceRepository::new(&db.conn, &token_address());
+    println!(
+        "Done in {:.1}s: token {} ({TEST_TOKEN_SYMBOL}), {} holders with a balance",
+        started.elapsed().as_secs_f64(),
+        token_address(),
+        balances.get_distribution(&[])?.total_holders
+    );
+    println!("Query it with DATABASE_URL=sqlite:{}", cli.path.display());
+
+    Ok(())
+}
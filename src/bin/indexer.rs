@@ -1,35 +1,174 @@
 use anyhow::Result;
-use eth_indexer::config::Config;
+use clap::Parser;
+use eth_indexer::config::{CliOverrides, Config, RpcMode};
+use eth_indexer::insertion_worker::WriteMode;
+use eth_indexer::logging::init_logging;
+use eth_indexer::progress::PROGRESS_TARGET;
 use eth_indexer::repository::Database;
-use eth_indexer::rpc::RpcClient;
+use eth_indexer::rpc::{RecordingRpc, ReplayRpc, RpcApi, RpcClient};
 use eth_indexer::scanner::Scanner;
+use eth_indexer::supervisor::Supervisor;
+use std::path::PathBuf;
+use tokio::sync::watch;
 use tracing::{error, info};
 
+#[derive(Parser)]
+#[command(name = "indexer")]
+#[command(about = "Index ERC20 transfers into SQLite", long_about = None)]
+struct Cli {
+    /// TOML config file, overridden by environment variables
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Only log the periodic progress summary, warnings and errors
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Re-fetch the token's name, symbol and decimals, then exit
+    #[arg(long)]
+    refresh_metadata: bool,
+
+    /// Fetch and decode as usual but write nothing, logging what would have
+    /// been written instead
+    #[arg(long)]
+    dry_run: bool,
+
+    #[command(flatten)]
+    overrides: CliOverrides,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt().init();
+    let cli = Cli::parse();
+    let config = Config::load_with_cli(cli.config.as_deref(), cli.overrides)?;
 
-    info!("Starting Ethereum Log Indexer");
+    let log_filter = if cli.quiet {
+        format!("warn,{PROGRESS_TARGET}=info")
+    } else {
+        config.log_filter.clone()
+    };
+    init_logging(config.log_format, &log_filter)?;
 
-    let config = Config::from_env()?;
+    info!("Starting Ethereum Log Indexer");
     info!("Configuration loaded");
+
+    if config.tokens_config.is_some() {
+        if cli.dry_run || cli.refresh_metadata {
+            anyhow::bail!(
+                "--dry-run and --refresh-metadata work on a single token, unset TOKENS_CONFIG"
+            );
+        }
+        return supervise(config).await;
+    }
+
     info!("Contract address: {:?}", config.erc20_contract_address);
     info!(
         "RPC URLs: {} endpoint(s) configured",
         config.json_rpc_urls.len()
     );
 
-    let db = Database::new(&config.database_url)?;
+    let (db, write_mode) = if cli.dry_run {
+        let db = Database::in_memory_copy(
+            &config.database_url,
+            config.sqlite_options(),
+            &config.erc20_contract_address,
+        )?;
+        info!("Dry run, {} is left untouched", config.database_url);
+        (db, WriteMode::DryRun)
+    } else {
+        let db = Database::with_options(&config.database_url, config.sqlite_options())?;
+        (db, WriteMode::Write)
+    };
     info!("Database initialized");
 
-    let client = RpcClient::new(&config.json_rpc_urls, &config)?;
+    match config.rpc_mode {
+        RpcMode::Live => {
+            let client = connect(&config).await?;
+            run(client, db, write_mode, &config, cli.refresh_metadata).await
+        }
+        RpcMode::Record => {
+            let client = RecordingRpc::new(connect(&config).await?, &config.rpc_fixture_dir)?;
+            info!(
+                "Recording RPC responses to {}",
+                config.rpc_fixture_dir.display()
+            );
+            run(client, db, write_mode, &config, cli.refresh_metadata).await
+        }
+        RpcMode::Replay => {
+            let client = ReplayRpc::load(&config.rpc_fixture_dir)?;
+            info!(
+                "Replaying RPC responses from {}",
+                config.rpc_fixture_dir.display()
+            );
+            run(client, db, write_mode, &config, cli.refresh_metadata).await
+        }
+    }
+}
+
+/// Index every token of TOKENS_CONFIG until they finish or a shutdown signal
+/// arrives, then wait for all of them to commit what they fetched
+async fn supervise(config: Config) -> Result<()> {
+    let tokens = config.tokens()?;
+    if let Some(path) = &config.tokens_config {
+        info!(
+            "Supervising {} tokens from {}",
+            tokens.len(),
+            path.display()
+        );
+    }
+
+    let (stop, shutdown) = watch::channel(false);
+    let run = Supervisor::new(config, tokens).run(shutdown);
+    tokio::pin!(run);
+    tokio::select! {
+        result = &mut run => return result,
+        _ = shutdown_signal() => {
+            info!("Shutdown requested, stopping every token");
+            stop.send_replace(true);
+        }
+    }
+    run.await
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn connect(config: &Config) -> Result<RpcClient> {
+    let client = RpcClient::new(&config.json_rpc_urls, config).await?;
     info!("RPC client connected");
+    client.spawn_health_probe();
+    Ok(client)
+}
+
+async fn run<C: RpcApi + Clone + 'static>(
+    client: C,
+    db: Database,
+    write_mode: WriteMode,
+    config: &Config,
+    refresh_metadata: bool,
+) -> Result<()> {
+    let mut scanner = Scanner::new(client, db, config)?.with_write_mode(write_mode);
 
-    let mut scanner = Scanner::new(client, db, &config)?;
+    if refresh_metadata {
+        return Ok(scanner.refresh_token_metadata().await?);
+    }
 
     if let Err(e) = scanner.run().await {
         error!("Scanner error: {}", e);
-        return Err(e);
+        return Err(e.into());
     }
 
     Ok(())
@@ -1,17 +1,166 @@
 use anyhow::Result;
-use eth_indexer::repository::Database;
+use clap::{Parser, Subcommand};
+use eth_indexer::repository::{Database, SqliteOptions};
+use std::time::Instant;
+
+#[derive(Parser)]
+#[command(name = "migrate")]
+#[command(about = "Create or upgrade the database schema", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    /// List the applied and pending migrations without applying any
+    #[arg(long)]
+    status: bool,
+
+    /// Undo the migrations applied above this version, newest first. Refuses
+    /// when one of them can't be undone; the indexer applies them again on
+    /// its next start.
+    #[arg(long, value_name = "VERSION", conflicts_with = "status")]
+    down_to: Option<i32>,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Optimize, analyze and vacuum the database, reporting its size before
+    /// and after. Refuses to run while another connection is writing.
+    Maintenance {
+        /// Rebuild the whole file with VACUUM instead of an incremental vacuum.
+        /// Needs free disk space about the size of the database.
+        #[arg(long)]
+        full: bool,
+
+        /// Also rebuild every index
+        #[arg(long)]
+        reindex: bool,
+
+        /// Run even when another writer is detected
+        #[arg(long)]
+        force: bool,
+    },
+}
 
 fn main() -> Result<()> {
+    let cli = Cli::parse();
     tracing_subscriber::fmt().init();
     dotenv::dotenv().ok();
 
     let database_url = std::env::var("DATABASE_URL").unwrap();
 
+    if cli.status {
+        return print_status(&database_url);
+    }
+
+    if let Some(target) = cli.down_to {
+        let undone = Database::migrate_down_to(&database_url, &SqliteOptions::default(), target)?;
+        if undone.is_empty() {
+            println!("Nothing to undo, the database is at or below version {target}");
+        } else {
+            println!("Undid migrations {undone:?}, the database is now at version {target}");
+        }
+        return Ok(());
+    }
+
     println!("Running migrations on database: {database_url}");
 
-    let _db = Database::new(&database_url)?;
+    let db = Database::new(&database_url)?;
 
     println!("Migrations completed successfully!");
 
+    if let Some(Commands::Maintenance {
+        full,
+        reindex,
+        force,
+    }) = cli.command
+    {
+        if !force {
+            db.ensure_no_other_writer()?;
+        }
+        run_maintenance(&db, full, reindex)?;
+    }
+
+    Ok(())
+}
+
+fn print_status(database_url: &str) -> Result<()> {
+    let migrations = Database::migration_status(database_url, &SqliteOptions::default())?;
+
+    println!("Database: {database_url}");
+    println!(
+        "{:<9}{:<10}{:<12}Applied at (UTC)",
+        "Version", "Status", "Reversible"
+    );
+    for migration in &migrations {
+        let status = match (migration.applied, migration.known) {
+            (true, true) => "applied",
+            (true, false) => "unknown",
+            (false, _) => "pending",
+        };
+        println!(
+            "{:<9}{:<10}{:<12}{}",
+            migration.version,
+            status,
+            if migration.reversible { "yes" } else { "no" },
+            migration.applied_at.as_deref().unwrap_or("-")
+        );
+    }
+
+    let pending = migrations.iter().filter(|m| !m.applied).count();
+    if migrations.iter().any(|m| !m.known) {
+        println!("The database was migrated by a newer binary, upgrade this one before using it");
+    } else if pending > 0 {
+        println!("{pending} pending migration(s), run migrate to apply them");
+    } else {
+        println!("Up to date");
+    }
+
+    Ok(())
+}
+
+fn run_maintenance(db: &Database, full: bool, reindex: bool) -> Result<()> {
+    let size_before = db.file_size()?;
+    let started = Instant::now();
+
+    timed("PRAGMA optimize", || db.optimize())?;
+    timed("ANALYZE", || db.analyze())?;
+    // Before the vacuum, which leaves the file compacted for the size report
+    if reindex {
+        timed("REINDEX", || db.reindex())?;
+    }
+    if full {
+        timed("VACUUM", || db.vacuum())?;
+    } else {
+        let vacuumed = timed("Incremental vacuum", || db.incremental_vacuum())?;
+        if !vacuumed {
+            println!(
+                "Incremental vacuum isn't enabled on this database, run with --full once to enable it"
+            );
+        }
+    }
+
+    let size_after = db.file_size()?;
+    println!(
+        "Maintenance finished in {:.1?}: {} -> {} ({:+.1} MiB)",
+        started.elapsed(),
+        format_mib(size_before),
+        format_mib(size_after),
+        (size_after as f64 - size_before as f64) / MIB
+    );
+
     Ok(())
 }
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+fn format_mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / MIB)
+}
+
+fn timed<T>(name: &str, operation: impl FnOnce() -> eth_indexer::error::Result<T>) -> Result<T> {
+    let start = Instant::now();
+    let result = operation()?;
+    println!("{name} took {:.1?}", start.elapsed());
+    Ok(result)
+}
@@ -1,28 +1,96 @@
-use anyhow::Result;
+use alloy_primitives::Address;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use eth_indexer::config::Config;
+use eth_indexer::events::balanceOfCall;
 use eth_indexer::query::commands::{
-    AddressHistoryQuery, TransferQuery, cmd_address_history, cmd_balance, cmd_stats,
-    cmd_top_holders, cmd_transfers,
+    AddressHistoryQuery, DEFAULT_TRANSFER_LIMIT, OnChainBalance, TransferQuery,
+    cmd_address_history, cmd_balance, cmd_balance_at_block, cmd_balance_of, cmd_block,
+    cmd_check_integrity, cmd_counterparties, cmd_coverage, cmd_distribution, cmd_events,
+    cmd_export_holders, cmd_indexing_log, cmd_label_add, cmd_label_import, cmd_label_remove,
+    cmd_list_tokens, cmd_notifications, cmd_owner_of, cmd_reorgs, cmd_snapshot, cmd_stats,
+    cmd_sync_status, cmd_token_id_balance, cmd_token_info, cmd_tokens_of, cmd_top_holders,
+    cmd_transfers, cmd_tx, cmd_volume, parse_address,
 };
-use eth_indexer::query::formatters::OutputFormat;
-use eth_indexer::repository::{BalanceRepository, Database, TokenRepository, TransferRepository};
+use eth_indexer::query::formatters::{FormatOptions, OutputFormat};
+use eth_indexer::repository::{
+    BalanceRepository, Database, EventRepository, IndexingLogRepository, LabelRepository,
+    MultiTokenRepository, NftRepository, NotificationRepository, ReorgRepository,
+    ScannedRangeRepository, TokenRepository, TransferRepository,
+};
+use eth_indexer::rpc::{RpcApi, RpcClient};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// How long `token-info` and `sync-status` wait for the chain head before
+/// leaving it out
+const HEAD_LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Parser)]
 #[command(name = "query")]
 #[command(about = "Query indexed ERC20 transfer data", long_about = None)]
 struct Cli {
-    #[arg(short, long, default_value = "table")]
-    format: String,
+    /// TOML config file, overridden by environment variables
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// table, json, jsonl, csv or markdown. Defaults to table, and to json
+    /// for sync-status.
+    #[arg(short, long)]
+    format: Option<String>,
+
+    /// Show transaction hashes in full instead of shortened in table and
+    /// markdown output
+    #[arg(long)]
+    full_hashes: bool,
+
+    /// Explorer URL template such as https://etherscan.io/tx/{hash}. JSON and
+    /// CSV output give transaction URLs instead of bare hashes.
+    #[arg(long, value_name = "TEMPLATE")]
+    explorer: Option<String>,
+
+    /// Write the output to this file instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Show labeled addresses by their label alone in table and markdown
+    /// output, instead of the address followed by the label
+    #[arg(long)]
+    labels_only: bool,
+
+    /// List the tokens of `TOKENS_CONFIG` and how far each is synced
+    #[arg(long)]
+    list_tokens: bool,
 
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     Balance {
         address: String,
+        /// ERC-1155 token id to get the balance of, decimal or 0x-prefixed hex
+        #[arg(long)]
+        token_id: Option<String>,
+        #[arg(long, default_value = "false")]
+        finalized: bool,
+        /// Balance at the end of this block instead of the current one,
+        /// counting unfinalized transfers
+        #[arg(long, conflicts_with_all = ["token_id", "finalized"])]
+        at_block: Option<u64>,
+    },
+    /// Balance read from the chain with an eth_call, next to the indexed
+    /// balance at the same block
+    #[command(name = "balanceof", alias = "balance-of")]
+    BalanceOf {
+        address: String,
+        /// Block to read at, the chain head by default
+        #[arg(long)]
+        block: Option<u64>,
     },
     Transfers {
         #[arg(long)]
@@ -31,16 +99,34 @@ enum Commands {
         #[arg(long)]
         to: Option<String>,
 
+        /// Transfers with this address on either side
+        #[arg(long)]
+        involving: Option<String>,
+
+        /// Leave out transfers sent by these addresses, comma-separated or
+        /// repeated
+        #[arg(long, value_delimiter = ',')]
+        exclude_from: Vec<String>,
+
+        /// Leave out transfers received by these addresses, e.g. the burn
+        /// address
+        #[arg(long, value_delimiter = ',')]
+        exclude_to: Vec<String>,
+
         #[arg(long)]
         block: Option<u64>,
 
         #[arg(long, num_args = 2, value_names = ["START", "END"])]
         block_range: Option<Vec<u64>>,
 
+        /// Only transfers moving this ERC-721 or ERC-1155 token id
+        #[arg(long)]
+        token_id: Option<String>,
+
         #[arg(long, default_value = "false")]
         finalized: bool,
 
-        #[arg(long, default_value = "100")]
+        #[arg(long, default_value_t = DEFAULT_TRANSFER_LIMIT)]
         limit: usize,
 
         #[arg(long, default_value = "0")]
@@ -49,41 +135,250 @@ enum Commands {
     TopHolders {
         #[arg(default_value = "10")]
         count: usize,
+        /// Leave out holders below this balance, in token units
+        #[arg(long)]
+        min_balance: Option<String>,
+    },
+    Stats {
+        /// Recompute from the transfers table instead of the running counters
+        #[arg(long, default_value = "false")]
+        exact: bool,
+    },
+    TokenInfo,
+    Distribution {
+        /// Balance thresholds to count holders above, in token units
+        #[arg(long, value_delimiter = ',', default_value = "1,100,10000")]
+        thresholds: Vec<String>,
+    },
+    Volume {
+        /// Blocks per bucket, 7200 is roughly one day
+        #[arg(long, default_value = "7200")]
+        bucket_blocks: u64,
+
+        #[arg(long, num_args = 2, value_names = ["START", "END"])]
+        block_range: Option<Vec<u64>>,
+    },
+    /// All transfers in a block with the block's totals
+    Block {
+        number: u64,
+    },
+    /// Every indexed transfer in a transaction
+    Tx {
+        hash: String,
+    },
+    /// Write every holder and balance to a file
+    ExportHolders {
+        #[arg(long)]
+        output: PathBuf,
+        /// csv or jsonl
+        #[arg(long, default_value = "csv")]
+        format: String,
+    },
+    /// Write every holder's balance at the end of a past block to a file, e.g.
+    /// for snapshot voting
+    Snapshot {
+        #[arg(long)]
+        block: u64,
+        #[arg(long)]
+        output: PathBuf,
+        /// csv or jsonl
+        #[arg(long, default_value = "csv")]
+        format: String,
+        /// Also count transfers that aren't finalized yet
+        #[arg(long)]
+        include_unfinalized: bool,
+    },
+    /// Name addresses, shown next to them in transfers, top-holders and
+    /// counterparties output. Opens the database for writing.
+    Label {
+        #[command(subcommand)]
+        action: LabelAction,
+    },
+    /// Addresses an address transferred with most, by combined volume
+    Counterparties {
+        address: String,
+        #[arg(long, default_value = "20")]
+        limit: usize,
+    },
+    /// Watchlist notifications the indexer fired, most recent first
+    Notifications {
+        #[arg(long, default_value = "50")]
+        limit: usize,
+    },
+    /// Blocks whose transfers were replaced after a chain reorg, most recent first
+    Reorgs {
+        #[arg(long, default_value = "50")]
+        limit: usize,
+    },
+    /// Ranges the indexer recently inserted and finalized, most recent first,
+    /// with INDEXING_LOG_RETENTION set
+    IndexingLog {
+        #[arg(long, default_value = "50")]
+        last: usize,
+    },
+    /// Events indexed with EVENT_SIGNATURE, most recent first
+    Events {
+        /// Only events whose decoded field has this value, e.g.
+        /// `sender=0xabc...`. Repeat to require several.
+        #[arg(long = "where", value_name = "FIELD=VALUE")]
+        conditions: Vec<String>,
+        #[arg(long, default_value = "false")]
+        finalized: bool,
+        #[arg(long, default_value = "50")]
+        limit: usize,
+    },
+    /// Current owner of an ERC-721 token, with TOKEN_STANDARD=erc721
+    OwnerOf {
+        /// Decimal, or hex with a 0x prefix
+        token_id: String,
+    },
+    /// ERC-721 tokens an address owns, with TOKEN_STANDARD=erc721
+    TokensOf {
+        address: String,
+    },
+    /// Recompute balances from the finalized transfers and check the stored
+    /// data against them and the token's cursors. Exits non-zero on problems.
+    CheckIntegrity,
+    /// Gaps in the blocks scanned between the token's deployment and the last
+    /// processed block. Exits non-zero when there are any, unless they were
+    /// queued with --repair.
+    Coverage {
+        /// Queue the gaps to be scanned on the next indexer start. Opens the
+        /// database for writing.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Cursors, chain head, lag and last insertion time, for health checks
+    SyncStatus {
+        /// Exit non-zero when the index is more than this many blocks behind
+        /// the chain head, or the head can't be fetched
+        #[arg(long, value_name = "N")]
+        exit_nonzero_if_behind: Option<u64>,
     },
-    Stats,
     AddressHistory {
         address: String,
         #[arg(long, default_value = "false")]
         finalized: bool,
-        #[arg(long, default_value = "100")]
+        #[arg(long, default_value_t = DEFAULT_TRANSFER_LIMIT)]
         limit: usize,
         #[arg(long, default_value = "0")]
         offset: usize,
+        /// Add the balance after each transfer; later pages carry on from the
+        /// earlier ones
+        #[arg(long)]
+        with_running_balance: bool,
+        /// Balance before the address's first transfer, in token units
+        #[arg(long, requires = "with_running_balance")]
+        starting_balance: Option<String>,
     },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+#[derive(Subcommand)]
+enum LabelAction {
+    /// Label an address, replacing its label if it has one
+    Add { address: String, label: String },
+    /// Drop an address's label
+    Remove { address: String },
+    /// Label the addresses of an `address,label` CSV file, which may start
+    /// with a header. Nothing is imported if any row is invalid.
+    ImportCsv { path: PathBuf },
+}
+
+// Queries read the database on the main thread, so it runs outside the
+// runtime, which only serves the commands that call a provider
+fn main() -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
     let cli = Cli::parse();
-    let format = OutputFormat::from(cli.format.as_str());
+    let default_format = match cli.command {
+        Some(Commands::SyncStatus { .. }) => "json",
+        _ => "table",
+    };
+    let format: OutputFormat = cli.format.as_deref().unwrap_or(default_format).parse()?;
+    let format_options = FormatOptions::new(cli.full_hashes, cli.explorer)?;
 
-    let config = Config::from_env()?;
+    let config = Config::load(cli.config.as_deref())?;
+
+    if cli.list_tokens && cli.command.is_some() {
+        anyhow::bail!("--list-tokens can't be combined with a command");
+    }
+    let Some(command) = cli.command else {
+        if !cli.list_tokens {
+            anyhow::bail!("No command given, run with --help to see them");
+        }
+        if config.tokens_config.is_none() {
+            anyhow::bail!("--list-tokens needs TOKENS_CONFIG to be set");
+        }
+        let mut out = open_output(cli.output.as_deref())?;
+        cmd_list_tokens(&config, &config.tokens()?, &format, &mut out)?;
+        out.flush()?;
+        return Ok(());
+    };
 
-    let db = Database::new(&config.database_url)?;
-    let transfer_repo = TransferRepository::new(&db.conn);
-    let token_repo = TokenRepository::new(&db.conn);
-    let balance_repo = BalanceRepository::new(&db.conn);
+    let db = Database::open_read_only(&config.database_url, config.sqlite_options())?;
+    let reader = db.reader()?;
     let token_address = &config.erc20_contract_address;
+    let transfer_repo = TransferRepository::new(&reader, token_address);
+    let token_repo = TokenRepository::new(&reader);
+    let balance_repo = BalanceRepository::new(&reader, token_address);
+    let format_options = format_options.with_labels(db.read_pool(), cli.labels_only)?;
 
-    match cli.command {
-        Commands::Balance { address } => {
-            cmd_balance(&balance_repo, &token_repo, token_address, &address, &format)?;
+    let mut out = open_output(cli.output.as_deref())?;
+
+    match command {
+        Commands::Balance {
+            address,
+            token_id,
+            finalized,
+            at_block,
+        } => match (token_id, at_block) {
+            (_, Some(block)) => cmd_balance_at_block(
+                &balance_repo,
+                &token_repo,
+                token_address,
+                &address,
+                block,
+                &format,
+                &mut out,
+            )?,
+            (Some(token_id), None) => cmd_token_id_balance(
+                &MultiTokenRepository::new(&reader, token_address),
+                &address,
+                &token_id,
+                finalized,
+                &format,
+                &mut out,
+            )?,
+            (None, None) => cmd_balance(
+                &balance_repo,
+                &token_repo,
+                token_address,
+                &address,
+                finalized,
+                &format,
+                &mut out,
+            )?,
+        },
+        Commands::BalanceOf { address, block } => {
+            let address = parse_address(&address)?;
+            let on_chain = runtime.block_on(fetch_balance_of(&config, address, block))?;
+            cmd_balance_of(
+                &balance_repo,
+                &token_repo,
+                token_address,
+                on_chain,
+                &format,
+                &mut out,
+            )?;
         }
         Commands::Transfers {
             from,
             to,
+            involving,
+            exclude_from,
+            exclude_to,
             block,
             block_range,
+            token_id,
             finalized,
             limit,
             offset,
@@ -92,35 +387,351 @@ async fn main() -> Result<()> {
             let query = TransferQuery {
                 from,
                 to,
+                involving,
+                exclude_from,
+                exclude_to,
                 block,
                 block_range: range,
+                token_id,
                 finalized,
                 limit,
                 offset,
             };
-            cmd_transfers(&transfer_repo, &token_repo, token_address, query, &format)?;
+            cmd_transfers(
+                &transfer_repo,
+                &token_repo,
+                token_address,
+                query,
+                &format_options,
+                &format,
+                &mut out,
+            )?;
         }
-        Commands::TopHolders { count } => {
-            cmd_top_holders(&balance_repo, &token_repo, token_address, count, &format)?;
+        Commands::TopHolders { count, min_balance } => {
+            cmd_top_holders(
+                &balance_repo,
+                &token_repo,
+                token_address,
+                (count, min_balance.as_deref()),
+                &format_options,
+                &format,
+                &mut out,
+            )?;
         }
-        Commands::Stats => {
-            cmd_stats(&transfer_repo, &format)?;
+        Commands::Stats { exact } => {
+            cmd_stats(&transfer_repo, exact, &format, &mut out)?;
+        }
+        Commands::Distribution { thresholds } => {
+            cmd_distribution(
+                &balance_repo,
+                &token_repo,
+                token_address,
+                &thresholds,
+                &format,
+                &mut out,
+            )?;
+        }
+        Commands::Volume {
+            bucket_blocks,
+            block_range,
+        } => {
+            let range = block_range.map(|v| if v.len() >= 2 { (v[0], v[1]) } else { (0, 0) });
+            cmd_volume(
+                &transfer_repo,
+                &token_repo,
+                token_address,
+                bucket_blocks,
+                range,
+                &format,
+                &mut out,
+            )?;
+        }
+        Commands::TokenInfo => {
+            let latest_block = runtime.block_on(fetch_latest_block(&config));
+            cmd_token_info(&token_repo, token_address, latest_block, &format, &mut out)?;
+        }
+        Commands::Block { number } => {
+            cmd_block(
+                &transfer_repo,
+                &token_repo,
+                token_address,
+                number,
+                &format_options,
+                &format,
+                &mut out,
+            )?;
+        }
+        Commands::Tx { hash } => {
+            cmd_tx(
+                &transfer_repo,
+                &token_repo,
+                token_address,
+                &hash,
+                &format,
+                &mut out,
+            )?;
+        }
+        Commands::ExportHolders {
+            output,
+            format: export_format,
+        } => {
+            cmd_export_holders(&reader, token_address, &output, &export_format)?;
+        }
+        Commands::Snapshot {
+            block,
+            output,
+            format: export_format,
+            include_unfinalized,
+        } => {
+            cmd_snapshot(
+                &reader,
+                token_address,
+                block,
+                include_unfinalized,
+                &output,
+                &export_format,
+            )?;
+        }
+        Commands::Counterparties { address, limit } => {
+            cmd_counterparties(
+                &transfer_repo,
+                &token_repo,
+                token_address,
+                (&address, limit),
+                &format_options,
+                &format,
+                &mut out,
+            )?;
+        }
+        Commands::Label { action } => {
+            let writable = Database::with_options(&config.database_url, config.sqlite_options())?;
+            let label_repo = LabelRepository::new(&writable.conn);
+            match action {
+                LabelAction::Add { address, label } => {
+                    cmd_label_add(&label_repo, &address, &label, &mut out)?
+                }
+                LabelAction::Remove { address } => {
+                    cmd_label_remove(&label_repo, &address, &mut out)?
+                }
+                LabelAction::ImportCsv { path } => {
+                    cmd_label_import(&label_repo, &path, &mut out)?;
+                }
+            }
+        }
+        Commands::Notifications { limit } => {
+            cmd_notifications(
+                &NotificationRepository::new(&reader, token_address),
+                &token_repo,
+                token_address,
+                limit,
+                &format_options,
+                &format,
+                &mut out,
+            )?;
+        }
+        Commands::Reorgs { limit } => {
+            cmd_reorgs(
+                &ReorgRepository::new(&reader, token_address),
+                limit,
+                &format_options,
+                &format,
+                &mut out,
+            )?;
+        }
+        Commands::IndexingLog { last } => {
+            cmd_indexing_log(
+                &IndexingLogRepository::new(&reader, token_address),
+                last,
+                &format,
+                &mut out,
+            )?;
+        }
+        Commands::Events {
+            conditions,
+            finalized,
+            limit,
+        } => {
+            cmd_events(
+                &EventRepository::new(&reader, token_address),
+                &conditions,
+                finalized,
+                limit,
+                &format_options,
+                &format,
+                &mut out,
+            )?;
+        }
+        Commands::OwnerOf { token_id } => {
+            cmd_owner_of(
+                &NftRepository::new(&reader, token_address),
+                &token_id,
+                &format,
+                &mut out,
+            )?;
+        }
+        Commands::TokensOf { address } => {
+            cmd_tokens_of(
+                &NftRepository::new(&reader, token_address),
+                &address,
+                &format,
+                &mut out,
+            )?;
+        }
+        Commands::CheckIntegrity => {
+            let problems = cmd_check_integrity(&reader, token_address, &format, &mut out)?;
+            if problems > 0 {
+                out.flush()?;
+                anyhow::bail!("Integrity check found {problems} problems");
+            }
+        }
+        Commands::Coverage { repair } => {
+            let coverage = cmd_coverage(
+                &token_repo,
+                &ScannedRangeRepository::new(&reader, token_address),
+                token_address,
+                &format,
+                &mut out,
+            )?;
+            if !coverage.gaps.is_empty() {
+                out.flush()?;
+                if !repair {
+                    anyhow::bail!(
+                        "Found {} unscanned blocks in {} gaps, rerun with --repair to queue them",
+                        coverage.missing_blocks(),
+                        coverage.gaps.len()
+                    );
+                }
+                let writable =
+                    Database::with_options(&config.database_url, config.sqlite_options())?;
+                ScannedRangeRepository::new(&writable.conn, token_address)
+                    .queue_repairs(&coverage.gaps)?;
+                eprintln!(
+                    "Queued {} gaps to be scanned on the next indexer start",
+                    coverage.gaps.len()
+                );
+            }
+        }
+        Commands::SyncStatus {
+            exit_nonzero_if_behind,
+        } => {
+            let chain_head = runtime.block_on(fetch_latest_block(&config));
+            let blocks_behind = cmd_sync_status(
+                &transfer_repo,
+                &token_repo,
+                token_address,
+                chain_head,
+                &format,
+                &mut out,
+            )?;
+            if let Some(max_behind) = exit_nonzero_if_behind {
+                match blocks_behind {
+                    Some(behind) if behind <= max_behind => {}
+                    Some(behind) => {
+                        out.flush()?;
+                        anyhow::bail!("Index is {behind} blocks behind, more than {max_behind}");
+                    }
+                    None => {
+                        out.flush()?;
+                        anyhow::bail!("Chain head unavailable, can't tell how far behind");
+                    }
+                }
+            }
         }
         Commands::AddressHistory {
             address,
             finalized,
             limit,
             offset,
+            with_running_balance,
+            starting_balance,
         } => {
             let query = AddressHistoryQuery {
                 address,
                 finalized,
                 limit,
                 offset,
+                with_running_balance,
+                starting_balance,
             };
-            cmd_address_history(&transfer_repo, &token_repo, token_address, query, &format)?;
+            cmd_address_history(
+                &transfer_repo,
+                &token_repo,
+                token_address,
+                query,
+                &format_options,
+                &format,
+                &mut out,
+            )?;
         }
     }
 
+    out.flush()?;
+
     Ok(())
 }
+
+/// `balanceOf(address)` on the token at `block`, or at the chain head
+async fn fetch_balance_of(
+    config: &Config,
+    address: Address,
+    block: Option<u64>,
+) -> Result<OnChainBalance> {
+    let client = RpcClient::new(&config.json_rpc_urls, config).await?;
+    let block_number = match block {
+        Some(block) => block,
+        None => client.get_latest_block().await?,
+    };
+    let token_address = config.contract_at(block_number);
+
+    match client
+        .call_contract_at_block(
+            token_address,
+            balanceOfCall { account: address },
+            block_number,
+        )
+        .await
+    {
+        Ok(balance) => Ok(OnChainBalance {
+            address,
+            block_number,
+            balance,
+        }),
+        Err(e) => {
+            let code = client
+                .get_code_at_block(token_address, block_number)
+                .await?;
+            if code.is_empty() {
+                anyhow::bail!(
+                    "There is no contract at {token_address:?} at block {block_number}. Check \
+                     ERC20_CONTRACT_ADDRESS and TOKEN_SEGMENTS, or pick a block after the token was deployed"
+                );
+            }
+            Err(anyhow::Error::from(e).context(format!(
+                "balanceOf failed on {token_address:?} at block {block_number}, is it an ERC20 token?"
+            )))
+        }
+    }
+}
+
+/// A buffered writer to `path`, or to stdout without one
+fn open_output(path: Option<&Path>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("Failed to create {}", path.display())
+            })?))
+        }
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    })
+}
+
+/// Best-effort chain head for `token-info` and `sync-status`, None if no provider answers in time
+async fn fetch_latest_block(config: &Config) -> Option<u64> {
+    timeout(HEAD_LOOKUP_TIMEOUT, async {
+        let client = RpcClient::new(&config.json_rpc_urls, config).await?;
+        client.get_latest_block().await
+    })
+    .await
+    .ok()?
+    .ok()
+}
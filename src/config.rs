@@ -1,78 +1,1135 @@
-use alloy_primitives::Address;
+use crate::events::CustomEvent;
+use crate::logging::LogFormat;
+use crate::repository::SqliteOptions;
+use crate::rpc::Transport;
+use alloy::transports::http::reqwest::header::{HeaderName, HeaderValue};
+use alloy_primitives::{Address, U256};
 use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
+use toml_edit::{DocumentMut, Value};
+
+/// Every setting, by environment variable name. A config file uses the same
+/// names in lower case, e.g. `batch_size = 500`.
+const SETTINGS: &[&str] = &[
+    "JSON_RPC_URLS",
+    "JSON_RPC_URL",
+    "ERC20_CONTRACT_ADDRESS",
+    "TOKEN_STANDARD",
+    "TRACK_DEPOSIT_WITHDRAWAL",
+    "EVENT_SIGNATURE",
+    "DATABASE_URL",
+    "DEPLOYMENT_BLOCK",
+    "DEPLOYMENT_SEARCH_HINT",
+    "TOKEN_SEGMENTS",
+    "BATCH_SIZE",
+    "MIN_BATCH_SIZE",
+    "MAX_BATCH_SIZE",
+    "TARGET_LOGS_PER_REQUEST",
+    "RATE_LIMIT_DELAY_MS",
+    "RPC_REQUESTS_PER_SECOND",
+    "MAX_PENDING_REQUESTS",
+    "PARALLEL_SEGMENTS",
+    "REQUEST_TIMEOUT_SECS",
+    "LIGHT_REQUEST_TIMEOUT_SECS",
+    "RPC_MAX_RETRIES",
+    "RPC_RETRY_BACKOFF_MS",
+    "RPC_RETRY_MAX_DELAY_MS",
+    "FINALITY_UPDATE_INTERVAL_SECS",
+    "SKIP_INITIAL_FINALITY",
+    "FINALITY_FULL_REFETCH",
+    "BALANCE_CHECKPOINT_INTERVAL",
+    "BLOCK_TIME_SECS",
+    "PROGRESS_INTERVAL_SECS",
+    "RPC_STATS_INTERVAL_SECS",
+    "PROVIDER_FAILURE_THRESHOLD",
+    "PROVIDER_QUARANTINE_SECS",
+    "PROVIDER_MAX_LAG_BLOCKS",
+    "SQLITE_BUSY_TIMEOUT_MS",
+    "SQLITE_CACHE_SIZE_KIB",
+    "SQLITE_MMAP_SIZE",
+    "SQLITE_READ_POOL_SIZE",
+    "MULTI_ROW_INSERT_THRESHOLD",
+    "RANGE_MAX_ATTEMPTS",
+    "INDEXING_LOG_RETENTION",
+    "STRICT_LOGS",
+    "DECODE_ERRORS",
+    "WATCH_ADDRESSES",
+    "WATCH_MIN_VALUE",
+    "WEBHOOK_URL",
+    "WEBHOOK_MAX_RETRIES",
+    "WEBHOOK_DEAD_LETTER_PATH",
+    "LOG_FORMAT",
+    "LOG_FILTER",
+    "INDEXER_MODE",
+    "EXPECTED_CHAIN_ID",
+    "RPC_MODE",
+    "RPC_FIXTURE_DIR",
+    "TOKENS_CONFIG",
+    "TOKEN_RESTART_DELAY_SECS",
+    "TOKEN_MAX_RESTARTS",
+];
+
+/// Whether the indexer keeps following the chain head after catching up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexerMode {
+    /// Poll for new blocks forever
+    Follow,
+    /// Scan up to the head seen at startup, run a finality pass and exit,
+    /// for running from cron
+    Once,
+}
+
+impl FromStr for IndexerMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "follow" => Ok(IndexerMode::Follow),
+            "once" => Ok(IndexerMode::Once),
+            _ => anyhow::bail!("Unknown indexer mode {s}, expected follow or once"),
+        }
+    }
+}
+
+/// Whether the indexer talks to the RPC providers, records what they answer
+/// or replays a recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcMode {
+    Live,
+    /// Query the providers and write every response to `rpc_fixture_dir`
+    Record,
+    /// Serve responses from `rpc_fixture_dir` without any network access,
+    /// failing requests that weren't recorded
+    Replay,
+}
+
+impl FromStr for RpcMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "live" => Ok(RpcMode::Live),
+            "record" => Ok(RpcMode::Record),
+            "replay" => Ok(RpcMode::Replay),
+            _ => anyhow::bail!("Unknown RPC mode {s}, expected live, record or replay"),
+        }
+    }
+}
+
+/// Which Transfer event the indexed contract emits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenStandard {
+    /// `Transfer(address indexed from, address indexed to, uint256 value)`
+    Erc20,
+    /// `Transfer(address indexed from, address indexed to, uint256 indexed tokenId)`.
+    /// Each transfer moves one token, so balances count the tokens held.
+    Erc721,
+    /// `TransferSingle` and `TransferBatch`, with a balance per address and id
+    Erc1155,
+}
+
+impl FromStr for TokenStandard {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().replace('-', "").as_str() {
+            "erc20" => Ok(TokenStandard::Erc20),
+            "erc721" => Ok(TokenStandard::Erc721),
+            "erc1155" => Ok(TokenStandard::Erc1155),
+            _ => anyhow::bail!("Unknown token standard {s}, expected erc20, erc721 or erc1155"),
+        }
+    }
+}
+
+/// What the indexer does with a Transfer log it can't decode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeErrorPolicy {
+    /// Stop indexing with the decode error
+    Fail,
+    /// Record the log in `decode_failures` and carry on without it
+    Skip,
+}
+
+impl FromStr for DecodeErrorPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(DecodeErrorPolicy::Fail),
+            "skip" => Ok(DecodeErrorPolicy::Skip),
+            _ => anyhow::bail!("Unknown decode error policy {s}, expected skip or fail"),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub json_rpc_urls: Vec<String>,
+    /// Requests per second for the URLs given as `url|rps`, overriding
+    /// `rpc_requests_per_second`
+    pub rpc_rate_limits: HashMap<String, f64>,
+    /// Headers sent to the URLs given as `url|Name=value`
+    pub rpc_headers: HashMap<String, Vec<RpcHeader>>,
     pub erc20_contract_address: Address,
+    pub token_standard: TokenStandard,
+    /// Also index WETH-style `Deposit` and `Withdrawal` events, as mints and
+    /// burns. ERC-20 only.
+    pub track_deposit_withdrawal: bool,
+    /// Index the logs of this event, decoded into the `events` table, in
+    /// place of the standard's transfers. No balances are kept.
+    pub custom_event: Option<CustomEvent>,
     pub database_url: String,
+    pub deployment_block: Option<u64>,
+    /// Block the deployment search checks first, typically one the token is
+    /// known to be deployed after
+    pub deployment_search_hint: Option<u64>,
+    /// Contracts the token's events were emitted by, oldest first, for a
+    /// token that moved to a new contract. Empty when it never did.
+    pub token_segments: Vec<TokenSegment>,
     pub batch_size: u64,
+    pub min_batch_size: u64,
+    pub max_batch_size: u64,
+    pub target_logs_per_request: u64,
     pub rate_limit_delay_ms: u64,
+    /// Requests per second each provider is sent, 0 for no limit
+    pub rpc_requests_per_second: f64,
     pub max_pending_requests: usize,
+    /// Segments the blocks up to the finalized head are split into and
+    /// fetched side by side before following the head. 1 scans them in order.
+    pub parallel_segments: usize,
+    /// Timeout of `eth_getLogs` and other requests whose cost grows with
+    /// their range
     pub request_timeout_secs: u64,
+    /// Timeout of single-value requests like the chain head or a block header
+    pub light_request_timeout_secs: u64,
+    /// Retries of a failed RPC request before it gives up
+    pub rpc_max_retries: usize,
+    /// Base of the exponential back-off between retries
+    pub rpc_retry_backoff_ms: u64,
+    pub rpc_retry_max_delay_ms: u64,
     pub finality_update_interval_secs: u64,
+    /// Start scanning without first verifying the blocks stored past the
+    /// finality cursor, leaving them to the periodic updates
+    pub skip_initial_finality: bool,
+    /// Re-fetch the logs of every newly finalized block, instead of only
+    /// where the canonical headers don't match the stored transfers
+    pub finality_full_refetch: bool,
+    /// Blocks between the balance checkpoints the finality worker writes
+    /// behind the finalized block, 0 for none
+    pub balance_checkpoint_interval: u64,
     pub block_time_secs: u64,
+    pub progress_interval_secs: u64,
+    /// Seconds between summaries of the RPC requests sent, 0 for only the
+    /// one at shutdown
+    pub rpc_stats_interval_secs: u64,
+    pub provider_failure_threshold: u32,
+    pub provider_quarantine_secs: u64,
+    pub provider_max_lag_blocks: u64,
+    pub sqlite_busy_timeout_ms: u64,
+    pub sqlite_cache_size_kib: u64,
+    pub sqlite_mmap_size: u64,
+    pub sqlite_read_pool_size: usize,
+    /// Batches with at least this many transfers are inserted with multi-row
+    /// statements
+    pub multi_row_insert_threshold: usize,
+    /// Times a block range is fetched, each with the RPC client's own
+    /// retries, before the scan stops
+    pub range_max_attempts: u32,
+    /// Rows of the `indexing_log` table kept, oldest pruned first. 0 turns
+    /// the log off.
+    pub indexing_log_retention: u64,
+    /// Fail on logs missing their block, transaction or index instead of
+    /// skipping them
+    pub strict_logs: bool,
+    pub decode_errors: DecodeErrorPolicy,
+    /// Transfers from or to these addresses are sent to the webhook
+    pub watch_addresses: Vec<Address>,
+    /// Transfers of at least this many base units are sent to the webhook
+    pub watch_min_value: Option<U256>,
+    pub webhook_url: Option<String>,
+    pub webhook_max_retries: usize,
+    /// Notifications that still failed after every retry are appended here
+    pub webhook_dead_letter_path: String,
+    pub log_format: LogFormat,
+    /// `EnvFilter` directives, e.g. `info,eth_indexer::rpc=warn`
+    pub log_filter: String,
+    pub mode: IndexerMode,
+    /// Chain every RPC provider must report, e.g. 1 for mainnet
+    pub expected_chain_id: Option<u64>,
+    pub rpc_mode: RpcMode,
+    /// Where `RPC_MODE=record` writes responses and `RPC_MODE=replay` reads them
+    pub rpc_fixture_dir: PathBuf,
+    /// TOML list of tokens the indexer supervises, each with its own database
+    pub tokens_config: Option<PathBuf>,
+    /// Wait before restarting a supervised token that failed, doubled for
+    /// each failure in a row
+    pub token_restart_delay_secs: u64,
+    /// Failures in a row after which a supervised token is given up on, 0
+    /// to keep restarting it
+    pub token_max_restarts: u32,
+}
+
+/// Indexer command line flags that take precedence over the environment and
+/// the config file
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct CliOverrides {
+    /// ERC20 contract to index, overrides ERC20_CONTRACT_ADDRESS
+    #[arg(long, value_name = "ADDRESS")]
+    pub contract: Option<Address>,
+
+    /// RPC endpoint, repeat for several. Replaces JSON_RPC_URLS. Append
+    /// `|RPS` to set its requests per second and `|Name=value` to send a header.
+    #[arg(long = "rpc-url", value_name = "URL", value_parser = parse_rpc_url)]
+    pub rpc_urls: Vec<String>,
+
+    /// Database URL, overrides DATABASE_URL
+    #[arg(long, value_name = "URL")]
+    pub database: Option<String>,
+
+    /// Initial blocks per log request, overrides BATCH_SIZE
+    #[arg(long)]
+    pub batch_size: Option<u64>,
+
+    /// Block to start from on a fresh database, overrides DEPLOYMENT_BLOCK.
+    /// Ignored once the database has progress for the contract.
+    #[arg(long)]
+    pub start_block: Option<u64>,
+
+    /// Exit once caught up with the chain head, same as INDEXER_MODE=once
+    #[arg(long)]
+    pub once: bool,
+}
+
+fn parse_rpc_url(url: &str) -> Result<String, String> {
+    parse_rpc_endpoint(url).map(|_| url.to_string())
+}
+
+/// One `JSON_RPC_URLS` entry, written as the URL followed by `|`-separated
+/// options: a requests-per-second limit and any number of `Name=value` headers
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcEndpoint {
+    pub url: String,
+    pub requests_per_second: Option<f64>,
+    pub headers: Vec<RpcHeader>,
+}
+
+/// Extra HTTP header sent with every request to one provider. Values are
+/// usually credentials, so `Debug` leaves them out.
+#[derive(Clone, PartialEq)]
+pub struct RpcHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl fmt::Debug for RpcHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: <redacted>", self.name)
+    }
+}
+
+/// Parse a `url[|rps][|Name=value...]` entry, checking the URL, the limit
+/// and that each header is valid in an HTTP request
+pub fn parse_rpc_endpoint(entry: &str) -> Result<RpcEndpoint, String> {
+    let mut parts = entry.split('|').map(str::trim);
+    let url = parts.next().unwrap_or_default();
+    Transport::parse(url)?;
+
+    let mut endpoint = RpcEndpoint {
+        url: url.to_string(),
+        requests_per_second: None,
+        headers: Vec::new(),
+    };
+    for part in parts {
+        if let Some((name, value)) = part.split_once('=') {
+            let name = name.trim();
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name {name:?} for {url}"))?;
+            // The value is left out, it is likely a secret
+            HeaderValue::from_str(value.trim())
+                .map_err(|_| format!("invalid value for header {name} of {url}"))?;
+            endpoint.headers.push(RpcHeader {
+                name: name.to_string(),
+                value: value.trim().to_string(),
+            });
+            continue;
+        }
+
+        match part.parse::<f64>() {
+            Ok(rps) if rps.is_finite() && rps >= 0.0 && endpoint.requests_per_second.is_none() => {
+                endpoint.requests_per_second = Some(rps)
+            }
+            _ => return Err(format!("invalid requests per second {part:?} for {url}")),
+        }
+    }
+    Ok(endpoint)
+}
+
+/// A contract that emitted the token's events over a range of blocks. An
+/// open start is found with the deployment search, an open end runs to the
+/// chain head.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenSegment {
+    pub address: Address,
+    pub from_block: Option<u64>,
+    pub to_block: Option<u64>,
+}
+
+impl TokenSegment {
+    pub fn contains(&self, block_number: u64) -> bool {
+        self.from_block.is_none_or(|from| block_number >= from)
+            && self.to_block.is_none_or(|to| block_number <= to)
+    }
+}
+
+/// Parse an `address[:from-to]` entry, where either block may be left out
+pub fn parse_token_segment(entry: &str) -> Result<TokenSegment, String> {
+    let (address, range) = entry.split_once(':').unwrap_or((entry, "-"));
+    let address = Address::from_str(address.trim())
+        .map_err(|e| format!("invalid address {:?}: {e}", address.trim()))?;
+    let (from, to) = range
+        .split_once('-')
+        .ok_or_else(|| format!("invalid block range {range:?} for {address}, expected from-to"))?;
+    let block = |value: &str| -> Result<Option<u64>, String> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(None);
+        }
+        value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid block {value:?} for {address}"))
+    };
+
+    let segment = TokenSegment {
+        address,
+        from_block: block(from)?,
+        to_block: block(to)?,
+    };
+    if let (Some(from), Some(to)) = (segment.from_block, segment.to_block)
+        && from > to
+    {
+        return Err(format!("block range {from}-{to} of {address} is empty"));
+    }
+    Ok(segment)
+}
+
+/// One token of `TOKENS_CONFIG`, indexed into a database of its own
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenConfig {
+    pub address: Address,
+    pub database_url: String,
+    /// Endpoints replacing `JSON_RPC_URLS` for this token, in the same
+    /// `url|rps|Name=value` form. Empty to use the shared ones.
+    pub json_rpc_urls: Vec<String>,
+    /// Tag of the token's log lines until its symbol is stored
+    pub symbol: Option<String>,
+}
+
+impl TokenConfig {
+    /// `base` with this token's contract, database and RPC endpoints
+    pub fn apply(&self, base: &Config) -> Result<Config> {
+        let mut config = base.clone();
+        config.erc20_contract_address = self.address;
+        config.database_url = self.database_url.clone();
+        if !self.json_rpc_urls.is_empty() {
+            config.json_rpc_urls.clear();
+            config.rpc_rate_limits.clear();
+            config.rpc_headers.clear();
+            for entry in &self.json_rpc_urls {
+                let endpoint = parse_rpc_endpoint(entry).map_err(|e| {
+                    anyhow::anyhow!("Invalid RPC URL of token {:?}: {e}", self.address)
+                })?;
+                if let Some(rps) = endpoint.requests_per_second {
+                    config.rpc_rate_limits.insert(endpoint.url.clone(), rps);
+                }
+                if !endpoint.headers.is_empty() {
+                    config
+                        .rpc_headers
+                        .insert(endpoint.url.clone(), endpoint.headers);
+                }
+                config.json_rpc_urls.push(endpoint.url);
+            }
+        }
+        if config.json_rpc_urls.is_empty() && config.rpc_mode != RpcMode::Replay {
+            anyhow::bail!(
+                "Token {:?} has no RPC URLs, give it json_rpc_urls or set JSON_RPC_URLS",
+                self.address
+            );
+        }
+        Ok(config)
+    }
+}
+
+/// Keys a `[[token]]` table of `TOKENS_CONFIG` may have
+const TOKEN_KEYS: &[&str] = &["address", "database_url", "json_rpc_urls", "symbol"];
+
+/// Parse a `TOKENS_CONFIG` file: a `[[token]]` table per token with its
+/// `address` and `database_url`, and optionally `json_rpc_urls` and
+/// `symbol`. Every problem found is reported together.
+pub fn load_tokens_config(path: &Path) -> Result<Vec<TokenConfig>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read tokens config {}", path.display()))?;
+    let document: DocumentMut = text
+        .parse()
+        .with_context(|| format!("Invalid TOML in tokens config {}", path.display()))?;
+
+    let mut errors = Vec::new();
+    let mut tokens: Vec<TokenConfig> = Vec::new();
+    for (key, _) in document.iter().filter(|(key, _)| *key != "token") {
+        errors.push(format!("Unknown key `{key}`, expected [[token]] tables"));
+    }
+    let tables = document
+        .get("token")
+        .and_then(|item| item.as_array_of_tables());
+    for (index, table) in tables
+        .into_iter()
+        .flat_map(|tables| tables.iter())
+        .enumerate()
+    {
+        let entry = format!("token {}", index + 1);
+        for (key, _) in table.iter() {
+            if !TOKEN_KEYS.contains(&key) {
+                errors.push(format!("Unknown key `{key}` in {entry}"));
+            }
+        }
+        let string = |key: &str| table.get(key).and_then(|item| item.as_str());
+
+        let address = match string("address").map(Address::from_str) {
+            Some(Ok(address)) => Some(address),
+            Some(Err(e)) => {
+                errors.push(format!("Invalid address of {entry}: {e}"));
+                None
+            }
+            None => {
+                errors.push(format!("No address in {entry}"));
+                None
+            }
+        };
+        let database_url = string("database_url").map(str::to_string);
+        if database_url.is_none() {
+            errors.push(format!("No database_url in {entry}"));
+        }
+        let json_rpc_urls = match table.get("json_rpc_urls") {
+            None => Vec::new(),
+            Some(item) => match item.as_value().and_then(toml_to_string) {
+                Some(urls) => urls
+                    .split(',')
+                    .map(|url| url.trim().to_string())
+                    .filter(|url| !url.is_empty())
+                    .collect(),
+                None => {
+                    errors.push(format!(
+                        "json_rpc_urls of {entry} must be a string or array of strings"
+                    ));
+                    Vec::new()
+                }
+            },
+        };
+        for url in &json_rpc_urls {
+            if let Err(e) = parse_rpc_endpoint(url) {
+                errors.push(format!("Invalid json_rpc_urls entry of {entry}: {e}"));
+            }
+        }
+
+        let (Some(address), Some(database_url)) = (address, database_url) else {
+            continue;
+        };
+        if tokens.iter().any(|token| token.address == address) {
+            errors.push(format!("{address} is listed more than once"));
+        }
+        if tokens
+            .iter()
+            .any(|token| token.database_url == database_url)
+        {
+            errors.push(format!(
+                "{database_url} is used by more than one token, each needs its own database"
+            ));
+        }
+        tokens.push(TokenConfig {
+            address,
+            database_url,
+            json_rpc_urls,
+            symbol: string("symbol").map(str::to_string),
+        });
+    }
+
+    if tokens.is_empty() && errors.is_empty() {
+        errors.push("No [[token]] tables".to_string());
+    }
+    if !errors.is_empty() {
+        anyhow::bail!(
+            "Invalid tokens config {}:\n  - {}",
+            path.display(),
+            errors.join("\n  - ")
+        );
+    }
+    Ok(tokens)
 }
 
 impl Config {
+    /// Tokens of `TOKENS_CONFIG`, empty when it isn't set
+    pub fn tokens(&self) -> Result<Vec<TokenConfig>> {
+        match &self.tokens_config {
+            Some(path) => load_tokens_config(path),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Contract emitting the token's events at a block, which on-chain calls
+    /// such as `balanceOf` go to. Blocks outside every segment get the last one.
+    pub fn contract_at(&self, block_number: u64) -> Address {
+        self.token_segments
+            .iter()
+            .find(|segment| segment.contains(block_number))
+            .or(self.token_segments.last())
+            .map_or(self.erc20_contract_address, |segment| segment.address)
+    }
+
+    /// Settings from the environment (and `.env`) only
     pub fn from_env() -> Result<Self> {
+        Self::load(None)
+    }
+
+    /// Settings from a TOML file, overridden by the environment
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::load(Some(path))
+    }
+
+    /// Precedence is environment variable > config file > default
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        Self::load_with_cli(path, CliOverrides::default())
+    }
+
+    /// Like [`Config::load`] with command line flags taking precedence over
+    /// everything else. A contract or RPC URL given on the command line
+    /// satisfies the requirement for one in the environment.
+    pub fn load_with_cli(path: Option<&Path>, overrides: CliOverrides) -> Result<Self> {
         dotenv::dotenv().ok();
 
-        let json_rpc_urls = if let Ok(urls) = std::env::var("JSON_RPC_URLS") {
-            urls.split(',')
+        let mut settings = match path {
+            Some(path) => Settings::from_file(path)?,
+            None => Settings::default(),
+        };
+        match settings.build(&overrides) {
+            Some(mut config) if settings.errors.is_empty() => {
+                config.merge_cli(overrides);
+                Ok(config)
+            }
+            _ => anyhow::bail!(
+                "Invalid configuration:\n  - {}",
+                settings.errors.join("\n  - ")
+            ),
+        }
+    }
+
+    /// Apply command line flags on top of the loaded settings
+    pub fn merge_cli(&mut self, overrides: CliOverrides) {
+        if let Some(contract) = overrides.contract {
+            self.erc20_contract_address = contract;
+        }
+        if let Some(database) = overrides.database {
+            self.database_url = database;
+        }
+        if let Some(batch_size) = overrides.batch_size {
+            self.batch_size = batch_size;
+        }
+        if let Some(start_block) = overrides.start_block {
+            self.deployment_block = Some(start_block);
+        }
+        if overrides.once {
+            self.mode = IndexerMode::Once;
+        }
+    }
+
+    pub fn sqlite_options(&self) -> SqliteOptions {
+        SqliteOptions {
+            busy_timeout: Duration::from_millis(self.sqlite_busy_timeout_ms),
+            cache_size_kib: self.sqlite_cache_size_kib,
+            mmap_size: self.sqlite_mmap_size,
+            read_pool_size: self.sqlite_read_pool_size,
+        }
+    }
+}
+
+/// URLs, requests-per-second limits and headers by URL
+type RpcEndpoints = (
+    Vec<String>,
+    HashMap<String, f64>,
+    HashMap<String, Vec<RpcHeader>>,
+);
+
+/// Looks settings up in the environment, then the config file, and collects
+/// every problem found so they can be reported together
+#[derive(Default)]
+struct Settings {
+    file: HashMap<String, String>,
+    errors: Vec<String>,
+}
+
+impl Settings {
+    fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let document: DocumentMut = text
+            .parse()
+            .with_context(|| format!("Invalid TOML in config file {}", path.display()))?;
+
+        let mut settings = Settings::default();
+        for (key, item) in document.iter() {
+            let name = key.to_uppercase();
+            if !SETTINGS.contains(&name.as_str()) {
+                settings
+                    .errors
+                    .push(format!("Unknown setting `{key}` in {}", path.display()));
+                continue;
+            }
+            match item.as_value().and_then(toml_to_string) {
+                Some(value) => {
+                    settings.file.insert(name, value);
+                }
+                None => settings.errors.push(format!(
+                    "`{key}` in {} must be a string, number, boolean or array of strings",
+                    path.display()
+                )),
+            }
+        }
+
+        Ok(settings)
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        std::env::var(name)
+            .ok()
+            .or_else(|| self.file.get(name).cloned())
+    }
+
+    fn parse<T>(&mut self, name: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self.get(name)?;
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                self.errors.push(format!("Invalid {name} {value:?}: {e}"));
+                None
+            }
+        }
+    }
+
+    fn parse_or<T>(&mut self, name: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.parse(name).unwrap_or(default)
+    }
+
+    fn list(&self, name: &str) -> Option<Vec<String>> {
+        self.get(name).map(|value| {
+            value
+                .split(',')
                 .map(|s| s.trim().to_string())
                 .filter(|s| !s.is_empty())
                 .collect()
-        } else if let Ok(url) = std::env::var("JSON_RPC_URL") {
-            vec![url]
+        })
+    }
+
+    /// The RPC URLs with the requests-per-second limits and headers given
+    /// after them
+    fn json_rpc_urls(&mut self, overrides: &CliOverrides) -> Option<RpcEndpoints> {
+        let urls = if !overrides.rpc_urls.is_empty() {
+            overrides.rpc_urls.clone()
         } else {
-            return Err(anyhow::anyhow!(
-                "Either JSON_RPC_URLS or JSON_RPC_URL must be set in .env"
-            ));
+            self.rpc_url_entries()?
+        };
+
+        let mut valid = true;
+        let mut endpoints = Vec::new();
+        let mut rate_limits = HashMap::new();
+        let mut headers = HashMap::new();
+        for entry in &urls {
+            match parse_rpc_endpoint(entry) {
+                Ok(endpoint) => {
+                    if let Some(rps) = endpoint.requests_per_second {
+                        rate_limits.insert(endpoint.url.clone(), rps);
+                    }
+                    if !endpoint.headers.is_empty() {
+                        headers.insert(endpoint.url.clone(), endpoint.headers);
+                    }
+                    endpoints.push(endpoint.url);
+                }
+                Err(e) => {
+                    self.errors
+                        .push(format!("Invalid JSON_RPC_URLS entry: {e}"));
+                    valid = false;
+                }
+            }
+        }
+        valid.then_some((endpoints, rate_limits, headers))
+    }
+
+    fn rpc_url_entries(&mut self) -> Option<Vec<String>> {
+        let urls = match self.list("JSON_RPC_URLS") {
+            Some(urls) => urls,
+            None => match self.get("JSON_RPC_URL") {
+                Some(url) => vec![url.trim().to_string()],
+                None => {
+                    self.errors
+                        .push("Either JSON_RPC_URLS or JSON_RPC_URL must be set".to_string());
+                    return None;
+                }
+            },
         };
 
-        if json_rpc_urls.is_empty() {
-            return Err(anyhow::anyhow!("At least one RPC URL must be provided"));
+        if urls.is_empty() {
+            self.errors
+                .push("At least one RPC URL must be provided".to_string());
+            return None;
+        }
+        Some(urls)
+    }
+
+    fn erc20_contract_address(&mut self, overrides: &CliOverrides) -> Option<Address> {
+        if overrides.contract.is_some() {
+            return overrides.contract;
+        }
+        if self.get("ERC20_CONTRACT_ADDRESS").is_none() {
+            self.errors
+                .push("ERC20_CONTRACT_ADDRESS must be set".to_string());
+            return None;
+        }
+        self.parse("ERC20_CONTRACT_ADDRESS")
+    }
+
+    fn watch_addresses(&mut self) -> Vec<Address> {
+        let mut addresses = Vec::new();
+        for entry in self.list("WATCH_ADDRESSES").unwrap_or_default() {
+            match Address::from_str(&entry) {
+                Ok(address) => addresses.push(address),
+                Err(e) => self
+                    .errors
+                    .push(format!("Invalid address {entry} in WATCH_ADDRESSES: {e}")),
+            }
+        }
+        addresses
+    }
+
+    /// Segments in order, each ending before the next one starts. Only the
+    /// last may run to the chain head.
+    fn token_segments(&mut self) -> Vec<TokenSegment> {
+        let mut segments: Vec<TokenSegment> = Vec::new();
+        for entry in self.list("TOKEN_SEGMENTS").unwrap_or_default() {
+            let segment = match parse_token_segment(&entry) {
+                Ok(segment) => segment,
+                Err(e) => {
+                    self.errors
+                        .push(format!("Invalid TOKEN_SEGMENTS entry: {e}"));
+                    continue;
+                }
+            };
+            if let Some(previous) = segments.last() {
+                match (previous.to_block, segment.from_block) {
+                    (None, _) => self.errors.push(format!(
+                        "Invalid TOKEN_SEGMENTS: {} is followed by {} but has no end block",
+                        previous.address, segment.address
+                    )),
+                    (Some(to), Some(from)) if from <= to => self.errors.push(format!(
+                        "Invalid TOKEN_SEGMENTS: {} starts at block {from}, before {} ends at block {to}",
+                        segment.address, previous.address
+                    )),
+                    _ => {}
+                }
+            }
+            segments.push(segment);
+        }
+        segments
+    }
+
+    fn watch_min_value(&mut self) -> Option<U256> {
+        let value = self.get("WATCH_MIN_VALUE")?;
+        match U256::from_str_radix(value.trim(), 10) {
+            Ok(min) => Some(min),
+            Err(e) => {
+                self.errors
+                    .push(format!("Invalid WATCH_MIN_VALUE {value:?}: {e}"));
+                None
+            }
+        }
+    }
+
+    fn custom_event(&mut self) -> Option<CustomEvent> {
+        let signature = self.get("EVENT_SIGNATURE")?;
+        match CustomEvent::parse(&signature) {
+            Ok(event) => Some(event),
+            Err(e) => {
+                self.errors.push(format!("Invalid EVENT_SIGNATURE: {e:#}"));
+                None
+            }
         }
+    }
 
-        let contract_address_str = std::env::var("ERC20_CONTRACT_ADDRESS")
-            .context("ERC20_CONTRACT_ADDRESS must be set in .env")?;
+    /// `None` when a required setting is missing or invalid, which is also
+    /// recorded in `errors`
+    fn build(&mut self, overrides: &CliOverrides) -> Option<Config> {
+        let rpc_mode = self.parse_or("RPC_MODE", RpcMode::Live);
+        let tokens_config = self.get("TOKENS_CONFIG").map(PathBuf::from);
+        // A replay never reaches a provider, so it doesn't need one, and the
+        // tokens of TOKENS_CONFIG can bring their own
+        let json_rpc_urls = if (rpc_mode == RpcMode::Replay || tokens_config.is_some())
+            && overrides.rpc_urls.is_empty()
+            && self.get("JSON_RPC_URLS").is_none()
+            && self.get("JSON_RPC_URL").is_none()
+        {
+            Some(Default::default())
+        } else {
+            self.json_rpc_urls(overrides)
+        };
+        // Each token of TOKENS_CONFIG names its own contract
+        let erc20_contract_address = if tokens_config.is_some()
+            && overrides.contract.is_none()
+            && self.get("ERC20_CONTRACT_ADDRESS").is_none()
+        {
+            Some(Address::ZERO)
+        } else {
+            self.erc20_contract_address(overrides)
+        };
 
-        let erc20_contract_address = Address::from_str(&contract_address_str)
-            .context("Invalid ERC20_CONTRACT_ADDRESS format")?;
+        let config = Config {
+            json_rpc_urls: Vec::new(),
+            rpc_rate_limits: HashMap::new(),
+            rpc_headers: HashMap::new(),
+            erc20_contract_address: Address::ZERO,
+            token_standard: self.parse_or("TOKEN_STANDARD", TokenStandard::Erc20),
+            track_deposit_withdrawal: self.parse_or("TRACK_DEPOSIT_WITHDRAWAL", false),
+            custom_event: self.custom_event(),
+            database_url: self
+                .get("DATABASE_URL")
+                .unwrap_or_else(|| "sqlite:./indexer.db".to_string()),
+            deployment_block: self.parse("DEPLOYMENT_BLOCK"),
+            deployment_search_hint: self.parse("DEPLOYMENT_SEARCH_HINT"),
+            token_segments: self.token_segments(),
+            batch_size: self.parse_or("BATCH_SIZE", 1000),
+            min_batch_size: self.parse_or("MIN_BATCH_SIZE", 10),
+            max_batch_size: self.parse_or("MAX_BATCH_SIZE", 10_000),
+            target_logs_per_request: self.parse_or("TARGET_LOGS_PER_REQUEST", 5000),
+            rate_limit_delay_ms: self.parse_or("RATE_LIMIT_DELAY_MS", 500),
+            rpc_requests_per_second: self.parse_or("RPC_REQUESTS_PER_SECOND", 2.0),
+            max_pending_requests: self.parse_or("MAX_PENDING_REQUESTS", 30),
+            parallel_segments: self.parse_or("PARALLEL_SEGMENTS", 1),
+            request_timeout_secs: self.parse_or("REQUEST_TIMEOUT_SECS", 120),
+            light_request_timeout_secs: self.parse_or("LIGHT_REQUEST_TIMEOUT_SECS", 10),
+            rpc_max_retries: self.parse_or("RPC_MAX_RETRIES", 5),
+            rpc_retry_backoff_ms: self.parse_or("RPC_RETRY_BACKOFF_MS", 100),
+            rpc_retry_max_delay_ms: self.parse_or("RPC_RETRY_MAX_DELAY_MS", 10_000),
+            // 32 slots * 12 seconds = 1 epoch
+            finality_update_interval_secs: self.parse_or("FINALITY_UPDATE_INTERVAL_SECS", 384),
+            skip_initial_finality: self.parse_or("SKIP_INITIAL_FINALITY", false),
+            finality_full_refetch: self.parse_or("FINALITY_FULL_REFETCH", false),
+            balance_checkpoint_interval: self.parse_or("BALANCE_CHECKPOINT_INTERVAL", 100_000),
+            // Ethereum mainnet block time
+            block_time_secs: self.parse_or("BLOCK_TIME_SECS", 12),
+            progress_interval_secs: self.parse_or("PROGRESS_INTERVAL_SECS", 30),
+            rpc_stats_interval_secs: self.parse_or("RPC_STATS_INTERVAL_SECS", 900),
+            provider_failure_threshold: self.parse_or("PROVIDER_FAILURE_THRESHOLD", 3),
+            provider_quarantine_secs: self.parse_or("PROVIDER_QUARANTINE_SECS", 60),
+            provider_max_lag_blocks: self.parse_or("PROVIDER_MAX_LAG_BLOCKS", 5),
+            sqlite_busy_timeout_ms: self.parse_or("SQLITE_BUSY_TIMEOUT_MS", 30_000),
+            sqlite_cache_size_kib: self.parse_or("SQLITE_CACHE_SIZE_KIB", 64 * 1024),
+            sqlite_mmap_size: self.parse_or("SQLITE_MMAP_SIZE", 256 * 1024 * 1024),
+            sqlite_read_pool_size: self.parse_or("SQLITE_READ_POOL_SIZE", 4),
+            multi_row_insert_threshold: self.parse_or("MULTI_ROW_INSERT_THRESHOLD", 1000),
+            range_max_attempts: self.parse_or("RANGE_MAX_ATTEMPTS", 5),
+            indexing_log_retention: self.parse_or("INDEXING_LOG_RETENTION", 0),
+            strict_logs: self.parse_or("STRICT_LOGS", true),
+            decode_errors: self.parse_or("DECODE_ERRORS", DecodeErrorPolicy::Fail),
+            watch_addresses: self.watch_addresses(),
+            watch_min_value: self.watch_min_value(),
+            webhook_url: self.get("WEBHOOK_URL"),
+            webhook_max_retries: self.parse_or("WEBHOOK_MAX_RETRIES", 5),
+            webhook_dead_letter_path: self
+                .get("WEBHOOK_DEAD_LETTER_PATH")
+                .unwrap_or_else(|| "./webhook_dead_letter.jsonl".to_string()),
+            log_format: self.parse_or("LOG_FORMAT", LogFormat::Text),
+            // RUST_LOG keeps working for anyone already using it
+            log_filter: self
+                .get("LOG_FILTER")
+                .or_else(|| std::env::var("RUST_LOG").ok())
+                .unwrap_or_else(|| "info".to_string()),
+            mode: self.parse_or("INDEXER_MODE", IndexerMode::Follow),
+            expected_chain_id: self.parse("EXPECTED_CHAIN_ID"),
+            rpc_mode,
+            rpc_fixture_dir: self
+                .get("RPC_FIXTURE_DIR")
+                .unwrap_or_else(|| "./rpc_fixtures".to_string())
+                .into(),
+            tokens_config,
+            token_restart_delay_secs: self.parse_or("TOKEN_RESTART_DELAY_SECS", 30),
+            token_max_restarts: self.parse_or("TOKEN_MAX_RESTARTS", 0),
+        };
 
-        let database_url =
-            std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:./indexer.db".to_string());
+        if config.custom_event.is_some()
+            && (config.token_standard != TokenStandard::Erc20 || config.track_deposit_withdrawal)
+        {
+            self.errors.push(
+                "EVENT_SIGNATURE replaces the transfers, it can't be combined with TOKEN_STANDARD or TRACK_DEPOSIT_WITHDRAWAL"
+                    .to_string(),
+            );
+            return None;
+        }
 
-        Ok(Config {
+        let (json_rpc_urls, rpc_rate_limits, rpc_headers) = json_rpc_urls?;
+        Some(Config {
             json_rpc_urls,
-            erc20_contract_address,
-            database_url,
-            batch_size: std::env::var("BATCH_SIZE")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(1000),
-            rate_limit_delay_ms: std::env::var("RATE_LIMIT_DELAY_MS")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(500),
-            max_pending_requests: std::env::var("MAX_PENDING_REQUESTS")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(30),
-            request_timeout_secs: std::env::var("REQUEST_TIMEOUT_SECS")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(120),
-            finality_update_interval_secs: std::env::var("FINALITY_UPDATE_INTERVAL_SECS")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(384), // 32 slots * 12 seconds = 1 epoch
-            block_time_secs: std::env::var("BLOCK_TIME_SECS")
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(12), // Ethereum mainnet block time
+            rpc_rate_limits,
+            rpc_headers,
+            erc20_contract_address: erc20_contract_address?,
+            ..config
         })
     }
 }
+
+/// Config file values are handled as the equivalent environment variable
+/// string, arrays become comma separated lists
+fn toml_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.value().clone()),
+        Value::Integer(i) => Some(i.value().to_string()),
+        Value::Float(f) => Some(f.value().to_string()),
+        Value::Boolean(b) => Some(b.value().to_string()),
+        Value::Array(array) => array
+            .iter()
+            .map(|v| v.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+            .map(|items| items.join(",")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    const FILE_CONTRACT: Address = Address::repeat_byte(0x11);
+    const CLI_CONTRACT: Address = Address::repeat_byte(0x22);
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        overrides: CliOverrides,
+    }
+
+    /// A config file with the settings the CLI can override
+    fn config_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "eth-indexer-config-{}-{name}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            format!(
+                "erc20_contract_address = \"{FILE_CONTRACT}\"
+json_rpc_url = \"http://file.example\"
+database_url = \"sqlite:file.db\"
+deployment_block = 100
+batch_size = 50
+max_batch_size = 20000
+"
+            ),
+        )
+        .unwrap();
+        path
+    }
+
+    #[test]
+    fn cli_beats_env_beats_file_beats_default() {
+        let path = config_file("precedence");
+        // Only this test sets it, and nothing else here loads a config
+        unsafe { std::env::set_var("BATCH_SIZE", "200") };
+
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.batch_size, 200, "env over file");
+        assert_eq!(config.max_batch_size, 20_000, "file over default");
+        assert_eq!(config.target_logs_per_request, 5000, "default");
+        assert_eq!(config.erc20_contract_address, FILE_CONTRACT);
+        assert_eq!(config.json_rpc_urls, vec!["http://file.example"]);
+        assert_eq!(config.mode, IndexerMode::Follow);
+
+        let cli = Cli::try_parse_from([
+            "indexer",
+            "--contract",
+            &CLI_CONTRACT.to_string(),
+            "--rpc-url",
+            "http://a.example|5",
+            "--rpc-url",
+            "http://b.example",
+            "--database",
+            "sqlite:cli.db",
+            "--batch-size",
+            "300",
+            "--start-block",
+            "7",
+            "--once",
+        ])
+        .unwrap();
+        let config = Config::load_with_cli(Some(&path), cli.overrides).unwrap();
+        unsafe { std::env::remove_var("BATCH_SIZE") };
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(config.batch_size, 300, "cli over env");
+        assert_eq!(config.erc20_contract_address, CLI_CONTRACT);
+        assert_eq!(
+            config.json_rpc_urls,
+            vec!["http://a.example", "http://b.example"]
+        );
+        assert_eq!(config.rpc_rate_limits.get("http://a.example"), Some(&5.0));
+        assert_eq!(config.database_url, "sqlite:cli.db");
+        assert_eq!(config.deployment_block, Some(7));
+        assert_eq!(config.mode, IndexerMode::Once);
+        assert_eq!(config.max_batch_size, 20_000);
+    }
+
+    #[test]
+    fn merge_cli_only_replaces_the_flags_given() {
+        let path = config_file("merge");
+        let mut config = Config::load(Some(&path)).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let loaded = config.clone();
+
+        config.merge_cli(CliOverrides::default());
+        assert_eq!(config.erc20_contract_address, loaded.erc20_contract_address);
+        assert_eq!(config.database_url, "sqlite:file.db");
+        assert_eq!(config.deployment_block, Some(100));
+        assert_eq!(config.batch_size, loaded.batch_size);
+        assert_eq!(config.mode, IndexerMode::Follow);
+
+        config.merge_cli(CliOverrides {
+            database: Some("sqlite:other.db".to_string()),
+            start_block: Some(0),
+            ..Default::default()
+        });
+        assert_eq!(config.database_url, "sqlite:other.db");
+        assert_eq!(config.deployment_block, Some(0));
+        assert_eq!(config.erc20_contract_address, FILE_CONTRACT);
+    }
+
+    #[test]
+    fn invalid_cli_overrides_are_clap_errors() {
+        let error = Cli::try_parse_from(["indexer", "--contract", "0x1234"])
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ValueValidation);
+        assert!(error.to_string().contains("--contract"), "{error}");
+
+        let error = Cli::try_parse_from(["indexer", "--rpc-url", "http://a.example|fast"])
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ValueValidation);
+    }
+}
@@ -1,37 +1,178 @@
-use crate::events::{decimalsCall, nameCall, symbolCall};
-use crate::rpc::RpcClient;
+use crate::config::TokenStandard;
+use crate::events::{bytes32_metadata, bytes32_to_string, decimalsCall, nameCall, symbolCall};
+use crate::repository::DeploymentSearchRepository;
+use crate::rpc::RpcApi;
 use alloy_primitives::Address;
 use anyhow::Result;
-use tracing::{info, warn};
+use std::collections::HashMap;
+use tracing::{debug, info, warn};
 
+/// `eth_getCode` results of a deployment search, keyed by (address, block).
+/// Lookups are also stored in the database so a resumed search doesn't repeat
+/// them.
+struct CodeCache<'a> {
+    search_repo: &'a DeploymentSearchRepository<'a>,
+    has_code: HashMap<(Address, u64), bool>,
+    /// `eth_getCode` requests actually sent
+    requests: usize,
+}
+
+impl<'a> CodeCache<'a> {
+    fn new(search_repo: &'a DeploymentSearchRepository<'a>) -> Self {
+        Self {
+            search_repo,
+            has_code: HashMap::new(),
+            requests: 0,
+        }
+    }
+
+    async fn has_code(
+        &mut self,
+        client: &impl RpcApi,
+        address: Address,
+        block: u64,
+    ) -> Result<bool> {
+        if let Some(&has_code) = self.has_code.get(&(address, block)) {
+            return Ok(has_code);
+        }
+        if let Some(has_code) = self.search_repo.get_code_presence(&address, block)? {
+            self.has_code.insert((address, block), has_code);
+            return Ok(has_code);
+        }
+
+        let has_code = !client.get_code_at_block(address, block).await?.is_empty();
+        self.requests += 1;
+        self.search_repo
+            .save_code_presence(&address, block, has_code)?;
+        self.has_code.insert((address, block), has_code);
+        Ok(has_code)
+    }
+}
+
+/// Deployment block from the creating transaction, when a provider exposes
+/// `ots_getContractCreator`. The block is only trusted once the contract is
+/// seen to have code at it and none at the block before.
+async fn find_by_creator(
+    client: &impl RpcApi,
+    cache: &mut CodeCache<'_>,
+    address: Address,
+) -> Result<Option<u64>> {
+    let Some(creation) = client.get_contract_creator(address).await? else {
+        return Ok(None);
+    };
+    let Some(block) = client.get_transaction_block(creation).await? else {
+        return Ok(None);
+    };
+
+    let deployed_at_block = cache.has_code(client, address, block).await?
+        && (block == 0 || !cache.has_code(client, address, block - 1).await?);
+    if !deployed_at_block {
+        warn!(
+            "ots_getContractCreator points at block {} but the code doesn't appear there, falling back to binary search",
+            block
+        );
+        return Ok(None);
+    }
+    Ok(Some(block))
+}
+
+/// Find the first block where the contract has code. Providers exposing
+/// `ots_getContractCreator` answer in a couple of requests, otherwise this
+/// falls back to a binary search over the code. The remaining
+/// range is stored after every step so an interrupted search picks up where it
+/// left off.
+///
+/// `hints` are blocks likely close to the deployment, such as the deployment
+/// blocks of other tokens from the same factory. They are bisected before the
+/// range itself, so a hint next to the deployment leaves only a short range
+/// to search, while useless ones cost about log2(hints) extra lookups.
 pub async fn find_deployment_block(
-    client: &RpcClient,
+    client: &impl RpcApi,
+    search_repo: &DeploymentSearchRepository<'_>,
     address: Address,
     latest_block: u64,
+    hints: &[u64],
 ) -> Result<u64> {
-    info!("Searching for deployment block of contract {:?}", address);
+    let mut cache = CodeCache::new(search_repo);
 
-    let code = client.get_code_at_block(address, latest_block).await?;
-    if code.is_empty() {
-        anyhow::bail!("Address {:?} is not a deployed contract", address);
-    }
+    let (mut left, mut right) = match search_repo.get_range(&address)? {
+        Some((low, high)) if low <= high && high <= latest_block => {
+            info!(
+                "Resuming deployment block search of contract {:?} in blocks {}-{}",
+                address, low, high
+            );
+            (low, high)
+        }
+        _ => {
+            info!("Searching for deployment block of contract {:?}", address);
 
-    let mut left = 0u64;
-    let mut right = latest_block;
+            if !cache.has_code(client, address, latest_block).await? {
+                anyhow::bail!("Address {:?} is not a deployed contract", address);
+            }
 
-    while left < right {
-        let mid = (left + right) / 2;
+            match find_by_creator(client, &mut cache, address).await {
+                Ok(Some(block)) => {
+                    info!(
+                        "Contract deployed at block {} (found with ots_getContractCreator)",
+                        block
+                    );
+                    return Ok(block);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Contract creator lookup failed, using binary search: {}", e),
+            }
+
+            (0, latest_block)
+        }
+    };
 
-        let code = client.get_code_at_block(address, mid).await?;
+    let mut hints: Vec<u64> = hints
+        .iter()
+        .copied()
+        .filter(|&hint| left <= hint && hint < right)
+        .collect();
+    hints.sort_unstable();
+    hints.dedup();
 
-        if code.is_empty() {
-            left = mid + 1;
+    // Every hint left in [low, high) stays inside the narrowed range
+    let (mut low, mut high) = (0, hints.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        let block = hints[mid];
+
+        if cache.has_code(client, address, block).await? {
+            right = block;
+            high = mid;
         } else {
+            left = block + 1;
+            low = mid + 1;
+        }
+
+        search_repo.save_range(&address, left, right)?;
+    }
+    if !hints.is_empty() {
+        debug!(
+            "Hints narrowed the deployment search to blocks {}-{}",
+            left, right
+        );
+    }
+
+    while left < right {
+        let mid = (left + right) / 2;
+
+        if cache.has_code(client, address, mid).await? {
             right = mid;
+        } else {
+            left = mid + 1;
         }
+
+        search_repo.save_range(&address, left, right)?;
     }
 
-    info!("Contract deployed at block {}", left);
+    info!(
+        "Contract deployed at block {} (found with binary search, {} code lookups)",
+        left, cache.requests
+    );
     Ok(left)
 }
 
@@ -42,42 +183,65 @@ pub struct TokenMetadata {
     pub decimals: Option<u8>,
 }
 
-pub async fn fetch_token_metadata(client: &RpcClient, address: Address) -> Result<TokenMetadata> {
+/// Name, symbol and decimals, each None when the contract doesn't answer.
+/// NFT and multi-token contracts have no `decimals()`, their amounts are whole
+/// units and they get 0.
+pub async fn fetch_token_metadata(
+    client: &impl RpcApi,
+    address: Address,
+    standard: TokenStandard,
+) -> Result<TokenMetadata> {
     info!("Fetching token metadata for {:?}", address);
 
-    // Try to fetch name
+    // Try to fetch name, falling back to the bytes32 variant
     let name = match client.call_contract(address, nameCall {}).await {
-        Ok(result) => {
-            info!("Token name: {}", result);
-            Some(result)
-        }
-        Err(e) => {
-            warn!("Failed to fetch token name: {}", e);
-            None
-        }
+        Ok(result) => Some(result),
+        Err(e) => match client
+            .call_contract(address, bytes32_metadata::nameCall {})
+            .await
+        {
+            Ok(result) => bytes32_to_string(result),
+            Err(_) => {
+                warn!("Failed to fetch token name: {}", e);
+                None
+            }
+        },
     };
+    if let Some(name) = &name {
+        info!("Token name: {}", name);
+    }
 
-    // Try to fetch symbol
+    // Try to fetch symbol, falling back to the bytes32 variant
     let symbol = match client.call_contract(address, symbolCall {}).await {
-        Ok(result) => {
-            info!("Token symbol: {}", result);
-            Some(result)
-        }
-        Err(e) => {
-            warn!("Failed to fetch token symbol: {}", e);
-            None
-        }
+        Ok(result) => Some(result),
+        Err(e) => match client
+            .call_contract(address, bytes32_metadata::symbolCall {})
+            .await
+        {
+            Ok(result) => bytes32_to_string(result),
+            Err(_) => {
+                warn!("Failed to fetch token symbol: {}", e);
+                None
+            }
+        },
     };
+    if let Some(symbol) = &symbol {
+        info!("Token symbol: {}", symbol);
+    }
 
     // Try to fetch decimals
-    let decimals = match client.call_contract(address, decimalsCall {}).await {
-        Ok(result) => {
-            info!("Token decimals: {}", result);
-            Some(result)
-        }
-        Err(e) => {
-            warn!("Failed to fetch token decimals: {}", e);
-            None
+    let decimals = if standard != TokenStandard::Erc20 {
+        Some(0)
+    } else {
+        match client.call_contract(address, decimalsCall {}).await {
+            Ok(result) => {
+                info!("Token decimals: {}", result);
+                Some(result)
+            }
+            Err(e) => {
+                warn!("Failed to fetch token decimals: {}", e);
+                None
+            }
         }
     };
 
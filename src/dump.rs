@@ -0,0 +1,358 @@
+use crate::error::IndexerError;
+use crate::repository::{
+    MultiTokenRepository, ScannedRangeRepository, Token, TokenAmount, TokenRepository, Transfer,
+    TransferRepository, addr_to_db_string,
+};
+use alloy_primitives::{Address, B256, U256};
+use anyhow::{Context, Result};
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use tracing::info;
+
+/// Identifies a transfer dump in its header line
+const DUMP_FORMAT: &str = "eth-indexer-transfers";
+const DUMP_VERSION: u32 = 1;
+
+/// Transfers handed to `insert_batch` at a time during an import
+const IMPORT_CHUNK_SIZE: usize = 10_000;
+
+/// Rows between progress lines
+const PROGRESS_EVERY: u64 = 100_000;
+
+/// First line of a dump. The block range is complete: every transfer of the
+/// token in `[from_block, to_block]` follows, which is what lets an import
+/// move the token's cursor.
+#[derive(Debug, Serialize, Deserialize)]
+struct DumpHeader {
+    format: String,
+    version: u32,
+    token_address: String,
+    deployment_block: u64,
+    from_block: u64,
+    to_block: u64,
+    name: Option<String>,
+    symbol: Option<String>,
+    decimals: Option<u8>,
+}
+
+/// One transfer per line, schema-independent: hashes and addresses as hex,
+/// the value, ERC-721 token id and ERC-1155 amounts as decimal strings
+#[derive(Debug, Serialize, Deserialize)]
+struct DumpRecord {
+    transaction_hash: String,
+    log_index: u64,
+    token_address: String,
+    from: String,
+    to: String,
+    value: String,
+    block_number: u64,
+    block_hash: String,
+    is_finalized: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    token_amounts: Vec<DumpAmount>,
+}
+
+/// An ERC-1155 id and amount of a `DumpRecord`
+#[derive(Debug, Serialize, Deserialize)]
+struct DumpAmount {
+    token_id: String,
+    value: String,
+}
+
+impl DumpRecord {
+    fn from_transfer(transfer: &Transfer) -> Self {
+        Self {
+            transaction_hash: format!("{:?}", transfer.transaction_hash),
+            log_index: transfer.log_index,
+            token_address: addr_to_db_string(&transfer.token_address),
+            from: addr_to_db_string(&transfer.from_address),
+            to: addr_to_db_string(&transfer.to_address),
+            value: transfer.value.to_string(),
+            block_number: transfer.block_number,
+            block_hash: format!("{:?}", transfer.block_hash),
+            is_finalized: transfer.is_finalized,
+            token_id: transfer.token_id.map(|id| id.to_string()),
+            token_amounts: transfer
+                .token_amounts
+                .iter()
+                .map(|amount| DumpAmount {
+                    token_id: amount.token_id.to_string(),
+                    value: amount.value.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn to_transfer(&self) -> Result<Transfer> {
+        Ok(Transfer {
+            transaction_hash: B256::from_str(&self.transaction_hash)
+                .with_context(|| format!("Invalid transaction hash {}", self.transaction_hash))?,
+            log_index: self.log_index,
+            token_address: Address::from_str(&self.token_address)
+                .with_context(|| format!("Invalid token address {}", self.token_address))?,
+            from_address: Address::from_str(&self.from)
+                .with_context(|| format!("Invalid from address {}", self.from))?,
+            to_address: Address::from_str(&self.to)
+                .with_context(|| format!("Invalid to address {}", self.to))?,
+            value: U256::from_str_radix(&self.value, 10)
+                .with_context(|| format!("Invalid value {}", self.value))?,
+            block_number: self.block_number,
+            block_hash: B256::from_str(&self.block_hash)
+                .with_context(|| format!("Invalid block hash {}", self.block_hash))?,
+            is_finalized: self.is_finalized,
+            token_id: self
+                .token_id
+                .as_deref()
+                .map(|id| {
+                    U256::from_str_radix(id, 10).with_context(|| format!("Invalid token id {id}"))
+                })
+                .transpose()?,
+            token_amounts: self
+                .token_amounts
+                .iter()
+                .map(|amount| {
+                    Ok(TokenAmount {
+                        token_id: U256::from_str_radix(&amount.token_id, 10)
+                            .with_context(|| format!("Invalid token id {}", amount.token_id))?,
+                        value: U256::from_str_radix(&amount.value, 10)
+                            .with_context(|| format!("Invalid value {}", amount.value))?,
+                    })
+                })
+                .collect::<Result<_>>()?,
+            event: None,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ExportSummary {
+    pub from_block: u64,
+    pub to_block: u64,
+    pub transfers: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub rows: u64,
+    pub inserted: u64,
+    pub duplicates: u64,
+    pub token_mismatches: u64,
+    /// Block range the dump covers
+    pub from_block: u64,
+    pub to_block: u64,
+    /// The token's last processed block after the import
+    pub last_processed_block: u64,
+    pub cursor_advanced: bool,
+}
+
+/// Write the token's transfers in `block_range` to `output` as JSON Lines, gzip
+/// compressed when the path ends in `.gz`. The range is clamped to the last
+/// processed block, since later blocks may be only partially indexed.
+pub fn export_transfers(
+    conn: &Connection,
+    token_address: &Address,
+    block_range: Option<(u64, u64)>,
+    output: &Path,
+) -> Result<ExportSummary> {
+    let tx = conn.unchecked_transaction()?;
+    let token = TokenRepository::new(&tx)
+        .get_token(token_address)?
+        .ok_or_else(|| anyhow::anyhow!("Token {token_address} has not been indexed"))?;
+    let last_processed = token.last_processed_block.unwrap_or(token.deployment_block);
+
+    let (from_block, to_block) = block_range.unwrap_or((token.deployment_block, last_processed));
+    let to_block = to_block.min(last_processed);
+    if from_block > to_block {
+        anyhow::bail!(
+            "Nothing to export: block {from_block} is past the last processed block {last_processed}"
+        );
+    }
+
+    let header = DumpHeader {
+        format: DUMP_FORMAT.to_string(),
+        version: DUMP_VERSION,
+        token_address: addr_to_db_string(token_address),
+        deployment_block: token.deployment_block,
+        from_block,
+        to_block,
+        name: token.name,
+        symbol: token.symbol,
+        decimals: token.decimals,
+    };
+    let transfer_repo = TransferRepository::new(&tx, token_address);
+    // Only ERC-1155 tokens have amounts to look up per transfer
+    let multi_token_repo = MultiTokenRepository::new(&tx, token_address);
+    let multi_token_repo = multi_token_repo.has_amounts()?.then_some(&multi_token_repo);
+
+    let file =
+        File::create(output).with_context(|| format!("Failed to create {}", output.display()))?;
+    let mut out = BufWriter::new(file);
+
+    let transfers = if is_gzip(output) {
+        let mut encoder = GzEncoder::new(out, Compression::default());
+        let transfers = write_dump(&mut encoder, &header, &transfer_repo, multi_token_repo)?;
+        // Only finish() writes the gzip trailer
+        encoder.finish()?.flush()?;
+        transfers
+    } else {
+        let transfers = write_dump(&mut out, &header, &transfer_repo, multi_token_repo)?;
+        out.flush()?;
+        transfers
+    };
+
+    Ok(ExportSummary {
+        from_block,
+        to_block,
+        transfers,
+    })
+}
+
+/// Insert the transfers of a dump written by `export_transfers`. Rows of other
+/// tokens are counted and skipped, rows already present are skipped by
+/// `insert_batch`, which adds the finalized ones it inserts to the balances. The token's cursor only moves when the dump's range starts
+/// at or before the block after it, so an import never leaves a gap behind it.
+pub fn import_transfers(
+    conn: &Connection,
+    token_address: &Address,
+    input: &Path,
+) -> Result<ImportSummary> {
+    let file = File::open(input).with_context(|| format!("Failed to open {}", input.display()))?;
+    let reader: Box<dyn Read> = if is_gzip(input) {
+        Box::new(MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let mut lines = BufReader::new(reader).lines();
+
+    let header_line = lines
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{} is empty", input.display()))??;
+    let header: DumpHeader = serde_json::from_str(&header_line).context("Invalid dump header")?;
+    if header.format != DUMP_FORMAT || header.version != DUMP_VERSION {
+        anyhow::bail!(
+            "Unsupported dump {} version {}, expected {DUMP_FORMAT} version {DUMP_VERSION}",
+            header.format,
+            header.version
+        );
+    }
+
+    // Transfers reference the token row, so a fresh database gets one first
+    let token_repo = TokenRepository::new(conn);
+    if token_repo.get_token(token_address)?.is_none() {
+        token_repo.insert(&Token {
+            address: *token_address,
+            deployment_block: header.deployment_block,
+            last_processed_block: None,
+            last_processed_finalized_block: None,
+            name: header.name.clone(),
+            symbol: header.symbol.clone(),
+            decimals: header.decimals,
+        })?;
+    }
+
+    let transfer_repo = TransferRepository::new(conn, token_address);
+    let mut summary = ImportSummary {
+        from_block: header.from_block,
+        to_block: header.to_block,
+        ..Default::default()
+    };
+    let mut chunk = Vec::with_capacity(IMPORT_CHUNK_SIZE);
+
+    // Line 1 is the header
+    for (index, line) in lines.enumerate() {
+        let line_number = index + 2;
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record: DumpRecord = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid transfer on line {line_number}"))?;
+        let transfer = record
+            .to_transfer()
+            .with_context(|| format!("Invalid transfer on line {line_number}"))?;
+        if transfer.block_number < header.from_block || transfer.block_number > header.to_block {
+            anyhow::bail!(
+                "Transfer on line {line_number} is in block {}, outside the dump's range {}-{}",
+                transfer.block_number,
+                header.from_block,
+                header.to_block
+            );
+        }
+
+        summary.rows += 1;
+        if transfer.token_address != *token_address {
+            summary.token_mismatches += 1;
+            continue;
+        }
+
+        chunk.push(transfer);
+        if chunk.len() == IMPORT_CHUNK_SIZE {
+            summary.inserted += transfer_repo.insert_batch(&chunk)? as u64;
+            chunk.clear();
+        }
+
+        if summary.rows.is_multiple_of(PROGRESS_EVERY) {
+            info!("Read {} transfers...", summary.rows);
+        }
+    }
+
+    if !chunk.is_empty() {
+        summary.inserted += transfer_repo.insert_batch(&chunk)? as u64;
+    }
+    summary.duplicates = summary.rows - summary.token_mismatches - summary.inserted;
+
+    // The dump holds every transfer of its range, as a scan of it would
+    ScannedRangeRepository::new(conn, token_address)
+        .record(&[(header.from_block, header.to_block)])?;
+
+    let cursor = token_repo
+        .get_last_processed_block(token_address)?
+        .unwrap_or(header.deployment_block);
+    summary.last_processed_block = cursor;
+    if header.from_block <= cursor + 1 && header.to_block > cursor {
+        token_repo.update_last_processed_block(token_address, header.to_block)?;
+        summary.last_processed_block = header.to_block;
+        summary.cursor_advanced = true;
+    }
+
+    Ok(summary)
+}
+
+fn write_dump<W: Write>(
+    out: &mut W,
+    header: &DumpHeader,
+    transfer_repo: &TransferRepository,
+    multi_token_repo: Option<&MultiTokenRepository>,
+) -> Result<u64> {
+    serde_json::to_writer(&mut *out, header)?;
+    writeln!(out)?;
+
+    let count = transfer_repo.iter_transfers_in_range(
+        header.from_block,
+        header.to_block,
+        |mut transfer| {
+            if let Some(multi_token_repo) = multi_token_repo {
+                transfer.token_amounts = multi_token_repo.get_amounts(&transfer)?;
+            }
+            serde_json::to_writer(&mut *out, &DumpRecord::from_transfer(&transfer))
+                .map_err(|e| IndexerError::Other(e.into()))?;
+            writeln!(out).map_err(|e| IndexerError::Other(e.into()))?;
+            Ok(())
+        },
+    )?;
+    Ok(count)
+}
+
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
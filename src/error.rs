@@ -0,0 +1,256 @@
+use alloy_primitives::{Address, U256};
+use std::fmt;
+use std::time::Duration;
+
+/// What the rpc, repository and scanner modules return
+pub type Result<T, E = IndexerError> = std::result::Result<T, E>;
+
+/// Failures an embedding application may want to tell apart, returned by the
+/// rpc, repository and scanner modules. Under `anyhow`, as the binaries and
+/// the query functions use it, `error.downcast_ref::<IndexerError>()` finds
+/// them under any added context.
+#[derive(Debug)]
+pub enum IndexerError {
+    /// A request kept failing on every provider until the retries ran out, or
+    /// was refused in a way retrying can't fix, e.g. an unsupported method
+    Rpc(RpcError),
+    /// The database failed or can't be used as it is
+    Storage(StorageError),
+    /// A block, token or transaction looked up doesn't exist
+    NotFound(String),
+    /// A log in the token's Transfer topic isn't a valid Transfer event
+    Decode {
+        block_number: Option<u64>,
+        log_index: Option<u64>,
+        source: alloy::sol_types::Error,
+    },
+    /// A log in a provider's response lacks a field needed to store it, e.g.
+    /// a null `block_number`. Only raised with `STRICT_LOGS` on.
+    MissingLogField {
+        field: &'static str,
+        /// Position of the log in the response
+        index: usize,
+        rpc_url: String,
+    },
+    /// Stored transfers in `block_number` can't be reconciled with the chain
+    /// yet; the range is retried on a later pass
+    Reorg { block_number: u64, reason: String },
+    /// Finalized transfers would take a stored balance below zero, so some
+    /// were missed or applied out of order. The write is rolled back, and as
+    /// retrying it fails the same way, the run stops.
+    BalanceShortfall {
+        address: Address,
+        /// Block of the transfer the balance first went below zero at
+        block_number: u64,
+        balance: U256,
+        shortfall: U256,
+    },
+    /// Anything else, such as invalid input or a failure reading a file
+    Other(anyhow::Error),
+}
+
+/// How the last attempt of a failed RPC request failed
+#[derive(Debug)]
+pub enum RpcError {
+    /// The provider throttled it: HTTP 429, a rate limit error code or
+    /// message, or exhausted compute units
+    RateLimited(anyhow::Error),
+    /// No response came within the request timeout
+    Timeout(Duration),
+    /// The request or its response was lost on the way, e.g. a refused
+    /// connection, an HTTP error status or a dropped WebSocket
+    Transport(anyhow::Error),
+    /// The provider answered with something the method doesn't return, e.g.
+    /// malformed JSON, a null result or a batch missing its calls
+    BadResponse(anyhow::Error),
+    /// The provider answered with a JSON-RPC error, e.g. an unsupported
+    /// method or too many results
+    ErrorResponse { code: i64, message: String },
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// SQLite couldn't open the database file at `path`
+    Open {
+        path: String,
+        source: rusqlite::Error,
+    },
+    /// SQLite failed to run a statement
+    Sqlite(rusqlite::Error),
+    /// The database can't be used as it is, e.g. its schema is missing or
+    /// from another version
+    Unusable(String),
+}
+
+impl fmt::Display for IndexerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexerError::Rpc(e) => write!(f, "RPC request failed: {e}"),
+            IndexerError::Storage(StorageError::Open { path, .. }) => {
+                write!(f, "Failed to open database {path}")
+            }
+            IndexerError::Storage(StorageError::Sqlite(e)) => write!(f, "{e}"),
+            IndexerError::Storage(StorageError::Unusable(message)) => write!(f, "{message}"),
+            IndexerError::NotFound(message) => write!(f, "{message}"),
+            IndexerError::Decode {
+                block_number: Some(block_number),
+                log_index: Some(log_index),
+                source,
+            } => write!(
+                f,
+                "Failed to decode transfer event at block {block_number} log {log_index}: {source}"
+            ),
+            IndexerError::Decode { source, .. } => {
+                write!(f, "Failed to decode transfer event: {source}")
+            }
+            IndexerError::MissingLogField {
+                field,
+                index,
+                rpc_url,
+            } => write!(
+                f,
+                "Log {index} of the response from {rpc_url} has no {field}; set STRICT_LOGS=false to skip such logs"
+            ),
+            IndexerError::Reorg { reason, .. } => write!(f, "{reason}"),
+            IndexerError::BalanceShortfall {
+                address,
+                block_number,
+                balance,
+                shortfall,
+            } => write!(
+                f,
+                "Balance of {address} would go {shortfall} below zero at block {block_number} (stored balance {balance}); transfers before it were missed, roll back below it with `admin rollback` and re-index"
+            ),
+            IndexerError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::RateLimited(e) => write!(f, "Rate limited: {e}"),
+            RpcError::Timeout(timeout) => {
+                write!(f, "Request timeout after {} seconds", timeout.as_secs())
+            }
+            RpcError::Transport(e) => write!(f, "{e}"),
+            RpcError::BadResponse(e) => write!(f, "Invalid response: {e}"),
+            RpcError::ErrorResponse { code, message } => {
+                write!(f, "Error response {code}: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RpcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            // They show the message of the error they hold
+            RpcError::RateLimited(e) | RpcError::Transport(e) | RpcError::BadResponse(e) => {
+                e.chain().nth(1)
+            }
+            RpcError::Timeout(_) | RpcError::ErrorResponse { .. } => None,
+        }
+    }
+}
+
+impl IndexerError {
+    /// The `IndexerError` in `error` when it was converted to `anyhow` without
+    /// added context, else `Other` holding it
+    pub fn from_anyhow(error: anyhow::Error) -> Self {
+        if !error
+            .chain()
+            .next()
+            .is_some_and(|outer| outer.is::<IndexerError>())
+        {
+            return IndexerError::Other(error);
+        }
+        error.downcast().unwrap_or_else(IndexerError::Other)
+    }
+
+    /// Whether running again fails the same way, so the indexer stops instead
+    /// of retrying or restarting
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            IndexerError::BalanceShortfall { .. } => true,
+            IndexerError::Other(e) => is_fatal(e),
+            _ => false,
+        }
+    }
+}
+
+/// Whether `error` is, or holds under added context, a fatal `IndexerError`
+pub fn is_fatal(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<IndexerError>()
+        .is_some_and(IndexerError::is_fatal)
+}
+
+impl std::error::Error for IndexerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IndexerError::Rpc(e) => Some(e),
+            IndexerError::Decode { source, .. } => Some(source),
+            IndexerError::Storage(StorageError::Open { source, .. }) => Some(source),
+            // Both stand in for the error they hold, whose message they show
+            IndexerError::Storage(StorageError::Sqlite(e)) => e.source(),
+            IndexerError::Other(e) => e.chain().nth(1),
+            IndexerError::Storage(StorageError::Unusable(_))
+            | IndexerError::NotFound(_)
+            | IndexerError::MissingLogField { .. }
+            | IndexerError::Reorg { .. }
+            | IndexerError::BalanceShortfall { .. } => None,
+        }
+    }
+}
+
+impl From<StorageError> for IndexerError {
+    fn from(error: StorageError) -> Self {
+        IndexerError::Storage(error)
+    }
+}
+
+impl From<rusqlite::Error> for IndexerError {
+    fn from(error: rusqlite::Error) -> Self {
+        IndexerError::Storage(StorageError::Sqlite(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn a_typed_error_passed_through_anyhow_comes_back_out() {
+        let error = anyhow::Error::from(IndexerError::NotFound("Block 7 not found".to_string()));
+        assert!(matches!(
+            IndexerError::from_anyhow(error),
+            IndexerError::NotFound(message) if message == "Block 7 not found"
+        ));
+
+        let error = anyhow::Error::from(rusqlite::Error::QueryReturnedNoRows);
+        assert!(matches!(
+            IndexerError::from_anyhow(error),
+            IndexerError::Other(_)
+        ));
+    }
+
+    #[test]
+    fn added_context_is_kept_and_the_typed_error_stays_reachable() {
+        let error = Err::<(), _>(IndexerError::NotFound("Block 7 not found".to_string()))
+            .context("Fetching the fork point failed")
+            .unwrap_err();
+        let error = IndexerError::from_anyhow(error);
+
+        assert!(matches!(error, IndexerError::Other(_)));
+        assert_eq!(error.to_string(), "Fetching the fork point failed");
+        let IndexerError::Other(inner) = &error else {
+            unreachable!()
+        };
+        assert!(matches!(
+            inner.downcast_ref::<IndexerError>(),
+            Some(IndexerError::NotFound(_))
+        ));
+    }
+}
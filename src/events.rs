@@ -1,6 +1,15 @@
+use crate::config::{DecodeErrorPolicy, TokenStandard};
+use crate::error::IndexerError;
+use crate::repository::{DecodeFailureRepository, EventFields, TokenAmount};
+use alloy::dyn_abi::{DynSolValue, EventExt};
+use alloy::json_abi::Event;
 use alloy::rpc::types::Log;
 use alloy::sol;
 use alloy::sol_types::SolEvent;
+use alloy_primitives::{Address, B256, U256};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::warn;
 
 sol! {
     event Transfer(address indexed from, address indexed to, uint256 value);
@@ -9,10 +18,826 @@ sol! {
     function name() external view returns (string memory);
     function symbol() external view returns (string memory);
     function decimals() external view returns (uint8);
+    function balanceOf(address account) external view returns (uint256);
 }
 
+/// Pre-standard tokens (MKR, SAI, ...) whose `name()` and `symbol()` return
+/// `bytes32` instead of `string`
+pub mod bytes32_metadata {
+    use alloy::sol;
+
+    sol! {
+        function name() external view returns (bytes32);
+        function symbol() external view returns (bytes32);
+    }
+}
+
+/// Wrapped ether, which mints on `Deposit` and burns on `Withdrawal` without
+/// emitting a `Transfer`
+pub mod weth {
+    use alloy::sol;
+
+    sol! {
+        event Deposit(address indexed dst, uint256 wad);
+        event Withdrawal(address indexed src, uint256 wad);
+    }
+}
+
+/// NFT Transfer, with the same signature hash as the ERC20 one but `tokenId`
+/// indexed in place of the value
+pub mod erc721 {
+    use alloy::sol;
+
+    sol! {
+        event Transfer(address indexed from, address indexed to, uint256 indexed tokenId);
+    }
+}
+
+/// Multi-token transfers. A batch moves several ids between the same two
+/// addresses in one log.
+pub mod erc1155 {
+    use alloy::sol;
+
+    sol! {
+        event TransferSingle(
+            address indexed operator,
+            address indexed from,
+            address indexed to,
+            uint256 id,
+            uint256 value
+        );
+        event TransferBatch(
+            address indexed operator,
+            address indexed from,
+            address indexed to,
+            uint256[] ids,
+            uint256[] values
+        );
+    }
+}
+
+/// A transfer event of any supported standard, as the indexer stores it
+#[derive(Debug, Clone)]
+pub struct TokenTransfer {
+    pub from: Address,
+    pub to: Address,
+    /// Amount moved: 1 for an NFT, the sum over all ids for ERC-1155
+    pub value: U256,
+    pub token_id: Option<U256>,
+    /// ERC-1155 ids and amounts, in event order
+    pub token_amounts: Vec<TokenAmount>,
+    /// Fields of a custom event, which moves nothing
+    pub event: Option<EventFields>,
+}
+
+/// Event signatures the logs of a contract of `standard` are requested for.
+/// `track_deposit_withdrawal` adds the WETH ones, for ERC-20 only.
+pub fn transfer_topics(standard: TokenStandard, track_deposit_withdrawal: bool) -> Vec<B256> {
+    match standard {
+        TokenStandard::Erc20 if track_deposit_withdrawal => vec![
+            Transfer::SIGNATURE_HASH,
+            weth::Deposit::SIGNATURE_HASH,
+            weth::Withdrawal::SIGNATURE_HASH,
+        ],
+        TokenStandard::Erc20 => vec![Transfer::SIGNATURE_HASH],
+        TokenStandard::Erc721 => vec![erc721::Transfer::SIGNATURE_HASH],
+        TokenStandard::Erc1155 => vec![
+            erc1155::TransferSingle::SIGNATURE_HASH,
+            erc1155::TransferBatch::SIGNATURE_HASH,
+        ],
+    }
+}
+
+/// An event given by its signature in `EVENT_SIGNATURE`, e.g.
+/// `Swap(address indexed sender, uint256 amount0In)`, indexed in place of
+/// transfers. Its logs are decoded at runtime into a JSON object of the
+/// fields by name.
+#[derive(Debug, Clone)]
+pub struct CustomEvent {
+    event: Event,
+}
+
+impl CustomEvent {
+    /// Parse a Solidity event signature, with or without the `event` keyword.
+    /// Anonymous events have no topic to request their logs by and are
+    /// rejected.
+    pub fn parse(signature: &str) -> anyhow::Result<Self> {
+        let event = Event::parse(signature.trim())
+            .map_err(|e| anyhow::anyhow!("Invalid event signature {signature:?}: {e}"))?;
+        if event.anonymous {
+            anyhow::bail!(
+                "Event {} is anonymous, its logs can't be requested",
+                event.name
+            );
+        }
+        Ok(Self { event })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.event.name
+    }
+
+    /// The canonical signature, e.g. `Swap(address,uint256)`
+    pub fn signature(&self) -> String {
+        self.event.signature()
+    }
+
+    /// Hash of the signature, the first topic of its logs
+    pub fn topic(&self) -> B256 {
+        self.event.selector()
+    }
+
+    /// The event's fields as a JSON object, in declaration order. Unnamed
+    /// fields are keyed by their position. Indexed strings, bytes and arrays
+    /// are only in the log as their hash.
+    pub fn decode(&self, log: &Log) -> anyhow::Result<EventFields> {
+        let decoded = self.event.decode_log(log.data()).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to decode log {:?} of block {:?} as {}: {e}",
+                log.log_index,
+                log.block_number,
+                self.event.name
+            )
+        })?;
+
+        let mut indexed = decoded.indexed.into_iter();
+        let mut body = decoded.body.into_iter();
+        let mut fields = serde_json::Map::new();
+        for (position, input) in self.event.inputs.iter().enumerate() {
+            let value = if input.indexed {
+                indexed.next()
+            } else {
+                body.next()
+            };
+            let key = if input.name.is_empty() {
+                position.to_string()
+            } else {
+                input.name.clone()
+            };
+            fields.insert(
+                key,
+                value.map_or(serde_json::Value::Null, |v| json_value(&v)),
+            );
+        }
+
+        Ok(EventFields {
+            name: self.event.name.clone(),
+            fields: serde_json::Value::Object(fields).to_string(),
+        })
+    }
+}
+
+/// JSON for a decoded value. Numbers are decimal strings, as they don't fit
+/// a JSON number, and addresses, hashes and bytes lowercase hex.
+fn json_value(value: &DynSolValue) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        DynSolValue::Bool(b) => Value::Bool(*b),
+        DynSolValue::Int(i, _) => Value::String(i.to_string()),
+        DynSolValue::Uint(u, _) => Value::String(u.to_string()),
+        DynSolValue::FixedBytes(word, size) => Value::String(format!(
+            "0x{}",
+            alloy_primitives::hex::encode(&word[..*size])
+        )),
+        DynSolValue::Address(address) => Value::String(format!("{address:?}")),
+        DynSolValue::Function(function) => Value::String(format!("{function:?}")),
+        DynSolValue::Bytes(bytes) => {
+            Value::String(format!("0x{}", alloy_primitives::hex::encode(bytes)))
+        }
+        DynSolValue::String(s) => Value::String(s.clone()),
+        DynSolValue::Array(values)
+        | DynSolValue::FixedArray(values)
+        | DynSolValue::Tuple(values) => Value::Array(values.iter().map(json_value).collect()),
+    }
+}
+
+/// Decode a `bytes32` string, dropping the trailing zero padding. Returns None
+/// if the value is empty or not valid UTF-8.
+pub fn bytes32_to_string(value: B256) -> Option<String> {
+    let bytes = value.as_slice();
+    let len = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    if len == 0 {
+        return None;
+    }
+
+    String::from_utf8(bytes[..len].to_vec()).ok()
+}
+
+/// Where a log sits on chain, which every stored transfer needs
+#[derive(Debug, Clone, Copy)]
+pub struct LogPosition {
+    pub block_number: u64,
+    pub block_hash: B256,
+    pub transaction_hash: B256,
+    pub log_index: u64,
+}
+
+impl LogPosition {
+    /// Fails with the name of the first missing field. Providers return null
+    /// for them on pending logs, and some do on other edge cases too.
+    pub fn of(log: &Log) -> Result<Self, &'static str> {
+        Ok(Self {
+            block_number: log.block_number.ok_or("block_number")?,
+            block_hash: log.block_hash.ok_or("block_hash")?,
+            transaction_hash: log.transaction_hash.ok_or("transaction_hash")?,
+            log_index: log.log_index.ok_or("log_index")?,
+        })
+    }
+}
+
+/// What to do with logs missing a position field: fail the range when strict,
+/// otherwise skip them and count how many were. Undecodable logs are failed
+/// or skipped by their own policy. Clones share the count.
+#[derive(Debug, Clone)]
+pub struct MalformedLogPolicy {
+    strict: bool,
+    standard: TokenStandard,
+    decode_errors: DecodeErrorPolicy,
+    /// Set when logs are decoded as a custom event instead of transfers
+    custom_event: Option<CustomEvent>,
+    skipped: Arc<AtomicU64>,
+}
+
+impl MalformedLogPolicy {
+    pub fn new(strict: bool, standard: TokenStandard, decode_errors: DecodeErrorPolicy) -> Self {
+        Self {
+            strict,
+            standard,
+            decode_errors,
+            custom_event: None,
+            skipped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Decode logs as `event` instead of the standard's transfers
+    pub fn with_custom_event(mut self, event: Option<CustomEvent>) -> Self {
+        self.custom_event = event;
+        self
+    }
+
+    /// Position of the log at `index` in a response from `rpc_url`, None when
+    /// it is incomplete and gets skipped
+    pub fn position(
+        &self,
+        log: &Log,
+        index: usize,
+        rpc_url: &str,
+    ) -> anyhow::Result<Option<LogPosition>> {
+        match LogPosition::of(log) {
+            Ok(position) => Ok(Some(position)),
+            Err(field) if self.strict => Err(IndexerError::MissingLogField {
+                field,
+                index,
+                rpc_url: rpc_url.to_string(),
+            }
+            .into()),
+            Err(field) => {
+                let skipped = self.skipped.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "Skipping log {} of the response from {} without {} ({} skipped so far)",
+                    index, rpc_url, field, skipped
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// The transfer in a positioned log, decoded as the configured standard's
+    /// event. None when it can't be decoded and the policy is to skip it, after
+    /// recording it in `failures`.
+    pub fn decode(
+        &self,
+        log: &Log,
+        position: &LogPosition,
+        failures: &DecodeFailureRepository,
+    ) -> anyhow::Result<Option<TokenTransfer>> {
+        let decoded = match (&self.custom_event, self.standard) {
+            (Some(event), _) => event.decode(log).map(|fields| TokenTransfer {
+                from: Address::ZERO,
+                to: Address::ZERO,
+                value: U256::ZERO,
+                token_id: None,
+                token_amounts: Vec::new(),
+                event: Some(fields),
+            }),
+            (None, TokenStandard::Erc20) => decode_fungible_transfer_event(log),
+            (None, TokenStandard::Erc721) => {
+                decode_nft_transfer_event(log).map(|event| TokenTransfer {
+                    from: event.from,
+                    to: event.to,
+                    value: U256::from(1),
+                    token_id: Some(event.tokenId),
+                    token_amounts: Vec::new(),
+                    event: None,
+                })
+            }
+            (None, TokenStandard::Erc1155) => decode_multi_token_transfer_event(log),
+        };
+        match decoded {
+            Ok(event) => Ok(Some(event)),
+            Err(e) if self.decode_errors == DecodeErrorPolicy::Fail => Err(e),
+            Err(e) => {
+                let skipped = self.skipped.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "Skipping undecodable log {} of transaction {:?}: {:#} ({} skipped so far)",
+                    position.log_index, position.transaction_hash, e, skipped
+                );
+                failures.record(log, position, &format!("{e:#}"))?;
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+}
+
+/// Decode a Transfer log. Logs that don't match the standard indexed-ness,
+/// such as tokens indexing none or only one of the addresses, or the value
+/// too, are decoded from their topics and data taken together in order.
 pub fn decode_transfer_event(log: &Log) -> anyhow::Result<Transfer> {
     let log_data = log.data();
-    let decoded = Transfer::decode_raw_log(log.topics(), &log_data.data)?;
+    match Transfer::decode_raw_log(log.topics(), &log_data.data) {
+        Ok(decoded) => Ok(decoded),
+        Err(source) => {
+            decode_non_standard_transfer(log).ok_or_else(|| decode_error(log, source).into())
+        }
+    }
+}
+
+/// Decode an ERC-20 Transfer log, or a WETH Deposit or Withdrawal as a mint
+/// from or burn to the zero address. The latter only come in when requested
+/// with `transfer_topics`.
+pub fn decode_fungible_transfer_event(log: &Log) -> anyhow::Result<TokenTransfer> {
+    let log_data = log.data();
+    let (from, to, value) = match log.topic0() {
+        Some(&weth::Deposit::SIGNATURE_HASH) => {
+            let event = weth::Deposit::decode_raw_log(log.topics(), &log_data.data)
+                .map_err(|source| decode_error(log, source))?;
+            (Address::ZERO, event.dst, event.wad)
+        }
+        Some(&weth::Withdrawal::SIGNATURE_HASH) => {
+            let event = weth::Withdrawal::decode_raw_log(log.topics(), &log_data.data)
+                .map_err(|source| decode_error(log, source))?;
+            (event.src, Address::ZERO, event.wad)
+        }
+        _ => {
+            let event = decode_transfer_event(log)?;
+            (event.from, event.to, event.value)
+        }
+    };
+
+    Ok(TokenTransfer {
+        from,
+        to,
+        value,
+        token_id: None,
+        token_amounts: Vec::new(),
+        event: None,
+    })
+}
+
+/// Decode an ERC-721 Transfer log, which has all three parameters indexed
+pub fn decode_nft_transfer_event(log: &Log) -> anyhow::Result<erc721::Transfer> {
+    let log_data = log.data();
+    let decoded = erc721::Transfer::decode_raw_log(log.topics(), &log_data.data)
+        .map_err(|source| decode_error(log, source))?;
     Ok(decoded)
 }
+
+/// Decode an ERC-1155 TransferSingle or TransferBatch log. A batch becomes one
+/// amount per id, in the order the event lists them.
+pub fn decode_multi_token_transfer_event(log: &Log) -> anyhow::Result<TokenTransfer> {
+    let log_data = log.data();
+    let (from, to, token_amounts) = if log.topic0() == Some(&erc1155::TransferBatch::SIGNATURE_HASH)
+    {
+        let event = erc1155::TransferBatch::decode_raw_log(log.topics(), &log_data.data)
+            .map_err(|source| decode_error(log, source))?;
+        if event.ids.len() != event.values.len() {
+            let source = alloy::sol_types::Error::custom(format!(
+                "TransferBatch has {} ids but {} values",
+                event.ids.len(),
+                event.values.len()
+            ));
+            return Err(decode_error(log, source).into());
+        }
+        let amounts = event
+            .ids
+            .iter()
+            .zip(&event.values)
+            .map(|(&token_id, &value)| TokenAmount { token_id, value })
+            .collect();
+        (event.from, event.to, amounts)
+    } else {
+        let event = erc1155::TransferSingle::decode_raw_log(log.topics(), &log_data.data)
+            .map_err(|source| decode_error(log, source))?;
+        let amount = TokenAmount {
+            token_id: event.id,
+            value: event.value,
+        };
+        (event.from, event.to, vec![amount])
+    };
+
+    Ok(TokenTransfer {
+        from,
+        to,
+        value: token_amounts
+            .iter()
+            .fold(U256::ZERO, |sum, amount| sum.saturating_add(amount.value)),
+        token_id: None,
+        token_amounts,
+        event: None,
+    })
+}
+
+fn decode_error(log: &Log, source: alloy::sol_types::Error) -> IndexerError {
+    IndexerError::Decode {
+        block_number: log.block_number,
+        log_index: log.log_index,
+        source,
+    }
+}
+
+/// `from`, `to` and `value` as the three 32-byte words following the event
+/// signature, across the indexed topics and the data
+fn decode_non_standard_transfer(log: &Log) -> Option<Transfer> {
+    let topics = log.topics();
+    let data = &log.data().data;
+    if topics.first() != Some(&Transfer::SIGNATURE_HASH)
+        || data.len() % 32 != 0
+        || topics.len() - 1 + data.len() / 32 != 3
+    {
+        return None;
+    }
+
+    let words: Vec<B256> = topics[1..]
+        .iter()
+        .copied()
+        .chain(data.chunks_exact(32).map(B256::from_slice))
+        .collect();
+    // Addresses are left-padded with zeroes, anything else isn't one
+    let address = |word: &B256| {
+        word[..12]
+            .iter()
+            .all(|&b| b == 0)
+            .then(|| Address::from_word(*word))
+    };
+
+    Some(Transfer {
+        from: address(&words[0])?,
+        to: address(&words[1])?,
+        value: U256::from_be_bytes(words[2].0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrity::check_integrity;
+    use crate::repository::{
+        BalanceRepository, TokenRepository, Transfer as StoredTransfer, TransferRepository,
+    };
+    use crate::testutil::{
+        fresh_database, remove_database, synthetic_transfers, temp_database_path, token_address,
+        transfer_log,
+    };
+    use alloy::sol_types::SolCall;
+    use alloy_primitives::{Bytes, LogData, hex};
+
+    const RPC_URL: &str = "http://provider.example";
+
+    /// Hand-built data of a Transfer declared with no parameter indexed, made
+    /// up of two arbitrary addresses and a value of 1,000,000 rather than
+    /// taken from a chain: `from`, `to` and the value all follow the
+    /// signature topic in the data
+    const UNINDEXED_TRANSFER_DATA: [u8; 96] = hex!(
+        "000000000000000000000000b1690c08e213a35ed9bab7b318de14420fb57d8c"
+        "0000000000000000000000006b0ce0c3e4c3dcd1b6b0c6e7b1cbf2bd0a08d2b1"
+        "00000000000000000000000000000000000000000000000000000000000f4240"
+    );
+    /// The same transfer with only `from` indexed, `to` and the value in the
+    /// data
+    const FROM_INDEXED_TRANSFER_TOPIC: [u8; 32] =
+        hex!("000000000000000000000000b1690c08e213a35ed9bab7b318de14420fb57d8c");
+    const FROM_INDEXED_TRANSFER_DATA: [u8; 64] = hex!(
+        "0000000000000000000000006b0ce0c3e4c3dcd1b6b0c6e7b1cbf2bd0a08d2b1"
+        "00000000000000000000000000000000000000000000000000000000000f4240"
+    );
+    const FIXTURE_FROM: Address = Address::new(hex!("b1690c08e213a35ed9bab7b318de14420fb57d8c"));
+    const FIXTURE_TO: Address = Address::new(hex!("6b0ce0c3e4c3dcd1b6b0c6e7b1cbf2bd0a08d2b1"));
+
+    /// A positioned log of `token_address()` with these topics and data
+    fn raw_log(topics: Vec<B256>, data: &[u8]) -> Log {
+        let mut log = transfer_log(&synthetic_transfers(0..1, 1)[0]);
+        log.inner.data = LogData::new_unchecked(topics, Bytes::copy_from_slice(data));
+        log
+    }
+
+    /// The positioned log of a WETH event, the `log_index`th of its block
+    fn weth_log(event: &impl SolEvent, log_index: u64) -> Log {
+        let data = event.encode_log_data();
+        let mut log = raw_log(data.topics().to_vec(), &data.data);
+        log.log_index = Some(log_index);
+        log
+    }
+
+    /// A transfer log with each position field in turn set to null, named
+    fn logs_missing_a_field() -> Vec<(&'static str, Log)> {
+        let complete = transfer_log(&synthetic_transfers(0..1, 1)[0]);
+        let without = |clear: fn(&mut Log)| {
+            let mut log = complete.clone();
+            clear(&mut log);
+            log
+        };
+        vec![
+            ("block_number", without(|log| log.block_number = None)),
+            ("block_hash", without(|log| log.block_hash = None)),
+            (
+                "transaction_hash",
+                without(|log| log.transaction_hash = None),
+            ),
+            ("log_index", without(|log| log.log_index = None)),
+        ]
+    }
+
+    /// `symbol()` and `name()` of MKR (0x9f8f72aa9304c8b593d555f12ef6589cc3a579a2)
+    const MKR_SYMBOL: [u8; 32] =
+        hex!("4d4b520000000000000000000000000000000000000000000000000000000000");
+    const MKR_NAME: [u8; 32] =
+        hex!("4d616b6572000000000000000000000000000000000000000000000000000000");
+
+    #[test]
+    fn mkr_metadata_decodes_as_bytes32_and_not_as_a_string() {
+        assert!(symbolCall::abi_decode_returns(&MKR_SYMBOL).is_err());
+
+        let symbol = bytes32_metadata::symbolCall::abi_decode_returns(&MKR_SYMBOL).unwrap();
+        assert_eq!(bytes32_to_string(symbol).as_deref(), Some("MKR"));
+        let name = bytes32_metadata::nameCall::abi_decode_returns(&MKR_NAME).unwrap();
+        assert_eq!(bytes32_to_string(name).as_deref(), Some("Maker"));
+    }
+
+    #[test]
+    fn bytes32_strings_drop_only_the_trailing_padding() {
+        let padded = |bytes: &[u8]| {
+            let mut word = [0u8; 32];
+            word[..bytes.len()].copy_from_slice(bytes);
+            B256::from(word)
+        };
+
+        assert_eq!(bytes32_to_string(padded(b"SAI")).as_deref(), Some("SAI"));
+        assert_eq!(
+            bytes32_to_string(B256::from(*b"a full thirty-two byte long name")).as_deref(),
+            Some("a full thirty-two byte long name")
+        );
+        // A zero byte inside the value isn't padding
+        assert_eq!(bytes32_to_string(padded(b"A\0B")).as_deref(), Some("A\0B"));
+        assert_eq!(bytes32_to_string(B256::ZERO), None);
+        // Multi-byte UTF-8 is fine, bytes that aren't UTF-8 give nothing
+        assert_eq!(
+            bytes32_to_string(padded("Ünï".as_bytes())).as_deref(),
+            Some("Ünï")
+        );
+        assert_eq!(bytes32_to_string(padded(&[0x4d, 0xff, 0xfe])), None);
+        assert_eq!(bytes32_to_string(padded(&"Ü".as_bytes()[..1])), None);
+    }
+
+    #[test]
+    fn strict_logs_fail_naming_the_missing_field_its_index_and_provider() {
+        let policy = MalformedLogPolicy::new(true, TokenStandard::Erc20, DecodeErrorPolicy::Fail);
+        for (field, log) in logs_missing_a_field() {
+            assert_eq!(LogPosition::of(&log).unwrap_err(), field);
+
+            let error = policy.position(&log, 7, RPC_URL).unwrap_err();
+            match error.downcast_ref::<IndexerError>() {
+                Some(IndexerError::MissingLogField {
+                    field: missing,
+                    index,
+                    rpc_url,
+                }) => {
+                    assert_eq!(*missing, field);
+                    assert_eq!(*index, 7);
+                    assert_eq!(rpc_url, RPC_URL);
+                }
+                other => panic!("{field}: unexpected {other:?}"),
+            }
+        }
+        assert_eq!(policy.skipped(), 0);
+    }
+
+    #[test]
+    fn lenient_logs_skip_and_count_logs_missing_a_field() {
+        let policy = MalformedLogPolicy::new(false, TokenStandard::Erc20, DecodeErrorPolicy::Fail);
+        let logs = logs_missing_a_field();
+        for (index, (field, log)) in logs.iter().enumerate() {
+            assert!(
+                policy.position(log, index, RPC_URL).unwrap().is_none(),
+                "{field}"
+            );
+        }
+        assert_eq!(policy.skipped(), logs.len() as u64);
+
+        // A complete log is positioned either way
+        let complete = transfer_log(&synthetic_transfers(0..1, 1)[0]);
+        let position = policy.position(&complete, 0, RPC_URL).unwrap().unwrap();
+        assert_eq!(Some(position.block_number), complete.block_number);
+        assert_eq!(policy.skipped(), logs.len() as u64);
+    }
+
+    #[test]
+    fn transfers_with_zero_or_one_indexed_parameters_decode_from_the_data() {
+        let unindexed = raw_log(vec![Transfer::SIGNATURE_HASH], &UNINDEXED_TRANSFER_DATA);
+        let from_indexed = raw_log(
+            vec![
+                Transfer::SIGNATURE_HASH,
+                B256::from(FROM_INDEXED_TRANSFER_TOPIC),
+            ],
+            &FROM_INDEXED_TRANSFER_DATA,
+        );
+
+        for log in [unindexed, from_indexed] {
+            let transfer = decode_transfer_event(&log).unwrap();
+            assert_eq!(transfer.from, FIXTURE_FROM);
+            assert_eq!(transfer.to, FIXTURE_TO);
+            assert_eq!(transfer.value, U256::from(1_000_000));
+        }
+    }
+
+    #[test]
+    fn undecodable_logs_fail_or_are_recorded_by_the_decode_errors_policy() {
+        // Four words after the signature, one too many for any layout
+        let mut data = UNINDEXED_TRANSFER_DATA.to_vec();
+        data.extend_from_slice(&[0u8; 32]);
+        let log = raw_log(vec![Transfer::SIGNATURE_HASH], &data);
+        let position = LogPosition::of(&log).unwrap();
+
+        let path = temp_database_path("decode-failures");
+        let db = fresh_database(&path).unwrap();
+        let failures = DecodeFailureRepository::new(&db.conn, &token_address());
+
+        let fail = MalformedLogPolicy::new(true, TokenStandard::Erc20, DecodeErrorPolicy::Fail);
+        let error = fail.decode(&log, &position, &failures).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<IndexerError>(),
+            Some(IndexerError::Decode { .. })
+        ));
+
+        let skip = MalformedLogPolicy::new(true, TokenStandard::Erc20, DecodeErrorPolicy::Skip);
+        assert!(skip.decode(&log, &position, &failures).unwrap().is_none());
+        assert_eq!(skip.skipped(), 1);
+
+        let (transaction_hash, log_index, topics, stored_data): (String, u64, String, String) = db
+            .conn
+            .query_row(
+                "SELECT transaction_hash, log_index, topics, data FROM decode_failures",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(transaction_hash, format!("{:?}", position.transaction_hash));
+        assert_eq!(log_index, position.log_index);
+        assert_eq!(topics, format!("{:?}", Transfer::SIGNATURE_HASH));
+        assert_eq!(stored_data, hex::encode_prefixed(&data));
+
+        drop(db);
+        remove_database(&path);
+    }
+
+    #[test]
+    fn deposits_decode_as_mints_and_withdrawals_as_burns() {
+        let wad = U256::from(1_000_000);
+        let deposit = weth_log(
+            &weth::Deposit {
+                dst: FIXTURE_TO,
+                wad,
+            },
+            0,
+        );
+        let withdrawal = weth_log(
+            &weth::Withdrawal {
+                src: FIXTURE_FROM,
+                wad,
+            },
+            1,
+        );
+
+        let mint = decode_fungible_transfer_event(&deposit).unwrap();
+        assert_eq!(
+            (mint.from, mint.to, mint.value),
+            (Address::ZERO, FIXTURE_TO, wad)
+        );
+        let burn = decode_fungible_transfer_event(&withdrawal).unwrap();
+        assert_eq!(
+            (burn.from, burn.to, burn.value),
+            (FIXTURE_FROM, Address::ZERO, wad)
+        );
+        assert!(mint.token_id.is_none() && mint.event.is_none());
+    }
+
+    #[test]
+    fn malformed_deposits_and_withdrawals_fail_to_decode() {
+        let wad = B256::from(U256::from(1_000_000));
+        let logs = [
+            // The indexed address missing from the topics
+            raw_log(vec![weth::Deposit::SIGNATURE_HASH], wad.as_slice()),
+            raw_log(vec![weth::Withdrawal::SIGNATURE_HASH], wad.as_slice()),
+            // The amount missing from the data, or cut short
+            raw_log(
+                vec![weth::Deposit::SIGNATURE_HASH, FIXTURE_TO.into_word()],
+                &[],
+            ),
+            raw_log(
+                vec![weth::Withdrawal::SIGNATURE_HASH, FIXTURE_FROM.into_word()],
+                &wad[..16],
+            ),
+        ];
+
+        for log in logs {
+            let error = decode_fungible_transfer_event(&log).unwrap_err();
+            assert!(
+                matches!(
+                    error.downcast_ref::<IndexerError>(),
+                    Some(IndexerError::Decode { .. })
+                ),
+                "{error:#}"
+            );
+        }
+    }
+
+    #[test]
+    fn deposits_and_withdrawals_reconcile_with_the_balances() {
+        let logs = [
+            weth_log(
+                &weth::Deposit {
+                    dst: FIXTURE_TO,
+                    wad: U256::from(700),
+                },
+                0,
+            ),
+            weth_log(
+                &weth::Deposit {
+                    dst: FIXTURE_FROM,
+                    wad: U256::from(500),
+                },
+                1,
+            ),
+            weth_log(
+                &weth::Withdrawal {
+                    src: FIXTURE_TO,
+                    wad: U256::from(300),
+                },
+                2,
+            ),
+        ];
+        let transfers: Vec<StoredTransfer> = logs
+            .iter()
+            .map(|log| {
+                let position = LogPosition::of(log).unwrap();
+                let event = decode_fungible_transfer_event(log).unwrap();
+                StoredTransfer {
+                    transaction_hash: position.transaction_hash,
+                    log_index: position.log_index,
+                    token_address: token_address(),
+                    from_address: event.from,
+                    to_address: event.to,
+                    value: event.value,
+                    block_number: position.block_number,
+                    block_hash: position.block_hash,
+                    is_finalized: true,
+                    token_id: None,
+                    token_amounts: Vec::new(),
+                    event: None,
+                }
+            })
+            .collect();
+
+        let path = temp_database_path("weth-balances");
+        let db = fresh_database(&path).unwrap();
+        // The withdrawal in a batch of its own, so the zero address gains
+        // from it without the deposits to net it against
+        let transfer_repo = TransferRepository::new(&db.conn, &token_address());
+        for batch in [&transfers[..2], &transfers[2..]] {
+            transfer_repo
+                .insert_and_finalize(batch, false, None)
+                .unwrap();
+        }
+        let token_repo = TokenRepository::new(&db.conn);
+        token_repo
+            .update_last_processed_block(&token_address(), 1)
+            .unwrap();
+        token_repo
+            .update_last_processed_finalized_block(&token_address(), 1)
+            .unwrap();
+
+        let balances = BalanceRepository::new(&db.conn, &token_address());
+        let balance = |address| balances.get_balance(&address, true).unwrap().balance;
+        assert_eq!(balance(FIXTURE_TO), U256::from(400));
+        assert_eq!(balance(FIXTURE_FROM), U256::from(500));
+        assert_eq!(balances.get_total_balance().unwrap(), U256::from(900));
+        let problems = check_integrity(&db.conn, &token_address()).unwrap();
+        assert!(problems.is_empty(), "{problems:?}");
+
+        drop(db);
+        remove_database(&path);
+    }
+}
@@ -0,0 +1,629 @@
+use crate::error::{IndexerError, is_fatal};
+use crate::events::MalformedLogPolicy;
+use crate::log_source::LogSource;
+use crate::progress::{PROGRESS_TARGET, format_duration};
+use crate::repository::{
+    BalanceRepository, CheckpointRepository, Database, DecodeFailureRepository, IndexingLogEntry,
+    IndexingLogRepository, IndexingStage, ReadPool, ReorgedBlock, TokenRepository, Transfer,
+    TransferRepository,
+};
+use crate::rpc::{RpcApi, RpcClient};
+use alloy::rpc::types::Log;
+use alloy_primitives::{Address, B256};
+use anyhow::{Context, Result};
+use rusqlite::{Transaction, TransactionBehavior};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{Instant, interval_at};
+use tracing::{error, info};
+
+/// Time between progress lines of a long finality update
+const PROGRESS_EVERY: Duration = Duration::from_secs(30);
+
+/// Rough bytes a balance checkpoint row takes with its primary key entry,
+/// for the size logged next to the time it saves
+const CHECKPOINT_ROW_BYTES: u64 = 250;
+
+/// Re-verifies newly finalized blocks against the chain and marks their
+/// transfers as finalized. Owns its own database connection so it can run
+/// concurrently with the insertion worker.
+pub struct FinalityTracker<C = RpcClient> {
+    client: C,
+    db: Mutex<Database>,
+    /// Reads that don't need the writer, such as the stored hashes to verify
+    readers: Arc<ReadPool>,
+    contract_address: Address,
+    log_source: LogSource,
+    batch_size: u64,
+    finalized_block: Arc<AtomicU64>,
+    malformed_logs: MalformedLogPolicy,
+    /// Re-fetch the logs of every block instead of only the ones whose
+    /// header doesn't match what's stored
+    full_refetch: bool,
+    /// Rows of the indexing log kept, 0 when it isn't written
+    indexing_log_retention: u64,
+    /// Blocks between balance checkpoints, 0 when none are written
+    balance_checkpoint_interval: u64,
+}
+
+/// Logs re-fetched for the blocks `from..=to` of a range being finalized
+struct RefetchedLogs<'a> {
+    from: u64,
+    to: u64,
+    logs: Vec<Log>,
+    rpc_url: &'a str,
+}
+
+impl<C: RpcApi> FinalityTracker<C> {
+    pub fn new(
+        client: C,
+        db: Database,
+        contract_address: Address,
+        log_source: LogSource,
+        batch_size: u64,
+        finalized_block: Arc<AtomicU64>,
+        malformed_logs: MalformedLogPolicy,
+    ) -> Self {
+        Self {
+            client,
+            readers: db.read_pool(),
+            db: Mutex::new(db),
+            contract_address,
+            log_source,
+            batch_size,
+            finalized_block,
+            malformed_logs,
+            full_refetch: false,
+            indexing_log_retention: 0,
+            balance_checkpoint_interval: 0,
+        }
+    }
+
+    /// Re-fetch the logs of every newly finalized block, as older versions did
+    pub fn with_full_refetch(mut self, full_refetch: bool) -> Self {
+        self.full_refetch = full_refetch;
+        self
+    }
+
+    /// Record every finalized chunk in the indexing log, keeping `retention` rows
+    pub fn with_indexing_log(mut self, retention: u64) -> Self {
+        self.indexing_log_retention = retention;
+        self
+    }
+
+    /// Write a balance checkpoint every `interval` blocks behind the finalized block
+    pub fn with_balance_checkpoints(mut self, interval: u64) -> Self {
+        self.balance_checkpoint_interval = interval;
+        self
+    }
+
+    /// Finalize transfers up to min(chain finalized block, `last_processed`),
+    /// re-fetching logs where the canonical headers show a reorg may have
+    /// changed them
+    pub async fn update_finality(&self, last_processed: u64, is_initial: bool) -> Result<()> {
+        let last_finalized = {
+            let db = self.db.lock().unwrap();
+            TokenRepository::new(&db.conn)
+                .get_last_processed_finalized_block(&self.contract_address)?
+                .unwrap_or(0)
+        };
+
+        let current_finalized = self.client.get_finalized_block().await?;
+
+        // Always process blocks only up to min(last_processed, current_finalized)
+        let target_finalized = current_finalized.min(last_processed);
+
+        if target_finalized <= last_finalized {
+            // No new blocks to finalize
+            return Ok(());
+        }
+
+        info!(
+            "Updating finality from block {} to {} (chain finalized: {}, last processed: {})",
+            last_finalized + 1,
+            target_finalized,
+            current_finalized,
+            last_processed
+        );
+
+        let mut current_from = last_finalized + 1;
+        let started = Instant::now();
+        let mut last_report = started;
+        // What the pass cost in requests, against one log request per chunk
+        // at least and the same headers when every block is re-fetched
+        let (mut chunks, mut refetched_blocks) = (0u64, 0u64);
+        let mut requests = PassRequests::default();
+
+        while current_from <= target_finalized {
+            let current_to = (current_from + self.batch_size - 1).min(target_finalized);
+            let chunk_start = Instant::now();
+
+            let (refetched, canonical_hashes) = if self.full_refetch {
+                let (logs, splits, rpc_url) = self
+                    .log_source
+                    .get_logs(&self.client, current_from, current_to)
+                    .await?;
+                let refetched = RefetchedLogs {
+                    from: current_from,
+                    to: current_to,
+                    logs,
+                    rpc_url,
+                };
+                requests.logs += u64::from(splits) + 1;
+                (
+                    Some(refetched),
+                    self.fetch_canonical_hashes(current_from, current_to, &mut requests)
+                        .await?,
+                )
+            } else {
+                self.refetch_inconsistent(current_from, current_to, &mut requests)
+                    .await?
+            };
+            chunks += 1;
+            if let Some(refetched) = &refetched {
+                refetched_blocks += refetched.to - refetched.from + 1;
+            }
+            let (log_count, provider_url) = refetched.as_ref().map_or((0, None), |refetched| {
+                (
+                    refetched.logs.len() as u64,
+                    Some(refetched.rpc_url.to_string()),
+                )
+            });
+
+            let inserted =
+                self.finalize_range(current_from, current_to, refetched, &canonical_hashes)?;
+            // Saved after every chunk, so an interrupted update resumes here
+            self.store_finalized(current_to)?;
+            if self.indexing_log_retention > 0 {
+                self.record_indexing_log(&IndexingLogEntry {
+                    stage: IndexingStage::Finality,
+                    from_block: current_from,
+                    to_block: current_to,
+                    log_count,
+                    inserted_count: inserted as u64,
+                    provider_url,
+                    duration_ms: chunk_start.elapsed().as_millis() as u64,
+                })?;
+            }
+
+            if last_report.elapsed() >= PROGRESS_EVERY && current_to < target_finalized {
+                let done = current_to - last_finalized;
+                let total = target_finalized - last_finalized;
+                let eta = started
+                    .elapsed()
+                    .mul_f64((total - done) as f64 / done as f64);
+                info!(
+                    target: PROGRESS_TARGET,
+                    "Finality update: block {} / {} ({:.1}%), ETA {}",
+                    current_to,
+                    target_finalized,
+                    done as f64 * 100.0 / total as f64,
+                    format_duration(eta)
+                );
+                last_report = Instant::now();
+            }
+
+            current_from = current_to + 1;
+        }
+
+        // For initial update, we can set to current_finalized since no concurrent processes
+        // For runtime updates, only update to target_finalized to avoid race conditions
+        let update_to = if is_initial {
+            current_finalized
+        } else {
+            target_finalized
+        };
+        self.store_finalized(update_to)?;
+        if !self.full_refetch {
+            // A full re-fetch asks for the same headers
+            let full_refetch_requests = chunks + requests.headers;
+            let spent = requests.logs + requests.headers;
+            info!(
+                "Finality update re-fetched the logs of {} of {} blocks in {} requests ({} for logs, {} for headers), saving {} of {}",
+                refetched_blocks,
+                target_finalized - last_finalized,
+                spent,
+                requests.logs,
+                requests.headers,
+                full_refetch_requests.saturating_sub(spent),
+                full_refetch_requests
+            );
+        }
+        info!("Updated last processed finalized block to {}", update_to);
+
+        Ok(())
+    }
+
+    /// Stand-in for the initial update with `SKIP_INITIAL_FINALITY`. With
+    /// nothing stored past the finality cursor it moves to the chain's
+    /// finalized block without fetching anything, the scan marks the
+    /// transfers of finalized blocks itself. Blocks stored past it wait for
+    /// the periodic updates.
+    pub async fn skip_initial_update(&self, last_processed: u64) -> Result<()> {
+        let (last_finalized, deployment_block) = {
+            let db = self.db.lock().unwrap();
+            let token_repo = TokenRepository::new(&db.conn);
+            (
+                token_repo
+                    .get_last_processed_finalized_block(&self.contract_address)?
+                    .unwrap_or(0),
+                token_repo
+                    .get_deployment_block(&self.contract_address)?
+                    .unwrap_or(0),
+            )
+        };
+
+        // Nothing is stored up to the deployment block
+        if last_processed > last_finalized.max(deployment_block) {
+            info!(
+                "Skipping the initial finality update, blocks {}-{} are left to the periodic updates",
+                last_finalized + 1,
+                last_processed
+            );
+            return Ok(());
+        }
+
+        let current_finalized = self.client.get_finalized_block().await?;
+        if current_finalized > last_finalized {
+            self.store_finalized(current_finalized)?;
+            info!(
+                "Skipped the initial finality update, last processed finalized block moved to {}",
+                current_finalized
+            );
+        }
+        Ok(())
+    }
+
+    /// Write the balance checkpoints missing up to the finalized cursor and
+    /// `last_processed`, below which every block is stored. Each is computed
+    /// on a read connection, from the checkpoint before it when that is
+    /// cheaper, and written in a transaction of its own, so an interrupted
+    /// pass resumes at the first one missing. Replaying the transfers of a
+    /// checkpoint takes long, so it runs on a blocking thread.
+    pub async fn write_checkpoints(self: &Arc<Self>, last_processed: u64) -> Result<()>
+    where
+        C: 'static,
+    {
+        let tracker = self.clone();
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            tracker.write_checkpoints_blocking(last_processed)
+        })
+        .await
+        .context("Writing balance checkpoints panicked")?
+    }
+
+    fn write_checkpoints_blocking(&self, last_processed: u64) -> Result<()> {
+        if self.balance_checkpoint_interval == 0 {
+            return Ok(());
+        }
+        let (last_finalized, deployment_block) = {
+            let db = self.db.lock().unwrap();
+            let token_repo = TokenRepository::new(&db.conn);
+            (
+                token_repo
+                    .get_last_processed_finalized_block(&self.contract_address)?
+                    .unwrap_or(0),
+                token_repo
+                    .get_deployment_block(&self.contract_address)?
+                    .unwrap_or(0),
+            )
+        };
+        let missing =
+            CheckpointRepository::new(&*self.readers.blocking_get()?, &self.contract_address)
+                .missing(
+                    self.balance_checkpoint_interval,
+                    deployment_block + 1,
+                    last_finalized.min(last_processed),
+                )?;
+
+        for block in missing {
+            let started = Instant::now();
+            let (snapshot, transfers) = {
+                let conn = self.readers.blocking_get()?;
+                let tx = conn.unchecked_transaction()?;
+                (
+                    BalanceRepository::new(&tx, &self.contract_address)
+                        .balances_at_block(block, false)?,
+                    CheckpointRepository::new(&tx, &self.contract_address)
+                        .count_finalized_up_to(block)?,
+                )
+            };
+
+            {
+                let db = self.db.lock().unwrap();
+                let tx = Transaction::new_unchecked(&db.conn, TransactionBehavior::Immediate)?;
+                let checkpoints = CheckpointRepository::new(&tx, &self.contract_address);
+                // Transfers a coverage repair stored below the block since
+                // would be missing from it, it's computed again next pass
+                if checkpoints.count_finalized_up_to(block)? != transfers {
+                    info!(
+                        "Transfers up to block {} changed while its balance checkpoint was computed, retrying on the next pass",
+                        block
+                    );
+                    return Ok(());
+                }
+                checkpoints.write(&snapshot, transfers)?;
+                tx.commit()?;
+            }
+
+            let holders = snapshot.holders.len() as u64;
+            info!(
+                "Wrote the balance checkpoint at block {}: {} holders, about {} KiB, in {:.1}s from {} transfers. \
+                 Balance queries past it replay the transfers since instead of all {} up to it; \
+                 a smaller BALANCE_CHECKPOINT_INTERVAL trades more of this storage for shorter replays",
+                block,
+                holders,
+                (holders * CHECKPOINT_ROW_BYTES).div_ceil(1024),
+                started.elapsed().as_secs_f64(),
+                snapshot.transfers_applied,
+                transfers
+            );
+        }
+        Ok(())
+    }
+
+    fn store_finalized(&self, block_number: u64) -> Result<()> {
+        {
+            let db = self.db.lock().unwrap();
+            TokenRepository::new(&db.conn)
+                .update_last_processed_finalized_block(&self.contract_address, block_number)?;
+        }
+        self.finalized_block.store(block_number, Ordering::Release);
+        Ok(())
+    }
+
+    fn record_indexing_log(&self, entry: &IndexingLogEntry) -> Result<()> {
+        let db = self.db.lock().unwrap();
+        Ok(IndexingLogRepository::new(&db.conn, &self.contract_address)
+            .record(entry, self.indexing_log_retention)?)
+    }
+
+    /// Check the blocks of a range that have stored transfers against their
+    /// canonical headers and re-fetch the logs of the part that doesn't check
+    /// out. A header commits to its parent, so the blocks up to the last
+    /// stored one before the first mismatch are unchanged; the logs are
+    /// re-fetched from after it to the end of the range. Blocks past the last
+    /// stored one aren't checked, a reorg that only touched blocks where
+    /// nothing was stored is left to the scanner's check at the head.
+    /// Returns those logs, None when every stored block matches, and the
+    /// canonical hashes of the stored blocks.
+    async fn refetch_inconsistent(
+        &self,
+        from: u64,
+        to: u64,
+        requests: &mut PassRequests,
+    ) -> Result<(Option<RefetchedLogs<'_>>, HashMap<u64, B256>)> {
+        let stored_hashes = {
+            let conn = self.readers.get().await?;
+            TransferRepository::new(&conn, &self.contract_address)
+                .get_block_hashes_in_range(from, to)?
+        };
+
+        let mut stored_blocks: Vec<u64> = stored_hashes.keys().copied().collect();
+        stored_blocks.sort_unstable();
+        let headers = self.client.get_block_headers_batch(&stored_blocks).await?;
+        requests.headers += stored_blocks.len() as u64;
+        let canonical_hashes: HashMap<u64, B256> = headers
+            .iter()
+            .map(|header| (header.number, header.hash))
+            .collect();
+
+        let first_changed = stored_blocks
+            .iter()
+            .position(|block| canonical_hashes.get(block) != stored_hashes.get(block));
+        let refetched = match first_changed {
+            Some(index) => {
+                let first = match index {
+                    0 => from,
+                    _ => stored_blocks[index - 1] + 1,
+                };
+                let (logs, splits, rpc_url) =
+                    self.log_source.get_logs(&self.client, first, to).await?;
+                requests.logs += u64::from(splits) + 1;
+                Some(RefetchedLogs {
+                    from: first,
+                    to,
+                    logs,
+                    rpc_url,
+                })
+            }
+            None => None,
+        };
+        Ok((refetched, canonical_hashes))
+    }
+
+    /// Canonical header hash of every block in the range that has stored transfers
+    async fn fetch_canonical_hashes(
+        &self,
+        from: u64,
+        to: u64,
+        requests: &mut PassRequests,
+    ) -> Result<HashMap<u64, B256>> {
+        let stored_blocks: Vec<u64> = {
+            let conn = self.readers.get().await?;
+            TransferRepository::new(&conn, &self.contract_address)
+                .get_block_hashes_in_range(from, to)?
+                .into_keys()
+                .collect()
+        };
+
+        let headers = self.client.get_block_headers_batch(&stored_blocks).await?;
+        requests.headers += stored_blocks.len() as u64;
+        Ok(headers
+            .into_iter()
+            .map(|header| (header.number, header.hash))
+            .collect())
+    }
+
+    /// Compare the re-fetched canonical logs with what's stored for their
+    /// blocks, replace the transfers of any block that differs and mark the
+    /// whole range as finalized. Without re-fetched logs every block matched
+    /// its canonical header and is only marked.
+    ///
+    /// Stored block hashes are checked against the canonical headers, so a
+    /// block whose logs are missing from the response is only treated as
+    /// reorged when its hash really changed. Otherwise the provider missed the
+    /// logs and the range is left for the next pass.
+    ///
+    /// The replaced transfers, the finalized flags and the balance changes
+    /// commit in one transaction. Only the finality cursor is saved separately,
+    /// at the end of the pass. Returns how many transfers were re-inserted.
+    fn finalize_range(
+        &self,
+        current_from: u64,
+        current_to: u64,
+        refetched: Option<RefetchedLogs>,
+        canonical_hashes: &HashMap<u64, B256>,
+    ) -> Result<usize> {
+        let db = self.db.lock().unwrap();
+        let transfer_repo = TransferRepository::new(&db.conn, &self.contract_address);
+
+        let stored_block_hashes = match &refetched {
+            Some(refetched) => {
+                transfer_repo.get_block_hashes_in_range(refetched.from, refetched.to)?
+            }
+            None => HashMap::new(),
+        };
+        let (chain_logs, rpc_url) = refetched.as_ref().map_or((&[][..], ""), |refetched| {
+            (refetched.logs.as_slice(), refetched.rpc_url)
+        });
+
+        let mut chain_block_hashes: HashMap<u64, B256> = HashMap::new();
+        let mut chain_transfers: Vec<Transfer> = Vec::new();
+        let failures = DecodeFailureRepository::new(&db.conn, &self.contract_address);
+
+        for (index, log) in chain_logs.iter().enumerate() {
+            let Some(position) = self.malformed_logs.position(log, index, rpc_url)? else {
+                continue;
+            };
+            let Some(event) = self.malformed_logs.decode(log, &position, &failures)? else {
+                continue;
+            };
+
+            chain_block_hashes.insert(position.block_number, position.block_hash);
+
+            chain_transfers.push(Transfer {
+                transaction_hash: position.transaction_hash,
+                log_index: position.log_index,
+                token_address: self.contract_address,
+                from_address: event.from,
+                to_address: event.to,
+                value: event.value,
+                block_number: position.block_number,
+                block_hash: position.block_hash,
+                is_finalized: true,
+                token_id: event.token_id,
+                token_amounts: event.token_amounts,
+                event: event.event,
+            });
+        }
+
+        for (block_num, stored_hash) in &stored_block_hashes {
+            let Some(canonical_hash) = canonical_hashes.get(block_num) else {
+                continue;
+            };
+            match chain_block_hashes.get(block_num) {
+                Some(chain_hash) if chain_hash != canonical_hash => {
+                    return Err(IndexerError::Reorg {
+                        block_number: *block_num,
+                        reason: format!(
+                            "Logs for block {} came from block {:?} but the canonical block is {:?}, retrying the range later",
+                            block_num, chain_hash, canonical_hash
+                        ),
+                    }
+                    .into());
+                }
+                None if stored_hash == canonical_hash => {
+                    return Err(IndexerError::Reorg {
+                        block_number: *block_num,
+                        reason: format!(
+                            "No logs returned for block {} although its stored transfers are on the canonical chain, retrying the range later",
+                            block_num
+                        ),
+                    }
+                    .into());
+                }
+                _ => {}
+            }
+        }
+
+        // Find blocks that need reprocessing
+        let reorged_blocks = ReorgedBlock::diff(&stored_block_hashes, &chain_block_hashes);
+        let transfers_to_insert = ReorgedBlock::transfers_in(&reorged_blocks, &chain_transfers);
+        let (deleted, inserted, finalized) = transfer_repo.process_finality_batch(
+            &reorged_blocks,
+            &transfers_to_insert,
+            current_from,
+            current_to,
+        )?;
+
+        if deleted > 0 {
+            info!(
+                "Deleted {} transfers from {} reorged blocks",
+                deleted,
+                reorged_blocks.len()
+            );
+        }
+        if inserted > 0 {
+            info!("Inserted {} transfers during finality update", inserted);
+        }
+        if finalized > 0 {
+            info!(
+                "Marked {} transfers as finalized in blocks {}-{}",
+                finalized, current_from, current_to
+            );
+        }
+
+        Ok(inserted)
+    }
+}
+
+/// Requests a finality pass sent, each call of a header batch counted
+#[derive(Default)]
+struct PassRequests {
+    logs: u64,
+    headers: u64,
+}
+
+/// Periodically run finality updates until the insertion worker goes away.
+/// `last_processed_rx` carries the last block the insertion worker committed,
+/// finality never advances past it. A failed update is retried on the next
+/// tick, except a fatal one, which ends the worker with the error.
+pub async fn run_finality_worker<C: RpcApi + 'static>(
+    tracker: Arc<FinalityTracker<C>>,
+    update_interval: Duration,
+    last_processed_rx: watch::Receiver<u64>,
+) -> Result<()> {
+    // The initial update has already run before scanning started
+    let mut update_interval = interval_at(Instant::now() + update_interval, update_interval);
+    update_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        update_interval.tick().await;
+
+        if last_processed_rx.has_changed().is_err() {
+            info!("Insertion worker has stopped, exiting finality worker");
+            break;
+        }
+
+        let last_processed = *last_processed_rx.borrow();
+        if let Err(e) = tracker.update_finality(last_processed, false).await {
+            // Retrying a shortfall fails the same way, so the run stops
+            if is_fatal(&e) {
+                error!("Finality update can't go on, stopping: {:#}", e);
+                return Err(e);
+            }
+            error!("Failed to update finality: {}", e);
+        }
+        if let Err(e) = tracker.write_checkpoints(last_processed).await {
+            error!("Failed to write balance checkpoints: {}", e);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use crate::config::{CliOverrides, Config, RpcMode};
+use crate::repository::{
+    BalanceRepository, Database, PooledConnection, ReadPool, TokenRepository, TransferRepository,
+};
+use crate::rpc::{RecordingRpc, ReplayRpc, RpcApi, RpcClient};
+use crate::scanner::Scanner;
+use alloy_primitives::Address;
+use anyhow::Result;
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+/// Entry point for embedding the indexer in another program. Settings not
+/// given to the builder come from the environment and an optional config file,
+/// the same way the `indexer` binary reads them.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use eth_indexer::Indexer;
+///
+/// let indexer = Indexer::builder()
+///     .rpc_url("https://eth.llamarpc.com")
+///     .contract("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48".parse()?)
+///     .database("sqlite:usdc.db")
+///     .build()?;
+///
+/// let mut handle = indexer.start().await?;
+/// let head = handle.wait_caught_up().await?;
+/// println!("Indexed up to block {head}");
+/// handle.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Indexer {
+    config: Config,
+    db: Database,
+}
+
+#[derive(Default)]
+pub struct IndexerBuilder {
+    config_file: Option<PathBuf>,
+    overrides: CliOverrides,
+}
+
+impl IndexerBuilder {
+    /// TOML config file for the settings the builder doesn't set
+    pub fn config_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.config_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Add an RPC endpoint. Any given here replace JSON_RPC_URLS.
+    pub fn rpc_url(mut self, url: impl Into<String>) -> Self {
+        self.overrides.rpc_urls.push(url.into());
+        self
+    }
+
+    pub fn contract(mut self, address: Address) -> Self {
+        self.overrides.contract = Some(address);
+        self
+    }
+
+    /// A `sqlite:` URL or a file path
+    pub fn database(mut self, url: impl Into<String>) -> Self {
+        self.overrides.database = Some(url.into());
+        self
+    }
+
+    /// Block to start from on a fresh database, skipping the deployment search
+    pub fn start_block(mut self, block: u64) -> Self {
+        self.overrides.start_block = Some(block);
+        self
+    }
+
+    /// Stop once caught up with the chain head instead of following it
+    pub fn once(mut self) -> Self {
+        self.overrides.once = true;
+        self
+    }
+
+    /// Load the configuration and open the database, applying migrations
+    pub fn build(self) -> Result<Indexer> {
+        let config = Config::load_with_cli(self.config_file.as_deref(), self.overrides)?;
+        Indexer::new(config)
+    }
+}
+
+impl Indexer {
+    pub fn builder() -> IndexerBuilder {
+        IndexerBuilder::default()
+    }
+
+    /// An indexer for an already loaded configuration, opening its database
+    /// and applying migrations
+    pub fn new(config: Config) -> Result<Self> {
+        let db = Database::with_options(&config.database_url, config.sqlite_options())?;
+        Ok(Indexer { config, db })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Start indexing in the background on the current tokio runtime, so this
+    /// must be called from inside one. The indexer writes through its own
+    /// connections, so the repositories below stay usable while it runs.
+    /// `RPC_MODE` decides whether it records or replays the RPC responses.
+    pub async fn start(&self) -> Result<IndexerHandle> {
+        let fixture_dir = &self.config.rpc_fixture_dir;
+        if self.config.rpc_mode == RpcMode::Replay {
+            return self.spawn(ReplayRpc::load(fixture_dir)?, None);
+        }
+
+        let client = RpcClient::new(&self.config.json_rpc_urls, &self.config).await?;
+        let health_probe = client.spawn_health_probe();
+        match self.config.rpc_mode {
+            RpcMode::Record => {
+                self.spawn(RecordingRpc::new(client, fixture_dir)?, Some(health_probe))
+            }
+            _ => self.spawn(client, Some(health_probe)),
+        }
+    }
+
+    /// `start` with another way of reaching the chain than the configured
+    /// RPC URLs, e.g. a [`RecordingRpc`](crate::rpc::RecordingRpc) around an
+    /// `RpcClient` or a [`ReplayRpc`](crate::rpc::ReplayRpc)
+    pub async fn start_with_rpc<C: RpcApi + Clone + 'static>(
+        &self,
+        client: C,
+    ) -> Result<IndexerHandle> {
+        self.spawn(client, None)
+    }
+
+    fn spawn<C: RpcApi + Clone + 'static>(
+        &self,
+        client: C,
+        health_probe: Option<JoinHandle<()>>,
+    ) -> Result<IndexerHandle> {
+        let mut scanner = Scanner::new(client, self.db.try_clone()?, &self.config)?;
+        let caught_up = scanner.subscribe_caught_up();
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        // The scanner keeps repositories over the writer connection across
+        // awaits, so its future isn't Send. It gets a blocking thread of its
+        // own, while the workers it spawns still run on this runtime. Its log
+        // lines keep the caller's span, e.g. the supervisor's token tag.
+        let runtime = tokio::runtime::Handle::current();
+        let span = tracing::Span::current();
+        let task = tokio::task::spawn_blocking(move || {
+            let result = runtime.block_on(scanner.run_until_shutdown(shutdown_rx).instrument(span));
+            if let Some(health_probe) = health_probe {
+                health_probe.abort();
+            }
+            result.map_err(anyhow::Error::from)
+        });
+
+        Ok(IndexerHandle {
+            shutdown,
+            caught_up,
+            task,
+        })
+    }
+
+    /// A read-only connection from the pool, for the repositories below. Reads
+    /// through it run alongside the indexer's writes. Blocks while every
+    /// pooled connection is borrowed and panics in an async task, which gets
+    /// one from `read_pool` instead.
+    pub fn reader(&self) -> Result<PooledConnection<'_>> {
+        Ok(self.db.reader()?)
+    }
+
+    /// The reader pool, whose `get` waits for a connection without blocking
+    /// the async task's thread
+    pub fn read_pool(&self) -> Arc<ReadPool> {
+        self.db.read_pool()
+    }
+
+    pub fn transfers<'a>(&self, reader: &'a Connection) -> TransferRepository<'a> {
+        TransferRepository::new(reader, &self.config.erc20_contract_address)
+    }
+
+    /// Balances include finalized transfers only
+    pub fn balances<'a>(&self, reader: &'a Connection) -> BalanceRepository<'a> {
+        BalanceRepository::new(reader, &self.config.erc20_contract_address)
+    }
+
+    pub fn tokens<'a>(&self, reader: &'a Connection) -> TokenRepository<'a> {
+        TokenRepository::new(reader)
+    }
+}
+
+/// A running indexer. Dropping the handle stops it like `shutdown`, without
+/// waiting for it to finish.
+pub struct IndexerHandle {
+    shutdown: watch::Sender<bool>,
+    caught_up: watch::Receiver<Option<u64>>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl IndexerHandle {
+    /// Wait until every transfer up to the chain head is committed and return
+    /// that head. Returns straight away if the indexer already caught up once.
+    pub async fn wait_caught_up(&mut self) -> Result<u64> {
+        match self.caught_up.wait_for(Option::is_some).await {
+            Ok(head) => Ok(head.unwrap_or_default()),
+            Err(_) => anyhow::bail!("The indexer stopped before catching up"),
+        }
+    }
+
+    /// Stop after committing the batches already fetched, and return the
+    /// indexer's result
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.send_replace(true);
+        self.task.await?
+    }
+
+    /// Wait for the indexer to stop on its own, e.g. when built with `once`
+    pub async fn wait(self) -> Result<()> {
+        self.task.await?
+    }
+
+    /// `wait`, or `shutdown` once `stop` turns true or its sender is dropped
+    pub async fn wait_or_shutdown(mut self, mut stop: watch::Receiver<bool>) -> Result<()> {
+        tokio::select! {
+            result = &mut self.task => result?,
+            _ = stop.wait_for(|stop| *stop) => self.shutdown().await,
+        }
+    }
+}
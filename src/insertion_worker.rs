@@ -1,61 +1,333 @@
+use crate::error::is_fatal;
+use crate::notifier::Notifier;
+use crate::progress::{PROGRESS_TARGET, ProgressCounters};
 use crate::repository::{
-    BalanceRepository, Database, TokenRepository, Transfer, TransferRepository,
+    Database, IndexingLogEntry, IndexingLogRepository, IndexingStage, ReorgedBlock,
+    ScannedRangeRepository, TokenRepository, Transfer, TransferRepository,
 };
-use alloy_primitives::Address;
+use alloy_primitives::{Address, B256};
 use anyhow::Result;
-use std::time::Instant;
-use tokio::sync::mpsc;
-use tracing::info;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, error, info};
 
 pub struct TransferBatch {
     pub transfers: Vec<Transfer>,
+    /// Last block of the fetched range the transfers come from
+    pub to_block: u64,
+    /// Every block up to here has been fetched, so the cursor can move here.
+    /// Ranges complete out of order, so this can be below `to_block`.
     pub end_block: u64,
+    /// Already processed blocks that were re-fetched after a reorg. Their
+    /// stored transfers are replaced where they differ from this batch's.
+    pub replace_range: Option<(u64, u64)>,
+    /// Finalized blocks this batch made contiguous. Transfers in them that were
+    /// stored unfinalized while a gap was below are finalized after the insert.
+    pub finalize_range: Option<(u64, u64)>,
+    /// Ranges whose logs the batch holds, for the indexing log and the record
+    /// of scanned ranges
+    pub fetches: Vec<RangeFetch>,
 }
 
+/// A block range whose logs were fetched, and from where
+pub struct RangeFetch {
+    pub from: u64,
+    pub to: u64,
+    pub log_count: u64,
+    pub rpc_url: String,
+    pub elapsed: Duration,
+}
+
+/// Whether fetched batches are written to the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    #[default]
+    Write,
+    /// Count what each batch would write and leave the database alone
+    DryRun,
+}
+
+/// How the insertion worker writes batches
+#[derive(Debug, Clone, Copy)]
+pub struct InsertionSettings {
+    /// Batches at least this large use multi-row insert statements
+    pub multi_row_threshold: usize,
+    /// Rows of the indexing log kept, 0 to not write it
+    pub indexing_log_retention: u64,
+    pub write_mode: WriteMode,
+}
+
+/// Time between the would-be write summaries of a dry run
+const DRY_RUN_SUMMARY_EVERY: Duration = Duration::from_secs(30);
+
+/// What a dry run would have written so far
+struct DryRunSummary {
+    batches: u64,
+    transfers: u64,
+    finalized_transfers: u64,
+    /// Ranges whose stored transfers would be compared and replaced after a reorg
+    replaced_ranges: u64,
+    /// Block the cursor would be at
+    last_block: u64,
+    last_report: Instant,
+}
+
+impl DryRunSummary {
+    fn new(last_block: u64) -> Self {
+        Self {
+            batches: 0,
+            transfers: 0,
+            finalized_transfers: 0,
+            replaced_ranges: 0,
+            last_block,
+            last_report: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, batch: &TransferBatch) {
+        self.batches += 1;
+        self.transfers += batch.transfers.len() as u64;
+        self.finalized_transfers +=
+            batch.transfers.iter().filter(|t| t.is_finalized).count() as u64;
+        self.replaced_ranges += u64::from(batch.replace_range.is_some());
+        self.last_block = batch.end_block;
+
+        if self.last_report.elapsed() >= DRY_RUN_SUMMARY_EVERY {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        info!(
+            target: PROGRESS_TARGET,
+            "Dry run: would have inserted {} transfers ({} finalized) in {} batches, replaced {} reorged ranges and moved the cursor to block {}",
+            self.transfers,
+            self.finalized_transfers,
+            self.batches,
+            self.replaced_ranges,
+            self.last_block
+        );
+        self.last_report = Instant::now();
+    }
+}
+
+/// Insert batches on a dedicated blocking thread that owns a single connection
+/// for the worker's lifetime, so prepared statements stay cached across batches.
+/// A dry run only counts them, with a summary every 30 seconds and at the end.
+/// The first batch that fails ends the worker with its error, which the scan
+/// returns; a fatal one, such as a balance shortfall, isn't restarted.
 pub async fn run_insertion_worker(
     db: Database,
     contract_address: Address,
     mut rx: mpsc::Receiver<TransferBatch>,
+    last_processed_tx: watch::Sender<u64>,
+    progress: Arc<ProgressCounters>,
+    notifier: Option<Notifier>,
+    settings: InsertionSettings,
 ) -> Result<()> {
-    while let Some(batch) = rx.recv().await {
-        let db_clone = db.clone();
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let mut dry_run = DryRunSummary::new(*last_processed_tx.borrow());
+        while let Some(first) = rx.blocking_recv() {
+            let batch = coalesce_queued(first, &mut rx);
+            let end_block = batch.end_block;
+            let transfers = batch.transfers.len() as u64;
+            match settings.write_mode {
+                WriteMode::Write => {
+                    if let Err(e) =
+                        process_batch(&db, contract_address, batch, notifier.as_ref(), settings)
+                    {
+                        // Written again after a restart, it would fail the same way
+                        if is_fatal(&e) {
+                            error!("Insertion can't go on, stopping: {:#}", e);
+                        }
+                        return Err(e);
+                    }
+                }
+                WriteMode::DryRun => dry_run.record(&batch),
+            }
+            progress.record_batch(end_block, transfers);
 
-        // Use spawn_blocking since database operations are blocking
-        tokio::task::spawn_blocking(move || process_batch(db_clone, contract_address, batch))
-            .await??;
+            // Let the finality worker know these blocks are committed
+            last_processed_tx.send_replace(end_block);
+        }
+        if settings.write_mode == WriteMode::DryRun {
+            dry_run.report();
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Merge every batch already waiting in the channel into `first` so they are
+/// written in one insert transaction with a single cursor update. Each batch's
+/// end_block is the scanner's watermark, which only grows, so the merged batch
+/// ends at the highest one and the cursor only moves once all of them are
+/// inserted together.
+fn coalesce_queued(first: TransferBatch, rx: &mut mpsc::Receiver<TransferBatch>) -> TransferBatch {
+    let mut merged = first;
+    let mut coalesced = 1;
+
+    while let Ok(next) = rx.try_recv() {
+        if let Some((from, to)) = next.replace_range {
+            // Transfers queued before the reorg was noticed for the blocks
+            // it re-fetched are from the abandoned fork and were never stored
+            merged
+                .transfers
+                .retain(|t| t.block_number < from || t.block_number > next.to_block);
+            merged.replace_range = Some(match merged.replace_range {
+                Some((merged_from, merged_to)) => (merged_from.min(from), merged_to.max(to)),
+                None => (from, to),
+            });
+        }
+        merged.transfers.extend(next.transfers);
+        merged.fetches.extend(next.fetches);
+        if let Some((from, to)) = next.finalize_range {
+            merged.finalize_range = Some(match merged.finalize_range {
+                Some((merged_from, merged_to)) => (merged_from.min(from), merged_to.max(to)),
+                None => (from, to),
+            });
+        }
+        merged.to_block = merged.to_block.max(next.to_block);
+        merged.end_block = merged.end_block.max(next.end_block);
+        coalesced += 1;
     }
-    Ok(())
+
+    if coalesced > 1 {
+        info!(
+            "Coalesced {} queued batches ({} transfers) up to block {}",
+            coalesced,
+            merged.transfers.len(),
+            merged.end_block
+        );
+    }
+
+    merged
 }
 
-fn process_batch(db: Database, contract_address: Address, batch: TransferBatch) -> Result<()> {
+fn process_batch(
+    db: &Database,
+    contract_address: Address,
+    batch: TransferBatch,
+    notifier: Option<&Notifier>,
+    settings: InsertionSettings,
+) -> Result<()> {
     let start = Instant::now();
+    let mut inserted_count = 0;
+
+    if let Some((from, to)) = batch.replace_range {
+        inserted_count += replace_reorged_blocks(db, contract_address, from, to, &batch.transfers)?;
+    }
 
-    if !batch.transfers.is_empty() {
-        let transfer_repo = TransferRepository::new(&db.conn);
-        let inserted = transfer_repo.insert_batch(&batch.transfers)?;
-        info!("Inserted {} transfers in {:?}", inserted, start.elapsed());
-
-        // Apply incremental balance updates for finalized transfers
-        let finalized_transfers: Vec<&Transfer> =
-            batch.transfers.iter().filter(|t| t.is_finalized).collect();
-
-        if !finalized_transfers.is_empty() {
-            let balance_repo = BalanceRepository::new(&db.conn);
-            // Convert references to owned for the apply_transfers method
-            let transfers_to_apply: Vec<Transfer> =
-                finalized_transfers.into_iter().cloned().collect();
-            balance_repo.apply_transfers(&transfers_to_apply)?;
-            info!(
-                "Applied balance updates for {} finalized transfers",
-                transfers_to_apply.len()
-            );
+    if !batch.transfers.is_empty() || batch.finalize_range.is_some() {
+        let transfer_repo = TransferRepository::new(&db.conn, &contract_address);
+        let inserted = transfer_repo.insert_and_finalize(
+            &batch.transfers,
+            batch.transfers.len() >= settings.multi_row_threshold,
+            batch.finalize_range,
+        )?;
+        inserted_count += inserted;
+        debug!(
+            end_block = batch.end_block,
+            transfer_count = batch.transfers.len(),
+            inserted,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "Inserted transfers"
+        );
+
+        if let Some(notifier) = notifier {
+            notifier.notify(&db.conn, &batch.transfers)?;
         }
     }
 
+    let scanned: Vec<_> = batch
+        .fetches
+        .iter()
+        .map(|fetch| (fetch.from, fetch.to))
+        .collect();
+    ScannedRangeRepository::new(&db.conn, &contract_address).record(&scanned)?;
+
     // Update last processed block after successful insertion
     let token_repo = TokenRepository::new(&db.conn);
     token_repo.update_last_processed_block(&contract_address, batch.end_block)?;
-    info!("Updated last processed block to {}", batch.end_block);
+    token_repo.update_last_inserted_at(&contract_address)?;
+    debug!(block = batch.end_block, "Updated last processed block");
+
+    if settings.indexing_log_retention > 0
+        && let Some(entry) = indexing_log_entry(&batch.fetches, inserted_count, start.elapsed())
+    {
+        IndexingLogRepository::new(&db.conn, &contract_address)
+            .record(&entry, settings.indexing_log_retention)?;
+    }
 
     Ok(())
 }
+
+/// One entry covering every range fetched for a committed batch, timed from
+/// the first request to the commit. None when nothing was fetched.
+fn indexing_log_entry(
+    fetches: &[RangeFetch],
+    inserted_count: usize,
+    commit_time: Duration,
+) -> Option<IndexingLogEntry> {
+    let from_block = fetches.iter().map(|fetch| fetch.from).min()?;
+    let to_block = fetches.iter().map(|fetch| fetch.to).max()?;
+    let providers: BTreeSet<&str> = fetches.iter().map(|fetch| fetch.rpc_url.as_str()).collect();
+    let fetch_time: Duration = fetches.iter().map(|fetch| fetch.elapsed).sum();
+
+    Some(IndexingLogEntry {
+        stage: IndexingStage::Insert,
+        from_block,
+        to_block,
+        log_count: fetches.iter().map(|fetch| fetch.log_count).sum(),
+        inserted_count: inserted_count as u64,
+        provider_url: Some(providers.into_iter().collect::<Vec<_>>().join(", ")),
+        duration_ms: (fetch_time + commit_time).as_millis() as u64,
+    })
+}
+
+/// Compare the stored transfers of re-fetched blocks with the canonical ones and
+/// replace the blocks that differ, recording them in the reorgs table the same
+/// way the finality pass does. Returns how many transfers were inserted.
+fn replace_reorged_blocks(
+    db: &Database,
+    contract_address: Address,
+    from: u64,
+    to: u64,
+    transfers: &[Transfer],
+) -> Result<usize> {
+    let transfer_repo = TransferRepository::new(&db.conn, &contract_address);
+    let stored_block_hashes = transfer_repo.get_block_hashes_in_range(from, to)?;
+
+    let chain_transfers: Vec<Transfer> = transfers
+        .iter()
+        .filter(|t| (from..=to).contains(&t.block_number))
+        .cloned()
+        .collect();
+    let chain_block_hashes: HashMap<u64, B256> = chain_transfers
+        .iter()
+        .map(|t| (t.block_number, t.block_hash))
+        .collect();
+
+    let reorged_blocks = ReorgedBlock::diff(&stored_block_hashes, &chain_block_hashes);
+    if reorged_blocks.is_empty() {
+        return Ok(0);
+    }
+
+    let transfers_to_insert = ReorgedBlock::transfers_in(&reorged_blocks, &chain_transfers);
+    let (deleted, inserted) =
+        transfer_repo.replace_blocks(&reorged_blocks, &transfers_to_insert)?;
+    info!(
+        "Replaced {} reorged blocks in {}-{}: deleted {} transfers, inserted {}",
+        reorged_blocks.len(),
+        from,
+        to,
+        deleted,
+        inserted
+    );
+
+    Ok(inserted)
+}
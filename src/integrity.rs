@@ -0,0 +1,126 @@
+use crate::repository::{BalanceRepository, TokenRepository, TransferRepository};
+use alloy_primitives::{Address, U256};
+use anyhow::Result;
+use rusqlite::Connection;
+use std::collections::HashMap;
+
+/// Something `check_integrity` found wrong with a token's stored data
+#[derive(Debug, Clone)]
+pub struct IntegrityProblem {
+    /// Which check failed, such as `balance_mismatch`
+    pub check: &'static str,
+    /// Holder the problem is about, None for checks over the whole token
+    pub address: Option<Address>,
+    pub detail: String,
+}
+
+impl IntegrityProblem {
+    fn token(check: &'static str, detail: String) -> Self {
+        Self {
+            check,
+            address: None,
+            detail,
+        }
+    }
+}
+
+/// Compare the stored balances with the ones derived from the finalized
+/// transfers and check the transfers against the token's cursors. Nothing is
+/// written; balance problems come first, ordered by address.
+pub fn check_integrity(
+    conn: &Connection,
+    token_address: &Address,
+) -> Result<Vec<IntegrityProblem>> {
+    let mut problems = check_balances(conn, token_address)?;
+
+    let transfer_repo = TransferRepository::new(conn, token_address);
+
+    let duplicates = transfer_repo.count_duplicate_transfers()?;
+    if duplicates > 0 {
+        problems.push(IntegrityProblem::token(
+            "duplicate_transfer",
+            format!("{duplicates} (transaction_hash, log_index) pairs are stored more than once"),
+        ));
+    }
+
+    let Some(token) = TokenRepository::new(conn).get_token(token_address)? else {
+        problems.push(IntegrityProblem::token(
+            "missing_token",
+            "The token has no row in the tokens table".to_string(),
+        ));
+        return Ok(problems);
+    };
+
+    let last_processed = token.last_processed_block.unwrap_or(0);
+    if let (count @ 1.., Some(lowest)) =
+        transfer_repo.count_transfers_above(last_processed, false)?
+    {
+        problems.push(IntegrityProblem::token(
+            "transfer_above_last_processed",
+            format!(
+                "{count} transfers are above the last processed block {last_processed}, the lowest in block {lowest}"
+            ),
+        ));
+    }
+
+    let last_finalized = token.last_processed_finalized_block.unwrap_or(0);
+    if let (count @ 1.., Some(lowest)) =
+        transfer_repo.count_transfers_above(last_finalized, true)?
+    {
+        problems.push(IntegrityProblem::token(
+            "finalized_above_last_finalized",
+            format!(
+                "{count} finalized transfers are above the last finalized block {last_finalized}, the lowest in block {lowest}"
+            ),
+        ));
+    }
+
+    Ok(problems)
+}
+
+fn check_balances(conn: &Connection, token_address: &Address) -> Result<Vec<IntegrityProblem>> {
+    let balance_repo = BalanceRepository::new(conn, token_address);
+    let derived = balance_repo.derive_from_transfers(conn)?;
+
+    let mut stored: HashMap<Address, U256> = HashMap::new();
+    balance_repo.iter_all_holders(|holder| {
+        stored.insert(holder.address, holder.balance);
+        Ok(())
+    })?;
+
+    let mut problems = Vec::new();
+
+    for (address, stored_balance) in &stored {
+        match derived.get(address) {
+            None => problems.push(IntegrityProblem {
+                check: "orphan_balance",
+                address: Some(*address),
+                detail: format!("Stored balance {stored_balance} but no finalized transfers"),
+            }),
+            Some(derived_balance) if derived_balance != stored_balance => {
+                problems.push(IntegrityProblem {
+                    check: "balance_mismatch",
+                    address: Some(*address),
+                    detail: format!(
+                        "Stored balance {stored_balance}, transfers add up to {derived_balance}"
+                    ),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+
+    // Zero balances have no row, so only non-zero derived balances can be missing
+    for (address, derived_balance) in &derived {
+        if !derived_balance.is_zero() && !stored.contains_key(address) {
+            problems.push(IntegrityProblem {
+                check: "balance_mismatch",
+                address: Some(*address),
+                detail: format!("No stored balance, transfers add up to {derived_balance}"),
+            });
+        }
+    }
+
+    problems.sort_by_key(|problem| problem.address);
+    Ok(problems)
+}
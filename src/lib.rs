@@ -1,8 +1,31 @@
+pub mod batch_sizer;
 pub mod config;
 pub mod deployment;
+pub mod dump;
+pub mod error;
 pub mod events;
+pub mod finality_worker;
+pub mod indexer;
 pub mod insertion_worker;
+pub mod integrity;
+pub mod log_source;
+pub mod logging;
+pub mod notifier;
+pub mod progress;
 pub mod query;
+pub mod recent_blocks;
 pub mod repository;
 pub mod rpc;
 pub mod scanner;
+pub mod segments;
+pub mod supervisor;
+pub mod testutil;
+pub mod watermark;
+
+pub use indexer::{Indexer, IndexerBuilder, IndexerHandle};
+pub use query::{
+    AddressHistory, AddressHistoryQuery, BalanceReport, Coverage, DistributionReport,
+    TopHoldersReport, TransferList, TransferQuery, coverage_report, distribution_report,
+    get_balance_at_block_report, get_balance_report, get_sync_status, get_token_id_balance_report,
+    list_address_history, list_transfers, top_holders_report,
+};
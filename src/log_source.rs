@@ -0,0 +1,106 @@
+use crate::config::TokenSegment;
+use crate::rpc::{LogsError, RpcApi};
+use alloy::rpc::types::Log;
+use alloy_primitives::{Address, B256};
+use anyhow::Result;
+
+/// Where a token's logs are fetched from: the contracts that emitted them over
+/// time and the event signatures to request. A token that moved to a new
+/// contract has one segment per contract, all indexed as the same token.
+#[derive(Debug, Clone)]
+pub struct LogSource {
+    segments: Vec<TokenSegment>,
+    topics: Vec<B256>,
+}
+
+impl LogSource {
+    /// A token that only ever had one contract
+    pub fn single(address: Address, topics: Vec<B256>) -> Self {
+        Self {
+            segments: vec![TokenSegment {
+                address,
+                from_block: None,
+                to_block: None,
+            }],
+            topics,
+        }
+    }
+
+    /// Segments in chain order, each ending before the next one starts
+    pub fn segmented(segments: Vec<TokenSegment>, topics: Vec<B256>) -> Result<Self> {
+        if segments.is_empty() {
+            anyhow::bail!("A token needs at least one contract");
+        }
+        for pair in segments.windows(2) {
+            let (previous, next) = (&pair[0], &pair[1]);
+            match (previous.to_block, next.from_block) {
+                (Some(to), Some(from)) if to < from => {}
+                _ => anyhow::bail!(
+                    "Contract {} ({:?}-{:?}) overlaps {} ({:?}-{:?})",
+                    previous.address,
+                    previous.from_block,
+                    previous.to_block,
+                    next.address,
+                    next.from_block,
+                    next.to_block
+                ),
+            }
+        }
+        Ok(Self { segments, topics })
+    }
+
+    pub fn topics(&self) -> &[B256] {
+        &self.topics
+    }
+
+    /// Contract emitting the token's events now, which its metadata is read from
+    pub fn current_contract(&self) -> Address {
+        self.segments[self.segments.len() - 1].address
+    }
+
+    /// Block the first contract starts at, None when it runs from its deployment
+    pub fn start_block(&self) -> Option<u64> {
+        self.segments.first().and_then(|segment| segment.from_block)
+    }
+
+    /// Fetch the logs of `from..=to` from the contracts active in the range,
+    /// keeping only the ones a contract emitted within its own segment.
+    /// Returns the logs, the number of splits and the answering provider, like
+    /// `RpcApi::get_logs_with_splits`.
+    pub async fn get_logs<'c, C: RpcApi>(
+        &self,
+        client: &'c C,
+        from: u64,
+        to: u64,
+    ) -> Result<(Vec<Log>, u32, &'c str), LogsError> {
+        let addresses: Vec<Address> = self
+            .segments
+            .iter()
+            .filter(|segment| {
+                segment.from_block.is_none_or(|start| start <= to)
+                    && segment.to_block.is_none_or(|end| end >= from)
+            })
+            .map(|segment| segment.address)
+            .collect();
+        if addresses.is_empty() {
+            return Ok((Vec::new(), 0, ""));
+        }
+
+        let (mut logs, splits, rpc_url) = client
+            .get_logs_with_splits(from, to, &addresses, &self.topics)
+            .await?;
+        // Logs without a block are left for the malformed log policy
+        logs.retain(|log| {
+            log.block_number
+                .is_none_or(|block| self.emitter_at(block) == Some(log.address()))
+        });
+        Ok((logs, splits, rpc_url))
+    }
+
+    fn emitter_at(&self, block_number: u64) -> Option<Address> {
+        self.segments
+            .iter()
+            .find(|segment| segment.contains(block_number))
+            .map(|segment| segment.address)
+    }
+}
@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+use std::str::FromStr;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line with the event's fields at the top level
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => anyhow::bail!("Unknown log format {s}, expected text or json"),
+        }
+    }
+}
+
+/// Install the global tracing subscriber. `filter` is an `EnvFilter` directive
+/// string such as `info,eth_indexer::scanner=warn`.
+pub fn init_logging(format: LogFormat, filter: &str) -> Result<()> {
+    let filter =
+        EnvFilter::try_new(filter).with_context(|| format!("Invalid log filter {filter}"))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().flatten_event(true).init(),
+    }
+
+    Ok(())
+}
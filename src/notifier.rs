@@ -0,0 +1,279 @@
+use crate::config::Config;
+use crate::repository::{Database, Notification, NotificationRepository, Transfer};
+use alloy::transports::http::reqwest;
+use alloy_primitives::{Address, U256};
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use serde_json::json;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tokio_retry::strategy::{ExponentialBackoff, jitter};
+use tracing::{error, info, warn};
+
+/// Timeout of a single webhook request
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which transfers fire a notification: any transfer from or to a watched
+/// address, and any transfer of at least `min_value`
+#[derive(Debug, Clone)]
+pub struct Watchlist {
+    addresses: HashSet<Address>,
+    min_value: Option<U256>,
+}
+
+impl Watchlist {
+    pub fn new(addresses: &[Address], min_value: Option<U256>) -> Self {
+        Self {
+            addresses: addresses.iter().copied().collect(),
+            min_value,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty() && self.min_value.is_none()
+    }
+
+    pub fn matches(&self, transfer: &Transfer) -> bool {
+        self.addresses.contains(&transfer.from_address)
+            || self.addresses.contains(&transfer.to_address)
+            || self.min_value.is_some_and(|min| transfer.value >= min)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NotifierConfig {
+    pub watchlist: Watchlist,
+    pub webhook_url: String,
+    pub max_retries: usize,
+    pub dead_letter_path: PathBuf,
+}
+
+impl NotifierConfig {
+    /// `None` unless both a watchlist and a webhook URL are configured
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let watchlist = Watchlist::new(&config.watch_addresses, config.watch_min_value);
+
+        match (&config.webhook_url, watchlist.is_empty()) {
+            (Some(url), false) => Some(Self {
+                watchlist,
+                webhook_url: url.clone(),
+                max_retries: config.webhook_max_retries,
+                dead_letter_path: PathBuf::from(&config.webhook_dead_letter_path),
+            }),
+            (Some(_), true) => {
+                warn!(
+                    "WEBHOOK_URL is set without WATCH_ADDRESSES or WATCH_MIN_VALUE, notifications are disabled"
+                );
+                None
+            }
+            (None, false) => {
+                warn!("A watchlist is configured without WEBHOOK_URL, notifications are disabled");
+                None
+            }
+            (None, true) => None,
+        }
+    }
+}
+
+/// Called by the insertion worker for every batch. Records the matching
+/// transfers and queues the ones not notified before for the webhook sender.
+pub struct Notifier {
+    watchlist: Watchlist,
+    contract_address: Address,
+    tx: mpsc::UnboundedSender<Notification>,
+}
+
+impl Notifier {
+    pub fn notify(&self, conn: &Connection, transfers: &[Transfer]) -> Result<()> {
+        let matching: Vec<&Transfer> = transfers
+            .iter()
+            .filter(|transfer| self.watchlist.matches(transfer))
+            .collect();
+        if matching.is_empty() {
+            return Ok(());
+        }
+
+        let recorded =
+            NotificationRepository::new(conn, &self.contract_address).record(&matching)?;
+        if !recorded.is_empty() {
+            info!("Queued {} watchlist notifications", recorded.len());
+        }
+        for notification in recorded {
+            // The sender only goes away at shutdown, the row stays pending and
+            // is sent on the next start
+            let _ = self.tx.send(notification);
+        }
+
+        Ok(())
+    }
+}
+
+/// POSTs notifications to the webhook one at a time, in the order they were
+/// recorded. Owns its own database connection to record delivery.
+pub struct WebhookSender {
+    db: Mutex<Database>,
+    contract_address: Address,
+    client: reqwest::Client,
+    config: NotifierConfig,
+    rx: mpsc::UnboundedReceiver<Notification>,
+}
+
+/// Create the notifier handed to the insertion worker and the sender it feeds.
+/// Notifications left pending by the previous run are queued first.
+pub fn start_notifier(
+    config: NotifierConfig,
+    db: Database,
+    contract_address: Address,
+) -> Result<(Notifier, WebhookSender)> {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let pending = NotificationRepository::new(&db.conn, &contract_address).get_pending()?;
+    if !pending.is_empty() {
+        info!(
+            "Re-sending {} pending watchlist notifications",
+            pending.len()
+        );
+    }
+    for notification in pending {
+        let _ = tx.send(notification);
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .context("Failed to create webhook HTTP client")?;
+
+    let notifier = Notifier {
+        watchlist: config.watchlist.clone(),
+        contract_address,
+        tx,
+    };
+    let sender = WebhookSender {
+        db: Mutex::new(db),
+        contract_address,
+        client,
+        config,
+        rx,
+    };
+
+    Ok((notifier, sender))
+}
+
+impl WebhookSender {
+    pub async fn run(mut self) {
+        while let Some(notification) = self.rx.recv().await {
+            self.deliver(&notification).await;
+        }
+    }
+
+    /// Retry with exponential back-off, then give up into the dead-letter log.
+    /// Either way the outcome is recorded so the notification isn't sent again.
+    async fn deliver(&self, notification: &Notification) {
+        let payload = self.payload(notification);
+        let mut delays = ExponentialBackoff::from_millis(500)
+            .factor(2)
+            .max_delay(Duration::from_secs(60))
+            .map(jitter)
+            .take(self.config.max_retries);
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+            let error = match self.post(&payload).await {
+                Ok(()) => {
+                    if let Err(e) =
+                        self.with_repo(|repo| Ok(repo.mark_sent(notification, attempts)?))
+                    {
+                        error!("Failed to record sent notification: {e}");
+                    }
+                    return;
+                }
+                Err(e) => e,
+            };
+
+            match delays.next() {
+                Some(delay) => {
+                    warn!(
+                        "Webhook delivery for {:?} failed (attempt {attempts}), retrying in {delay:?}: {error:#}",
+                        notification.transaction_hash
+                    );
+                    sleep(delay).await;
+                }
+                None => {
+                    error!(
+                        "Webhook delivery for {:?} failed after {attempts} attempts, writing it to {}: {error:#}",
+                        notification.transaction_hash,
+                        self.config.dead_letter_path.display()
+                    );
+                    if let Err(e) = self.dead_letter(&payload, attempts, &error) {
+                        error!("Failed to write the dead-letter log: {e:#}");
+                    }
+                    if let Err(e) =
+                        self.with_repo(|repo| Ok(repo.mark_failed(notification, attempts)?))
+                    {
+                        error!("Failed to record failed notification: {e}");
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn with_repo<T>(&self, f: impl FnOnce(&NotificationRepository) -> Result<T>) -> Result<T> {
+        let db = self.db.lock().unwrap();
+        f(&NotificationRepository::new(
+            &db.conn,
+            &self.contract_address,
+        ))
+    }
+
+    async fn post(&self, payload: &serde_json::Value) -> Result<()> {
+        self.client
+            .post(&self.config.webhook_url)
+            .header("content-type", "application/json")
+            .body(payload.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn payload(&self, notification: &Notification) -> serde_json::Value {
+        json!({
+            "token_address": format!("{:?}", self.contract_address),
+            "transaction_hash": format!("{:?}", notification.transaction_hash),
+            "log_index": notification.log_index,
+            "from": format!("{:?}", notification.from_address),
+            "to": format!("{:?}", notification.to_address),
+            "value": notification.value.to_string(),
+            "block_number": notification.block_number,
+            "is_finalized": notification.is_finalized,
+        })
+    }
+
+    /// Append the undeliverable payload as one JSON line
+    fn dead_letter(
+        &self,
+        payload: &serde_json::Value,
+        attempts: u32,
+        error: &anyhow::Error,
+    ) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.dead_letter_path)?;
+        let entry = json!({
+            "payload": payload,
+            "attempts": attempts,
+            "error": format!("{error:#}"),
+        });
+        writeln!(file, "{entry}")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Log target of the periodic summary, kept when per-batch logs are silenced
+pub const PROGRESS_TARGET: &str = "progress";
+
+/// Counters the insertion worker updates as batches are committed
+#[derive(Debug)]
+pub struct ProgressCounters {
+    /// Last block committed to the database
+    pub indexed_block: AtomicU64,
+    /// Transfers inserted since startup
+    pub transfers_inserted: AtomicU64,
+}
+
+impl ProgressCounters {
+    pub fn new(indexed_block: u64) -> Self {
+        Self {
+            indexed_block: AtomicU64::new(indexed_block),
+            transfers_inserted: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_batch(&self, end_block: u64, transfers: u64) {
+        self.indexed_block.store(end_block, Ordering::Release);
+        self.transfers_inserted
+            .fetch_add(transfers, Ordering::Relaxed);
+    }
+}
+
+/// Turns the shared counters into a periodic summary, with rates measured over
+/// the time since the previous report
+pub struct ProgressReporter {
+    counters: Arc<ProgressCounters>,
+    last_block: u64,
+    last_transfers: u64,
+    last_report: Instant,
+}
+
+impl ProgressReporter {
+    pub fn new(counters: Arc<ProgressCounters>) -> Self {
+        let last_block = counters.indexed_block.load(Ordering::Acquire);
+        let last_transfers = counters.transfers_inserted.load(Ordering::Relaxed);
+        Self {
+            counters,
+            last_block,
+            last_transfers,
+            last_report: Instant::now(),
+        }
+    }
+
+    pub fn report(&mut self, latest_block: u64, queue_depth: usize, batch_size: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_report).as_secs_f64();
+        let block = self.counters.indexed_block.load(Ordering::Acquire);
+        let transfers = self.counters.transfers_inserted.load(Ordering::Relaxed);
+
+        let (blocks_per_sec, transfers_per_sec) = if elapsed > 0.0 {
+            (
+                block.saturating_sub(self.last_block) as f64 / elapsed,
+                transfers.saturating_sub(self.last_transfers) as f64 / elapsed,
+            )
+        } else {
+            (0.0, 0.0)
+        };
+
+        let remaining = latest_block.saturating_sub(block);
+        let eta = if remaining == 0 {
+            "caught up".to_string()
+        } else if blocks_per_sec > 0.0 {
+            format_duration(Duration::from_secs_f64(remaining as f64 / blocks_per_sec))
+        } else {
+            "unknown".to_string()
+        };
+
+        info!(
+            target: PROGRESS_TARGET,
+            "Progress: block {} / {} ({} behind), {:.1} blocks/s, {:.1} transfers/s, ETA {}, {} batches queued, batch size {}",
+            block,
+            latest_block,
+            remaining,
+            blocks_per_sec,
+            transfers_per_sec,
+            eta,
+            queue_depth,
+            batch_size
+        );
+
+        self.last_block = block;
+        self.last_transfers = transfers;
+        self.last_report = now;
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, seconds) = (
+        secs / 86_400,
+        secs % 86_400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    );
+
+    if days > 0 {
+        format!("{days}d {hours}h {minutes}m")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else if minutes > 0 {
+        format!("{minutes}m {seconds}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
@@ -1,136 +1,1925 @@
+use crate::config::{Config, TokenConfig};
+use crate::error::IndexerError;
+use crate::integrity::check_integrity;
+use crate::query::export::{ExportFormat, export_holders, export_snapshot};
 use crate::query::formatters::{
-    OutputFormat, format_balance, format_stats, format_top_holders, format_transfers,
+    AddressHistoryCsvWriter, AddressLabels, BalanceComparison, Coverage, FormatOptions,
+    OutputFormat, RunningBalance, SyncStatus, TokenListing, TransferCsvWriter,
+    address_history_entry_to_json, format_address_history, format_balance,
+    format_balance_comparison, format_block_summary, format_counterparties, format_coverage,
+    format_distribution, format_events, format_indexing_log, format_integrity_problems,
+    format_nft_owners, format_notifications, format_reorgs, format_stats, format_sync_status,
+    format_token_info, format_token_listing, format_top_holders, format_transfers,
+    format_tx_transfers, format_volume, transfer_to_json,
 };
-use crate::repository::{BalanceRepository, TokenRepository, TransferRepository};
-use alloy_primitives::Address;
+use crate::repository::{
+    AddressHistoryEntry, BalanceInfo, BalanceRepository, Database, Distribution, EventFilter,
+    EventRepository, IndexingLogRepository, LabelImport, LabelRepository, MultiTokenRepository,
+    NftRepository, NotificationRepository, ReorgRepository, ScannedRangeRepository,
+    SnapshotDirection, TokenHolder, TokenRepository, TransferFilter, TransferRepository,
+    TransferView,
+};
+use alloy_primitives::utils::parse_units;
+use alloy_primitives::{Address, B256, I256, U256};
 use anyhow::Result;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
 use std::str::FromStr;
 
+/// Row count above which table output warns that it is rendered in memory
+const TABLE_ROW_WARNING_THRESHOLD: usize = 10_000;
+
+/// Streamed transfers whose labels are looked up together
+const LABEL_BATCH: usize = 500;
+
+/// Parse an address typed by the user. All-lowercase and all-uppercase hex are
+/// accepted as is, mixed case must be a valid EIP-55 checksum so a typo in a
+/// checksummed address isn't silently looked up as a different account.
+pub fn parse_address(input: &str) -> Result<Address> {
+    let hex = input
+        .strip_prefix("0x")
+        .or_else(|| input.strip_prefix("0X"))
+        .unwrap_or(input);
+
+    let is_single_case = !hex.chars().any(|c| c.is_ascii_lowercase())
+        || !hex.chars().any(|c| c.is_ascii_uppercase());
+
+    if is_single_case {
+        Address::from_str(hex).map_err(|_| anyhow::anyhow!("Invalid address format: {}", input))
+    } else {
+        Address::parse_checksummed(format!("0x{hex}"), None)
+            .map_err(|_| anyhow::anyhow!("Invalid address checksum: {}", input))
+    }
+}
+
+/// Balance of an address with the token's decimals
+#[derive(Debug)]
+pub struct BalanceReport {
+    pub address: Address,
+    pub info: BalanceInfo,
+    /// None when the token doesn't report decimals
+    pub decimals: Option<u8>,
+}
+
+pub fn get_balance_report(
+    balance_repo: &BalanceRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    address: &str,
+    finalized: bool,
+) -> Result<BalanceReport> {
+    let address = parse_address(address)?;
+
+    Ok(BalanceReport {
+        address,
+        info: balance_repo.get_balance(&address, finalized)?,
+        decimals: token_repo.get_token_decimals(token_address)?,
+    })
+}
+
 pub fn cmd_balance(
     balance_repo: &BalanceRepository,
     token_repo: &TokenRepository,
     token_address: &Address,
     address: &str,
+    finalized: bool,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let report = get_balance_report(balance_repo, token_repo, token_address, address, finalized)?;
+    let output = format_balance(report.info, report.decimals, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+/// Balance of an address at the end of `block` and its activity up to it,
+/// finalized or not. Fails for a block the indexer hasn't processed yet.
+pub fn get_balance_at_block_report(
+    balance_repo: &BalanceRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    address: &str,
+    block: u64,
+) -> Result<BalanceReport> {
+    let address = parse_address(address)?;
+    let Some(last_processed) = token_repo.get_last_processed_block(token_address)? else {
+        anyhow::bail!("Token {token_address:?} has not been indexed yet");
+    };
+    if block > last_processed {
+        anyhow::bail!("Block {block} is past the last processed block {last_processed}");
+    }
+
+    Ok(BalanceReport {
+        address,
+        info: balance_repo.get_balance_at(&address, block)?,
+        decimals: token_repo.get_token_decimals(token_address)?,
+    })
+}
+
+pub fn cmd_balance_at_block(
+    balance_repo: &BalanceRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    address: &str,
+    block: u64,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let report =
+        get_balance_at_block_report(balance_repo, token_repo, token_address, address, block)?;
+    let output = format_balance(report.info, report.decimals, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+/// ERC-1155 balance of one token id and the transfers of that id the address
+/// took part in. ERC-1155 amounts have no decimals.
+pub fn get_token_id_balance_report(
+    multi_token_repo: &MultiTokenRepository,
+    address: &str,
+    token_id: &str,
+    finalized: bool,
+) -> Result<BalanceReport> {
+    let address = parse_address(address)?;
+    let token_id = parse_token_id(token_id)?;
+
+    Ok(BalanceReport {
+        address,
+        info: multi_token_repo.get_balance(&address, &token_id, finalized)?,
+        decimals: Some(0),
+    })
+}
+
+pub fn cmd_token_id_balance(
+    multi_token_repo: &MultiTokenRepository,
+    address: &str,
+    token_id: &str,
+    finalized: bool,
     format: &OutputFormat,
+    out: &mut dyn Write,
 ) -> Result<()> {
-    let address = Address::from_str(address)
-        .map_err(|_| anyhow::anyhow!("Invalid address format: {}", address))?;
+    let report = get_token_id_balance_report(multi_token_repo, address, token_id, finalized)?;
+    let output = format_balance(report.info, report.decimals, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+/// A `balanceOf` result read from the chain
+pub struct OnChainBalance {
+    pub address: Address,
+    pub block_number: u64,
+    pub balance: U256,
+}
 
-    let balance_info = balance_repo.get_balance(&address)?;
+/// Print an on-chain balance next to the indexed one at the same block. The
+/// indexed side is N/A until the indexer has processed that block.
+pub fn cmd_balance_of(
+    balance_repo: &BalanceRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    on_chain: OnChainBalance,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let indexed = match token_repo.get_last_processed_block(token_address)? {
+        Some(last) if last >= on_chain.block_number => {
+            Some(balance_repo.get_balance_at_block(&on_chain.address, on_chain.block_number)?)
+        }
+        _ => None,
+    };
+
+    let comparison = BalanceComparison {
+        address: format!("{:?}", on_chain.address),
+        block_number: on_chain.block_number,
+        on_chain: on_chain.balance,
+        indexed,
+    };
     let decimals = token_repo.get_token_decimals(token_address)?;
-    let output = format_balance(balance_info, decimals, format);
-    println!("{output}");
+    writeln!(
+        out,
+        "{}",
+        format_balance_comparison(&comparison, decimals, format)
+    )?;
 
     Ok(())
 }
 
-#[derive(Default)]
+/// Page size of transfer listings when none is given
+pub const DEFAULT_TRANSFER_LIMIT: usize = 100;
+
+/// Filters and page of a transfer listing, as typed by the user. At least one
+/// of `from`, `to`, `involving`, `block`, `block_range` or `token_id` must be
+/// set; the exclusions only narrow those down. Set filters are combined with
+/// AND.
 pub struct TransferQuery {
     pub from: Option<String>,
     pub to: Option<String>,
+    /// Transfers with this address on either side
+    pub involving: Option<String>,
+    pub exclude_from: Vec<String>,
+    pub exclude_to: Vec<String>,
     pub block: Option<u64>,
     pub block_range: Option<(u64, u64)>,
+    pub token_id: Option<String>,
     pub finalized: bool,
     pub limit: usize,
     pub offset: usize,
 }
 
+impl Default for TransferQuery {
+    fn default() -> Self {
+        Self {
+            from: None,
+            to: None,
+            involving: None,
+            exclude_from: Vec::new(),
+            exclude_to: Vec::new(),
+            block: None,
+            block_range: None,
+            token_id: None,
+            finalized: false,
+            limit: DEFAULT_TRANSFER_LIMIT,
+            offset: 0,
+        }
+    }
+}
+
+impl TransferQuery {
+    /// Parse and check the filters
+    pub fn to_filter(&self) -> Result<TransferFilter> {
+        let from_address = self
+            .from
+            .as_ref()
+            .map(|addr| {
+                parse_address(addr).map_err(|e| anyhow::anyhow!("Invalid from address: {}", e))
+            })
+            .transpose()?;
+
+        let to_address = self
+            .to
+            .as_ref()
+            .map(|addr| {
+                parse_address(addr).map_err(|e| anyhow::anyhow!("Invalid to address: {}", e))
+            })
+            .transpose()?;
+
+        let involving = self
+            .involving
+            .as_ref()
+            .map(|addr| {
+                parse_address(addr).map_err(|e| anyhow::anyhow!("Invalid involving address: {}", e))
+            })
+            .transpose()?;
+
+        let parse_excluded = |addresses: &[String], side: &str| {
+            addresses
+                .iter()
+                .map(|addr| {
+                    parse_address(addr)
+                        .map_err(|e| anyhow::anyhow!("Invalid excluded {} address: {}", side, e))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let exclude_from = parse_excluded(&self.exclude_from, "from")?;
+        let exclude_to = parse_excluded(&self.exclude_to, "to")?;
+
+        let block_range = if let Some(block_num) = self.block {
+            Some((block_num, block_num))
+        } else {
+            self.block_range
+        };
+
+        let token_id = self.token_id.as_deref().map(parse_token_id).transpose()?;
+
+        if from_address.is_none()
+            && to_address.is_none()
+            && involving.is_none()
+            && block_range.is_none()
+            && token_id.is_none()
+        {
+            return Err(anyhow::anyhow!(
+                "Please specify at least one filter: --from, --to, --involving, --block, --block-range or --token-id"
+            ));
+        }
+
+        Ok(TransferFilter {
+            from_address,
+            to_address,
+            involving,
+            exclude_from,
+            exclude_to,
+            block_range,
+            token_id,
+            finalized_only: self.finalized,
+        })
+    }
+}
+
+/// One page of transfers with the token's decimals
+#[derive(Debug)]
+pub struct TransferList {
+    pub transfers: Vec<TransferView>,
+    pub decimals: Option<u8>,
+}
+
+pub fn list_transfers(
+    transfer_repo: &TransferRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    query: &TransferQuery,
+) -> Result<TransferList> {
+    let filter = query.to_filter()?;
+
+    Ok(TransferList {
+        transfers: collect_transfers(transfer_repo, &filter, (query.limit, query.offset))?,
+        decimals: token_repo.get_token_decimals(token_address)?,
+    })
+}
+
 pub fn cmd_transfers(
     transfer_repo: &TransferRepository,
     token_repo: &TokenRepository,
     token_address: &Address,
     query: TransferQuery,
+    options: &FormatOptions,
     format: &OutputFormat,
+    out: &mut dyn Write,
 ) -> Result<()> {
-    let from_address = query
-        .from
-        .as_ref()
-        .map(|addr| {
-            Address::from_str(addr).map_err(|_| anyhow::anyhow!("Invalid from address: {}", addr))
-        })
-        .transpose()?;
+    let filter = query.to_filter()?;
 
-    let to_address = query
-        .to
-        .as_ref()
-        .map(|addr| {
-            Address::from_str(addr).map_err(|_| anyhow::anyhow!("Invalid to address: {}", addr))
-        })
-        .transpose()?;
+    let decimals = token_repo.get_token_decimals(token_address)?;
+    write_transfers(
+        transfer_repo,
+        &filter,
+        (query.limit, query.offset),
+        decimals,
+        options,
+        format,
+        out,
+    )
+}
 
-    let block_range = if let Some(block_num) = query.block {
-        Some((block_num, block_num))
-    } else {
-        query.block_range
-    };
+fn collect_transfers(
+    transfer_repo: &TransferRepository,
+    filter: &TransferFilter,
+    (limit, offset): (usize, usize),
+) -> Result<Vec<TransferView>> {
+    let mut transfers = Vec::new();
+    transfer_repo.stream_transfers(filter, limit, offset, |transfer| {
+        transfers.push(transfer);
+        Ok(())
+    })?;
+    Ok(transfers)
+}
 
-    if from_address.is_none() && to_address.is_none() && block_range.is_none() {
-        return Err(anyhow::anyhow!(
-            "Please specify at least one filter: --from, --to, --block, or --block-range"
-        ));
+/// Write the matching transfers. CSV rows and JSON lines are streamed straight
+/// from the database to `out`, the other formats are built in memory.
+fn write_transfers(
+    transfer_repo: &TransferRepository,
+    filter: &TransferFilter,
+    (limit, offset): (usize, usize),
+    decimals: Option<u8>,
+    options: &FormatOptions,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    if let OutputFormat::Csv = format {
+        let mut writer = TransferCsvWriter::new(out, decimals, options)?;
+        let mut batch = Vec::new();
+        transfer_repo.stream_transfers(filter, limit, offset, |transfer| {
+            batch.push(transfer);
+            if batch.len() < LABEL_BATCH {
+                return Ok(());
+            }
+            write_labelled(&mut batch, options, |t, labels| writer.write(t, labels))
+                .map_err(IndexerError::from_anyhow)
+        })?;
+        write_labelled(&mut batch, options, |t, labels| writer.write(t, labels))?;
+        writer.finish()?.flush()?;
+        return Ok(());
     }
 
-    let transfers = transfer_repo.query_transfers(
-        from_address.as_ref(),
-        to_address.as_ref(),
-        block_range,
-        query.finalized,
-        query.limit,
-        query.offset,
-    )?;
+    if let OutputFormat::JsonLines = format {
+        let mut batch = Vec::new();
+        let mut write_line = |t: &TransferView, labels: &AddressLabels| -> Result<()> {
+            writeln!(out, "{}", transfer_to_json(t, decimals, options, labels))?;
+            Ok(())
+        };
+        transfer_repo.stream_transfers(filter, limit, offset, |transfer| {
+            batch.push(transfer);
+            if batch.len() < LABEL_BATCH {
+                return Ok(());
+            }
+            write_labelled(&mut batch, options, &mut write_line).map_err(IndexerError::from_anyhow)
+        })?;
+        write_labelled(&mut batch, options, &mut write_line)?;
+        out.flush()?;
+        return Ok(());
+    }
 
-    let decimals = token_repo.get_token_decimals(token_address)?;
-    let output = format_transfers(&transfers, decimals, format);
-    println!("{output}");
+    let transfers = collect_transfers(transfer_repo, filter, (limit, offset))?;
+
+    if let OutputFormat::Table | OutputFormat::Markdown = format
+        && transfers.len() > TABLE_ROW_WARNING_THRESHOLD
+    {
+        eprintln!(
+            "Warning: rendering {} transfers as a table, use -f csv --output <file> for large results",
+            transfers.len()
+        );
+    }
+
+    let labels = options.lookup_labels(transfer_addresses(&transfers))?;
+    let output = format_transfers(&transfers, decimals, options, &labels, format);
+    writeln!(out, "{output}")?;
 
     Ok(())
 }
 
-pub fn cmd_top_holders(
+/// Write the streamed rows of `batch` with their labels, found in one lookup,
+/// and empty it
+fn write_labelled(
+    batch: &mut Vec<TransferView>,
+    options: &FormatOptions,
+    mut write_row: impl FnMut(&TransferView, &AddressLabels) -> Result<()>,
+) -> Result<()> {
+    let labels = options.lookup_labels(transfer_addresses(batch))?;
+    for transfer in batch.drain(..) {
+        write_row(&transfer, &labels)?;
+    }
+    Ok(())
+}
+
+fn transfer_addresses(transfers: &[TransferView]) -> impl Iterator<Item = &Address> {
+    transfers
+        .iter()
+        .flat_map(|t| [&t.from_address, &t.to_address])
+}
+
+/// Largest holders with the token's decimals
+#[derive(Debug)]
+pub struct TopHoldersReport {
+    pub holders: Vec<TokenHolder>,
+    pub decimals: Option<u8>,
+}
+
+/// The `count` largest holders, leaving out those holding less than
+/// `min_balance` token units, e.g. "0.5" for half a token
+pub fn top_holders_report(
     balance_repo: &BalanceRepository,
     token_repo: &TokenRepository,
     token_address: &Address,
     count: usize,
+    min_balance: Option<&str>,
+) -> Result<TopHoldersReport> {
+    let decimals = token_repo.get_token_decimals(token_address)?;
+
+    let min_balance = match min_balance {
+        Some(amount) => parse_token_units(amount, decimals)
+            .map_err(|e| anyhow::anyhow!("Invalid minimum balance {}: {}", amount, e))?,
+        None => U256::ZERO,
+    };
+
+    let (holders, _) = balance_repo.get_top_holders_with_share(count, min_balance)?;
+    Ok(TopHoldersReport { holders, decimals })
+}
+
+pub fn cmd_top_holders(
+    balance_repo: &BalanceRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    (count, min_balance): (usize, Option<&str>),
+    options: &FormatOptions,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let report = top_holders_report(balance_repo, token_repo, token_address, count, min_balance)?;
+    let labels = options.lookup_labels(report.holders.iter().map(|h| &h.address))?;
+    let output = format_top_holders(report.holders, report.decimals, &labels, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+/// Holder counts by balance with the token's decimals
+#[derive(Debug)]
+pub struct DistributionReport {
+    pub distribution: Distribution,
+    pub decimals: Option<u8>,
+}
+
+/// Holders bucketed at `thresholds`, given in token units
+pub fn distribution_report(
+    balance_repo: &BalanceRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    thresholds: &[String],
+) -> Result<DistributionReport> {
+    let decimals = token_repo.get_token_decimals(token_address)?;
+
+    let thresholds = thresholds
+        .iter()
+        .map(|amount| {
+            parse_token_units(amount, decimals)
+                .map_err(|e| anyhow::anyhow!("Invalid threshold {}: {}", amount, e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(DistributionReport {
+        distribution: balance_repo.get_distribution(&thresholds)?,
+        decimals,
+    })
+}
+
+pub fn cmd_distribution(
+    balance_repo: &BalanceRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    thresholds: &[String],
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let report = distribution_report(balance_repo, token_repo, token_address, thresholds)?;
+    let output = format_distribution(&report.distribution, report.decimals, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+/// Base units of an amount given in token units, 18 decimals when the token
+/// reports none
+fn parse_token_units(amount: &str, decimals: Option<u8>) -> Result<U256> {
+    Ok(parse_units(amount, decimals.unwrap_or(18))?.get_absolute())
+}
+
+pub fn cmd_volume(
+    transfer_repo: &TransferRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    bucket_blocks: u64,
+    block_range: Option<(u64, u64)>,
     format: &OutputFormat,
+    out: &mut dyn Write,
 ) -> Result<()> {
-    let holders = balance_repo.get_top_holders(count)?;
     let decimals = token_repo.get_token_decimals(token_address)?;
-    let output = format_top_holders(holders, decimals, format);
-    println!("{output}");
+    let buckets = transfer_repo.get_volume_by_period(bucket_blocks, block_range)?;
+    let output = format_volume(&buckets, bucket_blocks, decimals, format);
+    writeln!(out, "{output}")?;
 
     Ok(())
 }
 
-pub fn cmd_stats(repo: &TransferRepository, format: &OutputFormat) -> Result<()> {
-    let stats = repo.get_statistics()?;
+pub fn cmd_stats(
+    repo: &TransferRepository,
+    exact: bool,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let stats = repo.get_statistics(exact)?;
     let output = format_stats(&stats, format);
-    println!("{output}");
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+pub fn cmd_token_info(
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    latest_block: Option<u64>,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let token = token_repo
+        .get_token(token_address)?
+        .ok_or_else(|| anyhow::anyhow!("Token {:?} has not been indexed yet", token_address))?;
+    let output = format_token_info(&token, latest_block, format);
+    writeln!(out, "{output}")?;
 
     Ok(())
 }
 
+pub fn get_sync_status(
+    transfer_repo: &TransferRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    chain_head: Option<u64>,
+) -> Result<SyncStatus> {
+    Ok(SyncStatus {
+        last_processed_block: token_repo.get_last_processed_block(token_address)?,
+        last_finalized_block: token_repo.get_last_processed_finalized_block(token_address)?,
+        chain_head,
+        transfers: transfer_repo.get_statistics(false)?.total_transfers,
+        last_inserted_at: token_repo.get_last_inserted_at(token_address)?,
+    })
+}
+
+/// Print the sync status and return how many blocks the index is behind the
+/// chain head, None when the head couldn't be fetched
+pub fn cmd_sync_status(
+    transfer_repo: &TransferRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    chain_head: Option<u64>,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<Option<u64>> {
+    let status = get_sync_status(transfer_repo, token_repo, token_address, chain_head)?;
+    let output = format_sync_status(&status, format);
+    writeln!(out, "{output}")?;
+
+    Ok(status.blocks_behind())
+}
+
+/// Sync status of each token of `TOKENS_CONFIG`, read from its own database.
+/// A token whose database can't be read is listed with the error.
+pub fn list_tokens(base: &Config, tokens: &[TokenConfig]) -> Vec<TokenListing> {
+    tokens
+        .iter()
+        .map(|token| {
+            let read = || -> Result<(SyncStatus, Option<String>)> {
+                let db = Database::open_read_only(&token.database_url, base.sqlite_options())?;
+                let reader = db.reader()?;
+                let token_repo = TokenRepository::new(&reader);
+                let status = get_sync_status(
+                    &TransferRepository::new(&reader, &token.address),
+                    &token_repo,
+                    &token.address,
+                    None,
+                )?;
+                let symbol = token_repo.get_token(&token.address)?.and_then(|t| t.symbol);
+                Ok((status, symbol))
+            };
+            let (status, stored_symbol) = match read() {
+                Ok((status, symbol)) => (Ok(status), symbol),
+                Err(e) => (Err(format!("{e:#}")), None),
+            };
+            TokenListing {
+                address: format!("{:?}", token.address),
+                symbol: token.symbol.clone().or(stored_symbol),
+                database_url: token.database_url.clone(),
+                status,
+            }
+        })
+        .collect()
+}
+
+pub fn cmd_list_tokens(
+    base: &Config,
+    tokens: &[TokenConfig],
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let output = format_token_listing(&list_tokens(base, tokens), format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+/// Gaps in the scanned blocks between the token's deployment and the
+/// indexer's cursor, and which of them are queued for a rescan
+pub fn coverage_report(
+    token_repo: &TokenRepository,
+    scanned_repo: &ScannedRangeRepository,
+    token_address: &Address,
+) -> Result<Coverage> {
+    let token = token_repo
+        .get_token(token_address)?
+        .ok_or_else(|| anyhow::anyhow!("Token {:?} has not been indexed yet", token_address))?;
+    let from_block = token.deployment_block + 1;
+    let gaps = match token.last_processed_block {
+        Some(to_block) => scanned_repo.gaps(from_block, to_block)?,
+        None => Vec::new(),
+    };
+
+    Ok(Coverage {
+        from_block,
+        to_block: token.last_processed_block,
+        gaps,
+        queued: scanned_repo.repairs()?,
+    })
+}
+
+/// Print the coverage report and return it, so the caller can queue the gaps
+pub fn cmd_coverage(
+    token_repo: &TokenRepository,
+    scanned_repo: &ScannedRangeRepository,
+    token_address: &Address,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<Coverage> {
+    let coverage = coverage_report(token_repo, scanned_repo, token_address)?;
+    let output = format_coverage(&coverage, format);
+    writeln!(out, "{output}")?;
+
+    Ok(coverage)
+}
+
 pub struct AddressHistoryQuery {
     pub address: String,
     pub finalized: bool,
     pub limit: usize,
     pub offset: usize,
+    /// Give the balance after each transfer, like a bank statement
+    pub with_running_balance: bool,
+    /// Balance before the address's first transfer in token units, zero when
+    /// None. Only used with `with_running_balance`.
+    pub starting_balance: Option<String>,
+}
+
+impl AddressHistoryQuery {
+    /// The first page of every transfer of `address`
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            finalized: false,
+            limit: DEFAULT_TRANSFER_LIMIT,
+            offset: 0,
+            with_running_balance: false,
+            starting_balance: None,
+        }
+    }
+}
+
+/// One page of an address's transfers with the token's decimals
+#[derive(Debug)]
+pub struct AddressHistory {
+    pub address: Address,
+    pub entries: Vec<AddressHistoryEntry>,
+    pub decimals: Option<u8>,
+    /// Balance before the first entry of the page, with a running balance
+    pub opening_balance: Option<I256>,
+}
+
+impl AddressHistory {
+    /// Balance after each entry, with a running balance
+    pub fn running_balances(&self) -> Option<Vec<I256>> {
+        let mut running_balance = RunningBalance::new(self.opening_balance);
+        self.entries
+            .iter()
+            .map(|entry| running_balance.apply(entry))
+            .collect()
+    }
 }
 
+/// One page of the transfers an address sent or received, oldest first
+pub fn list_address_history(
+    transfer_repo: &TransferRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    query: &AddressHistoryQuery,
+) -> Result<AddressHistory> {
+    let address = parse_address(&query.address)?;
+    let decimals = token_repo.get_token_decimals(token_address)?;
+
+    Ok(AddressHistory {
+        address,
+        entries: transfer_repo.get_address_history(
+            &address,
+            query.finalized,
+            query.limit,
+            query.offset,
+        )?,
+        decimals,
+        opening_balance: opening_balance(transfer_repo, &address, query, decimals)?,
+    })
+}
+
+/// Balance before the page `query` asks for: the starting balance plus what
+/// the transfers on the earlier pages moved, so pages line up with each other
+fn opening_balance(
+    transfer_repo: &TransferRepository,
+    address: &Address,
+    query: &AddressHistoryQuery,
+    decimals: Option<u8>,
+) -> Result<Option<I256>> {
+    if !query.with_running_balance {
+        return Ok(None);
+    }
+
+    let starting_balance = match &query.starting_balance {
+        Some(amount) => parse_token_units(amount, decimals)
+            .ok()
+            .and_then(|units| I256::try_from(units).ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid starting balance: {}", amount))?,
+        None => I256::ZERO,
+    };
+    let earlier = transfer_repo.get_address_history_net(address, query.finalized, query.offset)?;
+
+    Ok(Some(starting_balance.saturating_add(earlier)))
+}
+
+/// Write the address's transfers. CSV rows and JSON lines are streamed straight
+/// from the database to `out`, the other formats are built in memory.
 pub fn cmd_address_history(
     transfer_repo: &TransferRepository,
     token_repo: &TokenRepository,
     token_address: &Address,
     query: AddressHistoryQuery,
+    options: &FormatOptions,
     format: &OutputFormat,
+    out: &mut dyn Write,
 ) -> Result<()> {
-    let address = Address::from_str(&query.address)
-        .map_err(|_| anyhow::anyhow!("Invalid address format: {}", query.address))?;
+    let address = parse_address(&query.address)?;
+    let decimals = token_repo.get_token_decimals(token_address)?;
+    let opening_balance = opening_balance(transfer_repo, &address, &query, decimals)?;
+    let page = (query.limit, query.offset);
 
-    let transfers =
-        transfer_repo.get_address_history(&address, query.finalized, query.limit, query.offset)?;
+    if let OutputFormat::Csv = format {
+        let mut writer = AddressHistoryCsvWriter::new(out, decimals, opening_balance, options)?;
+        transfer_repo.stream_address_history(
+            &address,
+            query.finalized,
+            page.0,
+            page.1,
+            |entry| writer.write(&entry).map_err(IndexerError::Other),
+        )?;
+        writer.finish()?.flush()?;
+        return Ok(());
+    }
+
+    if let OutputFormat::JsonLines = format {
+        let mut running_balance = RunningBalance::new(opening_balance);
+        transfer_repo.stream_address_history(
+            &address,
+            query.finalized,
+            page.0,
+            page.1,
+            |entry| {
+                let balance = running_balance.apply(&entry);
+                writeln!(
+                    out,
+                    "{}",
+                    address_history_entry_to_json(&entry, decimals, balance, options)
+                )
+                .map_err(|e| IndexerError::Other(e.into()))
+            },
+        )?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    let entries = transfer_repo.get_address_history(&address, query.finalized, page.0, page.1)?;
+    if let OutputFormat::Table | OutputFormat::Markdown = format
+        && entries.len() > TABLE_ROW_WARNING_THRESHOLD
+    {
+        eprintln!(
+            "Warning: rendering {} transfers as a table, use -f csv --output <file> for large results",
+            entries.len()
+        );
+    }
+
+    let output = format_address_history(&entries, decimals, opening_balance, options, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+pub fn cmd_tx(
+    transfer_repo: &TransferRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    tx_hash: &str,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let tx_hash = B256::from_str(tx_hash)
+        .map_err(|_| anyhow::anyhow!("Invalid transaction hash: {}", tx_hash))?;
+
+    let transfers = transfer_repo.get_transfers_by_tx(&tx_hash)?;
+    if transfers.is_empty() {
+        // Without the transaction's block we can only say how far indexing got
+        match token_repo.get_last_processed_block(token_address)? {
+            Some(last_block) => writeln!(
+                out,
+                "Transaction {tx_hash:?} not indexed: no transfers of this token found in blocks up to {last_block}. \
+                 If the transaction is in block {} or later it has not been processed yet.",
+                last_block + 1
+            ),
+            None => writeln!(
+                out,
+                "Transaction {tx_hash:?} not indexed: no blocks have been processed for this token yet."
+            ),
+        }?;
+        return Ok(());
+    }
+
+    let decimals = token_repo.get_token_decimals(token_address)?;
+    let output = format_tx_transfers(&transfers, decimals, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+pub fn cmd_block(
+    transfer_repo: &TransferRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    block_number: u64,
+    options: &FormatOptions,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    // An unprocessed block would otherwise look like a block without transfers
+    match token_repo.get_last_processed_block(token_address)? {
+        Some(last_block) if block_number <= last_block => {}
+        Some(last_block) => {
+            writeln!(
+                out,
+                "Block {block_number} has not been processed yet (last processed block: {last_block})."
+            )?;
+            return Ok(());
+        }
+        None => {
+            writeln!(
+                out,
+                "Block {block_number} has not been processed yet: no blocks have been processed for this token."
+            )?;
+            return Ok(());
+        }
+    }
+
+    let summary = transfer_repo.get_block_summary(block_number)?;
+    let decimals = token_repo.get_token_decimals(token_address)?;
+    let labels = options.lookup_labels(transfer_addresses(&summary.transfers))?;
+    let output = format_block_summary(&summary, decimals, options, &labels, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+pub fn cmd_counterparties(
+    transfer_repo: &TransferRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    (address, limit): (&str, usize),
+    options: &FormatOptions,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let address = parse_address(address)?;
+
+    let counterparties = transfer_repo.get_counterparties(&address, limit)?;
     let decimals = token_repo.get_token_decimals(token_address)?;
-    let output = format_transfers(&transfers, decimals, format);
-    println!("{output}");
+    let labels = options.lookup_labels(counterparties.iter().map(|c| &c.address))?;
+    let output = format_counterparties(&counterparties, decimals, &labels, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+pub fn cmd_label_add(
+    label_repo: &LabelRepository,
+    address: &str,
+    label: &str,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let address = parse_address(address)?;
+    let label = label.trim();
+    if label.is_empty() {
+        anyhow::bail!("Label for {address} is empty");
+    }
+
+    match label_repo.set(&address, label)? {
+        Some(previous) if previous != label => {
+            writeln!(out, "Labeled {address} {label:?} (was {previous:?})")?
+        }
+        _ => writeln!(out, "Labeled {address} {label:?}")?,
+    }
+    Ok(())
+}
 
+pub fn cmd_label_remove(
+    label_repo: &LabelRepository,
+    address: &str,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let address = parse_address(address)?;
+    match label_repo.remove(&address)? {
+        Some(label) => writeln!(out, "Removed label {label:?} from {address}")?,
+        None => writeln!(out, "{address} has no label")?,
+    }
     Ok(())
 }
+
+/// Import `address,label` rows from a CSV file, with or without a header.
+/// Every row is checked first and nothing is imported if any is invalid. An
+/// address listed twice keeps its last label, and each duplicate is reported.
+pub fn cmd_label_import(
+    label_repo: &LabelRepository,
+    path: &Path,
+    out: &mut dyn Write,
+) -> Result<LabelImport> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+
+    let mut labels: Vec<(Address, String)> = Vec::new();
+    // Index in `labels` and line of each address seen so far
+    let mut seen: HashMap<Address, (usize, u64)> = HashMap::new();
+    let mut errors = Vec::new();
+    let mut duplicates = Vec::new();
+
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let line = record.position().map_or(i as u64 + 1, |p| p.line());
+        let (Some(address), Some(label)) = (record.get(0), record.get(1)) else {
+            errors.push(format!("line {line}: expected address,label"));
+            continue;
+        };
+        if i == 0 && address.eq_ignore_ascii_case("address") {
+            continue;
+        }
+        let address = match parse_address(address) {
+            Ok(address) => address,
+            Err(e) => {
+                errors.push(format!("line {line}: {e}"));
+                continue;
+            }
+        };
+        if label.is_empty() {
+            errors.push(format!("line {line}: empty label for {address}"));
+            continue;
+        }
+
+        match seen.get(&address) {
+            Some(&(index, first_line)) => {
+                duplicates.push(format!(
+                    "{address} on lines {first_line} and {line}, keeping {label:?}"
+                ));
+                labels[index].1 = label.to_string();
+                seen.insert(address, (index, line));
+            }
+            None => {
+                seen.insert(address, (labels.len(), line));
+                labels.push((address, label.to_string()));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        anyhow::bail!(
+            "{} invalid rows in {}, nothing imported:\n  {}",
+            errors.len(),
+            path.display(),
+            errors.join("\n  ")
+        );
+    }
+
+    for duplicate in &duplicates {
+        writeln!(out, "Duplicate: {duplicate}")?;
+    }
+    let summary = label_repo.import(&labels)?;
+    writeln!(
+        out,
+        "Imported {} labels from {}: {} new, {} replaced, {} unchanged",
+        labels.len(),
+        path.display(),
+        summary.added,
+        summary.replaced,
+        summary.unchanged
+    )?;
+    Ok(summary)
+}
+
+pub fn cmd_notifications(
+    notification_repo: &NotificationRepository,
+    token_repo: &TokenRepository,
+    token_address: &Address,
+    limit: usize,
+    options: &FormatOptions,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let notifications = notification_repo.list(limit)?;
+    let decimals = token_repo.get_token_decimals(token_address)?;
+    let output = format_notifications(&notifications, decimals, options, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+pub fn cmd_reorgs(
+    reorg_repo: &ReorgRepository,
+    limit: usize,
+    options: &FormatOptions,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let reorgs = reorg_repo.list(limit)?;
+    let output = format_reorgs(&reorgs, options, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+pub fn cmd_indexing_log(
+    indexing_log_repo: &IndexingLogRepository,
+    last: usize,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let records = indexing_log_repo.list(last)?;
+    let output = format_indexing_log(&records, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+/// Events indexed with `EVENT_SIGNATURE`, most recent first. Each `field=value`
+/// of `conditions` must match a decoded field.
+pub fn cmd_events(
+    event_repo: &EventRepository,
+    conditions: &[String],
+    finalized_only: bool,
+    limit: usize,
+    options: &FormatOptions,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let filter = EventFilter {
+        fields: parse_field_conditions(conditions)?,
+        finalized_only,
+    };
+
+    let events = event_repo.get_events(&filter, limit)?;
+    let output = format_events(&events, options, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+/// `field=value` pairs, split at the first `=`
+fn parse_field_conditions(conditions: &[String]) -> Result<Vec<(String, String)>> {
+    conditions
+        .iter()
+        .map(|condition| match condition.split_once('=') {
+            Some((field, value)) if !field.trim().is_empty() => {
+                Ok((field.trim().to_string(), value.trim().to_string()))
+            }
+            _ => Err(anyhow::anyhow!(
+                "Invalid condition {condition:?}, expected field=value"
+            )),
+        })
+        .collect()
+}
+
+/// Current owner of an ERC-721 token. Nothing is listed for tokens that were
+/// never minted, were burned or whose transfers aren't finalized yet.
+pub fn cmd_owner_of(
+    nft_repo: &NftRepository,
+    token_id: &str,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let token_id = parse_token_id(token_id)?;
+
+    let owner = nft_repo.get_owner(&token_id)?;
+    let output = format_nft_owners(owner.as_slice(), format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+/// ERC-721 tokens an address owns
+pub fn cmd_tokens_of(
+    nft_repo: &NftRepository,
+    address: &str,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let address = parse_address(address)?;
+
+    let tokens = nft_repo.get_tokens_of(&address)?;
+    let output = format_nft_owners(&tokens, format);
+    writeln!(out, "{output}")?;
+
+    Ok(())
+}
+
+/// Decimal, or hex with a 0x prefix
+fn parse_token_id(input: &str) -> Result<U256> {
+    U256::from_str(input.trim()).map_err(|_| anyhow::anyhow!("Invalid token id: {}", input))
+}
+
+/// Returns the number of problems found
+pub fn cmd_check_integrity(
+    conn: &rusqlite::Connection,
+    token_address: &Address,
+    format: &OutputFormat,
+    out: &mut dyn Write,
+) -> Result<usize> {
+    let problems = check_integrity(conn, token_address)?;
+    let output = format_integrity_problems(&problems, format);
+    writeln!(out, "{output}")?;
+
+    Ok(problems.len())
+}
+
+pub fn cmd_export_holders(
+    conn: &rusqlite::Connection,
+    token_address: &Address,
+    output: &Path,
+    format: &str,
+) -> Result<()> {
+    let format = ExportFormat::from_str(format)?;
+
+    let count = export_holders(conn, token_address, output, format)?;
+    eprintln!("Exported {count} holders to {}", output.display());
+
+    Ok(())
+}
+
+/// Write the balances at the end of `block` to `output`. The block must be
+/// finalized, or only indexed with `include_unfinalized`, so the snapshot
+/// doesn't miss transfers the indexer hasn't stored yet.
+pub fn cmd_snapshot(
+    conn: &rusqlite::Connection,
+    token_address: &Address,
+    block: u64,
+    include_unfinalized: bool,
+    output: &Path,
+    format: &str,
+) -> Result<()> {
+    let format = ExportFormat::from_str(format)?;
+
+    // Cursors and transfers read in one transaction, so they match
+    let tx = conn.unchecked_transaction()?;
+    let token_repo = TokenRepository::new(&tx);
+    let (cursor, cursor_name) = if include_unfinalized {
+        (
+            token_repo.get_last_processed_block(token_address)?,
+            "last processed",
+        )
+    } else {
+        (
+            token_repo.get_last_processed_finalized_block(token_address)?,
+            "last finalized",
+        )
+    };
+    let Some(cursor) = cursor else {
+        anyhow::bail!("Token {token_address:?} has not been indexed yet");
+    };
+    if block > cursor {
+        anyhow::bail!(
+            "Block {block} is past the {cursor_name} block {cursor}{}",
+            if include_unfinalized {
+                ""
+            } else {
+                ", pass --include-unfinalized to count unfinalized transfers"
+            }
+        );
+    }
+
+    let snapshot =
+        BalanceRepository::new(&tx, token_address).balances_at_block(block, include_unfinalized)?;
+    let count = export_snapshot(
+        &tx,
+        token_address,
+        &snapshot,
+        include_unfinalized,
+        output,
+        format,
+    )?;
+    let how = match snapshot.direction {
+        SnapshotDirection::Forward => format!("{} transfers added up", snapshot.transfers_applied),
+        SnapshotDirection::Reverse => format!(
+            "the current balances with {} transfers taken back out",
+            snapshot.transfers_applied
+        ),
+        SnapshotDirection::FromCheckpoint(checkpoint) => format!(
+            "the balance checkpoint at block {checkpoint} with {} transfers added",
+            snapshot.transfers_applied
+        ),
+    };
+    eprintln!(
+        "Exported {count} holders at block {block} to {} ({how})",
+        output.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{Database, Direction, TransferView};
+    use crate::testutil::{
+        DataGenerator, holder, populate_with, remove_database, temp_database_path, token_address,
+    };
+
+    /// 150 transfers between 10 equally active holders in blocks 1 to 15
+    fn database(name: &str) -> Database {
+        let generator = DataGenerator::new(1).with_holders(10, 0.0);
+        populate_with(
+            &temp_database_path(&format!("query-{name}")),
+            generator,
+            150,
+        )
+        .unwrap()
+    }
+
+    fn cleanup(name: &str) {
+        remove_database(&temp_database_path(&format!("query-{name}")));
+    }
+
+    fn transfers(db: &Database, query: &TransferQuery) -> Result<TransferList> {
+        let transfer_repo = TransferRepository::new(&db.conn, &token_address());
+        let token_repo = TokenRepository::new(&db.conn);
+        list_transfers(&transfer_repo, &token_repo, &token_address(), query)
+    }
+
+    #[test]
+    fn transfer_query_needs_a_filter() {
+        let error = TransferQuery::default().to_filter().unwrap_err();
+        assert!(error.to_string().contains("at least one filter"));
+
+        let finalized_only = TransferQuery {
+            finalized: true,
+            ..Default::default()
+        };
+        assert!(finalized_only.to_filter().is_err());
+    }
+
+    #[test]
+    fn transfer_query_rejects_bad_input() {
+        let bad_from = TransferQuery {
+            from: Some("0x1234".to_string()),
+            ..Default::default()
+        };
+        let error = bad_from.to_filter().unwrap_err().to_string();
+        assert!(error.starts_with("Invalid from address"), "{error}");
+
+        let bad_to = TransferQuery {
+            to: Some("not an address".to_string()),
+            ..Default::default()
+        };
+        let error = bad_to.to_filter().unwrap_err().to_string();
+        assert!(error.starts_with("Invalid to address"), "{error}");
+
+        let bad_token_id = TransferQuery {
+            token_id: Some("0xzz".to_string()),
+            ..Default::default()
+        };
+        let error = bad_token_id.to_filter().unwrap_err().to_string();
+        assert!(error.starts_with("Invalid token id"), "{error}");
+    }
+
+    #[test]
+    fn exclusions_alone_are_not_a_filter() {
+        let query = TransferQuery {
+            exclude_to: vec![Address::ZERO.to_string()],
+            ..Default::default()
+        };
+        assert!(query.to_filter().is_err());
+
+        let bad_exclusion = TransferQuery {
+            involving: Some(holder(1).to_string()),
+            exclude_from: vec![holder(2).to_string(), "0x12".to_string()],
+            ..Default::default()
+        };
+        let error = bad_exclusion.to_filter().unwrap_err().to_string();
+        assert!(
+            error.starts_with("Invalid excluded from address"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn combined_filters_narrow_each_other() {
+        let db = database("combined");
+        let everything = transfers(
+            &db,
+            &TransferQuery {
+                block_range: Some((0, 100)),
+                limit: 1000,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .transfers;
+        let expected = |keep: &dyn Fn(&TransferView) -> bool| {
+            let mut hashes: Vec<_> = everything
+                .iter()
+                .filter(|t| keep(t))
+                .map(|t| t.transaction_hash)
+                .collect();
+            hashes.sort();
+            hashes
+        };
+        let listed = |query: TransferQuery| {
+            let mut hashes: Vec<_> = transfers(
+                &db,
+                &TransferQuery {
+                    limit: 1000,
+                    ..query
+                },
+            )
+            .unwrap()
+            .transfers
+            .iter()
+            .map(|t| t.transaction_hash)
+            .collect();
+            hashes.sort();
+            hashes
+        };
+        let (x, y) = (holder(1), holder(2));
+
+        // Either side, within a block range
+        let involving_in_range = listed(TransferQuery {
+            involving: Some(x.to_string()),
+            block_range: Some((3, 9)),
+            ..Default::default()
+        });
+        assert!(!involving_in_range.is_empty());
+        assert_eq!(
+            involving_in_range,
+            expected(&|t| (t.from_address == x || t.to_address == x)
+                && (3..=9).contains(&t.block_number))
+        );
+
+        // The OR stays inside its parentheses: only y's transfers to x
+        let from_y_involving_x = listed(TransferQuery {
+            from: Some(y.to_string()),
+            involving: Some(x.to_string()),
+            ..Default::default()
+        });
+        assert!(!from_y_involving_x.is_empty());
+        assert_eq!(
+            from_y_involving_x,
+            expected(&|t| t.from_address == y && t.to_address == x)
+        );
+
+        // Mints are sent by the zero address
+        let mints = expected(&|t| t.from_address == Address::ZERO);
+        assert!(!mints.is_empty());
+        let without_mints = listed(TransferQuery {
+            involving: Some(x.to_string()),
+            exclude_from: vec![Address::ZERO.to_string()],
+            exclude_to: vec![y.to_string(), holder(3).to_string()],
+            ..Default::default()
+        });
+        assert_eq!(
+            without_mints,
+            expected(&|t| (t.from_address == x || t.to_address == x)
+                && t.from_address != Address::ZERO
+                && t.to_address != y
+                && t.to_address != holder(3))
+        );
+
+        cleanup("combined");
+    }
+
+    #[test]
+    fn transfer_query_block_overrides_range() {
+        let query = TransferQuery {
+            block: Some(7),
+            block_range: Some((1, 100)),
+            ..Default::default()
+        };
+        assert_eq!(query.to_filter().unwrap().block_range, Some((7, 7)));
+    }
+
+    #[test]
+    fn queries_default_to_the_first_page() {
+        let query = TransferQuery::default();
+        assert_eq!((query.limit, query.offset), (DEFAULT_TRANSFER_LIMIT, 0));
+
+        let query = AddressHistoryQuery::new("0x0000000000000000000000000000000000000001");
+        assert_eq!((query.limit, query.offset), (DEFAULT_TRANSFER_LIMIT, 0));
+        assert!(!query.finalized);
+    }
+
+    #[test]
+    fn list_transfers_pages_results() {
+        let db = database("pages");
+
+        let all_blocks = TransferQuery {
+            block_range: Some((0, 100)),
+            ..Default::default()
+        };
+        let first = transfers(&db, &all_blocks).unwrap();
+        assert_eq!(first.transfers.len(), DEFAULT_TRANSFER_LIMIT);
+        assert_eq!(first.decimals, Some(6));
+
+        let rest = transfers(
+            &db,
+            &TransferQuery {
+                offset: DEFAULT_TRANSFER_LIMIT,
+                ..all_blocks
+            },
+        )
+        .unwrap();
+        assert_eq!(rest.transfers.len(), 50);
+
+        let one_block = TransferQuery {
+            block: Some(3),
+            ..Default::default()
+        };
+        let block = transfers(&db, &one_block).unwrap();
+        assert_eq!(block.transfers.len(), 10);
+        assert!(block.transfers.iter().all(|t| t.block_number == 3));
+
+        cleanup("pages");
+    }
+
+    #[test]
+    fn jsonl_transfers_stream_every_row_across_label_batches() {
+        let path = temp_database_path("query-jsonl");
+        let db = populate_with(&path, DataGenerator::new(2).with_holders(10, 0.0), 1200).unwrap();
+        let transfer_repo = TransferRepository::new(&db.conn, &token_address());
+        let token_repo = TokenRepository::new(&db.conn);
+        let query = || TransferQuery {
+            block_range: Some((0, 1_000_000)),
+            limit: 1200,
+            ..Default::default()
+        };
+
+        let mut out = Vec::new();
+        let options = FormatOptions::new(true, None).unwrap();
+        cmd_transfers(
+            &transfer_repo,
+            &token_repo,
+            &token_address(),
+            query(),
+            &options,
+            &OutputFormat::JsonLines,
+            &mut out,
+        )
+        .unwrap();
+
+        // Written as they were read, in more than one batch of labels, in the
+        // order the in-memory formats list them
+        const { assert!(1200 > 2 * LABEL_BATCH) };
+        let expected = list_transfers(&transfer_repo, &token_repo, &token_address(), &query())
+            .unwrap()
+            .transfers;
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1200);
+        for (line, transfer) in lines.iter().zip(&expected) {
+            assert_eq!(
+                line["transaction_hash"],
+                format!("{:?}", transfer.transaction_hash)
+            );
+            assert_eq!(line["value_wei"], transfer.value.to_string());
+        }
+
+        drop(db);
+        remove_database(&path);
+    }
+
+    #[test]
+    fn address_history_gives_direction_and_counterparty() {
+        let db = database("history");
+        let transfer_repo = TransferRepository::new(&db.conn, &token_address());
+        let token_repo = TokenRepository::new(&db.conn);
+        let address = holder(4);
+
+        let query = AddressHistoryQuery::new(address.to_string());
+        let history =
+            list_address_history(&transfer_repo, &token_repo, &token_address(), &query).unwrap();
+        assert!(!history.entries.is_empty());
+        for entry in &history.entries {
+            let t = &entry.transfer;
+            let (direction, counterparty) =
+                match (t.from_address == address, t.to_address == address) {
+                    (true, true) => (Direction::ToSelf, address),
+                    (true, false) => (Direction::Out, t.to_address),
+                    (false, true) => (Direction::In, t.from_address),
+                    (false, false) => panic!("{t:?} doesn't involve {address}"),
+                };
+            assert_eq!(
+                (entry.direction, entry.counterparty),
+                (direction, counterparty)
+            );
+        }
+        assert!(history.entries.iter().any(|e| e.direction == Direction::In));
+        assert!(
+            history
+                .entries
+                .iter()
+                .any(|e| e.direction == Direction::Out)
+        );
+        assert!(
+            history
+                .entries
+                .windows(2)
+                .all(|pair| pair[0].transfer.block_number <= pair[1].transfer.block_number)
+        );
+
+        let invalid = AddressHistoryQuery::new("0xabc");
+        assert!(
+            list_address_history(&transfer_repo, &token_repo, &token_address(), &invalid).is_err()
+        );
+
+        cleanup("history");
+    }
+
+    #[test]
+    fn running_balance_carries_across_pages() {
+        let db = database("running-balance");
+        let transfer_repo = TransferRepository::new(&db.conn, &token_address());
+        let token_repo = TokenRepository::new(&db.conn);
+        let balance_repo = BalanceRepository::new(&db.conn, &token_address());
+        let address = holder(4);
+        let history = |query: &AddressHistoryQuery| {
+            list_address_history(&transfer_repo, &token_repo, &token_address(), query).unwrap()
+        };
+
+        let plain = history(&AddressHistoryQuery::new(address.to_string()));
+        assert_eq!(plain.opening_balance, None);
+        assert_eq!(plain.running_balances(), None);
+
+        let whole = history(&AddressHistoryQuery {
+            with_running_balance: true,
+            ..AddressHistoryQuery::new(address.to_string())
+        });
+        let balances = whole.running_balances().unwrap();
+        let indexed = balance_repo.get_balance(&address, true).unwrap().balance;
+        assert_eq!(
+            balances.last().copied(),
+            Some(I256::try_from(indexed).unwrap())
+        );
+
+        let split = whole.entries.len() / 2;
+        let second_page = history(&AddressHistoryQuery {
+            offset: split,
+            with_running_balance: true,
+            ..AddressHistoryQuery::new(address.to_string())
+        });
+        assert_eq!(second_page.running_balances().unwrap(), balances[split..]);
+
+        let topped_up = history(&AddressHistoryQuery {
+            with_running_balance: true,
+            starting_balance: Some("1.5".to_string()),
+            ..AddressHistoryQuery::new(address.to_string())
+        });
+        assert_eq!(
+            topped_up.opening_balance,
+            Some(I256::try_from(1_500_000).unwrap())
+        );
+
+        let invalid = AddressHistoryQuery {
+            with_running_balance: true,
+            starting_balance: Some("-1".to_string()),
+            ..AddressHistoryQuery::new(address.to_string())
+        };
+        assert!(
+            list_address_history(&transfer_repo, &token_repo, &token_address(), &invalid).is_err()
+        );
+
+        cleanup("running-balance");
+    }
+
+    #[test]
+    fn top_holders_report_applies_the_minimum() {
+        let db = database("top-holders");
+        let balance_repo = BalanceRepository::new(&db.conn, &token_address());
+        let token_repo = TokenRepository::new(&db.conn);
+
+        let report =
+            top_holders_report(&balance_repo, &token_repo, &token_address(), 3, None).unwrap();
+        assert_eq!(report.holders.len(), 3);
+        assert_eq!(report.decimals, Some(6));
+
+        // 150 transfers of at most a million tokens can't add up to a billion
+        let none = top_holders_report(
+            &balance_repo,
+            &token_repo,
+            &token_address(),
+            10,
+            Some("1000000000"),
+        )
+        .unwrap();
+        assert!(none.holders.is_empty());
+
+        let error = top_holders_report(
+            &balance_repo,
+            &token_repo,
+            &token_address(),
+            10,
+            Some("lots"),
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Invalid minimum balance lots")
+        );
+
+        cleanup("top-holders");
+    }
+
+    #[test]
+    fn coverage_report_finds_and_queues_gaps() {
+        let db = database("coverage");
+        let token_repo = TokenRepository::new(&db.conn);
+        let scanned_repo = ScannedRangeRepository::new(&db.conn, &token_address());
+
+        let coverage = coverage_report(&token_repo, &scanned_repo, &token_address()).unwrap();
+        assert_eq!((coverage.from_block, coverage.to_block), (1, Some(15)));
+        assert!(coverage.gaps.is_empty());
+
+        // Adjacent and out of order ranges merge, leaving two gaps
+        db.conn.execute("DELETE FROM scanned_ranges", []).unwrap();
+        scanned_repo
+            .record(&[(1, 4), (9, 10), (5, 5), (12, 15)])
+            .unwrap();
+        assert_eq!(
+            scanned_repo.ranges().unwrap(),
+            vec![(1, 5), (9, 10), (12, 15)]
+        );
+        let coverage = coverage_report(&token_repo, &scanned_repo, &token_address()).unwrap();
+        assert_eq!(coverage.gaps, vec![(6, 8), (11, 11)]);
+        assert_eq!(coverage.missing_blocks(), 4);
+        assert!(!coverage.is_queued(6, 8));
+
+        // Scanning part of a queued gap leaves the rest of it queued
+        scanned_repo.queue_repairs(&coverage.gaps).unwrap();
+        scanned_repo.record(&[(6, 7)]).unwrap();
+        assert_eq!(scanned_repo.repairs().unwrap(), vec![(8, 8), (11, 11)]);
+        let coverage = coverage_report(&token_repo, &scanned_repo, &token_address()).unwrap();
+        assert_eq!(coverage.gaps, vec![(8, 8), (11, 11)]);
+        assert!(coverage.is_queued(8, 8) && coverage.is_queued(11, 11));
+
+        cleanup("coverage");
+    }
+
+    #[test]
+    fn event_conditions_split_at_the_first_equals() {
+        let conditions = ["sender = 0xAbC".to_string(), "$.data=a=b".to_string()];
+        assert_eq!(
+            parse_field_conditions(&conditions).unwrap(),
+            vec![
+                ("sender".to_string(), "0xAbC".to_string()),
+                ("$.data".to_string(), "a=b".to_string()),
+            ]
+        );
+
+        for bad in ["sender", "=0x1"] {
+            let error = parse_field_conditions(&[bad.to_string()]).unwrap_err();
+            assert!(
+                error.to_string().contains("expected field=value"),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn snapshots_match_replayed_balances_either_way() {
+        let db = database("snapshot");
+        let balance_repo = BalanceRepository::new(&db.conn, &token_address());
+
+        // Few transfers up to block 3 are added up, few after block 12 taken back out
+        for (block, direction) in [
+            (3, SnapshotDirection::Forward),
+            (12, SnapshotDirection::Reverse),
+        ] {
+            let snapshot = balance_repo.balances_at_block(block, false).unwrap();
+            assert_eq!(snapshot.direction, direction);
+            assert!(!snapshot.holders.is_empty());
+            assert!(
+                snapshot
+                    .holders
+                    .windows(2)
+                    .all(|pair| pair[0].balance >= pair[1].balance)
+            );
+            for index in 0..10 {
+                let expected = balance_repo
+                    .get_balance_at_block(&holder(index), block)
+                    .unwrap();
+                let found = snapshot
+                    .holders
+                    .iter()
+                    .find(|h| h.address == holder(index))
+                    .map_or(U256::ZERO, |h| h.balance);
+                assert_eq!(found, expected, "holder {index} at block {block}");
+            }
+        }
+
+        let output = temp_database_path("query-snapshot-out").with_extension("csv");
+        let error = cmd_snapshot(&db.conn, &token_address(), 16, false, &output, "csv")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("past the last finalized block 15"),
+            "{error}"
+        );
+
+        cmd_snapshot(&db.conn, &token_address(), 12, false, &output, "csv").unwrap();
+        let csv = std::fs::read_to_string(&output).unwrap();
+        assert!(csv.contains("# snapshot_block: 12"));
+        assert!(csv.contains("# include_unfinalized: false"));
+        let _ = std::fs::remove_file(&output);
+
+        cleanup("snapshot");
+    }
+
+    #[test]
+    fn label_import_validates_and_labels_holder_output() {
+        let db = database("labels");
+        let label_repo = LabelRepository::new(&db.conn);
+        let path = temp_database_path("query-labels-in").with_extension("csv");
+
+        // No labels stored, no label column
+        let options = FormatOptions::new(false, None)
+            .unwrap()
+            .with_labels(db.read_pool(), false)
+            .unwrap();
+        let balance_repo = BalanceRepository::new(&db.conn, &token_address());
+        let token_repo = TokenRepository::new(&db.conn);
+        let top_holders = |options: &FormatOptions, format: &OutputFormat| {
+            let mut out = Vec::new();
+            cmd_top_holders(
+                &balance_repo,
+                &token_repo,
+                &token_address(),
+                (10, None),
+                options,
+                format,
+                &mut out,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert!(!top_holders(&options, &OutputFormat::Csv).contains("label"));
+
+        std::fs::write(
+            &path,
+            format!("address,label\n{:?},Treasury\n0x1234,Bad\n", holder(0)),
+        )
+        .unwrap();
+        let error = cmd_label_import(&label_repo, &path, &mut Vec::new())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("line 3: Invalid address"), "{error}");
+        assert_eq!(label_repo.count().unwrap(), 0);
+
+        label_repo.set(&holder(1), "Exchange").unwrap();
+        std::fs::write(
+            &path,
+            format!(
+                "{:?},Team\n{:?},Exchange\n{:?},Treasury\n",
+                holder(0),
+                holder(1),
+                holder(0)
+            ),
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        let summary = cmd_label_import(&label_repo, &path, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            summary,
+            LabelImport {
+                added: 1,
+                replaced: 0,
+                unchanged: 1
+            }
+        );
+        assert!(
+            out.contains("on lines 1 and 3, keeping \"Treasury\""),
+            "{out}"
+        );
+        assert_eq!(
+            label_repo
+                .lookup([&holder(0), &holder(1), &holder(2), &holder(0)])
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let options = FormatOptions::new(false, None)
+            .unwrap()
+            .with_labels(db.read_pool(), false)
+            .unwrap();
+        let table = top_holders(&options, &OutputFormat::Markdown);
+        assert!(
+            table.contains(&format!("`{:#}` (Treasury)", holder(0))),
+            "{table}"
+        );
+        let csv = top_holders(&options, &OutputFormat::Csv);
+        assert!(csv.lines().next().unwrap().ends_with(",label"));
+        assert!(csv.contains(",Exchange\n"), "{csv}");
+
+        let labels_only = FormatOptions::new(false, None)
+            .unwrap()
+            .with_labels(db.read_pool(), true)
+            .unwrap();
+        let table = top_holders(&labels_only, &OutputFormat::Markdown);
+        assert!(table.contains("| Treasury |"), "{table}");
+        assert!(!table.contains(&format!("{:#}", holder(0))), "{table}");
+
+        let _ = std::fs::remove_file(&path);
+        cleanup("labels");
+    }
+}
@@ -0,0 +1,196 @@
+use crate::error::IndexerError;
+use crate::repository::{BalanceRepository, BalanceSnapshot, TokenHolder, TokenRepository};
+use alloy_primitives::Address;
+use alloy_primitives::utils::format_units;
+use anyhow::{Context, Result};
+use csv::Writer;
+use rusqlite::Connection;
+use serde_json::json;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Rows between progress lines while exporting
+const PROGRESS_EVERY: u64 = 100_000;
+
+/// File formats for exports, which are written row by row
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    Csv,
+    JsonLines,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" => Ok(ExportFormat::JsonLines),
+            _ => anyhow::bail!("Unknown export format {s}, expected csv or jsonl"),
+        }
+    }
+}
+
+/// Write every holder of the token to `output`, largest balance first.
+///
+/// The snapshot block is the last finalized block, which the balances table
+/// reflects. Both are read in one transaction so they match even while the
+/// indexer keeps writing. CSV files start with `#` comment lines carrying the
+/// snapshot header, JSON Lines files with a header object.
+pub fn export_holders(
+    conn: &Connection,
+    token_address: &Address,
+    output: &Path,
+    format: ExportFormat,
+) -> Result<u64> {
+    let tx = conn.unchecked_transaction()?;
+    let token_repo = TokenRepository::new(&tx);
+    let balance_repo = BalanceRepository::new(&tx, token_address);
+
+    let decimals = token_repo.get_token_decimals(token_address)?.unwrap_or(18);
+    let snapshot_block = token_repo.get_last_processed_finalized_block(token_address)?;
+
+    let header = [
+        ("token_address", json!(format!("{token_address:?}"))),
+        ("snapshot_block", json!(snapshot_block)),
+        ("generated_at", json!(unix_now()?)),
+    ];
+    let mut writer = HolderWriter::create(output, format, decimals, &header)?;
+    balance_repo.iter_all_holders(|holder| writer.write(&holder).map_err(IndexerError::Other))?;
+    writer.finish()
+}
+
+/// Write the balances of every holder at the end of `block_number` to
+/// `output`, largest first, in the layout of `export_holders`. The header
+/// also says whether unfinalized transfers were counted.
+pub fn export_snapshot(
+    conn: &Connection,
+    token_address: &Address,
+    snapshot: &BalanceSnapshot,
+    include_unfinalized: bool,
+    output: &Path,
+    format: ExportFormat,
+) -> Result<u64> {
+    let decimals = TokenRepository::new(conn)
+        .get_token_decimals(token_address)?
+        .unwrap_or(18);
+
+    let header = [
+        ("token_address", json!(format!("{token_address:?}"))),
+        ("snapshot_block", json!(snapshot.block_number)),
+        ("include_unfinalized", json!(include_unfinalized)),
+        ("generated_at", json!(unix_now()?)),
+    ];
+    let mut writer = HolderWriter::create(output, format, decimals, &header)?;
+    for holder in &snapshot.holders {
+        writer.write(holder)?;
+    }
+    writer.finish()
+}
+
+fn unix_now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Ranked holders written to a file one row at a time, after a header
+struct HolderWriter {
+    sink: HolderSink,
+    decimals: u8,
+    /// Holders written so far
+    rank: u64,
+}
+
+enum HolderSink {
+    Csv(Box<Writer<BufWriter<File>>>),
+    JsonLines(BufWriter<File>),
+}
+
+impl HolderWriter {
+    /// Create `output` and write `header`: `#` comment lines before the CSV
+    /// column names, or a JSON object on the first line
+    fn create(
+        output: &Path,
+        format: ExportFormat,
+        decimals: u8,
+        header: &[(&str, serde_json::Value)],
+    ) -> Result<Self> {
+        let file = File::create(output)
+            .with_context(|| format!("Failed to create {}", output.display()))?;
+        let mut out = BufWriter::new(file);
+
+        let sink = match format {
+            ExportFormat::Csv => {
+                for (key, value) in header {
+                    let value = match value {
+                        serde_json::Value::Null => "N/A".to_string(),
+                        serde_json::Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    };
+                    writeln!(out, "# {key}: {value}")?;
+                }
+
+                let mut wtr = Writer::from_writer(out);
+                wtr.write_record(["rank", "address", "balance", "balance_wei"])?;
+                HolderSink::Csv(Box::new(wtr))
+            }
+            ExportFormat::JsonLines => {
+                let header: serde_json::Map<_, _> = header
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.clone()))
+                    .collect();
+                serde_json::to_writer(&mut out, &header)?;
+                writeln!(out)?;
+                HolderSink::JsonLines(out)
+            }
+        };
+
+        Ok(Self {
+            sink,
+            decimals,
+            rank: 0,
+        })
+    }
+
+    fn write(&mut self, holder: &TokenHolder) -> Result<()> {
+        self.rank += 1;
+        let address = format!("{:?}", &holder.address);
+        let balance = format_units(holder.balance, self.decimals)?;
+        match &mut self.sink {
+            HolderSink::Csv(wtr) => {
+                wtr.write_record([
+                    self.rank.to_string(),
+                    address,
+                    balance,
+                    holder.balance.to_string(),
+                ])?;
+            }
+            HolderSink::JsonLines(out) => {
+                let line = json!({
+                    "rank": self.rank,
+                    "address": address,
+                    "balance": balance,
+                    "balance_wei": holder.balance.to_string(),
+                });
+                serde_json::to_writer(&mut *out, &line)?;
+                writeln!(out)?;
+            }
+        }
+
+        if self.rank.is_multiple_of(PROGRESS_EVERY) {
+            eprintln!("Exported {} holders...", self.rank);
+        }
+        Ok(())
+    }
+
+    /// Flush the file, returning the number of holders written
+    fn finish(self) -> Result<u64> {
+        match self.sink {
+            HolderSink::Csv(mut wtr) => wtr.flush()?,
+            HolderSink::JsonLines(mut out) => out.flush()?,
+        }
+        Ok(self.rank)
+    }
+}
@@ -1,39 +1,246 @@
-use crate::repository::{BalanceInfo, TokenHolder, TransferStats, TransferView};
+use crate::integrity::IntegrityProblem;
+use crate::repository::{
+    AddressHistoryEntry, BalanceInfo, BlockSummary, Counterparty, Distribution, IndexingLogRecord,
+    LabelRepository, NftOwner, Notification, ReadPool, Reorg, StoredEvent, Token, TokenHolder,
+    Transfer, TransferStats, TransferView, VolumeBucket, gaps_between,
+};
 use alloy_primitives::utils::format_units;
-use comfy_table::{Cell, Table, modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL};
+use alloy_primitives::{Address, B256, I256, U256};
+use comfy_table::{Cell, Row, Table, modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL};
 use csv::Writer;
 use serde_json::json;
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub enum OutputFormat {
     Table,
     Json,
+    /// One compact JSON object per line
+    JsonLines,
     Csv,
+    /// GitHub-flavored pipe tables, for pasting into issues and docs
+    Markdown,
 }
 
-impl From<&str> for OutputFormat {
-    fn from(s: &str) -> Self {
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
         match s.to_lowercase().as_str() {
-            "json" => OutputFormat::Json,
-            "csv" => OutputFormat::Csv,
-            _ => OutputFormat::Table,
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "csv" => Ok(OutputFormat::Csv),
+            "markdown" | "md" => Ok(OutputFormat::Markdown),
+            _ => anyhow::bail!(
+                "Unknown output format {s}, expected one of: table, json, jsonl, csv, markdown"
+            ),
+        }
+    }
+}
+
+/// How transaction hashes and addresses are shown
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Shorten hashes to `0x1234...abcd` in table and markdown output
+    pub truncate_hashes: bool,
+    /// e.g. `https://etherscan.io/tx/{hash}`. JSON and CSV output give the
+    /// transaction's URL in place of the bare hash.
+    pub explorer_url_template: Option<String>,
+    /// Where address labels are looked up, None when none are stored
+    label_source: Option<Arc<ReadPool>>,
+    /// Show labelled addresses in tables by their label alone
+    labels_only: bool,
+}
+
+impl FormatOptions {
+    pub fn new(full_hashes: bool, explorer_url_template: Option<String>) -> anyhow::Result<Self> {
+        if let Some(template) = &explorer_url_template
+            && !template.contains("{hash}")
+        {
+            anyhow::bail!("Explorer URL template {template} has no {{hash}} placeholder");
+        }
+
+        Ok(Self {
+            truncate_hashes: !full_hashes,
+            explorer_url_template,
+            label_source: None,
+            labels_only: false,
+        })
+    }
+
+    /// Show the labels of `address_labels` with the addresses of transfers,
+    /// holders and counterparties. Outputs only change once a label is stored.
+    pub fn with_labels(
+        mut self,
+        readers: Arc<ReadPool>,
+        labels_only: bool,
+    ) -> anyhow::Result<Self> {
+        if LabelRepository::new(&*readers.blocking_get()?).count()? > 0 {
+            self.label_source = Some(readers);
+        }
+        self.labels_only = labels_only;
+        Ok(self)
+    }
+
+    /// Labels of the addresses about to be formatted, in one batched lookup
+    pub fn lookup_labels<'a>(
+        &self,
+        addresses: impl IntoIterator<Item = &'a Address>,
+    ) -> anyhow::Result<AddressLabels> {
+        let Some(readers) = &self.label_source else {
+            return Ok(AddressLabels::default());
+        };
+        Ok(AddressLabels {
+            enabled: true,
+            labels_only: self.labels_only,
+            labels: LabelRepository::new(&*readers.blocking_get()?).lookup(addresses)?,
+        })
+    }
+
+    /// Full hash, or its explorer URL when a template is set
+    fn tx_hash_or_url(&self, hash: &B256) -> String {
+        let hash = format!("{hash:?}");
+        match &self.explorer_url_template {
+            Some(template) => template.replace("{hash}", &hash),
+            None => hash,
+        }
+    }
+
+    fn display_tx_hash(&self, hash: &B256) -> String {
+        let hash = format!("{hash:?}");
+        if self.truncate_hashes {
+            format_tx_hash(&hash)
+        } else {
+            hash
+        }
+    }
+}
+
+/// Labels of the addresses of the rows being formatted. Tables show a label
+/// after the address, or in its place with `labels_only`; JSON and CSV give
+/// it in a field of its own, empty for an address without one.
+#[derive(Debug, Clone, Default)]
+pub struct AddressLabels {
+    /// Whether outputs get label fields, which is once any label is stored
+    enabled: bool,
+    labels_only: bool,
+    labels: HashMap<Address, String>,
+}
+
+impl AddressLabels {
+    pub fn get(&self, address: &Address) -> Option<&str> {
+        self.labels.get(address).map(String::as_str)
+    }
+
+    /// Shortened address for a table cell, with its label
+    fn cell(&self, address: &Address, format: &OutputFormat) -> String {
+        let shown = inline_code(format!("{address:#}"), format);
+        match self.get(address) {
+            Some(label) if self.labels_only => label.to_string(),
+            Some(label) => format!("{shown} ({label})"),
+            None => shown,
+        }
+    }
+
+    /// Add the label of `address` to a JSON object under `key`
+    fn add_to_json(&self, value: &mut serde_json::Value, key: &str, address: &Address) {
+        if self.enabled {
+            value[key] = json!(self.get(address));
         }
     }
+
+    /// CSV column of the label of `address`
+    fn csv_field(&self, address: &Address) -> String {
+        self.get(address).unwrap_or_default().to_string()
+    }
+}
+
+/// Pretty-printed for `Json`. For `JsonLines` every element of an array goes on
+/// its own line, and any other value on a single line.
+fn render_json(value: serde_json::Value, format: &OutputFormat) -> String {
+    match (format, value) {
+        (OutputFormat::JsonLines, serde_json::Value::Array(items)) => items
+            .iter()
+            .map(|item| item.to_string())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        (OutputFormat::JsonLines, value) => value.to_string(),
+        (_, value) => serde_json::to_string_pretty(&value).unwrap_or_default(),
+    }
+}
+
+/// Render a table for the terminal, or for `Markdown` as a pipe table with the
+/// `right_aligned` columns aligned right
+fn render_table(table: &Table, right_aligned: &[usize], format: &OutputFormat) -> String {
+    let OutputFormat::Markdown = format else {
+        return table.to_string();
+    };
+
+    let cells = |row: &Row| -> Vec<String> {
+        row.cell_iter()
+            .map(|cell| cell.content().replace('|', "\\|"))
+            .collect()
+    };
+    let line = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+
+    let mut lines = Vec::new();
+    if let Some(header) = table.header() {
+        let header = cells(header);
+        let separator = (0..header.len())
+            .map(|i| {
+                if right_aligned.contains(&i) {
+                    "---:".to_string()
+                } else {
+                    "---".to_string()
+                }
+            })
+            .collect();
+        lines.push(line(header));
+        lines.push(line(separator));
+    }
+    lines.extend(table.row_iter().map(|row| line(cells(row))));
+
+    lines.join("\n")
+}
+
+/// Addresses and hashes go in backticks in Markdown so they aren't autolinked
+/// or reflowed
+fn inline_code(text: String, format: &OutputFormat) -> String {
+    match format {
+        OutputFormat::Markdown => format!("`{text}`"),
+        _ => text,
+    }
 }
 
 pub fn format_transfers(
     transfers: &[TransferView],
     decimals: Option<u8>,
+    options: &FormatOptions,
+    labels: &AddressLabels,
     format: &OutputFormat,
 ) -> String {
     match format {
-        OutputFormat::Table => format_transfers_table(transfers, decimals),
-        OutputFormat::Json => format_transfers_json(transfers, decimals),
-        OutputFormat::Csv => format_transfers_csv(transfers, decimals),
+        OutputFormat::Table | OutputFormat::Markdown => {
+            format_transfers_table(transfers, decimals, options, labels, format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            format_transfers_json(transfers, decimals, options, labels, format)
+        }
+        OutputFormat::Csv => format_transfers_csv(transfers, decimals, options, labels),
     }
 }
 
-fn format_transfers_table(transfers: &[TransferView], decimals: Option<u8>) -> String {
+fn format_transfers_table(
+    transfers: &[TransferView],
+    decimals: Option<u8>,
+    options: &FormatOptions,
+    labels: &AddressLabels,
+    format: &OutputFormat,
+) -> String {
     if transfers.is_empty() {
         return "No transfers found.".to_string();
     }
@@ -57,67 +264,516 @@ fn format_transfers_table(transfers: &[TransferView], decimals: Option<u8>) -> S
             format_units(transfer.value, decimals).unwrap_or_else(|_| transfer.value.to_string());
         table.add_row(vec![
             Cell::new(transfer.block_number),
-            Cell::new(format!("{:#}", transfer.from_address)),
-            Cell::new(format!("{:#}", transfer.to_address)),
+            Cell::new(labels.cell(&transfer.from_address, format)),
+            Cell::new(labels.cell(&transfer.to_address, format)),
             Cell::new(formatted_value),
             Cell::new(transfer.value.to_string()),
-            Cell::new(format_tx_hash(&format!("{:?}", transfer.transaction_hash))),
+            Cell::new(inline_code(
+                options.display_tx_hash(&transfer.transaction_hash),
+                format,
+            )),
         ]);
     }
 
-    table.to_string()
+    render_table(&table, &[0, 3, 4], format)
 }
 
-fn format_transfers_json(transfers: &[TransferView], decimals: Option<u8>) -> String {
-    let decimals = decimals.unwrap_or(18);
-    let json_transfers: Vec<_> = transfers
+fn format_transfers_json(
+    transfers: &[TransferView],
+    decimals: Option<u8>,
+    options: &FormatOptions,
+    labels: &AddressLabels,
+    format: &OutputFormat,
+) -> String {
+    render_json(
+        json!(transfers_to_json(transfers, decimals, options, labels)),
+        format,
+    )
+}
+
+fn transfers_to_json(
+    transfers: &[TransferView],
+    decimals: Option<u8>,
+    options: &FormatOptions,
+    labels: &AddressLabels,
+) -> Vec<serde_json::Value> {
+    transfers
         .iter()
-        .map(|t| {
-            let formatted_value =
-                format_units(t.value, decimals).unwrap_or_else(|_| t.value.to_string());
-            json!({
-                "block_number": t.block_number,
-                "transaction_hash": format!("{:?}", t.transaction_hash),
-                "from": format!("{:?}", t.from_address),
-                "to": format!("{:?}", t.to_address),
-                "value": formatted_value,
-                "value_wei": t.value.to_string(),
-            })
+        .map(|t| transfer_to_json(t, decimals, options, labels))
+        .collect()
+}
+
+/// JSON object of one transfer, shared by the json and jsonl formats
+pub fn transfer_to_json(
+    t: &TransferView,
+    decimals: Option<u8>,
+    options: &FormatOptions,
+    labels: &AddressLabels,
+) -> serde_json::Value {
+    let formatted_value =
+        format_units(t.value, decimals.unwrap_or(18)).unwrap_or_else(|_| t.value.to_string());
+    let mut value = json!({
+        "block_number": t.block_number,
+        "transaction_hash": options.tx_hash_or_url(&t.transaction_hash),
+        "from": format!("{:?}", t.from_address),
+        "to": format!("{:?}", t.to_address),
+        "value": formatted_value,
+        "value_wei": t.value.to_string(),
+    });
+    labels.add_to_json(&mut value, "from_label", &t.from_address);
+    labels.add_to_json(&mut value, "to_label", &t.to_address);
+    value
+}
+
+fn format_transfers_csv(
+    transfers: &[TransferView],
+    decimals: Option<u8>,
+    options: &FormatOptions,
+    labels: &AddressLabels,
+) -> String {
+    let write = || -> anyhow::Result<Vec<u8>> {
+        let mut writer = TransferCsvWriter::new(vec![], decimals, options)?;
+        for transfer in transfers {
+            writer.write(transfer, labels)?;
+        }
+        writer.finish()
+    };
+
+    String::from_utf8(write().unwrap_or_default()).unwrap_or_default()
+}
+
+/// Writes transfers as CSV rows as they arrive, for results too large to
+/// collect first
+pub struct TransferCsvWriter<W: Write> {
+    wtr: Writer<W>,
+    decimals: u8,
+    options: FormatOptions,
+}
+
+impl<W: Write> TransferCsvWriter<W> {
+    /// Label columns are added when `options` shows labels
+    pub fn new(out: W, decimals: Option<u8>, options: &FormatOptions) -> anyhow::Result<Self> {
+        let mut wtr = Writer::from_writer(out);
+        let mut header = vec![
+            "block_number",
+            "from",
+            "to",
+            "value",
+            "value_wei",
+            "transaction_hash",
+        ];
+        if options.label_source.is_some() {
+            header.extend(["from_label", "to_label"]);
+        }
+        wtr.write_record(&header)?;
+
+        Ok(Self {
+            wtr,
+            decimals: decimals.unwrap_or(18),
+            options: options.clone(),
         })
-        .collect();
+    }
 
-    serde_json::to_string_pretty(&json_transfers).unwrap_or_else(|_| "[]".to_string())
+    /// Write one row, with the labels of its addresses from `labels`
+    pub fn write(&mut self, transfer: &TransferView, labels: &AddressLabels) -> anyhow::Result<()> {
+        let formatted_value = format_units(transfer.value, self.decimals)
+            .unwrap_or_else(|_| transfer.value.to_string());
+        let mut record = vec![
+            transfer.block_number.to_string(),
+            format!("{:?}", transfer.from_address),
+            format!("{:?}", transfer.to_address),
+            formatted_value,
+            transfer.value.to_string(),
+            self.options.tx_hash_or_url(&transfer.transaction_hash),
+        ];
+        if self.options.label_source.is_some() {
+            record.push(labels.csv_field(&transfer.from_address));
+            record.push(labels.csv_field(&transfer.to_address));
+        }
+        self.wtr.write_record(&record)?;
+        Ok(())
+    }
+
+    /// Flush the remaining rows and hand back the sink
+    pub fn finish(self) -> anyhow::Result<W> {
+        self.wtr
+            .into_inner()
+            .map_err(|e| anyhow::anyhow!("Failed to flush CSV output: {}", e.error()))
+    }
 }
 
-fn format_transfers_csv(transfers: &[TransferView], decimals: Option<u8>) -> String {
-    let decimals = decimals.unwrap_or(18);
-    let mut wtr = Writer::from_writer(vec![]);
+/// Transfers of one address, each with the side the address was on and the
+/// address on the other side. Values are signed by their effect on the
+/// address's balance: + received, - sent. With an `opening_balance`, the
+/// balance before the first entry, every row also gets the balance after it.
+pub fn format_address_history(
+    entries: &[AddressHistoryEntry],
+    decimals: Option<u8>,
+    opening_balance: Option<I256>,
+    options: &FormatOptions,
+    format: &OutputFormat,
+) -> String {
+    let mut running_balance = RunningBalance::new(opening_balance);
 
-    // Write header
-    let _ = wtr.write_record([
-        "block_number",
-        "from",
-        "to",
-        "value",
-        "value_wei",
-        "transaction_hash",
-    ]);
-
-    // Write records
-    for transfer in transfers {
-        let formatted_value =
-            format_units(transfer.value, decimals).unwrap_or_else(|_| transfer.value.to_string());
-        let _ = wtr.write_record([
-            &transfer.block_number.to_string(),
-            &format!("{:?}", transfer.from_address),
-            &format!("{:?}", transfer.to_address),
-            &formatted_value,
-            &transfer.value.to_string(),
-            &format!("{:?}", transfer.transaction_hash),
-        ]);
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            if entries.is_empty() {
+                return "No transfers found.".to_string();
+            }
+
+            let mut header = vec!["Block", "Direction", "Counterparty", "Value", "Value (Wei)"];
+            if opening_balance.is_some() {
+                header.push("Balance");
+            }
+            header.push("Tx Hash");
+
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(header);
+
+            for entry in entries {
+                let mut row = vec![
+                    Cell::new(entry.transfer.block_number),
+                    Cell::new(entry.direction.as_str()),
+                    Cell::new(inline_code(format!("{:#}", entry.counterparty), format)),
+                    Cell::new(signed_value(entry, decimals)),
+                    Cell::new(entry.transfer.value.to_string()),
+                ];
+                if let Some(balance) = running_balance.apply(entry) {
+                    row.push(Cell::new(format_signed_units(balance, decimals)));
+                }
+                row.push(Cell::new(inline_code(
+                    options.display_tx_hash(&entry.transfer.transaction_hash),
+                    format,
+                )));
+                table.add_row(row);
+            }
+
+            let right_aligned: &[usize] = match opening_balance {
+                Some(_) => &[0, 3, 4, 5],
+                None => &[0, 3, 4],
+            };
+            render_table(&table, right_aligned, format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let items: Vec<_> = entries
+                .iter()
+                .map(|entry| {
+                    let balance = running_balance.apply(entry);
+                    address_history_entry_to_json(entry, decimals, balance, options)
+                })
+                .collect();
+            render_json(json!(items), format)
+        }
+        OutputFormat::Csv => {
+            let write = || -> anyhow::Result<Vec<u8>> {
+                let mut writer =
+                    AddressHistoryCsvWriter::new(vec![], decimals, opening_balance, options)?;
+                for entry in entries {
+                    writer.write(entry)?;
+                }
+                writer.finish()
+            };
+
+            String::from_utf8(write().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+/// The value in token units with the sign of its effect on the balance
+fn signed_value(entry: &AddressHistoryEntry, decimals: Option<u8>) -> String {
+    let value = entry.transfer.value;
+    let units = format_units(value, decimals.unwrap_or(18)).unwrap_or_else(|_| value.to_string());
+    format!("{}{units}", entry.direction.sign())
+}
+
+/// A balance in token units, negative when the history doesn't start at the
+/// address's first transfer
+fn format_signed_units(balance: I256, decimals: Option<u8>) -> String {
+    format_units(balance, decimals.unwrap_or(18)).unwrap_or_else(|_| balance.to_string())
+}
+
+/// Balance of the queried address as its history is formatted, None when no
+/// running balance was asked for
+pub struct RunningBalance(Option<I256>);
+
+impl RunningBalance {
+    pub fn new(opening_balance: Option<I256>) -> Self {
+        Self(opening_balance)
     }
 
-    String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+    /// The balance after `entry`
+    pub fn apply(&mut self, entry: &AddressHistoryEntry) -> Option<I256> {
+        let balance = self.0.as_mut()?;
+        *balance = entry.direction.apply(*balance, entry.transfer.value);
+        Some(*balance)
+    }
+}
+
+/// JSON object of one address history entry, shared by the json and jsonl
+/// formats. `balance` is the running balance after the entry, if any.
+pub fn address_history_entry_to_json(
+    entry: &AddressHistoryEntry,
+    decimals: Option<u8>,
+    balance: Option<I256>,
+    options: &FormatOptions,
+) -> serde_json::Value {
+    let t = &entry.transfer;
+    let mut object = json!({
+        "block_number": t.block_number,
+        "transaction_hash": options.tx_hash_or_url(&t.transaction_hash),
+        "direction": entry.direction.as_str(),
+        "counterparty": format!("{:?}", entry.counterparty),
+        "from": format!("{:?}", t.from_address),
+        "to": format!("{:?}", t.to_address),
+        "value": signed_value(entry, decimals),
+        "value_wei": t.value.to_string(),
+    });
+    if let Some(balance) = balance {
+        object["balance"] = json!(format_signed_units(balance, decimals));
+    }
+    object
+}
+
+/// `TransferCsvWriter` for address history rows, with a `balance` column when
+/// given an opening balance
+pub struct AddressHistoryCsvWriter<W: Write> {
+    wtr: Writer<W>,
+    decimals: Option<u8>,
+    running_balance: RunningBalance,
+    options: FormatOptions,
+}
+
+impl<W: Write> AddressHistoryCsvWriter<W> {
+    pub fn new(
+        out: W,
+        decimals: Option<u8>,
+        opening_balance: Option<I256>,
+        options: &FormatOptions,
+    ) -> anyhow::Result<Self> {
+        let mut header = vec![
+            "block_number",
+            "direction",
+            "counterparty",
+            "from",
+            "to",
+            "value",
+            "value_wei",
+        ];
+        if opening_balance.is_some() {
+            header.push("balance");
+        }
+        header.push("transaction_hash");
+
+        let mut wtr = Writer::from_writer(out);
+        wtr.write_record(header)?;
+
+        Ok(Self {
+            wtr,
+            decimals,
+            running_balance: RunningBalance::new(opening_balance),
+            options: options.clone(),
+        })
+    }
+
+    pub fn write(&mut self, entry: &AddressHistoryEntry) -> anyhow::Result<()> {
+        let t = &entry.transfer;
+        let mut record = vec![
+            t.block_number.to_string(),
+            entry.direction.as_str().to_string(),
+            format!("{:?}", entry.counterparty),
+            format!("{:?}", t.from_address),
+            format!("{:?}", t.to_address),
+            signed_value(entry, self.decimals),
+            t.value.to_string(),
+        ];
+        if let Some(balance) = self.running_balance.apply(entry) {
+            record.push(format_signed_units(balance, self.decimals));
+        }
+        record.push(self.options.tx_hash_or_url(&t.transaction_hash));
+        self.wtr.write_record(record)?;
+        Ok(())
+    }
+
+    /// Flush the remaining rows and hand back the sink
+    pub fn finish(self) -> anyhow::Result<W> {
+        self.wtr
+            .into_inner()
+            .map_err(|e| anyhow::anyhow!("Failed to flush CSV output: {}", e.error()))
+    }
+}
+
+/// Transfers of one block followed by the block's totals. CSV output ends with
+/// a `total` row carrying the counts in the address and hash columns.
+pub fn format_block_summary(
+    summary: &BlockSummary,
+    decimals: Option<u8>,
+    options: &FormatOptions,
+    labels: &AddressLabels,
+    format: &OutputFormat,
+) -> String {
+    let units = |amount: U256| {
+        format_units(amount, decimals.unwrap_or(18)).unwrap_or_else(|_| amount.to_string())
+    };
+
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec!["Metric", "Value"]);
+
+            table.add_row(vec![Cell::new("Block"), Cell::new(summary.block_number)]);
+            table.add_row(vec![
+                Cell::new("Transfers"),
+                Cell::new(summary.transfer_count),
+            ]);
+            table.add_row(vec![
+                Cell::new("Total Value"),
+                Cell::new(units(summary.total_value)),
+            ]);
+            table.add_row(vec![
+                Cell::new("Unique Senders"),
+                Cell::new(summary.unique_senders),
+            ]);
+            table.add_row(vec![
+                Cell::new("Unique Receivers"),
+                Cell::new(summary.unique_receivers),
+            ]);
+
+            // Markdown tables need a blank line between them
+            let separator = if let OutputFormat::Markdown = format {
+                "\n\n"
+            } else {
+                "\n"
+            };
+            format!(
+                "{}{separator}{}",
+                format_transfers_table(&summary.transfers, decimals, options, labels, format),
+                render_table(&table, &[1], format)
+            )
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => render_json(
+            json!({
+                "block_number": summary.block_number,
+                "transfers": transfers_to_json(&summary.transfers, decimals, options, labels),
+                "summary": {
+                    "transfer_count": summary.transfer_count,
+                    "total_value": units(summary.total_value),
+                    "total_value_wei": summary.total_value.to_string(),
+                    "unique_senders": summary.unique_senders,
+                    "unique_receivers": summary.unique_receivers,
+                },
+            }),
+            format,
+        ),
+        OutputFormat::Csv => {
+            let mut csv = format_transfers_csv(&summary.transfers, decimals, options, labels);
+
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record([
+                "total".to_string(),
+                format!("{} senders", summary.unique_senders),
+                format!("{} receivers", summary.unique_receivers),
+                units(summary.total_value),
+                summary.total_value.to_string(),
+                format!("{} transfers", summary.transfer_count),
+            ]);
+            csv.push_str(
+                &String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default(),
+            );
+            csv
+        }
+    }
+}
+
+/// Transfers of a single transaction, with their log index and finality
+pub fn format_tx_transfers(
+    transfers: &[Transfer],
+    decimals: Option<u8>,
+    format: &OutputFormat,
+) -> String {
+    let decimals = decimals.unwrap_or(18);
+    let units =
+        |amount: U256| format_units(amount, decimals).unwrap_or_else(|_| amount.to_string());
+
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec![
+                    "Log Index",
+                    "Block",
+                    "From",
+                    "To",
+                    "Value",
+                    "Value (Wei)",
+                    "Finalized",
+                ]);
+
+            for transfer in transfers {
+                table.add_row(vec![
+                    Cell::new(transfer.log_index),
+                    Cell::new(transfer.block_number),
+                    Cell::new(inline_code(format!("{:#}", transfer.from_address), format)),
+                    Cell::new(inline_code(format!("{:#}", transfer.to_address), format)),
+                    Cell::new(units(transfer.value)),
+                    Cell::new(transfer.value.to_string()),
+                    Cell::new(if transfer.is_finalized { "yes" } else { "no" }),
+                ]);
+            }
+
+            render_table(&table, &[0, 1, 4, 5], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let json_transfers: Vec<_> = transfers
+                .iter()
+                .map(|t| {
+                    json!({
+                        "log_index": t.log_index,
+                        "block_number": t.block_number,
+                        "transaction_hash": format!("{:?}", t.transaction_hash),
+                        "from": format!("{:?}", t.from_address),
+                        "to": format!("{:?}", t.to_address),
+                        "value": units(t.value),
+                        "value_wei": t.value.to_string(),
+                        "is_finalized": t.is_finalized,
+                    })
+                })
+                .collect();
+
+            render_json(json!(json_transfers), format)
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record([
+                "log_index",
+                "block_number",
+                "from",
+                "to",
+                "value",
+                "value_wei",
+                "is_finalized",
+            ]);
+            for transfer in transfers {
+                let _ = wtr.write_record([
+                    transfer.log_index.to_string(),
+                    transfer.block_number.to_string(),
+                    format!("{:?}", transfer.from_address),
+                    format!("{:?}", transfer.to_address),
+                    units(transfer.value),
+                    transfer.value.to_string(),
+                    transfer.is_finalized.to_string(),
+                ]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
 }
 
 pub fn format_balance(
@@ -128,9 +784,23 @@ pub fn format_balance(
     let decimals = decimals.unwrap_or(18); // Default to 18 decimals for most ERC20 tokens
     let balance_formatted = format_units(balance_info.balance, decimals)
         .unwrap_or_else(|_| balance_info.balance.to_string());
+    let block_or_na = |block: Option<u64>| block.map_or("N/A".to_string(), |b| b.to_string());
+
+    let activity = [
+        (
+            "incoming_transfers",
+            balance_info.incoming_count.to_string(),
+        ),
+        (
+            "outgoing_transfers",
+            balance_info.outgoing_count.to_string(),
+        ),
+        ("first_block", block_or_na(balance_info.first_block)),
+        ("last_block", block_or_na(balance_info.last_block)),
+    ];
 
     match format {
-        OutputFormat::Table => {
+        OutputFormat::Table | OutputFormat::Markdown => {
             let mut table = Table::new();
             table
                 .load_preset(UTF8_FULL)
@@ -142,11 +812,26 @@ pub fn format_balance(
                 Cell::new(&balance_formatted),
                 Cell::new(balance_info.balance.to_string()),
             ]);
-            table.to_string()
+
+            let labels = [
+                "Incoming Transfers",
+                "Outgoing Transfers",
+                "First Activity Block",
+                "Last Activity Block",
+            ];
+            for (label, (_, value)) in labels.iter().zip(&activity) {
+                table.add_row(vec![Cell::new(label), Cell::new(value), Cell::new("")]);
+            }
+
+            render_table(&table, &[1, 2], format)
         }
-        OutputFormat::Json => json!({
+        OutputFormat::Json | OutputFormat::JsonLines => json!({
             "balance": balance_formatted,
             "balance_wei": balance_info.balance.to_string(),
+            "incoming_transfers": balance_info.incoming_count,
+            "outgoing_transfers": balance_info.outgoing_count,
+            "first_block": balance_info.first_block,
+            "last_block": balance_info.last_block,
         })
         .to_string(),
         OutputFormat::Csv => {
@@ -157,6 +842,96 @@ pub fn format_balance(
                 &balance_formatted,
                 &balance_info.balance.to_string(),
             ]);
+            for (metric, value) in &activity {
+                let _ = wtr.write_record([*metric, value.as_str(), ""]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+/// An on-chain `balanceOf` next to the indexed balance at the same block
+pub struct BalanceComparison {
+    pub address: String,
+    pub block_number: u64,
+    pub on_chain: U256,
+    /// None when the index hasn't reached the block
+    pub indexed: Option<U256>,
+}
+
+pub fn format_balance_comparison(
+    comparison: &BalanceComparison,
+    decimals: Option<u8>,
+    format: &OutputFormat,
+) -> String {
+    let decimals = decimals.unwrap_or(18);
+    let units = |value: U256| format_units(value, decimals).unwrap_or_else(|_| value.to_string());
+    let delta = comparison.indexed.map(|indexed| {
+        if indexed >= comparison.on_chain {
+            ("+", indexed - comparison.on_chain)
+        } else {
+            ("-", comparison.on_chain - indexed)
+        }
+    });
+    let na = || "N/A".to_string();
+
+    let rows = [
+        (
+            "on_chain",
+            units(comparison.on_chain),
+            comparison.on_chain.to_string(),
+        ),
+        (
+            "indexed",
+            comparison.indexed.map_or_else(na, units),
+            comparison.indexed.map_or_else(na, |v| v.to_string()),
+        ),
+        (
+            "delta",
+            delta.map_or_else(na, |(sign, v)| format!("{sign}{}", units(v))),
+            delta.map_or_else(na, |(sign, v)| format!("{sign}{v}")),
+        ),
+    ];
+
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec![
+                    format!("Block {}", comparison.block_number),
+                    "Value (Formatted)".to_string(),
+                    "Value (Wei)".to_string(),
+                ]);
+            let labels = ["On-chain", "Indexed", "Delta"];
+            for (label, (_, formatted, wei)) in labels.iter().zip(&rows) {
+                table.add_row(vec![Cell::new(label), Cell::new(formatted), Cell::new(wei)]);
+            }
+            render_table(&table, &[1, 2], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            // N/A becomes null
+            let [on_chain, indexed, delta_row] = &rows;
+            let known = comparison.indexed.is_some();
+            let value = json!({
+                "address": comparison.address,
+                "block": comparison.block_number,
+                "on_chain": on_chain.1,
+                "on_chain_wei": on_chain.2,
+                "indexed": known.then_some(&indexed.1),
+                "indexed_wei": known.then_some(&indexed.2),
+                "delta": known.then_some(&delta_row.1),
+                "delta_wei": known.then_some(&delta_row.2),
+            });
+            render_json(value, format)
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record(["metric", "value_formatted", "value_wei"]);
+            for (metric, formatted, wei) in &rows {
+                let _ = wtr.write_record([*metric, formatted.as_str(), wei.as_str()]);
+            }
             String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
         }
     }
@@ -165,42 +940,73 @@ pub fn format_balance(
 pub fn format_top_holders(
     holders: Vec<TokenHolder>,
     decimals: Option<u8>,
+    labels: &AddressLabels,
     format: &OutputFormat,
 ) -> String {
     match format {
-        OutputFormat::Table => format_top_holders_table(&holders, decimals),
-        OutputFormat::Json => format_top_holders_json(&holders, decimals),
-        OutputFormat::Csv => format_top_holders_csv(&holders, decimals),
+        OutputFormat::Table | OutputFormat::Markdown => {
+            format_top_holders_table(&holders, decimals, labels, format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            format_top_holders_json(&holders, decimals, labels, format)
+        }
+        OutputFormat::Csv => format_top_holders_csv(&holders, decimals, labels),
     }
 }
 
-fn format_top_holders_table(holders: &[TokenHolder], decimals: Option<u8>) -> String {
+fn format_share(share: f64) -> String {
+    format!("{share:.2}%")
+}
+
+fn format_top_holders_table(
+    holders: &[TokenHolder],
+    decimals: Option<u8>,
+    labels: &AddressLabels,
+    format: &OutputFormat,
+) -> String {
     if holders.is_empty() {
         return "No holders found.".to_string();
     }
 
     let decimals = decimals.unwrap_or(18);
+    let with_share = holders.iter().any(|h| h.share.is_some());
+    let mut header = vec!["Rank", "Address", "Balance", "Balance (Wei)"];
+    if with_share {
+        header.push("Share");
+    }
+
     let mut table = Table::new();
     table
         .load_preset(UTF8_FULL)
         .apply_modifier(UTF8_ROUND_CORNERS)
-        .set_header(vec!["Rank", "Address", "Balance", "Balance (Wei)"]);
+        .set_header(header);
 
     for (i, holder) in holders.iter().enumerate() {
         let formatted_balance =
             format_units(holder.balance, decimals).unwrap_or_else(|_| holder.balance.to_string());
-        table.add_row(vec![
+        let mut row = vec![
             Cell::new(i + 1),
-            Cell::new(format!("{:#}", &holder.address)),
+            Cell::new(labels.cell(&holder.address, format)),
             Cell::new(formatted_balance),
             Cell::new(holder.balance.to_string()),
-        ]);
+        ];
+        if with_share {
+            row.push(Cell::new(
+                holder.share.map(format_share).unwrap_or_default(),
+            ));
+        }
+        table.add_row(row);
     }
 
-    table.to_string()
+    render_table(&table, &[0, 2, 3, 4], format)
 }
 
-fn format_top_holders_json(holders: &[TokenHolder], decimals: Option<u8>) -> String {
+fn format_top_holders_json(
+    holders: &[TokenHolder],
+    decimals: Option<u8>,
+    labels: &AddressLabels,
+    format: &OutputFormat,
+) -> String {
     let decimals = decimals.unwrap_or(18);
     let json_holders: Vec<_> = holders
         .iter()
@@ -208,41 +1014,98 @@ fn format_top_holders_json(holders: &[TokenHolder], decimals: Option<u8>) -> Str
         .map(|(i, holder)| {
             let formatted = format_units(holder.balance, decimals)
                 .unwrap_or_else(|_| holder.balance.to_string());
-            json!({
+            let mut value = json!({
                 "rank": i + 1,
                 "address": holder.address,
                 "balance": formatted,
                 "balance_wei": holder.balance.to_string(),
-            })
+            });
+            if let Some(share) = holder.share {
+                value["share_percent"] = json!((share * 100.0).round() / 100.0);
+            }
+            labels.add_to_json(&mut value, "label", &holder.address);
+            value
         })
         .collect();
 
-    serde_json::to_string_pretty(&json_holders).unwrap_or_else(|_| "[]".to_string())
+    render_json(json!(json_holders), format)
 }
 
-fn format_top_holders_csv(holders: &[TokenHolder], decimals: Option<u8>) -> String {
+fn format_top_holders_csv(
+    holders: &[TokenHolder],
+    decimals: Option<u8>,
+    labels: &AddressLabels,
+) -> String {
     let decimals = decimals.unwrap_or(18);
+    let with_share = holders.iter().any(|h| h.share.is_some());
     let mut wtr = Writer::from_writer(vec![]);
 
-    let _ = wtr.write_record(["rank", "address", "balance", "balance_wei"]);
+    let mut header = vec!["rank", "address", "balance", "balance_wei"];
+    if with_share {
+        header.push("share_percent");
+    }
+    if labels.enabled {
+        header.push("label");
+    }
+    let _ = wtr.write_record(&header);
 
     for (i, holder) in holders.iter().enumerate() {
         let formatted =
             format_units(holder.balance, decimals).unwrap_or_else(|_| holder.balance.to_string());
-        let _ = wtr.write_record([
-            &(i + 1).to_string(),
-            &format!("{:?}", holder.address),
-            &formatted,
-            &holder.balance.to_string(),
-        ]);
+        let mut record = vec![
+            (i + 1).to_string(),
+            format!("{:?}", holder.address),
+            formatted,
+            holder.balance.to_string(),
+        ];
+        if with_share {
+            record.push(
+                holder
+                    .share
+                    .map(|share| format!("{share:.2}"))
+                    .unwrap_or_default(),
+            );
+        }
+        if labels.enabled {
+            record.push(labels.csv_field(&holder.address));
+        }
+        let _ = wtr.write_record(&record);
     }
 
     String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
 }
 
-pub fn format_stats(stats: &TransferStats, format: &OutputFormat) -> String {
+pub fn format_distribution(
+    distribution: &Distribution,
+    decimals: Option<u8>,
+    format: &OutputFormat,
+) -> String {
+    let decimals = decimals.unwrap_or(18);
+    let units =
+        |amount: U256| format_units(amount, decimals).unwrap_or_else(|_| amount.to_string());
+
+    let mut rows = vec![
+        (
+            "total_holders".to_string(),
+            distribution.total_holders.to_string(),
+        ),
+        (
+            "total_balance".to_string(),
+            units(distribution.total_balance),
+        ),
+    ];
+    for (threshold, count) in &distribution.holders_above {
+        rows.push((
+            format!("holders_above_{}", units(*threshold)),
+            count.to_string(),
+        ));
+    }
+    for (n, share) in &distribution.top_shares {
+        rows.push((format!("top_{n}_share"), format_share(*share)));
+    }
+
     match format {
-        OutputFormat::Table => {
+        OutputFormat::Table | OutputFormat::Markdown => {
             let mut table = Table::new();
             table
                 .load_preset(UTF8_FULL)
@@ -250,7 +1113,256 @@ pub fn format_stats(stats: &TransferStats, format: &OutputFormat) -> String {
                 .set_header(vec!["Metric", "Value"]);
 
             table.add_row(vec![
-                Cell::new("Total Transfers"),
+                Cell::new("Total Holders"),
+                Cell::new(distribution.total_holders),
+            ]);
+            table.add_row(vec![
+                Cell::new("Total Balance"),
+                Cell::new(units(distribution.total_balance)),
+            ]);
+            for (threshold, count) in &distribution.holders_above {
+                table.add_row(vec![
+                    Cell::new(format!("Holders >= {}", units(*threshold))),
+                    Cell::new(count),
+                ]);
+            }
+            for (n, share) in &distribution.top_shares {
+                table.add_row(vec![
+                    Cell::new(format!("Top {n} Share")),
+                    Cell::new(format_share(*share)),
+                ]);
+            }
+
+            render_table(&table, &[1], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => render_json(
+            json!({
+                "total_holders": distribution.total_holders,
+                "total_balance": units(distribution.total_balance),
+                "holders_above": distribution
+                    .holders_above
+                    .iter()
+                    .map(|(threshold, count)| json!({
+                        "threshold": units(*threshold),
+                        "holders": count,
+                    }))
+                    .collect::<Vec<_>>(),
+                "top_shares": distribution
+                    .top_shares
+                    .iter()
+                    .map(|(n, share)| json!({
+                        "top": n,
+                        "share_percent": (share * 100.0).round() / 100.0,
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+            format,
+        ),
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record(["metric", "value"]);
+            for (metric, value) in &rows {
+                let _ = wtr.write_record([metric, value]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+pub fn format_counterparties(
+    counterparties: &[Counterparty],
+    decimals: Option<u8>,
+    labels: &AddressLabels,
+    format: &OutputFormat,
+) -> String {
+    let decimals = decimals.unwrap_or(18);
+    let units =
+        |amount: U256| format_units(amount, decimals).unwrap_or_else(|_| amount.to_string());
+
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            if counterparties.is_empty() {
+                return "No counterparties found.".to_string();
+            }
+
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec![
+                    "Rank",
+                    "Counterparty",
+                    "Sent",
+                    "Sent Total",
+                    "Received",
+                    "Received Total",
+                    "Volume",
+                ]);
+
+            for (i, counterparty) in counterparties.iter().enumerate() {
+                table.add_row(vec![
+                    Cell::new(i + 1),
+                    Cell::new(labels.cell(&counterparty.address, format)),
+                    Cell::new(counterparty.sent_count),
+                    Cell::new(units(counterparty.sent_total)),
+                    Cell::new(counterparty.received_count),
+                    Cell::new(units(counterparty.received_total)),
+                    Cell::new(units(counterparty.total_volume())),
+                ]);
+            }
+
+            render_table(&table, &[0, 2, 3, 4, 5, 6], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let json_counterparties: Vec<_> = counterparties
+                .iter()
+                .enumerate()
+                .map(|(i, c)| {
+                    let mut value = json!({
+                        "rank": i + 1,
+                        "address": format!("{:?}", c.address),
+                        "sent_count": c.sent_count,
+                        "sent_total": units(c.sent_total),
+                        "received_count": c.received_count,
+                        "received_total": units(c.received_total),
+                        "volume": units(c.total_volume()),
+                    });
+                    labels.add_to_json(&mut value, "label", &c.address);
+                    value
+                })
+                .collect();
+
+            render_json(json!(json_counterparties), format)
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let mut header = vec![
+                "rank",
+                "address",
+                "sent_count",
+                "sent_total",
+                "received_count",
+                "received_total",
+                "volume",
+            ];
+            if labels.enabled {
+                header.push("label");
+            }
+            let _ = wtr.write_record(&header);
+            for (i, c) in counterparties.iter().enumerate() {
+                let mut record = vec![
+                    (i + 1).to_string(),
+                    format!("{:?}", c.address),
+                    c.sent_count.to_string(),
+                    units(c.sent_total),
+                    c.received_count.to_string(),
+                    units(c.received_total),
+                    units(c.total_volume()),
+                ];
+                if labels.enabled {
+                    record.push(labels.csv_field(&c.address));
+                }
+                let _ = wtr.write_record(&record);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+/// Transfer volume per block bucket. Buckets are labelled by their first and
+/// last block, `bucket_blocks` wide.
+pub fn format_volume(
+    buckets: &[VolumeBucket],
+    bucket_blocks: u64,
+    decimals: Option<u8>,
+    format: &OutputFormat,
+) -> String {
+    let decimals = decimals.unwrap_or(18);
+    let units =
+        |amount: U256| format_units(amount, decimals).unwrap_or_else(|_| amount.to_string());
+    let end_block = |bucket: &VolumeBucket| bucket.bucket_start_block + bucket_blocks - 1;
+
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec![
+                    "Start Block",
+                    "End Block",
+                    "Transfers",
+                    "Volume",
+                    "Senders",
+                    "Receivers",
+                ]);
+
+            for bucket in buckets {
+                table.add_row(vec![
+                    Cell::new(bucket.bucket_start_block),
+                    Cell::new(end_block(bucket)),
+                    Cell::new(bucket.transfer_count),
+                    Cell::new(units(bucket.total_value)),
+                    Cell::new(bucket.unique_senders),
+                    Cell::new(bucket.unique_receivers),
+                ]);
+            }
+
+            render_table(&table, &[0, 1, 2, 3, 4, 5], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let json_buckets: Vec<_> = buckets
+                .iter()
+                .map(|bucket| {
+                    json!({
+                        "start_block": bucket.bucket_start_block,
+                        "end_block": end_block(bucket),
+                        "transfer_count": bucket.transfer_count,
+                        "volume": units(bucket.total_value),
+                        "unique_senders": bucket.unique_senders,
+                        "unique_receivers": bucket.unique_receivers,
+                    })
+                })
+                .collect();
+
+            render_json(json!(json_buckets), format)
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record([
+                "start_block",
+                "end_block",
+                "transfer_count",
+                "volume",
+                "unique_senders",
+                "unique_receivers",
+            ]);
+            for bucket in buckets {
+                let _ = wtr.write_record([
+                    bucket.bucket_start_block.to_string(),
+                    end_block(bucket).to_string(),
+                    bucket.transfer_count.to_string(),
+                    units(bucket.total_value),
+                    bucket.unique_senders.to_string(),
+                    bucket.unique_receivers.to_string(),
+                ]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+pub fn format_stats(stats: &TransferStats, format: &OutputFormat) -> String {
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec!["Metric", "Value"]);
+
+            table.add_row(vec![
+                Cell::new("Total Transfers"),
                 Cell::new(stats.total_transfers),
             ]);
             table.add_row(vec![
@@ -274,15 +1386,17 @@ pub fn format_stats(stats: &TransferStats, format: &OutputFormat) -> String {
                 ),
             ]);
 
-            table.to_string()
+            render_table(&table, &[1], format)
         }
-        OutputFormat::Json => serde_json::to_string_pretty(&json!({
-            "total_transfers": stats.total_transfers,
-            "unique_addresses": stats.unique_addresses,
-            "earliest_block": stats.earliest_block,
-            "latest_block": stats.latest_block,
-        }))
-        .unwrap_or_else(|_| "{}".to_string()),
+        OutputFormat::Json | OutputFormat::JsonLines => render_json(
+            json!({
+                "total_transfers": stats.total_transfers,
+                "unique_addresses": stats.unique_addresses,
+                "earliest_block": stats.earliest_block,
+                "latest_block": stats.latest_block,
+            }),
+            format,
+        ),
         OutputFormat::Csv => {
             let mut wtr = Writer::from_writer(vec![]);
             let _ = wtr.write_record(["metric", "value"]);
@@ -305,6 +1419,829 @@ pub fn format_stats(stats: &TransferStats, format: &OutputFormat) -> String {
     }
 }
 
+/// Watchlist notifications with their delivery state
+pub fn format_notifications(
+    notifications: &[Notification],
+    decimals: Option<u8>,
+    options: &FormatOptions,
+    format: &OutputFormat,
+) -> String {
+    let decimals = decimals.unwrap_or(18);
+    let units =
+        |amount: U256| format_units(amount, decimals).unwrap_or_else(|_| amount.to_string());
+
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            if notifications.is_empty() {
+                return "No notifications found.".to_string();
+            }
+
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec![
+                    "Block", "Tx Hash", "From", "To", "Value", "Status", "Attempts",
+                ]);
+
+            for n in notifications {
+                table.add_row(vec![
+                    Cell::new(n.block_number),
+                    Cell::new(inline_code(
+                        options.display_tx_hash(&n.transaction_hash),
+                        format,
+                    )),
+                    Cell::new(inline_code(format!("{:#}", n.from_address), format)),
+                    Cell::new(inline_code(format!("{:#}", n.to_address), format)),
+                    Cell::new(units(n.value)),
+                    Cell::new(n.status.as_str()),
+                    Cell::new(n.attempts),
+                ]);
+            }
+
+            render_table(&table, &[0, 4, 6], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let json_notifications: Vec<_> = notifications
+                .iter()
+                .map(|n| {
+                    json!({
+                        "block_number": n.block_number,
+                        "transaction_hash": options.tx_hash_or_url(&n.transaction_hash),
+                        "log_index": n.log_index,
+                        "from": format!("{:?}", n.from_address),
+                        "to": format!("{:?}", n.to_address),
+                        "value": units(n.value),
+                        "value_wei": n.value.to_string(),
+                        "is_finalized": n.is_finalized,
+                        "status": n.status.as_str(),
+                        "attempts": n.attempts,
+                        "created_at": n.created_at,
+                        "sent_at": n.sent_at,
+                    })
+                })
+                .collect();
+
+            render_json(json!(json_notifications), format)
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record([
+                "block_number",
+                "transaction_hash",
+                "log_index",
+                "from",
+                "to",
+                "value",
+                "value_wei",
+                "is_finalized",
+                "status",
+                "attempts",
+                "created_at",
+                "sent_at",
+            ]);
+            for n in notifications {
+                let _ = wtr.write_record([
+                    n.block_number.to_string(),
+                    options.tx_hash_or_url(&n.transaction_hash),
+                    n.log_index.to_string(),
+                    format!("{:?}", n.from_address),
+                    format!("{:?}", n.to_address),
+                    units(n.value),
+                    n.value.to_string(),
+                    n.is_finalized.to_string(),
+                    n.status.as_str().to_string(),
+                    n.attempts.to_string(),
+                    n.created_at.to_string(),
+                    n.sent_at.map(|t| t.to_string()).unwrap_or_default(),
+                ]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+/// Blocks whose transfers were replaced after a reorg. A missing hash means
+/// that side had no transfers in the block.
+pub fn format_integrity_problems(problems: &[IntegrityProblem], format: &OutputFormat) -> String {
+    let address = |problem: &IntegrityProblem| {
+        problem
+            .address
+            .map_or(String::new(), |address| format!("{address:?}"))
+    };
+
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            if problems.is_empty() {
+                return "No integrity problems found.".to_string();
+            }
+
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec!["Check", "Address", "Detail"]);
+
+            for problem in problems {
+                let address = match problem.address {
+                    Some(address) => inline_code(format!("{address:#}"), format),
+                    None => "-".to_string(),
+                };
+                table.add_row(vec![
+                    Cell::new(problem.check),
+                    Cell::new(address),
+                    Cell::new(&problem.detail),
+                ]);
+            }
+
+            render_table(&table, &[], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let json_problems: Vec<_> = problems
+                .iter()
+                .map(|problem| {
+                    json!({
+                        "check": problem.check,
+                        "address": problem.address.map(|address| format!("{address:?}")),
+                        "detail": problem.detail,
+                    })
+                })
+                .collect();
+
+            render_json(json!(json_problems), format)
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record(["check", "address", "detail"]);
+            for problem in problems {
+                let _ = wtr.write_record([problem.check, &address(problem), &problem.detail]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+/// ERC-721 tokens and their current owners
+pub fn format_nft_owners(owners: &[NftOwner], format: &OutputFormat) -> String {
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            if owners.is_empty() {
+                return "No tokens found.".to_string();
+            }
+
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec!["Token ID", "Owner", "Since Block"]);
+
+            for owner in owners {
+                table.add_row(vec![
+                    Cell::new(owner.token_id),
+                    Cell::new(inline_code(format!("{:#}", owner.owner), format)),
+                    Cell::new(owner.block_number),
+                ]);
+            }
+
+            render_table(&table, &[0, 2], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let json_owners: Vec<_> = owners
+                .iter()
+                .map(|owner| {
+                    json!({
+                        "token_id": owner.token_id.to_string(),
+                        "owner": format!("{:?}", owner.owner),
+                        "block_number": owner.block_number,
+                    })
+                })
+                .collect();
+
+            render_json(json!(json_owners), format)
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record(["token_id", "owner", "block_number"]);
+            for owner in owners {
+                let _ = wtr.write_record([
+                    owner.token_id.to_string(),
+                    format!("{:?}", owner.owner),
+                    owner.block_number.to_string(),
+                ]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+/// Custom events with their decoded fields, which JSON output nests as an
+/// object and the other formats show as its JSON text
+pub fn format_events(
+    events: &[StoredEvent],
+    options: &FormatOptions,
+    format: &OutputFormat,
+) -> String {
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            if events.is_empty() {
+                return "No events found.".to_string();
+            }
+
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec![
+                    "Block",
+                    "Tx Hash",
+                    "Log",
+                    "Event",
+                    "Fields",
+                    "Finalized",
+                ]);
+
+            for event in events {
+                table.add_row(vec![
+                    Cell::new(event.block_number),
+                    Cell::new(inline_code(
+                        options.display_tx_hash(&event.transaction_hash),
+                        format,
+                    )),
+                    Cell::new(event.log_index),
+                    Cell::new(&event.event.name),
+                    Cell::new(inline_code(event.event.fields.clone(), format)),
+                    Cell::new(if event.is_finalized { "yes" } else { "no" }),
+                ]);
+            }
+
+            render_table(&table, &[0, 2], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let json_events: Vec<_> = events
+                .iter()
+                .map(|event| {
+                    let fields = serde_json::from_str::<serde_json::Value>(&event.event.fields)
+                        .unwrap_or_else(|_| json!(event.event.fields));
+                    json!({
+                        "block_number": event.block_number,
+                        "transaction_hash": options.tx_hash_or_url(&event.transaction_hash),
+                        "log_index": event.log_index,
+                        "event": event.event.name,
+                        "fields": fields,
+                        "is_finalized": event.is_finalized,
+                    })
+                })
+                .collect();
+
+            render_json(json!(json_events), format)
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record([
+                "block_number",
+                "transaction_hash",
+                "log_index",
+                "event",
+                "fields",
+                "is_finalized",
+            ]);
+            for event in events {
+                let _ = wtr.write_record([
+                    event.block_number.to_string(),
+                    options.tx_hash_or_url(&event.transaction_hash),
+                    event.log_index.to_string(),
+                    event.event.name.clone(),
+                    event.event.fields.clone(),
+                    event.is_finalized.to_string(),
+                ]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+pub fn format_reorgs(reorgs: &[Reorg], options: &FormatOptions, format: &OutputFormat) -> String {
+    let hash_or = |hash: Option<B256>, missing: &str| {
+        hash.map_or(missing.to_string(), |hash| format!("{hash:?}"))
+    };
+
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            if reorgs.is_empty() {
+                return "No reorgs recorded.".to_string();
+            }
+
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec![
+                    "Block",
+                    "Old Block Hash",
+                    "New Block Hash",
+                    "Deleted",
+                    "Inserted",
+                    "Detected At (unix)",
+                ]);
+
+            for reorg in reorgs {
+                let display = |hash: Option<B256>| match hash {
+                    Some(hash) => inline_code(options.display_tx_hash(&hash), format),
+                    None => "-".to_string(),
+                };
+                table.add_row(vec![
+                    Cell::new(reorg.block_number),
+                    Cell::new(display(reorg.old_block_hash)),
+                    Cell::new(display(reorg.new_block_hash)),
+                    Cell::new(reorg.transfers_deleted),
+                    Cell::new(reorg.transfers_inserted),
+                    Cell::new(reorg.detected_at),
+                ]);
+            }
+
+            render_table(&table, &[0, 3, 4, 5], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let json_reorgs: Vec<_> = reorgs
+                .iter()
+                .map(|reorg| {
+                    json!({
+                        "block_number": reorg.block_number,
+                        "old_block_hash": reorg.old_block_hash.map(|hash| format!("{hash:?}")),
+                        "new_block_hash": reorg.new_block_hash.map(|hash| format!("{hash:?}")),
+                        "transfers_deleted": reorg.transfers_deleted,
+                        "transfers_inserted": reorg.transfers_inserted,
+                        "detected_at": reorg.detected_at,
+                    })
+                })
+                .collect();
+
+            render_json(json!(json_reorgs), format)
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record([
+                "block_number",
+                "old_block_hash",
+                "new_block_hash",
+                "transfers_deleted",
+                "transfers_inserted",
+                "detected_at",
+            ]);
+            for reorg in reorgs {
+                let _ = wtr.write_record([
+                    reorg.block_number.to_string(),
+                    hash_or(reorg.old_block_hash, ""),
+                    hash_or(reorg.new_block_hash, ""),
+                    reorg.transfers_deleted.to_string(),
+                    reorg.transfers_inserted.to_string(),
+                    reorg.detected_at.to_string(),
+                ]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+pub fn format_indexing_log(records: &[IndexingLogRecord], format: &OutputFormat) -> String {
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            if records.is_empty() {
+                return "No indexing log entries recorded.".to_string();
+            }
+
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec![
+                    "Stage",
+                    "From Block",
+                    "To Block",
+                    "Logs",
+                    "Inserted",
+                    "Provider",
+                    "Duration (ms)",
+                    "Recorded At (unix)",
+                ]);
+
+            for record in records {
+                let entry = &record.entry;
+                table.add_row(vec![
+                    Cell::new(entry.stage.as_str()),
+                    Cell::new(entry.from_block),
+                    Cell::new(entry.to_block),
+                    Cell::new(entry.log_count),
+                    Cell::new(entry.inserted_count),
+                    Cell::new(entry.provider_url.as_deref().unwrap_or("-")),
+                    Cell::new(entry.duration_ms),
+                    Cell::new(record.recorded_at),
+                ]);
+            }
+
+            render_table(&table, &[1, 2, 3, 4, 6, 7], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let json_records: Vec<_> = records
+                .iter()
+                .map(|record| {
+                    let entry = &record.entry;
+                    json!({
+                        "stage": entry.stage.as_str(),
+                        "from_block": entry.from_block,
+                        "to_block": entry.to_block,
+                        "log_count": entry.log_count,
+                        "inserted_count": entry.inserted_count,
+                        "provider_url": entry.provider_url,
+                        "duration_ms": entry.duration_ms,
+                        "recorded_at": record.recorded_at,
+                    })
+                })
+                .collect();
+
+            render_json(json!(json_records), format)
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record([
+                "stage",
+                "from_block",
+                "to_block",
+                "log_count",
+                "inserted_count",
+                "provider_url",
+                "duration_ms",
+                "recorded_at",
+            ]);
+            for record in records {
+                let entry = &record.entry;
+                let _ = wtr.write_record([
+                    entry.stage.as_str().to_string(),
+                    entry.from_block.to_string(),
+                    entry.to_block.to_string(),
+                    entry.log_count.to_string(),
+                    entry.inserted_count.to_string(),
+                    entry.provider_url.clone().unwrap_or_default(),
+                    entry.duration_ms.to_string(),
+                    record.recorded_at.to_string(),
+                ]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+/// Token metadata and sync state. `latest_block` is the live chain head, the
+/// blocks-behind-head row is left out when it couldn't be fetched.
+pub fn format_token_info(
+    token: &Token,
+    latest_block: Option<u64>,
+    format: &OutputFormat,
+) -> String {
+    let or_na = |value: Option<String>| value.unwrap_or_else(|| "N/A".to_string());
+    let blocks_behind = latest_block.map(|head| {
+        head.saturating_sub(token.last_processed_block.unwrap_or(token.deployment_block))
+    });
+
+    let mut rows = vec![
+        ("address", format!("{:?}", token.address)),
+        ("name", or_na(token.name.clone())),
+        ("symbol", or_na(token.symbol.clone())),
+        ("decimals", or_na(token.decimals.map(|d| d.to_string()))),
+        ("deployment_block", token.deployment_block.to_string()),
+        (
+            "last_processed_block",
+            or_na(token.last_processed_block.map(|b| b.to_string())),
+        ),
+        (
+            "last_processed_finalized_block",
+            or_na(token.last_processed_finalized_block.map(|b| b.to_string())),
+        ),
+    ];
+    if let Some(blocks_behind) = blocks_behind {
+        rows.push(("blocks_behind_head", blocks_behind.to_string()));
+    }
+
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec!["Field", "Value"]);
+
+            for (field, value) in &rows {
+                table.add_row(vec![Cell::new(field), Cell::new(value)]);
+            }
+
+            render_table(&table, &[], format)
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let mut value = json!({
+                "address": format!("{:?}", token.address),
+                "name": token.name,
+                "symbol": token.symbol,
+                "decimals": token.decimals,
+                "deployment_block": token.deployment_block,
+                "last_processed_block": token.last_processed_block,
+                "last_processed_finalized_block": token.last_processed_finalized_block,
+            });
+            if let Some(blocks_behind) = blocks_behind {
+                value["blocks_behind_head"] = json!(blocks_behind);
+            }
+            render_json(json!(value), format)
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record(["field", "value"]);
+            for (field, value) in &rows {
+                let _ = wtr.write_record([*field, value.as_str()]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+/// How far the index is and how recently it was written to
+pub struct SyncStatus {
+    pub last_processed_block: Option<u64>,
+    pub last_finalized_block: Option<u64>,
+    /// None when no provider answered in time
+    pub chain_head: Option<u64>,
+    pub transfers: usize,
+    /// Unix time of the insertion worker's last committed batch
+    pub last_inserted_at: Option<u64>,
+}
+
+impl SyncStatus {
+    /// Blocks between the chain head and the last processed block, None when
+    /// the head is unknown
+    pub fn blocks_behind(&self) -> Option<u64> {
+        self.chain_head
+            .map(|head| head.saturating_sub(self.last_processed_block.unwrap_or(0)))
+    }
+}
+
+pub fn format_sync_status(status: &SyncStatus, format: &OutputFormat) -> String {
+    match format {
+        OutputFormat::Json | OutputFormat::JsonLines => render_json(
+            json!({
+                "last_processed_block": status.last_processed_block,
+                "last_finalized_block": status.last_finalized_block,
+                "chain_head": status.chain_head,
+                "blocks_behind": status.blocks_behind(),
+                "transfers": status.transfers,
+                "last_inserted_at": status.last_inserted_at,
+            }),
+            format,
+        ),
+        _ => {
+            let or_na = |value: Option<u64>| value.map_or("N/A".to_string(), |v| v.to_string());
+            let rows = [
+                ("last_processed_block", or_na(status.last_processed_block)),
+                ("last_finalized_block", or_na(status.last_finalized_block)),
+                ("chain_head", or_na(status.chain_head)),
+                ("blocks_behind", or_na(status.blocks_behind())),
+                ("transfers", status.transfers.to_string()),
+                ("last_inserted_at", or_na(status.last_inserted_at)),
+            ];
+
+            if matches!(format, OutputFormat::Csv) {
+                let mut wtr = Writer::from_writer(vec![]);
+                let _ = wtr.write_record(["field", "value"]);
+                for (field, value) in &rows {
+                    let _ = wtr.write_record([*field, value.as_str()]);
+                }
+                return String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default();
+            }
+
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec!["Field", "Value"]);
+            for (field, value) in &rows {
+                table.add_row(vec![Cell::new(field), Cell::new(value)]);
+            }
+            render_table(&table, &[], format)
+        }
+    }
+}
+
+/// A token of `TOKENS_CONFIG` and how far its database is synced
+pub struct TokenListing {
+    pub address: String,
+    /// The configured symbol, else the one stored in the database
+    pub symbol: Option<String>,
+    pub database_url: String,
+    /// Why the database couldn't be read, e.g. before the token's first run
+    pub status: Result<SyncStatus, String>,
+}
+
+pub fn format_token_listing(tokens: &[TokenListing], format: &OutputFormat) -> String {
+    match format {
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let values: Vec<_> = tokens
+                .iter()
+                .map(|token| {
+                    let mut value = json!({
+                        "address": token.address,
+                        "symbol": token.symbol,
+                        "database_url": token.database_url,
+                    });
+                    match &token.status {
+                        Ok(status) => {
+                            value["last_processed_block"] = json!(status.last_processed_block);
+                            value["last_finalized_block"] = json!(status.last_finalized_block);
+                            value["transfers"] = json!(status.transfers);
+                            value["last_inserted_at"] = json!(status.last_inserted_at);
+                        }
+                        Err(e) => value["error"] = json!(e),
+                    }
+                    value
+                })
+                .collect();
+            render_json(json!(values), format)
+        }
+        _ => {
+            let or_empty = |value: Option<u64>| value.map_or(String::new(), |v| v.to_string());
+            let rows: Vec<[String; 8]> = tokens
+                .iter()
+                .map(|token| {
+                    let mut row = [
+                        token.symbol.clone().unwrap_or_default(),
+                        token.address.clone(),
+                        token.database_url.clone(),
+                        String::new(),
+                        String::new(),
+                        String::new(),
+                        String::new(),
+                        String::new(),
+                    ];
+                    match &token.status {
+                        Ok(status) => {
+                            row[3] = or_empty(status.last_processed_block);
+                            row[4] = or_empty(status.last_finalized_block);
+                            row[5] = status.transfers.to_string();
+                            row[6] = or_empty(status.last_inserted_at);
+                        }
+                        Err(e) => row[7] = e.clone(),
+                    }
+                    row
+                })
+                .collect();
+
+            if matches!(format, OutputFormat::Csv) {
+                let mut wtr = Writer::from_writer(vec![]);
+                let _ = wtr.write_record([
+                    "symbol",
+                    "address",
+                    "database_url",
+                    "last_processed_block",
+                    "last_finalized_block",
+                    "transfers",
+                    "last_inserted_at",
+                    "error",
+                ]);
+                for row in &rows {
+                    let _ = wtr.write_record(row);
+                }
+                return String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default();
+            }
+
+            if tokens.is_empty() {
+                return "No tokens configured.".to_string();
+            }
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec![
+                    "Symbol",
+                    "Address",
+                    "Database",
+                    "Last Processed",
+                    "Finalized",
+                    "Transfers",
+                    "Last Inserted At",
+                    "Error",
+                ]);
+            for row in &rows {
+                table.add_row(row.iter().map(Cell::new).collect::<Vec<_>>());
+            }
+            render_table(&table, &[3, 4, 5], format)
+        }
+    }
+}
+
+/// Which blocks between the token's deployment and the indexer's cursor were
+/// scanned for transfers
+pub struct Coverage {
+    /// The block after the deployment block, where scanning starts
+    pub from_block: u64,
+    /// The last processed block, None before the first batch
+    pub to_block: Option<u64>,
+    /// Ranges of `from_block..=to_block` no scan covered, in block order
+    pub gaps: Vec<(u64, u64)>,
+    /// Ranges queued to be scanned on the next indexer start
+    pub queued: Vec<(u64, u64)>,
+}
+
+impl Coverage {
+    pub fn missing_blocks(&self) -> u64 {
+        self.gaps.iter().map(|(from, to)| to - from + 1).sum()
+    }
+
+    /// Whether the whole of `from..=to` is queued to be scanned
+    pub fn is_queued(&self, from: u64, to: u64) -> bool {
+        gaps_between(&self.queued, from, to).is_empty()
+    }
+}
+
+pub fn format_coverage(coverage: &Coverage, format: &OutputFormat) -> String {
+    let gap_rows = coverage
+        .gaps
+        .iter()
+        .map(|&(from, to)| (from, to, to - from + 1, coverage.is_queued(from, to)));
+
+    match format {
+        OutputFormat::Table | OutputFormat::Markdown => {
+            let Some(to_block) = coverage.to_block else {
+                return "No blocks have been scanned yet.".to_string();
+            };
+            if coverage.gaps.is_empty() {
+                return format!(
+                    "Blocks {} to {to_block} are fully scanned.",
+                    coverage.from_block
+                );
+            }
+
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .apply_modifier(UTF8_ROUND_CORNERS)
+                .set_header(vec!["From", "To", "Blocks", "Queued"]);
+            for (from, to, blocks, queued) in gap_rows {
+                table.add_row(vec![
+                    Cell::new(from),
+                    Cell::new(to),
+                    Cell::new(blocks),
+                    Cell::new(if queued { "yes" } else { "no" }),
+                ]);
+            }
+
+            format!(
+                "{} unscanned blocks in {} gaps between blocks {} and {to_block}\n{}",
+                coverage.missing_blocks(),
+                coverage.gaps.len(),
+                coverage.from_block,
+                render_table(&table, &[0, 1, 2], format)
+            )
+        }
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            let gaps: Vec<_> = gap_rows
+                .map(|(from, to, blocks, queued)| {
+                    json!({
+                        "from_block": from,
+                        "to_block": to,
+                        "blocks": blocks,
+                        "queued": queued,
+                    })
+                })
+                .collect();
+            render_json(
+                json!({
+                    "from_block": coverage.from_block,
+                    "to_block": coverage.to_block,
+                    "missing_blocks": coverage.missing_blocks(),
+                    "gaps": gaps,
+                }),
+                format,
+            )
+        }
+        OutputFormat::Csv => {
+            let mut wtr = Writer::from_writer(vec![]);
+            let _ = wtr.write_record(["from_block", "to_block", "blocks", "queued"]);
+            for (from, to, blocks, queued) in gap_rows {
+                let _ = wtr.write_record([
+                    from.to_string(),
+                    to.to_string(),
+                    blocks.to_string(),
+                    queued.to_string(),
+                ]);
+            }
+            String::from_utf8(wtr.into_inner().unwrap_or_default()).unwrap_or_default()
+        }
+    }
+}
+
+/// `0x1234...abcd`, or the hash as is when it's too short to shorten
 fn format_tx_hash(hash: &str) -> String {
-    format!("{}...{}", &hash[..6], &hash[hash.len() - 4..])
+    if hash.len() <= 10 {
+        return hash.to_string();
+    }
+    match (hash.get(..6), hash.get(hash.len() - 4..)) {
+        (Some(head), Some(tail)) => format!("{head}...{tail}"),
+        _ => hash.to_string(),
+    }
 }
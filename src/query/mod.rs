@@ -1,4 +1,5 @@
 pub mod commands;
+pub mod export;
 pub mod formatters;
 
 pub use commands::*;
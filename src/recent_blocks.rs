@@ -0,0 +1,50 @@
+use alloy_primitives::B256;
+use std::collections::BTreeMap;
+
+/// Number of unfinalized block hashes kept to find where a head reorg forked
+pub const RECENT_BLOCKS_CAPACITY: usize = 256;
+
+/// Canonical hashes of the last blocks of recently processed unfinalized
+/// batches. A new batch whose parent hash doesn't match the recorded hash
+/// means the chain reorged under blocks that were already stored.
+pub struct RecentBlocks {
+    hashes: BTreeMap<u64, B256>,
+    capacity: usize,
+}
+
+impl RecentBlocks {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            hashes: BTreeMap::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn get(&self, block_number: u64) -> Option<B256> {
+        self.hashes.get(&block_number).copied()
+    }
+
+    /// Record a block's hash, forgetting finalized blocks (they can't reorg)
+    /// and the oldest ones beyond capacity
+    pub fn record(&mut self, block_number: u64, hash: B256, finalized_block: u64) {
+        self.hashes.insert(block_number, hash);
+        self.hashes = self.hashes.split_off(&(finalized_block + 1));
+        while self.hashes.len() > self.capacity {
+            self.hashes.pop_first();
+        }
+    }
+
+    /// Recorded blocks below `block_number`, newest first
+    pub fn before(&self, block_number: u64) -> Vec<(u64, B256)> {
+        self.hashes
+            .range(..block_number)
+            .rev()
+            .map(|(block, hash)| (*block, *hash))
+            .collect()
+    }
+
+    /// Forget `block_number` and everything after it
+    pub fn truncate_from(&mut self, block_number: u64) {
+        self.hashes.split_off(&block_number);
+    }
+}
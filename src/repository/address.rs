@@ -0,0 +1,25 @@
+use crate::error::{Result, StorageError};
+use alloy_primitives::Address;
+use std::str::FromStr;
+
+/// Canonical database form of an address: 0x-prefixed lowercase hex. Every
+/// insert and every WHERE clause goes through this so lookups never miss rows
+/// because of letter case.
+pub fn addr_to_db_string(address: &Address) -> String {
+    format!("{address:?}")
+}
+
+pub fn addr_from_db_string(value: &str) -> Result<Address> {
+    Address::from_str(value).map_err(|e| {
+        StorageError::Unusable(format!("Invalid address in database: {value}: {e}")).into()
+    })
+}
+
+/// Read an address column, reporting a malformed value as a conversion error on
+/// that column
+pub fn addr_column(row: &rusqlite::Row, index: usize) -> rusqlite::Result<Address> {
+    let value: String = row.get(index)?;
+    addr_from_db_string(&value).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, e.into())
+    })
+}
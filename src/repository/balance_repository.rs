@@ -1,46 +1,169 @@
+use crate::error::Result;
 use alloy_primitives::{Address, U256};
-use anyhow::Result;
-use rusqlite::{Connection, params};
-use std::collections::HashMap;
-use std::str::FromStr;
-use tracing::info;
+use rusqlite::{Connection, OptionalExtension, Transaction, TransactionBehavior, params};
+use std::collections::{BTreeSet, HashMap};
+use tracing::{error, info};
 
+use crate::error::IndexerError;
 use crate::repository::Transfer;
+use crate::repository::address::{addr_column, addr_from_db_string, addr_to_db_string};
+use crate::repository::checkpoint_repository::CheckpointRepository;
+use crate::repository::codec::{u256_column, u256_to_blob};
 
 #[derive(Debug)]
 pub struct BalanceInfo {
     pub balance: U256,
+    pub incoming_count: u64,
+    pub outgoing_count: u64,
+    pub first_block: Option<u64>,
+    pub last_block: Option<u64>,
 }
 
 #[derive(Debug)]
 pub struct TokenHolder {
     pub address: Address,
     pub balance: U256,
+    /// Percentage of the summed balances held, when requested
+    pub share: Option<f64>,
 }
 
+/// How a token's supply is spread across holders
+#[derive(Debug)]
+pub struct Distribution {
+    pub total_holders: u64,
+    pub total_balance: U256,
+    /// Number of holders with at least each threshold, in the order given
+    pub holders_above: Vec<(U256, u64)>,
+    /// Percentage of the summed balances held by the top N holders
+    pub top_shares: Vec<(usize, f64)>,
+}
+
+/// Which way `balances_at_block` reached the block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotDirection {
+    /// Adding up the transfers up to the block
+    Forward,
+    /// Taking the transfers above the block back out of the current balances
+    Reverse,
+    /// Adding the transfers since the balance checkpoint at this block to it
+    FromCheckpoint(u64),
+}
+
+/// Balances at the end of a block
+#[derive(Debug)]
+pub struct BalanceSnapshot {
+    pub block_number: u64,
+    pub direction: SnapshotDirection,
+    /// Transfers added or taken back out to get there
+    pub transfers_applied: u64,
+    /// Holders with a non-zero balance, largest first
+    pub holders: Vec<TokenHolder>,
+}
+
+/// Top-N cut-offs reported by `get_distribution`
+const DISTRIBUTION_TOP_N: [usize; 3] = [10, 50, 100];
+
+/// Balance queries scoped to a single token
 pub struct BalanceRepository<'a> {
     conn: &'a Connection,
+    token: Address,
+    token_address: String,
 }
 
 impl<'a> BalanceRepository<'a> {
-    pub fn new(conn: &'a Connection) -> Self {
-        Self { conn }
-    }
+    const SELECT_BALANCE: &'static str =
+        "SELECT balance_padded FROM balances WHERE token_address = ?1 AND address = ?2";
+
+    const UPSERT_BALANCE: &'static str = "INSERT OR REPLACE INTO balances (token_address, address, balance_padded) VALUES (?1, ?2, ?3)";
+
+    const SELECT_INCOMING_ACTIVITY: &'static str =
+        "SELECT COUNT(*), MIN(block_number), MAX(block_number) FROM transfers
+         WHERE token_address = ?1 AND to_address = ?2 AND (?3 = 0 OR is_finalized = 1)";
+
+    const SELECT_OUTGOING_ACTIVITY: &'static str =
+        "SELECT COUNT(*), MIN(block_number), MAX(block_number) FROM transfers
+         WHERE token_address = ?1 AND from_address = ?2 AND (?3 = 0 OR is_finalized = 1)";
+
+    const SELECT_INCOMING_ACTIVITY_UP_TO: &'static str =
+        "SELECT COUNT(*), MIN(block_number), MAX(block_number) FROM transfers
+         WHERE token_address = ?1 AND to_address = ?2 AND block_number <= ?3";
+
+    const SELECT_OUTGOING_ACTIVITY_UP_TO: &'static str =
+        "SELECT COUNT(*), MIN(block_number), MAX(block_number) FROM transfers
+         WHERE token_address = ?1 AND from_address = ?2 AND block_number <= ?3";
+
+    /// Transfers up to ?3 not in the checkpoint at ?4, which holds the
+    /// finalized ones up to it; ?4 = -1 selects them all
+    const SELECT_ADDRESS_TRANSFERS_UP_TO: &'static str =
+        "SELECT from_address, to_address, value FROM transfers
+         WHERE token_address = ?1 AND (from_address = ?2 OR to_address = ?2) AND block_number <= ?3
+            AND (block_number > ?4 OR is_finalized = 0)
+         ORDER BY block_number, log_index";
+
+    const COUNT_TRANSFERS_UP_TO: &'static str = "SELECT COUNT(*) FROM transfers
+         WHERE token_address = ?1 AND block_number <= ?2 AND (?3 = 1 OR is_finalized = 1)";
+
+    const SELECT_TRANSFERS_UP_TO: &'static str = "SELECT from_address, to_address, value
+         FROM transfers
+         WHERE token_address = ?1 AND block_number <= ?2 AND (?3 = 1 OR is_finalized = 1)
+         ORDER BY block_number, log_index";
+
+    const COUNT_TRANSFERS_SINCE_CHECKPOINT: &'static str = "SELECT COUNT(*) FROM transfers
+         WHERE token_address = ?1 AND block_number <= ?2 AND (?3 = 1 OR is_finalized = 1)
+            AND (block_number > ?4 OR is_finalized = 0)";
+
+    const SELECT_TRANSFERS_SINCE_CHECKPOINT: &'static str = "SELECT from_address, to_address, value
+         FROM transfers
+         WHERE token_address = ?1 AND block_number <= ?2 AND (?3 = 1 OR is_finalized = 1)
+            AND (block_number > ?4 OR is_finalized = 0)
+         ORDER BY block_number, log_index";
+
+    const COUNT_FINALIZED_TRANSFERS_ABOVE: &'static str = "SELECT COUNT(*) FROM transfers
+         WHERE token_address = ?1 AND block_number > ?2 AND is_finalized = 1";
+
+    const SELECT_FINALIZED_TRANSFERS_ABOVE: &'static str = "SELECT from_address, to_address, value
+         FROM transfers
+         WHERE token_address = ?1 AND block_number > ?2 AND is_finalized = 1
+         ORDER BY block_number DESC, log_index DESC";
+
+    const COUNT_UNFINALIZED_TRANSFERS_UP_TO: &'static str = "SELECT COUNT(*) FROM transfers
+         WHERE token_address = ?1 AND block_number <= ?2 AND is_finalized = 0";
+
+    const SELECT_UNFINALIZED_TRANSFERS_UP_TO: &'static str =
+        "SELECT from_address, to_address, value
+         FROM transfers
+         WHERE token_address = ?1 AND block_number <= ?2 AND is_finalized = 0
+         ORDER BY block_number, log_index";
+
+    const COUNT_HOLDERS: &'static str = "SELECT COUNT(*) FROM balances WHERE token_address = ?1";
+
+    const SELECT_ALL_BALANCES: &'static str =
+        "SELECT address, balance_padded FROM balances WHERE token_address = ?1";
+
+    const DELETE_BALANCE: &'static str =
+        "DELETE FROM balances WHERE token_address = ?1 AND address = ?2";
+
+    const DELETE_ALL_BALANCES: &'static str = "DELETE FROM balances WHERE token_address = ?1";
+
+    const INSERT_ANOMALY: &'static str = "INSERT OR IGNORE INTO balance_anomalies (
+            token_address, address, block_number, detected_at, balance_padded, shortfall_padded
+         ) VALUES (?1, ?2, ?3, unixepoch(), ?4, ?5)";
 
-    /// Pad a U256 balance to 78 digits for proper sorting
-    /// U256 max is approximately 10^77, so 78 digits is sufficient
-    pub fn pad_balance(balance: &U256) -> String {
-        format!("{balance:0>78}")
+    pub fn new(conn: &'a Connection, token_address: &Address) -> Self {
+        Self {
+            conn,
+            token: *token_address,
+            token_address: addr_to_db_string(token_address),
+        }
     }
 
     /// Update balance for a single address
     pub fn update_balance(&self, address: &Address, balance: &U256) -> Result<()> {
-        let address_str = format!("{address:?}");
-        let padded = Self::pad_balance(balance);
+        let address_str = addr_to_db_string(address);
 
         self.conn.execute(
-            "INSERT OR REPLACE INTO balances (address, balance_padded) VALUES (?1, ?2)",
-            params![address_str, padded],
+            Self::UPSERT_BALANCE,
+            params![self.token_address, address_str, u256_to_blob(balance)],
         )?;
 
         Ok(())
@@ -53,11 +176,45 @@ impl<'a> BalanceRepository<'a> {
             return Ok(());
         }
 
+        let result = self.apply_in_own_tx(transfers);
+        self.record_shortfall(result)
+    }
+
+    fn apply_in_own_tx(&self, transfers: &[Transfer]) -> Result<()> {
+        let tx = Transaction::new_unchecked(self.conn, TransactionBehavior::Immediate)?;
+        self.apply_in_tx(&tx, &transfers.iter().collect::<Vec<_>>())?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Add the finalized transfers' amounts inside the caller's transaction, so
+    /// they are applied if and only if the write that finalized them commits
+    pub fn apply_in_tx(&self, conn: &Connection, transfers: &[&Transfer]) -> Result<()> {
+        let (increases, decreases) = Self::finalized_deltas(transfers);
+        self.write_deltas(conn, &increases, &decreases, |address, balance| {
+            Self::shortfall_block(transfers, address, balance, false)
+        })
+    }
+
+    /// Take back the amounts of finalized transfers that are being deleted,
+    /// inside the caller's transaction
+    pub fn revert_in_tx(&self, conn: &Connection, transfers: &[&Transfer]) -> Result<()> {
+        let (increases, decreases) = Self::finalized_deltas(transfers);
+        self.write_deltas(conn, &decreases, &increases, |address, balance| {
+            Self::shortfall_block(transfers, address, balance, true)
+        })
+    }
+
+    /// Amounts received and sent per address by the finalized transfers
+    fn finalized_deltas(
+        transfers: &[&Transfer],
+    ) -> (HashMap<Address, U256>, HashMap<Address, U256>) {
         let mut balance_increases: HashMap<Address, U256> = HashMap::new();
         let mut balance_decreases: HashMap<Address, U256> = HashMap::new();
 
         for transfer in transfers {
-            if !transfer.is_finalized {
+            // Custom events move nothing, balances aren't tracked for them
+            if !transfer.is_finalized || transfer.event.is_some() {
                 continue;
             }
 
@@ -70,105 +227,171 @@ impl<'a> BalanceRepository<'a> {
                 .or_insert(U256::ZERO) += transfer.value;
         }
 
-        let tx = self.conn.unchecked_transaction()?;
+        (balance_increases, balance_decreases)
+    }
+
+    /// Block of the first of the finalized `transfers` after which `address`,
+    /// starting out with `balance`, would hold less than nothing. Transfers
+    /// are applied in block and log order, and reverted in the opposite one.
+    fn shortfall_block(
+        transfers: &[&Transfer],
+        address: &Address,
+        balance: U256,
+        reverting: bool,
+    ) -> u64 {
+        let mut ordered: Vec<&Transfer> = transfers
+            .iter()
+            .copied()
+            .filter(|t| t.is_finalized && t.event.is_none())
+            .collect();
+        ordered.sort_by_key(|t| (t.block_number, t.log_index));
+        if reverting {
+            ordered.reverse();
+        }
+
+        let mut received = balance;
+        let mut sent = U256::ZERO;
+        for transfer in &ordered {
+            let (gains, loses) = if reverting {
+                (&transfer.from_address, &transfer.to_address)
+            } else {
+                (&transfer.to_address, &transfer.from_address)
+            };
+            if gains == address {
+                received = received.saturating_add(transfer.value);
+            }
+            if loses == address {
+                sent = sent.saturating_add(transfer.value);
+            }
+            if sent > received {
+                return transfer.block_number;
+            }
+        }
+        ordered.last().map_or(0, |t| t.block_number)
+    }
+
+    /// Apply each address's net change. A net decrease larger than the stored
+    /// balance means transfers were applied out of order or missed, and is an
+    /// `IndexerError::BalanceShortfall` at the block `shortfall_block` finds
+    /// from the address and its stored balance; the caller rolls back and
+    /// records it with [`BalanceRepository::record_shortfall`]. Overflowing a
+    /// U256 can't happen with real token data and is an error too.
+    fn write_deltas(
+        &self,
+        conn: &Connection,
+        balance_increases: &HashMap<Address, U256>,
+        balance_decreases: &HashMap<Address, U256>,
+        shortfall_block: impl Fn(&Address, U256) -> u64,
+    ) -> Result<()> {
+        // In a fixed order, so the same batch always fails on the same address
+        let addresses: BTreeSet<&Address> = balance_increases
+            .keys()
+            .chain(balance_decreases.keys())
+            .collect();
 
         // TODO: Optimize by batch fetching all current balances in a single query
         // instead of individual queries per address. For batches with many addresses,
         // we could use WHERE address IN (?, ?, ...) with chunking to respect SQL limits.
         // Current approach is fine for typical batches but could be improved for large ones.
-        for (address, increase) in &balance_increases {
-            let address_str = format!("{address:?}");
+        for address in addresses {
+            let increase = balance_increases
+                .get(address)
+                .copied()
+                .unwrap_or(U256::ZERO);
+            let decrease = balance_decreases
+                .get(address)
+                .copied()
+                .unwrap_or(U256::ZERO);
+            // Mints come from and burns go to the zero address, which never
+            // holds a balance, so the balances add up to the supply
+            if increase == decrease || address.is_zero() {
+                continue;
+            }
 
-            let current: Option<String> = tx
-                .query_row(
-                    "SELECT balance_padded FROM balances WHERE address = ?1",
-                    params![&address_str],
-                    |row| row.get(0),
-                )
-                .ok();
-
-            let mut balance = match current {
-                Some(padded) => {
-                    let trimmed = padded.trim_start_matches('0');
-                    if trimmed.is_empty() {
-                        U256::ZERO
-                    } else {
-                        U256::from_str(trimmed)?
+            let address_str = addr_to_db_string(address);
+
+            let current: U256 = conn
+                .prepare_cached(Self::SELECT_BALANCE)?
+                .query_row(params![self.token_address, &address_str], |row| {
+                    u256_column(row, 0)
+                })
+                .optional()?
+                .unwrap_or(U256::ZERO);
+
+            // Signed net delta, kept as a magnitude and a direction
+            let balance = if increase > decrease {
+                let net_increase = increase - decrease;
+                current.checked_add(net_increase).ok_or_else(|| {
+                    IndexerError::Other(anyhow::anyhow!(
+                        "Balance of {} overflows: stored {} plus net increase {}",
+                        address,
+                        current,
+                        net_increase
+                    ))
+                })?
+            } else {
+                let net_decrease = decrease - increase;
+                match current.checked_sub(net_decrease) {
+                    Some(balance) => balance,
+                    None => {
+                        return Err(IndexerError::BalanceShortfall {
+                            address: *address,
+                            block_number: shortfall_block(address, current),
+                            balance: current,
+                            shortfall: net_decrease - current,
+                        });
                     }
                 }
-                None => U256::ZERO,
             };
 
-            balance = balance.saturating_add(*increase);
-
-            if let Some(decrease) = balance_decreases.get(address) {
-                balance = balance.saturating_sub(*decrease);
-            }
-
             if balance > U256::ZERO {
-                let padded = Self::pad_balance(&balance);
-                tx.execute(
-                    "INSERT OR REPLACE INTO balances (address, balance_padded) VALUES (?1, ?2)",
-                    params![address_str, padded],
-                )?;
+                conn.prepare_cached(Self::UPSERT_BALANCE)?.execute(params![
+                    self.token_address,
+                    address_str,
+                    u256_to_blob(&balance)
+                ])?;
             } else {
                 // Remove zero balances
-                tx.execute(
-                    "DELETE FROM balances WHERE address = ?1",
-                    params![address_str],
-                )?;
+                conn.prepare_cached(Self::DELETE_BALANCE)?
+                    .execute(params![self.token_address, address_str])?;
             }
         }
 
-        // Handle addresses that only sent (not received)
-        for (address, decrease) in balance_decreases {
-            if balance_increases.contains_key(&address) {
-                continue; // Already handled above
-            }
-
-            let address_str = format!("{address:?}");
+        Ok(())
+    }
 
-            // Get current balance
-            let current: Option<String> = tx
-                .query_row(
-                    "SELECT balance_padded FROM balances WHERE address = ?1",
-                    params![&address_str],
-                    |row| row.get(0),
-                )
-                .ok();
-
-            match current {
-                Some(padded) => {
-                    let trimmed = padded.trim_start_matches('0');
-                    let balance = if trimmed.is_empty() {
-                        U256::ZERO
-                    } else {
-                        U256::from_str(trimmed)?
-                    };
-
-                    let new_balance = balance.saturating_sub(decrease);
-
-                    if new_balance > U256::ZERO {
-                        let padded = Self::pad_balance(&new_balance);
-                        tx.execute(
-                            "INSERT OR REPLACE INTO balances (address, balance_padded) VALUES (?1, ?2)",
-                            params![address_str, padded],
-                        )?;
-                    } else {
-                        tx.execute(
-                            "DELETE FROM balances WHERE address = ?1",
-                            params![address_str],
-                        )?;
-                    }
-                }
-                None => {
-                    // Address has no balance but is sending - this shouldn't happen with finalized transfers
-                }
+    /// Pass `result` through, first recording the balance shortfall it
+    /// failed on in `balance_anomalies`. Call it once the transaction that ran
+    /// into the shortfall has rolled back, so the record outlives it. The same
+    /// shortfall hit again, e.g. by the same batch after a restart, is only
+    /// recorded once.
+    pub fn record_shortfall<T>(&self, result: Result<T>) -> Result<T> {
+        let Err(error) = result else {
+            return result;
+        };
+        if let IndexerError::BalanceShortfall {
+            address,
+            block_number,
+            balance,
+            shortfall,
+        } = &error
+        {
+            error!("{error}, the write was rolled back");
+            if let Err(e) = self
+                .conn
+                .prepare_cached(Self::INSERT_ANOMALY)?
+                .execute(params![
+                    self.token_address,
+                    addr_to_db_string(address),
+                    block_number,
+                    u256_to_blob(balance),
+                    u256_to_blob(shortfall)
+                ])
+            {
+                error!("Failed to record the balance shortfall of {address}: {e}");
             }
         }
-
-        tx.commit()?;
-        Ok(())
+        Err(error)
     }
 
     /// Update balances for addresses affected by new finalized transfers
@@ -185,35 +408,35 @@ impl<'a> BalanceRepository<'a> {
         let mut balances = HashMap::new();
 
         for address in addresses {
-            let address_str = format!("{address:?}");
+            let address_str = addr_to_db_string(address);
 
             // Calculate balance from all finalized transfers
             // Get incoming values
             let mut stmt = conn
-                .prepare("SELECT value FROM transfers WHERE to_address = ? AND is_finalized = 1")?;
+                .prepare("SELECT value FROM transfers WHERE token_address = ? AND to_address = ? AND is_finalized = 1")?;
             let incoming_values = stmt
-                .query_map(params![address_str], |row| row.get::<_, String>(0))?
+                .query_map(params![self.token_address, address_str], |row| {
+                    u256_column(row, 0)
+                })?
                 .collect::<Result<Vec<_>, _>>()?;
 
             let mut total_incoming = U256::ZERO;
-            for value_str in incoming_values {
-                let value = U256::from_str(&value_str)
-                    .map_err(|_| anyhow::anyhow!("Invalid value format: {}", value_str))?;
+            for value in incoming_values {
                 total_incoming = total_incoming.saturating_add(value);
             }
 
             // Get outgoing values
             let mut stmt = conn.prepare(
-                "SELECT value FROM transfers WHERE from_address = ? AND is_finalized = 1",
+                "SELECT value FROM transfers WHERE token_address = ? AND from_address = ? AND is_finalized = 1",
             )?;
             let outgoing_values = stmt
-                .query_map(params![address_str], |row| row.get::<_, String>(0))?
+                .query_map(params![self.token_address, address_str], |row| {
+                    u256_column(row, 0)
+                })?
                 .collect::<Result<Vec<_>, _>>()?;
 
             let mut total_outgoing = U256::ZERO;
-            for value_str in outgoing_values {
-                let value = U256::from_str(&value_str)
-                    .map_err(|_| anyhow::anyhow!("Invalid value format: {}", value_str))?;
+            for value in outgoing_values {
                 total_outgoing = total_outgoing.saturating_add(value);
             }
 
@@ -225,8 +448,8 @@ impl<'a> BalanceRepository<'a> {
             } else {
                 // Delete zero balances
                 self.conn.execute(
-                    "DELETE FROM balances WHERE address = ?",
-                    params![address_str],
+                    Self::DELETE_BALANCE,
+                    params![self.token_address, address_str],
                 )?;
             }
         }
@@ -239,128 +462,488 @@ impl<'a> BalanceRepository<'a> {
         Ok(())
     }
 
-    /// Update multiple balances in a single transaction
+    /// Update multiple balances in a single transaction, or in the caller's
+    /// when one is open (e.g. a migration's)
     pub fn update_balances_batch(&self, balances: &HashMap<Address, U256>) -> Result<()> {
-        let tx = self.conn.unchecked_transaction()?;
+        let tx = if self.conn.is_autocommit() {
+            Some(Transaction::new_unchecked(
+                self.conn,
+                TransactionBehavior::Immediate,
+            )?)
+        } else {
+            None
+        };
 
         {
-            let mut stmt = tx.prepare(
-                "INSERT OR REPLACE INTO balances (address, balance_padded) VALUES (?1, ?2)",
-            )?;
+            let mut stmt = self.conn.prepare_cached(Self::UPSERT_BALANCE)?;
 
             for (address, balance) in balances {
-                let address_str = format!("{address:?}");
-                let padded = Self::pad_balance(balance);
-                stmt.execute(params![address_str, padded])?;
+                let address_str = addr_to_db_string(address);
+                stmt.execute(params![
+                    self.token_address,
+                    address_str,
+                    u256_to_blob(balance)
+                ])?;
             }
         }
 
-        tx.commit()?;
+        if let Some(tx) = tx {
+            tx.commit()?;
+        }
         Ok(())
     }
 
-    /// Get balance for an address (returns BalanceInfo)
-    pub fn get_balance(&self, address: &Address) -> Result<BalanceInfo> {
-        let address_str = format!("{address:?}");
+    /// Get balance for an address along with how many transfers it sent and
+    /// received and the blocks of its first and last transfer. The activity
+    /// counts only finalized transfers when `finalized_only` is set; the balance
+    /// itself is always the finalized one.
+    pub fn get_balance(&self, address: &Address, finalized_only: bool) -> Result<BalanceInfo> {
+        let address_str = addr_to_db_string(address);
 
-        let padded: Option<String> = self
+        let balance = self
             .conn
             .query_row(
-                "SELECT balance_padded FROM balances WHERE address = ?1",
-                params![address_str],
-                |row| row.get(0),
+                Self::SELECT_BALANCE,
+                params![self.token_address, address_str],
+                |row| u256_column(row, 0),
             )
-            .ok();
-
-        let balance = match padded {
-            Some(p) => {
-                // Remove leading zeros and parse
-                let trimmed = p.trim_start_matches('0');
-                if trimmed.is_empty() {
-                    U256::ZERO
-                } else {
-                    U256::from_str(trimmed)
-                        .map_err(|_| anyhow::anyhow!("Invalid balance format in database"))?
-                }
+            .optional()?
+            .unwrap_or(U256::ZERO);
+
+        let activity = |query: &str| -> Result<(u64, Option<u64>, Option<u64>)> {
+            Ok(self.conn.query_row(
+                query,
+                params![self.token_address, address_str, finalized_only],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?)
+        };
+
+        let (incoming_count, first_in, last_in) = activity(Self::SELECT_INCOMING_ACTIVITY)?;
+        let (outgoing_count, first_out, last_out) = activity(Self::SELECT_OUTGOING_ACTIVITY)?;
+
+        let first_block = [first_in, first_out].into_iter().flatten().min();
+        let last_block = [last_in, last_out].into_iter().flatten().max();
+
+        Ok(BalanceInfo {
+            balance,
+            incoming_count,
+            outgoing_count,
+            first_block,
+            last_block,
+        })
+    }
+
+    /// `get_balance` as of the end of `block_number`: the balance from
+    /// `get_balance_at_block` and the activity up to the block, finalized or
+    /// not
+    pub fn get_balance_at(&self, address: &Address, block_number: u64) -> Result<BalanceInfo> {
+        let address_str = addr_to_db_string(address);
+        let activity = |query: &str| -> Result<(u64, Option<u64>, Option<u64>)> {
+            Ok(self.conn.query_row(
+                query,
+                params![self.token_address, address_str, block_number],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?)
+        };
+
+        let (incoming_count, first_in, last_in) = activity(Self::SELECT_INCOMING_ACTIVITY_UP_TO)?;
+        let (outgoing_count, first_out, last_out) = activity(Self::SELECT_OUTGOING_ACTIVITY_UP_TO)?;
+
+        Ok(BalanceInfo {
+            balance: self.get_balance_at_block(address, block_number)?,
+            incoming_count,
+            outgoing_count,
+            first_block: [first_in, first_out].into_iter().flatten().min(),
+            last_block: [last_in, last_out].into_iter().flatten().max(),
+        })
+    }
+
+    /// Balance of an address at the end of `block_number`, from the nearest
+    /// balance checkpoint at or below it and the address's stored transfers
+    /// since, finalized or not, in chain order. Without a checkpoint every
+    /// transfer of the address is replayed. Only meaningful when every block
+    /// up to `block_number` has been indexed.
+    pub fn get_balance_at_block(&self, address: &Address, block_number: u64) -> Result<U256> {
+        let address_str = addr_to_db_string(address);
+        let checkpoints = CheckpointRepository::new(self.conn, &self.token);
+        let (mut balance, since) = match checkpoints.nearest(block_number)? {
+            Some(checkpoint) => (
+                checkpoints.balance(checkpoint.block_number, address)?,
+                checkpoint.block_number as i64,
+            ),
+            None => (U256::ZERO, -1),
+        };
+
+        let mut stmt = self.conn.prepare(Self::SELECT_ADDRESS_TRANSFERS_UP_TO)?;
+        let rows = stmt.query_map(
+            params![self.token_address, address_str, block_number, since],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    u256_column(row, 2)?,
+                ))
+            },
+        )?;
+
+        for row in rows {
+            let (from, to, value) = row?;
+            // A self-transfer leaves the balance as it is
+            if from == address_str {
+                balance = balance.saturating_sub(value);
             }
-            None => U256::ZERO,
+            if to == address_str {
+                balance = balance.saturating_add(value);
+            }
+        }
+        Ok(balance)
+    }
+
+    /// Balances of every holder at the end of `block_number`, from the
+    /// finalized transfers, plus the unfinalized ones up to the block when
+    /// `include_unfinalized`. Whichever is fewer rows is streamed: the
+    /// transfers up to the block, added up from nothing, the nearest balance
+    /// checkpoint below the block with the transfers since added to it, or
+    /// the current balances with the finalized transfers above the block
+    /// taken back out. Only meaningful when every block up to `block_number`
+    /// has been indexed.
+    pub fn balances_at_block(
+        &self,
+        block_number: u64,
+        include_unfinalized: bool,
+    ) -> Result<BalanceSnapshot> {
+        let count = |sql: &str| -> Result<u64> {
+            let count =
+                self.conn
+                    .query_row(sql, params![self.token_address, block_number], |row| {
+                        row.get(0)
+                    })?;
+            Ok(count)
+        };
+        let forward_rows: u64 = self.conn.query_row(
+            Self::COUNT_TRANSFERS_UP_TO,
+            params![self.token_address, block_number, include_unfinalized],
+            |row| row.get(0),
+        )?;
+        let holders: u64 =
+            self.conn
+                .query_row(Self::COUNT_HOLDERS, params![self.token_address], |row| {
+                    row.get(0)
+                })?;
+        let above = count(Self::COUNT_FINALIZED_TRANSFERS_ABOVE)?;
+        let unfinalized = if include_unfinalized {
+            count(Self::COUNT_UNFINALIZED_TRANSFERS_UP_TO)?
+        } else {
+            0
         };
+        let checkpoints = CheckpointRepository::new(self.conn, &self.token);
+        // Rows to read starting from the checkpoint: its balances, then the
+        // transfers it doesn't hold
+        let from_checkpoint = match checkpoints.nearest(block_number)? {
+            Some(checkpoint) => {
+                let since: u64 = self.conn.query_row(
+                    Self::COUNT_TRANSFERS_SINCE_CHECKPOINT,
+                    params![
+                        self.token_address,
+                        block_number,
+                        include_unfinalized,
+                        checkpoint.block_number
+                    ],
+                    |row| row.get(0),
+                )?;
+                Some((checkpoint.block_number, checkpoint.holders + since, since))
+            }
+            None => None,
+        };
+        let reverse_rows = holders + above + unfinalized;
 
-        Ok(BalanceInfo { balance })
+        let mut balances: HashMap<String, U256> = HashMap::new();
+        let (direction, transfers_applied) = if let Some((checkpoint, _, since)) =
+            from_checkpoint.filter(|&(_, rows, _)| rows <= forward_rows && rows <= reverse_rows)
+        {
+            checkpoints.load_into(checkpoint, &mut balances)?;
+            self.stream_deltas(
+                &mut balances,
+                Self::SELECT_TRANSFERS_SINCE_CHECKPOINT,
+                params![
+                    self.token_address,
+                    block_number,
+                    include_unfinalized,
+                    checkpoint
+                ],
+                false,
+            )?;
+            (SnapshotDirection::FromCheckpoint(checkpoint), since)
+        } else if reverse_rows < forward_rows {
+            let mut stmt = self.conn.prepare(Self::SELECT_ALL_BALANCES)?;
+            let mut rows = stmt.query(params![self.token_address])?;
+            while let Some(row) = rows.next()? {
+                balances.insert(row.get(0)?, u256_column(row, 1)?);
+            }
+            self.stream_deltas(
+                &mut balances,
+                Self::SELECT_FINALIZED_TRANSFERS_ABOVE,
+                params![self.token_address, block_number],
+                true,
+            )?;
+            if include_unfinalized {
+                self.stream_deltas(
+                    &mut balances,
+                    Self::SELECT_UNFINALIZED_TRANSFERS_UP_TO,
+                    params![self.token_address, block_number],
+                    false,
+                )?;
+            }
+            (SnapshotDirection::Reverse, above + unfinalized)
+        } else {
+            self.stream_deltas(
+                &mut balances,
+                Self::SELECT_TRANSFERS_UP_TO,
+                params![self.token_address, block_number, include_unfinalized],
+                false,
+            )?;
+            (SnapshotDirection::Forward, forward_rows)
+        };
+
+        // Mints come from the zero address, which never holds a balance
+        let zero = addr_to_db_string(&Address::ZERO);
+        let mut holders = balances
+            .into_iter()
+            .filter(|(address, balance)| !balance.is_zero() && *address != zero)
+            .map(|(address, balance)| {
+                Ok(TokenHolder {
+                    address: addr_from_db_string(&address)?,
+                    balance,
+                    share: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        holders.sort_by(|a, b| b.balance.cmp(&a.balance).then(a.address.cmp(&b.address)));
+
+        Ok(BalanceSnapshot {
+            block_number,
+            direction,
+            transfers_applied,
+            holders,
+        })
+    }
+
+    /// Add the transfers `sql` returns to `balances` one row at a time, or
+    /// take them back out when `revert`
+    fn stream_deltas(
+        &self,
+        balances: &mut HashMap<String, U256>,
+        sql: &str,
+        params: impl rusqlite::Params,
+        revert: bool,
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let mut rows = stmt.query(params)?;
+        while let Some(row) = rows.next()? {
+            let (mut from, mut to): (String, String) = (row.get(0)?, row.get(1)?);
+            if revert {
+                std::mem::swap(&mut from, &mut to);
+            }
+            let value = u256_column(row, 2)?;
+            let sender = balances.entry(from).or_default();
+            *sender = sender.saturating_sub(value);
+            let recipient = balances.entry(to).or_default();
+            *recipient = recipient.saturating_add(value);
+        }
+        Ok(())
     }
 
     /// Get top holders sorted by balance
     pub fn get_top_holders(&self, limit: usize) -> Result<Vec<TokenHolder>> {
         let mut stmt = self.conn.prepare(
             "SELECT address, balance_padded FROM balances 
+             WHERE token_address = ?1
              ORDER BY balance_padded DESC 
-             LIMIT ?1",
+             LIMIT ?2",
         )?;
 
         let holders = stmt
-            .query_map(params![limit], |row| {
-                let address_str: String = row.get(0)?;
-                let padded: String = row.get(1)?;
-
-                let address = Address::from_str(&address_str).map_err(|e| {
-                    rusqlite::Error::FromSqlConversionFailure(
-                        0,
-                        rusqlite::types::Type::Text,
-                        Box::new(e),
-                    )
-                })?;
+            .query_map(params![self.token_address, limit], |row| {
+                let address = addr_column(row, 0)?;
+
+                let balance = u256_column(row, 1)?;
 
-                // Remove leading zeros and parse
-                let trimmed = padded.trim_start_matches('0');
-                let balance = if trimmed.is_empty() {
-                    U256::ZERO
-                } else {
-                    U256::from_str(trimmed).map_err(|e| {
-                        rusqlite::Error::FromSqlConversionFailure(
-                            1,
-                            rusqlite::types::Type::Text,
-                            Box::new(e),
-                        )
-                    })?
-                };
-
-                Ok(TokenHolder { address, balance })
+                Ok(TokenHolder {
+                    address,
+                    balance,
+                    share: None,
+                })
             })?
             .collect::<Result<Vec<_>, _>>()?;
 
         Ok(holders)
     }
 
-    /// Populate initial balances from existing transfers
-    /// This is used during migration to build the initial balance table
-    pub fn populate_from_transfers(&self, conn: &Connection) -> Result<()> {
+    /// Visit every holder in balance-descending order, one row at a time so
+    /// the full holder set is never held in memory. Returns the number visited.
+    pub fn iter_all_holders<F>(&self, mut visit: F) -> Result<u64>
+    where
+        F: FnMut(TokenHolder) -> Result<()>,
+    {
+        let mut stmt = self.conn.prepare(
+            "SELECT address, balance_padded FROM balances
+             WHERE token_address = ?1
+             ORDER BY balance_padded DESC",
+        )?;
+
+        let mut rows = stmt.query(params![self.token_address])?;
+        let mut count = 0;
+        while let Some(row) = rows.next()? {
+            visit(TokenHolder {
+                address: addr_column(row, 0)?,
+                balance: u256_column(row, 1)?,
+                share: None,
+            })?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Sum of all balances of the token. Blobs can't be summed in SQL, so this
+    /// walks every balance row.
+    pub fn get_total_balance(&self) -> Result<U256> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT balance_padded FROM balances WHERE token_address = ?1")?;
+
+        let mut total = U256::ZERO;
+        let balances = stmt.query_map(params![self.token_address], |row| u256_column(row, 0))?;
+        for balance in balances {
+            total = total.saturating_add(balance?);
+        }
+
+        Ok(total)
+    }
+
+    /// Top holders with at least `min_balance`, each with its percentage of the
+    /// summed balances. Returns the holders and that sum.
+    pub fn get_top_holders_with_share(
+        &self,
+        limit: usize,
+        min_balance: U256,
+    ) -> Result<(Vec<TokenHolder>, U256)> {
+        let total = self.get_total_balance()?;
+
+        // Fixed-width big-endian blobs compare like the numbers they encode
+        let mut stmt = self.conn.prepare(
+            "SELECT address, balance_padded FROM balances
+             WHERE token_address = ?1 AND balance_padded >= ?2
+             ORDER BY balance_padded DESC
+             LIMIT ?3",
+        )?;
+
+        let holders = stmt
+            .query_map(
+                params![self.token_address, u256_to_blob(&min_balance), limit],
+                |row| {
+                    let balance = u256_column(row, 1)?;
+                    Ok(TokenHolder {
+                        address: addr_column(row, 0)?,
+                        balance,
+                        share: Some(share_of(balance, total)),
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((holders, total))
+    }
+
+    /// Holder counts overall and above each of `thresholds`, plus the share of
+    /// the top 10/50/100 holders
+    pub fn get_distribution(&self, thresholds: &[U256]) -> Result<Distribution> {
+        let total_holders: u64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM balances WHERE token_address = ?1",
+            params![self.token_address],
+            |row| row.get(0),
+        )?;
+        let total_balance = self.get_total_balance()?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT COUNT(*) FROM balances WHERE token_address = ?1 AND balance_padded >= ?2",
+        )?;
+        let holders_above = thresholds
+            .iter()
+            .map(|threshold| {
+                let count = stmt.query_row(
+                    params![self.token_address, u256_to_blob(threshold)],
+                    |row| row.get(0),
+                )?;
+                Ok((*threshold, count))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // One pass over the largest balances covers every top-N cut-off
+        let largest = DISTRIBUTION_TOP_N.iter().copied().max().unwrap_or(0);
+        let top_balances = self
+            .get_top_holders(largest)?
+            .into_iter()
+            .map(|holder| holder.balance)
+            .collect::<Vec<_>>();
+
+        let top_shares = DISTRIBUTION_TOP_N
+            .iter()
+            .map(|&n| {
+                let held = top_balances
+                    .iter()
+                    .take(n)
+                    .fold(U256::ZERO, |sum, balance| sum.saturating_add(*balance));
+                (n, share_of(held, total_balance))
+            })
+            .collect();
+
+        Ok(Distribution {
+            total_holders,
+            total_balance,
+            holders_above,
+            top_shares,
+        })
+    }
+
+    /// Drop the token's balances and recompute them from its finalized transfers
+    pub fn rebuild_from_transfers(&self) -> Result<()> {
+        self.conn
+            .execute(Self::DELETE_ALL_BALANCES, params![self.token_address])?;
+        self.populate_from_transfers(self.conn)
+    }
+
+    /// Balances of every address that appears in a finalized transfer, computed
+    /// in memory from the transfers table. Addresses that end up at zero are
+    /// kept, except the zero address, which never holds a balance.
+    pub fn derive_from_transfers(&self, conn: &Connection) -> Result<HashMap<Address, U256>> {
         info!("Loading all finalized transfers into memory...");
 
         let mut balances: HashMap<Address, U256> = HashMap::new();
 
-        // Load all transfers in one query and process in memory
+        // Load all transfers in one query and process in memory, in chain order
+        // so a balance is never saturated by a send seen before its receive
         let mut stmt = conn.prepare(
-            "SELECT from_address, to_address, value 
-             FROM transfers 
-             WHERE is_finalized = 1",
+            "SELECT from_address, to_address, value
+             FROM transfers
+             WHERE token_address = ?1 AND is_finalized = 1
+             ORDER BY block_number, log_index",
         )?;
 
         let mut count = 0;
-        let rows = stmt.query_map([], |row| {
+        let rows = stmt.query_map(params![self.token_address], |row| {
             Ok((
                 row.get::<_, String>(0)?, // from_address
                 row.get::<_, String>(1)?, // to_address
-                row.get::<_, String>(2)?, // value
+                u256_column(row, 2)?,     // value
             ))
         })?;
 
         for row in rows {
-            let (from_str, to_str, value_str) = row?;
+            let (from_str, to_str, value) = row?;
 
-            let from_address = Address::from_str(&from_str)?;
-            let to_address = Address::from_str(&to_str)?;
-            let value = U256::from_str(&value_str)
-                .map_err(|_| anyhow::anyhow!("Invalid value format: {}", value_str))?;
+            let from_address = addr_from_db_string(&from_str)?;
+            let to_address = addr_from_db_string(&to_str)?;
 
             // Subtract from sender
             let from_balance = balances.entry(from_address).or_insert(U256::ZERO);
@@ -376,9 +959,19 @@ impl<'a> BalanceRepository<'a> {
             }
         }
 
+        balances.remove(&Address::ZERO);
+
         info!("Processed {} total transfers", count);
         info!("Calculated balances for {} addresses", balances.len());
 
+        Ok(balances)
+    }
+
+    /// Populate initial balances from existing transfers
+    /// This is used during migration to build the initial balance table
+    pub fn populate_from_transfers(&self, conn: &Connection) -> Result<()> {
+        let balances = self.derive_from_transfers(conn)?;
+
         // Filter out zero balances
         let non_zero_balances: HashMap<Address, U256> = balances
             .into_iter()
@@ -418,3 +1011,290 @@ impl<'a> BalanceRepository<'a> {
         Ok(())
     }
 }
+
+/// `part` as a percentage of `total`
+fn share_of(part: U256, total: U256) -> f64 {
+    if total.is_zero() {
+        return 0.0;
+    }
+    f64::from(part) / f64::from(total) * 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{Database, TransferRepository};
+    use crate::testutil::{
+        DataGenerator, fresh_database, holder, mix, remove_database, temp_database_path,
+        token_address,
+    };
+
+    fn database(name: &str) -> Database {
+        fresh_database(&temp_database_path(&format!("balances-{name}"))).unwrap()
+    }
+
+    fn cleanup(name: &str) {
+        remove_database(&temp_database_path(&format!("balances-{name}")));
+    }
+
+    fn stored(db: &Database) -> HashMap<Address, U256> {
+        let repo = BalanceRepository::new(&db.conn, &token_address());
+        db.conn
+            .prepare(BalanceRepository::SELECT_ALL_BALANCES)
+            .unwrap()
+            .query_map(params![repo.token_address], |row| {
+                Ok((addr_column(row, 0)?, u256_column(row, 1)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    /// A transfer of `value` in `block`, finalized
+    fn transfer(block: u64, from: Address, to: Address, value: u64) -> Transfer {
+        DataGenerator::new(block)
+            .with_start_block(block)
+            .map(|generated| Transfer {
+                from_address: from,
+                to_address: to,
+                value: U256::from(value),
+                ..generated
+            })
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn applying_in_random_batches_matches_recomputing_from_scratch() {
+        for seed in 1..=8 {
+            let name = format!("random-{seed}");
+            let db = database(&name);
+            let mut generator = DataGenerator::new(seed)
+                .with_holders(20, 1.0)
+                .with_value_range(1, 1_000);
+            let transfers = generator.next_batch(400);
+
+            // Stored unfinalized, so only apply_transfers touches the balances
+            let unfinalized: Vec<Transfer> = transfers
+                .iter()
+                .map(|t| Transfer {
+                    is_finalized: false,
+                    ..t.clone()
+                })
+                .collect();
+            TransferRepository::new(&db.conn, &token_address())
+                .insert_batch(&unfinalized)
+                .unwrap();
+
+            // Batches of 1 to 40 in chain order, each applied back to front:
+            // netting per address makes the order inside a batch irrelevant
+            let repo = BalanceRepository::new(&db.conn, &token_address());
+            let mut rest = transfers.as_slice();
+            let mut draw = 0;
+            while !rest.is_empty() {
+                draw += 1;
+                let size = (1 + mix(seed * 1_000 + draw) % 40) as usize;
+                let (batch, tail) = rest.split_at(size.min(rest.len()));
+                let reversed: Vec<Transfer> = batch.iter().rev().cloned().collect();
+                repo.apply_transfers(&reversed).unwrap();
+                rest = tail;
+            }
+            let applied = stored(&db);
+
+            db.conn
+                .execute("UPDATE transfers SET is_finalized = 1", [])
+                .unwrap();
+            repo.rebuild_from_transfers().unwrap();
+            assert_eq!(applied, stored(&db), "seed {seed}");
+
+            let expected: HashMap<Address, U256> = generator
+                .balances()
+                .iter()
+                .filter(|(_, balance)| !balance.is_zero())
+                .map(|(address, balance)| (*address, *balance))
+                .collect();
+            assert_eq!(applied, expected, "seed {seed}");
+
+            drop(db);
+            cleanup(&name);
+        }
+    }
+
+    #[test]
+    fn a_net_decrease_past_the_stored_balance_is_an_error() {
+        let db = database("shortfall");
+        let repo = BalanceRepository::new(&db.conn, &token_address());
+        repo.apply_transfers(&[
+            transfer(1, Address::ZERO, holder(1), 100),
+            transfer(1, Address::ZERO, holder(2), 30),
+        ])
+        .unwrap();
+
+        // Receives 30 but sends 200 in the same batch: 70 short
+        let error = repo
+            .apply_transfers(&[
+                transfer(2, holder(2), holder(1), 30),
+                transfer(2, holder(1), holder(3), 200),
+            ])
+            .unwrap_err();
+
+        match error {
+            IndexerError::BalanceShortfall {
+                address,
+                block_number,
+                balance,
+                shortfall,
+            } => assert_eq!(
+                (address, block_number, balance, shortfall),
+                (holder(1), 2, U256::from(100), U256::from(70))
+            ),
+            error => panic!("expected a balance shortfall, got {error:#}"),
+        }
+        // The whole batch was rolled back
+        assert_eq!(
+            stored(&db),
+            HashMap::from([(holder(1), U256::from(100)), (holder(2), U256::from(30))])
+        );
+
+        drop(db);
+        cleanup("shortfall");
+    }
+
+    /// Rows of `balance_anomalies` as (address, block, balance, shortfall)
+    fn anomalies(db: &Database) -> Vec<(Address, u64, U256, U256)> {
+        db.conn
+            .prepare(
+                "SELECT address, block_number, balance_padded, shortfall_padded
+                 FROM balance_anomalies ORDER BY id",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    addr_column(row, 0)?,
+                    row.get(1)?,
+                    u256_column(row, 2)?,
+                    u256_column(row, 3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn a_sender_without_a_balance_row_is_recorded_as_an_anomaly() {
+        let db = database("anomaly");
+        let transfers = TransferRepository::new(&db.conn, &token_address());
+
+        // holder(1) never received anything
+        let error = transfers
+            .insert_batch(&[transfer(5, holder(1), holder(2), 40)])
+            .unwrap_err();
+        assert!(
+            matches!(error, IndexerError::BalanceShortfall { .. }),
+            "{error:#}"
+        );
+
+        // Recorded after the rollback, which took the transfer back out
+        assert_eq!(
+            anomalies(&db),
+            vec![(holder(1), 5, U256::ZERO, U256::from(40))]
+        );
+        assert!(stored(&db).is_empty());
+        let count: u64 = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM transfers", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+
+        drop(db);
+        cleanup("anomaly");
+    }
+
+    #[test]
+    fn a_batch_failing_twice_on_a_shortfall_records_it_once() {
+        let db = database("anomaly-once");
+        let transfers = TransferRepository::new(&db.conn, &token_address());
+        transfers
+            .insert_batch(&[transfer(1, Address::ZERO, holder(1), 50)])
+            .unwrap();
+
+        // Down to 10 at block 2, below zero at block 4 and back up by 10 at
+        // block 5, still 10 short in all
+        let batch = [
+            transfer(2, holder(1), holder(2), 40),
+            transfer(4, holder(1), holder(2), 30),
+            transfer(5, holder(2), holder(1), 10),
+        ];
+        for _ in 0..2 {
+            let error = transfers.insert_batch(&batch).unwrap_err();
+            assert!(error.is_fatal(), "{error:#}");
+            assert!(
+                matches!(
+                    error,
+                    IndexerError::BalanceShortfall {
+                        block_number: 4,
+                        ..
+                    }
+                ),
+                "{error:#}"
+            );
+        }
+
+        assert_eq!(
+            anomalies(&db),
+            vec![(holder(1), 4, U256::from(50), U256::from(10))]
+        );
+        assert_eq!(stored(&db), HashMap::from([(holder(1), U256::from(50))]));
+
+        drop(db);
+        cleanup("anomaly-once");
+    }
+
+    #[test]
+    fn a_net_negative_batch_within_the_prior_balance_is_applied() {
+        let db = database("net-negative");
+        let repo = BalanceRepository::new(&db.conn, &token_address());
+        repo.apply_transfers(&[transfer(1, Address::ZERO, holder(1), 100)])
+            .unwrap();
+
+        // Sends before it receives in the batch, and 30 more than it receives
+        repo.apply_transfers(&[
+            transfer(2, holder(1), holder(2), 50),
+            transfer(2, holder(2), holder(1), 20),
+            transfer(3, holder(1), holder(3), 10),
+            transfer(3, holder(3), holder(1), 10),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            stored(&db),
+            HashMap::from([(holder(1), U256::from(70)), (holder(2), U256::from(30))])
+        );
+        assert!(anomalies(&db).is_empty());
+
+        drop(db);
+        cleanup("net-negative");
+    }
+
+    #[test]
+    fn mints_from_the_zero_address_are_never_anomalies() {
+        let db = database("mints");
+        let repo = BalanceRepository::new(&db.conn, &token_address());
+
+        repo.apply_transfers(&[
+            transfer(1, Address::ZERO, holder(1), 100),
+            transfer(1, Address::ZERO, holder(2), 5),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            stored(&db),
+            HashMap::from([(holder(1), U256::from(100)), (holder(2), U256::from(5))])
+        );
+        assert!(anomalies(&db).is_empty());
+
+        drop(db);
+        cleanup("mints");
+    }
+}
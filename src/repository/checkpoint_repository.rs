@@ -0,0 +1,180 @@
+use super::address::addr_to_db_string;
+use super::balance_repository::BalanceSnapshot;
+use super::codec::{u256_column, u256_to_blob};
+use crate::error::Result;
+use alloy_primitives::{Address, U256};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::collections::{HashMap, HashSet};
+
+/// A complete checkpoint of a token's balances
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BalanceCheckpoint {
+    pub block_number: u64,
+    /// Holders with a non-zero balance, which is how many rows it stores
+    pub holders: u64,
+    /// Finalized transfers up to the block when it was written
+    pub transfers: u64,
+}
+
+/// Finalized balances of every holder at the end of every
+/// `BALANCE_CHECKPOINT_INTERVAL` blocks, which historical balance queries
+/// start from instead of replaying every transfer. Only finalized blocks are
+/// checkpointed, and any write to the transfers at or below a checkpoint
+/// drops it, so it's written again with the change in.
+pub struct CheckpointRepository<'a> {
+    conn: &'a Connection,
+    token_address: String,
+}
+
+impl<'a> CheckpointRepository<'a> {
+    const SELECT_NEAREST: &'static str =
+        "SELECT block_number, holders, transfers FROM balance_checkpoint_blocks
+         WHERE token_address = ?1 AND block_number <= ?2
+         ORDER BY block_number DESC LIMIT 1";
+
+    const SELECT_BLOCKS_IN_RANGE: &'static str =
+        "SELECT block_number FROM balance_checkpoint_blocks
+         WHERE token_address = ?1 AND block_number BETWEEN ?2 AND ?3";
+
+    const SELECT_BALANCES: &'static str = "SELECT address, balance_padded FROM balance_checkpoints
+         WHERE token_address = ?1 AND block_number = ?2";
+
+    const SELECT_BALANCE: &'static str = "SELECT balance_padded FROM balance_checkpoints
+         WHERE token_address = ?1 AND block_number = ?2 AND address = ?3";
+
+    const INSERT_BALANCE: &'static str = "INSERT INTO balance_checkpoints (
+            token_address, block_number, address, balance_padded
+         ) VALUES (?1, ?2, ?3, ?4)";
+
+    const INSERT_BLOCK: &'static str = "INSERT INTO balance_checkpoint_blocks (
+            token_address, block_number, holders, transfers, created_at
+         ) VALUES (?1, ?2, ?3, ?4, unixepoch())";
+
+    const DELETE_BALANCES_FROM: &'static str =
+        "DELETE FROM balance_checkpoints WHERE token_address = ?1 AND block_number >= ?2";
+
+    const DELETE_BLOCKS_FROM: &'static str =
+        "DELETE FROM balance_checkpoint_blocks WHERE token_address = ?1 AND block_number >= ?2";
+
+    const COUNT_FINALIZED_TRANSFERS_UP_TO: &'static str = "SELECT COUNT(*) FROM transfers
+         WHERE token_address = ?1 AND block_number <= ?2 AND is_finalized = 1";
+
+    pub fn new(conn: &'a Connection, token_address: &Address) -> Self {
+        Self {
+            conn,
+            token_address: addr_to_db_string(token_address),
+        }
+    }
+
+    /// The last checkpoint at or below `block_number`
+    pub fn nearest(&self, block_number: u64) -> Result<Option<BalanceCheckpoint>> {
+        let checkpoint = self
+            .conn
+            .query_row(
+                Self::SELECT_NEAREST,
+                params![self.token_address, block_number],
+                |row| {
+                    Ok(BalanceCheckpoint {
+                        block_number: row.get(0)?,
+                        holders: row.get(1)?,
+                        transfers: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(checkpoint)
+    }
+
+    /// Multiples of `interval` in `from..=to` that have no checkpoint yet, in
+    /// block order
+    pub fn missing(&self, interval: u64, from: u64, to: u64) -> Result<Vec<u64>> {
+        if interval == 0 || from > to {
+            return Ok(Vec::new());
+        }
+        let written: HashSet<u64> = self
+            .conn
+            .prepare(Self::SELECT_BLOCKS_IN_RANGE)?
+            .query_map(params![self.token_address, from, to], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        let first = from.div_ceil(interval).max(1) * interval;
+        Ok((first..=to)
+            .step_by(interval as usize)
+            .filter(|block| !written.contains(block))
+            .collect())
+    }
+
+    /// Add the balances stored at `block_number` to `balances`, keyed by the
+    /// stored address
+    pub fn load_into(&self, block_number: u64, balances: &mut HashMap<String, U256>) -> Result<()> {
+        let mut stmt = self.conn.prepare(Self::SELECT_BALANCES)?;
+        let mut rows = stmt.query(params![self.token_address, block_number])?;
+        while let Some(row) = rows.next()? {
+            balances.insert(row.get(0)?, u256_column(row, 1)?);
+        }
+        Ok(())
+    }
+
+    /// Balance of one address at the checkpoint at `block_number`, zero when
+    /// it held nothing then
+    pub fn balance(&self, block_number: u64, address: &Address) -> Result<U256> {
+        let balance = self
+            .conn
+            .query_row(
+                Self::SELECT_BALANCE,
+                params![self.token_address, block_number, addr_to_db_string(address)],
+                |row| u256_column(row, 0),
+            )
+            .optional()?;
+        Ok(balance.unwrap_or(U256::ZERO))
+    }
+
+    /// Store `snapshot` as the checkpoint of its block, inside the caller's
+    /// transaction. `transfers` is the count of finalized transfers up to the
+    /// block it was computed from.
+    pub fn write(&self, snapshot: &BalanceSnapshot, transfers: u64) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(Self::INSERT_BALANCE)?;
+        for holder in &snapshot.holders {
+            stmt.execute(params![
+                self.token_address,
+                snapshot.block_number,
+                addr_to_db_string(&holder.address),
+                u256_to_blob(&holder.balance)
+            ])?;
+        }
+        self.conn.execute(
+            Self::INSERT_BLOCK,
+            params![
+                self.token_address,
+                snapshot.block_number,
+                snapshot.holders.len() as u64,
+                transfers
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Drop the checkpoints at or above `block_number`, inside the caller's
+    /// transaction, returning how many there were
+    pub fn invalidate_from(&self, block_number: u64) -> Result<usize> {
+        self.conn
+            .prepare_cached(Self::DELETE_BALANCES_FROM)?
+            .execute(params![self.token_address, block_number])?;
+        let dropped = self
+            .conn
+            .prepare_cached(Self::DELETE_BLOCKS_FROM)?
+            .execute(params![self.token_address, block_number])?;
+        Ok(dropped)
+    }
+
+    /// Finalized transfers up to `block_number`, which tells whether they
+    /// changed between computing a checkpoint and writing it
+    pub fn count_finalized_up_to(&self, block_number: u64) -> Result<u64> {
+        let count = self.conn.query_row(
+            Self::COUNT_FINALIZED_TRANSFERS_UP_TO,
+            params![self.token_address, block_number],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+}
@@ -0,0 +1,28 @@
+use crate::error::{Result, StorageError};
+use alloy_primitives::U256;
+
+/// Encode a token amount as a 32-byte big-endian blob. Fixed-width big-endian
+/// blobs compare the same way as the numbers, so ORDER BY still works.
+pub fn u256_to_blob(value: &U256) -> [u8; 32] {
+    value.to_be_bytes()
+}
+
+pub fn blob_to_u256(blob: &[u8]) -> Result<U256> {
+    if blob.len() != 32 {
+        return Err(StorageError::Unusable(format!(
+            "Expected a 32-byte amount, got {} bytes",
+            blob.len()
+        ))
+        .into());
+    }
+    Ok(U256::from_be_slice(blob))
+}
+
+/// Read a 32-byte amount column, reporting a bad blob as a conversion error on
+/// that column
+pub fn u256_column(row: &rusqlite::Row, index: usize) -> rusqlite::Result<U256> {
+    let blob: Vec<u8> = row.get(index)?;
+    blob_to_u256(&blob).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Blob, e.into())
+    })
+}
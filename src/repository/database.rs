@@ -1,26 +1,435 @@
+use super::address::{addr_from_db_string, addr_to_db_string};
 use super::balance_repository::BalanceRepository;
-use anyhow::{Context, Result};
-use rusqlite::Connection;
+use super::codec::u256_to_blob;
+use super::pool::{PooledConnection, ReadPool};
+use crate::error::Result;
+use crate::error::{IndexerError, StorageError};
+use alloy_primitives::{Address, U256};
+use anyhow::Context;
+use rusqlite::{Connection, OpenFlags, Transaction, TransactionBehavior, params};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use tracing::info;
 
+/// Highest migration this binary knows about. Read-only connections refuse
+/// databases at any other version, since they can't migrate them, and
+/// writers refuse databases a newer binary has migrated past it.
+pub const SCHEMA_VERSION: i32 = 25;
+
+/// Connection-level SQLite tuning applied to every connection we open
+#[derive(Debug, Clone)]
+pub struct SqliteOptions {
+    /// How long a connection waits on a lock held by another connection (e.g.
+    /// the finality worker and the insertion worker writing concurrently)
+    pub busy_timeout: Duration,
+    /// Page cache size in KiB
+    pub cache_size_kib: u64,
+    /// Maximum bytes of the database file to memory-map, 0 disables mmap
+    pub mmap_size: u64,
+    /// Read-only connections readers share alongside the writer connection
+    pub read_pool_size: usize,
+}
+
+impl Default for SqliteOptions {
+    fn default() -> Self {
+        Self {
+            busy_timeout: Duration::from_secs(30),
+            cache_size_kib: 64 * 1024,
+            mmap_size: 256 * 1024 * 1024,
+            read_pool_size: 4,
+        }
+    }
+}
+
+/// Whether a migration has been applied to a database
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i32,
+    pub applied: bool,
+    /// As SQLite's CURRENT_TIMESTAMP wrote it, in UTC
+    pub applied_at: Option<String>,
+    /// False for a migration a newer binary applied
+    pub known: bool,
+    /// Whether `migrate --down-to` can undo it
+    pub reversible: bool,
+}
+
 pub struct Database {
     pub conn: Connection,
     db_path: String,
+    options: SqliteOptions,
+    read_only: bool,
+    readers: Arc<ReadPool>,
+    /// Connections opened by this database and its clones, not counting the
+    /// reader pool's
+    opened: Arc<AtomicUsize>,
 }
 
 impl Database {
     pub fn new(db_path: &str) -> Result<Self> {
-        let db_path = db_path.strip_prefix("sqlite:").unwrap_or(db_path);
-        let conn = Connection::open(db_path).context("Failed to open database")?;
+        Self::with_options(db_path, SqliteOptions::default())
+    }
+
+    pub fn with_options(db_path: &str, options: SqliteOptions) -> Result<Self> {
+        let db_path = sqlite_path(db_path);
+        let conn = Self::open_connection(db_path, &options)?;
+
+        // Migrating a database a newer binary wrote would leave it half
+        // understood, so don't touch it
+        if let Some(version) = applied_version(&conn)?
+            && version > SCHEMA_VERSION
+        {
+            return Err(StorageError::Unusable(format!(
+                "{db_path} is at schema version {version}, newer than the {SCHEMA_VERSION} this binary understands; upgrade it"
+            ))
+            .into());
+        }
 
         let db = Database {
             conn,
             db_path: db_path.to_string(),
+            readers: Arc::new(ReadPool::new(
+                db_path,
+                options.clone(),
+                options.read_pool_size,
+            )),
+            options,
+            read_only: false,
+            opened: Arc::new(AtomicUsize::new(1)),
         };
         db.create_tables()?;
         Ok(db)
     }
 
+    /// A private in-memory database with the current schema, for a dry run.
+    /// When `db_path` exists, `token_address`'s row of `tokens` and its
+    /// resolved segments are copied from it so the run resumes where the
+    /// indexer stopped. `db_path` is only ever read.
+    pub fn in_memory_copy(
+        db_path: &str,
+        options: SqliteOptions,
+        token_address: &Address,
+    ) -> Result<Self> {
+        let source = sqlite_path(db_path);
+        // Shared cache, so every clone's connection sees the same database
+        let memory_path = format!(
+            "file:eth-indexer-dry-run-{}?mode=memory&cache=shared",
+            std::process::id()
+        );
+        let db = Self::with_options(&memory_path, options)?;
+
+        if Path::new(source).exists() {
+            db.conn
+                .execute(
+                    "ATTACH DATABASE ?1 AS source",
+                    params![format!("file:{source}?mode=ro")],
+                )
+                .with_context(|| format!("Failed to open {source} read-only"))
+                .map_err(IndexerError::Other)?;
+            let address = addr_to_db_string(token_address);
+            db.conn
+                .execute(
+                    "INSERT INTO tokens (address, deployment_block, last_processed_block,
+                        last_processed_finalized_block, name, symbol, decimals, chain_id)
+                     SELECT address, deployment_block, last_processed_block,
+                        last_processed_finalized_block, name, symbol, decimals, chain_id
+                     FROM source.tokens WHERE address = ?1",
+                    params![address],
+                )
+                .and_then(|_| {
+                    db.conn.execute(
+                        "INSERT INTO token_segments (token_address, address, from_block)
+                         SELECT token_address, address, from_block
+                         FROM source.token_segments WHERE token_address = ?1",
+                        params![address],
+                    )
+                })
+                .with_context(|| {
+                    format!(
+                        "Failed to copy the token from {source}, run the migrate binary on it first"
+                    )
+                })
+                .map_err(IndexerError::Other)?;
+            db.conn.execute("DETACH DATABASE source", [])?;
+        }
+
+        Ok(db)
+    }
+
+    /// Open an existing database without creating tables or applying
+    /// migrations, so readers never take a schema write lock on a database the
+    /// indexer is writing. Fails when the schema isn't the one this binary
+    /// was built for.
+    pub fn open_read_only(db_path: &str, options: SqliteOptions) -> Result<Self> {
+        let db_path = sqlite_path(db_path);
+        let conn = Self::open_read_only_connection(db_path, &options)?;
+
+        let problem = match applied_version(&conn)? {
+            Some(version) if version > SCHEMA_VERSION => Some(format!(
+                "{db_path} is at schema version {version}, newer than the {SCHEMA_VERSION} this binary understands; upgrade it"
+            )),
+            Some(version) if version < SCHEMA_VERSION => Some(format!(
+                "{db_path} is at schema version {version}, older than the {SCHEMA_VERSION} this binary expects; run the migrate binary first"
+            )),
+            None => Some(format!(
+                "{db_path} has no schema yet, run the migrate or indexer binary first"
+            )),
+            Some(_) => None,
+        };
+        if let Some(problem) = problem {
+            return Err(StorageError::Unusable(problem).into());
+        }
+
+        Ok(Database {
+            conn,
+            db_path: db_path.to_string(),
+            readers: Arc::new(ReadPool::new(
+                db_path,
+                options.clone(),
+                options.read_pool_size,
+            )),
+            options,
+            read_only: true,
+            opened: Arc::new(AtomicUsize::new(1)),
+        })
+    }
+
+    /// Every migration this binary knows about and when it was applied to the
+    /// database at `db_path`, without applying the pending ones. A database
+    /// that doesn't exist yet has them all pending.
+    pub fn migration_status(
+        db_path: &str,
+        options: &SqliteOptions,
+    ) -> Result<Vec<MigrationStatus>> {
+        let db_path = sqlite_path(db_path);
+        let mut applied = std::collections::BTreeMap::new();
+        if Path::new(db_path).exists() {
+            let conn = Self::open_read_only_connection(db_path, options)?;
+            let has_table: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations')",
+                [],
+                |row| row.get(0),
+            )?;
+            if has_table {
+                let mut stmt = conn.prepare("SELECT version, applied_at FROM schema_migrations")?;
+                let rows = stmt.query_map([], |row| {
+                    Ok((row.get::<_, i32>(0)?, row.get::<_, Option<String>>(1)?))
+                })?;
+                for row in rows {
+                    let (version, applied_at) = row?;
+                    applied.insert(version, applied_at);
+                }
+            }
+        }
+
+        // Versions past SCHEMA_VERSION come from a newer binary and are listed too
+        let last = applied
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or(0)
+            .max(SCHEMA_VERSION);
+        let migrations = migrations();
+        Ok((1..=last)
+            .map(|version| {
+                let migration = migrations.iter().find(|m| m.version == version);
+                MigrationStatus {
+                    version,
+                    applied: applied.contains_key(&version),
+                    applied_at: applied.get(&version).cloned().flatten(),
+                    known: migration.is_some(),
+                    reversible: migration.is_some_and(|m| m.down.is_some()),
+                }
+            })
+            .collect())
+    }
+
+    /// Open another connection to the same database, e.g. for a worker task
+    pub fn try_clone(&self) -> Result<Self> {
+        let conn = if self.read_only {
+            Self::open_read_only_connection(&self.db_path, &self.options)
+        } else {
+            Self::open_connection(&self.db_path, &self.options)
+        }?;
+        self.opened.fetch_add(1, Ordering::Relaxed);
+        Ok(Database {
+            conn,
+            db_path: self.db_path.clone(),
+            options: self.options.clone(),
+            read_only: self.read_only,
+            readers: self.readers.clone(),
+            opened: self.opened.clone(),
+        })
+    }
+
+    /// Connections opened so far by this database and every clone of it,
+    /// its own included. The reader pool's aren't counted.
+    pub fn connections_opened(&self) -> usize {
+        self.opened.load(Ordering::Relaxed)
+    }
+
+    /// Borrow a read-only connection from the pool shared by every clone of
+    /// this database. `conn` stays the writer. Blocks while every pooled
+    /// connection is in use and panics in an async task, which goes through
+    /// [`Database::read_pool`] instead.
+    pub fn reader(&self) -> Result<PooledConnection<'_>> {
+        self.readers.blocking_get()
+    }
+
+    /// The reader pool, for tasks that read without holding this `Database`
+    pub fn read_pool(&self) -> Arc<ReadPool> {
+        self.readers.clone()
+    }
+
+    /// Bytes the database takes on disk, its write-ahead log included
+    pub fn file_size(&self) -> Result<u64> {
+        let wal_path = format!("{}-wal", self.db_path);
+        let size = [self.db_path.as_str(), wal_path.as_str()]
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        Ok(size)
+    }
+
+    /// Fail when another connection is in the middle of a write or holds a
+    /// read open that stops the log from being checkpointed, as a running
+    /// indexer does between its batches only briefly. Best effort: an idle
+    /// connection isn't noticed.
+    pub fn ensure_no_other_writer(&self) -> Result<()> {
+        self.conn.busy_timeout(Duration::ZERO)?;
+        let result = self.check_no_other_writer();
+        self.conn.busy_timeout(self.options.busy_timeout)?;
+        result
+    }
+
+    fn check_no_other_writer(&self) -> Result<()> {
+        let busy = |e: rusqlite::Error| -> IndexerError {
+            match e.sqlite_error_code() {
+                Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked) => {
+                    StorageError::Unusable(format!(
+                        "{} is being written by another connection, stop the indexer first",
+                        self.db_path
+                    ))
+                    .into()
+                }
+                _ => e.into(),
+            }
+        };
+
+        self.conn
+            .execute_batch("BEGIN IMMEDIATE; ROLLBACK;")
+            .map_err(busy)?;
+        // Returns 1 in the first column when another connection kept the log
+        // from being fully checkpointed
+        let checkpoint_busy: i64 = self
+            .conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))
+            .map_err(busy)?;
+        if checkpoint_busy != 0 {
+            return Err(StorageError::Unusable(format!(
+                "{} has another connection in a transaction, stop the indexer first",
+                self.db_path
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Let SQLite run the analysis it judges worthwhile
+    pub fn optimize(&self) -> Result<()> {
+        self.conn.execute_batch("PRAGMA optimize")?;
+        Ok(())
+    }
+
+    /// Refresh the statistics the query planner picks indexes with
+    pub fn analyze(&self) -> Result<()> {
+        self.conn.execute_batch("ANALYZE")?;
+        Ok(())
+    }
+
+    /// Give the free pages back to the file system. Only databases with
+    /// incremental auto-vacuum support this, returns false for the others.
+    pub fn incremental_vacuum(&self) -> Result<bool> {
+        let auto_vacuum: i64 = self
+            .conn
+            .query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+        // 2 is INCREMENTAL
+        if auto_vacuum != 2 {
+            return Ok(false);
+        }
+        self.conn.execute_batch("PRAGMA incremental_vacuum")?;
+        self.checkpoint()?;
+        Ok(true)
+    }
+
+    /// Rebuild the whole file without fragmentation. It also switches the
+    /// database to incremental auto-vacuum so later runs can use
+    /// [`Database::incremental_vacuum`]. Needs free disk space about the
+    /// size of the database.
+    pub fn vacuum(&self) -> Result<()> {
+        self.conn
+            .execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+        self.checkpoint()?;
+        Ok(())
+    }
+
+    /// Rebuild every index from its table
+    pub fn reindex(&self) -> Result<()> {
+        self.conn.execute_batch("REINDEX")?;
+        Ok(())
+    }
+
+    /// Copy the log into the database file and truncate it, so the file size
+    /// reflects a vacuum
+    fn checkpoint(&self) -> Result<()> {
+        self.conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    /// Open a connection in WAL mode so readers don't block the writer, with a
+    /// busy timeout so concurrent writers wait for each other instead of failing.
+    /// synchronous=NORMAL is safe under WAL and avoids an fsync per commit.
+    fn open_connection(db_path: &str, options: &SqliteOptions) -> Result<Connection> {
+        let conn = Connection::open(db_path).map_err(|source| StorageError::Open {
+            path: db_path.to_string(),
+            source,
+        })?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.busy_timeout(options.busy_timeout)?;
+        // A negative cache_size is interpreted by SQLite as KiB rather than pages
+        conn.pragma_update(None, "cache_size", -(options.cache_size_kib as i64))?;
+        conn.pragma_update(None, "mmap_size", options.mmap_size)?;
+        Ok(conn)
+    }
+
+    /// The journal mode is the writer's business, a read-only connection only
+    /// gets the tuning that doesn't write to the file
+    pub(super) fn open_read_only_connection(
+        db_path: &str,
+        options: &SqliteOptions,
+    ) -> Result<Connection> {
+        let conn = Connection::open_with_flags(
+            db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(|source| StorageError::Open {
+            path: db_path.to_string(),
+            source,
+        })?;
+        conn.busy_timeout(options.busy_timeout)?;
+        conn.pragma_update(None, "cache_size", -(options.cache_size_kib as i64))?;
+        conn.pragma_update(None, "mmap_size", options.mmap_size)?;
+        Ok(conn)
+    }
+
     fn create_tables(&self) -> Result<()> {
         self.conn.execute(
             "CREATE TABLE IF NOT EXISTS tokens (
@@ -42,7 +451,7 @@ impl Database {
                 token_address TEXT NOT NULL,
                 from_address TEXT NOT NULL,
                 to_address TEXT NOT NULL,
-                value TEXT NOT NULL,
+                value BLOB NOT NULL,
                 block_number INTEGER NOT NULL,
                 block_hash TEXT DEFAULT '',
                 is_finalized BOOLEAN DEFAULT FALSE,
@@ -52,24 +461,7 @@ impl Database {
             [],
         )?;
 
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_transfers_block_number 
-             ON transfers(block_number)",
-            [],
-        )?;
-
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_transfers_from 
-             ON transfers(from_address)",
-            [],
-        )?;
-
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_transfers_to 
-             ON transfers(to_address)",
-            [],
-        )?;
-
+        // Transfer indexes are created by the migrations
         self.run_migrations()?;
 
         Ok(())
@@ -84,7 +476,136 @@ impl Database {
             [],
         )?;
 
-        self.apply_migration(1, |conn| {
+        for migration in migrations() {
+            self.apply_migration(&migration)?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply a migration and record it in one transaction, so one that fails
+    /// halfway leaves the database at the previous version
+    fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        let version = migration.version;
+        let already_applied: bool = self
+            .conn
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM schema_migrations WHERE version = ?)",
+                [version],
+                |row| row.get(0),
+            )
+            .unwrap_or(false);
+
+        if !already_applied {
+            info!("Applying migration {version}");
+
+            let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+            (migration.up)(&tx)
+                .with_context(|| format!("Migration {version} failed, the database is unchanged"))
+                .map_err(IndexerError::Other)?;
+            tx.execute(
+                "INSERT INTO schema_migrations (version) VALUES (?)",
+                [version],
+            )?;
+            tx.commit()?;
+
+            info!("Applied migration {version}");
+        }
+
+        Ok(())
+    }
+
+    /// Undo the migrations applied above `target`, newest first, each with
+    /// its removal from `schema_migrations` in one transaction. Nothing is
+    /// undone when one of them has no down migration. Returns the versions
+    /// undone.
+    pub fn migrate_down_to(
+        db_path: &str,
+        options: &SqliteOptions,
+        target: i32,
+    ) -> Result<Vec<i32>> {
+        let db_path = sqlite_path(db_path);
+        if !Path::new(db_path).exists() {
+            return Err(StorageError::Unusable(format!("{db_path} doesn't exist")).into());
+        }
+        // Opened without create_tables, which would apply the pending migrations
+        let conn = Self::open_connection(db_path, options)?;
+
+        let mut stmt = conn.prepare(
+            "SELECT version FROM schema_migrations WHERE version > ?1 ORDER BY version DESC",
+        )?;
+        let versions = stmt
+            .query_map([target], |row| row.get::<_, i32>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+
+        let migrations = migrations();
+        let mut downs = Vec::with_capacity(versions.len());
+        for &version in &versions {
+            let down = migrations
+                .iter()
+                .find(|migration| migration.version == version)
+                .and_then(|migration| migration.down);
+            match down {
+                Some(down) => downs.push((version, down)),
+                None => {
+                    return Err(StorageError::Unusable(format!(
+                        "Migration {version} can't be undone, the lowest version {db_path} can go down to is {version}"
+                    ))
+                    .into());
+                }
+            }
+        }
+
+        for (version, down) in downs {
+            info!("Undoing migration {version}");
+            let tx = Transaction::new_unchecked(&conn, TransactionBehavior::Immediate)?;
+            down(&tx)
+                .with_context(|| {
+                    format!("Undoing migration {version} failed, the database is still at it")
+                })
+                .map_err(IndexerError::Other)?;
+            tx.execute(
+                "DELETE FROM schema_migrations WHERE version = ?1",
+                [version],
+            )?;
+            tx.commit()?;
+        }
+
+        Ok(versions)
+    }
+}
+
+/// A schema change, and how to undo it when that's possible without losing data
+struct Migration {
+    version: i32,
+    up: fn(&Connection) -> Result<()>,
+    down: Option<fn(&Connection) -> Result<()>>,
+}
+
+impl Migration {
+    fn new(version: i32, up: fn(&Connection) -> Result<()>) -> Self {
+        Self {
+            version,
+            up,
+            down: None,
+        }
+    }
+
+    fn with_down(mut self, down: fn(&Connection) -> Result<()>) -> Self {
+        self.down = Some(down);
+        self
+    }
+}
+
+/// Every schema change in order. Migrations that only add tables, columns or
+/// indexes can be undone with `migrate --down-to`. 3, 6 and 7 rewrite data
+/// in ways that can't be reversed and 2's table was replaced by 3's, so they
+/// have no down migration. 1's drops the finality columns and what they held,
+/// so only a database that never went past it can go back to version 0.
+fn migrations() -> Vec<Migration> {
+    vec![
+        Migration::new(1, |conn| {
             // Migration 1: Add finality tracking columns
 
             let mut stmt = conn.prepare("PRAGMA table_info(transfers)")?;
@@ -134,9 +655,47 @@ impl Database {
             }
 
             Ok(())
-        })?;
+        })
+        .with_down(|conn| {
+            // Rebuild both tables as they were before migration 1. The block
+            // hashes, finality flags and finalized cursors are lost.
+            conn.execute_batch(
+                "CREATE TABLE tokens_before_finality (
+                    address TEXT PRIMARY KEY,
+                    deployment_block INTEGER NOT NULL,
+                    last_processed_block INTEGER,
+                    name TEXT,
+                    symbol TEXT,
+                    decimals INTEGER
+                );
+                INSERT INTO tokens_before_finality
+                    SELECT address, deployment_block, last_processed_block, name, symbol, decimals
+                    FROM tokens;
+                DROP TABLE tokens;
+                ALTER TABLE tokens_before_finality RENAME TO tokens;
 
-        self.apply_migration(2, |conn| {
+                CREATE TABLE transfers_before_finality (
+                    transaction_hash TEXT NOT NULL,
+                    log_index INTEGER NOT NULL,
+                    token_address TEXT NOT NULL,
+                    from_address TEXT NOT NULL,
+                    to_address TEXT NOT NULL,
+                    value BLOB NOT NULL,
+                    block_number INTEGER NOT NULL,
+                    PRIMARY KEY (transaction_hash, log_index),
+                    FOREIGN KEY (token_address) REFERENCES tokens(address)
+                );
+                INSERT INTO transfers_before_finality
+                    SELECT transaction_hash, log_index, token_address, from_address,
+                        to_address, value, block_number
+                    FROM transfers;
+                DROP TABLE transfers;
+                ALTER TABLE transfers_before_finality RENAME TO transfers;",
+            )?;
+            Ok(())
+        }),
+
+        Migration::new(2, |conn| {
             // Migration 2: Add denormalized balance table
             conn.execute(
                 "CREATE TABLE IF NOT EXISTS balances (
@@ -152,54 +711,982 @@ impl Database {
                 [],
             )?;
 
-            // Populate initial balances from existing finalized transfers
-            info!("Populating initial balances from existing transfers...");
-            let balance_repo = BalanceRepository::new(conn);
-            balance_repo.populate_from_transfers(conn)?;
+            // Balances are populated per token by migration 3, which rebuilds this table
 
             Ok(())
-        })?;
+        }),
 
-        Ok(())
-    }
+        Migration::new(3, |conn| {
+            // Migration 3: Scope balances by token so multiple tokens can share a database.
+            // Transfers keep (transaction_hash, log_index) as their key since a log is
+            // globally unique, but every query now filters on token_address.
+            conn.execute("DROP TABLE IF EXISTS balances", [])?;
+            conn.execute(
+                "CREATE TABLE balances (
+                    token_address TEXT NOT NULL,
+                    address TEXT NOT NULL,
+                    balance_padded BLOB NOT NULL,
+                    PRIMARY KEY (token_address, address)
+                )",
+                [],
+            )?;
 
-    fn apply_migration<F>(&self, version: i32, migration: F) -> Result<()>
-    where
-        F: FnOnce(&Connection) -> Result<()>,
-    {
-        let already_applied: bool = self
-            .conn
-            .query_row(
-                "SELECT EXISTS(SELECT 1 FROM schema_migrations WHERE version = ?)",
-                [version],
-                |row| row.get(0),
-            )
-            .unwrap_or(false);
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_balances_token_padded
+                 ON balances(token_address, balance_padded DESC)",
+                [],
+            )?;
 
-        if !already_applied {
-            info!("Applying migration {version}");
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_transfers_token_block
+                 ON transfers(token_address, block_number)",
+                [],
+            )?;
 
-            migration(&self.conn)?;
+            // Balances are rebuilt from blob values, convert transfers written before
+            // migration 6 first so it has nothing left to do for them
+            convert_amounts_to_blobs(conn, "transfers", "value")?;
 
-            self.conn.execute(
-                "INSERT INTO schema_migrations (version) VALUES (?)",
-                [version],
+            // Rebuild balances for every indexed token from its finalized transfers
+            let mut stmt = conn.prepare("SELECT DISTINCT token_address FROM transfers")?;
+            let tokens = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            for token in tokens {
+                let token_address = addr_from_db_string(&token)?;
+                info!("Populating balances for token {token}...");
+                let balance_repo = BalanceRepository::new(conn, &token_address);
+                balance_repo.populate_from_transfers(conn)?;
+            }
+
+            Ok(())
+        }),
+
+        Migration::new(4, |conn| {
+            // Migration 4: Persist the deployment block search so it can resume
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS deployment_search (
+                    token_address TEXT PRIMARY KEY,
+                    low_block INTEGER NOT NULL,
+                    high_block INTEGER NOT NULL
+                )",
+                [],
             )?;
 
-            info!("Applied migration {version}");
-        }
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS deployment_search", [])?;
+            Ok(())
+        }),
 
-        Ok(())
+        Migration::new(5, |conn| {
+            // Migration 5: Composite indexes so address filters can also use the
+            // block range and ordering instead of sorting every matching row
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_transfers_from_block
+                 ON transfers(from_address, block_number, log_index)",
+                [],
+            )?;
+
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_transfers_to_block
+                 ON transfers(to_address, block_number, log_index)",
+                [],
+            )?;
+
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_transfers_block_log
+                 ON transfers(block_number, log_index)",
+                [],
+            )?;
+
+            // The single-column indexes are prefixes of the ones above
+            conn.execute("DROP INDEX IF EXISTS idx_transfers_block_number", [])?;
+            conn.execute("DROP INDEX IF EXISTS idx_transfers_from", [])?;
+            conn.execute("DROP INDEX IF EXISTS idx_transfers_to", [])?;
+
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_transfers_block_number ON transfers(block_number)",
+                [],
+            )?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_transfers_from ON transfers(from_address)",
+                [],
+            )?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_transfers_to ON transfers(to_address)",
+                [],
+            )?;
+            conn.execute("DROP INDEX IF EXISTS idx_transfers_from_block", [])?;
+            conn.execute("DROP INDEX IF EXISTS idx_transfers_to_block", [])?;
+            conn.execute("DROP INDEX IF EXISTS idx_transfers_block_log", [])?;
+            Ok(())
+        }),
+
+        Migration::new(6, |conn| {
+            // Migration 6: Store transfer values and balances as 32-byte
+            // big-endian blobs instead of decimal strings
+            convert_amounts_to_blobs(conn, "transfers", "value")?;
+            convert_amounts_to_blobs(conn, "balances", "balance_padded")?;
+            Ok(())
+        }),
+
+        Migration::new(7, |conn| {
+            // Migration 7: Lowercase addresses written in any other form (e.g.
+            // rows imported by hand with checksummed addresses)
+
+            // Mixed-case balance rows may duplicate lowercase ones, so the affected
+            // tokens are rebuilt from their normalized transfers afterwards
+            let mut stmt = conn.prepare(
+                "SELECT DISTINCT lower(token_address) FROM balances
+                 WHERE token_address != lower(token_address) OR address != lower(address)",
+            )?;
+            let rebuild_tokens = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // Token rows and the transfers referencing them change together, so
+            // foreign keys are only checked at commit
+            conn.pragma_update(None, "defer_foreign_keys", true)?;
+
+            // A token row may already exist in lowercase, keep that one
+            conn.execute(
+                "UPDATE OR IGNORE tokens SET address = lower(address) WHERE address != lower(address)",
+                [],
+            )?;
+            conn.execute("DELETE FROM tokens WHERE address != lower(address)", [])?;
+
+            conn.execute(
+                "UPDATE transfers SET
+                    token_address = lower(token_address),
+                    from_address = lower(from_address),
+                    to_address = lower(to_address)
+                 WHERE token_address != lower(token_address)
+                    OR from_address != lower(from_address)
+                    OR to_address != lower(to_address)",
+                [],
+            )?;
+
+            conn.execute(
+                "UPDATE OR IGNORE deployment_search SET token_address = lower(token_address)
+                 WHERE token_address != lower(token_address)",
+                [],
+            )?;
+            conn.execute(
+                "DELETE FROM deployment_search WHERE token_address != lower(token_address)",
+                [],
+            )?;
+
+            for token in &rebuild_tokens {
+                conn.execute(
+                    "DELETE FROM balances WHERE lower(token_address) = ?1",
+                    [token],
+                )?;
+            }
+
+            for token in rebuild_tokens {
+                let token_address = addr_from_db_string(&token)?;
+                info!("Rebuilding balances for token {token} with normalized addresses...");
+                let balance_repo = BalanceRepository::new(conn, &token_address);
+                balance_repo.populate_from_transfers(conn)?;
+            }
+
+            Ok(())
+        }),
+
+        Migration::new(8, |conn| {
+            // Migration 8: Running counters so stats don't scan every transfer
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS stats (
+                    token_address TEXT PRIMARY KEY,
+                    total_transfers INTEGER NOT NULL DEFAULT 0,
+                    unique_addresses INTEGER NOT NULL DEFAULT 0,
+                    earliest_block INTEGER,
+                    latest_block INTEGER
+                )",
+                [],
+            )?;
+
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS seen_addresses (
+                    token_address TEXT NOT NULL,
+                    address TEXT NOT NULL,
+                    PRIMARY KEY (token_address, address)
+                ) WITHOUT ROWID",
+                [],
+            )?;
+
+            info!("Computing transfer statistics from existing transfers...");
+
+            conn.execute(
+                "INSERT OR IGNORE INTO seen_addresses (token_address, address)
+                 SELECT token_address, from_address FROM transfers
+                 UNION
+                 SELECT token_address, to_address FROM transfers",
+                [],
+            )?;
+
+            conn.execute(
+                "INSERT OR REPLACE INTO stats (
+                    token_address, total_transfers, unique_addresses, earliest_block, latest_block
+                 )
+                 SELECT t.token_address, COUNT(*),
+                    (SELECT COUNT(*) FROM seen_addresses s WHERE s.token_address = t.token_address),
+                    MIN(t.block_number), MAX(t.block_number)
+                 FROM transfers t
+                 GROUP BY t.token_address",
+                [],
+            )?;
+
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS stats", [])?;
+            conn.execute("DROP TABLE IF EXISTS seen_addresses", [])?;
+            Ok(())
+        }),
+
+        Migration::new(9, |conn| {
+            // Migration 9: Watchlist notifications, so a restart doesn't re-send them
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS notifications (
+                    token_address TEXT NOT NULL,
+                    transaction_hash TEXT NOT NULL,
+                    log_index INTEGER NOT NULL,
+                    block_number INTEGER NOT NULL,
+                    from_address TEXT NOT NULL,
+                    to_address TEXT NOT NULL,
+                    value BLOB NOT NULL,
+                    is_finalized BOOLEAN NOT NULL,
+                    status TEXT NOT NULL,
+                    attempts INTEGER NOT NULL DEFAULT 0,
+                    created_at INTEGER NOT NULL,
+                    sent_at INTEGER,
+                    PRIMARY KEY (token_address, transaction_hash, log_index)
+                )",
+                [],
+            )?;
+
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS notifications", [])?;
+            Ok(())
+        }),
+
+        Migration::new(10, |conn| {
+            // Migration 10: Chain the token was indexed on, checked against the
+            // RPC providers on startup. Existing rows get it on their next run.
+            conn.execute("ALTER TABLE tokens ADD COLUMN chain_id INTEGER", [])?;
+
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("ALTER TABLE tokens DROP COLUMN chain_id", [])?;
+            Ok(())
+        }),
+
+        Migration::new(11, |conn| {
+            // Migration 11: Audit trail of blocks whose transfers were replaced
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS reorgs (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    token_address TEXT NOT NULL,
+                    detected_at INTEGER NOT NULL,
+                    block_number INTEGER NOT NULL,
+                    old_block_hash TEXT,
+                    new_block_hash TEXT,
+                    transfers_deleted INTEGER NOT NULL,
+                    transfers_inserted INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_reorgs_token ON reorgs(token_address, id)",
+                [],
+            )?;
+
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS reorgs", [])?;
+            Ok(())
+        }),
+
+        Migration::new(12, |conn| {
+            // Migration 12: Last block whose finalized transfers are in the balances,
+            // so re-running a finality range after a crash doesn't apply it twice.
+            // Balances so far were applied up to the finality cursor.
+            conn.execute(
+                "ALTER TABLE tokens ADD COLUMN last_balance_applied_block INTEGER",
+                [],
+            )?;
+            conn.execute(
+                "UPDATE tokens SET last_balance_applied_block = last_processed_finalized_block",
+                [],
+            )?;
+
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("ALTER TABLE tokens DROP COLUMN last_balance_applied_block", [])?;
+            Ok(())
+        }),
+
+        Migration::new(13, |conn| {
+            // Migration 13: Balance decreases that exceeded the stored balance
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS balance_anomalies (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    token_address TEXT NOT NULL,
+                    address TEXT NOT NULL,
+                    detected_at INTEGER NOT NULL,
+                    balance_padded BLOB NOT NULL,
+                    shortfall_padded BLOB NOT NULL
+                )",
+                [],
+            )?;
+
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS balance_anomalies", [])?;
+            Ok(())
+        }),
+
+        Migration::new(14, |conn| {
+            // Migration 14: eth_getCode results of an unfinished deployment search
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS code_presence (
+                    address TEXT NOT NULL,
+                    block_number INTEGER NOT NULL,
+                    has_code INTEGER NOT NULL,
+                    PRIMARY KEY (address, block_number)
+                )",
+                [],
+            )?;
+
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS code_presence", [])?;
+            Ok(())
+        }),
+
+        Migration::new(15, |conn| {
+            // Migration 15: Transfer logs skipped because they couldn't be decoded
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS decode_failures (
+                    token_address TEXT NOT NULL,
+                    transaction_hash TEXT NOT NULL,
+                    log_index INTEGER NOT NULL,
+                    block_number INTEGER NOT NULL,
+                    topics TEXT NOT NULL,
+                    data TEXT NOT NULL,
+                    error TEXT NOT NULL,
+                    recorded_at INTEGER NOT NULL,
+                    PRIMARY KEY (token_address, transaction_hash, log_index)
+                )",
+                [],
+            )?;
+
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS decode_failures", [])?;
+            Ok(())
+        }),
+
+        Migration::new(16, |conn| {
+            // Migration 16: ERC-721 token ids and their current owners
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS nft_transfers (
+                    transaction_hash TEXT NOT NULL,
+                    log_index INTEGER NOT NULL,
+                    token_id BLOB NOT NULL,
+                    PRIMARY KEY (transaction_hash, log_index)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_nft_transfers_token_id ON nft_transfers(token_id)",
+                [],
+            )?;
+
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS nft_owners (
+                    token_address TEXT NOT NULL,
+                    token_id BLOB NOT NULL,
+                    owner TEXT NOT NULL,
+                    block_number INTEGER NOT NULL,
+                    PRIMARY KEY (token_address, token_id)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_nft_owners_owner ON nft_owners(token_address, owner)",
+                [],
+            )?;
+
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS nft_transfers", [])?;
+            conn.execute("DROP TABLE IF EXISTS nft_owners", [])?;
+            Ok(())
+        }),
+
+        Migration::new(17, |conn| {
+            // Migration 17: ERC-1155 ids and amounts, and balances per id
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS multi_token_transfers (
+                    transaction_hash TEXT NOT NULL,
+                    log_index INTEGER NOT NULL,
+                    array_index INTEGER NOT NULL,
+                    token_address TEXT NOT NULL,
+                    token_id BLOB NOT NULL,
+                    value BLOB NOT NULL,
+                    PRIMARY KEY (transaction_hash, log_index, array_index)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_multi_token_transfers_token_id
+                 ON multi_token_transfers(token_address, token_id)",
+                [],
+            )?;
+
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS multi_token_balances (
+                    token_address TEXT NOT NULL,
+                    address TEXT NOT NULL,
+                    token_id BLOB NOT NULL,
+                    balance_padded BLOB NOT NULL,
+                    PRIMARY KEY (token_address, address, token_id)
+                )",
+                [],
+            )?;
+
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS multi_token_transfers", [])?;
+            conn.execute("DROP TABLE IF EXISTS multi_token_balances", [])?;
+            Ok(())
+        }),
+
+        Migration::new(18, |conn| {
+            // Migration 18: start blocks of the contracts a token moved between,
+            // as found by the deployment search
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS token_segments (
+                    token_address TEXT NOT NULL,
+                    address TEXT NOT NULL,
+                    from_block INTEGER NOT NULL,
+                    PRIMARY KEY (token_address, address)
+                )",
+                [],
+            )?;
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS token_segments", [])?;
+            Ok(())
+        }),
+
+        Migration::new(19, |conn| {
+            // Migration 19: when the insertion worker last committed a batch,
+            // reported by sync-status
+            conn.execute("ALTER TABLE tokens ADD COLUMN last_inserted_at INTEGER", [])?;
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("ALTER TABLE tokens DROP COLUMN last_inserted_at", [])?;
+            Ok(())
+        }),
+
+        Migration::new(20, |conn| {
+            // Migration 20: ranges the insertion worker and finality pass
+            // committed, kept up to INDEXING_LOG_RETENTION rows
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS indexing_log (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    token_address TEXT NOT NULL,
+                    stage TEXT NOT NULL,
+                    from_block INTEGER NOT NULL,
+                    to_block INTEGER NOT NULL,
+                    log_count INTEGER NOT NULL,
+                    inserted_count INTEGER NOT NULL,
+                    provider_url TEXT,
+                    duration_ms INTEGER NOT NULL,
+                    recorded_at INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_indexing_log_token ON indexing_log(token_address, id)",
+                [],
+            )?;
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS indexing_log", [])?;
+            Ok(())
+        }),
+
+        Migration::new(21, |conn| {
+            // Migration 21: block ranges the scan covered, merged, and the gaps
+            // queued to be scanned again by `query coverage --repair`
+            for table in ["scanned_ranges", "coverage_repairs"] {
+                conn.execute(
+                    &format!(
+                        "CREATE TABLE IF NOT EXISTS {table} (
+                            token_address TEXT NOT NULL,
+                            from_block INTEGER NOT NULL,
+                            to_block INTEGER NOT NULL,
+                            PRIMARY KEY (token_address, from_block)
+                        )"
+                    ),
+                    [],
+                )?;
+            }
+            // Nothing tells which blocks were scanned before, so everything
+            // up to the cursor is taken as covered
+            conn.execute(
+                "INSERT OR IGNORE INTO scanned_ranges (token_address, from_block, to_block)
+                 SELECT address, deployment_block + 1, last_processed_block FROM tokens
+                 WHERE last_processed_block > deployment_block",
+                [],
+            )?;
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS scanned_ranges", [])?;
+            conn.execute("DROP TABLE IF EXISTS coverage_repairs", [])?;
+            Ok(())
+        }),
+
+        Migration::new(22, |conn| {
+            // Migration 22: decoded fields of the logs of EVENT_SIGNATURE, as
+            // a JSON object, keyed like nft_transfers by their transfers row
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS events (
+                    transaction_hash TEXT NOT NULL,
+                    log_index INTEGER NOT NULL,
+                    token_address TEXT NOT NULL,
+                    event_name TEXT NOT NULL,
+                    fields TEXT NOT NULL,
+                    PRIMARY KEY (transaction_hash, log_index)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_events_token ON events(token_address, event_name)",
+                [],
+            )?;
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS events", [])?;
+            Ok(())
+        }),
+
+        Migration::new(23, |conn| {
+            // Migration 23: holder balances at every BALANCE_CHECKPOINT_INTERVAL
+            // blocks, so historical balances replay only the transfers since
+            // the nearest one. A checkpoint counts once its row in
+            // balance_checkpoint_blocks is written, in the same transaction.
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS balance_checkpoints (
+                    token_address TEXT NOT NULL,
+                    block_number INTEGER NOT NULL,
+                    address TEXT NOT NULL,
+                    balance_padded BLOB NOT NULL,
+                    PRIMARY KEY (token_address, block_number, address)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS balance_checkpoint_blocks (
+                    token_address TEXT NOT NULL,
+                    block_number INTEGER NOT NULL,
+                    holders INTEGER NOT NULL,
+                    transfers INTEGER NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    PRIMARY KEY (token_address, block_number)
+                )",
+                [],
+            )?;
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS balance_checkpoints", [])?;
+            conn.execute("DROP TABLE IF EXISTS balance_checkpoint_blocks", [])?;
+            Ok(())
+        }),
+
+        Migration::new(24, |conn| {
+            // Migration 24: names given to addresses with `query label`, shown
+            // next to them in the query output. Not scoped to a token.
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS address_labels (
+                    address TEXT PRIMARY KEY,
+                    label TEXT NOT NULL,
+                    updated_at INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP TABLE IF EXISTS address_labels", [])?;
+            Ok(())
+        }),
+
+        Migration::new(25, |conn| {
+            // Migration 25: the block a balance anomaly was hit at, so hitting
+            // it again, as retrying the same write does, adds no second row.
+            // Rows recorded before have no block and are kept as they are.
+            conn.execute(
+                "ALTER TABLE balance_anomalies ADD COLUMN block_number INTEGER",
+                [],
+            )?;
+            conn.execute(
+                "CREATE UNIQUE INDEX IF NOT EXISTS idx_balance_anomalies_block
+                 ON balance_anomalies(token_address, address, block_number)",
+                [],
+            )?;
+            Ok(())
+        })
+        .with_down(|conn| {
+            conn.execute("DROP INDEX IF EXISTS idx_balance_anomalies_block", [])?;
+            conn.execute("ALTER TABLE balance_anomalies DROP COLUMN block_number", [])?;
+            Ok(())
+        }),
+    ]
+}
+
+/// Highest migration applied to the database, None before the first one.
+/// Fails when the database can't be read, e.g. it's corrupted or locked.
+fn applied_version(conn: &Connection) -> Result<Option<i32>> {
+    let has_migrations: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_migrations {
+        return Ok(None);
     }
+    Ok(
+        conn.query_row("SELECT MAX(version) FROM schema_migrations", [], |row| {
+            row.get(0)
+        })?,
+    )
 }
 
-impl Clone for Database {
-    fn clone(&self) -> Self {
-        let conn = Connection::open(&self.db_path)
-            .expect("Failed to open database connection during clone");
-        Database {
-            conn,
-            db_path: self.db_path.clone(),
+/// The file path of a `sqlite:` URL, or `database_url` as it is
+fn sqlite_path(database_url: &str) -> &str {
+    database_url.strip_prefix("sqlite:").unwrap_or(database_url)
+}
+
+/// Rewrite a column of decimal amount strings as 32-byte blobs, one batch per
+/// transaction. Rows already holding a blob are skipped, so an interrupted
+/// conversion picks up where it stopped.
+fn convert_amounts_to_blobs(conn: &Connection, table: &str, column: &str) -> Result<()> {
+    const BATCH_SIZE: usize = 50_000;
+
+    let total: usize = conn.query_row(
+        &format!("SELECT COUNT(*) FROM {table} WHERE typeof({column}) = 'text'"),
+        [],
+        |row| row.get(0),
+    )?;
+    if total == 0 {
+        return Ok(());
+    }
+
+    info!("Converting {total} {table}.{column} values to blobs...");
+
+    let select = format!(
+        "SELECT rowid, {column} FROM {table}
+         WHERE rowid > ?1 AND typeof({column}) = 'text'
+         ORDER BY rowid LIMIT ?2"
+    );
+    let update = format!("UPDATE {table} SET {column} = ?1 WHERE rowid = ?2");
+
+    let mut last_rowid = i64::MIN;
+    let mut converted = 0;
+
+    loop {
+        let rows = conn
+            .prepare_cached(&select)?
+            .query_map(params![last_rowid, BATCH_SIZE], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let Some(&(last, _)) = rows.last() else {
+            break;
+        };
+
+        {
+            let mut stmt = conn.prepare_cached(&update)?;
+            for (rowid, amount) in &rows {
+                // Balances were zero-padded to 78 digits
+                let digits = amount.trim_start_matches('0');
+                let value = if digits.is_empty() {
+                    U256::ZERO
+                } else {
+                    U256::from_str(digits)
+                        .with_context(|| format!("Invalid amount {amount:?} in {table}.{column}"))
+                        .map_err(IndexerError::Other)?
+                };
+                stmt.execute(params![u256_to_blob(&value), rowid])?;
+            }
         }
+
+        last_rowid = last;
+        converted += rows.len();
+        info!(
+            "Converted {}/{} {} values ({:.1}% complete)",
+            converted,
+            total,
+            table,
+            (converted as f64 / total as f64) * 100.0
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{remove_database, temp_database_path};
+
+    fn path(name: &str) -> String {
+        let path = temp_database_path(&format!("migrations-{name}"));
+        remove_database(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Every table, index and trigger with its definition
+    fn schema(conn: &Connection) -> Vec<(String, Option<String>)> {
+        conn.prepare("SELECT name, sql FROM sqlite_master ORDER BY name")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn versions(conn: &Connection) -> Vec<i32> {
+        conn.prepare("SELECT version FROM schema_migrations ORDER BY version")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn a_migration_failing_halfway_leaves_the_database_unchanged() {
+        let path = path("failing");
+        let db = Database::new(&path).unwrap();
+        let (schema_before, versions_before) = (schema(&db.conn), versions(&db.conn));
+
+        let failing = Migration::new(SCHEMA_VERSION + 1, |conn| {
+            conn.execute("CREATE TABLE half_done (id INTEGER)", [])?;
+            conn.execute("ALTER TABLE transfers ADD COLUMN half_done INTEGER", [])?;
+            Err(IndexerError::Other(anyhow::anyhow!("injected failure")))
+        });
+        let error = db.apply_migration(&failing).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Migration {} failed, the database is unchanged",
+                SCHEMA_VERSION + 1
+            )
+        );
+        assert_eq!(schema(&db.conn), schema_before);
+        assert_eq!(versions(&db.conn), versions_before);
+        drop(db);
+        remove_database(Path::new(&path));
+    }
+
+    #[test]
+    fn migrate_down_to_undoes_newest_first_and_stops_at_irreversible_ones() {
+        let path = path("down");
+        drop(Database::new(&path).unwrap());
+        let options = SqliteOptions::default();
+
+        let undone = Database::migrate_down_to(&path, &options, 20).unwrap();
+        assert_eq!(undone, vec![25, 24, 23, 22, 21]);
+        let conn = Database::open_connection(&path, &options).unwrap();
+        assert_eq!(versions(&conn), (1..=20).collect::<Vec<_>>());
+
+        // Migration 7 can't be undone, so nothing is
+        let schema_before = schema(&conn);
+        let error = Database::migrate_down_to(&path, &options, 0).unwrap_err();
+        assert!(
+            error.to_string().starts_with("Migration 7 can't be undone"),
+            "{error}"
+        );
+        assert_eq!(schema(&conn), schema_before);
+        assert_eq!(versions(&conn), (1..=20).collect::<Vec<_>>());
+        drop(conn);
+
+        // The next start applies them again
+        let db = Database::new(&path).unwrap();
+        assert_eq!(applied_version(&db.conn).unwrap(), Some(SCHEMA_VERSION));
+        drop(db);
+        remove_database(Path::new(&path));
+    }
+
+    #[test]
+    fn a_database_from_a_newer_binary_is_refused_by_writers_and_readers() {
+        let path = path("newer");
+        let db = Database::new(&path).unwrap();
+        db.conn
+            .execute(
+                "INSERT INTO schema_migrations (version) VALUES (?1)",
+                [SCHEMA_VERSION + 1],
+            )
+            .unwrap();
+        drop(db);
+
+        let upgrade = format!(
+            "{path} is at schema version {}, newer than the {SCHEMA_VERSION} this binary understands; upgrade it",
+            SCHEMA_VERSION + 1
+        );
+        let error = Database::new(&path).err().unwrap();
+        assert!(matches!(
+            error,
+            IndexerError::Storage(StorageError::Unusable(_))
+        ));
+        assert_eq!(error.to_string(), upgrade);
+        let error = Database::open_read_only(&path, SqliteOptions::default())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error,
+            IndexerError::Storage(StorageError::Unusable(_))
+        ));
+        assert_eq!(error.to_string(), upgrade);
+
+        // And the writer left it as it was
+        let conn = Database::open_connection(&path, &SqliteOptions::default()).unwrap();
+        assert_eq!(applied_version(&conn).unwrap(), Some(SCHEMA_VERSION + 1));
+        drop(conn);
+        remove_database(Path::new(&path));
+    }
+
+    #[test]
+    fn a_corrupted_database_is_a_sqlite_error_not_a_missing_schema() {
+        let path = path("corrupted");
+        drop(Database::new(&path).unwrap());
+        // Keep the file header but garble the page holding the schema
+        let mut file = std::fs::read(&path).unwrap();
+        file[100..4096].fill(0xab);
+        std::fs::write(&path, file).unwrap();
+
+        let error = Database::open_read_only(&path, SqliteOptions::default())
+            .err()
+            .unwrap();
+        assert!(
+            matches!(error, IndexerError::Storage(StorageError::Sqlite(_))),
+            "{error}"
+        );
+        let error = Database::new(&path).err().unwrap();
+        assert!(
+            matches!(error, IndexerError::Storage(StorageError::Sqlite(_))),
+            "{error}"
+        );
+        remove_database(Path::new(&path));
+    }
+
+    #[test]
+    fn a_locked_database_is_an_error_not_a_missing_schema() {
+        let path = path("locked");
+        // Without WAL an exclusive lock keeps readers out too
+        let writer = Connection::open(&path).unwrap();
+        writer
+            .execute_batch(
+                "CREATE TABLE schema_migrations (version INTEGER PRIMARY KEY);
+                 INSERT INTO schema_migrations (version) VALUES (1);
+                 BEGIN EXCLUSIVE;",
+            )
+            .unwrap();
+        let reader = Connection::open(&path).unwrap();
+        reader.busy_timeout(Duration::ZERO).unwrap();
+
+        let error = applied_version(&reader).unwrap_err();
+        assert!(
+            matches!(error, IndexerError::Storage(StorageError::Sqlite(_))),
+            "{error}"
+        );
+        writer.execute_batch("ROLLBACK").unwrap();
+        assert_eq!(applied_version(&reader).unwrap(), Some(1));
+        drop((reader, writer));
+        remove_database(Path::new(&path));
+    }
+
+    #[test]
+    fn migration_1_goes_down_to_the_tables_before_finality() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE tokens (
+                address TEXT PRIMARY KEY,
+                deployment_block INTEGER NOT NULL,
+                last_processed_block INTEGER,
+                name TEXT,
+                symbol TEXT,
+                decimals INTEGER
+            );
+            CREATE TABLE transfers (
+                transaction_hash TEXT NOT NULL,
+                log_index INTEGER NOT NULL,
+                token_address TEXT NOT NULL,
+                from_address TEXT NOT NULL,
+                to_address TEXT NOT NULL,
+                value BLOB NOT NULL,
+                block_number INTEGER NOT NULL,
+                PRIMARY KEY (transaction_hash, log_index)
+            );
+            INSERT INTO tokens VALUES ('0xaa', 1, 50, 'Token', 'TKN', 6);
+            INSERT INTO transfers VALUES ('0x01', 0, '0xaa', '0xbb', '0xcc', x'2a', 10);",
+        )
+        .unwrap();
+        let columns = |table: &str| -> Vec<String> {
+            conn.prepare(&format!("PRAGMA table_info({table})"))
+                .unwrap()
+                .query_map([], |row| row.get(1))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        let (tokens_before, transfers_before) = (columns("tokens"), columns("transfers"));
+
+        let migration = migrations().remove(0);
+        (migration.up)(&conn).unwrap();
+        assert!(columns("transfers").contains(&"is_finalized".to_string()));
+        (migration.down.unwrap())(&conn).unwrap();
+
+        assert_eq!(columns("tokens"), tokens_before);
+        assert_eq!(columns("transfers"), transfers_before);
+        let kept: (i64, Vec<u8>, i64) = conn
+            .query_row(
+                "SELECT t.block_number, t.value, k.last_processed_block
+                 FROM transfers t JOIN tokens k ON k.address = t.token_address",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(kept, (10, vec![0x2a], 50));
     }
 }
@@ -0,0 +1,50 @@
+use super::address::addr_to_db_string;
+use crate::error::Result;
+use crate::events::LogPosition;
+use alloy::rpc::types::Log;
+use alloy_primitives::{Address, hex};
+use rusqlite::{Connection, params};
+
+/// Transfer logs the indexer couldn't decode and skipped, kept raw for
+/// inspection
+pub struct DecodeFailureRepository<'a> {
+    conn: &'a Connection,
+    token_address: String,
+}
+
+impl<'a> DecodeFailureRepository<'a> {
+    const INSERT_FAILURE: &'static str = "INSERT OR IGNORE INTO decode_failures (
+            token_address, transaction_hash, log_index, block_number, topics, data,
+            error, recorded_at
+         ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, unixepoch())";
+
+    pub fn new(conn: &'a Connection, token_address: &Address) -> Self {
+        Self {
+            conn,
+            token_address: addr_to_db_string(token_address),
+        }
+    }
+
+    /// Topics are stored comma separated and the data as 0x-prefixed hex. A
+    /// log seen again, by a rescan or the finality pass, keeps its first record.
+    pub fn record(&self, log: &Log, position: &LogPosition, error: &str) -> Result<()> {
+        let topics = log
+            .topics()
+            .iter()
+            .map(|topic| format!("{topic:?}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.conn
+            .prepare_cached(Self::INSERT_FAILURE)?
+            .execute(params![
+                self.token_address,
+                format!("{:?}", position.transaction_hash),
+                position.log_index,
+                position.block_number,
+                topics,
+                hex::encode_prefixed(&log.data().data),
+                error,
+            ])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use super::address::addr_to_db_string;
+use crate::error::Result;
+use alloy_primitives::Address;
+use rusqlite::{OptionalExtension, params};
+
+/// Progress of an in-flight deployment block search, so a restart resumes the
+/// binary search instead of starting over from the whole chain
+pub struct DeploymentSearchRepository<'a> {
+    conn: &'a rusqlite::Connection,
+}
+
+impl<'a> DeploymentSearchRepository<'a> {
+    const GET_RANGE: &'static str =
+        "SELECT low_block, high_block FROM deployment_search WHERE token_address = ?1";
+
+    const UPSERT_RANGE: &'static str =
+        "INSERT INTO deployment_search (token_address, low_block, high_block) VALUES (?1, ?2, ?3)
+         ON CONFLICT(token_address) DO UPDATE SET low_block = excluded.low_block, high_block = excluded.high_block";
+
+    const DELETE_RANGE: &'static str = "DELETE FROM deployment_search WHERE token_address = ?1";
+
+    const GET_CODE_PRESENCE: &'static str =
+        "SELECT has_code FROM code_presence WHERE address = ?1 AND block_number = ?2";
+
+    const INSERT_CODE_PRESENCE: &'static str = "INSERT OR REPLACE INTO code_presence (address, block_number, has_code) VALUES (?1, ?2, ?3)";
+
+    const DELETE_CODE_PRESENCE: &'static str = "DELETE FROM code_presence WHERE address = ?1";
+
+    pub fn new(conn: &'a rusqlite::Connection) -> Self {
+        Self { conn }
+    }
+
+    /// The remaining `[low, high]` range the deployment block is known to be in
+    pub fn get_range(&self, address: &Address) -> Result<Option<(u64, u64)>> {
+        let range = self
+            .conn
+            .query_row(
+                Self::GET_RANGE,
+                params![addr_to_db_string(address)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(range)
+    }
+
+    pub fn save_range(&self, address: &Address, low: u64, high: u64) -> Result<()> {
+        self.conn
+            .prepare_cached(Self::UPSERT_RANGE)?
+            .execute(params![addr_to_db_string(address), low, high])?;
+        Ok(())
+    }
+
+    /// Whether the address had code at the block, if a search already asked
+    pub fn get_code_presence(&self, address: &Address, block: u64) -> Result<Option<bool>> {
+        let has_code = self
+            .conn
+            .prepare_cached(Self::GET_CODE_PRESENCE)?
+            .query_row(params![addr_to_db_string(address), block], |row| row.get(0))
+            .optional()?;
+        Ok(has_code)
+    }
+
+    pub fn save_code_presence(&self, address: &Address, block: u64, has_code: bool) -> Result<()> {
+        self.conn
+            .prepare_cached(Self::INSERT_CODE_PRESENCE)?
+            .execute(params![addr_to_db_string(address), block, has_code])?;
+        Ok(())
+    }
+
+    /// Drop the search state and code lookups of a finished search
+    pub fn clear(&self, address: &Address) -> Result<()> {
+        let address = addr_to_db_string(address);
+        self.conn.execute(Self::DELETE_RANGE, params![address])?;
+        self.conn
+            .execute(Self::DELETE_CODE_PRESENCE, params![address])?;
+        Ok(())
+    }
+}
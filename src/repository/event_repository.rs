@@ -0,0 +1,147 @@
+use super::address::addr_to_db_string;
+use super::models::{EventFields, Transfer};
+use crate::error::Result;
+use alloy_primitives::{Address, B256};
+use rusqlite::types::ToSql;
+use rusqlite::{Connection, Row, params, params_from_iter};
+
+/// A stored log of the custom event, with where it is on chain
+#[derive(Debug, Clone)]
+pub struct StoredEvent {
+    pub transaction_hash: B256,
+    pub log_index: u64,
+    pub block_number: u64,
+    pub is_finalized: bool,
+    pub event: EventFields,
+}
+
+/// Which events `get_events` returns. Set filters are combined with AND.
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    /// Field and value pairs the decoded fields must have. A field is a
+    /// top-level name or a JSON path such as `$.path[0]`.
+    pub fields: Vec<(String, String)>,
+    pub finalized_only: bool,
+}
+
+/// Decoded fields of the logs indexed with `EVENT_SIGNATURE`. The logs
+/// themselves are stored in `transfers`, between the zero address and for no
+/// value, so reorgs and finality handle them like transfers.
+pub struct EventRepository<'a> {
+    conn: &'a Connection,
+    token_address: String,
+}
+
+impl<'a> EventRepository<'a> {
+    const INSERT_EVENT: &'static str = "INSERT OR IGNORE INTO events (
+            transaction_hash, log_index, token_address, event_name, fields
+         ) VALUES (?1, ?2, ?3, ?4, ?5)";
+
+    const DELETE_EVENTS_FOR_BLOCK: &'static str = "DELETE FROM events
+         WHERE (transaction_hash, log_index) IN (
+            SELECT transaction_hash, log_index FROM transfers
+            WHERE token_address = ?1 AND block_number = ?2
+         )";
+
+    const SELECT_EVENTS: &'static str =
+        "SELECT e.transaction_hash, e.log_index, t.block_number, t.is_finalized,
+            e.event_name, e.fields
+         FROM events e
+         JOIN transfers t USING (transaction_hash, log_index)
+         WHERE e.token_address = ?";
+
+    pub fn new(conn: &'a Connection, token_address: &Address) -> Self {
+        Self {
+            conn,
+            token_address: addr_to_db_string(token_address),
+        }
+    }
+
+    /// Store the fields of newly inserted custom events, inside the caller's
+    /// transaction. Transfers have none and are passed over.
+    pub fn insert_events(&self, transfers: &[&Transfer]) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(Self::INSERT_EVENT)?;
+        for transfer in transfers {
+            if let Some(event) = &transfer.event {
+                stmt.execute(params![
+                    format!("{:?}", transfer.transaction_hash),
+                    transfer.log_index,
+                    self.token_address,
+                    event.name,
+                    event.fields
+                ])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the events of a block's transfers before they are deleted
+    pub fn delete_block(&self, block_number: u64) -> Result<()> {
+        self.conn
+            .prepare_cached(Self::DELETE_EVENTS_FOR_BLOCK)?
+            .execute(params![self.token_address, block_number])?;
+        Ok(())
+    }
+
+    /// Events matching `filter`, most recent first. Fields are compared as
+    /// stored: numbers as decimal strings, addresses in lowercase hex and
+    /// booleans as `true` or `false`.
+    pub fn get_events(&self, filter: &EventFilter, limit: usize) -> Result<Vec<StoredEvent>> {
+        let mut conditions = Vec::new();
+        let mut params: Vec<Box<dyn ToSql>> = vec![Box::new(self.token_address.clone())];
+
+        for (field, value) in &filter.fields {
+            conditions.push("json_extract(e.fields, ?) = ?");
+            params.push(Box::new(json_path(field)));
+            params.push(match value.as_str() {
+                // json_extract gives JSON booleans as 1 and 0
+                "true" => Box::new(1),
+                "false" => Box::new(0),
+                hex if hex.starts_with("0x") => Box::new(hex.to_lowercase()),
+                other => Box::new(other.to_string()),
+            });
+        }
+
+        if filter.finalized_only {
+            conditions.push("t.is_finalized = 1");
+        }
+
+        let query = format!(
+            "{}{}{} ORDER BY t.block_number DESC, e.log_index DESC LIMIT {limit}",
+            Self::SELECT_EVENTS,
+            if conditions.is_empty() { "" } else { " AND " },
+            conditions.join(" AND ")
+        );
+        let events = self
+            .conn
+            .prepare(&query)?
+            .query_map(params_from_iter(params), Self::row_to_event)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(events)
+    }
+
+    fn row_to_event(row: &Row) -> rusqlite::Result<StoredEvent> {
+        let transaction_hash = row.get::<_, String>(0)?.parse::<B256>().map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+        Ok(StoredEvent {
+            transaction_hash,
+            log_index: row.get(1)?,
+            block_number: row.get(2)?,
+            is_finalized: row.get(3)?,
+            event: EventFields {
+                name: row.get(4)?,
+                fields: row.get(5)?,
+            },
+        })
+    }
+}
+
+/// A field name as the JSON path of the top-level key, a path as is
+fn json_path(field: &str) -> String {
+    if field.starts_with('$') {
+        field.to_string()
+    } else {
+        format!("$.\"{field}\"")
+    }
+}
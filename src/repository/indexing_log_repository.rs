@@ -0,0 +1,141 @@
+use super::address::addr_to_db_string;
+use crate::error::Result;
+use alloy_primitives::Address;
+use rusqlite::{Connection, Row, params};
+
+/// Which part of the indexer worked on a range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexingStage {
+    /// Fetched by the scan and committed by the insertion worker
+    Insert,
+    /// Checked against the canonical chain and marked finalized
+    Finality,
+}
+
+impl IndexingStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IndexingStage::Insert => "insert",
+            IndexingStage::Finality => "finality",
+        }
+    }
+
+    fn from_db(value: &str) -> Option<Self> {
+        match value {
+            "insert" => Some(IndexingStage::Insert),
+            "finality" => Some(IndexingStage::Finality),
+            _ => None,
+        }
+    }
+}
+
+/// A block range the indexer committed work for
+#[derive(Debug, Clone)]
+pub struct IndexingLogEntry {
+    pub stage: IndexingStage,
+    pub from_block: u64,
+    pub to_block: u64,
+    /// Logs fetched for the range, only the re-fetched ones for finality
+    pub log_count: u64,
+    /// Transfer rows the commit added
+    pub inserted_count: u64,
+    /// Providers the logs came from, None when nothing was fetched
+    pub provider_url: Option<String>,
+    /// Time spent fetching and committing the range
+    pub duration_ms: u64,
+}
+
+/// An entry as stored, with when it was recorded
+#[derive(Debug, Clone)]
+pub struct IndexingLogRecord {
+    /// Unix timestamp
+    pub recorded_at: u64,
+    pub entry: IndexingLogEntry,
+}
+
+pub struct IndexingLogRepository<'a> {
+    conn: &'a Connection,
+    token_address: String,
+}
+
+impl<'a> IndexingLogRepository<'a> {
+    const INSERT_ENTRY: &'static str = "INSERT INTO indexing_log (
+            token_address, stage, from_block, to_block, log_count, inserted_count,
+            provider_url, duration_ms, recorded_at
+         ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, unixepoch())";
+
+    // Everything from the newest row past the ones kept down
+    const PRUNE: &'static str = "DELETE FROM indexing_log
+         WHERE token_address = ?1 AND id <= (
+             SELECT id FROM indexing_log WHERE token_address = ?1
+             ORDER BY id DESC LIMIT 1 OFFSET ?2
+         )";
+
+    const LIST_ENTRIES: &'static str = "SELECT recorded_at, stage, from_block, to_block,
+            log_count, inserted_count, provider_url, duration_ms
+         FROM indexing_log WHERE token_address = ?1
+         ORDER BY id DESC
+         LIMIT ?2";
+
+    pub fn new(conn: &'a Connection, token_address: &Address) -> Self {
+        Self {
+            conn,
+            token_address: addr_to_db_string(token_address),
+        }
+    }
+
+    /// Record an entry and prune the table down to the `retention` most
+    /// recent rows
+    pub fn record(&self, entry: &IndexingLogEntry, retention: u64) -> Result<()> {
+        self.conn
+            .prepare_cached(Self::INSERT_ENTRY)?
+            .execute(params![
+                self.token_address,
+                entry.stage.as_str(),
+                entry.from_block,
+                entry.to_block,
+                entry.log_count,
+                entry.inserted_count,
+                entry.provider_url,
+                entry.duration_ms,
+            ])?;
+        self.conn
+            .prepare_cached(Self::PRUNE)?
+            .execute(params![self.token_address, retention])?;
+        Ok(())
+    }
+
+    /// Most recently recorded first
+    pub fn list(&self, limit: usize) -> Result<Vec<IndexingLogRecord>> {
+        let records = self
+            .conn
+            .prepare(Self::LIST_ENTRIES)?
+            .query_map(params![self.token_address, limit], Self::row_to_record)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(records)
+    }
+
+    fn row_to_record(row: &Row) -> rusqlite::Result<IndexingLogRecord> {
+        let stage: String = row.get(1)?;
+        let stage = IndexingStage::from_db(&stage).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(
+                1,
+                rusqlite::types::Type::Text,
+                format!("Unknown indexing stage {stage}").into(),
+            )
+        })?;
+
+        Ok(IndexingLogRecord {
+            recorded_at: row.get(0)?,
+            entry: IndexingLogEntry {
+                stage,
+                from_block: row.get(2)?,
+                to_block: row.get(3)?,
+                log_count: row.get(4)?,
+                inserted_count: row.get(5)?,
+                provider_url: row.get(6)?,
+                duration_ms: row.get(7)?,
+            },
+        })
+    }
+}
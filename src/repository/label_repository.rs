@@ -0,0 +1,121 @@
+use super::address::{addr_column, addr_to_db_string};
+use crate::error::Result;
+use alloy_primitives::Address;
+use rusqlite::{Connection, OptionalExtension, Transaction, TransactionBehavior, params};
+use std::collections::{HashMap, HashSet};
+
+/// Addresses looked up per statement, well under SQLite's limit on bound
+/// parameters
+const LOOKUP_CHUNK: usize = 500;
+
+/// What `LabelRepository::import` changed
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LabelImport {
+    pub added: usize,
+    pub replaced: usize,
+    /// Addresses that already had the same label
+    pub unchanged: usize,
+}
+
+/// Names given to addresses, such as "Binance 14" or "Team multisig". An
+/// address is the same account whatever the token, so labels aren't scoped
+/// to one.
+pub struct LabelRepository<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> LabelRepository<'a> {
+    const UPSERT_LABEL: &'static str =
+        "INSERT INTO address_labels (address, label, updated_at) VALUES (?1, ?2, unixepoch())
+         ON CONFLICT (address) DO UPDATE SET label = excluded.label, updated_at = excluded.updated_at";
+
+    const SELECT_LABEL: &'static str = "SELECT label FROM address_labels WHERE address = ?1";
+
+    const DELETE_LABEL: &'static str = "DELETE FROM address_labels WHERE address = ?1";
+
+    const COUNT_LABELS: &'static str = "SELECT COUNT(*) FROM address_labels";
+
+    pub fn new(conn: &'a Connection) -> Self {
+        Self { conn }
+    }
+
+    /// Label an address, returning the label it had before
+    pub fn set(&self, address: &Address, label: &str) -> Result<Option<String>> {
+        let previous = self.get(address)?;
+        self.conn
+            .prepare_cached(Self::UPSERT_LABEL)?
+            .execute(params![addr_to_db_string(address), label])?;
+        Ok(previous)
+    }
+
+    /// Drop an address's label, returning it, or None when it had none
+    pub fn remove(&self, address: &Address) -> Result<Option<String>> {
+        let previous = self.get(address)?;
+        self.conn
+            .execute(Self::DELETE_LABEL, params![addr_to_db_string(address)])?;
+        Ok(previous)
+    }
+
+    pub fn get(&self, address: &Address) -> Result<Option<String>> {
+        let label = self
+            .conn
+            .prepare_cached(Self::SELECT_LABEL)?
+            .query_row(params![addr_to_db_string(address)], |row| row.get(0))
+            .optional()?;
+        Ok(label)
+    }
+
+    pub fn count(&self) -> Result<u64> {
+        let count = self
+            .conn
+            .query_row(Self::COUNT_LABELS, [], |row| row.get(0))?;
+        Ok(count)
+    }
+
+    /// Labels of the given addresses that have one, in a statement per
+    /// `LOOKUP_CHUNK` distinct addresses rather than one per address
+    pub fn lookup<'x>(
+        &self,
+        addresses: impl IntoIterator<Item = &'x Address>,
+    ) -> Result<HashMap<Address, String>> {
+        let addresses: Vec<String> = addresses
+            .into_iter()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .map(addr_to_db_string)
+            .collect();
+
+        let mut labels = HashMap::new();
+        for chunk in addresses.chunks(LOOKUP_CHUNK) {
+            let query = format!(
+                "SELECT address, label FROM address_labels WHERE address IN ({})",
+                vec!["?"; chunk.len()].join(", ")
+            );
+            let mut stmt = self.conn.prepare_cached(&query)?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(chunk), |row| {
+                Ok((addr_column(row, 0)?, row.get::<_, String>(1)?))
+            })?;
+            for row in rows {
+                let (address, label) = row?;
+                labels.insert(address, label);
+            }
+        }
+        Ok(labels)
+    }
+
+    /// Label every address of `labels` in one transaction, so an import
+    /// that fails partway changes nothing
+    pub fn import(&self, labels: &[(Address, String)]) -> Result<LabelImport> {
+        let tx = Transaction::new_unchecked(self.conn, TransactionBehavior::Immediate)?;
+        let mut summary = LabelImport::default();
+        for (address, label) in labels {
+            match self.set(address, label)? {
+                None => summary.added += 1,
+                Some(previous) if previous == *label => summary.unchanged += 1,
+                Some(_) => summary.replaced += 1,
+            }
+        }
+        tx.commit()?;
+        Ok(summary)
+    }
+}
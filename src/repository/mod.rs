@@ -1,11 +1,46 @@
+pub mod address;
 pub mod balance_repository;
+pub mod checkpoint_repository;
+pub mod codec;
 pub mod database;
+pub mod decode_failure_repository;
+pub mod deployment_search_repository;
+pub mod event_repository;
+pub mod indexing_log_repository;
+pub mod label_repository;
 pub mod models;
+pub mod multi_token_repository;
+pub mod nft_repository;
+pub mod notification_repository;
+pub mod pool;
+pub mod reorg_repository;
+pub mod scanned_range_repository;
 pub mod token_repository;
 pub mod transfer_repository;
 
-pub use balance_repository::{BalanceInfo, BalanceRepository, TokenHolder};
-pub use database::Database;
-pub use models::{Token, Transfer};
+pub use address::{addr_from_db_string, addr_to_db_string};
+pub use balance_repository::{
+    BalanceInfo, BalanceRepository, BalanceSnapshot, Distribution, SnapshotDirection, TokenHolder,
+};
+pub use checkpoint_repository::{BalanceCheckpoint, CheckpointRepository};
+pub use codec::{blob_to_u256, u256_to_blob};
+pub use database::{Database, MigrationStatus, SqliteOptions};
+pub use decode_failure_repository::DecodeFailureRepository;
+pub use deployment_search_repository::DeploymentSearchRepository;
+pub use event_repository::{EventFilter, EventRepository, StoredEvent};
+pub use indexing_log_repository::{
+    IndexingLogEntry, IndexingLogRecord, IndexingLogRepository, IndexingStage,
+};
+pub use label_repository::{LabelImport, LabelRepository};
+pub use models::{EventFields, Token, TokenAmount, Transfer};
+pub use multi_token_repository::MultiTokenRepository;
+pub use nft_repository::{NftOwner, NftRepository};
+pub use notification_repository::{Notification, NotificationRepository, NotificationStatus};
+pub use pool::{PooledConnection, ReadPool};
+pub use reorg_repository::{Reorg, ReorgRepository, ReorgedBlock};
+pub use scanned_range_repository::{ScannedRangeRepository, gaps_between};
 pub use token_repository::TokenRepository;
-pub use transfer_repository::{TransferRepository, TransferStats, TransferView};
+pub use transfer_repository::{
+    AddressHistoryEntry, BlockSummary, Counterparty, Direction, RollbackSummary, TransferFilter,
+    TransferRepository, TransferStats, TransferView, VolumeBucket,
+};
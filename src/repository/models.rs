@@ -22,4 +22,28 @@ pub struct Transfer {
     pub block_number: u64,
     pub block_hash: B256,
     pub is_finalized: bool,
+    /// ERC-721 token id, None for other standards
+    pub token_id: Option<U256>,
+    /// ERC-1155 ids and amounts moved, with `value` their sum. Only set on
+    /// decoded transfers, the stored ones are read from `multi_token_transfers`.
+    pub token_amounts: Vec<TokenAmount>,
+    /// Fields of a custom event, None for transfers. Only set on decoded
+    /// logs, the stored ones are read from `events`.
+    pub event: Option<EventFields>,
+}
+
+/// An id and amount moved by an ERC-1155 transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenAmount {
+    pub token_id: U256,
+    pub value: U256,
+}
+
+/// A log decoded as the event of `EVENT_SIGNATURE`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventFields {
+    /// Event name, e.g. `Swap`
+    pub name: String,
+    /// JSON object of the decoded fields by name
+    pub fields: String,
 }
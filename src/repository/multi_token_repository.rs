@@ -0,0 +1,339 @@
+use super::address::{addr_column, addr_to_db_string};
+use super::balance_repository::BalanceInfo;
+use super::codec::{u256_column, u256_to_blob};
+use super::models::{TokenAmount, Transfer};
+use crate::error::Result;
+use alloy_primitives::{Address, U256};
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use std::collections::{HashMap, HashSet};
+use tracing::error;
+
+/// One id and amount of a stored ERC-1155 transfer, with its two sides
+#[derive(Debug, Clone, Copy)]
+struct Movement {
+    from: Address,
+    to: Address,
+    token_id: U256,
+    value: U256,
+}
+
+/// ERC-1155 ids and amounts of stored transfers and the balances per address
+/// and id they add up to. The transfers themselves stay in `transfers`, with
+/// the sum of their amounts, so reorgs and finality handle them like ERC-20
+/// transfers. Like `balances`, only finalized transfers count.
+pub struct MultiTokenRepository<'a> {
+    conn: &'a Connection,
+    token_address: String,
+}
+
+impl<'a> MultiTokenRepository<'a> {
+    const INSERT_AMOUNT: &'static str = "INSERT OR IGNORE INTO multi_token_transfers (
+            transaction_hash, log_index, array_index, token_address, token_id, value
+         ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+
+    const SELECT_UNFINALIZED_MOVEMENTS_IN_RANGE: &'static str =
+        "SELECT t.from_address, t.to_address, m.token_id, m.value
+         FROM multi_token_transfers m
+         JOIN transfers t USING (transaction_hash, log_index)
+         WHERE t.token_address = ?1 AND t.block_number >= ?2 AND t.block_number <= ?3
+            AND t.is_finalized = 0";
+
+    const SELECT_FINALIZED_MOVEMENTS_FOR_BLOCK: &'static str =
+        "SELECT t.from_address, t.to_address, m.token_id, m.value
+         FROM multi_token_transfers m
+         JOIN transfers t USING (transaction_hash, log_index)
+         WHERE t.token_address = ?1 AND t.block_number = ?2 AND t.is_finalized = 1";
+
+    const DELETE_AMOUNTS_FOR_BLOCK: &'static str = "DELETE FROM multi_token_transfers
+         WHERE (transaction_hash, log_index) IN (
+            SELECT transaction_hash, log_index FROM transfers
+            WHERE token_address = ?1 AND block_number = ?2
+         )";
+
+    const SELECT_AMOUNTS_OF: &'static str = "SELECT token_id, value FROM multi_token_transfers
+         WHERE transaction_hash = ?1 AND log_index = ?2
+         ORDER BY array_index";
+
+    const HAS_AMOUNTS: &'static str =
+        "SELECT EXISTS(SELECT 1 FROM multi_token_transfers WHERE token_address = ?1)";
+
+    const SELECT_BALANCE: &'static str = "SELECT balance_padded FROM multi_token_balances
+         WHERE token_address = ?1 AND address = ?2 AND token_id = ?3";
+
+    const UPSERT_BALANCE: &'static str = "INSERT OR REPLACE INTO multi_token_balances (
+            token_address, address, token_id, balance_padded
+         ) VALUES (?1, ?2, ?3, ?4)";
+
+    const DELETE_BALANCE: &'static str = "DELETE FROM multi_token_balances
+         WHERE token_address = ?1 AND address = ?2 AND token_id = ?3";
+
+    const SELECT_INCOMING_ACTIVITY: &'static str =
+        "SELECT COUNT(*), MIN(block_number), MAX(block_number) FROM transfers
+         WHERE token_address = ?1 AND to_address = ?2 AND (?3 = 0 OR is_finalized = 1)
+            AND (transaction_hash, log_index) IN (
+                SELECT transaction_hash, log_index FROM multi_token_transfers
+                WHERE token_address = ?1 AND token_id = ?4
+            )";
+
+    const SELECT_OUTGOING_ACTIVITY: &'static str =
+        "SELECT COUNT(*), MIN(block_number), MAX(block_number) FROM transfers
+         WHERE token_address = ?1 AND from_address = ?2 AND (?3 = 0 OR is_finalized = 1)
+            AND (transaction_hash, log_index) IN (
+                SELECT transaction_hash, log_index FROM multi_token_transfers
+                WHERE token_address = ?1 AND token_id = ?4
+            )";
+
+    pub fn new(conn: &'a Connection, token_address: &Address) -> Self {
+        Self {
+            conn,
+            token_address: addr_to_db_string(token_address),
+        }
+    }
+
+    /// Store the amounts of newly inserted transfers, inside the caller's
+    /// transaction. Transfers of other standards have none and are passed over.
+    pub fn insert_amounts(&self, transfers: &[&Transfer]) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(Self::INSERT_AMOUNT)?;
+        for transfer in transfers {
+            let transaction_hash = format!("{:?}", transfer.transaction_hash);
+            for (array_index, amount) in transfer.token_amounts.iter().enumerate() {
+                stmt.execute(params![
+                    transaction_hash,
+                    transfer.log_index,
+                    array_index,
+                    self.token_address,
+                    u256_to_blob(&amount.token_id),
+                    u256_to_blob(&amount.value)
+                ])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stored amounts of a transfer, in event order
+    pub fn get_amounts(&self, transfer: &Transfer) -> Result<Vec<TokenAmount>> {
+        let amounts = self
+            .conn
+            .prepare_cached(Self::SELECT_AMOUNTS_OF)?
+            .query_map(
+                params![
+                    format!("{:?}", transfer.transaction_hash),
+                    transfer.log_index
+                ],
+                |row| {
+                    Ok(TokenAmount {
+                        token_id: u256_column(row, 0)?,
+                        value: u256_column(row, 1)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(amounts)
+    }
+
+    /// Whether any of the token's transfers carry amounts, i.e. it was indexed
+    /// as ERC-1155
+    pub fn has_amounts(&self) -> Result<bool> {
+        let has_amounts = self
+            .conn
+            .prepare_cached(Self::HAS_AMOUNTS)?
+            .query_row(params![self.token_address], |row| row.get(0))?;
+        Ok(has_amounts)
+    }
+
+    /// Add the amounts of the finalized transfers among `transfers`, which
+    /// must carry their `token_amounts`
+    pub fn apply(&self, transfers: &[&Transfer]) -> Result<()> {
+        let movements: Vec<Movement> = transfers
+            .iter()
+            .filter(|t| t.is_finalized)
+            .flat_map(|t| {
+                t.token_amounts.iter().map(|amount| Movement {
+                    from: t.from_address,
+                    to: t.to_address,
+                    token_id: amount.token_id,
+                    value: amount.value,
+                })
+            })
+            .collect();
+        self.write_movements(&movements, false)
+    }
+
+    /// Add the amounts of the stored unfinalized transfers in `from..=to`,
+    /// before the caller marks them finalized
+    pub fn apply_unfinalized_in_range(&self, from: u64, to: u64) -> Result<()> {
+        let movements = self.query_movements(
+            Self::SELECT_UNFINALIZED_MOVEMENTS_IN_RANGE,
+            params![self.token_address, from, to],
+        )?;
+        self.write_movements(&movements, false)
+    }
+
+    /// Take back the amounts of a block's finalized transfers and drop its
+    /// stored amounts, before the caller deletes its transfers
+    pub fn revert_block(&self, block_number: u64) -> Result<()> {
+        let movements = self.query_movements(
+            Self::SELECT_FINALIZED_MOVEMENTS_FOR_BLOCK,
+            params![self.token_address, block_number],
+        )?;
+        self.write_movements(&movements, true)?;
+
+        self.conn
+            .prepare_cached(Self::DELETE_AMOUNTS_FOR_BLOCK)?
+            .execute(params![self.token_address, block_number])?;
+        Ok(())
+    }
+
+    fn query_movements(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<Movement>> {
+        let movements = self
+            .conn
+            .prepare_cached(sql)?
+            .query_map(params, Self::row_to_movement)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(movements)
+    }
+
+    /// Apply each (address, id) pair's net change, reversed when `revert`. A
+    /// net decrease larger than the stored balance is logged and stored as
+    /// zero, mints come from the zero address which never holds a balance.
+    fn write_movements(&self, movements: &[Movement], revert: bool) -> Result<()> {
+        let mut increases: HashMap<(Address, U256), U256> = HashMap::new();
+        let mut decreases: HashMap<(Address, U256), U256> = HashMap::new();
+        for movement in movements {
+            let (receiver, sender) = if revert {
+                (movement.from, movement.to)
+            } else {
+                (movement.to, movement.from)
+            };
+            let received = increases
+                .entry((receiver, movement.token_id))
+                .or_insert(U256::ZERO);
+            *received = received.saturating_add(movement.value);
+            let sent = decreases
+                .entry((sender, movement.token_id))
+                .or_insert(U256::ZERO);
+            *sent = sent.saturating_add(movement.value);
+        }
+
+        let keys: Vec<(Address, U256)> = increases
+            .keys()
+            .chain(decreases.keys())
+            .copied()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        for (address, token_id) in keys {
+            let increase = increases
+                .get(&(address, token_id))
+                .copied()
+                .unwrap_or(U256::ZERO);
+            let decrease = decreases
+                .get(&(address, token_id))
+                .copied()
+                .unwrap_or(U256::ZERO);
+            if increase == decrease || address.is_zero() {
+                continue;
+            }
+
+            let address_str = addr_to_db_string(&address);
+            let token_id_blob = u256_to_blob(&token_id);
+            let current: U256 = self
+                .conn
+                .prepare_cached(Self::SELECT_BALANCE)?
+                .query_row(
+                    params![self.token_address, address_str, token_id_blob],
+                    |row| u256_column(row, 0),
+                )
+                .optional()?
+                .unwrap_or(U256::ZERO);
+
+            let balance = if increase > decrease {
+                current.saturating_add(increase - decrease)
+            } else {
+                let net_decrease = decrease - increase;
+                current.checked_sub(net_decrease).unwrap_or_else(|| {
+                    error!(
+                        "Balance of {} in id {} would go {} below zero (stored balance {}), storing zero",
+                        address,
+                        token_id,
+                        net_decrease - current,
+                        current
+                    );
+                    U256::ZERO
+                })
+            };
+
+            if balance > U256::ZERO {
+                self.conn
+                    .prepare_cached(Self::UPSERT_BALANCE)?
+                    .execute(params![
+                        self.token_address,
+                        address_str,
+                        token_id_blob,
+                        u256_to_blob(&balance)
+                    ])?;
+            } else {
+                self.conn
+                    .prepare_cached(Self::DELETE_BALANCE)?
+                    .execute(params![self.token_address, address_str, token_id_blob])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Balance of one id and the transfers of that id an address took part in
+    pub fn get_balance(
+        &self,
+        address: &Address,
+        token_id: &U256,
+        finalized_only: bool,
+    ) -> Result<BalanceInfo> {
+        let address_str = addr_to_db_string(address);
+        let token_id_blob = u256_to_blob(token_id);
+
+        let balance = self
+            .conn
+            .prepare_cached(Self::SELECT_BALANCE)?
+            .query_row(
+                params![self.token_address, address_str, token_id_blob],
+                |row| u256_column(row, 0),
+            )
+            .optional()?
+            .unwrap_or(U256::ZERO);
+
+        let activity = |sql: &str| -> Result<(u64, Option<u64>, Option<u64>)> {
+            Ok(self.conn.prepare_cached(sql)?.query_row(
+                params![
+                    self.token_address,
+                    address_str,
+                    finalized_only,
+                    token_id_blob
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?)
+        };
+        let (incoming_count, incoming_first, incoming_last) =
+            activity(Self::SELECT_INCOMING_ACTIVITY)?;
+        let (outgoing_count, outgoing_first, outgoing_last) =
+            activity(Self::SELECT_OUTGOING_ACTIVITY)?;
+
+        Ok(BalanceInfo {
+            balance,
+            incoming_count,
+            outgoing_count,
+            first_block: [incoming_first, outgoing_first].into_iter().flatten().min(),
+            last_block: [incoming_last, outgoing_last].into_iter().flatten().max(),
+        })
+    }
+
+    fn row_to_movement(row: &Row) -> rusqlite::Result<Movement> {
+        Ok(Movement {
+            from: addr_column(row, 0)?,
+            to: addr_column(row, 1)?,
+            token_id: u256_column(row, 2)?,
+            value: u256_column(row, 3)?,
+        })
+    }
+}
@@ -0,0 +1,157 @@
+use super::address::{addr_column, addr_to_db_string};
+use super::codec::{u256_column, u256_to_blob};
+use super::models::Transfer;
+use crate::error::Result;
+use alloy_primitives::{Address, U256};
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use std::collections::BTreeSet;
+
+/// An ERC-721 token and the holder its last finalized transfer went to
+#[derive(Debug, Clone)]
+pub struct NftOwner {
+    pub token_id: U256,
+    pub owner: Address,
+    /// Block of the transfer that gave the owner the token
+    pub block_number: u64,
+}
+
+/// ERC-721 token ids of stored transfers and the owners they add up to. The
+/// transfers themselves stay in `transfers`, so reorgs and finality handle
+/// NFTs the same way as ERC-20 amounts.
+pub struct NftRepository<'a> {
+    conn: &'a Connection,
+    token_address: String,
+}
+
+impl<'a> NftRepository<'a> {
+    const INSERT_NFT_TRANSFER: &'static str = "INSERT OR IGNORE INTO nft_transfers (
+            transaction_hash, log_index, token_id
+         ) VALUES (?1, ?2, ?3)";
+
+    const DELETE_NFT_TRANSFERS_FOR_BLOCK: &'static str = "DELETE FROM nft_transfers
+         WHERE (transaction_hash, log_index) IN (
+            SELECT transaction_hash, log_index FROM transfers
+            WHERE token_address = ?1 AND block_number = ?2
+         )";
+
+    const SELECT_LAST_FINALIZED_TRANSFER: &'static str = "SELECT to_address, block_number
+         FROM nft_transfers JOIN transfers USING (transaction_hash, log_index)
+         WHERE token_address = ?1 AND token_id = ?2 AND is_finalized = 1
+         ORDER BY block_number DESC, log_index DESC
+         LIMIT 1";
+
+    const UPSERT_OWNER: &'static str = "INSERT OR REPLACE INTO nft_owners (
+            token_address, token_id, owner, block_number
+         ) VALUES (?1, ?2, ?3, ?4)";
+
+    const DELETE_OWNER: &'static str =
+        "DELETE FROM nft_owners WHERE token_address = ?1 AND token_id = ?2";
+
+    const SELECT_OWNER: &'static str = "SELECT token_id, owner, block_number FROM nft_owners
+         WHERE token_address = ?1 AND token_id = ?2";
+
+    const SELECT_TOKENS_OF: &'static str = "SELECT token_id, owner, block_number FROM nft_owners
+         WHERE token_address = ?1 AND owner = ?2
+         ORDER BY token_id";
+
+    pub fn new(conn: &'a Connection, token_address: &Address) -> Self {
+        Self {
+            conn,
+            token_address: addr_to_db_string(token_address),
+        }
+    }
+
+    /// Store the token ids of newly inserted transfers, inside the caller's
+    /// transaction. ERC-20 transfers have none and are passed over.
+    pub fn insert_token_ids(&self, transfers: &[&Transfer]) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(Self::INSERT_NFT_TRANSFER)?;
+        for transfer in transfers {
+            if let Some(token_id) = &transfer.token_id {
+                stmt.execute(params![
+                    format!("{:?}", transfer.transaction_hash),
+                    transfer.log_index,
+                    u256_to_blob(token_id)
+                ])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the token ids of a block's transfers before they are deleted
+    pub fn delete_block(&self, block_number: u64) -> Result<()> {
+        self.conn
+            .prepare_cached(Self::DELETE_NFT_TRANSFERS_FOR_BLOCK)?
+            .execute(params![self.token_address, block_number])?;
+        Ok(())
+    }
+
+    /// Recompute the owner of every token the transfers moved from its last
+    /// finalized transfer, so the result doesn't depend on the order transfers
+    /// were finalized or reverted in. Burned tokens have no owner.
+    pub fn refresh_owners(&self, transfers: &[&Transfer]) -> Result<()> {
+        let token_ids: BTreeSet<U256> = transfers.iter().filter_map(|t| t.token_id).collect();
+
+        for token_id in token_ids {
+            let token_id = u256_to_blob(&token_id);
+            let last_transfer: Option<(Address, u64)> = self
+                .conn
+                .prepare_cached(Self::SELECT_LAST_FINALIZED_TRANSFER)?
+                .query_row(params![self.token_address, token_id], |row| {
+                    Ok((addr_column(row, 0)?, row.get(1)?))
+                })
+                .optional()?;
+
+            match last_transfer {
+                Some((owner, block_number)) if !owner.is_zero() => {
+                    self.conn
+                        .prepare_cached(Self::UPSERT_OWNER)?
+                        .execute(params![
+                            self.token_address,
+                            token_id,
+                            addr_to_db_string(&owner),
+                            block_number
+                        ])?;
+                }
+                _ => {
+                    self.conn
+                        .prepare_cached(Self::DELETE_OWNER)?
+                        .execute(params![self.token_address, token_id])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_owner(&self, token_id: &U256) -> Result<Option<NftOwner>> {
+        let owner = self
+            .conn
+            .prepare_cached(Self::SELECT_OWNER)?
+            .query_row(
+                params![self.token_address, u256_to_blob(token_id)],
+                Self::row_to_owner,
+            )
+            .optional()?;
+        Ok(owner)
+    }
+
+    /// Tokens held by `owner`, by token id
+    pub fn get_tokens_of(&self, owner: &Address) -> Result<Vec<NftOwner>> {
+        let tokens = self
+            .conn
+            .prepare_cached(Self::SELECT_TOKENS_OF)?
+            .query_map(
+                params![self.token_address, addr_to_db_string(owner)],
+                Self::row_to_owner,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(tokens)
+    }
+
+    fn row_to_owner(row: &Row) -> rusqlite::Result<NftOwner> {
+        Ok(NftOwner {
+            token_id: u256_column(row, 0)?,
+            owner: addr_column(row, 1)?,
+            block_number: row.get(2)?,
+        })
+    }
+}
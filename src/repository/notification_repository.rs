@@ -0,0 +1,212 @@
+use super::address::{addr_column, addr_to_db_string};
+use super::codec::{u256_column, u256_to_blob};
+use super::models::Transfer;
+use crate::error::Result;
+use alloy_primitives::{Address, B256, U256};
+use rusqlite::{Connection, Row, params};
+
+/// Delivery state of a watchlist notification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationStatus {
+    /// Recorded, not delivered yet
+    Pending,
+    Sent,
+    /// Gave up after the configured retries, the payload is in the dead-letter log
+    Failed,
+}
+
+impl NotificationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationStatus::Pending => "pending",
+            NotificationStatus::Sent => "sent",
+            NotificationStatus::Failed => "failed",
+        }
+    }
+
+    fn from_db(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(NotificationStatus::Pending),
+            "sent" => Some(NotificationStatus::Sent),
+            "failed" => Some(NotificationStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A transfer that matched the watchlist. The transfer's fields are copied so
+/// the notification outlives a reorg that replaces the transfer row.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub transaction_hash: B256,
+    pub log_index: u64,
+    pub block_number: u64,
+    pub from_address: Address,
+    pub to_address: Address,
+    pub value: U256,
+    pub is_finalized: bool,
+    pub status: NotificationStatus,
+    pub attempts: u32,
+    /// Unix timestamps
+    pub created_at: u64,
+    pub sent_at: Option<u64>,
+}
+
+impl Notification {
+    fn from_transfer(transfer: &Transfer) -> Self {
+        Self {
+            transaction_hash: transfer.transaction_hash,
+            log_index: transfer.log_index,
+            block_number: transfer.block_number,
+            from_address: transfer.from_address,
+            to_address: transfer.to_address,
+            value: transfer.value,
+            is_finalized: transfer.is_finalized,
+            status: NotificationStatus::Pending,
+            attempts: 0,
+            created_at: 0,
+            sent_at: None,
+        }
+    }
+}
+
+pub struct NotificationRepository<'a> {
+    conn: &'a Connection,
+    token_address: &'a Address,
+}
+
+impl<'a> NotificationRepository<'a> {
+    const INSERT_NOTIFICATION: &'static str = "INSERT OR IGNORE INTO notifications (
+            token_address, transaction_hash, log_index, block_number, from_address, to_address,
+            value, is_finalized, status, attempts, created_at
+         ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 'pending', 0, unixepoch())";
+
+    const SELECT_COLUMNS: &'static str = "SELECT transaction_hash, log_index, block_number,
+            from_address, to_address, value, is_finalized, status, attempts, created_at, sent_at
+         FROM notifications";
+
+    const MARK_SENT: &'static str = "UPDATE notifications
+         SET status = 'sent', attempts = ?4, sent_at = unixepoch()
+         WHERE token_address = ?1 AND transaction_hash = ?2 AND log_index = ?3";
+
+    const MARK_FAILED: &'static str = "UPDATE notifications
+         SET status = 'failed', attempts = ?4
+         WHERE token_address = ?1 AND transaction_hash = ?2 AND log_index = ?3";
+
+    pub fn new(conn: &'a Connection, token_address: &'a Address) -> Self {
+        Self {
+            conn,
+            token_address,
+        }
+    }
+
+    /// Record a pending notification for each transfer, returning only those
+    /// that weren't recorded before. A transfer seen again after a restart
+    /// therefore never fires twice.
+    pub fn record(&self, transfers: &[&Transfer]) -> Result<Vec<Notification>> {
+        let token = addr_to_db_string(self.token_address);
+        let mut stmt = self.conn.prepare_cached(Self::INSERT_NOTIFICATION)?;
+
+        let mut recorded = Vec::new();
+        for transfer in transfers {
+            let inserted = stmt.execute(params![
+                token,
+                format!("{:?}", transfer.transaction_hash),
+                transfer.log_index,
+                transfer.block_number,
+                addr_to_db_string(&transfer.from_address),
+                addr_to_db_string(&transfer.to_address),
+                u256_to_blob(&transfer.value),
+                transfer.is_finalized,
+            ])?;
+            if inserted > 0 {
+                recorded.push(Notification::from_transfer(transfer));
+            }
+        }
+
+        Ok(recorded)
+    }
+
+    /// Notifications still waiting for delivery, oldest first
+    pub fn get_pending(&self) -> Result<Vec<Notification>> {
+        let sql = format!(
+            "{} WHERE token_address = ?1 AND status = 'pending'
+             ORDER BY block_number, log_index",
+            Self::SELECT_COLUMNS
+        );
+        let notifications = self
+            .conn
+            .prepare(&sql)?
+            .query_map(
+                params![addr_to_db_string(self.token_address)],
+                Self::row_to_notification,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(notifications)
+    }
+
+    /// Most recent notifications first
+    pub fn list(&self, limit: usize) -> Result<Vec<Notification>> {
+        let sql = format!(
+            "{} WHERE token_address = ?1
+             ORDER BY block_number DESC, log_index DESC
+             LIMIT ?2",
+            Self::SELECT_COLUMNS
+        );
+        let notifications = self
+            .conn
+            .prepare(&sql)?
+            .query_map(
+                params![addr_to_db_string(self.token_address), limit],
+                Self::row_to_notification,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(notifications)
+    }
+
+    pub fn mark_sent(&self, notification: &Notification, attempts: u32) -> Result<()> {
+        self.update(Self::MARK_SENT, notification, attempts)
+    }
+
+    pub fn mark_failed(&self, notification: &Notification, attempts: u32) -> Result<()> {
+        self.update(Self::MARK_FAILED, notification, attempts)
+    }
+
+    fn update(&self, sql: &str, notification: &Notification, attempts: u32) -> Result<()> {
+        self.conn.prepare_cached(sql)?.execute(params![
+            addr_to_db_string(self.token_address),
+            format!("{:?}", notification.transaction_hash),
+            notification.log_index,
+            attempts,
+        ])?;
+        Ok(())
+    }
+
+    fn row_to_notification(row: &Row) -> rusqlite::Result<Notification> {
+        let transaction_hash = row.get::<_, String>(0)?.parse::<B256>().map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+        let status = row.get::<_, String>(7)?;
+        let status = NotificationStatus::from_db(&status).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(
+                7,
+                rusqlite::types::Type::Text,
+                format!("Unknown notification status {status}").into(),
+            )
+        })?;
+
+        Ok(Notification {
+            transaction_hash,
+            log_index: row.get(1)?,
+            block_number: row.get(2)?,
+            from_address: addr_column(row, 3)?,
+            to_address: addr_column(row, 4)?,
+            value: u256_column(row, 5)?,
+            is_finalized: row.get(6)?,
+            status,
+            attempts: row.get(8)?,
+            created_at: row.get(9)?,
+            sent_at: row.get(10)?,
+        })
+    }
+}
@@ -0,0 +1,175 @@
+use super::database::{Database, SqliteOptions};
+use crate::error::{IndexerError, Result, StorageError};
+use rusqlite::Connection;
+use std::ops::Deref;
+use std::sync::Mutex;
+use tokio::runtime::Handle;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Smallest pool size. A reader streaming rows holds one connection while
+/// the labels of those rows are looked up on another.
+const MIN_POOL_SIZE: usize = 2;
+
+/// Read-only connections shared by readers, so queries run alongside the
+/// writer connection instead of queueing behind its transactions. WAL mode
+/// lets every reader see the last committed state while a write is open.
+/// Connections are opened on first use, up to `size`.
+#[derive(Debug)]
+pub struct ReadPool {
+    db_path: String,
+    options: SqliteOptions,
+    /// One per connection that may be borrowed at once
+    permits: Semaphore,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl ReadPool {
+    pub fn new(db_path: &str, options: SqliteOptions, size: usize) -> Self {
+        Self {
+            db_path: db_path.to_string(),
+            options,
+            permits: Semaphore::new(size.max(MIN_POOL_SIZE)),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Borrow a connection, waiting without blocking the thread for one to be
+    /// returned when all are in use. It goes back to the pool when the guard
+    /// is dropped.
+    pub async fn get(&self) -> Result<PooledConnection<'_>> {
+        let permit = self.permits.acquire().await.map_err(|_| closed())?;
+        self.check_out(permit)
+    }
+
+    /// `get` for code that isn't async, blocking the thread while every
+    /// connection is in use: a `spawn_blocking` closure, or a program without
+    /// a runtime. Panics when called from an async task, whose worker thread
+    /// it would stall; those go through `get`.
+    pub fn blocking_get(&self) -> Result<PooledConnection<'_>> {
+        let permit = match Handle::try_current() {
+            // Panics in an async context
+            Ok(runtime) => runtime.block_on(self.permits.acquire()),
+            Err(_) => futures::executor::block_on(self.permits.acquire()),
+        }
+        .map_err(|_| closed())?;
+        self.check_out(permit)
+    }
+
+    fn check_out<'a>(&'a self, permit: SemaphorePermit<'a>) -> Result<PooledConnection<'a>> {
+        let idle = self.idle.lock().unwrap().pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => Database::open_read_only_connection(&self.db_path, &self.options)?,
+        };
+        Ok(PooledConnection {
+            pool: self,
+            conn: Some(conn),
+            _permit: permit,
+        })
+    }
+
+    fn put_back(&self, conn: Connection) {
+        self.idle.lock().unwrap().push(conn);
+    }
+}
+
+/// The semaphore is never closed, but acquiring it is fallible all the same
+fn closed() -> IndexerError {
+    StorageError::Unusable("The read pool was closed".to_string()).into()
+}
+
+/// A connection borrowed from a `ReadPool`, usable wherever a `&Connection` is
+pub struct PooledConnection<'a> {
+    pool: &'a ReadPool,
+    conn: Option<Connection>,
+    /// Released after `drop` put the connection back
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+            .as_ref()
+            .expect("connection is only taken on drop")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.put_back(conn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{fresh_database, remove_database, temp_database_path};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn waiting_for_a_connection_leaves_the_thread_to_other_tasks() {
+        let path = temp_database_path("pool-wait");
+        let db = fresh_database(&path).unwrap();
+        // Raised to the minimum of two
+        let pool = Arc::new(ReadPool::new(
+            &path.to_string_lossy(),
+            SqliteOptions::default(),
+            1,
+        ));
+
+        let borrowed = [pool.get().await.unwrap(), pool.get().await.unwrap()];
+        let waiting = {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let conn = pool.get().await.unwrap();
+                conn.query_row("SELECT COUNT(*) FROM tokens", [], |row| {
+                    row.get::<_, u64>(0)
+                })
+                .unwrap()
+            })
+        };
+
+        // With a single thread, this only runs if the waiting task yielded it
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        drop(borrowed);
+        assert_eq!(waiting.await.unwrap(), 1);
+
+        drop(pool);
+        drop(db);
+        remove_database(&path);
+    }
+
+    #[tokio::test]
+    async fn blocking_get_is_for_blocking_threads_and_panics_in_a_task() {
+        let path = temp_database_path("pool-blocking");
+        let db = fresh_database(&path).unwrap();
+        let pool = Arc::new(ReadPool::new(
+            &path.to_string_lossy(),
+            SqliteOptions::default(),
+            2,
+        ));
+
+        let blocking = {
+            let pool = pool.clone();
+            tokio::task::spawn_blocking(move || pool.blocking_get().map(|_| ()))
+        };
+        blocking.await.unwrap().unwrap();
+
+        let in_task = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.blocking_get().map(|_| ()) })
+        };
+        assert!(in_task.await.unwrap_err().is_panic());
+
+        drop(pool);
+        drop(db);
+        remove_database(&path);
+    }
+}
@@ -0,0 +1,160 @@
+use super::address::addr_to_db_string;
+use super::models::Transfer;
+use crate::error::Result;
+use alloy_primitives::{Address, B256};
+use rusqlite::{Connection, Row, params};
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+use tracing::warn;
+
+/// A block whose stored transfers were replaced because they didn't match the
+/// canonical chain. A hash is `None` when that side had no transfers in the block.
+#[derive(Debug, Clone)]
+pub struct Reorg {
+    /// Unix timestamp
+    pub detected_at: u64,
+    pub block_number: u64,
+    pub old_block_hash: Option<B256>,
+    pub new_block_hash: Option<B256>,
+    pub transfers_deleted: u64,
+    pub transfers_inserted: u64,
+}
+
+/// A block whose stored transfers are replaced with the canonical ones
+#[derive(Debug, Clone, Copy)]
+pub struct ReorgedBlock {
+    pub block_number: u64,
+    pub old_block_hash: Option<B256>,
+    pub new_block_hash: Option<B256>,
+}
+
+impl ReorgedBlock {
+    /// Blocks whose stored transfers don't match the canonical chain, given the
+    /// block hashes of the blocks with transfers on each side, in block order
+    pub fn diff(stored: &HashMap<u64, B256>, chain: &HashMap<u64, B256>) -> Vec<ReorgedBlock> {
+        let mut blocks = BTreeSet::new();
+
+        for (block_num, chain_hash) in chain {
+            match stored.get(block_num) {
+                Some(stored_hash) if stored_hash != chain_hash => {
+                    warn!(
+                        "Reorg detected at block {}! Hash mismatch: chain {:?} vs stored {:?}",
+                        block_num, chain_hash, stored_hash
+                    );
+                    blocks.insert(*block_num);
+                }
+                None => {
+                    warn!("Block {} has transfers on chain but not in DB", block_num);
+                    blocks.insert(*block_num);
+                }
+                _ => {} // Hashes match, all good
+            }
+        }
+
+        for block_num in stored.keys() {
+            if !chain.contains_key(block_num) {
+                warn!("Block {} has transfers in DB but not on chain", block_num);
+                blocks.insert(*block_num);
+            }
+        }
+
+        blocks
+            .into_iter()
+            .map(|block_number| ReorgedBlock {
+                block_number,
+                old_block_hash: stored.get(&block_number).copied(),
+                new_block_hash: chain.get(&block_number).copied(),
+            })
+            .collect()
+    }
+
+    /// The canonical transfers to re-insert for `blocks`
+    pub fn transfers_in(blocks: &[ReorgedBlock], transfers: &[Transfer]) -> Vec<Transfer> {
+        let block_numbers: BTreeSet<u64> = blocks.iter().map(|b| b.block_number).collect();
+        transfers
+            .iter()
+            .filter(|t| block_numbers.contains(&t.block_number))
+            .cloned()
+            .collect()
+    }
+}
+
+pub struct ReorgRepository<'a> {
+    conn: &'a Connection,
+    token_address: String,
+}
+
+impl<'a> ReorgRepository<'a> {
+    const INSERT_REORG: &'static str = "INSERT INTO reorgs (
+            token_address, detected_at, block_number, old_block_hash, new_block_hash,
+            transfers_deleted, transfers_inserted
+         ) VALUES (?1, unixepoch(), ?2, ?3, ?4, ?5, ?6)";
+
+    const LIST_REORGS: &'static str = "SELECT detected_at, block_number, old_block_hash,
+            new_block_hash, transfers_deleted, transfers_inserted
+         FROM reorgs WHERE token_address = ?1
+         ORDER BY id DESC
+         LIMIT ?2";
+
+    pub fn new(conn: &'a Connection, token_address: &Address) -> Self {
+        Self {
+            conn,
+            token_address: addr_to_db_string(token_address),
+        }
+    }
+
+    /// Callers pass the transaction that replaced the transfers, so the record
+    /// is written if and only if the change is
+    pub fn record(
+        &self,
+        block: &ReorgedBlock,
+        transfers_deleted: usize,
+        transfers_inserted: usize,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached(Self::INSERT_REORG)?
+            .execute(params![
+                self.token_address,
+                block.block_number,
+                block.old_block_hash.map(|hash| format!("{hash:?}")),
+                block.new_block_hash.map(|hash| format!("{hash:?}")),
+                transfers_deleted,
+                transfers_inserted,
+            ])?;
+        Ok(())
+    }
+
+    /// Most recently detected first
+    pub fn list(&self, limit: usize) -> Result<Vec<Reorg>> {
+        let reorgs = self
+            .conn
+            .prepare(Self::LIST_REORGS)?
+            .query_map(params![self.token_address, limit], Self::row_to_reorg)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(reorgs)
+    }
+
+    fn row_to_reorg(row: &Row) -> rusqlite::Result<Reorg> {
+        let hash_column = |index: usize| -> rusqlite::Result<Option<B256>> {
+            row.get::<_, Option<String>>(index)?
+                .map(|hash| B256::from_str(&hash))
+                .transpose()
+                .map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        index,
+                        rusqlite::types::Type::Text,
+                        Box::new(e),
+                    )
+                })
+        };
+
+        Ok(Reorg {
+            detected_at: row.get(0)?,
+            block_number: row.get(1)?,
+            old_block_hash: hash_column(2)?,
+            new_block_hash: hash_column(3)?,
+            transfers_deleted: row.get(4)?,
+            transfers_inserted: row.get(5)?,
+        })
+    }
+}
@@ -0,0 +1,195 @@
+use super::address::addr_to_db_string;
+use crate::error::Result;
+use alloy_primitives::Address;
+use rusqlite::{Connection, Transaction, TransactionBehavior, params};
+
+/// Block ranges whose logs were fetched and committed, kept merged into
+/// contiguous ranges, and the gaps between them queued to be scanned again on
+/// the next start
+pub struct ScannedRangeRepository<'a> {
+    conn: &'a Connection,
+    token_address: String,
+}
+
+impl<'a> ScannedRangeRepository<'a> {
+    // Ranges overlapping or adjacent to ?2..=?3, which a new range merges with
+    const SELECT_TOUCHING: &'static str = "SELECT from_block, to_block FROM scanned_ranges
+         WHERE token_address = ?1 AND from_block <= ?3 + 1 AND to_block + 1 >= ?2";
+
+    const DELETE_TOUCHING: &'static str = "DELETE FROM scanned_ranges
+         WHERE token_address = ?1 AND from_block <= ?3 + 1 AND to_block + 1 >= ?2";
+
+    const INSERT_RANGE: &'static str =
+        "INSERT INTO scanned_ranges (token_address, from_block, to_block) VALUES (?1, ?2, ?3)";
+
+    const SELECT_RANGES: &'static str = "SELECT from_block, to_block FROM scanned_ranges
+         WHERE token_address = ?1 ORDER BY from_block";
+
+    const DELETE_RANGES_ABOVE: &'static str =
+        "DELETE FROM scanned_ranges WHERE token_address = ?1 AND from_block > ?2";
+
+    const CLIP_RANGES_ABOVE: &'static str =
+        "UPDATE scanned_ranges SET to_block = ?2 WHERE token_address = ?1 AND to_block > ?2";
+
+    const SELECT_OVERLAPPING_REPAIRS: &'static str = "SELECT from_block, to_block
+         FROM coverage_repairs
+         WHERE token_address = ?1 AND from_block <= ?3 AND to_block >= ?2";
+
+    const DELETE_OVERLAPPING_REPAIRS: &'static str = "DELETE FROM coverage_repairs
+         WHERE token_address = ?1 AND from_block <= ?3 AND to_block >= ?2";
+
+    const INSERT_REPAIR: &'static str = "INSERT OR REPLACE INTO coverage_repairs
+         (token_address, from_block, to_block) VALUES (?1, ?2, ?3)";
+
+    const SELECT_REPAIRS: &'static str = "SELECT from_block, to_block FROM coverage_repairs
+         WHERE token_address = ?1 ORDER BY from_block";
+
+    const DELETE_REPAIRS_ABOVE: &'static str =
+        "DELETE FROM coverage_repairs WHERE token_address = ?1 AND from_block > ?2";
+
+    const CLIP_REPAIRS_ABOVE: &'static str =
+        "UPDATE coverage_repairs SET to_block = ?2 WHERE token_address = ?1 AND to_block > ?2";
+
+    pub fn new(conn: &'a Connection, token_address: &Address) -> Self {
+        Self {
+            conn,
+            token_address: addr_to_db_string(token_address),
+        }
+    }
+
+    /// Mark each of `ranges` scanned, merging them with the ranges they touch,
+    /// and take them off the repair queue
+    pub fn record(&self, ranges: &[(u64, u64)]) -> Result<()> {
+        if ranges.is_empty() {
+            return Ok(());
+        }
+
+        let tx = Transaction::new_unchecked(self.conn, TransactionBehavior::Immediate)?;
+        for &(from, to) in ranges {
+            self.record_in_tx(&tx, from, to)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn record_in_tx(&self, tx: &Transaction, from: u64, to: u64) -> Result<()> {
+        let (mut merged_from, mut merged_to) = (from, to);
+        for (touching_from, touching_to) in self.query_ranges(
+            tx,
+            Self::SELECT_TOUCHING,
+            params![self.token_address, from, to],
+        )? {
+            merged_from = merged_from.min(touching_from);
+            merged_to = merged_to.max(touching_to);
+        }
+        tx.prepare_cached(Self::DELETE_TOUCHING)?
+            .execute(params![self.token_address, from, to])?;
+        tx.prepare_cached(Self::INSERT_RANGE)?.execute(params![
+            self.token_address,
+            merged_from,
+            merged_to
+        ])?;
+
+        // What's left of the queued repairs the range covers part of
+        let repairs = self.query_ranges(
+            tx,
+            Self::SELECT_OVERLAPPING_REPAIRS,
+            params![self.token_address, from, to],
+        )?;
+        if !repairs.is_empty() {
+            tx.prepare_cached(Self::DELETE_OVERLAPPING_REPAIRS)?
+                .execute(params![self.token_address, from, to])?;
+            for (repair_from, repair_to) in repairs {
+                if repair_from < from {
+                    self.insert_repair(tx, repair_from, from - 1)?;
+                }
+                if repair_to > to {
+                    self.insert_repair(tx, to + 1, repair_to)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Scanned ranges in block order
+    pub fn ranges(&self) -> Result<Vec<(u64, u64)>> {
+        self.query_ranges(self.conn, Self::SELECT_RANGES, params![self.token_address])
+    }
+
+    /// Ranges of `from..=to` no scan has covered, in block order
+    pub fn gaps(&self, from: u64, to: u64) -> Result<Vec<(u64, u64)>> {
+        Ok(gaps_between(&self.ranges()?, from, to))
+    }
+
+    /// Queue ranges to be scanned on the next start
+    pub fn queue_repairs(&self, ranges: &[(u64, u64)]) -> Result<()> {
+        let tx = Transaction::new_unchecked(self.conn, TransactionBehavior::Immediate)?;
+        for &(from, to) in ranges {
+            self.insert_repair(&tx, from, to)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Ranges queued to be scanned, in block order
+    pub fn repairs(&self) -> Result<Vec<(u64, u64)>> {
+        self.query_ranges(self.conn, Self::SELECT_REPAIRS, params![self.token_address])
+    }
+
+    /// Forget what was scanned and queued past `block_number`, for a rollback.
+    /// Runs on the caller's connection, so inside its transaction.
+    pub fn truncate_above(&self, block_number: u64) -> Result<()> {
+        for sql in [
+            Self::DELETE_RANGES_ABOVE,
+            Self::CLIP_RANGES_ABOVE,
+            Self::DELETE_REPAIRS_ABOVE,
+            Self::CLIP_REPAIRS_ABOVE,
+        ] {
+            self.conn
+                .execute(sql, params![self.token_address, block_number])?;
+        }
+        Ok(())
+    }
+
+    fn insert_repair(&self, conn: &Connection, from: u64, to: u64) -> Result<()> {
+        conn.prepare_cached(Self::INSERT_REPAIR)?
+            .execute(params![self.token_address, from, to])?;
+        Ok(())
+    }
+
+    fn query_ranges(
+        &self,
+        conn: &Connection,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<(u64, u64)>> {
+        let mut stmt = conn.prepare_cached(sql)?;
+        let ranges = stmt
+            .query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ranges)
+    }
+}
+
+/// Parts of `from..=to` outside `ranges`, which must be sorted and disjoint
+pub fn gaps_between(ranges: &[(u64, u64)], from: u64, to: u64) -> Vec<(u64, u64)> {
+    let mut gaps = Vec::new();
+    let mut next = from;
+    for &(range_from, range_to) in ranges {
+        if next > to {
+            break;
+        }
+        if range_to < next {
+            continue;
+        }
+        if range_from > next {
+            gaps.push((next, (range_from - 1).min(to)));
+        }
+        next = next.max(range_to.saturating_add(1));
+    }
+    if next <= to {
+        gaps.push((next, to));
+    }
+    gaps
+}
@@ -1,6 +1,7 @@
+use super::address::addr_to_db_string;
 use super::models::Token;
+use crate::error::Result;
 use alloy_primitives::Address;
-use anyhow::Result;
 use rusqlite::{OptionalExtension, params};
 
 pub struct TokenRepository<'a> {
@@ -16,9 +17,26 @@ impl<'a> TokenRepository<'a> {
     const UPDATE_LAST_PROCESSED_BLOCK: &'static str =
         "UPDATE tokens SET last_processed_block = ?1 WHERE address = ?2";
 
+    // Values that couldn't be fetched keep whatever is stored
+    const UPDATE_METADATA: &'static str =
+        "UPDATE tokens SET name = COALESCE(?1, name), symbol = COALESCE(?2, symbol), decimals = COALESCE(?3, decimals)
+         WHERE address = ?4";
+
+    const UPDATE_LAST_INSERTED_AT: &'static str =
+        "UPDATE tokens SET last_inserted_at = unixepoch() WHERE address = ?1";
+
+    const GET_LAST_INSERTED_AT: &'static str =
+        "SELECT last_inserted_at FROM tokens WHERE address = ?1";
+
+    const GET_TOKEN: &'static str =
+        "SELECT deployment_block, last_processed_block, last_processed_finalized_block, name, symbol, decimals
+         FROM tokens WHERE address = ?1";
+
     const GET_DEPLOYMENT_BLOCK: &'static str =
         "SELECT deployment_block FROM tokens WHERE address = ?1";
 
+    const GET_OTHER_DEPLOYMENT_BLOCKS: &'static str = "SELECT DISTINCT deployment_block FROM tokens WHERE address != ?1 ORDER BY deployment_block";
+
     const GET_LAST_PROCESSED_BLOCK: &'static str =
         "SELECT last_processed_block FROM tokens WHERE address = ?1";
 
@@ -30,6 +48,32 @@ impl<'a> TokenRepository<'a> {
     const UPDATE_LAST_PROCESSED_FINALIZED_BLOCK: &'static str =
         "UPDATE tokens SET last_processed_finalized_block = ?1 WHERE address = ?2";
 
+    const GET_CHAIN_ID: &'static str = "SELECT chain_id FROM tokens WHERE address = ?1";
+
+    // Only the first run records the chain, later runs are checked against it
+    const SET_CHAIN_ID: &'static str =
+        "UPDATE tokens SET chain_id = ?1 WHERE address = ?2 AND chain_id IS NULL";
+
+    const GET_LAST_BALANCE_APPLIED_BLOCK: &'static str =
+        "SELECT last_balance_applied_block FROM tokens WHERE address = ?1";
+
+    // Never moves backwards, re-running a finalized range leaves it in place
+    const RAISE_LAST_BALANCE_APPLIED_BLOCK: &'static str = "UPDATE tokens
+         SET last_balance_applied_block = MAX(COALESCE(last_balance_applied_block, 0), ?1)
+         WHERE address = ?2";
+
+    // MIN keeps a NULL cursor NULL
+    const REWIND_CURSORS: &'static str = "UPDATE tokens
+         SET last_processed_block = MIN(last_processed_block, ?1),
+             last_processed_finalized_block = MIN(last_processed_finalized_block, ?1),
+             last_balance_applied_block = MIN(last_balance_applied_block, ?1)
+         WHERE address = ?2";
+
+    const GET_SEGMENT_START: &'static str =
+        "SELECT from_block FROM token_segments WHERE token_address = ?1 AND address = ?2";
+
+    const SAVE_SEGMENT_START: &'static str = "INSERT OR REPLACE INTO token_segments (token_address, address, from_block) VALUES (?1, ?2, ?3)";
+
     pub fn new(conn: &'a rusqlite::Connection) -> Self {
         Self { conn }
     }
@@ -38,7 +82,7 @@ impl<'a> TokenRepository<'a> {
         self.conn.execute(
             Self::INSERT_TOKEN,
             params![
-                format!("{:?}", token.address),
+                addr_to_db_string(&token.address),
                 token.deployment_block,
                 token.last_processed_block.unwrap_or(token.deployment_block),
                 token.name,
@@ -49,59 +93,177 @@ impl<'a> TokenRepository<'a> {
         Ok(())
     }
 
+    pub fn update_metadata(
+        &self,
+        address: &Address,
+        name: Option<&str>,
+        symbol: Option<&str>,
+        decimals: Option<u8>,
+    ) -> Result<()> {
+        self.conn.execute(
+            Self::UPDATE_METADATA,
+            params![name, symbol, decimals, addr_to_db_string(address)],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_token(&self, address: &Address) -> Result<Option<Token>> {
+        let token = self
+            .conn
+            .query_row(
+                Self::GET_TOKEN,
+                params![addr_to_db_string(address)],
+                |row| {
+                    Ok(Token {
+                        address: *address,
+                        deployment_block: row.get(0)?,
+                        last_processed_block: row.get(1)?,
+                        last_processed_finalized_block: row.get(2)?,
+                        name: row.get(3)?,
+                        symbol: row.get(4)?,
+                        decimals: row.get(5)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(token)
+    }
+
     pub fn get_deployment_block(&self, address: &Address) -> Result<Option<u64>> {
         let block: Option<u64> = self
             .conn
             .query_row(
                 Self::GET_DEPLOYMENT_BLOCK,
-                params![format!("{:?}", address)],
+                params![addr_to_db_string(address)],
                 |row| row.get(0),
             )
             .optional()?;
         Ok(block)
     }
 
-    pub fn get_last_processed_block(&self, address: &Address) -> Result<Option<u64>> {
-        let block: Option<u64> = self
+    /// Block a contract of a token that moved between contracts was found to
+    /// start at
+    pub fn get_segment_start(&self, token: &Address, contract: &Address) -> Result<Option<u64>> {
+        let block = self
             .conn
             .query_row(
-                Self::GET_LAST_PROCESSED_BLOCK,
-                params![format!("{:?}", address)],
+                Self::GET_SEGMENT_START,
+                params![addr_to_db_string(token), addr_to_db_string(contract)],
                 |row| row.get(0),
             )
             .optional()?;
         Ok(block)
     }
 
-    pub fn update_last_processed_block(&self, address: &Address, block_number: u64) -> Result<()> {
+    pub fn save_segment_start(
+        &self,
+        token: &Address,
+        contract: &Address,
+        block: u64,
+    ) -> Result<()> {
         self.conn.execute(
-            Self::UPDATE_LAST_PROCESSED_BLOCK,
-            params![block_number, format!("{:?}", address)],
+            Self::SAVE_SEGMENT_START,
+            params![addr_to_db_string(token), addr_to_db_string(contract), block],
         )?;
         Ok(())
     }
 
+    /// Deployment blocks of every other indexed token, in ascending order
+    pub fn get_other_deployment_blocks(&self, address: &Address) -> Result<Vec<u64>> {
+        let mut stmt = self.conn.prepare(Self::GET_OTHER_DEPLOYMENT_BLOCKS)?;
+        let blocks = stmt
+            .query_map(params![addr_to_db_string(address)], |row| row.get(0))?
+            .collect::<Result<Vec<u64>, _>>()?;
+        Ok(blocks)
+    }
+
+    pub fn get_last_processed_block(&self, address: &Address) -> Result<Option<u64>> {
+        let block: Option<u64> = self
+            .conn
+            .query_row(
+                Self::GET_LAST_PROCESSED_BLOCK,
+                params![addr_to_db_string(address)],
+                |row| row.get::<_, Option<u64>>(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(block)
+    }
+
+    pub fn update_last_processed_block(&self, address: &Address, block_number: u64) -> Result<()> {
+        self.conn
+            .prepare_cached(Self::UPDATE_LAST_PROCESSED_BLOCK)?
+            .execute(params![block_number, addr_to_db_string(address)])?;
+        Ok(())
+    }
+
+    /// Record that a batch was just committed
+    pub fn update_last_inserted_at(&self, address: &Address) -> Result<()> {
+        self.conn
+            .prepare_cached(Self::UPDATE_LAST_INSERTED_AT)?
+            .execute(params![addr_to_db_string(address)])?;
+        Ok(())
+    }
+
+    /// Unix time of the last committed batch, None before the first one
+    pub fn get_last_inserted_at(&self, address: &Address) -> Result<Option<u64>> {
+        let timestamp: Option<u64> = self
+            .conn
+            .query_row(
+                Self::GET_LAST_INSERTED_AT,
+                params![addr_to_db_string(address)],
+                |row| row.get::<_, Option<u64>>(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(timestamp)
+    }
+
     pub fn get_token_decimals(&self, address: &Address) -> Result<Option<u8>> {
         let decimals: Option<u8> = self
             .conn
             .query_row(
                 Self::GET_TOKEN_DECIMALS,
-                params![format!("{:?}", address)],
-                |row| row.get(0),
+                params![addr_to_db_string(address)],
+                |row| row.get::<_, Option<u8>>(0),
             )
-            .optional()?;
+            .optional()?
+            .flatten();
         Ok(decimals)
     }
 
+    pub fn get_chain_id(&self, address: &Address) -> Result<Option<u64>> {
+        let chain_id: Option<u64> = self
+            .conn
+            .query_row(
+                Self::GET_CHAIN_ID,
+                params![addr_to_db_string(address)],
+                |row| row.get::<_, Option<u64>>(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(chain_id)
+    }
+
+    /// Record the chain unless one is already stored
+    pub fn set_chain_id(&self, address: &Address, chain_id: u64) -> Result<()> {
+        self.conn.execute(
+            Self::SET_CHAIN_ID,
+            params![chain_id, addr_to_db_string(address)],
+        )?;
+        Ok(())
+    }
+
     pub fn get_last_processed_finalized_block(&self, address: &Address) -> Result<Option<u64>> {
         let block: Option<u64> = self
             .conn
             .query_row(
                 Self::GET_LAST_PROCESSED_FINALIZED_BLOCK,
-                params![format!("{:?}", address)],
-                |row| row.get(0),
+                params![addr_to_db_string(address)],
+                |row| row.get::<_, Option<u64>>(0),
             )
-            .optional()?;
+            .optional()?
+            .flatten();
         Ok(block)
     }
 
@@ -112,7 +274,42 @@ impl<'a> TokenRepository<'a> {
     ) -> Result<()> {
         self.conn.execute(
             Self::UPDATE_LAST_PROCESSED_FINALIZED_BLOCK,
-            params![block_number, format!("{:?}", address)],
+            params![block_number, addr_to_db_string(address)],
+        )?;
+        Ok(())
+    }
+
+    /// Last block whose newly finalized transfers are included in the balances
+    pub fn get_last_balance_applied_block(&self, address: &Address) -> Result<Option<u64>> {
+        let block: Option<u64> = self
+            .conn
+            .query_row(
+                Self::GET_LAST_BALANCE_APPLIED_BLOCK,
+                params![addr_to_db_string(address)],
+                |row| row.get::<_, Option<u64>>(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(block)
+    }
+
+    pub fn raise_last_balance_applied_block(
+        &self,
+        address: &Address,
+        block_number: u64,
+    ) -> Result<()> {
+        self.conn.execute(
+            Self::RAISE_LAST_BALANCE_APPLIED_BLOCK,
+            params![block_number, addr_to_db_string(address)],
+        )?;
+        Ok(())
+    }
+
+    /// Move every cursor at or past `block_number` back to it
+    pub fn rewind_cursors(&self, address: &Address, block_number: u64) -> Result<()> {
+        self.conn.execute(
+            Self::REWIND_CURSORS,
+            params![block_number, addr_to_db_string(address)],
         )?;
         Ok(())
     }
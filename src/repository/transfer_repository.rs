@@ -1,11 +1,29 @@
+use super::address::{addr_column, addr_to_db_string};
+use super::balance_repository::BalanceRepository;
+use super::checkpoint_repository::CheckpointRepository;
+use super::codec::{blob_to_u256, u256_column, u256_to_blob};
+use super::event_repository::EventRepository;
 use super::models::Transfer;
-use alloy_primitives::{Address, B256, U256};
-use anyhow::Result;
-use rusqlite::{Row, ToSql, params, params_from_iter};
+use super::multi_token_repository::MultiTokenRepository;
+use super::nft_repository::NftRepository;
+use super::reorg_repository::{ReorgRepository, ReorgedBlock};
+use super::scanned_range_repository::ScannedRangeRepository;
+use super::token_repository::TokenRepository;
+use crate::error::{IndexerError, Result, StorageError};
+use alloy_primitives::{Address, B256, I256, U256};
+use rusqlite::{
+    CachedStatement, Connection, OptionalExtension, Row, ToSql, Transaction, TransactionBehavior,
+    params, params_from_iter,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
 use std::str::FromStr;
 
+/// Transfer queries scoped to a single token
 pub struct TransferRepository<'a> {
     conn: &'a rusqlite::Connection,
+    token: Address,
+    token_address: String,
 }
 
 impl<'a> TransferRepository<'a> {
@@ -15,56 +33,322 @@ impl<'a> TransferRepository<'a> {
             from_address, to_address, value, block_number, block_hash, is_finalized
         ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)";
 
+    /// `INSERT_TRANSFER` for several rows: this is followed by one `(?, ...)`
+    /// group of `TRANSFER_COLUMNS` parameters per row and `RETURNING_INSERTED`.
+    /// Ignored rows return nothing, which tells the inserted ones apart.
+    const INSERT_TRANSFERS_MULTI_ROW: &'static str = "INSERT OR IGNORE INTO transfers (
+            transaction_hash, log_index, token_address,
+            from_address, to_address, value, block_number, block_hash, is_finalized
+        ) VALUES ";
+
+    const RETURNING_INSERTED: &'static str = " RETURNING transaction_hash, log_index";
+
     const SELECT_TRANSFER_VIEW: &'static str =
         "SELECT transaction_hash, from_address, to_address, value, block_number FROM transfers";
 
-    const UPDATE_FINALITY_STATUS: &'static str =
-        "UPDATE transfers SET is_finalized = ?1 WHERE block_number >= ?2 AND block_number <= ?3";
+    const UPDATE_FINALITY_STATUS: &'static str = "UPDATE transfers SET is_finalized = ?1
+         WHERE token_address = ?2 AND block_number >= ?3 AND block_number <= ?4";
 
     const DELETE_TRANSFERS_FOR_BLOCK: &'static str =
-        "DELETE FROM transfers WHERE block_number = ?1";
+        "DELETE FROM transfers WHERE token_address = ?1 AND block_number = ?2";
 
-    pub fn new(conn: &'a rusqlite::Connection) -> Self {
-        Self { conn }
-    }
+    const SELECT_FINALIZED_TRANSFERS_FOR_BLOCK: &'static str =
+        "SELECT transaction_hash, log_index, token_address,
+            from_address, to_address, value, block_number, block_hash, is_finalized, token_id
+        FROM transfers LEFT JOIN nft_transfers USING (transaction_hash, log_index) WHERE token_address = ?1 AND block_number = ?2 AND is_finalized = 1";
+
+    const SELECT_FINALIZED_TRANSFERS_ABOVE: &'static str =
+        "SELECT transaction_hash, log_index, token_address,
+            from_address, to_address, value, block_number, block_hash, is_finalized, token_id
+        FROM transfers LEFT JOIN nft_transfers USING (transaction_hash, log_index) WHERE token_address = ?1 AND block_number > ?2 AND is_finalized = 1";
+
+    const SELECT_BLOCKS_ABOVE: &'static str = "SELECT DISTINCT block_number FROM transfers
+         WHERE token_address = ?1 AND block_number > ?2";
+
+    const DELETE_TRANSFERS_ABOVE: &'static str =
+        "DELETE FROM transfers WHERE token_address = ?1 AND block_number > ?2";
+
+    const SELECT_UNFINALIZED_TRANSFERS_IN_RANGE: &'static str =
+        "SELECT transaction_hash, log_index, token_address,
+            from_address, to_address, value, block_number, block_hash, is_finalized, token_id
+        FROM transfers LEFT JOIN nft_transfers USING (transaction_hash, log_index) WHERE token_address = ?1 AND block_number >= ?2 AND block_number <= ?3
+            AND is_finalized = 0";
+
+    const SELECT_TRANSFERS_BY_TX: &'static str =
+        "SELECT transaction_hash, log_index, token_address,
+            from_address, to_address, value, block_number, block_hash, is_finalized, token_id
+        FROM transfers LEFT JOIN nft_transfers USING (transaction_hash, log_index) WHERE token_address = ?1 AND transaction_hash = ?2
+        ORDER BY log_index";
+
+    const SELECT_ADDRESS_TRANSFERS: &'static str =
+        "SELECT from_address, to_address, value FROM transfers
+        WHERE token_address = ?1 AND (from_address = ?2 OR to_address = ?2)";
+
+    /// Transfers of `?1` oldest first, with the side it was on and the address
+    /// on the other side. `?3` set keeps finalized transfers only.
+    const SELECT_ADDRESS_HISTORY: &'static str =
+        "SELECT transaction_hash, from_address, to_address, value, block_number,
+            CASE WHEN from_address = ?1 AND to_address = ?1 THEN 'self'
+                WHEN from_address = ?1 THEN 'out' ELSE 'in' END,
+            CASE WHEN from_address = ?1 THEN to_address ELSE from_address END
+        FROM transfers
+        WHERE token_address = ?2 AND (from_address = ?1 OR to_address = ?1)
+            AND (?3 = 0 OR is_finalized = 1)
+        ORDER BY block_number, log_index
+        LIMIT ?4 OFFSET ?5";
+
+    const SELECT_TRANSFERS_IN_RANGE: &'static str =
+        "SELECT transaction_hash, log_index, token_address,
+            from_address, to_address, value, block_number, block_hash, is_finalized, token_id
+        FROM transfers LEFT JOIN nft_transfers USING (transaction_hash, log_index) WHERE token_address = ?1 AND block_number >= ?2 AND block_number <= ?3
+        ORDER BY block_number, log_index";
 
-    fn transfer_params(transfer: &Transfer) -> Vec<Box<dyn ToSql>> {
-        vec![
-            Box::new(format!("{:?}", transfer.transaction_hash)),
-            Box::new(transfer.log_index),
-            Box::new(format!("{:?}", transfer.token_address)),
-            Box::new(format!("{:?}", transfer.from_address)),
-            Box::new(format!("{:?}", transfer.to_address)),
-            Box::new(transfer.value.to_string()),
-            Box::new(transfer.block_number),
-            Box::new(format!("{:?}", transfer.block_hash)),
-            Box::new(transfer.is_finalized),
-        ]
+    const SELECT_BLOCK_COUNTS: &'static str =
+        "SELECT COUNT(*), COUNT(DISTINCT from_address), COUNT(DISTINCT to_address)
+        FROM transfers WHERE token_address = ?1 AND block_number = ?2";
+
+    const INSERT_SEEN_ADDRESS: &'static str =
+        "INSERT OR IGNORE INTO seen_addresses (token_address, address) VALUES (?1, ?2)";
+
+    const SELECT_BLOCK_BOUNDS: &'static str =
+        "SELECT MIN(block_number), MAX(block_number) FROM transfers WHERE token_address = ?1";
+
+    const COUNT_DUPLICATE_TRANSFERS: &'static str = "SELECT COUNT(*) FROM (
+            SELECT 1 FROM transfers WHERE token_address = ?1
+            GROUP BY transaction_hash, log_index HAVING COUNT(*) > 1
+         )";
+
+    const SELECT_TRANSFERS_ABOVE: &'static str = "SELECT COUNT(*), MIN(block_number) FROM transfers
+         WHERE token_address = ?1 AND block_number > ?2 AND (?3 = 0 OR is_finalized = 1)";
+
+    const UPSERT_STATS: &'static str = "INSERT INTO stats (
+            token_address, total_transfers, unique_addresses, earliest_block, latest_block
+        ) VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT(token_address) DO UPDATE SET
+            total_transfers = total_transfers + excluded.total_transfers,
+            unique_addresses = unique_addresses + excluded.unique_addresses,
+            earliest_block = excluded.earliest_block,
+            latest_block = excluded.latest_block";
+
+    const SELECT_STATS: &'static str =
+        "SELECT total_transfers, unique_addresses, earliest_block, latest_block
+         FROM stats WHERE token_address = ?1";
+
+    pub fn new(conn: &'a rusqlite::Connection, token_address: &Address) -> Self {
+        Self {
+            conn,
+            token: *token_address,
+            token_address: addr_to_db_string(token_address),
+        }
     }
 
     pub fn insert(&self, transfer: &Transfer) -> Result<()> {
-        let params = Self::transfer_params(transfer);
-        self.conn
-            .execute(Self::INSERT_TRANSFER, params_from_iter(params))?;
+        self.insert_batch(std::slice::from_ref(transfer))?;
         Ok(())
     }
 
     pub fn insert_batch(&self, transfers: &[Transfer]) -> Result<usize> {
-        let tx = self.conn.unchecked_transaction()?;
-        let mut count = 0;
+        self.insert_and_finalize(transfers, false, None)
+    }
 
-        {
-            let mut stmt = tx.prepare(Self::INSERT_TRANSFER)?;
+    /// `insert_batch` binding `MULTI_ROW_INSERT_ROWS` transfers per statement,
+    /// which saves SQLite's per-statement overhead on large batches. Rows left
+    /// over after the last full statement are inserted one by one.
+    pub fn insert_batch_multi_row(&self, transfers: &[Transfer]) -> Result<usize> {
+        self.insert_and_finalize(transfers, true, None)
+    }
+
+    /// Insert a batch and, in the same transaction, finalize the unfinalized
+    /// transfers stored in `finalize_range`. Both add to the balances in one
+    /// step, so the result doesn't depend on the order the transfers were
+    /// stored in. Returns how many transfers were inserted. A balance going
+    /// below zero rolls it all back and fails with the fatal
+    /// `IndexerError::BalanceShortfall`, recorded once however often the same
+    /// batch is tried.
+    pub fn insert_and_finalize(
+        &self,
+        transfers: &[Transfer],
+        multi_row: bool,
+        finalize_range: Option<(u64, u64)>,
+    ) -> Result<usize> {
+        let result = self.insert_and_finalize_in_own_tx(transfers, multi_row, finalize_range);
+        BalanceRepository::new(self.conn, &self.token).record_shortfall(result)
+    }
+
+    fn insert_and_finalize_in_own_tx(
+        &self,
+        transfers: &[Transfer],
+        multi_row: bool,
+        finalize_range: Option<(u64, u64)>,
+    ) -> Result<usize> {
+        let tx = Transaction::new_unchecked(self.conn, TransactionBehavior::Immediate)?;
+        let inserted = if multi_row {
+            Self::insert_rows_multi_row(&tx, transfers)?
+        } else {
+            Self::insert_rows(&tx, transfers)?
+        };
+        self.update_stats(&tx, &inserted, 0)?;
+        let nft_repo = NftRepository::new(&tx, &self.token);
+        nft_repo.insert_token_ids(&inserted)?;
+        let multi_token_repo = MultiTokenRepository::new(&tx, &self.token);
+        multi_token_repo.insert_amounts(&inserted)?;
+        EventRepository::new(&tx, &self.token).insert_events(&inserted)?;
 
-            for transfer in transfers {
-                let params = Self::transfer_params(transfer);
-                let result = stmt.execute(params_from_iter(params))?;
-                count += result;
+        // Rows inserted unfinalized just now are picked up here too, which is
+        // fine: balances only count finalized transfers
+        let newly_finalized = match finalize_range {
+            Some((from, to)) => {
+                multi_token_repo.apply_unfinalized_in_range(from, to)?;
+                self.finalize_in_tx(&tx, from, to)?
             }
+            None => Vec::new(),
+        };
+
+        // Rows that were already present had their balances applied when first inserted
+        let mut applied = inserted.clone();
+        applied.extend(newly_finalized.iter());
+        BalanceRepository::new(&tx, &self.token).apply_in_tx(&tx, &applied)?;
+        nft_repo.refresh_owners(&applied)?;
+        multi_token_repo.apply(&inserted)?;
+        // Checkpoints at or above a changed block no longer hold, only a
+        // coverage repair writes that far back
+        if let Some(lowest) = applied.iter().map(|t| t.block_number).min() {
+            CheckpointRepository::new(&tx, &self.token).invalidate_from(lowest)?;
         }
 
         tx.commit()?;
-        Ok(count)
+        Ok(inserted.len())
+    }
+
+    fn insert_rows_multi_row<'t>(
+        tx: &Transaction,
+        transfers: &'t [Transfer],
+    ) -> Result<Vec<&'t Transfer>> {
+        let mut inserted = Vec::new();
+
+        let mut chunks = transfers.chunks_exact(MULTI_ROW_INSERT_ROWS);
+        {
+            let mut stmt = tx.prepare_cached(&Self::multi_row_insert_sql(MULTI_ROW_INSERT_ROWS))?;
+            let mut row = TransferRow::default();
+
+            for chunk in &mut chunks {
+                for (i, transfer) in chunk.iter().enumerate() {
+                    row.bind(&mut stmt, i * TRANSFER_COLUMNS, transfer)?;
+                }
+
+                let mut returned = HashSet::new();
+                let mut rows = stmt.raw_query();
+                while let Some(returned_row) = rows.next()? {
+                    let hash: String = returned_row.get(0)?;
+                    let log_index: u64 = returned_row.get(1)?;
+                    let hash = B256::from_str(&hash).map_err(|e| {
+                        StorageError::Unusable(format!(
+                            "Invalid transaction hash in database: {hash}: {e}"
+                        ))
+                    })?;
+                    returned.insert((hash, log_index));
+                }
+
+                // A key repeated within the chunk is only inserted the first time
+                inserted.extend(
+                    chunk
+                        .iter()
+                        .filter(|t| returned.remove(&(t.transaction_hash, t.log_index))),
+                );
+            }
+        }
+
+        inserted.extend(Self::insert_rows(tx, chunks.remainder())?);
+        Ok(inserted)
+    }
+
+    fn multi_row_insert_sql(rows: usize) -> String {
+        let placeholders = ["?"; TRANSFER_COLUMNS].join(", ");
+        let values = vec![format!("({placeholders})"); rows].join(", ");
+        format!(
+            "{}{values}{}",
+            Self::INSERT_TRANSFERS_MULTI_ROW,
+            Self::RETURNING_INSERTED
+        )
+    }
+
+    /// Insert transfers one statement each, returning the ones that were new
+    fn insert_rows<'t>(tx: &Transaction, transfers: &'t [Transfer]) -> Result<Vec<&'t Transfer>> {
+        let mut inserted = Vec::new();
+        let mut stmt = tx.prepare_cached(Self::INSERT_TRANSFER)?;
+        let mut row = TransferRow::default();
+
+        for transfer in transfers {
+            if row.insert(&mut stmt, transfer)? > 0 {
+                inserted.push(transfer);
+            }
+        }
+        Ok(inserted)
+    }
+
+    /// Mark the unfinalized transfers in `from..=to` as finalized and return
+    /// them, for the caller to add to the balances
+    fn finalize_in_tx(&self, tx: &Transaction, from: u64, to: u64) -> Result<Vec<Transfer>> {
+        let newly_finalized: Vec<Transfer> = {
+            let mut stmt = tx.prepare_cached(Self::SELECT_UNFINALIZED_TRANSFERS_IN_RANGE)?;
+            stmt.query_map(params![self.token_address, from, to], |row| {
+                Self::row_to_transfer(row).map(|transfer| Transfer {
+                    is_finalized: true,
+                    ..transfer
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?
+        };
+
+        if !newly_finalized.is_empty() {
+            tx.execute(
+                Self::UPDATE_FINALITY_STATUS,
+                params![true, self.token_address, from, to],
+            )?;
+        }
+        Ok(newly_finalized)
+    }
+
+    /// Bring the running counters in line with a write, inside its transaction.
+    /// Addresses stay counted once seen, so ones that only appeared in reorged
+    /// blocks are still included until the counters are rebuilt.
+    fn update_stats(
+        &self,
+        conn: &Connection,
+        inserted: &[&Transfer],
+        deleted: usize,
+    ) -> Result<()> {
+        if inserted.is_empty() && deleted == 0 {
+            return Ok(());
+        }
+
+        let mut new_addresses = 0;
+        {
+            let mut stmt = conn.prepare_cached(Self::INSERT_SEEN_ADDRESS)?;
+            for transfer in inserted {
+                for address in [&transfer.from_address, &transfer.to_address] {
+                    new_addresses +=
+                        stmt.execute(params![self.token_address, addr_to_db_string(address)])?;
+                }
+            }
+        }
+
+        // Both ends resolve through the (token_address, block_number) index
+        let (earliest_block, latest_block): (Option<u64>, Option<u64>) = conn
+            .prepare_cached(Self::SELECT_BLOCK_BOUNDS)?
+            .query_row(params![self.token_address], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+
+        conn.prepare_cached(Self::UPSERT_STATS)?.execute(params![
+            self.token_address,
+            inserted.len() as i64 - deleted as i64,
+            new_addresses,
+            earliest_block,
+            latest_block
+        ])?;
+
+        Ok(())
     }
 
     pub fn query_transfers(
@@ -76,73 +360,360 @@ impl<'a> TransferRepository<'a> {
         limit: usize,
         offset: usize,
     ) -> Result<Vec<TransferView>> {
-        let mut conditions = Vec::new();
-        let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+        let filter = TransferFilter {
+            from_address: from_address.copied(),
+            to_address: to_address.copied(),
+            block_range,
+            finalized_only,
+            ..Default::default()
+        };
+        self.collect_transfers(&filter, limit, offset)
+    }
 
-        if let Some(from) = from_address {
-            conditions.push("from_address = ?");
-            params.push(Box::new(format!("{from:?}")));
+    pub fn get_address_history(
+        &self,
+        address: &Address,
+        finalized_only: bool,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<AddressHistoryEntry>> {
+        let mut entries = Vec::new();
+        self.stream_address_history(address, finalized_only, limit, offset, |entry| {
+            entries.push(entry);
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    /// Net amount the first `count` transfers of the address's history moved
+    /// into it, the balance before the page at offset `count` of a history
+    /// starting from zero. The amounts are stored as blobs, so they are added
+    /// up here.
+    pub fn get_address_history_net(
+        &self,
+        address: &Address,
+        finalized_only: bool,
+        count: usize,
+    ) -> Result<I256> {
+        let mut net = I256::ZERO;
+        if count > 0 {
+            self.stream_address_history(address, finalized_only, count, 0, |entry| {
+                net = entry.direction.apply(net, entry.transfer.value);
+                Ok(())
+            })?;
         }
+        Ok(net)
+    }
 
-        if let Some(to) = to_address {
-            conditions.push("to_address = ?");
-            params.push(Box::new(format!("{to:?}")));
+    /// Visit the transfers `address` sent or received oldest first, one row at
+    /// a time. Returns the number visited.
+    pub fn stream_address_history<F>(
+        &self,
+        address: &Address,
+        finalized_only: bool,
+        limit: usize,
+        offset: usize,
+        mut visit: F,
+    ) -> Result<u64>
+    where
+        F: FnMut(AddressHistoryEntry) -> Result<()>,
+    {
+        let mut stmt = self.conn.prepare(Self::SELECT_ADDRESS_HISTORY)?;
+        let mut rows = stmt.query(params![
+            addr_to_db_string(address),
+            self.token_address,
+            finalized_only,
+            limit as i64,
+            offset as i64
+        ])?;
+
+        let mut count = 0;
+        while let Some(row) = rows.next()? {
+            let direction = match row.get::<_, String>(5)?.as_str() {
+                "in" => Direction::In,
+                "out" => Direction::Out,
+                _ => Direction::ToSelf,
+            };
+            visit(AddressHistoryEntry {
+                transfer: Self::row_to_transfer_view(row)?,
+                direction,
+                counterparty: addr_column(row, 6)?,
+            })?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Visit the transfers matching `filter` one row at a time, so large result
+    /// sets can be written out without holding them in memory. Returns the
+    /// number visited.
+    pub fn stream_transfers<F>(
+        &self,
+        filter: &TransferFilter,
+        limit: usize,
+        offset: usize,
+        mut visit: F,
+    ) -> Result<u64>
+    where
+        F: FnMut(TransferView) -> Result<()>,
+    {
+        let mut conditions = vec!["token_address = ?".to_string()];
+        let mut params: Vec<Box<dyn ToSql>> = vec![Box::new(self.token_address.clone())];
+
+        if let Some(from) = &filter.from_address {
+            conditions.push("from_address = ?".to_string());
+            params.push(Box::new(addr_to_db_string(from)));
+        }
+
+        if let Some(to) = &filter.to_address {
+            conditions.push("to_address = ?".to_string());
+            params.push(Box::new(addr_to_db_string(to)));
         }
 
-        if let Some((start, end)) = block_range {
-            conditions.push("block_number >= ?");
+        // Parenthesized so the OR doesn't take the other conditions with it
+        if let Some(address) = &filter.involving {
+            let address_str = addr_to_db_string(address);
+            conditions.push("(from_address = ? OR to_address = ?)".to_string());
+            params.push(Box::new(address_str.clone()));
+            params.push(Box::new(address_str));
+        }
+
+        let exclusions = [
+            ("from_address", &filter.exclude_from),
+            ("to_address", &filter.exclude_to),
+        ];
+        for (column, excluded) in exclusions {
+            if excluded.is_empty() {
+                continue;
+            }
+            let placeholders = vec!["?"; excluded.len()].join(", ");
+            conditions.push(format!("{column} NOT IN ({placeholders})"));
+            params.extend(
+                excluded
+                    .iter()
+                    .map(|address| Box::new(addr_to_db_string(address)) as Box<dyn ToSql>),
+            );
+        }
+
+        if let Some((start, end)) = filter.block_range {
+            conditions.push("block_number >= ?".to_string());
             params.push(Box::new(start));
-            conditions.push("block_number <= ?");
+            conditions.push("block_number <= ?".to_string());
             params.push(Box::new(end));
         }
 
-        if finalized_only {
-            conditions.push("is_finalized = ?");
+        if let Some(token_id) = &filter.token_id {
+            conditions.push(
+                "(transaction_hash, log_index) IN (
+                    SELECT transaction_hash, log_index FROM nft_transfers WHERE token_id = ?
+                    UNION ALL
+                    SELECT transaction_hash, log_index FROM multi_token_transfers
+                    WHERE token_address = ? AND token_id = ?
+                )"
+                .to_string(),
+            );
+            params.push(Box::new(u256_to_blob(token_id)));
+            params.push(Box::new(self.token_address.clone()));
+            params.push(Box::new(u256_to_blob(token_id)));
+        }
+
+        if filter.finalized_only {
+            conditions.push("is_finalized = ?".to_string());
             params.push(Box::new(true));
         }
 
-        self.execute_paginated_query(conditions, params, limit, offset, None)
+        let query = format!(
+            "{} WHERE {} LIMIT {limit} OFFSET {offset}",
+            Self::SELECT_TRANSFER_VIEW,
+            conditions.join(" AND ")
+        );
+
+        let mut stmt = self.conn.prepare(&query)?;
+        let mut rows = stmt.query(params_from_iter(params))?;
+
+        let mut count = 0;
+        while let Some(row) = rows.next()? {
+            visit(Self::row_to_transfer_view(row)?)?;
+            count += 1;
+        }
+
+        Ok(count)
     }
 
-    pub fn get_address_history(
+    fn collect_transfers(
         &self,
-        address: &Address,
-        finalized_only: bool,
+        filter: &TransferFilter,
         limit: usize,
         offset: usize,
     ) -> Result<Vec<TransferView>> {
-        let address_str = format!("{address:?}");
-        let mut conditions = vec!["(from_address = ? OR to_address = ?)"];
-        let mut params: Vec<Box<dyn ToSql>> =
-            vec![Box::new(address_str.clone()), Box::new(address_str)];
+        let mut transfers = Vec::new();
+        self.stream_transfers(filter, limit, offset, |transfer| {
+            transfers.push(transfer);
+            Ok(())
+        })?;
+        Ok(transfers)
+    }
 
-        if finalized_only {
-            conditions.push("is_finalized = ?");
-            params.push(Box::new(true));
+    /// Addresses `address` has transferred with, by combined sent and received
+    /// volume. The totals are added up here as the amounts are stored as blobs.
+    pub fn get_counterparties(&self, address: &Address, limit: usize) -> Result<Vec<Counterparty>> {
+        let mut stmt = self.conn.prepare(Self::SELECT_ADDRESS_TRANSFERS)?;
+        let rows = stmt.query_map(
+            params![self.token_address, addr_to_db_string(address)],
+            |row| {
+                Ok((
+                    addr_column(row, 0)?,
+                    addr_column(row, 1)?,
+                    u256_column(row, 2)?,
+                ))
+            },
+        )?;
+
+        let mut counterparties: HashMap<Address, Counterparty> = HashMap::new();
+        for row in rows {
+            let (from, to, value) = row?;
+
+            // A transfer to itself counts as both sent and received
+            if from == *address {
+                let counterparty = counterparties
+                    .entry(to)
+                    .or_insert_with(|| Counterparty::new(to));
+                counterparty.sent_count += 1;
+                counterparty.sent_total = counterparty.sent_total.saturating_add(value);
+            }
+            if to == *address {
+                let counterparty = counterparties
+                    .entry(from)
+                    .or_insert_with(|| Counterparty::new(from));
+                counterparty.received_count += 1;
+                counterparty.received_total = counterparty.received_total.saturating_add(value);
+            }
+        }
+
+        let mut counterparties: Vec<Counterparty> = counterparties.into_values().collect();
+        counterparties.sort_by(|a, b| {
+            b.total_volume()
+                .cmp(&a.total_volume())
+                .then(a.address.cmp(&b.address))
+        });
+        counterparties.truncate(limit);
+
+        Ok(counterparties)
+    }
+
+    /// All transfers of the token in one block with the block's totals. Values
+    /// are summed here since the amount blobs can't be added up in SQL.
+    pub fn get_block_summary(&self, block_number: u64) -> Result<BlockSummary> {
+        let transfers = self.query_transfers(
+            None,
+            None,
+            Some((block_number, block_number)),
+            false,
+            i64::MAX as usize,
+            0,
+        )?;
+
+        let (transfer_count, unique_senders, unique_receivers) = self.conn.query_row(
+            Self::SELECT_BLOCK_COUNTS,
+            params![self.token_address, block_number],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+
+        let total_value = transfers.iter().fold(U256::ZERO, |sum, transfer| {
+            sum.saturating_add(transfer.value)
+        });
+
+        Ok(BlockSummary {
+            block_number,
+            transfers,
+            transfer_count,
+            total_value,
+            unique_senders,
+            unique_receivers,
+        })
+    }
+
+    /// Visit the token's transfers in `[from_block, to_block]` in chain order,
+    /// one row at a time. Returns the number visited.
+    pub fn iter_transfers_in_range<F>(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        mut visit: F,
+    ) -> Result<u64>
+    where
+        F: FnMut(Transfer) -> Result<()>,
+    {
+        let mut stmt = self.conn.prepare(Self::SELECT_TRANSFERS_IN_RANGE)?;
+        let mut rows = stmt.query(params![self.token_address, from_block, to_block])?;
+
+        let mut count = 0;
+        while let Some(row) = rows.next()? {
+            visit(Self::row_to_transfer(row)?)?;
+            count += 1;
         }
 
-        self.execute_paginated_query(conditions, params, limit, offset, None)
+        Ok(count)
     }
 
-    // TODO: Also needs denormalization to perform normally on USDC
-    pub fn get_statistics(&self) -> Result<TransferStats> {
-        let total_transfers: usize =
-            self.conn
-                .query_row("SELECT COUNT(*) FROM transfers", [], |row| row.get(0))?;
+    /// Every indexed transfer of the token emitted by one transaction, in log order
+    pub fn get_transfers_by_tx(&self, tx_hash: &B256) -> Result<Vec<Transfer>> {
+        let mut stmt = self.conn.prepare_cached(Self::SELECT_TRANSFERS_BY_TX)?;
+        let transfers = stmt
+            .query_map(
+                params![self.token_address, format!("{tx_hash:?}")],
+                Self::row_to_transfer,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(transfers)
+    }
+
+    /// Statistics from the running counters. `exact` recomputes them from the
+    /// transfers table instead, which scans every transfer of the token.
+    pub fn get_statistics(&self, exact: bool) -> Result<TransferStats> {
+        if !exact {
+            let counters = self
+                .conn
+                .query_row(Self::SELECT_STATS, params![self.token_address], |row| {
+                    Ok(TransferStats {
+                        total_transfers: row.get(0)?,
+                        unique_addresses: row.get(1)?,
+                        earliest_block: row.get(2)?,
+                        latest_block: row.get(3)?,
+                    })
+                })
+                .optional()?;
+
+            if let Some(stats) = counters {
+                return Ok(stats);
+            }
+        }
+
+        self.compute_statistics()
+    }
+
+    fn compute_statistics(&self) -> Result<TransferStats> {
+        let total_transfers: usize = self.conn.query_row(
+            "SELECT COUNT(*) FROM transfers WHERE token_address = ?1",
+            params![self.token_address],
+            |row| row.get(0),
+        )?;
 
         let unique_addresses: usize = self.conn.query_row(
             "SELECT COUNT(DISTINCT address) FROM (
-                SELECT from_address as address FROM transfers
+                SELECT from_address as address FROM transfers WHERE token_address = ?1
                 UNION
-                SELECT to_address as address FROM transfers
+                SELECT to_address as address FROM transfers WHERE token_address = ?1
             )",
-            [],
+            params![self.token_address],
             |row| row.get(0),
         )?;
 
         let (earliest_block, latest_block): (Option<u64>, Option<u64>) = self.conn.query_row(
-            "SELECT MIN(block_number), MAX(block_number) FROM transfers",
-            [],
+            "SELECT MIN(block_number), MAX(block_number) FROM transfers WHERE token_address = ?1",
+            params![self.token_address],
             |row| Ok((row.get(0)?, row.get(1)?)),
         )?;
 
@@ -154,33 +725,65 @@ impl<'a> TransferRepository<'a> {
         })
     }
 
-    fn execute_paginated_query(
+    /// Transfer volume grouped into buckets of `bucket_blocks` blocks, aligned to
+    /// multiples of the bucket size. Values can't be summed in SQL, so the
+    /// transfers are streamed in block order and aggregated one bucket at a time.
+    /// Nothing is precomputed: distinct senders and receivers don't add up
+    /// across buckets, so a stored table couldn't serve arbitrary bucket sizes.
+    pub fn get_volume_by_period(
         &self,
-        conditions: Vec<&str>,
-        params: Vec<Box<dyn ToSql>>,
-        limit: usize,
-        offset: usize,
-        order_by: Option<&str>,
-    ) -> Result<Vec<TransferView>> {
-        let mut query = Self::SELECT_TRANSFER_VIEW.to_string();
-
-        if !conditions.is_empty() {
-            query.push_str(" WHERE ");
-            query.push_str(&conditions.join(" AND "));
+        bucket_blocks: u64,
+        block_range: Option<(u64, u64)>,
+    ) -> Result<Vec<VolumeBucket>> {
+        if bucket_blocks == 0 {
+            return Err(IndexerError::Other(anyhow::anyhow!(
+                "Bucket size must be at least one block"
+            )));
         }
 
-        if let Some(order) = order_by {
-            query.push_str(order);
-        }
+        let (start, end) = block_range.unwrap_or((0, u64::MAX));
+        let mut stmt = self.conn.prepare(
+            "SELECT block_number, from_address, to_address, value FROM transfers
+             WHERE token_address = ?1 AND block_number >= ?2 AND block_number <= ?3
+             ORDER BY block_number",
+        )?;
+        let rows = stmt.query_map(
+            params![self.token_address, start, end.min(i64::MAX as u64)],
+            |row| {
+                Ok((
+                    row.get::<_, u64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    u256_column(row, 3)?,
+                ))
+            },
+        )?;
 
-        query.push_str(&format!(" LIMIT {limit} OFFSET {offset}"));
+        let mut buckets = Vec::new();
+        let mut current: Option<BucketAccumulator> = None;
 
-        let mut stmt = self.conn.prepare(&query)?;
-        let transfers = stmt
-            .query_map(params_from_iter(params), Self::row_to_transfer_view)?
-            .collect::<Result<Vec<_>, _>>()?;
+        for row in rows {
+            let (block_number, from, to, value) = row?;
+            let bucket_start = block_number - block_number % bucket_blocks;
 
-        Ok(transfers)
+            let accumulator = match current.take() {
+                Some(acc) if acc.bucket.bucket_start_block == bucket_start => acc,
+                finished => {
+                    buckets.extend(finished.map(BucketAccumulator::finish));
+                    BucketAccumulator::new(bucket_start)
+                }
+            };
+            let accumulator = current.insert(accumulator);
+
+            accumulator.bucket.transfer_count += 1;
+            accumulator.bucket.total_value = accumulator.bucket.total_value.saturating_add(value);
+            accumulator.senders.insert(from);
+            accumulator.receivers.insert(to);
+        }
+
+        buckets.extend(current.map(BucketAccumulator::finish));
+
+        Ok(buckets)
     }
 
     fn row_to_transfer_view(row: &Row) -> rusqlite::Result<TransferView> {
@@ -188,17 +791,10 @@ impl<'a> TransferRepository<'a> {
             rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
         })?;
 
-        let from_address = Address::from_str(&row.get::<_, String>(1)?).map_err(|e| {
-            rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(e))
-        })?;
-
-        let to_address = Address::from_str(&row.get::<_, String>(2)?).map_err(|e| {
-            rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(e))
-        })?;
+        let from_address = addr_column(row, 1)?;
+        let to_address = addr_column(row, 2)?;
 
-        let value = U256::from_str(&row.get::<_, String>(3)?).map_err(|e| {
-            rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(e))
-        })?;
+        let value = u256_column(row, 3)?;
 
         Ok(TransferView {
             transaction_hash,
@@ -209,6 +805,69 @@ impl<'a> TransferRepository<'a> {
         })
     }
 
+    fn row_to_transfer(row: &Row) -> rusqlite::Result<Transfer> {
+        let hash_column = |index: usize| {
+            row.get::<_, String>(index)?.parse::<B256>().map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    index,
+                    rusqlite::types::Type::Text,
+                    Box::new(e),
+                )
+            })
+        };
+
+        Ok(Transfer {
+            transaction_hash: hash_column(0)?,
+            log_index: row.get(1)?,
+            token_address: addr_column(row, 2)?,
+            from_address: addr_column(row, 3)?,
+            to_address: addr_column(row, 4)?,
+            value: u256_column(row, 5)?,
+            block_number: row.get(6)?,
+            block_hash: hash_column(7)?,
+            is_finalized: row.get(8)?,
+            token_id: row
+                .get_ref(9)?
+                .as_blob_or_null()?
+                .map(|blob| {
+                    blob_to_u256(blob).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            9,
+                            rusqlite::types::Type::Blob,
+                            e.into(),
+                        )
+                    })
+                })
+                .transpose()?,
+            token_amounts: Vec::new(),
+            event: None,
+        })
+    }
+
+    /// (transaction_hash, log_index) pairs stored more than once
+    pub fn count_duplicate_transfers(&self) -> Result<u64> {
+        let count = self.conn.query_row(
+            Self::COUNT_DUPLICATE_TRANSFERS,
+            params![self.token_address],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    /// Number of transfers above `block_number` and the lowest block among them
+    pub fn count_transfers_above(
+        &self,
+        block_number: u64,
+        finalized_only: bool,
+    ) -> Result<(u64, Option<u64>)> {
+        let counts = self.conn.query_row(
+            Self::SELECT_TRANSFERS_ABOVE,
+            params![self.token_address, block_number, finalized_only],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(counts)
+    }
+
     pub fn get_block_hashes_in_range(
         &self,
         from_block: u64,
@@ -216,12 +875,12 @@ impl<'a> TransferRepository<'a> {
     ) -> Result<std::collections::HashMap<u64, B256>> {
         let query = "SELECT DISTINCT block_number, block_hash 
                      FROM transfers 
-                     WHERE block_number >= ? AND block_number <= ?";
+                     WHERE token_address = ? AND block_number >= ? AND block_number <= ?";
 
         let mut stmt = self.conn.prepare(query)?;
         let mut block_hashes = std::collections::HashMap::new();
 
-        let rows = stmt.query_map(params![from_block, to_block], |row| {
+        let rows = stmt.query_map(params![self.token_address, from_block, to_block], |row| {
             let block_num: u64 = row.get(0)?;
             let block_hash = B256::from_str(&row.get::<_, String>(1)?).map_err(|e| {
                 rusqlite::Error::FromSqlConversionFailure(
@@ -235,15 +894,14 @@ impl<'a> TransferRepository<'a> {
 
         for row in rows {
             let (block_num, block_hash) = row?;
-            if let Some(existing_hash) = block_hashes.get(&block_num) {
-                if existing_hash != &block_hash {
-                    anyhow::bail!(
-                        "Block {} has multiple distinct block hashes in DB ({:?} and {:?}), this should be impossible!",
-                        block_num,
-                        existing_hash,
-                        block_hash
-                    );
-                }
+            if let Some(existing_hash) = block_hashes.get(&block_num)
+                && existing_hash != &block_hash
+            {
+                return Err(StorageError::Unusable(format!(
+                    "Block {} has multiple distinct block hashes in DB ({:?} and {:?}), this should be impossible!",
+                    block_num, existing_hash, block_hash
+                ))
+                .into());
             }
             block_hashes.insert(block_num, block_hash);
         }
@@ -251,41 +909,292 @@ impl<'a> TransferRepository<'a> {
         Ok(block_hashes)
     }
 
+    /// Replace reorged blocks, mark the range finalized and apply the newly
+    /// finalized transfers to the balances in one transaction, returning
+    /// (deleted, inserted, finalized)
     pub fn process_finality_batch(
         &self,
-        blocks_to_delete: &[u64],
+        reorged_blocks: &[ReorgedBlock],
         transfers_to_insert: &[Transfer],
         mark_finalized_from: u64,
         mark_finalized_to: u64,
     ) -> Result<(usize, usize, usize)> {
-        let tx = self.conn.unchecked_transaction()?;
+        let result = self.process_finality_batch_in_own_tx(
+            reorged_blocks,
+            transfers_to_insert,
+            (mark_finalized_from, mark_finalized_to),
+        );
+        BalanceRepository::new(self.conn, &self.token).record_shortfall(result)
+    }
 
-        let mut deleted_count = 0;
-        let mut inserted_count = 0;
+    fn process_finality_batch_in_own_tx(
+        &self,
+        reorged_blocks: &[ReorgedBlock],
+        transfers_to_insert: &[Transfer],
+        (mark_finalized_from, mark_finalized_to): (u64, u64),
+    ) -> Result<(usize, usize, usize)> {
+        let tx = Transaction::new_unchecked(self.conn, TransactionBehavior::Immediate)?;
+        let token_repo = TokenRepository::new(&tx);
+        let balance_applied_to = token_repo
+            .get_last_balance_applied_block(&self.token)?
+            .unwrap_or(0);
 
-        for block_num in blocks_to_delete {
-            deleted_count += tx.execute(Self::DELETE_TRANSFERS_FOR_BLOCK, params![block_num])?;
-        }
+        let (deleted_count, inserted_count) =
+            self.replace_in_tx(&tx, reorged_blocks, transfers_to_insert)?;
 
-        if !transfers_to_insert.is_empty() {
-            let mut stmt = tx.prepare(Self::INSERT_TRANSFER)?;
-            for transfer in transfers_to_insert {
-                let params = Self::transfer_params(transfer);
-                let result = stmt.execute(params_from_iter(params))?;
-                inserted_count += result;
-            }
-        }
+        // Stored transfers this range finalizes. Blocks at or below the balance
+        // watermark were applied by an earlier run that committed before a crash.
+        let newly_finalized: Vec<Transfer> = {
+            let mut stmt = tx.prepare(Self::SELECT_UNFINALIZED_TRANSFERS_IN_RANGE)?;
+            stmt.query_map(
+                params![
+                    self.token_address,
+                    mark_finalized_from.max(balance_applied_to + 1),
+                    mark_finalized_to
+                ],
+                Self::row_to_transfer,
+            )?
+            .map(|transfer| {
+                transfer.map(|transfer| Transfer {
+                    is_finalized: true,
+                    ..transfer
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?
+        };
+
+        MultiTokenRepository::new(&tx, &self.token).apply_unfinalized_in_range(
+            mark_finalized_from.max(balance_applied_to + 1),
+            mark_finalized_to,
+        )?;
 
         // Mark transfers as finalized
         let finalized_count = tx.execute(
             Self::UPDATE_FINALITY_STATUS,
-            params![true, mark_finalized_from, mark_finalized_to],
+            params![
+                true,
+                self.token_address,
+                mark_finalized_from,
+                mark_finalized_to
+            ],
         )?;
 
+        let newly_finalized: Vec<&Transfer> = newly_finalized.iter().collect();
+        BalanceRepository::new(&tx, &self.token).apply_in_tx(&tx, &newly_finalized)?;
+        NftRepository::new(&tx, &self.token).refresh_owners(&newly_finalized)?;
+        if finalized_count > 0 {
+            CheckpointRepository::new(&tx, &self.token).invalidate_from(mark_finalized_from)?;
+        }
+        token_repo.raise_last_balance_applied_block(&self.token, mark_finalized_to)?;
+
         tx.commit()?;
 
         Ok((deleted_count, inserted_count, finalized_count))
     }
+
+    /// Delete every transfer above `block_number`, take the finalized ones back
+    /// out of the balances and move the cursors back to `block_number`, in one
+    /// transaction, so the next scan resumes at the block after it. Refuses to
+    /// go below the deployment block.
+    pub fn rollback_to(&self, block_number: u64) -> Result<RollbackSummary> {
+        let result = self.rollback_to_in_own_tx(block_number);
+        BalanceRepository::new(self.conn, &self.token).record_shortfall(result)
+    }
+
+    fn rollback_to_in_own_tx(&self, block_number: u64) -> Result<RollbackSummary> {
+        let tx = Transaction::new_unchecked(self.conn, TransactionBehavior::Immediate)?;
+        let token_repo = TokenRepository::new(&tx);
+        let Some(deployment_block) = token_repo.get_deployment_block(&self.token)? else {
+            return Err(IndexerError::NotFound(format!(
+                "Token {:?} has not been indexed yet",
+                self.token
+            )));
+        };
+        if block_number < deployment_block {
+            return Err(IndexerError::Other(anyhow::anyhow!(
+                "Can't roll back to block {block_number}, below the deployment block {deployment_block}"
+            )));
+        }
+        let previous_last_processed_block = token_repo.get_last_processed_block(&self.token)?;
+        let previous_last_finalized_block =
+            token_repo.get_last_processed_finalized_block(&self.token)?;
+
+        let finalized: Vec<Transfer> = tx
+            .prepare(Self::SELECT_FINALIZED_TRANSFERS_ABOVE)?
+            .query_map(
+                params![self.token_address, block_number],
+                Self::row_to_transfer,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        let blocks: Vec<u64> = tx
+            .prepare(Self::SELECT_BLOCKS_ABOVE)?
+            .query_map(params![self.token_address, block_number], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let nft_repo = NftRepository::new(&tx, &self.token);
+        let multi_token_repo = MultiTokenRepository::new(&tx, &self.token);
+        let event_repo = EventRepository::new(&tx, &self.token);
+        for block in &blocks {
+            nft_repo.delete_block(*block)?;
+            multi_token_repo.revert_block(*block)?;
+            event_repo.delete_block(*block)?;
+        }
+        let deleted = tx.execute(
+            Self::DELETE_TRANSFERS_ABOVE,
+            params![self.token_address, block_number],
+        )?;
+        self.update_stats(&tx, &[], deleted)?;
+
+        let finalized: Vec<&Transfer> = finalized.iter().collect();
+        BalanceRepository::new(&tx, &self.token).revert_in_tx(&tx, &finalized)?;
+        nft_repo.refresh_owners(&finalized)?;
+        token_repo.rewind_cursors(&self.token, block_number)?;
+        CheckpointRepository::new(&tx, &self.token).invalidate_from(block_number + 1)?;
+
+        // The scan resumes after `block_number`, gaps below it stay unscanned
+        let scanned_ranges = ScannedRangeRepository::new(&tx, &self.token);
+        scanned_ranges.truncate_above(block_number)?;
+        let unscanned_below = scanned_ranges.gaps(
+            deployment_block + 1,
+            previous_last_processed_block.map_or(0, |block| block.min(block_number)),
+        )?;
+
+        tx.commit()?;
+
+        Ok(RollbackSummary {
+            transfers_deleted: deleted,
+            finalized_reverted: finalized.len(),
+            blocks_deleted: blocks.len(),
+            previous_last_processed_block,
+            previous_last_finalized_block,
+            unscanned_below,
+        })
+    }
+
+    /// Replace the stored transfers of reorged blocks with the canonical ones,
+    /// returning (deleted, inserted)
+    pub fn replace_blocks(
+        &self,
+        reorged_blocks: &[ReorgedBlock],
+        transfers_to_insert: &[Transfer],
+    ) -> Result<(usize, usize)> {
+        let result = self.replace_blocks_in_own_tx(reorged_blocks, transfers_to_insert);
+        BalanceRepository::new(self.conn, &self.token).record_shortfall(result)
+    }
+
+    fn replace_blocks_in_own_tx(
+        &self,
+        reorged_blocks: &[ReorgedBlock],
+        transfers_to_insert: &[Transfer],
+    ) -> Result<(usize, usize)> {
+        let tx = Transaction::new_unchecked(self.conn, TransactionBehavior::Immediate)?;
+        let counts = self.replace_in_tx(&tx, reorged_blocks, transfers_to_insert)?;
+        tx.commit()?;
+        Ok(counts)
+    }
+
+    fn replace_in_tx(
+        &self,
+        tx: &Transaction,
+        reorged_blocks: &[ReorgedBlock],
+        transfers_to_insert: &[Transfer],
+    ) -> Result<(usize, usize)> {
+        let mut deleted_count = 0;
+        let mut deleted_per_block = Vec::with_capacity(reorged_blocks.len());
+        let mut deleted_finalized = Vec::new();
+        let nft_repo = NftRepository::new(tx, &self.token);
+        let multi_token_repo = MultiTokenRepository::new(tx, &self.token);
+        let event_repo = EventRepository::new(tx, &self.token);
+
+        for block in reorged_blocks {
+            // Finalized transfers are in the balances, their amounts come back out
+            let mut stmt = tx.prepare_cached(Self::SELECT_FINALIZED_TRANSFERS_FOR_BLOCK)?;
+            for transfer in stmt.query_map(
+                params![self.token_address, block.block_number],
+                Self::row_to_transfer,
+            )? {
+                deleted_finalized.push(transfer?);
+            }
+
+            nft_repo.delete_block(block.block_number)?;
+            multi_token_repo.revert_block(block.block_number)?;
+            event_repo.delete_block(block.block_number)?;
+            let deleted = tx.execute(
+                Self::DELETE_TRANSFERS_FOR_BLOCK,
+                params![self.token_address, block.block_number],
+            )?;
+            deleted_per_block.push(deleted);
+            deleted_count += deleted;
+        }
+
+        let inserted = Self::insert_rows(tx, transfers_to_insert)?;
+        nft_repo.insert_token_ids(&inserted)?;
+        multi_token_repo.insert_amounts(&inserted)?;
+        event_repo.insert_events(&inserted)?;
+
+        self.update_stats(tx, &inserted, deleted_count)?;
+
+        let deleted_finalized: Vec<&Transfer> = deleted_finalized.iter().collect();
+        let balance_repo = BalanceRepository::new(tx, &self.token);
+        balance_repo.revert_in_tx(tx, &deleted_finalized)?;
+        balance_repo.apply_in_tx(tx, &inserted)?;
+        // Owners are recomputed from what's stored now, reverted tokens included
+        let mut moved = deleted_finalized;
+        moved.extend(inserted.iter());
+        nft_repo.refresh_owners(&moved)?;
+        multi_token_repo.apply(&inserted)?;
+        let lowest = reorged_blocks
+            .iter()
+            .map(|block| block.block_number)
+            .chain(inserted.iter().map(|t| t.block_number))
+            .min();
+        if let Some(lowest) = lowest {
+            CheckpointRepository::new(tx, &self.token).invalidate_from(lowest)?;
+        }
+
+        // Audit trail of every replaced block, committed with the change itself
+        let reorg_repo = ReorgRepository::new(tx, &self.token);
+        for (block, deleted) in reorged_blocks.iter().zip(deleted_per_block) {
+            let inserted_in_block = inserted
+                .iter()
+                .filter(|t| t.block_number == block.block_number)
+                .count();
+            reorg_repo.record(block, deleted, inserted_in_block)?;
+        }
+
+        Ok((deleted_count, inserted.len()))
+    }
+}
+
+/// What [`TransferRepository::rollback_to`] removed
+#[derive(Debug)]
+pub struct RollbackSummary {
+    pub transfers_deleted: usize,
+    /// Deleted transfers that were finalized, whose amounts came out of the balances
+    pub finalized_reverted: usize,
+    pub blocks_deleted: usize,
+    pub previous_last_processed_block: Option<u64>,
+    pub previous_last_finalized_block: Option<u64>,
+    /// Gaps the scan never covered below the new cursor, which the rollback
+    /// doesn't bring back
+    pub unscanned_below: Vec<(u64, u64)>,
+}
+
+/// Which transfers `stream_transfers` returns. Set filters are combined with AND.
+#[derive(Debug, Default)]
+pub struct TransferFilter {
+    pub from_address: Option<Address>,
+    pub to_address: Option<Address>,
+    /// Transfers with this address on either side
+    pub involving: Option<Address>,
+    /// Leave out transfers sent by any of these
+    pub exclude_from: Vec<Address>,
+    /// Leave out transfers received by any of these
+    pub exclude_to: Vec<Address>,
+    pub block_range: Option<(u64, u64)>,
+    /// Transfers that moved this ERC-721 or ERC-1155 token id
+    pub token_id: Option<U256>,
+    pub finalized_only: bool,
 }
 
 #[derive(Debug)]
@@ -297,6 +1206,187 @@ pub struct TransferView {
     pub block_number: u64,
 }
 
+/// Which side of a transfer the queried address was on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+    /// The address sent to itself, its balance is unchanged
+    ToSelf,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+            Direction::ToSelf => "self",
+        }
+    }
+
+    /// Sign the transfer's value takes in the address's balance
+    pub fn sign(&self) -> &'static str {
+        match self {
+            Direction::In => "+",
+            Direction::Out => "-",
+            Direction::ToSelf => "",
+        }
+    }
+
+    /// `balance` after a transfer of `value` in this direction
+    pub fn apply(&self, balance: I256, value: U256) -> I256 {
+        let value = I256::try_from(value).unwrap_or(I256::MAX);
+        match self {
+            Direction::In => balance.saturating_add(value),
+            Direction::Out => balance.saturating_sub(value),
+            Direction::ToSelf => balance,
+        }
+    }
+}
+
+/// A transfer seen from one of the addresses in it
+#[derive(Debug)]
+pub struct AddressHistoryEntry {
+    pub transfer: TransferView,
+    pub direction: Direction,
+    /// The address on the other side, the queried one for self transfers
+    pub counterparty: Address,
+}
+
+/// Transfers between a queried address and one other address. "Sent" is from
+/// the queried address to the counterparty.
+#[derive(Debug)]
+pub struct Counterparty {
+    pub address: Address,
+    pub sent_count: u64,
+    pub sent_total: U256,
+    pub received_count: u64,
+    pub received_total: U256,
+}
+
+impl Counterparty {
+    fn new(address: Address) -> Self {
+        Self {
+            address,
+            sent_count: 0,
+            sent_total: U256::ZERO,
+            received_count: 0,
+            received_total: U256::ZERO,
+        }
+    }
+
+    pub fn total_volume(&self) -> U256 {
+        self.sent_total.saturating_add(self.received_total)
+    }
+}
+
+#[derive(Debug)]
+pub struct BlockSummary {
+    pub block_number: u64,
+    pub transfers: Vec<TransferView>,
+    pub transfer_count: u64,
+    pub total_value: U256,
+    pub unique_senders: u64,
+    pub unique_receivers: u64,
+}
+
+#[derive(Debug)]
+pub struct VolumeBucket {
+    pub bucket_start_block: u64,
+    pub transfer_count: u64,
+    pub total_value: U256,
+    pub unique_senders: u64,
+    pub unique_receivers: u64,
+}
+
+/// A volume bucket being filled, with the addresses seen so far
+struct BucketAccumulator {
+    bucket: VolumeBucket,
+    senders: HashSet<String>,
+    receivers: HashSet<String>,
+}
+
+impl BucketAccumulator {
+    fn new(bucket_start_block: u64) -> Self {
+        Self {
+            bucket: VolumeBucket {
+                bucket_start_block,
+                transfer_count: 0,
+                total_value: U256::ZERO,
+                unique_senders: 0,
+                unique_receivers: 0,
+            },
+            senders: HashSet::new(),
+            receivers: HashSet::new(),
+        }
+    }
+
+    fn finish(mut self) -> VolumeBucket {
+        self.bucket.unique_senders = self.senders.len() as u64;
+        self.bucket.unique_receivers = self.receivers.len() as u64;
+        self.bucket
+    }
+}
+
+/// Parameters `INSERT_TRANSFER` binds per row
+const TRANSFER_COLUMNS: usize = 9;
+
+/// Rows per `insert_batch_multi_row` statement. At 900 parameters this stays
+/// under SQLite's historical limit of 999 bound parameters.
+pub const MULTI_ROW_INSERT_ROWS: usize = 100;
+
+/// Text columns of a transfer being inserted. The buffers are reused for every
+/// row of a batch, so binding a row doesn't allocate.
+#[derive(Default)]
+struct TransferRow {
+    transaction_hash: String,
+    token_address: String,
+    from_address: String,
+    to_address: String,
+    block_hash: String,
+}
+
+impl TransferRow {
+    /// Run `INSERT_TRANSFER` for `transfer`, returning the number of rows inserted
+    fn insert(&mut self, stmt: &mut CachedStatement, transfer: &Transfer) -> Result<usize> {
+        self.bind(stmt, 0, transfer)?;
+        Ok(stmt.raw_execute()?)
+    }
+
+    /// Bind `transfer` to the `TRANSFER_COLUMNS` parameters after `offset`, in
+    /// `INSERT_TRANSFER` column order. SQLite copies bound text, so the buffers
+    /// can be refilled for the next row right away.
+    fn bind(
+        &mut self,
+        stmt: &mut CachedStatement,
+        offset: usize,
+        transfer: &Transfer,
+    ) -> Result<()> {
+        fill(&mut self.transaction_hash, &transfer.transaction_hash);
+        fill(&mut self.token_address, &transfer.token_address);
+        fill(&mut self.from_address, &transfer.from_address);
+        fill(&mut self.to_address, &transfer.to_address);
+        fill(&mut self.block_hash, &transfer.block_hash);
+
+        stmt.raw_bind_parameter(offset + 1, &self.transaction_hash)?;
+        stmt.raw_bind_parameter(offset + 2, transfer.log_index)?;
+        stmt.raw_bind_parameter(offset + 3, &self.token_address)?;
+        stmt.raw_bind_parameter(offset + 4, &self.from_address)?;
+        stmt.raw_bind_parameter(offset + 5, &self.to_address)?;
+        stmt.raw_bind_parameter(offset + 6, u256_to_blob(&transfer.value))?;
+        stmt.raw_bind_parameter(offset + 7, transfer.block_number)?;
+        stmt.raw_bind_parameter(offset + 8, &self.block_hash)?;
+        stmt.raw_bind_parameter(offset + 9, transfer.is_finalized)?;
+        Ok(())
+    }
+}
+
+/// Replace `buffer` with the lowercase 0x-hex form `addr_to_db_string` uses
+fn fill(buffer: &mut String, value: &impl std::fmt::Debug) {
+    buffer.clear();
+    write!(buffer, "{value:?}").expect("writing to a String can't fail");
+}
+
 #[derive(Debug)]
 pub struct TransferStats {
     pub total_transfers: usize,
@@ -304,3 +1394,242 @@ pub struct TransferStats {
     pub earliest_block: Option<u64>,
     pub latest_block: Option<u64>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::Database;
+    use crate::testutil::{
+        DataGenerator, fresh_database, holder, populate_with, remove_database, temp_database_path,
+        token_address,
+    };
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn database(name: &str) -> Database {
+        fresh_database(&temp_database_path(&format!("finality-{name}"))).unwrap()
+    }
+
+    fn cleanup(name: &str) {
+        remove_database(&temp_database_path(&format!("finality-{name}")));
+    }
+
+    /// An unfinalized transfer of `value` in `block`
+    fn transfer(block: u64, log_index: u64, from: Address, to: Address, value: u64) -> Transfer {
+        let generated = DataGenerator::new(block * 100 + log_index)
+            .with_start_block(block)
+            .next()
+            .unwrap();
+        Transfer {
+            log_index,
+            from_address: from,
+            to_address: to,
+            value: U256::from(value),
+            is_finalized: false,
+            ..generated
+        }
+    }
+
+    fn balance(db: &Database, index: u64) -> U256 {
+        BalanceRepository::new(&db.conn, &token_address())
+            .get_balance(&holder(index), true)
+            .unwrap()
+            .balance
+    }
+
+    fn finalized_count(db: &Database) -> u64 {
+        db.conn
+            .query_row(
+                "SELECT COUNT(*) FROM transfers WHERE is_finalized = 1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn a_finality_batch_rerun_after_a_crash_applies_balances_once() {
+        let db = database("crash");
+        let repo = TransferRepository::new(&db.conn, &token_address());
+        let transfers = [
+            transfer(5, 0, Address::ZERO, holder(1), 100),
+            transfer(6, 0, holder(1), holder(2), 30),
+        ];
+        repo.insert_batch(&transfers).unwrap();
+
+        // A run that applied the balances of blocks 1-10 and crashed before
+        // the transfers were marked finalized
+        let finalized: Vec<Transfer> = transfers
+            .iter()
+            .map(|t| Transfer {
+                is_finalized: true,
+                ..t.clone()
+            })
+            .collect();
+        BalanceRepository::new(&db.conn, &token_address())
+            .apply_transfers(&finalized)
+            .unwrap();
+        TokenRepository::new(&db.conn)
+            .raise_last_balance_applied_block(&token_address(), 10)
+            .unwrap();
+
+        // The restart finalizes them without applying them again, and so
+        // does a rerun of the same range before the cursor moved
+        for _ in 0..2 {
+            repo.process_finality_batch(&[], &[], 1, 10).unwrap();
+            assert_eq!(finalized_count(&db), 2);
+            assert_eq!(balance(&db, 1), U256::from(70));
+            assert_eq!(balance(&db, 2), U256::from(30));
+        }
+
+        // Past the watermark, balances are applied as usual
+        repo.insert_batch(&[transfer(12, 0, holder(2), holder(3), 5)])
+            .unwrap();
+        repo.process_finality_batch(&[], &[], 11, 20).unwrap();
+        assert_eq!(balance(&db, 2), U256::from(25));
+        assert_eq!(balance(&db, 3), U256::from(5));
+
+        drop(db);
+        cleanup("crash");
+    }
+
+    #[test]
+    fn a_reorged_finalized_block_is_reverted_before_its_replacement_is_applied() {
+        let db = database("reorg");
+        let repo = TransferRepository::new(&db.conn, &token_address());
+        let old = transfer(6, 0, holder(1), holder(2), 30);
+        repo.insert_batch(&[transfer(5, 0, Address::ZERO, holder(1), 100), old.clone()])
+            .unwrap();
+        repo.process_finality_batch(&[], &[], 1, 10).unwrap();
+        assert_eq!(balance(&db, 2), U256::from(30));
+
+        // Block 6 turns out to hold a transfer to holder 3 instead
+        let replacement = Transfer {
+            transaction_hash: B256::repeat_byte(0x66),
+            block_hash: B256::repeat_byte(0x06),
+            to_address: holder(3),
+            value: U256::from(20),
+            is_finalized: true,
+            ..old.clone()
+        };
+        let reorged = ReorgedBlock {
+            block_number: 6,
+            old_block_hash: Some(old.block_hash),
+            new_block_hash: Some(replacement.block_hash),
+        };
+        let (deleted, inserted, _) = repo
+            .process_finality_batch(&[reorged], &[replacement], 1, 10)
+            .unwrap();
+
+        assert_eq!((deleted, inserted), (1, 1));
+        assert_eq!(balance(&db, 1), U256::from(80));
+        assert_eq!(balance(&db, 2), U256::ZERO);
+        assert_eq!(balance(&db, 3), U256::from(20));
+
+        drop(db);
+        cleanup("reorg");
+    }
+
+    #[test]
+    fn rolling_back_a_token_that_was_never_indexed_is_not_found() {
+        let db = database("rollback-unknown");
+        let error = TransferRepository::new(&db.conn, &holder(1))
+            .rollback_to(10)
+            .unwrap_err();
+        assert!(matches!(error, IndexerError::NotFound(_)), "{error}");
+
+        drop(db);
+        cleanup("rollback-unknown");
+    }
+
+    #[test]
+    fn address_history_searches_the_composite_indexes() {
+        let path = temp_database_path("transfers-query-plan");
+        // Each address in a small share of the transfers, as on a real token;
+        // one in most of them is read faster by scanning in block order
+        let generator = DataGenerator::new(3).with_holders(1_000, 0.0);
+        let db = populate_with(&path, generator, 5_000).unwrap();
+        db.analyze().unwrap();
+
+        let plan: Vec<String> = db
+            .conn
+            .prepare(&format!(
+                "EXPLAIN QUERY PLAN {}",
+                TransferRepository::SELECT_ADDRESS_HISTORY
+            ))
+            .unwrap()
+            .query_map(
+                params![
+                    addr_to_db_string(&holder(500)),
+                    addr_to_db_string(&token_address()),
+                    false,
+                    100,
+                    0
+                ],
+                |row| row.get(3),
+            )
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let uses = |index: &str| plan.iter().any(|step| step.contains(index));
+        assert!(uses("idx_transfers_from_block"), "{plan:#?}");
+        assert!(uses("idx_transfers_to_block"), "{plan:#?}");
+        assert!(
+            !plan.iter().any(|step| step.starts_with("SCAN transfers")),
+            "{plan:#?}"
+        );
+
+        drop(db);
+        remove_database(&path);
+    }
+
+    #[test]
+    fn concurrent_inserts_and_finality_batches_never_hit_a_locked_database() {
+        let db = database("concurrent");
+        let writer = db.try_clone().unwrap();
+        let finalizer = db.try_clone().unwrap();
+        let inserted = &AtomicU64::new(0);
+
+        // Each thread owns its connection, as the insertion and finality
+        // workers do
+        std::thread::scope(|scope| {
+            let writing = scope.spawn(move || {
+                let repo = TransferRepository::new(&writer.conn, &token_address());
+                for batch in 0..50 {
+                    let transfers: Vec<Transfer> = (1..=10)
+                        .map(|i| {
+                            let block = batch * 10 + i;
+                            transfer(block, 0, Address::ZERO, holder(block % 10), 1)
+                        })
+                        .collect();
+                    repo.insert_batch(&transfers).unwrap();
+                    inserted.store(batch * 10 + 10, Ordering::Release);
+                }
+            });
+
+            let repo = TransferRepository::new(&finalizer.conn, &token_address());
+            let mut finalized = 0;
+            // Until the writer is done and everything it inserted is finalized
+            loop {
+                let done = writing.is_finished();
+                let to = inserted.load(Ordering::Acquire);
+                if to > finalized {
+                    repo.process_finality_batch(&[], &[], finalized + 1, to)
+                        .unwrap();
+                    finalized = to;
+                } else if done {
+                    break;
+                }
+            }
+            writing.join().unwrap();
+        });
+
+        assert_eq!(finalized_count(&db), 500);
+        for index in 0..10 {
+            assert_eq!(balance(&db, index), U256::from(50));
+        }
+
+        drop(db);
+        cleanup("concurrent");
+    }
+}
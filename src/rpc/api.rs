@@ -0,0 +1,194 @@
+use super::{LogsError, MethodStats, RpcClient};
+use crate::error::{IndexerError, Result};
+use alloy::rpc::types::{Header, Log};
+use alloy::sol_types::SolCall;
+use alloy_primitives::{Address, B256, Bytes};
+use std::future::Future;
+
+/// The chain access the scanner, the finality tracker and the deployment
+/// search need. [`RpcClient`] implements it over JSON-RPC providers; other
+/// implementations can wrap one, like [`RecordingRpc`](super::RecordingRpc),
+/// or stand in for it, like [`ReplayRpc`](super::ReplayRpc).
+pub trait RpcApi: Send + Sync {
+    fn get_latest_block(&self) -> impl Future<Output = Result<u64>> + Send;
+
+    fn get_finalized_block(&self) -> impl Future<Output = Result<u64>> + Send;
+
+    fn get_chain_id(&self) -> impl Future<Output = Result<u64>> + Send;
+
+    fn get_block_hash(&self, block_number: u64) -> impl Future<Output = Result<B256>> + Send;
+
+    /// Headers of the given blocks, in the same order
+    fn get_block_headers_batch(
+        &self,
+        block_numbers: &[u64],
+    ) -> impl Future<Output = Result<Vec<Header>>> + Send;
+
+    /// Logs with one of `topics` emitted by `addresses` in `from_block..=to_block`
+    /// in block order, how many times the range was split to fit a result
+    /// limit, and the URL of the provider that answered
+    fn get_logs_with_splits(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        addresses: &[Address],
+        topics: &[B256],
+    ) -> impl Future<Output = Result<(Vec<Log>, u32, &str), LogsError>> + Send;
+
+    fn get_code_at_block(
+        &self,
+        address: Address,
+        block_number: u64,
+    ) -> impl Future<Output = Result<Bytes>> + Send;
+
+    /// `eth_call` of `input` on `address` at the end of `block_number`, or at
+    /// the head. Fails when the call returns no data.
+    fn call(
+        &self,
+        address: Address,
+        input: Bytes,
+        block_number: Option<u64>,
+    ) -> impl Future<Output = Result<Bytes>> + Send;
+
+    /// Block a transaction was included in, None if it isn't known
+    fn get_transaction_block(&self, hash: B256)
+    -> impl Future<Output = Result<Option<u64>>> + Send;
+
+    /// Transaction that created the contract at `address`, None when it
+    /// can't be told
+    fn get_contract_creator(
+        &self,
+        address: Address,
+    ) -> impl Future<Output = Result<Option<B256>>> + Send;
+
+    /// Chain head several providers agree on, just the head with one
+    fn get_consensus_latest_block(&self) -> impl Future<Output = Result<u64>> + Send {
+        self.get_latest_block()
+    }
+
+    /// Chain id of each provider, by URL, so each one can be checked
+    fn get_provider_chain_ids(&self) -> impl Future<Output = Vec<(String, Result<u64>)>> + Send {
+        async move { vec![("rpc".to_string(), self.get_chain_id().await)] }
+    }
+
+    /// Resolves when a request can be sent without going over a rate limit
+    fn wait_for_capacity(&self) -> impl Future<Output = ()> + Send {
+        std::future::ready(())
+    }
+
+    /// Requests sent so far by method and provider, empty when nothing is
+    /// sent over JSON-RPC
+    fn stats_snapshot(&self) -> Vec<MethodStats> {
+        Vec::new()
+    }
+
+    fn call_contract<C: SolCall + Send>(
+        &self,
+        address: Address,
+        call: C,
+    ) -> impl Future<Output = Result<C::Return>> + Send
+    where
+        Self: Sized,
+    {
+        call_and_decode(self, address, call, None)
+    }
+
+    /// `call_contract` against the state at the end of `block_number`
+    fn call_contract_at_block<C: SolCall + Send>(
+        &self,
+        address: Address,
+        call: C,
+        block_number: u64,
+    ) -> impl Future<Output = Result<C::Return>> + Send
+    where
+        Self: Sized,
+    {
+        call_and_decode(self, address, call, Some(block_number))
+    }
+}
+
+async fn call_and_decode<A: RpcApi, C: SolCall + Send>(
+    api: &A,
+    address: Address,
+    call: C,
+    block_number: Option<u64>,
+) -> Result<C::Return> {
+    let input = call.abi_encode().into();
+    let output = api.call(address, input, block_number).await?;
+    C::abi_decode_returns(&output).map_err(|e| {
+        IndexerError::Other(anyhow::anyhow!("Failed to decode contract response: {}", e))
+    })
+}
+
+impl RpcApi for RpcClient {
+    async fn get_latest_block(&self) -> Result<u64> {
+        RpcClient::get_latest_block(self).await
+    }
+
+    async fn get_finalized_block(&self) -> Result<u64> {
+        RpcClient::get_finalized_block(self).await
+    }
+
+    async fn get_chain_id(&self) -> Result<u64> {
+        RpcClient::get_chain_id(self).await
+    }
+
+    async fn get_block_hash(&self, block_number: u64) -> Result<B256> {
+        RpcClient::get_block_hash(self, block_number).await
+    }
+
+    async fn get_block_headers_batch(&self, block_numbers: &[u64]) -> Result<Vec<Header>> {
+        RpcClient::get_block_headers_batch(self, block_numbers).await
+    }
+
+    async fn get_logs_with_splits(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        addresses: &[Address],
+        topics: &[B256],
+    ) -> Result<(Vec<Log>, u32, &str), LogsError> {
+        RpcClient::get_logs_with_splits(self, from_block, to_block, addresses, topics).await
+    }
+
+    async fn get_code_at_block(&self, address: Address, block_number: u64) -> Result<Bytes> {
+        RpcClient::get_code_at_block(self, address, block_number).await
+    }
+
+    async fn call(
+        &self,
+        address: Address,
+        input: Bytes,
+        block_number: Option<u64>,
+    ) -> Result<Bytes> {
+        RpcClient::call(self, address, input, block_number).await
+    }
+
+    async fn get_transaction_block(&self, hash: B256) -> Result<Option<u64>> {
+        RpcClient::get_transaction_block(self, hash).await
+    }
+
+    async fn get_contract_creator(&self, address: Address) -> Result<Option<B256>> {
+        RpcClient::get_contract_creator(self, address).await
+    }
+
+    async fn get_consensus_latest_block(&self) -> Result<u64> {
+        RpcClient::get_consensus_latest_block(self).await
+    }
+
+    async fn get_provider_chain_ids(&self) -> Vec<(String, Result<u64>)> {
+        RpcClient::get_provider_chain_ids(self)
+            .await
+            .into_iter()
+            .map(|(url, result)| (url.to_string(), result))
+            .collect()
+    }
+
+    async fn wait_for_capacity(&self) {
+        RpcClient::wait_for_capacity(self).await
+    }
+
+    fn stats_snapshot(&self) -> Vec<MethodStats> {
+        RpcClient::stats_snapshot(self)
+    }
+}
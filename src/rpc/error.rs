@@ -0,0 +1,311 @@
+use crate::error::{IndexerError, RpcError};
+use alloy::transports::{RpcError as AlloyRpcError, TransportError, TransportErrorKind};
+use regex::Regex;
+use std::fmt;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+static SUGGESTED_RANGE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"retry with the range (\d+)-(\d+)").expect("valid suggested range regex")
+});
+
+static RETRY_AFTER_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?i)(?:retry[-_ ]?after|backoff_seconds|try again in)"?\s*[:=]?\s*(\d+(?:\.\d+)?)\s*(ms|milliseconds?|s|secs?|seconds?)?"#,
+    )
+    .expect("valid retry-after regex")
+});
+
+/// Classification of a failed RPC request, used to decide how to retry it
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcErrorKind {
+    /// The provider is throttling us (HTTP 429, exhausted compute units, ...)
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// The response would exceed the provider's result-size limit. Retrying the
+    /// same request is pointless, the caller has to narrow the range.
+    TooManyResults,
+    /// The provider doesn't implement the method, e.g. a non-standard one
+    MethodNotFound,
+    Other,
+}
+
+impl RpcErrorKind {
+    pub fn classify(message: &str) -> Self {
+        let lower = message.to_lowercase();
+
+        if is_too_many_results(&lower) {
+            return RpcErrorKind::TooManyResults;
+        }
+
+        if is_method_not_found(&lower) {
+            return RpcErrorKind::MethodNotFound;
+        }
+
+        if is_rate_limit(&lower) {
+            return RpcErrorKind::RateLimited {
+                retry_after: parse_retry_after(message),
+            };
+        }
+
+        RpcErrorKind::Other
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        !matches!(
+            self,
+            RpcErrorKind::TooManyResults | RpcErrorKind::MethodNotFound
+        )
+    }
+}
+
+impl RpcError {
+    /// What the failure of an attempt was, from the alloy error it holds when
+    /// there is one, as providers word their rate limits in many ways also
+    /// from its message
+    pub fn classify(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<RpcError>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        let rate_limited = matches!(
+            RpcErrorKind::classify(&error.to_string()),
+            RpcErrorKind::RateLimited { .. }
+        );
+
+        match error.downcast_ref::<TransportError>() {
+            Some(AlloyRpcError::ErrorResp(payload)) if payload.code == 429 || rate_limited => {
+                RpcError::RateLimited(error)
+            }
+            Some(AlloyRpcError::ErrorResp(payload)) => RpcError::ErrorResponse {
+                code: payload.code,
+                message: payload.message.to_string(),
+            },
+            Some(AlloyRpcError::Transport(TransportErrorKind::HttpError(http)))
+                if http.is_rate_limit_err() || rate_limited =>
+            {
+                RpcError::RateLimited(error)
+            }
+            Some(
+                AlloyRpcError::NullResp
+                | AlloyRpcError::DeserError { .. }
+                | AlloyRpcError::Transport(TransportErrorKind::MissingBatchResponse(_)),
+            ) => RpcError::BadResponse(error),
+            _ if rate_limited => RpcError::RateLimited(error),
+            _ => RpcError::Transport(error),
+        }
+    }
+}
+
+/// Response-size errors across providers:
+/// - Alchemy: "Log response size exceeded ... exceeds max results, retry with the range X-Y"
+/// - Infura: "query returned more than 10000 results"
+/// - QuickNode and others: "response size should not be greater than ...", "too many logs"
+fn is_too_many_results(lower: &str) -> bool {
+    [
+        "exceeds max results",
+        "returned more than",
+        "too many results",
+        "too many logs",
+        "response size",
+        "result set too large",
+        "block range is too large",
+        "block range too large",
+        "block range limit exceeded",
+    ]
+    .iter()
+    .any(|pattern| lower.contains(pattern))
+}
+
+/// JSON-RPC error -32601 and the wording providers use when they don't expose
+/// a method: "the method ots_getContractCreator does not exist/is not available",
+/// "method not found", "unsupported method", ...
+fn is_method_not_found(lower: &str) -> bool {
+    [
+        "-32601",
+        "method not found",
+        "does not exist/is not available",
+        "unsupported method",
+        "method not supported",
+        "is not supported",
+        "not whitelisted",
+    ]
+    .iter()
+    .any(|pattern| lower.contains(pattern))
+}
+
+fn is_rate_limit(lower: &str) -> bool {
+    if lower.contains("429") || lower.contains("too many requests") || lower.contains("rate limit")
+    {
+        return true;
+    }
+
+    // "Your app has exceeded its compute units per second capacity", "daily request
+    // count exceeded", ... but not "block range limit exceeded"
+    lower.contains("exceeded")
+        && !lower.contains("range")
+        && [
+            "capacity",
+            "quota",
+            "limit",
+            "compute units",
+            "per second",
+            "request count",
+        ]
+        .iter()
+        .any(|hint| lower.contains(hint))
+}
+
+/// Extract a retry-after hint from an error message. Values without a unit are
+/// interpreted as seconds, matching the HTTP `Retry-After` header.
+pub fn parse_retry_after(message: &str) -> Option<Duration> {
+    let captures = RETRY_AFTER_RE.captures(message)?;
+    let value: f64 = captures.get(1)?.as_str().parse().ok()?;
+
+    let duration = match captures.get(2).map(|m| m.as_str().to_lowercase()) {
+        Some(unit) if unit.starts_with("ms") || unit.starts_with("milli") => {
+            Duration::from_secs_f64(value / 1000.0)
+        }
+        _ => Duration::from_secs_f64(value),
+    };
+
+    Some(duration)
+}
+
+/// Failure of an `eth_getLogs` request
+#[derive(Debug)]
+pub enum LogsError {
+    /// The provider refused to return this many logs. Some providers (Alchemy)
+    /// suggest a range that fits, others leave the split to us.
+    TooManyResults {
+        from_block: u64,
+        to_block: u64,
+        suggested: Option<(u64, u64)>,
+        source: RpcError,
+    },
+    Other(IndexerError),
+}
+
+impl LogsError {
+    pub fn from_response(error: IndexerError, from_block: u64, to_block: u64) -> Self {
+        match error {
+            IndexerError::Rpc(source)
+                if RpcErrorKind::classify(&source.to_string()) == RpcErrorKind::TooManyResults =>
+            {
+                LogsError::TooManyResults {
+                    from_block,
+                    to_block,
+                    suggested: parse_suggested_range(&source.to_string()),
+                    source,
+                }
+            }
+            error => LogsError::Other(error),
+        }
+    }
+}
+
+impl fmt::Display for LogsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogsError::TooManyResults {
+                from_block,
+                to_block,
+                suggested: Some((from, to)),
+                ..
+            } => write!(
+                f,
+                "Too many results for blocks {from_block}-{to_block} (provider suggested {from}-{to})"
+            ),
+            LogsError::TooManyResults {
+                from_block,
+                to_block,
+                suggested: None,
+                ..
+            } => write!(f, "Too many results for blocks {from_block}-{to_block}"),
+            LogsError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// A range that can't be split any further is a request that can't succeed
+impl From<LogsError> for IndexerError {
+    fn from(error: LogsError) -> Self {
+        match error {
+            LogsError::Other(e) => e,
+            LogsError::TooManyResults { source, .. } => IndexerError::Rpc(source),
+        }
+    }
+}
+
+impl std::error::Error for LogsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LogsError::Other(e) => Some(e),
+            LogsError::TooManyResults { source, .. } => Some(source),
+        }
+    }
+}
+
+/// Parse Alchemy's "retry with the range X-Y" hint
+pub fn parse_suggested_range(message: &str) -> Option<(u64, u64)> {
+    let captures = SUGGESTED_RANGE_RE.captures(message)?;
+
+    let from = captures.get(1)?.as_str().parse().ok()?;
+    let to = captures.get(2)?.as_str().parse().ok()?;
+
+    Some((from, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::rpc::json_rpc::ErrorPayload;
+    use std::borrow::Cow;
+
+    fn error_response(code: i64, message: &'static str) -> anyhow::Error {
+        let error: TransportError = AlloyRpcError::ErrorResp(ErrorPayload {
+            code,
+            message: Cow::Borrowed(message),
+            data: None,
+        });
+        error.into()
+    }
+
+    #[test]
+    fn alloy_errors_are_classified_by_kind() {
+        assert!(matches!(
+            RpcError::classify(TransportErrorKind::http_error(429, String::new()).into()),
+            RpcError::RateLimited(_)
+        ));
+        assert!(matches!(
+            RpcError::classify(error_response(-32005, "daily request count exceeded")),
+            RpcError::RateLimited(_)
+        ));
+        assert!(matches!(
+            RpcError::classify(error_response(-32601, "the method eth_foo does not exist")),
+            RpcError::ErrorResponse { code: -32601, message } if message.contains("eth_foo")
+        ));
+        assert!(matches!(
+            RpcError::classify(TransportErrorKind::http_error(503, "down".to_string()).into()),
+            RpcError::Transport(_)
+        ));
+        assert!(matches!(
+            RpcError::classify(AlloyRpcError::<TransportErrorKind>::NullResp.into()),
+            RpcError::BadResponse(_)
+        ));
+    }
+
+    #[test]
+    fn a_classified_error_passed_through_anyhow_keeps_its_kind() {
+        let error = anyhow::Error::from(RpcError::Timeout(Duration::from_secs(3)));
+        assert!(matches!(
+            RpcError::classify(error),
+            RpcError::Timeout(timeout) if timeout == Duration::from_secs(3)
+        ));
+
+        // Without an alloy error only the message is left to go by
+        let error = anyhow::anyhow!("connection refused");
+        assert!(matches!(RpcError::classify(error), RpcError::Transport(_)));
+    }
+}
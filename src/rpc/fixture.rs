@@ -0,0 +1,458 @@
+use super::{LogsError, MethodStats, RpcApi};
+use crate::error::{IndexerError, Result};
+use alloy::rpc::types::{Header, Log};
+use alloy_primitives::{Address, B256, Bytes, keccak256};
+use anyhow::{Context, anyhow};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Fixture file holding the last response to one request
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedResponse {
+    key: String,
+    response: Value,
+}
+
+/// Fixture file holding the logs of one fetched block range
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedLogs {
+    filter: String,
+    from_block: u64,
+    to_block: u64,
+    /// When the range was fetched, in nanoseconds. A later fetch of the same
+    /// blocks, e.g. after a reorg, wins.
+    sequence: u64,
+    logs: Vec<Log>,
+}
+
+/// Responses loaded from a fixture directory
+#[derive(Debug, Default)]
+struct Fixture {
+    /// Last successful response per request, keyed by method and parameters
+    responses: BTreeMap<String, Value>,
+    /// Fetched log ranges per address and topic filter
+    logs: BTreeMap<String, Vec<LogRange>>,
+}
+
+#[derive(Debug)]
+struct LogRange {
+    from_block: u64,
+    to_block: u64,
+    logs: Vec<Log>,
+}
+
+impl Fixture {
+    /// Ranges have to be added in the order they were fetched
+    fn add_logs(&mut self, recorded: RecordedLogs) {
+        let RecordedLogs {
+            filter,
+            from_block,
+            to_block,
+            logs,
+            ..
+        } = recorded;
+        let ranges = self.logs.entry(filter).or_default();
+        for range in ranges.iter_mut() {
+            range.logs.retain(|log| {
+                !log.block_number
+                    .is_some_and(|block| (from_block..=to_block).contains(&block))
+            });
+        }
+        ranges.push(LogRange {
+            from_block,
+            to_block,
+            logs,
+        });
+    }
+}
+
+fn key(method: &str, params: &impl Serialize) -> String {
+    let params = serde_json::to_string(params).expect("RPC parameters serialize to JSON");
+    format!("{method} {params}")
+}
+
+fn filter_key(addresses: &[Address], topics: &[B256]) -> String {
+    key("get_logs", &(addresses, topics))
+}
+
+/// First 16 hex digits of the key's keccak256, to name its fixture file
+fn key_hash(key: &str) -> String {
+    keccak256(key.as_bytes()).to_string()[2..18].to_string()
+}
+
+fn write_fixture(path: &Path, value: &impl Serialize) -> anyhow::Result<()> {
+    // Written aside and renamed so an interrupted recording leaves no
+    // truncated file behind
+    let partial = path.with_extension("partial");
+    let mut encoder = GzEncoder::new(
+        BufWriter::new(File::create(&partial)?),
+        Compression::default(),
+    );
+    serde_json::to_writer(&mut encoder, value)?;
+    encoder.finish()?.flush()?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+fn read_fixture<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let decoder = GzDecoder::new(BufReader::new(File::open(path)?));
+    Ok(serde_json::from_reader(decoder)?)
+}
+
+/// An [`RpcApi`] that forwards to another one and writes every successful
+/// response to a fixture directory a [`ReplayRpc`] can serve. Each request
+/// is a gzipped JSON file named after its method and a hash of its
+/// parameters, written as soon as the response arrives.
+#[derive(Clone)]
+pub struct RecordingRpc<C> {
+    inner: C,
+    dir: Arc<PathBuf>,
+}
+
+impl<C: RpcApi> RecordingRpc<C> {
+    /// Record into `dir`, created if missing. Fixtures already there are
+    /// kept, a request recorded again replaces its file.
+    pub fn new(inner: C, dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create fixture directory {}", dir.display()))
+            .map_err(IndexerError::Other)?;
+        Ok(Self {
+            inner,
+            dir: Arc::new(dir),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn write(&self, name: String, value: &impl Serialize) {
+        let path = self.dir.join(name);
+        if let Err(e) = write_fixture(&path, value) {
+            warn!("Failed to record RPC fixture {}: {e:#}", path.display());
+        }
+    }
+
+    fn record<T: Serialize>(&self, key: String, value: &T) {
+        let method = key.split(' ').next().unwrap_or_default();
+        let name = format!("{method}-{}.json.gz", key_hash(&key));
+        let response = serde_json::to_value(value).expect("RPC responses serialize to JSON");
+        self.write(name, &RecordedResponse { key, response });
+    }
+
+    /// Record the headers of a fetched range and the block before it. The
+    /// scanner checks the hashes at the edges of each range above its own
+    /// finality cursor, which can be behind the chain's, and a replay may
+    /// split the range differently.
+    async fn record_range_headers(&self, from_block: u64, to_block: u64) {
+        let first = from_block.saturating_sub(1);
+        let blocks: Vec<u64> = (first..=to_block).collect();
+        if let Err(e) = self.get_block_headers_batch(&blocks).await {
+            warn!("Failed to record headers of blocks {first}-{to_block}: {e:#}");
+        }
+    }
+
+    fn record_logs(&self, filter: String, from_block: u64, to_block: u64, logs: &[Log]) {
+        let name = format!(
+            "get_logs-{}-{from_block}-{to_block}.json.gz",
+            key_hash(&filter)
+        );
+        let sequence = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default();
+        self.write(
+            name,
+            &RecordedLogs {
+                filter,
+                from_block,
+                to_block,
+                sequence,
+                logs: logs.to_vec(),
+            },
+        );
+    }
+}
+
+impl<C: RpcApi> RpcApi for RecordingRpc<C> {
+    async fn get_latest_block(&self) -> Result<u64> {
+        let block = self.inner.get_latest_block().await?;
+        self.record("get_latest_block".to_string(), &block);
+        Ok(block)
+    }
+
+    async fn get_finalized_block(&self) -> Result<u64> {
+        let block = self.inner.get_finalized_block().await?;
+        self.record("get_finalized_block".to_string(), &block);
+        Ok(block)
+    }
+
+    async fn get_chain_id(&self) -> Result<u64> {
+        let chain_id = self.inner.get_chain_id().await?;
+        self.record("get_chain_id".to_string(), &chain_id);
+        Ok(chain_id)
+    }
+
+    async fn get_block_hash(&self, block_number: u64) -> Result<B256> {
+        let hash = self.inner.get_block_hash(block_number).await?;
+        self.record(key("get_block_hash", &block_number), &hash);
+        Ok(hash)
+    }
+
+    async fn get_block_headers_batch(&self, block_numbers: &[u64]) -> Result<Vec<Header>> {
+        let headers = self.inner.get_block_headers_batch(block_numbers).await?;
+        for (block_number, header) in block_numbers.iter().zip(&headers) {
+            self.record(key("get_block_header", block_number), header);
+        }
+        Ok(headers)
+    }
+
+    async fn get_logs_with_splits(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        addresses: &[Address],
+        topics: &[B256],
+    ) -> Result<(Vec<Log>, u32, &str), LogsError> {
+        let (logs, splits, url) = self
+            .inner
+            .get_logs_with_splits(from_block, to_block, addresses, topics)
+            .await?;
+        self.record_logs(filter_key(addresses, topics), from_block, to_block, &logs);
+        self.record_range_headers(from_block, to_block).await;
+        Ok((logs, splits, url))
+    }
+
+    async fn get_code_at_block(&self, address: Address, block_number: u64) -> Result<Bytes> {
+        let code = self.inner.get_code_at_block(address, block_number).await?;
+        self.record(key("get_code_at_block", &(address, block_number)), &code);
+        Ok(code)
+    }
+
+    async fn call(
+        &self,
+        address: Address,
+        input: Bytes,
+        block_number: Option<u64>,
+    ) -> Result<Bytes> {
+        let request = key("call", &(address, &input, block_number));
+        let output = self.inner.call(address, input, block_number).await?;
+        self.record(request, &output);
+        Ok(output)
+    }
+
+    async fn get_transaction_block(&self, hash: B256) -> Result<Option<u64>> {
+        let block = self.inner.get_transaction_block(hash).await?;
+        self.record(key("get_transaction_block", &hash), &block);
+        Ok(block)
+    }
+
+    async fn get_contract_creator(&self, address: Address) -> Result<Option<B256>> {
+        let creator = self.inner.get_contract_creator(address).await?;
+        self.record(key("get_contract_creator", &address), &creator);
+        Ok(creator)
+    }
+
+    async fn get_consensus_latest_block(&self) -> Result<u64> {
+        let block = self.inner.get_consensus_latest_block().await?;
+        self.record("get_latest_block".to_string(), &block);
+        Ok(block)
+    }
+
+    async fn get_provider_chain_ids(&self) -> Vec<(String, Result<u64>)> {
+        let chain_ids = self.inner.get_provider_chain_ids().await;
+        if let Some(chain_id) = chain_ids.iter().find_map(|(_, r)| r.as_ref().ok()) {
+            self.record("get_chain_id".to_string(), chain_id);
+        }
+        chain_ids
+    }
+
+    async fn wait_for_capacity(&self) {
+        self.inner.wait_for_capacity().await
+    }
+
+    fn stats_snapshot(&self) -> Vec<MethodStats> {
+        self.inner.stats_snapshot()
+    }
+}
+
+/// An [`RpcApi`] serving the responses a [`RecordingRpc`] wrote, without
+/// any network access. Requests that weren't recorded fail; log ranges are
+/// served as long as earlier fetches covered them, however they were split.
+#[derive(Clone)]
+pub struct ReplayRpc {
+    fixture: Arc<Fixture>,
+}
+
+impl ReplayRpc {
+    /// Load every fixture in `dir`
+    pub fn load(dir: &Path) -> Result<Self> {
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read fixture directory {}", dir.display()))
+            .map_err(IndexerError::Other)?;
+
+        let mut fixture = Fixture::default();
+        let mut log_ranges = Vec::new();
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!("Failed to read fixture directory {}", dir.display()))
+                .map_err(IndexerError::Other)?
+                .path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if !name.ends_with(".json.gz") {
+                continue;
+            }
+            let invalid = || format!("Invalid RPC fixture {}", path.display());
+            if name.starts_with("get_logs-") {
+                let recorded = read_fixture::<RecordedLogs>(&path)
+                    .with_context(invalid)
+                    .map_err(IndexerError::Other)?;
+                log_ranges.push(recorded);
+            } else {
+                let recorded: RecordedResponse = read_fixture(&path)
+                    .with_context(invalid)
+                    .map_err(IndexerError::Other)?;
+                fixture.responses.insert(recorded.key, recorded.response);
+            }
+        }
+        log_ranges.sort_by_key(|range| range.sequence);
+        for range in log_ranges {
+            fixture.add_logs(range);
+        }
+
+        Ok(Self {
+            fixture: Arc::new(fixture),
+        })
+    }
+
+    fn response<T: DeserializeOwned>(&self, key: &str) -> Result<T> {
+        let value = self
+            .fixture
+            .responses
+            .get(key)
+            .ok_or_else(|| IndexerError::NotFound(format!("No recorded response for {key}")))?;
+        T::deserialize(value)
+            .with_context(|| format!("Invalid recorded response for {key}"))
+            .map_err(IndexerError::Other)
+    }
+
+    fn logs(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        addresses: &[Address],
+        topics: &[B256],
+    ) -> anyhow::Result<Vec<Log>> {
+        let missing = || anyhow!("No recorded logs for blocks {from_block}-{to_block}");
+        let ranges = self
+            .fixture
+            .logs
+            .get(&filter_key(addresses, topics))
+            .ok_or_else(missing)?;
+
+        let mut bounds: Vec<_> = ranges.iter().map(|r| (r.from_block, r.to_block)).collect();
+        bounds.sort_unstable();
+        let mut next = from_block;
+        for (from, to) in bounds {
+            if from > next {
+                break;
+            }
+            next = next.max(to.saturating_add(1));
+        }
+        if next <= to_block {
+            return Err(missing());
+        }
+
+        let mut seen = HashSet::new();
+        let mut logs: Vec<Log> = ranges
+            .iter()
+            .flat_map(|range| &range.logs)
+            .filter(|log| {
+                log.block_number
+                    .is_some_and(|block| (from_block..=to_block).contains(&block))
+            })
+            .filter(|log| seen.insert((log.transaction_hash, log.log_index)))
+            .cloned()
+            .collect();
+        logs.sort_by_key(|log| (log.block_number, log.log_index));
+        Ok(logs)
+    }
+}
+
+impl RpcApi for ReplayRpc {
+    async fn get_latest_block(&self) -> Result<u64> {
+        self.response("get_latest_block")
+    }
+
+    async fn get_finalized_block(&self) -> Result<u64> {
+        self.response("get_finalized_block")
+    }
+
+    async fn get_chain_id(&self) -> Result<u64> {
+        self.response("get_chain_id")
+    }
+
+    async fn get_block_hash(&self, block_number: u64) -> Result<B256> {
+        self.response(&key("get_block_hash", &block_number))
+            .or_else(|e| {
+                self.response::<Header>(&key("get_block_header", &block_number))
+                    .map(|header| header.hash)
+                    .map_err(|_| e)
+            })
+    }
+
+    async fn get_block_headers_batch(&self, block_numbers: &[u64]) -> Result<Vec<Header>> {
+        block_numbers
+            .iter()
+            .map(|block_number| self.response(&key("get_block_header", block_number)))
+            .collect()
+    }
+
+    async fn get_logs_with_splits(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        addresses: &[Address],
+        topics: &[B256],
+    ) -> Result<(Vec<Log>, u32, &str), LogsError> {
+        let logs = self
+            .logs(from_block, to_block, addresses, topics)
+            .map_err(|e| LogsError::Other(IndexerError::Other(e)))?;
+        Ok((logs, 0, "replay"))
+    }
+
+    async fn get_code_at_block(&self, address: Address, block_number: u64) -> Result<Bytes> {
+        self.response(&key("get_code_at_block", &(address, block_number)))
+    }
+
+    async fn call(
+        &self,
+        address: Address,
+        input: Bytes,
+        block_number: Option<u64>,
+    ) -> Result<Bytes> {
+        self.response(&key("call", &(address, &input, block_number)))
+    }
+
+    async fn get_transaction_block(&self, hash: B256) -> Result<Option<u64>> {
+        self.response(&key("get_transaction_block", &hash))
+    }
+
+    async fn get_contract_creator(&self, address: Address) -> Result<Option<B256>> {
+        self.response(&key("get_contract_creator", &address))
+    }
+}
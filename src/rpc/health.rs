@@ -0,0 +1,282 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Weight of the newest sample in the rolling latency average
+const LATENCY_EWMA_ALPHA: f64 = 0.2;
+
+#[derive(Debug, Clone, Default)]
+pub struct ProviderStats {
+    pub total_requests: u64,
+    pub total_failures: u64,
+    pub consecutive_failures: u32,
+    pub last_error_at: Option<Instant>,
+    pub avg_latency: Option<Duration>,
+    pub quarantined_until: Option<Instant>,
+    pub rate_limit_hits: u64,
+    pub rate_limited_until: Option<Instant>,
+    pub head_block: Option<u64>,
+    pub is_lagging: bool,
+}
+
+impl ProviderStats {
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined_until.is_some()
+    }
+
+    pub fn is_rate_limited(&self, now: Instant) -> bool {
+        self.rate_limited_until.is_some_and(|until| until > now)
+    }
+
+    fn record_latency(&mut self, latency: Duration) {
+        self.avg_latency = Some(match self.avg_latency {
+            Some(avg) => {
+                avg.mul_f64(1.0 - LATENCY_EWMA_ALPHA) + latency.mul_f64(LATENCY_EWMA_ALPHA)
+            }
+            None => latency,
+        });
+    }
+}
+
+/// Tracks per-provider health and quarantines providers that keep failing.
+///
+/// A provider is quarantined after `failure_threshold` consecutive failures and
+/// stays out of rotation until a successful request (usually the background
+/// half-open probe) re-admits it.
+pub struct ProviderHealth {
+    stats: Mutex<Vec<ProviderStats>>,
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+impl ProviderHealth {
+    pub fn new(provider_count: usize, failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            stats: Mutex::new(vec![ProviderStats::default(); provider_count]),
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+        }
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    pub fn failure_threshold(&self) -> u32 {
+        self.failure_threshold
+    }
+
+    /// Record a successful request. Returns true if the provider was quarantined
+    /// and has now been re-admitted.
+    pub fn record_success(&self, index: usize, latency: Duration) -> bool {
+        let mut stats = self.stats.lock().unwrap();
+        let entry = &mut stats[index];
+        entry.total_requests += 1;
+        entry.consecutive_failures = 0;
+        entry.record_latency(latency);
+        entry.quarantined_until.take().is_some()
+    }
+
+    /// Record a failed request. Returns true if this failure pushed the provider
+    /// into quarantine.
+    pub fn record_failure(&self, index: usize) -> bool {
+        let now = Instant::now();
+        let mut stats = self.stats.lock().unwrap();
+        let entry = &mut stats[index];
+        entry.total_requests += 1;
+        entry.total_failures += 1;
+        entry.consecutive_failures = entry.consecutive_failures.saturating_add(1);
+        entry.last_error_at = Some(now);
+
+        if entry.is_quarantined() {
+            // Failed half-open probe, extend the cool-down
+            entry.quarantined_until = Some(now + self.cooldown);
+            false
+        } else if entry.consecutive_failures >= self.failure_threshold {
+            entry.quarantined_until = Some(now + self.cooldown);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Record a rate-limit response. The provider is skipped until `until` but
+    /// this does not count towards quarantine since the provider is healthy.
+    /// Returns the total number of rate-limit hits for the provider.
+    pub fn record_rate_limit(&self, index: usize, until: Instant) -> u64 {
+        let mut stats = self.stats.lock().unwrap();
+        let entry = &mut stats[index];
+        entry.total_requests += 1;
+        entry.rate_limit_hits += 1;
+        entry.rate_limited_until = Some(entry.rate_limited_until.map_or(until, |u| u.max(until)));
+        entry.rate_limit_hits
+    }
+
+    pub fn is_available(&self, index: usize) -> bool {
+        let stats = self.stats.lock().unwrap();
+        !stats[index].is_quarantined() && !stats[index].is_rate_limited(Instant::now())
+    }
+
+    pub fn any_available(&self) -> bool {
+        let now = Instant::now();
+        self.stats
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|s| !s.is_quarantined() && !s.is_rate_limited(now))
+    }
+
+    /// Time until the earliest rate-limit deadline among non-quarantined providers
+    pub fn next_rate_limit_expiry(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.stats
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| !s.is_quarantined())
+            .filter_map(|s| s.rate_limited_until)
+            .min()
+            .map(|until| until.saturating_duration_since(now))
+    }
+
+    /// Quarantined providers whose cool-down has elapsed and are due for a probe
+    pub fn probe_candidates(&self) -> Vec<usize> {
+        let now = Instant::now();
+        self.stats
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.quarantined_until.is_some_and(|until| until <= now))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn quarantined(&self) -> Vec<usize> {
+        self.stats
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.is_quarantined())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn record_head(&self, index: usize, head: u64) {
+        self.stats.lock().unwrap()[index].head_block = Some(head);
+    }
+
+    /// Flag a provider as lagging (or caught up). Returns true if the state changed.
+    pub fn set_lagging(&self, index: usize, lagging: bool) -> bool {
+        let mut stats = self.stats.lock().unwrap();
+        let changed = stats[index].is_lagging != lagging;
+        stats[index].is_lagging = lagging;
+        changed
+    }
+
+    /// Whether the provider is known to have seen `block`. Providers whose head
+    /// hasn't been observed yet get the benefit of the doubt.
+    pub fn has_block(&self, index: usize, block: u64) -> bool {
+        self.stats.lock().unwrap()[index]
+            .head_block
+            .is_none_or(|head| head >= block)
+    }
+
+    /// The highest head reached by at least half of the non-quarantined
+    /// providers (the upper median), so a single provider racing ahead or
+    /// falling behind doesn't move it
+    pub fn consensus_head(&self) -> Option<u64> {
+        let mut heads: Vec<u64> = self
+            .stats
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| !s.is_quarantined())
+            .filter_map(|s| s.head_block)
+            .collect();
+
+        if heads.is_empty() {
+            return None;
+        }
+
+        heads.sort_unstable_by(|a, b| b.cmp(a));
+        Some(heads[(heads.len() - 1) / 2])
+    }
+
+    pub fn snapshot(&self) -> Vec<ProviderStats> {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn consecutive_failures_quarantine_until_a_probe_succeeds() {
+        let health = ProviderHealth::new(2, 3, COOLDOWN);
+
+        assert!(!health.record_failure(0));
+        assert!(!health.record_failure(0));
+        // A success in between starts the count over
+        assert!(!health.record_success(0, Duration::from_millis(10)));
+        assert!(!health.record_failure(0));
+        assert!(!health.record_failure(0));
+        assert!(health.is_available(0));
+
+        assert!(health.record_failure(0));
+        assert!(!health.is_available(0));
+        assert!(health.is_available(1));
+        assert!(health.any_available());
+        assert_eq!(health.quarantined(), vec![0]);
+        // Skipped, and not probed before the cool-down is over
+        assert!(health.probe_candidates().is_empty());
+        assert_eq!(health.consensus_head(), None);
+
+        std::thread::sleep(COOLDOWN);
+        assert_eq!(health.probe_candidates(), vec![0]);
+        // Still out of rotation until the probe goes through
+        assert!(!health.is_available(0));
+
+        assert!(health.record_success(0, Duration::from_millis(10)));
+        assert!(health.is_available(0));
+        assert!(health.quarantined().is_empty());
+        assert_eq!(health.snapshot()[0].consecutive_failures, 0);
+        assert_eq!(health.snapshot()[0].total_failures, 5);
+    }
+
+    #[test]
+    fn a_failed_probe_extends_the_quarantine() {
+        let health = ProviderHealth::new(1, 1, COOLDOWN);
+        assert!(health.record_failure(0));
+        assert!(!health.any_available());
+
+        std::thread::sleep(COOLDOWN);
+        assert_eq!(health.probe_candidates(), vec![0]);
+        // Not newly quarantined, but due again only after another cool-down
+        assert!(!health.record_failure(0));
+        assert!(health.probe_candidates().is_empty());
+        assert!(!health.is_available(0));
+
+        std::thread::sleep(COOLDOWN);
+        assert_eq!(health.probe_candidates(), vec![0]);
+        assert!(health.record_success(0, Duration::from_millis(5)));
+        assert!(health.any_available());
+    }
+
+    #[test]
+    fn rate_limits_skip_a_provider_without_quarantining_it() {
+        let health = ProviderHealth::new(2, 1, COOLDOWN);
+        let until = Instant::now() + COOLDOWN;
+        assert_eq!(health.record_rate_limit(1, until), 1);
+
+        assert!(!health.is_available(1));
+        assert!(health.quarantined().is_empty());
+        assert!(health.next_rate_limit_expiry().unwrap() <= COOLDOWN);
+
+        std::thread::sleep(COOLDOWN);
+        assert!(health.is_available(1));
+    }
+}
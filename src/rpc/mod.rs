@@ -0,0 +1,1012 @@
+use crate::config::{Config, RpcHeader};
+use crate::error::{IndexerError, Result, RpcError};
+use alloy::providers::fillers::FillProvider;
+use alloy::providers::{IpcConnect, Provider, ProviderBuilder, WsConnect};
+use alloy::rpc::client::BatchRequest;
+use alloy::rpc::json_rpc::{RpcRecv, RpcSend};
+use alloy::rpc::types::{Block, BlockNumberOrTag, Filter, Header, Log};
+use alloy::transports::Authorization;
+use alloy::transports::http::reqwest::{
+    self,
+    header::{HeaderMap, HeaderName, HeaderValue},
+};
+use alloy_primitives::{Address, B256, Bytes};
+use anyhow::Context;
+use futures::future::join_all;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, timeout};
+use tokio_retry::strategy::{ExponentialBackoff, jitter};
+use tracing::{Instrument, debug, info, warn};
+
+pub mod api;
+pub mod error;
+pub mod fixture;
+pub mod health;
+pub mod rate_limit;
+pub mod stats;
+pub mod transport;
+
+pub use api::RpcApi;
+pub use error::{LogsError, RpcErrorKind};
+pub use fixture::{RecordingRpc, ReplayRpc};
+pub use health::{ProviderHealth, ProviderStats};
+pub use rate_limit::TokenBucket;
+pub use stats::{MethodStats, RequestCounters, RpcStats, format_rpc_stats};
+pub use transport::Transport;
+
+/// Back-off applied to a rate-limited provider when it gives no retry-after hint
+const DEFAULT_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound on a single wait when every provider is rate limited
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(30);
+
+/// Reconnection attempts of a dropped WebSocket before its requests fail
+const WS_MAX_RECONNECTS: u32 = 10;
+
+/// Pause between WebSocket reconnection attempts
+const WS_RECONNECT_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Calls per JSON-RPC batch when fetching block headers
+const HEADER_BATCH_SIZE: usize = 50;
+
+/// Name header batches are counted under in the request statistics, each
+/// call of a batch as one request
+const HEADER_BATCH_METHOD: &str = "eth_getBlockByNumber (batch)";
+
+type AlloyFullProvider = FillProvider<
+    alloy::providers::fillers::JoinFill<
+        alloy::providers::Identity,
+        alloy::providers::fillers::JoinFill<
+            alloy::providers::fillers::GasFiller,
+            alloy::providers::fillers::JoinFill<
+                alloy::providers::fillers::BlobGasFiller,
+                alloy::providers::fillers::JoinFill<
+                    alloy::providers::fillers::NonceFiller,
+                    alloy::providers::fillers::ChainIdFiller,
+                >,
+            >,
+        >,
+    >,
+    alloy::providers::RootProvider,
+>;
+
+#[derive(Clone)]
+pub struct RpcClient {
+    providers: Vec<AlloyFullProvider>,
+    urls: Vec<String>,
+    /// Round-robin position for picking the provider of the next request
+    next_provider: Arc<AtomicUsize>,
+    health: Arc<ProviderHealth>,
+    /// Requests sent by method and provider
+    stats: Arc<RpcStats>,
+    /// Requests-per-second limit of each provider
+    buckets: Arc<Vec<TokenBucket>>,
+    max_retries: usize,
+    retry_backoff_ms: u64,
+    retry_max_delay: Duration,
+    /// For `eth_getLogs` and header batches
+    request_timeout: Duration,
+    /// For single-value requests
+    light_request_timeout: Duration,
+    max_head_lag: u64,
+}
+
+impl RpcClient {
+    /// Build a provider per URL. WebSocket and IPC providers connect here, so
+    /// an unreachable one fails construction rather than every later request.
+    pub async fn new(rpc_urls: &[String], config: &Config) -> Result<Self> {
+        if rpc_urls.is_empty() {
+            return Err(IndexerError::Other(anyhow::anyhow!(
+                "At least one RPC URL must be provided"
+            )));
+        }
+
+        let mut providers = Vec::new();
+        for url in rpc_urls {
+            let transport =
+                Transport::parse(url).map_err(|e| IndexerError::Other(anyhow::anyhow!(e)))?;
+            let headers = config.rpc_headers.get(url).map(Vec::as_slice);
+            let provider = connect(&transport, headers.unwrap_or_default())
+                .await
+                .with_context(|| format!("Failed to connect to {} over {}", url, transport))
+                .map_err(|e| IndexerError::Rpc(RpcError::Transport(e)))?;
+            debug!("Connected to {} over {}", url, transport);
+            providers.push(provider);
+        }
+
+        let health = ProviderHealth::new(
+            providers.len(),
+            config.provider_failure_threshold,
+            Duration::from_secs(config.provider_quarantine_secs),
+        );
+
+        let buckets = rpc_urls
+            .iter()
+            .map(|url| {
+                let rps = config
+                    .rpc_rate_limits
+                    .get(url)
+                    .copied()
+                    .unwrap_or(config.rpc_requests_per_second);
+                TokenBucket::new(rps)
+            })
+            .collect();
+
+        Ok(RpcClient {
+            providers,
+            urls: rpc_urls.to_vec(),
+            next_provider: Arc::new(AtomicUsize::new(0)),
+            health: Arc::new(health),
+            stats: Arc::new(RpcStats::default()),
+            buckets: Arc::new(buckets),
+            max_retries: config.rpc_max_retries,
+            retry_backoff_ms: config.rpc_retry_backoff_ms,
+            retry_max_delay: Duration::from_millis(config.rpc_retry_max_delay_ms),
+            request_timeout: Duration::from_secs(config.request_timeout_secs),
+            light_request_timeout: Duration::from_secs(config.light_request_timeout_secs),
+            max_head_lag: config.provider_max_lag_blocks,
+        })
+    }
+
+    /// Pick the provider for a new request, round-robin. The request keeps it
+    /// for every attempt unless an attempt fails, so concurrent requests never
+    /// move each other to another provider.
+    fn pick_provider(&self, min_block: Option<u64>) -> usize {
+        let start = self.next_provider.fetch_add(1, Ordering::Relaxed) % self.providers.len();
+        self.usable_from(start, min_block)
+    }
+
+    /// Provider for a request's next attempt after its attempt on `index` failed
+    fn next_provider_after(&self, index: usize, min_block: Option<u64>) -> usize {
+        let len = self.providers.len();
+        let next = self.usable_from((index + 1) % len, min_block);
+
+        if len > 1 {
+            let quarantined = self.quarantined_urls();
+            if quarantined.is_empty() {
+                debug!("Rotating to RPC provider #{}", next);
+            } else {
+                debug!(
+                    "Rotating to RPC provider #{} ({} of {} quarantined: {:?})",
+                    next,
+                    quarantined.len(),
+                    len,
+                    quarantined
+                );
+            }
+        }
+        next
+    }
+
+    /// The first provider from `start` on, in round-robin order, that is not
+    /// quarantined and, when `min_block` is given, is known to have reached it.
+    /// Falls back to `start` if none qualifies rather than stalling.
+    fn usable_from(&self, start: usize, min_block: Option<u64>) -> usize {
+        let len = self.providers.len();
+        let index = (0..len)
+            .map(|step| (start + step) % len)
+            .find(|&i| {
+                self.health.is_available(i)
+                    && min_block.is_none_or(|block| self.health.has_block(i, block))
+            })
+            .unwrap_or(start);
+
+        if let Some(block) = min_block
+            && index != start
+            && !self.health.has_block(start, block)
+        {
+            debug!(
+                "Provider {} has not reached block {}, using {} instead",
+                self.urls[start], block, self.urls[index]
+            );
+        }
+
+        index
+    }
+
+    /// Take a permit for each of the `calls` a request carries from the
+    /// provider at `index`. When it hasn't that many left another usable
+    /// provider that has is taken instead, and only when none has capacity
+    /// does this wait for `index`'s. Returns the index the permits are for.
+    async fn acquire_permit(&self, index: usize, min_block: Option<u64>, calls: u32) -> usize {
+        if self.buckets[index].try_acquire(calls).is_ok() {
+            return index;
+        }
+
+        let len = self.providers.len();
+        let other = (1..len).map(|step| (index + step) % len).find(|&i| {
+            self.health.is_available(i)
+                && min_block.is_none_or(|block| self.health.has_block(i, block))
+                && self.buckets[i].try_acquire(calls).is_ok()
+        });
+        if let Some(other) = other {
+            return other;
+        }
+
+        self.buckets[index].acquire(calls).await;
+        index
+    }
+
+    /// Resolves once some usable provider can take a request without waiting
+    /// for its rate limit. Nothing is reserved, the request takes its permit
+    /// when it runs.
+    pub async fn wait_for_capacity(&self) {
+        loop {
+            let wait = (0..self.providers.len())
+                .filter(|&i| self.health.is_available(i))
+                .map(|i| self.buckets[i].wait_time())
+                .min()
+                // Every provider is quarantined, requests go round-robin anyway
+                .unwrap_or_else(|| {
+                    (0..self.providers.len())
+                        .map(|i| self.buckets[i].wait_time())
+                        .min()
+                        .unwrap_or_default()
+                });
+            if wait.is_zero() {
+                return;
+            }
+            sleep(wait).await;
+        }
+    }
+
+    pub fn quarantined_urls(&self) -> Vec<&str> {
+        self.health
+            .quarantined()
+            .into_iter()
+            .map(|i| self.urls[i].as_str())
+            .collect()
+    }
+
+    pub fn provider_stats(&self) -> Vec<(&str, ProviderStats)> {
+        self.urls
+            .iter()
+            .map(String::as_str)
+            .zip(self.health.snapshot())
+            .collect()
+    }
+
+    /// Requests sent so far by method and provider, by every clone of this
+    /// client
+    pub fn stats_snapshot(&self) -> Vec<MethodStats> {
+        self.stats.snapshot(&self.urls)
+    }
+
+    /// Count a request sent outside `request_at`, which has no retries
+    fn record_attempt<T, E>(
+        &self,
+        method: &'static str,
+        index: usize,
+        started: Instant,
+        result: &Result<Result<T, E>, tokio::time::error::Elapsed>,
+    ) {
+        let failed = !matches!(result, Ok(Ok(_)));
+        self.stats
+            .record(method, index, 1, started.elapsed(), false, failed);
+    }
+
+    fn record_success(&self, index: usize, started: Instant) {
+        if self.health.record_success(index, started.elapsed()) {
+            info!("RPC provider {} recovered, re-admitting", self.urls[index]);
+        }
+    }
+
+    fn record_failure(&self, index: usize) {
+        if self.health.record_failure(index) {
+            warn!(
+                "Quarantining RPC provider {} for {}s after {} consecutive failures",
+                self.urls[index],
+                self.health.cooldown().as_secs(),
+                self.health.failure_threshold()
+            );
+        }
+    }
+
+    /// Spawn a background task that probes quarantined providers once their
+    /// cool-down has elapsed and re-admits the ones that respond
+    pub fn spawn_health_probe(&self) -> JoinHandle<()> {
+        let client = self.clone();
+        tokio::spawn(
+            async move {
+                let period = (client.health.cooldown() / 2).max(Duration::from_secs(1));
+                let mut probe_interval = interval(period);
+                loop {
+                    probe_interval.tick().await;
+                    client.probe_quarantined().await;
+                }
+            }
+            .in_current_span(),
+        )
+    }
+
+    async fn probe_quarantined(&self) {
+        for index in self.health.probe_candidates() {
+            let started = Instant::now();
+            let probe = self.providers[index].get_block_number();
+            let result = timeout(self.light_request_timeout, probe).await;
+            self.record_attempt("eth_blockNumber", index, started, &result);
+            match result {
+                Ok(Ok(_)) => self.record_success(index, started),
+                Ok(Err(e)) => {
+                    debug!("Health probe failed on {}: {}", self.urls[index], e);
+                    self.health.record_failure(index);
+                }
+                Err(_) => {
+                    debug!("Health probe timed out on {}", self.urls[index]);
+                    self.health.record_failure(index);
+                }
+            }
+        }
+    }
+
+    fn get_retry_strategy(&self) -> impl Iterator<Item = Duration> {
+        ExponentialBackoff::from_millis(self.retry_backoff_ms)
+            .factor(2)
+            .max_delay(self.retry_max_delay)
+            .map(jitter)
+            .take(self.max_retries)
+    }
+
+    fn handle_error(&self, index: usize, error_str: &str) {
+        warn!(
+            "RPC error on {}: {}, rotating provider",
+            self.urls[index], error_str
+        );
+        self.record_failure(index);
+    }
+
+    fn handle_timeout(&self, index: usize, request_timeout: Duration) -> anyhow::Error {
+        warn!(
+            "Request timeout after {} seconds on {}, rotating provider",
+            request_timeout.as_secs(),
+            self.urls[index]
+        );
+        self.record_failure(index);
+        RpcError::Timeout(request_timeout).into()
+    }
+
+    fn handle_rate_limit(&self, index: usize, retry_after: Option<Duration>, error_str: &str) {
+        let wait = retry_after.unwrap_or(DEFAULT_RATE_LIMIT_BACKOFF);
+        let hits = self.health.record_rate_limit(index, Instant::now() + wait);
+        warn!(
+            "Rate limited by {} (hit #{}), backing off for {:?}: {}",
+            self.urls[index], hits, wait, error_str
+        );
+    }
+
+    /// Run a single-value request against a provider of its own, retrying on
+    /// failure. Each attempt gets the light request timeout.
+    ///
+    /// Errors are classified so that rate limits move on to the next provider
+    /// without sleeping, while responses that can never succeed as-is (too
+    /// many results) are returned to the caller straight away.
+    async fn request<T, F, Fut>(&self, method: &'static str, op: F) -> Result<T>
+    where
+        F: FnMut(AlloyFullProvider) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        self.request_at(method, 1, None, self.light_request_timeout, op)
+            .await
+            .map(|(value, _)| value)
+    }
+
+    /// `request`, restricted to providers that have seen `min_block` when given
+    /// and with an attempt timeout of its own. Also returns the index of the
+    /// provider that answered. Every attempt takes a rate limit permit and is
+    /// counted as a request under `method` for each of the `calls` it
+    /// carries, more than one for a batch.
+    async fn request_at<T, F, Fut>(
+        &self,
+        method: &'static str,
+        calls: usize,
+        min_block: Option<u64>,
+        request_timeout: Duration,
+        mut op: F,
+    ) -> Result<(T, usize)>
+    where
+        F: FnMut(AlloyFullProvider) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut backoff = self.get_retry_strategy();
+        let mut rate_limit_retries = 0;
+        let max_rate_limit_retries = self.providers.len() * self.max_retries;
+
+        let mut index = self.pick_provider(min_block);
+        let mut attempts = 0;
+
+        loop {
+            index = self.acquire_permit(index, min_block, calls as u32).await;
+            let provider = &self.providers[index];
+            let started = Instant::now();
+
+            let result = timeout(request_timeout, op(provider.clone())).await;
+            self.stats.record(
+                method,
+                index,
+                calls as u64,
+                started.elapsed(),
+                attempts > 0,
+                !matches!(result, Ok(Ok(_))),
+            );
+            attempts += 1;
+
+            let error = match result {
+                Ok(Ok(value)) => {
+                    self.record_success(index, started);
+                    return Ok((value, index));
+                }
+                Ok(Err(e)) => {
+                    let error_str = e.to_string();
+                    match RpcErrorKind::classify(&error_str) {
+                        RpcErrorKind::TooManyResults => {
+                            // The provider answered, it just wants a smaller request
+                            self.record_success(index, started);
+                            return Err(IndexerError::Rpc(RpcError::classify(e)));
+                        }
+                        RpcErrorKind::MethodNotFound => {
+                            // Retrying won't add the method, the caller falls back
+                            self.record_success(index, started);
+                            return Err(IndexerError::Rpc(RpcError::classify(e)));
+                        }
+                        RpcErrorKind::RateLimited { retry_after } => {
+                            self.handle_rate_limit(index, retry_after, &error_str);
+                            if rate_limit_retries < max_rate_limit_retries {
+                                rate_limit_retries += 1;
+                                if !self.health.any_available() {
+                                    let wait = self
+                                        .health
+                                        .next_rate_limit_expiry()
+                                        .unwrap_or(DEFAULT_RATE_LIMIT_BACKOFF)
+                                        .min(MAX_RATE_LIMIT_WAIT);
+                                    debug!("All providers rate limited, waiting {:?}", wait);
+                                    sleep(wait).await;
+                                }
+                                index = self.next_provider_after(index, min_block);
+                                continue;
+                            }
+                        }
+                        RpcErrorKind::Other => self.handle_error(index, &error_str),
+                    }
+                    e
+                }
+                Err(_) => self.handle_timeout(index, request_timeout),
+            };
+
+            // Only this request moves on, others keep their provider
+            index = self.next_provider_after(index, min_block);
+
+            match backoff.next() {
+                Some(delay) => sleep(delay).await,
+                // Every provider was asked, so what none of them has is missing
+                None => match error.downcast::<IndexerError>() {
+                    Ok(error @ IndexerError::NotFound(_)) => return Err(error),
+                    Ok(error) => return Err(IndexerError::Rpc(RpcError::classify(error.into()))),
+                    Err(error) => return Err(IndexerError::Rpc(RpcError::classify(error))),
+                },
+            }
+        }
+    }
+
+    pub async fn get_latest_block(&self) -> Result<u64> {
+        self.request("eth_blockNumber", |provider| async move {
+            Ok(provider.get_block_number().await?)
+        })
+        .await
+    }
+
+    /// Query the head of every non-quarantined provider concurrently and record
+    /// it, warning about providers more than `max_head_lag` blocks behind
+    pub async fn refresh_provider_heads(&self) {
+        let quarantined = self.health.quarantined();
+        let queries = (0..self.providers.len())
+            .filter(|i| !quarantined.contains(i))
+            .map(|index| async move {
+                let started = Instant::now();
+                let result = timeout(
+                    self.light_request_timeout,
+                    self.providers[index].get_block_number(),
+                )
+                .await;
+                (index, started, result)
+            });
+
+        for (index, started, result) in join_all(queries).await {
+            self.record_attempt("eth_blockNumber", index, started, &result);
+            match result {
+                Ok(Ok(head)) => {
+                    self.record_success(index, started);
+                    self.health.record_head(index, head);
+                }
+                Ok(Err(e)) => {
+                    debug!("Failed to fetch head from {}: {}", self.urls[index], e);
+                    self.record_failure(index);
+                }
+                Err(_) => {
+                    debug!("Timed out fetching head from {}", self.urls[index]);
+                    self.record_failure(index);
+                }
+            }
+        }
+
+        let stats = self.health.snapshot();
+        let Some(max_head) = stats.iter().filter_map(|s| s.head_block).max() else {
+            return;
+        };
+
+        for (index, provider_stats) in stats.iter().enumerate() {
+            let Some(head) = provider_stats.head_block else {
+                continue;
+            };
+            let lag = max_head - head;
+            let lagging = lag > self.max_head_lag;
+
+            if self.health.set_lagging(index, lagging) {
+                if lagging {
+                    warn!(
+                        "RPC provider {} is {} blocks behind (head {} vs {})",
+                        self.urls[index], lag, head, max_head
+                    );
+                } else {
+                    info!(
+                        "RPC provider {} caught up at block {}",
+                        self.urls[index], head
+                    );
+                }
+            }
+        }
+    }
+
+    /// Chain head agreed on by the providers: the highest block reached by at
+    /// least half of them. Falls back to a single provider's view if no head
+    /// could be observed.
+    pub async fn get_consensus_latest_block(&self) -> Result<u64> {
+        self.refresh_provider_heads().await;
+
+        match self.health.consensus_head() {
+            Some(head) => Ok(head),
+            None => self.get_latest_block().await,
+        }
+    }
+
+    pub async fn get_chain_id(&self) -> Result<u64> {
+        self.request("eth_chainId", |provider| async move {
+            Ok(provider.get_chain_id().await?)
+        })
+        .await
+    }
+
+    /// Ask every provider for its chain id concurrently, without retries, so
+    /// each one can be checked individually
+    pub async fn get_provider_chain_ids(&self) -> Vec<(&str, Result<u64>)> {
+        let queries = self
+            .providers
+            .iter()
+            .enumerate()
+            .map(|(index, provider)| async move {
+                let started = Instant::now();
+                let result = timeout(self.light_request_timeout, provider.get_chain_id()).await;
+                self.record_attempt("eth_chainId", index, started, &result);
+                match result {
+                    Ok(Ok(chain_id)) => Ok(chain_id),
+                    Ok(Err(e)) => Err(IndexerError::Rpc(RpcError::classify(e.into()))),
+                    Err(_) => Err(IndexerError::Rpc(RpcError::Timeout(
+                        self.light_request_timeout,
+                    ))),
+                }
+            });
+
+        self.urls
+            .iter()
+            .map(String::as_str)
+            .zip(join_all(queries).await)
+            .collect()
+    }
+
+    pub async fn get_finalized_block(&self) -> Result<u64> {
+        self.request("eth_getBlockByNumber", |provider| async move {
+            // Get the finalized block using the "finalized" tag
+            match provider
+                .get_block_by_number(BlockNumberOrTag::Finalized)
+                .await?
+            {
+                Some(block) => Ok(block.header.number),
+                None => Err(IndexerError::NotFound("Finalized block not found".to_string()).into()),
+            }
+        })
+        .await
+    }
+
+    pub async fn get_block_hash(&self, block_number: u64) -> Result<B256> {
+        self.request("eth_getBlockByNumber", |provider| async move {
+            match provider
+                .get_block_by_number(BlockNumberOrTag::Number(block_number))
+                .await?
+            {
+                Some(block) => Ok(block.header.hash),
+                None => {
+                    Err(IndexerError::NotFound(format!("Block {} not found", block_number)).into())
+                }
+            }
+        })
+        .await
+    }
+
+    /// Headers of the given blocks in the same order, fetched with JSON-RPC
+    /// batch requests of up to `HEADER_BATCH_SIZE` calls. A batch goes through
+    /// the same retries and provider rotation as a single request, and takes
+    /// a rate limit permit and counts as a request per call; calls that
+    /// failed inside an otherwise answered batch are sent again in a smaller
+    /// one, up to `max_retries` times.
+    pub async fn get_block_headers_batch(&self, block_numbers: &[u64]) -> Result<Vec<Header>> {
+        let mut headers: HashMap<u64, Header> = HashMap::with_capacity(block_numbers.len());
+
+        for chunk in block_numbers.chunks(HEADER_BATCH_SIZE) {
+            let mut missing: Vec<u64> = chunk
+                .iter()
+                .copied()
+                .filter(|n| !headers.contains_key(n))
+                .collect();
+            let mut retries = 0;
+
+            while !missing.is_empty() {
+                let (fetched, index) = self
+                    .request_at(
+                        HEADER_BATCH_METHOD,
+                        missing.len(),
+                        None,
+                        self.request_timeout,
+                        |provider| {
+                            let missing = missing.clone();
+                            async move { fetch_headers(&provider, &missing).await }
+                        },
+                    )
+                    .await?;
+                headers.extend(fetched.into_iter().map(|h| (h.number, h)));
+                missing.retain(|n| !headers.contains_key(n));
+
+                if !missing.is_empty() {
+                    retries += 1;
+                    self.stats.record_failed_calls(
+                        HEADER_BATCH_METHOD,
+                        index,
+                        missing.len() as u64,
+                        retries <= self.max_retries,
+                    );
+                    if retries > self.max_retries {
+                        return Err(IndexerError::Rpc(RpcError::BadResponse(anyhow::anyhow!(
+                            "{} block headers still missing after {} batches, first is block {}",
+                            missing.len(),
+                            retries,
+                            missing[0]
+                        ))));
+                    }
+                    debug!(
+                        "{} calls failed in header batch, retrying them",
+                        missing.len()
+                    );
+                }
+            }
+        }
+
+        block_numbers
+            .iter()
+            .map(|n| {
+                headers
+                    .get(n)
+                    .cloned()
+                    .ok_or_else(|| IndexerError::NotFound(format!("Block {} not found", n)))
+            })
+            .collect()
+    }
+
+    /// Call a method the typed API doesn't cover, such as the `ots_` or
+    /// `trace_` namespaces. Returns None when the provider doesn't implement
+    /// it, other errors are retried like any request.
+    pub async fn raw_request<P, R>(&self, method: &'static str, params: P) -> Result<Option<R>>
+    where
+        P: RpcSend + Clone,
+        R: RpcRecv,
+    {
+        let result = self
+            .request(method, |provider| {
+                let params = params.clone();
+                async move { Ok(provider.raw_request::<P, R>(method.into(), params).await?) }
+            })
+            .await;
+
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if RpcErrorKind::classify(&e.to_string()) == RpcErrorKind::MethodNotFound => {
+                debug!("{} is not supported: {}", method, e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Transaction that created `address`, from Erigon's and Otterscan's
+    /// `ots_getContractCreator`. None when the provider doesn't expose it.
+    pub async fn get_contract_creator(&self, address: Address) -> Result<Option<B256>> {
+        let creator = self
+            .raw_request::<_, Option<ContractCreator>>("ots_getContractCreator", (address,))
+            .await?;
+        Ok(creator.flatten().map(|creator| creator.hash))
+    }
+
+    /// Block a transaction was included in, None if no provider knows it
+    pub async fn get_transaction_block(&self, hash: B256) -> Result<Option<u64>> {
+        self.request("eth_getTransactionReceipt", |provider| async move {
+            Ok(provider
+                .get_transaction_receipt(hash)
+                .await?
+                .and_then(|receipt| receipt.block_number))
+        })
+        .await
+    }
+
+    pub async fn get_code_at_block(&self, address: Address, block_number: u64) -> Result<Bytes> {
+        self.request("eth_getCode", |provider| async move {
+            Ok(provider
+                .get_code_at(address)
+                .block_id(BlockNumberOrTag::Number(block_number).into())
+                .await?)
+        })
+        .await
+    }
+
+    async fn get_logs_internal(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        addresses: &[Address],
+        topics: &[B256],
+    ) -> Result<(Vec<Log>, &str), LogsError> {
+        let filter = Filter::new()
+            .address(addresses.to_vec())
+            .event_signature(topics.to_vec())
+            .from_block(from_block)
+            .to_block(to_block);
+
+        // Near the head a lagging provider would silently return no logs for
+        // blocks it hasn't seen, so only ask providers that have reached to_block
+        self.request_at(
+            "eth_getLogs",
+            1,
+            Some(to_block),
+            self.request_timeout,
+            |provider| {
+                let filter = filter.clone();
+                async move { Ok(provider.get_logs(&filter).await?) }
+            },
+        )
+        .await
+        .map(|(logs, index)| (logs, self.urls[index].as_str()))
+        .map_err(|e| LogsError::from_response(e, from_block, to_block))
+    }
+
+    /// Fetch the logs with one of `topics` emitted by `addresses` in a block
+    /// range, splitting it whenever the provider refuses to return that many
+    /// results. The provider's suggested range is used when it gives one,
+    /// otherwise the range is bisected, down to single blocks. Logs are
+    /// returned in block order.
+    pub async fn get_logs(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        addresses: &[Address],
+        topics: &[B256],
+    ) -> Result<Vec<Log>, LogsError> {
+        self.get_logs_with_splits(from_block, to_block, addresses, topics)
+            .await
+            .map(|(logs, _, _)| logs)
+    }
+
+    /// Like `get_logs`, but also returns how many times the range had to be
+    /// split because of the provider's result-size limit and the URL of the
+    /// provider that answered the last request
+    pub async fn get_logs_with_splits(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        addresses: &[Address],
+        topics: &[B256],
+    ) -> Result<(Vec<Log>, u32, &str), LogsError> {
+        let mut all_logs = Vec::new();
+        let mut splits = 0;
+        let mut rpc_url = "";
+        // Ranges still to fetch, the next one on top
+        let mut pending = vec![(from_block, to_block)];
+
+        while let Some((current_from, current_to)) = pending.pop() {
+            match self
+                .get_logs_internal(current_from, current_to, addresses, topics)
+                .await
+            {
+                Ok((logs, url)) => {
+                    all_logs.extend(logs);
+                    rpc_url = url;
+                }
+                Err(LogsError::TooManyResults { suggested, .. }) if current_from < current_to => {
+                    let split_at = match suggested {
+                        Some((suggested_from, suggested_to))
+                            if suggested_from == current_from
+                                && suggested_to >= current_from
+                                && suggested_to < current_to =>
+                        {
+                            info!(
+                                "Hit max results limit for blocks {}-{}, splitting at suggested block {}",
+                                current_from, current_to, suggested_to
+                            );
+                            suggested_to
+                        }
+                        _ => {
+                            let mid = current_from + (current_to - current_from) / 2;
+                            info!(
+                                "Hit max results limit for blocks {}-{}, bisecting at block {}",
+                                current_from, current_to, mid
+                            );
+                            mid
+                        }
+                    };
+
+                    pending.push((split_at + 1, current_to));
+                    pending.push((current_from, split_at));
+                    splits += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok((all_logs, splits, rpc_url))
+    }
+
+    /// `eth_call` of `input` on `address` at the end of `block_number`, or at
+    /// the head. Only providers that have reached the block are asked. Fails
+    /// when the call returns no data.
+    pub async fn call(
+        &self,
+        address: Address,
+        input: Bytes,
+        block_number: Option<u64>,
+    ) -> Result<Bytes> {
+        let tx_request = alloy::rpc::types::TransactionRequest::default()
+            .to(address)
+            .input(input.into());
+        let block_id = block_number.map_or(BlockNumberOrTag::Latest, BlockNumberOrTag::Number);
+
+        let index = self
+            .acquire_permit(self.pick_provider(block_number), block_number, 1)
+            .await;
+        let provider = &self.providers[index];
+        let started = Instant::now();
+        let call = provider.call(tx_request).block(block_id.into());
+        let result = timeout(self.light_request_timeout, call).await;
+        self.record_attempt("eth_call", index, started, &result);
+        let result = match result {
+            Ok(Ok(result)) => {
+                self.record_success(index, started);
+                result
+            }
+            Ok(Err(e)) => {
+                let error_str = e.to_string();
+                if let RpcErrorKind::RateLimited { retry_after } =
+                    RpcErrorKind::classify(&error_str)
+                {
+                    self.handle_rate_limit(index, retry_after, &error_str);
+                } else {
+                    self.record_failure(index);
+                }
+                return Err(IndexerError::Rpc(RpcError::classify(e.into())));
+            }
+            Err(_) => {
+                self.record_failure(index);
+                return Err(IndexerError::Rpc(RpcError::Timeout(
+                    self.light_request_timeout,
+                )));
+            }
+        };
+
+        if result.is_empty() {
+            return Err(IndexerError::Other(anyhow::anyhow!(
+                "{} returned no data, it is not a contract or lacks the function",
+                address
+            )));
+        }
+
+        Ok(result)
+    }
+}
+
+/// Answer of `ots_getContractCreator`
+#[derive(Debug, Clone, Deserialize)]
+struct ContractCreator {
+    /// Transaction that created the contract
+    hash: B256,
+}
+
+/// Connect to a provider, sending `headers` with each request. A WebSocket
+/// can only carry an `Authorization` header, sent once when it connects, and
+/// an IPC socket none at all.
+async fn connect(
+    transport: &Transport,
+    headers: &[RpcHeader],
+) -> anyhow::Result<AlloyFullProvider> {
+    let builder = ProviderBuilder::new();
+    let provider = match transport {
+        Transport::Http(url) if headers.is_empty() => builder.connect_http(url.clone()),
+        Transport::Http(url) => {
+            let mut header_map = HeaderMap::new();
+            for header in headers {
+                let mut value = HeaderValue::from_str(&header.value)?;
+                value.set_sensitive(true);
+                header_map.insert(HeaderName::from_bytes(header.name.as_bytes())?, value);
+            }
+            let client = reqwest::Client::builder()
+                .default_headers(header_map)
+                .build()?;
+            builder.connect_reqwest(client, url.clone())
+        }
+        Transport::Ws(url) => {
+            let mut ws = WsConnect::new(url.as_str())
+                .with_max_retries(WS_MAX_RECONNECTS)
+                .with_retry_interval(WS_RECONNECT_INTERVAL);
+            for header in headers {
+                if !header.name.eq_ignore_ascii_case("authorization") {
+                    anyhow::bail!(
+                        "Header {} can't be sent over WebSocket, only Authorization",
+                        header.name
+                    );
+                }
+                ws = ws.with_auth(Authorization::raw(header.value.clone()));
+            }
+            builder.connect_ws(ws).await?
+        }
+        Transport::Ipc(_) if !headers.is_empty() => {
+            anyhow::bail!("Headers can't be sent over IPC")
+        }
+        Transport::Ipc(path) => builder.connect_ipc(IpcConnect::new(path.clone())).await?,
+    };
+    Ok(provider)
+}
+
+/// Send one batch of `eth_getBlockByNumber` calls and return the headers of
+/// the calls that succeeded. Fails only when none did, so the batch is retried
+/// as a whole.
+async fn fetch_headers(
+    provider: &AlloyFullProvider,
+    block_numbers: &[u64],
+) -> anyhow::Result<Vec<Header>> {
+    let mut batch = BatchRequest::new(provider.client());
+    let mut waiters = Vec::with_capacity(block_numbers.len());
+    for &block_number in block_numbers {
+        let waiter = batch.add_call::<_, Option<Block>>(
+            "eth_getBlockByNumber",
+            &(BlockNumberOrTag::Number(block_number), false),
+        )?;
+        waiters.push((block_number, waiter));
+    }
+    batch.send().await?;
+
+    let mut headers = Vec::with_capacity(waiters.len());
+    let mut last_error = None;
+    for (block_number, waiter) in waiters {
+        match waiter.await {
+            Ok(Some(block)) => headers.push(block.header),
+            Ok(None) => {
+                last_error =
+                    Some(IndexerError::NotFound(format!("Block {} not found", block_number)).into())
+            }
+            Err(e) => last_error = Some(e.into()),
+        }
+    }
+
+    match last_error {
+        Some(e) if headers.is_empty() => Err(e),
+        _ => Ok(headers),
+    }
+}
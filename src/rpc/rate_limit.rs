@@ -0,0 +1,169 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Requests-per-second limit of one provider. Tokens refill continuously up to
+/// one second's worth, so a provider that was idle can take a short burst.
+pub struct TokenBucket {
+    /// Tokens added per second, None for no limit
+    rate: Option<f64>,
+    capacity: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// A limit of zero or less means no limit
+    pub fn new(requests_per_second: f64) -> Self {
+        let rate = (requests_per_second > 0.0).then_some(requests_per_second);
+        let capacity = requests_per_second.max(1.0);
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `tokens` tokens, one per call a request carries, if available,
+    /// otherwise return how long until they are. More than the capacity are
+    /// taken from a full bucket, which is left owing the rest.
+    pub fn try_acquire(&self, tokens: u32) -> Result<(), Duration> {
+        let Some(rate) = self.rate else {
+            return Ok(());
+        };
+
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, rate);
+        let needed = f64::from(tokens).min(self.capacity);
+        if state.tokens >= needed {
+            state.tokens -= f64::from(tokens);
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((needed - state.tokens) / rate))
+        }
+    }
+
+    /// Wait for `tokens` tokens and take them
+    pub async fn acquire(&self, tokens: u32) {
+        while let Err(wait) = self.try_acquire(tokens) {
+            sleep(wait).await;
+        }
+    }
+
+    /// How long until a token is available, zero when one is now
+    pub fn wait_time(&self) -> Duration {
+        let Some(rate) = self.rate else {
+            return Duration::ZERO;
+        };
+
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, rate);
+        if state.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - state.tokens) / rate)
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState, rate: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate).min(self.capacity);
+        state.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(bucket: &TokenBucket) -> usize {
+        let mut taken = 0;
+        while bucket.try_acquire(1).is_ok() {
+            taken += 1;
+        }
+        taken
+    }
+
+    #[test]
+    fn a_full_bucket_allows_one_seconds_burst_then_waits() {
+        let bucket = TokenBucket::new(5.0);
+        assert_eq!(drain(&bucket), 5);
+
+        let wait = bucket.try_acquire(1).unwrap_err();
+        assert!(
+            wait > Duration::from_millis(150) && wait <= Duration::from_millis(200),
+            "{wait:?}"
+        );
+        assert!(!bucket.wait_time().is_zero() && bucket.wait_time() <= wait);
+
+        std::thread::sleep(Duration::from_millis(210));
+        assert!(bucket.try_acquire(1).is_ok());
+        assert!(bucket.try_acquire(1).is_err());
+    }
+
+    #[test]
+    fn tokens_refill_with_time_up_to_the_capacity() {
+        let bucket = TokenBucket::new(20.0);
+        drain(&bucket);
+
+        std::thread::sleep(Duration::from_millis(260));
+        // 5.2 tokens came back
+        assert_eq!(drain(&bucket), 5);
+
+        // Idle for longer than a second refills to the capacity, no more
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(drain(&bucket), 20);
+    }
+
+    #[test]
+    fn a_batch_takes_a_token_per_call_and_one_past_the_capacity_owes_the_rest() {
+        let bucket = TokenBucket::new(10.0);
+        assert!(bucket.try_acquire(4).is_ok());
+        assert_eq!(drain(&bucket), 6);
+
+        // Fifty calls wait for a full bucket, then leave it forty short
+        let bucket = TokenBucket::new(10.0);
+        assert!(bucket.try_acquire(1).is_ok());
+        assert!(bucket.try_acquire(50).is_err());
+        std::thread::sleep(Duration::from_millis(110));
+        assert!(bucket.try_acquire(50).is_ok());
+        let wait = bucket.try_acquire(1).unwrap_err();
+        assert!(
+            wait > Duration::from_millis(4000) && wait <= Duration::from_millis(4100),
+            "{wait:?}"
+        );
+    }
+
+    #[test]
+    fn no_limit_never_waits() {
+        let bucket = TokenBucket::new(0.0);
+        for _ in 0..10_000 {
+            assert!(bucket.try_acquire(1).is_ok());
+        }
+        assert_eq!(bucket.wait_time(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_for_the_next_token() {
+        let bucket = TokenBucket::new(10.0);
+        drain(&bucket);
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            bucket.acquire(1).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(280) && elapsed < Duration::from_millis(600),
+            "{elapsed:?}"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Counters of the requests sent for one JSON-RPC method to one provider
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestCounters {
+    /// Attempts sent, which is what a provider billing per request counts
+    pub requests: u64,
+    /// Attempts repeating one that failed
+    pub retries: u64,
+    /// Attempts that failed, timed out or were rate limited
+    pub failures: u64,
+    /// Time spent waiting on the attempts
+    pub total_latency: Duration,
+}
+
+impl RequestCounters {
+    pub fn avg_latency(&self) -> Option<Duration> {
+        (self.requests > 0).then(|| self.total_latency / self.requests as u32)
+    }
+
+    fn add(&mut self, other: &RequestCounters) {
+        self.requests += other.requests;
+        self.retries += other.retries;
+        self.failures += other.failures;
+        self.total_latency += other.total_latency;
+    }
+}
+
+/// Counters of one method on one provider, as of a snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodStats {
+    pub method: &'static str,
+    pub provider: String,
+    pub counters: RequestCounters,
+}
+
+/// Request counters by method and provider since the client was built,
+/// shared by its clones
+#[derive(Debug, Default)]
+pub struct RpcStats {
+    counters: Mutex<BTreeMap<(&'static str, usize), RequestCounters>>,
+}
+
+impl RpcStats {
+    /// Count one attempt of `method` on the provider at `index`, as many
+    /// requests as the `calls` it carried: one, or those of a batch
+    pub fn record(
+        &self,
+        method: &'static str,
+        index: usize,
+        calls: u64,
+        latency: Duration,
+        retry: bool,
+        failed: bool,
+    ) {
+        let mut counters = self.counters.lock().unwrap();
+        let entry = counters.entry((method, index)).or_default();
+        entry.requests += calls;
+        entry.retries += calls * u64::from(retry);
+        entry.failures += calls * u64::from(failed);
+        entry.total_latency += latency;
+    }
+
+    /// Count `calls` that failed inside a batch the provider at `index`
+    /// answered, and that are sent again when `resent`
+    pub fn record_failed_calls(
+        &self,
+        method: &'static str,
+        index: usize,
+        calls: u64,
+        resent: bool,
+    ) {
+        let mut counters = self.counters.lock().unwrap();
+        let entry = counters.entry((method, index)).or_default();
+        entry.retries += calls * u64::from(resent);
+        entry.failures += calls;
+    }
+
+    /// Counters by method, then by provider in the order of `urls`
+    pub fn snapshot(&self, urls: &[String]) -> Vec<MethodStats> {
+        self.counters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(method, index), counters)| MethodStats {
+                method,
+                provider: urls.get(index).cloned().unwrap_or_default(),
+                counters: counters.clone(),
+            })
+            .collect()
+    }
+}
+
+/// A text table of `stats` with a row per method and provider and a total,
+/// for the log. Empty when no request was sent.
+pub fn format_rpc_stats(stats: &[MethodStats]) -> String {
+    if stats.is_empty() {
+        return String::new();
+    }
+
+    let mut total = RequestCounters::default();
+    let mut rows = vec![[
+        "Method".to_string(),
+        "Provider".to_string(),
+        "Requests".to_string(),
+        "Retries".to_string(),
+        "Failures".to_string(),
+        "Avg latency".to_string(),
+        "Total latency".to_string(),
+    ]];
+    let row = |method: &str, provider: &str, counters: &RequestCounters| {
+        [
+            method.to_string(),
+            provider.to_string(),
+            counters.requests.to_string(),
+            counters.retries.to_string(),
+            counters.failures.to_string(),
+            counters
+                .avg_latency()
+                .map_or(String::new(), |avg| format!("{}ms", avg.as_millis())),
+            format!("{:.1}s", counters.total_latency.as_secs_f64()),
+        ]
+    };
+    for entry in stats {
+        total.add(&entry.counters);
+        rows.push(row(entry.method, &entry.provider, &entry.counters));
+    }
+    rows.push(row("total", "", &total));
+
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .enumerate()
+                .map(|(column, (cell, &width))| {
+                    // Counts and latencies are right-aligned
+                    if column < 2 {
+                        format!("{cell:<width$}")
+                    } else {
+                        format!("{cell:>width$}")
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
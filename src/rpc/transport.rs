@@ -0,0 +1,91 @@
+use alloy::transports::http::reqwest::Url;
+use std::fmt;
+use std::path::PathBuf;
+
+/// How a provider is reached, chosen from the form of its URL
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    Http(Url),
+    /// `ws://` or `wss://`, reconnecting automatically when dropped
+    Ws(Url),
+    /// A local node's IPC socket, given as a plain path or an `ipc://` URL
+    Ipc(PathBuf),
+}
+
+impl Transport {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        if let Some(path) = url.strip_prefix("ipc://") {
+            return Ok(Transport::Ipc(PathBuf::from(path)));
+        }
+        if !url.contains("://") {
+            if url.ends_with(".ipc") || url.starts_with('/') || url.starts_with('.') {
+                return Ok(Transport::Ipc(PathBuf::from(url)));
+            }
+            return Err(format!(
+                "invalid RPC URL {url:?}: expected an http(s):// or ws(s):// URL or an IPC socket path"
+            ));
+        }
+
+        let parsed = Url::parse(url).map_err(|e| format!("invalid RPC URL {url:?}: {e}"))?;
+        match parsed.scheme() {
+            "http" | "https" => Ok(Transport::Http(parsed)),
+            "ws" | "wss" => Ok(Transport::Ws(parsed)),
+            scheme => Err(format!(
+                "unsupported RPC URL scheme {scheme:?} in {url:?}: expected http, https, ws, wss or an IPC socket path"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Http(_) => write!(f, "HTTP"),
+            Transport::Ws(_) => write!(f, "WebSocket"),
+            Transport::Ipc(_) => write!(f, "IPC"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(text: &str) -> Url {
+        Url::parse(text).unwrap()
+    }
+
+    #[test]
+    fn each_scheme_picks_its_transport() {
+        for text in ["http://node.example:8545", "https://node.example/key"] {
+            assert_eq!(Transport::parse(text), Ok(Transport::Http(url(text))));
+        }
+        for text in ["ws://node.example:8546", "wss://node.example/key"] {
+            assert_eq!(Transport::parse(text), Ok(Transport::Ws(url(text))));
+        }
+        for (text, path) in [
+            ("ipc:///tmp/geth.ipc", "/tmp/geth.ipc"),
+            ("/var/run/reth.sock", "/var/run/reth.sock"),
+            ("./node/geth.ipc", "./node/geth.ipc"),
+            ("geth.ipc", "geth.ipc"),
+        ] {
+            assert_eq!(Transport::parse(text), Ok(Transport::Ipc(path.into())));
+        }
+    }
+
+    #[test]
+    fn other_schemes_and_bare_names_are_refused() {
+        let error = Transport::parse("ftp://node.example").unwrap_err();
+        assert!(
+            error.starts_with("unsupported RPC URL scheme \"ftp\""),
+            "{error}"
+        );
+
+        for text in ["node.example:8545", "localhost"] {
+            let error = Transport::parse(text).unwrap_err();
+            assert!(error.starts_with("invalid RPC URL"), "{error}");
+        }
+        let error = Transport::parse("http://").unwrap_err();
+        assert!(error.starts_with("invalid RPC URL"), "{error}");
+    }
+}
@@ -1,195 +1,927 @@
-use crate::config::Config;
+use crate::batch_sizer::BatchSizer;
+use crate::config::{Config, IndexerMode, TokenSegment, TokenStandard};
 use crate::deployment::{fetch_token_metadata, find_deployment_block};
-use crate::events::{Transfer as EventTransfer, decode_transfer_event};
-use crate::insertion_worker::{TransferBatch, run_insertion_worker};
+use crate::error::{IndexerError, Result, is_fatal};
+use crate::events::{MalformedLogPolicy, transfer_topics};
+use crate::finality_worker::{FinalityTracker, run_finality_worker};
+use crate::insertion_worker::{
+    InsertionSettings, RangeFetch, TransferBatch, WriteMode, run_insertion_worker,
+};
+use crate::log_source::LogSource;
+use crate::notifier::{NotifierConfig, start_notifier};
+use crate::progress::{PROGRESS_TARGET, ProgressCounters, ProgressReporter};
+use crate::recent_blocks::{RECENT_BLOCKS_CAPACITY, RecentBlocks};
 use crate::repository::{
-    BalanceRepository, Database, Token, TokenRepository, Transfer, TransferRepository,
+    Database, DecodeFailureRepository, DeploymentSearchRepository, ScannedRangeRepository, Token,
+    TokenRepository, Transfer,
 };
-use crate::rpc::RpcClient;
-use alloy::sol_types::SolEvent;
+use crate::rpc::{LogsError, RpcApi, RpcClient, format_rpc_stats};
+use crate::segments::HistoricalSegments;
+use crate::watermark::Watermark;
+use alloy::rpc::types::Log;
 use alloy_primitives::{Address, B256};
-use anyhow::Result;
-use futures::stream::{FuturesOrdered, StreamExt};
+use anyhow::Context;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use std::time::Instant;
-use tokio::sync::mpsc;
-use tokio::time::interval;
-use tracing::{error, info, warn};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, interval_at};
+use tracing::{Instrument, debug, error, info, warn};
 
-pub struct Scanner {
-    client: RpcClient,
+pub struct Scanner<C = RpcClient> {
+    client: C,
     db: Database,
     contract_address: Address,
-    transfer_topic: B256,
+    /// Contracts and event signatures the logs are requested for
+    log_source: LogSource,
+    /// Configured contracts of a token that moved, before their start blocks
+    /// are resolved into `log_source`
+    token_segments: Vec<TokenSegment>,
+    /// Which Transfer event the logs are decoded as
+    token_standard: TokenStandard,
+    /// Configured deployment block, skips the search when set
+    deployment_block: Option<u64>,
+    /// Checked first by the deployment search
+    deployment_search_hint: Option<u64>,
     batch_size: u64,
+    batch_sizer: BatchSizer,
     rate_limit_delay_ms: u64,
+    /// Batches at least this large use multi-row insert statements
+    multi_row_insert_threshold: usize,
+    /// Attempts at fetching a range before the scan stops
+    range_max_attempts: u32,
+    /// Rows of the indexing log kept, 0 when it isn't written
+    indexing_log_retention: u64,
+    /// Shared with the finality worker, which decodes the same logs
+    malformed_logs: MalformedLogPolicy,
     max_pending_requests: usize,
+    /// Segments the historical blocks are fetched in side by side, 1 for
+    /// fetching them in order
+    parallel_segments: usize,
     finality_update_interval_secs: u64,
+    skip_initial_finality: bool,
+    finality_full_refetch: bool,
+    balance_checkpoint_interval: u64,
     block_time_secs: u64,
+    progress_interval_secs: u64,
+    /// Seconds between summaries of the RPC requests sent, 0 for only the
+    /// one when the scan stops
+    rpc_stats_interval_secs: u64,
+    mode: IndexerMode,
+    write_mode: WriteMode,
+    expected_chain_id: Option<u64>,
+    /// Set when a watchlist and webhook are configured
+    notifier_config: Option<NotifierConfig>,
+    /// Last block finalized by the finality worker, shared with it so marking
+    /// transfers doesn't need a database read
+    finalized_block: Arc<AtomicU64>,
+    /// Hashes of processed unfinalized blocks, to catch reorgs at the head
+    recent_blocks: RecentBlocks,
+    /// Chain head everything was committed up to, each time the scan catches up
+    caught_up: watch::Sender<Option<u64>>,
 }
 
-impl Scanner {
-    pub fn new(client: RpcClient, db: Database, config: &Config) -> Result<Self> {
-        let transfer_topic = EventTransfer::SIGNATURE_HASH;
+impl<C: RpcApi + Clone + 'static> Scanner<C> {
+    pub fn new(client: C, db: Database, config: &Config) -> Result<Self> {
         Ok(Scanner {
             client,
             db,
             contract_address: config.erc20_contract_address,
-            transfer_topic,
+            log_source: LogSource::single(
+                config.erc20_contract_address,
+                match &config.custom_event {
+                    Some(event) => vec![event.topic()],
+                    None => transfer_topics(config.token_standard, config.track_deposit_withdrawal),
+                },
+            ),
+            token_segments: config.token_segments.clone(),
+            token_standard: config.token_standard,
+            deployment_block: config.deployment_block,
+            deployment_search_hint: config.deployment_search_hint,
             batch_size: config.batch_size,
+            batch_sizer: BatchSizer::new(
+                config.batch_size,
+                config.min_batch_size,
+                config.max_batch_size,
+                config.target_logs_per_request,
+            ),
             rate_limit_delay_ms: config.rate_limit_delay_ms,
+            multi_row_insert_threshold: config.multi_row_insert_threshold,
+            range_max_attempts: config.range_max_attempts.max(1),
+            indexing_log_retention: config.indexing_log_retention,
+            malformed_logs: MalformedLogPolicy::new(
+                config.strict_logs,
+                config.token_standard,
+                config.decode_errors,
+            )
+            .with_custom_event(config.custom_event.clone()),
             max_pending_requests: config.max_pending_requests,
+            parallel_segments: config.parallel_segments.max(1),
             finality_update_interval_secs: config.finality_update_interval_secs,
+            skip_initial_finality: config.skip_initial_finality,
+            finality_full_refetch: config.finality_full_refetch,
+            balance_checkpoint_interval: config.balance_checkpoint_interval,
             block_time_secs: config.block_time_secs,
+            progress_interval_secs: config.progress_interval_secs,
+            rpc_stats_interval_secs: config.rpc_stats_interval_secs,
+            mode: config.mode,
+            write_mode: WriteMode::Write,
+            expected_chain_id: config.expected_chain_id,
+            notifier_config: NotifierConfig::from_config(config),
+            finalized_block: Arc::new(AtomicU64::new(0)),
+            recent_blocks: RecentBlocks::new(RECENT_BLOCKS_CAPACITY),
+            caught_up: watch::channel(None).0,
         })
     }
 
+    /// Only count what would be written with [`WriteMode::DryRun`], given a
+    /// database that may be thrown away such as [`Database::in_memory_copy`]
+    pub fn with_write_mode(mut self, write_mode: WriteMode) -> Self {
+        self.write_mode = write_mode;
+        self
+    }
+
+    /// Receives the chain head every time all transfers up to it are committed
+    pub fn subscribe_caught_up(&self) -> watch::Receiver<Option<u64>> {
+        self.caught_up.subscribe()
+    }
+
     pub async fn run(&mut self) -> Result<()> {
+        let (_keep_running, shutdown) = watch::channel(false);
+        self.run_until_shutdown(shutdown).await
+    }
+
+    /// `run`, stopping when `shutdown` turns true or its sender is dropped.
+    /// Batches already handed to the insertion worker are committed first.
+    /// The RPC requests sent are summarized when it stops, also on failure.
+    pub async fn run_until_shutdown(&mut self, shutdown: watch::Receiver<bool>) -> Result<()> {
+        let result = self.scan(shutdown).await;
+        self.log_rpc_stats("RPC requests sent");
+        result
+    }
+
+    /// Log a table of the RPC requests sent so far, by method and provider
+    fn log_rpc_stats(&self, heading: &str) {
+        let table = format_rpc_stats(&self.client.stats_snapshot());
+        if !table.is_empty() {
+            info!(target: PROGRESS_TARGET, "{}:\n{}", heading, table);
+        }
+    }
+
+    async fn scan(&mut self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+        let chain_id = self.verify_chain_id().await?;
+        self.resolve_token_segments().await?;
         let deployment_block = self.ensure_deployment_block().await?;
 
         let token_repo = TokenRepository::new(&self.db.conn);
+        token_repo.set_chain_id(&self.contract_address, chain_id)?;
         let last_processed_block = token_repo
             .get_last_processed_block(&self.contract_address)?
             .unwrap_or(deployment_block);
 
         info!("Starting scan from block {}", last_processed_block);
 
+        let finality_tracker = FinalityTracker::new(
+            self.client.clone(),
+            self.db.try_clone()?,
+            self.contract_address,
+            self.log_source.clone(),
+            self.batch_size,
+            self.finalized_block.clone(),
+            self.malformed_logs.clone(),
+        )
+        .with_full_refetch(self.finality_full_refetch)
+        .with_indexing_log(self.indexing_log_retention)
+        .with_balance_checkpoints(self.balance_checkpoint_interval);
+
+        let last_finalized = token_repo
+            .get_last_processed_finalized_block(&self.contract_address)?
+            .unwrap_or(0);
+        self.finalized_block
+            .store(last_finalized, Ordering::Release);
+
         // Do initial finality update before starting main loop
-        info!("Performing initial finality update...");
-        if let Err(e) = self.update_finality(true).await {
+        let initial_update = if self.write_mode == WriteMode::DryRun {
+            // Nothing is stored for finality updates to verify, the scan marks
+            // the transfers of finalized blocks against the chain's
+            info!("Dry run: nothing is written and finality updates are skipped");
+            self.client
+                .get_finalized_block()
+                .await
+                .map(|finalized| self.finalized_block.store(finalized, Ordering::Release))
+                .map_err(anyhow::Error::from)
+        } else if self.skip_initial_finality {
+            finality_tracker
+                .skip_initial_update(last_processed_block)
+                .await
+        } else {
+            info!("Performing initial finality update...");
+            finality_tracker
+                .update_finality(last_processed_block, true)
+                .await
+        };
+        if let Err(e) = initial_update {
+            if is_fatal(&e) {
+                return Err(IndexerError::from_anyhow(e));
+            }
             error!("Initial finality update failed: {}", e);
         }
 
         // Create channel for sending batches to insertion worker
         let (tx, rx) = mpsc::channel::<TransferBatch>(10);
+        let (last_processed_tx, last_processed_rx) = watch::channel(last_processed_block);
+
+        // Spawn insertion worker, it reports committed batches for the progress summary
+        let progress = Arc::new(ProgressCounters::new(last_processed_block));
+        let mut progress_reporter = ProgressReporter::new(progress.clone());
+        // Spawn the webhook sender, the insertion worker feeds it matching transfers
+        let notifier_config = self
+            .notifier_config
+            .clone()
+            .filter(|_| self.write_mode == WriteMode::Write);
+        let (notifier, notifier_handle) = match notifier_config {
+            Some(config) => {
+                let (notifier, sender) =
+                    start_notifier(config, self.db.try_clone()?, self.contract_address)
+                        .map_err(IndexerError::from_anyhow)?;
+                (
+                    Some(notifier),
+                    Some(tokio::spawn(sender.run().in_current_span())),
+                )
+            }
+            None => (None, None),
+        };
 
-        // Spawn insertion worker
-        let db_clone = self.db.clone();
+        let db_clone = self.db.try_clone()?;
         let contract_address = self.contract_address;
-        let insertion_handle =
-            tokio::spawn(async move { run_insertion_worker(db_clone, contract_address, rx).await });
-
-        let mut rate_limit_interval = interval(Duration::from_millis(self.rate_limit_delay_ms));
-        rate_limit_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let insertion_settings = InsertionSettings {
+            multi_row_threshold: self.multi_row_insert_threshold,
+            indexing_log_retention: self.indexing_log_retention,
+            write_mode: self.write_mode,
+        };
+        let insertion_handle = tokio::spawn(
+            async move {
+                run_insertion_worker(
+                    db_clone,
+                    contract_address,
+                    rx,
+                    last_processed_tx,
+                    progress,
+                    notifier,
+                    insertion_settings,
+                )
+                .await
+            }
+            .in_current_span(),
+        );
 
-        let mut finality_interval =
-            interval(Duration::from_secs(self.finality_update_interval_secs));
-        finality_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        // Spawn finality worker, it follows the insertion worker's progress. In
+        // once mode a single pass runs after the insertion worker is drained
+        // instead, so two updates never race on the finalized cursor.
+        let (final_finality_tracker, mut finality_handle) = match self.mode {
+            _ if self.write_mode == WriteMode::DryRun => (None, None),
+            IndexerMode::Follow => {
+                let handle = tokio::spawn(
+                    run_finality_worker(
+                        Arc::new(finality_tracker),
+                        Duration::from_secs(self.finality_update_interval_secs),
+                        last_processed_rx.clone(),
+                    )
+                    .in_current_span(),
+                );
+                (None, Some(handle))
+            }
+            IndexerMode::Once => (Some(Arc::new(finality_tracker)), None),
+        };
 
         let mut block_poll_interval = interval(Duration::from_secs(self.block_time_secs));
         block_poll_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
-        let mut next_block_to_fetch = last_processed_block + 1;
-        let mut next_block_to_process = last_processed_block + 1;
+        let progress_period = Duration::from_secs(self.progress_interval_secs.max(1));
+        let mut progress_interval = interval_at(
+            tokio::time::Instant::now() + progress_period,
+            progress_period,
+        );
+        progress_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
-        let mut pending_fetches = FuturesOrdered::<_>::new();
+        let rpc_stats_period = Duration::from_secs(self.rpc_stats_interval_secs.max(1));
+        let mut rpc_stats_interval = interval_at(
+            tokio::time::Instant::now() + rpc_stats_period,
+            rpc_stats_period,
+        );
+        rpc_stats_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
-        loop {
-            let latest_block = self.client.get_latest_block().await?;
+        let mut next_block_to_fetch = last_processed_block + 1;
+        let mut watermark = Watermark::new(last_processed_block);
+        // Watermark of the last batch sent. Ranges a restart finds already
+        // scanned move the watermark before any batch covers them.
+        let mut sent_watermark = last_processed_block;
+
+        // Ranges are processed as their requests complete, so one slow
+        // response doesn't hold back the ones fired after it
+        let mut pending_fetches = FuturesUnordered::new();
+        let mut failure = self.scan_repairs(&tx, last_processed_block).await.err();
+
+        // The chain head is refreshed on block_poll_interval, fetch decisions use
+        // this cached value
+        let mut latest_block = self.initial_latest_block().await?;
+
+        // Until every segment is fetched, requests are fired from the
+        // segments instead of from next_block_to_fetch
+        let mut segments = self.plan_segments(&mut watermark, latest_block).await?;
+
+        while failure.is_none() {
+            if let Some(historical) = &segments
+                && historical.is_empty()
+                && pending_fetches.is_empty()
+            {
+                info!(
+                    "All {} segments fetched up to block {}, following the head",
+                    historical.len(),
+                    historical.end()
+                );
+                next_block_to_fetch = watermark.get() + 1;
+                segments = None;
+            }
 
-            if next_block_to_fetch > latest_block && pending_fetches.is_empty() {
+            if segments.is_none()
+                && next_block_to_fetch > latest_block
+                && pending_fetches.is_empty()
+            {
+                // Everything up to the head is queued, report it once committed
+                let mut committed = last_processed_rx.clone();
+                if committed
+                    .wait_for(|block| *block >= latest_block)
+                    .await
+                    .is_ok()
+                {
+                    self.caught_up.send_replace(Some(latest_block));
+                }
+
+                if self.mode == IndexerMode::Once {
+                    info!("Caught up to latest block {}, finishing", latest_block);
+                    break;
+                }
                 info!(
                     "Caught up to latest block {}. Waiting for new blocks...",
                     latest_block
                 );
-                block_poll_interval.tick().await;
-                next_block_to_fetch = next_block_to_process;
+                tokio::select! {
+                    _ = block_poll_interval.tick() => {}
+                    _ = shutdown_requested(&mut shutdown) => {
+                        info!("Shutdown requested, stopping");
+                        break;
+                    }
+                    e = finality_failed(&mut finality_handle) => {
+                        failure = Some(IndexerError::from_anyhow(e));
+                        break;
+                    }
+                }
+                latest_block = self.refresh_latest_block(latest_block).await;
+                next_block_to_fetch = watermark.get() + 1;
                 continue;
             }
 
             tokio::select! {
-                // Periodically update finality
-                _ = finality_interval.tick() => {
-                    if let Err(e) = self.update_finality(false).await {
-                        error!("Failed to update finality: {}", e);
+                // In-flight requests are dropped, queued batches still commit
+                _ = shutdown_requested(&mut shutdown) => {
+                    info!("Shutdown requested, stopping");
+                    break;
+                }
+
+                // Queued batches still commit before the error is returned
+                e = finality_failed(&mut finality_handle) => {
+                    failure = Some(IndexerError::from_anyhow(e));
+                    break;
+                }
+
+                // Periodically refresh the chain head. Once mode stops at the
+                // head seen at startup.
+                _ = block_poll_interval.tick() => {
+                    if self.mode == IndexerMode::Follow {
+                        latest_block = self.refresh_latest_block(latest_block).await;
                     }
                 }
 
-                // Fire new requests at the rate limit interval
-                _ = rate_limit_interval.tick() => {
-                    if pending_fetches.len() < self.max_pending_requests && next_block_to_fetch <= latest_block {
-                        let from = next_block_to_fetch;
-                        let to = (from + self.batch_size - 1).min(latest_block);
-
-                        info!("Firing request for blocks {} to {}", from, to);
-
-                        // Clone what we need for the async task
-                        let client = self.client.clone();
-                        let contract_address = self.contract_address;
-                        let transfer_topic = self.transfer_topic;
-
-                        // Rotate to next RPC for load distribution
-                        client.rotate_provider();
-
-                        // Create the future and push it to FuturesOrdered
-                        let fetch_future = async move {
-                            let rpc_url = client.get_current_url().to_string();
-                            let start = Instant::now();
-                            let logs = client
-                                .get_logs(from, to, contract_address, transfer_topic)
-                                .await?;
-                            let elapsed = start.elapsed();
-                            Ok::<_, anyhow::Error>((from, to, logs, elapsed, rpc_url))
-                        };
-
-                        pending_fetches.push_back(fetch_future);
-                        next_block_to_fetch = to + 1;
+                // Periodic progress summary
+                _ = progress_interval.tick() => {
+                    let queue_depth = tx.max_capacity() - tx.capacity();
+                    progress_reporter.report(latest_block, queue_depth, self.batch_sizer.current());
+                    if let Some(historical) = &segments {
+                        info!(
+                            target: PROGRESS_TARGET,
+                            "Historical segments: {:?} blocks left to request",
+                            historical.remaining_blocks()
+                        );
                     }
                 }
 
-                // Process results as they come in, in order
-                Some(result) = pending_fetches.next() => {
-                    let (from, to, logs, elapsed, rpc_url) = result?;
-
-                    info!("Processing {} logs for blocks {} to {} (took {:?} from {})",
-                          logs.len(), from, to, elapsed.as_secs_f64(), rpc_url);
-
-                    let mut transfers = Vec::new();
-
-                    for log in &logs {
-                        match decode_transfer_event(log) {
-                            Ok(event) => {
-                                let block_num = log.block_number.unwrap();
-                                transfers.push(Transfer {
-                                    transaction_hash: log.transaction_hash.unwrap(),
-                                    log_index: log.log_index.unwrap(),
-                                    token_address: self.contract_address,
-                                    from_address: event.from,
-                                    to_address: event.to,
-                                    value: event.value,
-                                    block_number: block_num,
-                                    block_hash: log.block_hash.unwrap(),
-                                    is_finalized: self.should_mark_as_finalized(block_num),
-                                });
-                            }
-                            Err(e) => {
-                                anyhow::bail!("Failed to decode transfer event: {}", e);
-                            }
+                // Periodic summary of the RPC requests sent
+                _ = rpc_stats_interval.tick(), if self.rpc_stats_interval_secs > 0 => {
+                    self.log_rpc_stats("RPC requests sent so far");
+                }
+
+                // Fire a new request whenever a provider's rate limit allows one
+                _ = self.client.wait_for_capacity(),
+                    if pending_fetches.len() < self.max_pending_requests
+                        && segments.as_ref().map_or(next_block_to_fetch <= latest_block, |historical| !historical.is_empty()) =>
+                {
+                    let batch_size = self.batch_sizer.current();
+                    let (from, to) = match segments.as_mut().and_then(|historical| historical.next_range(batch_size)) {
+                        Some(range) => range,
+                        None => {
+                            let from = next_block_to_fetch;
+                            let to = (from + batch_size - 1).min(latest_block);
+                            next_block_to_fetch = to + 1;
+                            (from, to)
                         }
-                    }
+                    };
 
-                    // Send batch to insertion worker
-                    if !transfers.is_empty() || next_block_to_process <= to {
-                        let batch = TransferBatch {
-                            transfers,
-                            end_block: to,
-                        };
+                    debug!(from_block = from, to_block = to, batch_size, "Firing log request");
 
-                        if tx.send(batch).await.is_err() {
-                            warn!("Insertion worker has stopped, exiting...");
+                    pending_fetches.push(self.fetch_range(from, to, 1));
+                }
+
+                // Process results as they complete, in any order
+                Some((from, to, attempt, result)) = pending_fetches.next() => {
+                    let processed = match result {
+                        Ok(fetched) => {
+                            self.batch_sizer.record(to - from + 1, fetched.logs.len() as u64, fetched.splits);
+
+                            debug!(
+                                from_block = from,
+                                to_block = to,
+                                log_count = fetched.logs.len(),
+                                elapsed_ms = fetched.elapsed.as_millis() as u64,
+                                rpc_url = %fetched.rpc_url,
+                                next_batch_size = self.batch_sizer.current(),
+                                ranges_ahead = watermark.ranges_ahead(),
+                                "Processing logs"
+                            );
+
+                            self.check_and_decode(from, to, fetched).await
+                        }
+                        Err(e) => Err(e),
+                    };
+
+                    // A failed range is fired again while the others carry on;
+                    // the cursor can't pass it, so only a range that keeps
+                    // failing stops the scan. That includes the re-fetch after
+                    // a reorg and decoding.
+                    let (mut transfers, fetch, replace_range) = match processed {
+                        Ok(processed) => processed,
+                        Err(e) if attempt < self.range_max_attempts => {
+                            warn!(
+                                "Fetching blocks {}-{} failed (attempt {} of {}), retrying: {:#}",
+                                from, to, attempt, self.range_max_attempts, e
+                            );
+                            pending_fetches.push(self.fetch_range(from, to, attempt + 1));
+                            continue;
+                        }
+                        // Batches already queued still commit before the error
+                        // is returned
+                        Err(e) => {
+                            error!("Fetching blocks {}-{} failed {} times", from, to, attempt);
+                            failure = Some(e);
                             break;
                         }
+                    };
+
+                    // Inserts are idempotent, so transfers past a gap are stored
+                    // right away, but the cursor only moves up to the gap
+                    let previous_watermark = sent_watermark;
+                    let end_block = watermark.complete(from, to);
+                    sent_watermark = end_block;
+
+                    // Balances must be applied in block order, so transfers past
+                    // the gap stay unfinalized until it is filled. The batch that
+                    // fills it finalizes everything it makes contiguous.
+                    for transfer in transfers.iter_mut().filter(|t| t.block_number > end_block) {
+                        transfer.is_finalized = false;
+                    }
+                    let finalized_block = self.finalized_block.load(Ordering::Acquire);
+                    let finalize_range = (end_block > previous_watermark && previous_watermark < finalized_block)
+                        .then(|| (previous_watermark + 1, end_block.min(finalized_block)));
+
+                    // Send batch to insertion worker, also without transfers
+                    // so the range is recorded as scanned
+                    let batch = TransferBatch {
+                        transfers,
+                        to_block: to,
+                        end_block,
+                        replace_range,
+                        finalize_range,
+                        fetches: vec![fetch],
+                    };
+
+                    if tx.send(batch).await.is_err() {
+                        warn!("Insertion worker has stopped, exiting...");
+                        break;
                     }
-
-                    next_block_to_process = to + 1;
                 }
             }
         }
 
         // Close channel and wait for insertion worker to finish
         drop(tx);
-        insertion_handle.await??;
+        insertion_handle
+            .await
+            .context("The insertion worker panicked")
+            .map_err(IndexerError::Other)?
+            .map_err(IndexerError::from_anyhow)?;
+        if let Some(handle) = finality_handle {
+            handle.abort();
+        }
+        if let Some(e) = failure {
+            return Err(e);
+        }
+
+        if let Some(tracker) = final_finality_tracker {
+            let last_processed = *last_processed_rx.borrow();
+            info!("Performing final finality update...");
+            tracker
+                .update_finality(last_processed, false)
+                .await
+                .map_err(IndexerError::from_anyhow)?;
+            tracker
+                .write_checkpoints(last_processed)
+                .await
+                .map_err(IndexerError::from_anyhow)?;
+        }
+
+        if let Some(handle) = notifier_handle {
+            match self.mode {
+                // The insertion worker dropped the notifier, the sender exits
+                // once the queued notifications are delivered
+                IndexerMode::Once => handle
+                    .await
+                    .context("The notifier panicked")
+                    .map_err(IndexerError::Other)?,
+                // Undelivered notifications stay pending and are sent on the next start
+                IndexerMode::Follow => handle.abort(),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the gaps `query coverage --repair` queued and hand their
+    /// transfers to the insertion worker, leaving the cursor where it is. The
+    /// worker takes each range off the queue as it records it scanned, so a
+    /// range that fails stays queued for the next start. Queued blocks past
+    /// the cursor are left to the scan.
+    async fn scan_repairs(
+        &self,
+        tx: &mpsc::Sender<TransferBatch>,
+        last_processed_block: u64,
+    ) -> Result<()> {
+        let repairs =
+            ScannedRangeRepository::new(&self.db.conn, &self.contract_address).repairs()?;
+        if repairs.is_empty() {
+            return Ok(());
+        }
+        info!("Scanning {} queued gaps", repairs.len());
+
+        for (from, to) in repairs {
+            let to = to.min(last_processed_block);
+            let mut chunk_from = from;
+            while chunk_from <= to {
+                let chunk_to = (chunk_from + self.batch_size - 1).min(to);
+                let start = Instant::now();
+                let (logs, _, rpc_url) = match self
+                    .log_source
+                    .get_logs(&self.client, chunk_from, chunk_to)
+                    .await
+                {
+                    Ok(fetched) => fetched,
+                    Err(e) => {
+                        warn!(
+                            "Scanning queued gap {}-{} failed, it stays queued: {}",
+                            chunk_from, to, e
+                        );
+                        break;
+                    }
+                };
+                let rpc_url = rpc_url.to_string();
+
+                let batch = TransferBatch {
+                    transfers: self.decode_transfers(&logs, &rpc_url)?,
+                    to_block: chunk_to,
+                    end_block: last_processed_block,
+                    replace_range: None,
+                    finalize_range: None,
+                    fetches: vec![RangeFetch {
+                        from: chunk_from,
+                        to: chunk_to,
+                        log_count: logs.len() as u64,
+                        rpc_url,
+                        elapsed: start.elapsed(),
+                    }],
+                };
+                if tx.send(batch).await.is_err() {
+                    return Err(IndexerError::Other(anyhow::anyhow!(
+                        "Insertion worker stopped while scanning queued gaps"
+                    )));
+                }
+                chunk_from = chunk_to + 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// With `PARALLEL_SEGMENTS` above 1, split the blocks from the cursor to
+    /// the chain's finalized block into that many segments. Ranges an earlier
+    /// run scanned past the cursor are left out and marked complete in
+    /// `watermark`, so every segment resumes where it stopped. None when
+    /// there's one segment or nothing historical left to fetch.
+    async fn plan_segments(
+        &self,
+        watermark: &mut Watermark,
+        latest_block: u64,
+    ) -> Result<Option<HistoricalSegments>> {
+        if self.parallel_segments < 2 {
+            return Ok(None);
+        }
+        let from = watermark.get() + 1;
+        let to = self.client.get_finalized_block().await?.min(latest_block);
+        if to < from {
+            return Ok(None);
+        }
+
+        let scanned: Vec<_> = ScannedRangeRepository::new(&self.db.conn, &self.contract_address)
+            .ranges()?
+            .into_iter()
+            .filter(|&(_, scanned_to)| scanned_to >= from)
+            .map(|(scanned_from, scanned_to)| (scanned_from, scanned_to.min(to)))
+            .filter(|&(scanned_from, _)| scanned_from <= to)
+            .collect();
+        let segments = HistoricalSegments::new(from, to, self.parallel_segments, &scanned);
+        if segments.is_empty() {
+            return Ok(None);
+        }
+        for &(scanned_from, scanned_to) in &scanned {
+            watermark.complete(scanned_from, scanned_to);
+        }
+
+        let already_scanned: u64 = scanned.iter().map(|(from, to)| to - from + 1).sum();
+        info!(
+            "Fetching blocks {}-{} in {} segments ({} blocks already scanned)",
+            from,
+            to,
+            segments.len(),
+            already_scanned
+        );
+        for (index, bounds) in segments.bounds().into_iter().enumerate() {
+            if let Some((segment_from, segment_to)) = bounds {
+                info!(
+                    "Segment {}: blocks {}-{}",
+                    index + 1,
+                    segment_from,
+                    segment_to
+                );
+            }
+        }
+        Ok(Some(segments))
+    }
+
+    /// Fire one attempt at fetching the logs of `from..=to`, plus the hashes
+    /// around the range near the head. The range and attempt come back with
+    /// the result so a failure can be fired again. Retries wait
+    /// `rate_limit_delay_ms` longer per earlier attempt.
+    fn fetch_range(
+        &self,
+        from: u64,
+        to: u64,
+        attempt: u32,
+    ) -> impl Future<Output = (u64, u64, u32, Result<FetchedRange>)> + use<C> {
+        // Clone what we need for the async task
+        let client = self.client.clone();
+        let log_source = self.log_source.clone();
+        let finalized_block = self.finalized_block.clone();
+        let retry_delay =
+            Duration::from_millis(self.rate_limit_delay_ms * u64::from(attempt.saturating_sub(1)));
+
+        async move {
+            if !retry_delay.is_zero() {
+                tokio::time::sleep(retry_delay).await;
+            }
+
+            let result = async {
+                let start = Instant::now();
+                let (logs, splits, rpc_url) = log_source
+                    .get_logs(&client, from, to)
+                    .await
+                    .inspect_err(|e| {
+                        if let LogsError::TooManyResults { .. } = e {
+                            error!("{} even after splitting down to a single block", e);
+                        }
+                    })?;
+                let elapsed = start.elapsed();
+                let rpc_url = rpc_url.to_string();
+
+                // Near the head, also fetch the hashes around the batch
+                // to check it extends the blocks already processed
+                let hashes = if to > finalized_block.load(Ordering::Acquire) {
+                    Some((
+                        client.get_block_hash(from - 1).await?,
+                        client.get_block_hash(to).await?,
+                    ))
+                } else {
+                    None
+                };
+
+                Ok(FetchedRange {
+                    logs,
+                    splits,
+                    elapsed,
+                    rpc_url,
+                    hashes,
+                })
+            }
+            .await;
+
+            (from, to, attempt, result)
+        }
+    }
+
+    /// The chain head to start scanning towards, retried like a failed range
+    /// since nothing can be fetched without it
+    async fn initial_latest_block(&self) -> Result<u64> {
+        let mut attempt = 1;
+        loop {
+            match self.client.get_consensus_latest_block().await {
+                Ok(latest) => return Ok(latest),
+                Err(e) if attempt < self.range_max_attempts => {
+                    warn!(
+                        "Fetching the latest block failed (attempt {} of {}), retrying: {:#}",
+                        attempt, self.range_max_attempts, e
+                    );
+                    tokio::time::sleep(Duration::from_millis(
+                        self.rate_limit_delay_ms * u64::from(attempt),
+                    ))
+                    .await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Fetch the chain head, falling back to the cached value on error. The head
+    /// never moves backwards so a lagging provider can't make us think we're
+    /// caught up.
+    async fn refresh_latest_block(&self, cached: u64) -> u64 {
+        match self.client.get_consensus_latest_block().await {
+            Ok(latest) if latest >= cached => latest,
+            Ok(latest) => {
+                debug!(
+                    "Provider reported head {} behind cached head {}, keeping cached",
+                    latest, cached
+                );
+                cached
+            }
+            Err(e) => {
+                warn!("Failed to refresh latest block, keeping {}: {}", cached, e);
+                cached
+            }
+        }
+    }
+
+    /// Check every RPC provider against `EXPECTED_CHAIN_ID`, or the chain the
+    /// database was indexed on, and return the verified chain id. Providers
+    /// that don't answer are skipped, one on another chain stops the indexer.
+    async fn verify_chain_id(&self) -> Result<u64> {
+        let stored = TokenRepository::new(&self.db.conn).get_chain_id(&self.contract_address)?;
+
+        let mut reference = match (self.expected_chain_id, stored) {
+            (Some(expected), Some(stored)) if expected != stored => {
+                return Err(IndexerError::Other(anyhow::anyhow!(
+                    "EXPECTED_CHAIN_ID is {expected} but the database holds data from chain {stored}"
+                )));
+            }
+            (Some(expected), _) => Some((expected, "EXPECTED_CHAIN_ID".to_string())),
+            (None, Some(stored)) => Some((stored, "the chain in the database".to_string())),
+            (None, None) => None,
+        };
+
+        let mut verified = None;
+        let mut last_failure = None;
+        for (url, result) in self.client.get_provider_chain_ids().await {
+            let chain_id = match result {
+                Ok(chain_id) => chain_id,
+                Err(e) => {
+                    warn!("Could not verify the chain id of RPC provider {url}: {e:#}");
+                    last_failure = Some(e);
+                    continue;
+                }
+            };
+            match &reference {
+                Some((expected, source)) if *expected != chain_id => {
+                    return Err(IndexerError::Other(anyhow::anyhow!(
+                        "RPC provider {url} is on chain {chain_id}, expected chain {expected} ({source})"
+                    )));
+                }
+                Some(_) => {}
+                None => reference = Some((chain_id, format!("reported by {url}"))),
+            }
+            verified = Some(chain_id);
+        }
+
+        // Every provider failed when none verified, the last one's error says how
+        let chain_id = verified.ok_or_else(|| {
+            last_failure.unwrap_or_else(|| {
+                IndexerError::Other(anyhow::anyhow!("No RPC provider returned its chain id"))
+            })
+        })?;
+        info!("Chain id {chain_id} verified");
+        Ok(chain_id)
+    }
+
+    /// First block that differs from what was processed, found by walking the
+    /// recorded hashes back from `mismatched` until one still matches the chain.
+    /// Falls back to the block after the last finalized one. The recorded
+    /// hashes from it on are left for the caller to drop.
+    async fn find_fork_block(&self, mismatched: u64) -> Result<u64> {
+        let mut fork_block = self.finalized_block.load(Ordering::Acquire) + 1;
+
+        for (block_number, hash) in self.recent_blocks.before(mismatched) {
+            if self.client.get_block_hash(block_number).await? == hash {
+                fork_block = block_number + 1;
+                break;
+            }
+        }
+
+        Ok(fork_block.min(mismatched))
+    }
+
+    /// Fill in the start block of each configured contract that leaves it
+    /// out, from its deployment. Searches run once, their results are stored.
+    async fn resolve_token_segments(&mut self) -> Result<()> {
+        if self.token_segments.is_empty() {
+            return Ok(());
+        }
+
+        let token_repo = TokenRepository::new(&self.db.conn);
+        let mut segments = self.token_segments.clone();
+        let mut previous_end = None;
+        for (index, segment) in segments.iter_mut().enumerate() {
+            if segment.from_block.is_none() {
+                let stored =
+                    token_repo.get_segment_start(&self.contract_address, &segment.address)?;
+                let from_block = match (stored, self.deployment_block) {
+                    (Some(block), _) => block,
+                    (None, Some(block)) if index == 0 => block,
+                    (None, _) => {
+                        info!(
+                            "Finding deployment block for contract {:?}",
+                            segment.address
+                        );
+                        let latest_block = self.client.get_latest_block().await?;
+                        let search_repo = DeploymentSearchRepository::new(&self.db.conn);
+                        // The previous contract's end is the likeliest spot
+                        let mut hints =
+                            token_repo.get_other_deployment_blocks(&self.contract_address)?;
+                        hints.extend(previous_end.map(|end: u64| end + 1));
+                        hints.extend(self.deployment_search_hint);
+                        let block = find_deployment_block(
+                            &self.client,
+                            &search_repo,
+                            segment.address,
+                            latest_block,
+                            &hints,
+                        )
+                        .await
+                        .map_err(IndexerError::from_anyhow)?;
+                        token_repo.save_segment_start(
+                            &self.contract_address,
+                            &segment.address,
+                            block,
+                        )?;
+                        search_repo.clear(&segment.address)?;
+                        block
+                    }
+                };
+                segment.from_block = Some(from_block);
+            }
+            previous_end = segment.to_block;
+        }
 
+        for segment in &segments {
+            info!(
+                "Indexing contract {:?} from block {} to {}",
+                segment.address,
+                segment.from_block.unwrap_or_default(),
+                segment
+                    .to_block
+                    .map_or_else(|| "the chain head".to_string(), |to| to.to_string())
+            );
+        }
+        self.log_source = LogSource::segmented(segments, self.log_source.topics().to_vec())
+            .map_err(IndexerError::Other)?;
         Ok(())
     }
 
@@ -197,19 +929,57 @@ impl Scanner {
         let token_repo = TokenRepository::new(&self.db.conn);
         if let Some(block) = token_repo.get_deployment_block(&self.contract_address)? {
             info!("Using cached deployment block: {}", block);
+
+            // Tokens indexed by older versions have no metadata, and the
+            // formatters can't scale values without decimals
+            if token_repo
+                .get_token_decimals(&self.contract_address)?
+                .is_none()
+            {
+                warn!("Token decimals are missing, refreshing metadata");
+                self.refresh_token_metadata().await?;
+            }
+
             return Ok(block);
         }
 
-        info!(
-            "Finding deployment block for contract {:?}",
-            self.contract_address
-        );
-        let latest_block = self.client.get_latest_block().await?;
-        let deployment_block =
-            find_deployment_block(&self.client, self.contract_address, latest_block).await?;
+        let deployment_block = match self.deployment_block.or(self.log_source.start_block()) {
+            Some(block) => {
+                info!("Using configured deployment block: {}", block);
+                block
+            }
+            None => {
+                info!(
+                    "Finding deployment block for contract {:?}",
+                    self.contract_address
+                );
+                let latest_block = self.client.get_latest_block().await?;
+                let search_repo = DeploymentSearchRepository::new(&self.db.conn);
+                // Tokens from the same factory tend to be deployed close to
+                // each other, so the other tokens' blocks make good split points
+                let mut hints = TokenRepository::new(&self.db.conn)
+                    .get_other_deployment_blocks(&self.contract_address)?;
+                hints.extend(self.deployment_search_hint);
+                find_deployment_block(
+                    &self.client,
+                    &search_repo,
+                    self.contract_address,
+                    latest_block,
+                    &hints,
+                )
+                .await
+                .map_err(IndexerError::from_anyhow)?
+            }
+        };
 
         // Fetch token metadata
-        let metadata = fetch_token_metadata(&self.client, self.contract_address).await?;
+        let metadata = fetch_token_metadata(
+            &self.client,
+            self.log_source.current_contract(),
+            self.token_standard,
+        )
+        .await
+        .map_err(IndexerError::from_anyhow)?;
 
         let token = Token {
             address: self.contract_address,
@@ -223,189 +993,269 @@ impl Scanner {
 
         let token_repo = TokenRepository::new(&self.db.conn);
         token_repo.insert(&token)?;
+
+        // The token row now holds the result, the search state is no longer needed
+        DeploymentSearchRepository::new(&self.db.conn).clear(&self.contract_address)?;
+
         Ok(deployment_block)
     }
 
-    async fn update_finality(&self, is_initial: bool) -> Result<()> {
+    /// Re-fetch name, symbol and decimals and store them on the token row.
+    /// Fields the contract doesn't answer for keep their stored value.
+    pub async fn refresh_token_metadata(&self) -> Result<()> {
         let token_repo = TokenRepository::new(&self.db.conn);
-        let transfer_repo = TransferRepository::new(&self.db.conn);
-
-        let last_finalized = token_repo
-            .get_last_processed_finalized_block(&self.contract_address)?
-            .unwrap_or(0);
-
-        let last_processed = token_repo
-            .get_last_processed_block(&self.contract_address)?
-            .unwrap_or(0);
-
-        let current_finalized = self.client.get_finalized_block().await?;
-
-        // Always process blocks only up to min(last_processed, current_finalized)
-        let target_finalized = current_finalized.min(last_processed);
-
-        if target_finalized <= last_finalized {
-            // No new blocks to finalize
-            return Ok(());
+        if token_repo.get_token(&self.contract_address)?.is_none() {
+            return Err(IndexerError::NotFound(format!(
+                "Token {:?} has not been indexed yet",
+                self.contract_address
+            )));
         }
 
+        let metadata = fetch_token_metadata(
+            &self.client,
+            self.log_source.current_contract(),
+            self.token_standard,
+        )
+        .await
+        .map_err(IndexerError::from_anyhow)?;
+        token_repo.update_metadata(
+            &self.contract_address,
+            metadata.name.as_deref(),
+            metadata.symbol.as_deref(),
+            metadata.decimals,
+        )?;
+
         info!(
-            "Updating finality from block {} to {} (chain finalized: {}, last processed: {})",
-            last_finalized + 1,
-            target_finalized,
-            current_finalized,
-            last_processed
+            "Refreshed token metadata: name={:?}, symbol={:?}, decimals={:?}",
+            metadata.name, metadata.symbol, metadata.decimals
         );
+        Ok(())
+    }
 
-        let mut current_from = last_finalized + 1;
+    pub fn should_mark_as_finalized(&self, block_number: u64) -> bool {
+        block_number <= self.finalized_block.load(Ordering::Acquire)
+    }
 
-        while current_from <= target_finalized {
-            let current_to = (current_from + self.batch_size - 1).min(target_finalized);
+    /// Decode a fetched range into transfers, first checking that it extends
+    /// the blocks already processed and re-fetching from the fork point when
+    /// it doesn't. Returns the transfers, what was fetched and the processed
+    /// blocks the transfers replace. The recorded hashes only move once all
+    /// of it succeeded, so a range fetched again after failing here runs into
+    /// the same reorg.
+    async fn check_and_decode(
+        &mut self,
+        from: u64,
+        to: u64,
+        fetched: FetchedRange,
+    ) -> Result<(Vec<Transfer>, RangeFetch, Option<(u64, u64)>)> {
+        let FetchedRange {
+            mut logs,
+            elapsed,
+            mut rpc_url,
+            hashes,
+            ..
+        } = fetched;
+        let mut fetch = RangeFetch {
+            from,
+            to,
+            log_count: 0,
+            rpc_url: String::new(),
+            elapsed,
+        };
 
-            let chain_logs = self
-                .client
-                .get_logs(
-                    current_from,
-                    current_to,
-                    self.contract_address,
-                    self.transfer_topic,
-                )
+        let mut replace_range = None;
+        if let Some((parent_hash, _)) = hashes
+            && self
+                .recent_blocks
+                .get(from - 1)
+                .is_some_and(|known| known != parent_hash)
+        {
+            let fork_block = self.find_fork_block(from - 1).await?;
+            warn!(
+                "Reorg detected at the head: block {} changed hash, re-fetching blocks {}-{}",
+                from - 1,
+                fork_block,
+                to
+            );
+            let refetch_start = Instant::now();
+            let (refetched, _, url) = self
+                .log_source
+                .get_logs(&self.client, fork_block, to)
                 .await?;
+            logs = refetched;
+            rpc_url = url.to_string();
+            fetch.from = fork_block;
+            fetch.elapsed += refetch_start.elapsed();
+            replace_range = Some((fork_block, from - 1));
+        }
 
-            let stored_block_hashes =
-                transfer_repo.get_block_hashes_in_range(current_from, current_to)?;
-
-            let mut chain_block_hashes: std::collections::HashMap<u64, B256> =
-                std::collections::HashMap::new();
-            let mut chain_transfers: Vec<Transfer> = Vec::new();
-
-            for log in &chain_logs {
-                match decode_transfer_event(log) {
-                    Ok(event) => {
-                        let block_num = log.block_number.unwrap();
-                        let block_hash = log.block_hash.unwrap();
-
-                        chain_block_hashes.insert(block_num, block_hash);
-
-                        chain_transfers.push(Transfer {
-                            transaction_hash: log.transaction_hash.unwrap(),
-                            log_index: log.log_index.unwrap(),
-                            token_address: self.contract_address,
-                            from_address: event.from,
-                            to_address: event.to,
-                            value: event.value,
-                            block_number: block_num,
-                            block_hash,
-                            is_finalized: true,
-                        });
-                    }
-                    Err(e) => {
-                        anyhow::bail!("Failed to decode transfer event: {}", e);
-                    }
-                }
-            }
-
-            // Find blocks that need reprocessing
-            let mut blocks_to_reprocess = std::collections::HashSet::new();
+        let transfers = self.decode_transfers(&logs, &rpc_url)?;
 
-            // Check each block that has transfers on chain
-            for (block_num, chain_hash) in &chain_block_hashes {
-                match stored_block_hashes.get(block_num) {
-                    Some(stored_hash) if stored_hash != chain_hash => {
-                        warn!(
-                            "Reorg detected at block {}! Hash mismatch: chain {:?} vs stored {:?}",
-                            block_num, chain_hash, stored_hash
-                        );
-                        blocks_to_reprocess.insert(*block_num);
-                    }
-                    None => {
-                        warn!("Block {} has transfers on chain but not in DB", block_num);
-                        blocks_to_reprocess.insert(*block_num);
-                    }
-                    _ => {} // Hashes match, all good
-                }
+        if let Some((_, end_hash)) = hashes {
+            if let Some((fork_block, _)) = replace_range {
+                self.recent_blocks.truncate_from(fork_block);
             }
+            self.recent_blocks
+                .record(to, end_hash, self.finalized_block.load(Ordering::Acquire));
+        }
+        fetch.log_count = logs.len() as u64;
+        fetch.rpc_url = rpc_url;
 
-            // Check for blocks that exist in DB but not on chain
-            for block_num in stored_block_hashes.keys() {
-                if !chain_block_hashes.contains_key(block_num) {
-                    warn!("Block {} has transfers in DB but not on chain", block_num);
-                    blocks_to_reprocess.insert(*block_num);
-                }
-            }
+        Ok((transfers, fetch, replace_range))
+    }
 
-            let mut transfers_to_insert = Vec::new();
-            for block_num in &blocks_to_reprocess {
-                transfers_to_insert.extend(
-                    chain_transfers
-                        .iter()
-                        .filter(|t| t.block_number == *block_num)
-                        .cloned(),
-                );
-            }
+    /// Decode a batch of logs fetched from `rpc_url` into transfers. The
+    /// finalized watermark is read once for the whole batch, so marking is a
+    /// plain comparison per log.
+    fn decode_transfers(&self, logs: &[Log], rpc_url: &str) -> Result<Vec<Transfer>> {
+        let finalized_block = self.finalized_block.load(Ordering::Acquire);
+        let mut transfers = Vec::with_capacity(logs.len());
+        let failures = DecodeFailureRepository::new(&self.db.conn, &self.contract_address);
+
+        for (index, log) in logs.iter().enumerate() {
+            let Some(position) = self
+                .malformed_logs
+                .position(log, index, rpc_url)
+                .map_err(IndexerError::from_anyhow)?
+            else {
+                continue;
+            };
+            let Some(event) = self
+                .malformed_logs
+                .decode(log, &position, &failures)
+                .map_err(IndexerError::from_anyhow)?
+            else {
+                continue;
+            };
+            transfers.push(Transfer {
+                transaction_hash: position.transaction_hash,
+                log_index: position.log_index,
+                token_address: self.contract_address,
+                from_address: event.from,
+                to_address: event.to,
+                value: event.value,
+                block_number: position.block_number,
+                block_hash: position.block_hash,
+                is_finalized: position.block_number <= finalized_block,
+                token_id: event.token_id,
+                token_amounts: event.token_amounts,
+                event: event.event,
+            });
+        }
 
-            let blocks_to_delete: Vec<u64> = blocks_to_reprocess.into_iter().collect();
-            let (deleted, inserted, finalized) = transfer_repo.process_finality_batch(
-                &blocks_to_delete,
-                &transfers_to_insert,
-                current_from,
-                current_to,
-            )?;
+        Ok(transfers)
+    }
+}
 
-            if deleted > 0 {
-                info!(
-                    "Deleted {} transfers from {} reorged blocks",
-                    deleted,
-                    blocks_to_delete.len()
-                );
-            }
-            if inserted > 0 {
-                info!("Inserted {} transfers during finality update", inserted);
-            }
-            if finalized > 0 {
-                info!(
-                    "Marked {} transfers as finalized in blocks {}-{}",
-                    finalized, current_from, current_to
-                );
-            }
+/// A range's logs and what the scanner needs to process them
+struct FetchedRange {
+    logs: Vec<Log>,
+    /// Times the range was split to fit the provider's result limit
+    splits: u32,
+    elapsed: Duration,
+    rpc_url: String,
+    /// Hash of the block before the range and of its last block, near the head
+    hashes: Option<(B256, B256)>,
+}
 
-            // Apply balance updates - transfers_to_insert are all finalized
-            // and chain_transfers contains all transfers in the range (including those just marked as finalized)
-            if !chain_transfers.is_empty() {
-                let balance_repo = BalanceRepository::new(&self.db.conn);
-                balance_repo.apply_transfers(&chain_transfers)?;
-                info!(
-                    "Applied balance updates for {} finalized transfers",
-                    chain_transfers.len()
-                );
-            }
+/// Resolves once shutdown is requested or its sender is dropped
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
 
-            current_from = current_to + 1;
-        }
+/// The error the finality worker stopped on, its handle then taken out so it
+/// isn't polled again. Pending while it runs, without one, and for good once
+/// it stops without an error, which it only does after the insertion worker.
+async fn finality_failed(handle: &mut Option<JoinHandle<anyhow::Result<()>>>) -> anyhow::Error {
+    let Some(running) = handle.as_mut() else {
+        return std::future::pending().await;
+    };
+    let result = running.await;
+    *handle = None;
+    match result {
+        Ok(Ok(())) => std::future::pending().await,
+        Ok(Err(e)) => e,
+        Err(e) => anyhow::Error::from(e).context("The finality worker panicked"),
+    }
+}
 
-        // Update last_processed_finalized_block at the very end in a separate transaction
-        // For initial update, we can set to current_finalized since no concurrent processes
-        // For runtime updates, only update to target_finalized to avoid race conditions
-        let update_to = if is_initial {
-            current_finalized
-        } else {
-            target_finalized
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::ReplayRpc;
+    use crate::testutil::{
+        fresh_database, remove_database, synthetic_transfers, temp_database_path, token_address,
+        transfer_log,
+    };
+
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("eth-indexer-scanner-{}-{name}", std::process::id()))
+    }
 
-        token_repo.update_last_processed_finalized_block(&self.contract_address, update_to)?;
-        info!("Updated last processed finalized block to {}", update_to);
+    fn cleanup(name: &str) {
+        let _ = std::fs::remove_dir_all(scratch_dir(name));
+        remove_database(&temp_database_path(&format!("scanner-{name}")));
+    }
 
-        Ok(())
+    /// A scanner over a fresh database, whose client fails every request
+    fn scanner(name: &str) -> Scanner<ReplayRpc> {
+        let dir = scratch_dir(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "erc20_contract_address = \"{}\"
+json_rpc_url = \"http://unused.example\"
+",
+                token_address()
+            ),
+        )
+        .unwrap();
+        let config = Config::from_file(&config_path).unwrap();
+        let db = fresh_database(&temp_database_path(&format!("scanner-{name}"))).unwrap();
+        Scanner::new(ReplayRpc::load(&dir).unwrap(), db, &config).unwrap()
     }
 
-    pub fn should_mark_as_finalized(&self, block_number: u64) -> bool {
-        let token_repo = TokenRepository::new(&self.db.conn);
+    #[test]
+    fn marking_a_batch_as_finalized_reads_nothing_from_the_database() {
+        let scanner = scanner("marking");
+        // 10 transfers per block, so blocks 1..=1000
+        let expected = synthetic_transfers(0..10_000, 100);
+        let logs: Vec<Log> = expected.iter().map(transfer_log).collect();
+        scanner.finalized_block.store(600, Ordering::Release);
+
+        // With every table gone, a read while decoding would fail
+        let tables: Vec<String> = scanner
+            .db
+            .conn
+            .prepare(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+            )
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        for table in tables {
+            scanner
+                .db
+                .conn
+                .execute_batch(&format!("DROP TABLE \"{table}\""))
+                .unwrap();
+        }
 
-        if let Ok(Some(last_finalized)) =
-            token_repo.get_last_processed_finalized_block(&self.contract_address)
-        {
-            block_number <= last_finalized
-        } else {
-            false
+        let transfers = scanner
+            .decode_transfers(&logs, "http://unused.example")
+            .unwrap();
+        assert_eq!(transfers.len(), 10_000);
+        for (transfer, expected) in transfers.iter().zip(&expected) {
+            assert_eq!(transfer.transaction_hash, expected.transaction_hash);
+            assert_eq!(transfer.value, expected.value);
+            assert_eq!(transfer.is_finalized, transfer.block_number <= 600);
         }
+        assert_eq!(transfers.iter().filter(|t| t.is_finalized).count(), 6000);
+
+        cleanup("marking");
     }
 }
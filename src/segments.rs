@@ -0,0 +1,101 @@
+use crate::repository::gaps_between;
+use std::collections::VecDeque;
+
+/// The historical part of a sync split into disjoint segments of about the
+/// same number of blocks, each fetched in block order from its own position,
+/// so the providers work on different parts of the history at once instead of
+/// all on the same window. Ranges an earlier run already scanned are left out,
+/// so a restart resumes every segment where it stopped.
+pub struct HistoricalSegments {
+    /// Blocks each segment still has to fetch, in order
+    segments: Vec<VecDeque<(u64, u64)>>,
+    /// Segment the next range is taken from
+    next: usize,
+    /// Last block of the historical range
+    end: u64,
+}
+
+impl HistoricalSegments {
+    /// Split the blocks of `from..=to` outside `scanned`, which must be sorted
+    /// and disjoint, into at most `count` segments
+    pub fn new(from: u64, to: u64, count: usize, scanned: &[(u64, u64)]) -> Self {
+        let gaps = gaps_between(scanned, from, to);
+        let total: u64 = gaps.iter().map(|(from, to)| to - from + 1).sum();
+        let per_segment = total.div_ceil(count.max(1) as u64).max(1);
+
+        let mut segments = Vec::new();
+        let mut current = VecDeque::new();
+        let mut current_blocks = 0;
+        for (mut gap_from, gap_to) in gaps {
+            while gap_from <= gap_to {
+                let take = (gap_to - gap_from + 1).min(per_segment - current_blocks);
+                current.push_back((gap_from, gap_from + take - 1));
+                current_blocks += take;
+                gap_from += take;
+                if current_blocks == per_segment {
+                    segments.push(std::mem::take(&mut current));
+                    current_blocks = 0;
+                }
+            }
+        }
+        if !current.is_empty() {
+            segments.push(current);
+        }
+
+        Self {
+            segments,
+            next: 0,
+            end: to,
+        }
+    }
+
+    /// Last block of the historical range
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Whether every range has been handed out
+    pub fn is_empty(&self) -> bool {
+        self.segments.iter().all(VecDeque::is_empty)
+    }
+
+    /// Blocks left to hand out, by segment
+    pub fn remaining_blocks(&self) -> Vec<u64> {
+        self.segments
+            .iter()
+            .map(|ranges| ranges.iter().map(|(from, to)| to - from + 1).sum())
+            .collect()
+    }
+
+    /// First and last block of each segment, None for one already handed out
+    pub fn bounds(&self) -> Vec<Option<(u64, u64)>> {
+        self.segments
+            .iter()
+            .map(|ranges| Some((ranges.front()?.0, ranges.back()?.1)))
+            .collect()
+    }
+
+    /// The next range of at most `batch_size` blocks, from each segment with
+    /// blocks left in turn. None once every range has been handed out.
+    pub fn next_range(&mut self, batch_size: u64) -> Option<(u64, u64)> {
+        for _ in 0..self.segments.len() {
+            let index = self.next;
+            self.next = (self.next + 1) % self.segments.len();
+
+            let ranges = &mut self.segments[index];
+            let Some((from, to)) = ranges.pop_front() else {
+                continue;
+            };
+            let range_to = (from + batch_size.max(1) - 1).min(to);
+            if range_to < to {
+                ranges.push_front((range_to + 1, to));
+            }
+            return Some((from, range_to));
+        }
+        None
+    }
+}
@@ -0,0 +1,151 @@
+use crate::config::{Config, TokenConfig};
+use crate::error::is_fatal;
+use crate::indexer::Indexer;
+use crate::repository::{Database, TokenRepository};
+use anyhow::Result;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::{Instrument, error, info, info_span, warn};
+
+/// Longest wait between restarts of a failing token. A run that lasted at
+/// least this long starts the count of failures in a row again.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(600);
+
+/// Runs an indexer for every token of `TOKENS_CONFIG`, each with its own
+/// database and RPC client, restarting the ones that fail without stopping
+/// the others. Log lines carry the token they are about.
+pub struct Supervisor {
+    base: Config,
+    tokens: Vec<TokenConfig>,
+}
+
+impl Supervisor {
+    /// `base` holds the settings shared by every token
+    pub fn new(base: Config, tokens: Vec<TokenConfig>) -> Self {
+        Self { base, tokens }
+    }
+
+    /// Run every token until it finishes, which only happens in once mode,
+    /// is given up on after `TOKEN_MAX_RESTARTS` failures in a row or a fatal
+    /// one, or `shutdown` turns true. A shutdown waits for every token to
+    /// commit the batches it already fetched. Fails naming the tokens given
+    /// up on.
+    pub async fn run(self, shutdown: watch::Receiver<bool>) -> Result<()> {
+        // Checked up front, a token with bad settings would only fail again
+        let mut configs = Vec::with_capacity(self.tokens.len());
+        for token in &self.tokens {
+            configs.push(token.apply(&self.base)?);
+        }
+
+        let runtime = tokio::runtime::Handle::current();
+        let mut tasks = JoinSet::new();
+        for (token, config) in self.tokens.iter().zip(configs) {
+            let tag = token_tag(token, &config).await;
+            let span = info_span!("token", symbol = %tag);
+            let shutdown = shutdown.clone();
+            let runtime = runtime.clone();
+            // An indexer's futures hold its database connection and aren't
+            // Send, so each token is supervised on a blocking thread of its
+            // own, the way `Indexer::start` runs the scanner
+            tasks.spawn_blocking(move || {
+                let result = runtime.block_on(supervise(config, shutdown).instrument(span));
+                (tag, result)
+            });
+        }
+
+        let total = tasks.len();
+        let mut failed = Vec::new();
+        while let Some(joined) = tasks.join_next().await {
+            let (tag, result) = joined?;
+            if let Err(e) = result {
+                failed.push(format!("{tag}: {e:#}"));
+            }
+        }
+        if !failed.is_empty() {
+            anyhow::bail!(
+                "{} of {} tokens failed:\n  - {}",
+                failed.len(),
+                total,
+                failed.join("\n  - ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Run one token's indexer, restarting it after a failure with a delay
+/// doubling for each failure in a row. A fatal failure isn't restarted.
+async fn supervise(config: Config, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let base_delay = Duration::from_secs(config.token_restart_delay_secs);
+    let mut failures = 0u32;
+    loop {
+        info!(
+            "Indexing {:?} into {}",
+            config.erc20_contract_address, config.database_url
+        );
+        let started = Instant::now();
+        let error = match run_token(&config, shutdown.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(e) if *shutdown.borrow() => return Err(e),
+            // A restart would run into it again
+            Err(e) if is_fatal(&e) => {
+                error!("Giving up, restarting can't get past this: {:#}", e);
+                return Err(e);
+            }
+            Err(e) => e,
+        };
+
+        if started.elapsed() >= MAX_RESTART_DELAY {
+            failures = 0;
+        }
+        failures += 1;
+        if config.token_max_restarts > 0 && failures > config.token_max_restarts {
+            error!(
+                "Giving up after {} failures in a row: {:#}",
+                failures, error
+            );
+            return Err(error);
+        }
+
+        let delay = base_delay
+            .saturating_mul(2u32.saturating_pow(failures - 1))
+            .min(MAX_RESTART_DELAY);
+        warn!(
+            "Indexer failed ({} in a row), restarting in {}s: {:#}",
+            failures,
+            delay.as_secs(),
+            error
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.wait_for(|stop| *stop) => return Ok(()),
+        }
+    }
+}
+
+async fn run_token(config: &Config, shutdown: watch::Receiver<bool>) -> Result<()> {
+    let indexer = Indexer::new(config.clone())?;
+    let handle = indexer.start().await?;
+    handle.wait_or_shutdown(shutdown).await
+}
+
+/// The configured symbol, else the one stored by an earlier run, else the
+/// contract address
+pub async fn token_tag(token: &TokenConfig, config: &Config) -> String {
+    let symbol = match &token.symbol {
+        Some(symbol) => Some(symbol.clone()),
+        None => stored_symbol(token, config).await,
+    };
+    symbol.unwrap_or_else(|| format!("{:?}", token.address))
+}
+
+async fn stored_symbol(token: &TokenConfig, config: &Config) -> Option<String> {
+    let db = Database::open_read_only(&config.database_url, config.sqlite_options()).ok()?;
+    let readers = db.read_pool();
+    let reader = readers.get().await.ok()?;
+    TokenRepository::new(&reader)
+        .get_token(&token.address)
+        .ok()??
+        .symbol
+}
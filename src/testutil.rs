@@ -0,0 +1,363 @@
+//! Deterministic synthetic data for benchmarks and tests. The simple
+//! generators derive every row from its index, so large tables can be built
+//! in chunks; [`DataGenerator`] produces a realistic stream from a seed.
+use crate::events::Transfer as TransferEvent;
+use crate::repository::{
+    BalanceRepository, Database, ScannedRangeRepository, Token, TokenRepository, Transfer,
+    TransferRepository,
+};
+use alloy::rpc::types::Log;
+use alloy::sol_types::SolEvent;
+use alloy_primitives::{Address, B256, U256};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Transfers written per batch by the populate helpers
+const POPULATE_BATCH: u64 = 10_000;
+
+/// Metadata of the token `populate_test_db` creates
+pub const TEST_TOKEN_NAME: &str = "Test Token";
+pub const TEST_TOKEN_SYMBOL: &str = "TEST";
+pub const TEST_TOKEN_DECIMALS: u8 = 6;
+
+/// Token the synthetic rows belong to
+pub fn token_address() -> Address {
+    Address::repeat_byte(0xaa)
+}
+
+/// The `index`th synthetic holder, never the zero address
+pub fn holder(index: u64) -> Address {
+    let mut bytes = [0u8; 20];
+    bytes[12..].copy_from_slice(&(index + 1).to_be_bytes());
+    Address::from(bytes)
+}
+
+/// splitmix64, a cheap well-mixed hash of the index
+pub fn mix(index: u64) -> u64 {
+    let mut z = index.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Transfers `range` of an endless sequence between `holders` addresses, ten
+/// per block, all finalized. The first `holders` transfers mint each holder
+/// its starting balance, after which every sender has been funded and no
+/// balance goes negative.
+pub fn synthetic_transfers(range: Range<u64>, holders: u64) -> Vec<Transfer> {
+    range
+        .map(|i| {
+            let (from, to, value) = if i < holders {
+                (Address::ZERO, holder(i), U256::from(1_000_000_000u64))
+            } else {
+                (
+                    holder(i % holders),
+                    holder(mix(i) % holders),
+                    U256::from(1 + i % 100),
+                )
+            };
+            let block_number = 1 + i / 10;
+            Transfer {
+                transaction_hash: B256::from(U256::from(i)),
+                log_index: i % 10,
+                token_address: token_address(),
+                from_address: from,
+                to_address: to,
+                value,
+                block_number,
+                block_hash: B256::from(U256::from(block_number)),
+                is_finalized: true,
+                token_id: None,
+                token_amounts: Vec::new(),
+                event: None,
+            }
+        })
+        .collect()
+}
+
+/// The ERC20 Transfer log a provider would return for `transfer`
+pub fn transfer_log(transfer: &Transfer) -> Log {
+    let event = TransferEvent {
+        from: transfer.from_address,
+        to: transfer.to_address,
+        value: transfer.value,
+    };
+    Log {
+        inner: alloy_primitives::Log {
+            address: transfer.token_address,
+            data: event.encode_log_data(),
+        },
+        block_hash: Some(transfer.block_hash),
+        block_number: Some(transfer.block_number),
+        block_timestamp: None,
+        transaction_hash: Some(transfer.transaction_hash),
+        transaction_index: Some(0),
+        log_index: Some(transfer.log_index),
+        removed: false,
+    }
+}
+
+/// Balances of `count` holders, spread over twelve orders of magnitude
+pub fn synthetic_balances(count: u64) -> HashMap<Address, U256> {
+    (0..count)
+        .map(|i| {
+            let hash = mix(i);
+            let balance =
+                U256::from(1 + hash % 1_000_000) * U256::from(10u64).pow(U256::from(hash % 12));
+            (holder(i), balance)
+        })
+        .collect()
+}
+
+/// Path of a scratch database in the system temp directory
+pub fn temp_database_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("eth-indexer-{name}.db"))
+}
+
+/// Delete a database file along with its WAL and shared-memory files
+pub fn remove_database(path: &Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+}
+
+/// A fresh database at `path`, replacing any there, with the row of
+/// `token_address()` the repositories expect
+pub fn fresh_database(path: &Path) -> Result<Database> {
+    remove_database(path);
+    let db = Database::new(&path.to_string_lossy())?;
+    TokenRepository::new(&db.conn).insert(&Token {
+        address: token_address(),
+        deployment_block: 0,
+        last_processed_block: Some(0),
+        last_processed_finalized_block: Some(0),
+        name: None,
+        symbol: None,
+        decimals: Some(6),
+    })?;
+    Ok(db)
+}
+
+/// The database at `path` when an earlier call finished populating it,
+/// otherwise a fresh one filled by `populate`, for tables too slow to build
+/// on every run. Delete the file to rebuild it, e.g. after changing the
+/// generators.
+pub fn cached_database(
+    path: &Path,
+    populate: impl FnOnce(&Database) -> Result<()>,
+) -> Result<Database> {
+    let complete = PathBuf::from(format!("{}.complete", path.display()));
+    if path.exists() && complete.exists() {
+        return Ok(Database::new(&path.to_string_lossy())?);
+    }
+    let _ = std::fs::remove_file(&complete);
+    let db = fresh_database(path)?;
+    populate(&db)?;
+    std::fs::write(&complete, "")?;
+    Ok(db)
+}
+
+/// Insert the first `count` synthetic transfers between `holders` addresses,
+/// with their balances
+pub fn populate_transfers(db: &Database, count: u64, holders: u64) -> Result<()> {
+    let repo = TransferRepository::new(&db.conn, &token_address());
+    let mut start = 0;
+    while start < count {
+        let end = (start + POPULATE_BATCH).min(count);
+        repo.insert_batch_multi_row(&synthetic_transfers(start..end, holders))?;
+        start = end;
+    }
+    Ok(())
+}
+
+/// Write `count` synthetic balances, without the transfers behind them
+pub fn populate_balances(db: &Database, count: u64) -> Result<()> {
+    Ok(BalanceRepository::new(&db.conn, &token_address())
+        .update_balances_batch(&synthetic_balances(count))?)
+}
+
+/// A deterministic pseudo-random stream of finalized transfers of
+/// `token_address()`. Senders and recipients are drawn from a Zipf
+/// distribution over the holders, so a few addresses take part in most
+/// transfers as on a real token. A sender never spends more than it holds: a
+/// transfer it can't afford is minted to it instead. The same seed and
+/// settings always give the same stream.
+#[derive(Debug, Clone)]
+pub struct DataGenerator {
+    seed: u64,
+    /// Random draws made so far
+    draws: u64,
+    /// Cumulative Zipf weights by holder rank, normalized to end at 1
+    popularity: Vec<f64>,
+    min_value: u64,
+    max_value: u64,
+    transfers_per_block: u64,
+    start_block: u64,
+    generated: u64,
+    balances: HashMap<Address, U256>,
+}
+
+impl DataGenerator {
+    /// 10,000 holders with a Zipf exponent of 1, values of 1 to 10^12 base
+    /// units and ten transfers per block from block 1
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            draws: 0,
+            popularity: Vec::new(),
+            min_value: 1,
+            max_value: 1_000_000_000_000,
+            transfers_per_block: 10,
+            start_block: 1,
+            generated: 0,
+            balances: HashMap::new(),
+        }
+        .with_holders(10_000, 1.0)
+    }
+
+    /// Draw addresses from `holders` holders, the one ranked `k` with weight
+    /// `1 / k^exponent`. 0 makes them equally likely.
+    pub fn with_holders(mut self, holders: u64, exponent: f64) -> Self {
+        let mut total = 0.0;
+        self.popularity = (1..=holders.max(1))
+            .map(|rank| {
+                total += 1.0 / (rank as f64).powf(exponent);
+                total
+            })
+            .collect();
+        for weight in &mut self.popularity {
+            *weight /= total;
+        }
+        self
+    }
+
+    /// Transfer values, in base units, drawn uniformly from `min..=max`
+    pub fn with_value_range(mut self, min: u64, max: u64) -> Self {
+        self.min_value = min.min(max);
+        self.max_value = max.max(min);
+        self
+    }
+
+    pub fn with_transfers_per_block(mut self, transfers: u64) -> Self {
+        self.transfers_per_block = transfers.max(1);
+        self
+    }
+
+    pub fn with_start_block(mut self, block: u64) -> Self {
+        self.start_block = block;
+        self
+    }
+
+    /// Balances after every transfer generated so far
+    pub fn balances(&self) -> &HashMap<Address, U256> {
+        &self.balances
+    }
+
+    /// Block of the last transfer generated, None before the first
+    pub fn last_block(&self) -> Option<u64> {
+        (self.generated > 0).then(|| self.block_of(self.generated - 1))
+    }
+
+    /// The next `count` transfers
+    pub fn next_batch(&mut self, count: u64) -> Vec<Transfer> {
+        self.by_ref().take(count as usize).collect()
+    }
+
+    fn block_of(&self, index: u64) -> u64 {
+        self.start_block + index / self.transfers_per_block
+    }
+
+    fn draw(&mut self) -> u64 {
+        self.draws += 1;
+        mix(self.seed ^ mix(self.draws))
+    }
+
+    fn draw_holder(&mut self) -> Address {
+        let point = (self.draw() >> 11) as f64 / (1u64 << 53) as f64;
+        let rank = self
+            .popularity
+            .partition_point(|&weight| weight < point)
+            .min(self.popularity.len() - 1);
+        holder(rank as u64)
+    }
+}
+
+impl Iterator for DataGenerator {
+    type Item = Transfer;
+
+    fn next(&mut self) -> Option<Transfer> {
+        let index = self.generated;
+        self.generated += 1;
+
+        let mut from = self.draw_holder();
+        let to = self.draw_holder();
+        let span = self.max_value - self.min_value;
+        let value = U256::from(self.min_value + self.draw() % span.saturating_add(1));
+
+        let held = self.balances.get(&from).copied().unwrap_or_default();
+        if held < value {
+            from = Address::ZERO;
+        } else {
+            self.balances.insert(from, held - value);
+        }
+        *self.balances.entry(to).or_default() += value;
+
+        let block_number = self.block_of(index);
+        Some(Transfer {
+            transaction_hash: B256::from((U256::from(self.seed) << 128) | U256::from(index)),
+            log_index: index % self.transfers_per_block,
+            token_address: token_address(),
+            from_address: from,
+            to_address: to,
+            value,
+            block_number,
+            block_hash: B256::from(U256::from(mix(block_number))),
+            is_finalized: true,
+            token_id: None,
+            token_amounts: Vec::new(),
+            event: None,
+        })
+    }
+}
+
+/// Write `n_transfers` transfers of `generator` to a fresh database at
+/// `path`, with the token's metadata, balances, cursors and scanned range as
+/// if the indexer had synced and finalized them
+pub fn populate_with(
+    path: &Path,
+    mut generator: DataGenerator,
+    n_transfers: u64,
+) -> Result<Database> {
+    let db = fresh_database(path)?;
+    let tokens = TokenRepository::new(&db.conn);
+    tokens.update_metadata(
+        &token_address(),
+        Some(TEST_TOKEN_NAME),
+        Some(TEST_TOKEN_SYMBOL),
+        Some(TEST_TOKEN_DECIMALS),
+    )?;
+
+    let transfers = TransferRepository::new(&db.conn, &token_address());
+    let mut remaining = n_transfers;
+    while remaining > 0 {
+        let batch = generator.next_batch(remaining.min(POPULATE_BATCH));
+        transfers.insert_batch_multi_row(&batch)?;
+        remaining -= batch.len() as u64;
+    }
+
+    let last_block = generator.last_block().unwrap_or(0);
+    tokens.update_last_processed_block(&token_address(), last_block)?;
+    tokens.update_last_processed_finalized_block(&token_address(), last_block)?;
+    tokens.raise_last_balance_applied_block(&token_address(), last_block)?;
+    if last_block > 0 {
+        ScannedRangeRepository::new(&db.conn, &token_address()).record(&[(1, last_block)])?;
+    }
+    Ok(db)
+}
+
+/// `populate_with` a default `DataGenerator` seeded with 0
+pub fn populate_test_db(path: &Path, n_transfers: u64) -> Result<Database> {
+    populate_with(path, DataGenerator::new(0), n_transfers)
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+
+/// Fetched block ranges complete out of order. This tracks the last block
+/// below which every range is complete, the only safe value for the stored
+/// cursor: a restart resumes right after it and refetches any gap.
+pub struct Watermark {
+    /// Every block up to here is complete
+    contiguous: u64,
+    /// Completed ranges past a gap, last block by first block
+    ahead: BTreeMap<u64, u64>,
+}
+
+impl Watermark {
+    pub fn new(last_complete_block: u64) -> Self {
+        Self {
+            contiguous: last_complete_block,
+            ahead: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.contiguous
+    }
+
+    /// Mark blocks `from..=to` complete and return the new watermark
+    pub fn complete(&mut self, from: u64, to: u64) -> u64 {
+        if to <= self.contiguous {
+            return self.contiguous;
+        }
+
+        let end = self.ahead.entry(from).or_insert(to);
+        *end = (*end).max(to);
+
+        // Absorb every range that now touches the contiguous prefix
+        while let Some(entry) = self.ahead.first_entry() {
+            if *entry.key() > self.contiguous + 1 {
+                break;
+            }
+            self.contiguous = self.contiguous.max(entry.remove());
+        }
+
+        self.contiguous
+    }
+
+    /// Completed ranges waiting for a gap below them to be filled
+    pub fn ranges_ahead(&self) -> usize {
+        self.ahead.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_order_completions_wait_for_the_gap() {
+        let mut watermark = Watermark::new(0);
+
+        assert_eq!(watermark.complete(21, 30), 0);
+        assert_eq!(watermark.complete(31, 40), 0);
+        assert_eq!(watermark.ranges_ahead(), 2);
+
+        assert_eq!(watermark.complete(1, 10), 10);
+        assert_eq!(watermark.complete(11, 20), 40);
+        assert_eq!(watermark.ranges_ahead(), 0);
+    }
+
+    #[test]
+    fn ranges_below_or_across_the_watermark_are_absorbed() {
+        let mut watermark = Watermark::new(100);
+
+        // Refetched after a restart, already covered
+        assert_eq!(watermark.complete(50, 80), 100);
+        assert_eq!(watermark.complete(90, 100), 100);
+        assert_eq!(watermark.ranges_ahead(), 0);
+
+        // Overlapping the watermark moves it to the range's end
+        assert_eq!(watermark.complete(95, 120), 120);
+
+        // A range completed twice, the second time split differently
+        assert_eq!(watermark.complete(131, 140), 120);
+        assert_eq!(watermark.complete(131, 150), 120);
+        assert_eq!(watermark.ranges_ahead(), 1);
+        assert_eq!(watermark.complete(121, 135), 150);
+    }
+
+    #[test]
+    fn a_failed_gap_holds_the_watermark_until_refetched() {
+        let mut watermark = Watermark::new(0);
+        watermark.complete(1, 10);
+        // 11..=20 failed, the later ranges still come in
+        watermark.complete(21, 30);
+        watermark.complete(31, 40);
+        assert_eq!(watermark.get(), 10);
+
+        // The retry is split in two, completing in reverse order
+        assert_eq!(watermark.complete(16, 20), 10);
+        assert_eq!(watermark.complete(11, 15), 40);
+        assert_eq!(watermark.ranges_ahead(), 0);
+    }
+}
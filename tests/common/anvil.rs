@@ -0,0 +1,143 @@
+//! A local anvil node for the end-to-end tests. The binary comes from the
+//! `ANVIL` environment variable, or `anvil` on the PATH.
+use alloy::providers::{Provider, RootProvider};
+use alloy_primitives::{Address, Bytes};
+use serde_json::{Value, json};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// Hand-assembled ERC-20 init code, see `tests/fixtures/minimal_erc20.asm`
+pub fn minimal_erc20() -> Bytes {
+    include_str!("../fixtures/minimal_erc20.hex")
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+/// A running anvil node, killed on drop. Every transaction is mined in a
+/// block of its own and the finalized block trails the head by two.
+pub struct Anvil {
+    child: Child,
+    url: String,
+    provider: RootProvider,
+}
+
+impl Anvil {
+    pub async fn spawn() -> Self {
+        // Free a port for anvil, which reports no port of its own choosing
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let binary = std::env::var("ANVIL").unwrap_or_else(|_| "anvil".to_string());
+        let child = Command::new(&binary)
+            .args(["--port", &port.to_string(), "--slots-in-an-epoch", "1"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| {
+                panic!("Failed to start {binary}, install Foundry or set ANVIL: {e}")
+            });
+
+        let url = format!("http://127.0.0.1:{port}");
+        let anvil = Self {
+            child,
+            provider: RootProvider::new_http(url.parse().unwrap()),
+            url,
+        };
+        for _ in 0..100 {
+            if anvil.provider.get_block_number().await.is_ok() {
+                return anvil;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("anvil didn't start listening on port {port}");
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn request(&self, method: &'static str, params: Value) -> Value {
+        self.provider
+            .raw_request(method.into(), params)
+            .await
+            .unwrap_or_else(|e| panic!("{method} failed: {e}"))
+    }
+
+    /// The node's unlocked, funded accounts
+    pub async fn accounts(&self) -> Vec<Address> {
+        serde_json::from_value(self.request("eth_accounts", json!([])).await).unwrap()
+    }
+
+    pub async fn block_number(&self) -> u64 {
+        self.provider.get_block_number().await.unwrap()
+    }
+
+    /// Send a transaction from an unlocked account and wait for it to succeed,
+    /// returning its receipt
+    pub async fn send(&self, from: Address, to: Option<Address>, data: Bytes) -> Value {
+        let hash = self
+            .request(
+                "eth_sendTransaction",
+                json!([{ "from": from, "to": to, "data": data, "gas": "0x100000" }]),
+            )
+            .await;
+        for _ in 0..100 {
+            let receipt = self
+                .request("eth_getTransactionReceipt", json!([hash]))
+                .await;
+            if !receipt.is_null() {
+                assert_eq!(receipt["status"], "0x1", "transaction reverted: {receipt}");
+                return receipt;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("transaction {hash} was never mined");
+    }
+
+    /// Deploy `init_code`, returning the contract and the block it was
+    /// deployed in
+    pub async fn deploy(&self, from: Address, init_code: Bytes) -> (Address, u64) {
+        let receipt = self.send(from, None, init_code).await;
+        let address = receipt["contractAddress"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        (address, block_of(&receipt))
+    }
+
+    /// Mine `blocks` empty blocks
+    pub async fn mine(&self, blocks: u64) {
+        self.request("anvil_mine", json!([format!("{blocks:#x}")]))
+            .await;
+    }
+
+    /// Remember the chain as it is now, for `revert`
+    pub async fn snapshot(&self) -> Value {
+        self.request("evm_snapshot", json!([])).await
+    }
+
+    /// Drop every block mined since `snapshot`, so the blocks mined next
+    /// replace them
+    pub async fn revert(&self, snapshot: Value) {
+        let reverted = self.request("evm_revert", json!([snapshot])).await;
+        assert_eq!(reverted, json!(true), "evm_revert failed");
+    }
+}
+
+impl Drop for Anvil {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Block a transaction receipt was mined in
+pub fn block_of(receipt: &Value) -> u64 {
+    let block = receipt["blockNumber"].as_str().unwrap();
+    u64::from_str_radix(block.trim_start_matches("0x"), 16).unwrap()
+}
@@ -0,0 +1,640 @@
+//! Scripted chains served over JSON-RPC, so the integration tests run the
+//! real `RpcClient` and scanner without a node or network access
+#![allow(dead_code)]
+
+pub mod anvil;
+
+use alloy_primitives::{Address, B256, U256, address, b256};
+use eth_indexer::IndexerBuilder;
+use eth_indexer::repository::{Database, SqliteOptions};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+pub const TOKEN: Address = address!("a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48");
+pub const CHAIN_ID: u64 = 1;
+
+const TRANSFER_TOPIC: B256 =
+    b256!("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef");
+
+/// An address made from a small number, easier to tell apart in failures
+pub fn holder(index: u8) -> Address {
+    Address::repeat_byte(index)
+}
+
+/// A chain with a head, a finalized block and the token's transfers. Every
+/// provider started from it serves the same chain, so changes show up on all
+/// of them at once.
+#[derive(Clone)]
+pub struct MockChain {
+    state: Arc<Mutex<ChainState>>,
+}
+
+struct ChainState {
+    head: u64,
+    finalized: u64,
+    /// Bumped by every reorg, the blocks it rewrote carry it in their hash
+    generations: HashMap<u64, u64>,
+    next_generation: u64,
+    transfers: Vec<MockTransfer>,
+    next_transaction: u64,
+    max_logs_per_request: Option<usize>,
+    /// First block the token's code is in
+    deployed_at: u64,
+}
+
+#[derive(Clone)]
+struct MockTransfer {
+    block: u64,
+    log_index: u64,
+    transaction_hash: B256,
+    from: Address,
+    to: Address,
+    value: U256,
+}
+
+impl MockChain {
+    pub fn new(head: u64, finalized: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ChainState {
+                head,
+                finalized,
+                generations: HashMap::new(),
+                next_generation: 1,
+                transfers: Vec::new(),
+                next_transaction: 1,
+                max_logs_per_request: None,
+                deployed_at: 0,
+            })),
+        }
+    }
+
+    /// Add a transfer of the token in `block`, after the block's other ones
+    pub fn transfer(&self, block: u64, from: Address, to: Address, value: u64) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        let log_index = state.transfers.iter().filter(|t| t.block == block).count() as u64;
+        let transaction_hash =
+            B256::from(U256::from(state.next_transaction) | (U256::from(1) << 255));
+        state.next_transaction += 1;
+        state.transfers.push(MockTransfer {
+            block,
+            log_index,
+            transaction_hash,
+            from,
+            to,
+            value: U256::from(value),
+        });
+        self
+    }
+
+    /// Mint `value` to `to` in `block`
+    pub fn mint(&self, block: u64, to: Address, value: u64) -> &Self {
+        self.transfer(block, Address::ZERO, to, value)
+    }
+
+    /// Move the head, and the finalized block with it when given
+    pub fn advance(&self, head: u64, finalized: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.head = head;
+        if let Some(finalized) = finalized {
+            state.finalized = finalized;
+        }
+    }
+
+    /// Replace the last `depth` blocks up to the head with new ones: their
+    /// hashes change and their transfers are dropped, add the new chain's with
+    /// [`MockChain::transfer`]
+    pub fn reorg(&self, depth: u64) {
+        let mut state = self.state.lock().unwrap();
+        let first = state.head + 1 - depth;
+        let generation = state.next_generation;
+        state.next_generation += 1;
+        for block in first..=state.head {
+            state.generations.insert(block, generation);
+        }
+        state.transfers.retain(|t| t.block < first);
+    }
+
+    /// Answer `eth_getLogs` with a too-many-results error above `max` logs
+    pub fn limit_logs_per_request(&self, max: usize) {
+        self.state.lock().unwrap().max_logs_per_request = Some(max);
+    }
+
+    /// Give the token no code below `block`
+    pub fn deploy_at(&self, block: u64) {
+        self.state.lock().unwrap().deployed_at = block;
+    }
+
+    pub fn head(&self) -> u64 {
+        self.state.lock().unwrap().head
+    }
+
+    /// Serve the chain on a new local port
+    pub async fn provider(&self) -> MockProvider {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let faults = Arc::new(Mutex::new(Faults::default()));
+        let stats = Arc::new(ProviderStats::default());
+
+        let chain = self.clone();
+        let (server_faults, server_stats) = (faults.clone(), stats.clone());
+        let server = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_connection(
+                    stream,
+                    chain.clone(),
+                    server_faults.clone(),
+                    server_stats.clone(),
+                ));
+            }
+        });
+
+        MockProvider {
+            url,
+            faults,
+            stats,
+            server,
+        }
+    }
+
+    fn respond(&self, request: &Value, faults: &Mutex<Faults>, stats: &ProviderStats) -> Value {
+        let id = request["id"].clone();
+        let method = request["method"].as_str().unwrap_or_default();
+        let params = &request["params"];
+        stats.record(method);
+        if method == "eth_getLogs" {
+            let from = parse_quantity(params[0]["fromBlock"].as_str().unwrap_or("0x0"));
+            stats.log_from_blocks.lock().unwrap().push(from);
+        }
+        if method == "eth_getBlockByNumber"
+            && let Some(number) = params[0].as_str().filter(|n| n.starts_with("0x"))
+        {
+            stats
+                .header_blocks
+                .lock()
+                .unwrap()
+                .push(parse_quantity(number));
+        }
+
+        let injected = {
+            let mut faults = faults.lock().unwrap();
+            let from = (method == "eth_getLogs")
+                .then(|| parse_quantity(params[0]["fromBlock"].as_str().unwrap_or("0x0")));
+            let remaining = match from {
+                Some(from) if faults.fail_logs_from.contains_key(&from) => {
+                    faults.fail_logs_from.get_mut(&from)
+                }
+                _ => faults.fail_next.get_mut(method),
+            };
+            match remaining {
+                Some(remaining) if *remaining > 0 => {
+                    *remaining -= 1;
+                    true
+                }
+                _ => false,
+            }
+        };
+        let result = if injected {
+            Err((-32000, "injected failure".to_string()))
+        } else {
+            self.call(method, params)
+        };
+
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+                json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+            }
+        }
+    }
+
+    fn call(&self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        let state = self.state.lock().unwrap();
+        match method {
+            "eth_chainId" => Ok(json!(format!("{CHAIN_ID:#x}"))),
+            "eth_blockNumber" => Ok(json!(format!("{:#x}", state.head))),
+            "eth_getBlockByNumber" => {
+                let number = match params[0].as_str().unwrap_or_default() {
+                    "latest" | "pending" => state.head,
+                    "finalized" | "safe" => state.finalized,
+                    "earliest" => 0,
+                    number => parse_quantity(number),
+                };
+                if number > state.head {
+                    return Ok(Value::Null);
+                }
+                Ok(state.block(number))
+            }
+            "eth_getCode" => {
+                let block = match params[1].as_str().unwrap_or("latest") {
+                    "latest" | "pending" | "finalized" | "safe" => state.head,
+                    "earliest" => 0,
+                    number => parse_quantity(number),
+                };
+                Ok(json!(if block < state.deployed_at {
+                    "0x"
+                } else {
+                    "0x6080"
+                }))
+            }
+            // No metadata, the indexer stores it as unknown
+            "eth_call" => Ok(json!("0x")),
+            "eth_getLogs" => {
+                let filter = &params[0];
+                let from = parse_quantity(filter["fromBlock"].as_str().unwrap_or("0x0"));
+                let to = filter["toBlock"]
+                    .as_str()
+                    .map_or(state.head, parse_quantity)
+                    .min(state.head);
+                let wants_token = match &filter["address"] {
+                    Value::String(address) => address.parse::<Address>() == Ok(TOKEN),
+                    Value::Array(addresses) => addresses
+                        .iter()
+                        .any(|a| a.as_str().and_then(|a| a.parse().ok()) == Some(TOKEN)),
+                    _ => true,
+                };
+
+                let logs: Vec<Value> = state
+                    .transfers
+                    .iter()
+                    .filter(|t| wants_token && (from..=to).contains(&t.block))
+                    .map(|t| state.log(t))
+                    .collect();
+                if let Some(max) = state.max_logs_per_request
+                    && logs.len() > max
+                {
+                    return Err((-32005, format!("query returned more than {max} results")));
+                }
+                Ok(Value::Array(logs))
+            }
+            _ => Err((
+                -32601,
+                format!("the method {method} does not exist/is not available"),
+            )),
+        }
+    }
+}
+
+impl ChainState {
+    fn block_hash(&self, number: u64) -> B256 {
+        let generation = self.generations.get(&number).copied().unwrap_or(0);
+        B256::from((U256::from(generation) << 64) | U256::from(number) | (U256::from(1) << 200))
+    }
+
+    fn block(&self, number: u64) -> Value {
+        let zero_hash = B256::ZERO.to_string();
+        let parent_hash = if number == 0 {
+            B256::ZERO
+        } else {
+            self.block_hash(number - 1)
+        };
+        json!({
+            "hash": self.block_hash(number).to_string(),
+            "parentHash": parent_hash.to_string(),
+            "sha3Uncles": zero_hash,
+            "miner": Address::ZERO.to_string(),
+            "stateRoot": zero_hash,
+            "transactionsRoot": zero_hash,
+            "receiptsRoot": zero_hash,
+            "logsBloom": format!("0x{}", "00".repeat(256)),
+            "difficulty": "0x0",
+            "number": format!("{number:#x}"),
+            "gasLimit": "0x1c9c380",
+            "gasUsed": "0x0",
+            "timestamp": format!("{:#x}", 1_700_000_000 + number * 12),
+            "extraData": "0x",
+            "mixHash": zero_hash,
+            "nonce": "0x0000000000000000",
+            "baseFeePerGas": "0x1",
+            "uncles": [],
+            "transactions": [],
+            "size": "0x1",
+        })
+    }
+
+    fn log(&self, transfer: &MockTransfer) -> Value {
+        json!({
+            "address": TOKEN.to_string(),
+            "topics": [
+                TRANSFER_TOPIC.to_string(),
+                transfer.from.into_word().to_string(),
+                transfer.to.into_word().to_string(),
+            ],
+            "data": B256::from(transfer.value).to_string(),
+            "blockNumber": format!("{:#x}", transfer.block),
+            "blockHash": self.block_hash(transfer.block).to_string(),
+            "transactionHash": transfer.transaction_hash.to_string(),
+            "transactionIndex": "0x0",
+            "logIndex": format!("{:#x}", transfer.log_index),
+            "removed": false,
+        })
+    }
+}
+
+fn parse_quantity(quantity: &str) -> u64 {
+    u64::from_str_radix(quantity.trim_start_matches("0x"), 16).unwrap_or_default()
+}
+
+/// What a provider does wrong, set by the test while it runs
+#[derive(Default)]
+struct Faults {
+    /// Answer every request with HTTP 503
+    down: bool,
+    /// Fail this many more calls of a method with a JSON-RPC error
+    fail_next: HashMap<String, u32>,
+    /// Fail this many more `eth_getLogs` calls starting at a block
+    fail_logs_from: HashMap<u64, u32>,
+    /// Hold every response this long, to run into the request timeout
+    delay: Option<Duration>,
+}
+
+#[derive(Default)]
+struct ProviderStats {
+    requests: Mutex<HashMap<String, usize>>,
+    errors: AtomicUsize,
+    /// HTTP headers of the last request, by lowercase name
+    last_headers: Mutex<HashMap<String, String>>,
+    /// `fromBlock` of every `eth_getLogs` call, in the order received
+    log_from_blocks: Mutex<Vec<u64>>,
+    /// Block numbers asked for with `eth_getBlockByNumber`, tags left out
+    header_blocks: Mutex<Vec<u64>>,
+}
+
+impl ProviderStats {
+    fn record(&self, method: &str) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry(method.to_string())
+            .or_default() += 1;
+    }
+}
+
+/// One JSON-RPC endpoint serving a [`MockChain`]. The server stops when it's
+/// dropped.
+pub struct MockProvider {
+    url: String,
+    faults: Arc<Mutex<Faults>>,
+    stats: Arc<ProviderStats>,
+    server: JoinHandle<()>,
+}
+
+impl MockProvider {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn set_down(&self, down: bool) {
+        self.faults.lock().unwrap().down = down;
+    }
+
+    pub fn fail_next(&self, method: &str, times: u32) {
+        self.faults
+            .lock()
+            .unwrap()
+            .fail_next
+            .insert(method.to_string(), times);
+    }
+
+    /// Fail the next `times` calls of `eth_getLogs` whose range starts at `block`
+    pub fn fail_logs_from(&self, block: u64, times: u32) {
+        self.faults
+            .lock()
+            .unwrap()
+            .fail_logs_from
+            .insert(block, times);
+    }
+
+    pub fn set_delay(&self, delay: Option<Duration>) {
+        self.faults.lock().unwrap().delay = delay;
+    }
+
+    /// Calls of `method` received, failed ones included
+    pub fn requests(&self, method: &str) -> usize {
+        self.stats
+            .requests
+            .lock()
+            .unwrap()
+            .get(method)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// JSON-RPC errors sent, injected ones included
+    pub fn errors(&self) -> usize {
+        self.stats.errors.load(Ordering::Relaxed)
+    }
+
+    /// Lowest `fromBlock` of the `eth_getLogs` calls received
+    pub fn first_log_block(&self) -> Option<u64> {
+        self.stats
+            .log_from_blocks
+            .lock()
+            .unwrap()
+            .iter()
+            .min()
+            .copied()
+    }
+
+    /// Block numbers asked for with `eth_getBlockByNumber` so far, in the
+    /// order received
+    pub fn header_blocks(&self) -> Vec<u64> {
+        self.stats.header_blocks.lock().unwrap().clone()
+    }
+
+    /// Value of an HTTP header of the last request received
+    pub fn last_header(&self, name: &str) -> Option<String> {
+        self.stats
+            .last_headers
+            .lock()
+            .unwrap()
+            .get(&name.to_lowercase())
+            .cloned()
+    }
+}
+
+impl Drop for MockProvider {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// A minimal HTTP/1.1 server: keep-alive POSTs with a Content-Length, which
+/// is all alloy's HTTP transport sends
+async fn serve_connection(
+    stream: TcpStream,
+    chain: MockChain,
+    faults: Arc<Mutex<Faults>>,
+    stats: Arc<ProviderStats>,
+) {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let mut content_length = 0;
+        let mut headers = HashMap::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match stream.read_line(&mut line).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+                headers.insert(name.to_lowercase(), value.trim().to_string());
+            }
+        }
+        *stats.last_headers.lock().unwrap() = headers;
+
+        let mut body = vec![0; content_length];
+        if stream.read_exact(&mut body).await.is_err() {
+            return;
+        }
+
+        let (down, delay) = {
+            let faults = faults.lock().unwrap();
+            (faults.down, faults.delay)
+        };
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        let (status, response) = if down {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+            ("503 Service Unavailable", "provider down".to_string())
+        } else {
+            let response = match serde_json::from_slice::<Value>(&body) {
+                Ok(Value::Array(requests)) => Value::Array(
+                    requests
+                        .iter()
+                        .map(|request| chain.respond(request, &faults, &stats))
+                        .collect(),
+                ),
+                Ok(request) => chain.respond(&request, &faults, &stats),
+                Err(_) => json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": { "code": -32700, "message": "parse error" }
+                }),
+            };
+            ("200 OK", response.to_string())
+        };
+
+        let reply = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{response}",
+            response.len()
+        );
+        if stream.get_mut().write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// A database file under the temp directory, removed with its config file
+/// when dropped
+pub struct TempDatabase {
+    path: PathBuf,
+}
+
+impl TempDatabase {
+    pub fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("eth-indexer-test-{}-{name}.db", std::process::id()));
+        let database = Self { path };
+        database.remove_files();
+        database
+    }
+
+    fn remove_files(&self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", self.path.display()));
+        }
+        let _ = std::fs::remove_file(self.path.with_extension("toml"));
+    }
+}
+
+impl Deref for TempDatabase {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        self.remove_files();
+    }
+}
+
+/// An indexer of `contract` writing to `database`, with settings that keep a
+/// test quick: small ranges, no rate limiting, short retries and one-second
+/// polling. `extra` is appended to the config file.
+pub fn fast_indexer_builder(database: &Path, contract: Address, extra: &str) -> IndexerBuilder {
+    let config_path = database.with_extension("toml");
+    let config = format!(
+        "batch_size = 10
+min_batch_size = 1
+rate_limit_delay_ms = 0
+rpc_requests_per_second = 10000
+rpc_max_retries = 2
+rpc_retry_backoff_ms = 10
+rpc_retry_max_delay_ms = 50
+request_timeout_secs = 2
+light_request_timeout_secs = 1
+block_time_secs = 1
+finality_update_interval_secs = 1
+progress_interval_secs = 3600
+{extra}"
+    );
+    std::fs::write(&config_path, config).unwrap();
+
+    eth_indexer::Indexer::builder()
+        .config_file(config_path)
+        .contract(contract)
+        .database(format!("sqlite:{}", database.display()))
+}
+
+/// `fast_indexer_builder` of `TOKEN` over mock providers
+pub fn indexer_builder(
+    database: &Path,
+    providers: &[&MockProvider],
+    extra: &str,
+) -> IndexerBuilder {
+    // The scan starts after the deployment block
+    let mut builder = fast_indexer_builder(database, TOKEN, extra).start_block(0);
+    for provider in providers {
+        builder = builder.rpc_url(provider.url());
+    }
+    builder
+}
+
+/// Read-only view of a database an indexer wrote
+pub fn open(database: &Path) -> Database {
+    Database::open_read_only(database.to_str().unwrap(), SqliteOptions::default()).unwrap()
+}
+
+/// Poll `condition` until it holds, failing the test after `timeout`
+pub async fn wait_until(timeout: Duration, what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while !condition() {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "timed out waiting for {what}"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
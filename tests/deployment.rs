@@ -0,0 +1,129 @@
+//! The deployment block search against a mock chain
+mod common;
+
+use common::{MockChain, TOKEN, TempDatabase, indexer_builder};
+use eth_indexer::deployment::find_deployment_block;
+use eth_indexer::repository::{Database, DeploymentSearchRepository};
+use eth_indexer::rpc::RpcClient;
+
+const HEAD: u64 = 1_000_000;
+const DEPLOYED_AT: u64 = 123_456;
+
+async fn client(database: &TempDatabase, provider: &common::MockProvider) -> RpcClient {
+    let indexer = indexer_builder(database, &[provider], "").build().unwrap();
+    let config = indexer.config();
+    RpcClient::new(&config.json_rpc_urls, config).await.unwrap()
+}
+
+#[tokio::test]
+async fn hints_around_the_deployment_cut_the_code_lookups() {
+    let chain = MockChain::new(HEAD, HEAD - 64);
+    chain.deploy_at(DEPLOYED_AT);
+
+    // Bisecting the whole chain
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("deployment-full");
+    let client_full = client(&database, &provider).await;
+    let db = Database::new(database.to_str().unwrap()).unwrap();
+    let block = find_deployment_block(
+        &client_full,
+        &DeploymentSearchRepository::new(&db.conn),
+        TOKEN,
+        HEAD,
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(block, DEPLOYED_AT);
+    let full = provider.requests("eth_getCode");
+    assert!(full >= 20, "{full} lookups for a million blocks");
+
+    // Other tokens of the factory were deployed just before and after it
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("deployment-hinted");
+    let client_hinted = client(&database, &provider).await;
+    let db = Database::new(database.to_str().unwrap()).unwrap();
+    let block = find_deployment_block(
+        &client_hinted,
+        &DeploymentSearchRepository::new(&db.conn),
+        TOKEN,
+        HEAD,
+        &[5_000, DEPLOYED_AT - 40, DEPLOYED_AT + 60, 900_000],
+    )
+    .await
+    .unwrap();
+    assert_eq!(block, DEPLOYED_AT);
+    let hinted = provider.requests("eth_getCode");
+    assert!(
+        hinted * 2 <= full,
+        "{hinted} lookups with hints, {full} without"
+    );
+}
+
+#[tokio::test]
+async fn a_finished_search_is_not_repeated() {
+    let chain = MockChain::new(HEAD, HEAD - 64);
+    chain.deploy_at(DEPLOYED_AT);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("deployment-repeat");
+    let client = client(&database, &provider).await;
+    let db = Database::new(database.to_str().unwrap()).unwrap();
+    let search_repo = DeploymentSearchRepository::new(&db.conn);
+
+    find_deployment_block(&client, &search_repo, TOKEN, HEAD, &[])
+        .await
+        .unwrap();
+    let first = provider.requests("eth_getCode");
+
+    // The stored range and code lookups answer the second search
+    let block = find_deployment_block(&client, &search_repo, TOKEN, HEAD, &[])
+        .await
+        .unwrap();
+    assert_eq!(block, DEPLOYED_AT);
+    assert_eq!(provider.requests("eth_getCode"), first);
+}
+
+#[tokio::test]
+async fn an_interrupted_search_resumes_from_its_stored_range() {
+    let chain = MockChain::new(HEAD, HEAD - 64);
+    chain.deploy_at(DEPLOYED_AT);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("deployment-resume");
+    let client = client(&database, &provider).await;
+    let db = Database::new(database.to_str().unwrap()).unwrap();
+    let search_repo = DeploymentSearchRepository::new(&db.conn);
+
+    // An earlier run narrowed it to 1024 blocks before it stopped
+    search_repo
+        .save_range(&TOKEN, DEPLOYED_AT - 500, DEPLOYED_AT + 523)
+        .unwrap();
+    let block = find_deployment_block(&client, &search_repo, TOKEN, HEAD, &[])
+        .await
+        .unwrap();
+
+    assert_eq!(block, DEPLOYED_AT);
+    // Ten halvings of the stored range, and no look at the head again
+    assert_eq!(provider.requests("eth_getCode"), 10);
+    assert_eq!(
+        search_repo.get_range(&TOKEN).unwrap(),
+        Some((DEPLOYED_AT, DEPLOYED_AT))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_configured_deployment_block_skips_the_search() {
+    let chain = MockChain::new(100, 90);
+    chain.deploy_at(50);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("deployment-configured");
+
+    let indexer = indexer_builder(&database, &[&provider], "")
+        .start_block(50)
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+
+    assert_eq!(provider.requests("eth_getCode"), 0);
+    assert!(provider.requests("eth_getLogs") > 0);
+}
@@ -0,0 +1,154 @@
+//! The whole pipeline against a local anvil node: deployment detection,
+//! metadata, scanning, finality and reorgs. Needs Foundry's anvil:
+//! `cargo test --features e2e --test e2e`
+#![cfg(feature = "e2e")]
+mod common;
+
+use alloy::sol;
+use alloy::sol_types::SolCall;
+use alloy_primitives::{Address, Bytes, U256};
+use common::anvil::{Anvil, block_of, minimal_erc20};
+use common::{TempDatabase, fast_indexer_builder, open};
+use eth_indexer::repository::{BalanceRepository, TokenRepository, TransferRepository};
+use std::path::Path;
+
+sol! {
+    function mint(address to, uint256 amount);
+    function transfer(address to, uint256 amount);
+}
+
+fn mint_to(to: Address, amount: u64) -> Bytes {
+    mintCall {
+        to,
+        amount: U256::from(amount),
+    }
+    .abi_encode()
+    .into()
+}
+
+fn transfer_to(to: Address, amount: u64) -> Bytes {
+    transferCall {
+        to,
+        amount: U256::from(amount),
+    }
+    .abi_encode()
+    .into()
+}
+
+/// Index `token` up to anvil's head and finalized block, then stop
+async fn index_once(anvil: &Anvil, database: &Path, token: Address) {
+    let indexer = fast_indexer_builder(database, token, "")
+        .rpc_url(anvil.url())
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+}
+
+fn balance(database: &Path, token: Address, holder: Address) -> U256 {
+    let db = open(database);
+    BalanceRepository::new(&db.conn, &token)
+        .get_balance(&holder, true)
+        .unwrap()
+        .balance
+}
+
+fn transfer_count(database: &Path, token: Address, finalized_only: bool) -> usize {
+    let db = open(database);
+    TransferRepository::new(&db.conn, &token)
+        .query_transfers(None, None, None, finalized_only, 1_000, 0)
+        .unwrap()
+        .len()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn indexes_a_deployed_token() {
+    let anvil = Anvil::spawn().await;
+    let accounts = anvil.accounts().await;
+    let (deployer, alice, bob, carol) = (accounts[0], accounts[1], accounts[2], accounts[3]);
+
+    // Some empty blocks first, so the deployment block has to be searched for
+    anvil.mine(20).await;
+    let (token, deployment_block) = anvil.deploy(deployer, minimal_erc20()).await;
+    anvil
+        .send(deployer, Some(token), mint_to(alice, 1_000_000))
+        .await;
+    anvil
+        .send(deployer, Some(token), mint_to(bob, 500_000))
+        .await;
+    anvil
+        .send(alice, Some(token), transfer_to(carol, 250_000))
+        .await;
+    anvil
+        .send(bob, Some(token), transfer_to(alice, 100_000))
+        .await;
+    anvil.send(carol, Some(token), transfer_to(carol, 1)).await;
+    // Finalize every transfer
+    anvil.mine(5).await;
+
+    let database = TempDatabase::new("e2e-sync");
+    index_once(&anvil, &database, token).await;
+
+    let db = open(&database);
+    let stored = TokenRepository::new(&db.conn)
+        .get_token(&token)
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.deployment_block, deployment_block);
+    assert_eq!(stored.name.as_deref(), Some("Test Token"));
+    assert_eq!(stored.symbol.as_deref(), Some("TEST"));
+    assert_eq!(stored.decimals, Some(6));
+    assert_eq!(
+        TokenRepository::new(&db.conn).get_chain_id(&token).unwrap(),
+        Some(31337)
+    );
+
+    assert_eq!(transfer_count(&database, token, true), 5);
+    assert_eq!(balance(&database, token, alice), U256::from(850_000));
+    assert_eq!(balance(&database, token, bob), U256::from(400_000));
+    assert_eq!(balance(&database, token, carol), U256::from(250_000));
+
+    let top: Vec<Address> = BalanceRepository::new(&db.conn, &token)
+        .get_top_holders(10)
+        .unwrap()
+        .into_iter()
+        .map(|holder| holder.address)
+        .collect();
+    assert_eq!(top, vec![alice, bob, carol]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn repairs_a_reverted_block() {
+    let anvil = Anvil::spawn().await;
+    let accounts = anvil.accounts().await;
+    let (deployer, alice, bob, carol) = (accounts[0], accounts[1], accounts[2], accounts[3]);
+
+    let (token, _) = anvil.deploy(deployer, minimal_erc20()).await;
+    anvil
+        .send(deployer, Some(token), mint_to(alice, 1_000))
+        .await;
+    anvil.mine(5).await;
+    let snapshot = anvil.snapshot().await;
+
+    // Indexed while still unfinalized, then dropped from the chain
+    let receipt = anvil.send(alice, Some(token), transfer_to(bob, 300)).await;
+    let reorged_block = block_of(&receipt);
+    let database = TempDatabase::new("e2e-reorg");
+    index_once(&anvil, &database, token).await;
+    assert_eq!(transfer_count(&database, token, false), 2);
+    assert_eq!(transfer_count(&database, token, true), 1);
+
+    // The replacement block holds another transfer
+    anvil.revert(snapshot).await;
+    let receipt = anvil.send(alice, Some(token), transfer_to(carol, 70)).await;
+    assert_eq!(block_of(&receipt), reorged_block);
+    anvil.mine(5).await;
+    index_once(&anvil, &database, token).await;
+
+    assert_eq!(transfer_count(&database, token, true), 2);
+    assert_eq!(transfer_count(&database, token, false), 2);
+    assert_eq!(balance(&database, token, alice), U256::from(930));
+    assert_eq!(balance(&database, token, bob), U256::ZERO);
+    assert_eq!(balance(&database, token, carol), U256::from(70));
+    assert!(anvil.block_number().await > reorged_block);
+}
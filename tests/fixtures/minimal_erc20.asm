@@ -0,0 +1,195 @@
+; Hand-assembled ERC-20 used by the anvil end-to-end tests, deployed from
+; minimal_erc20.hex (the init code below followed by the runtime code).
+;
+; name() "Test Token", symbol() "TEST", decimals() 6, totalSupply(),
+; balanceOf(address), transfer(address,uint256) and mint(address,uint256),
+; which anyone may call. Both transfer and mint emit the standard
+; Transfer(address indexed, address indexed, uint256). A balance is stored at
+; the slot equal to its address, the total supply at slot 2^160. There is no
+; allowance, approve or transferFrom.
+
+; Init code: copy the runtime code to memory and return it
+0000  PUSH2 0x277
+0003  PUSH2 @runtime
+0006  PUSH1 0x0
+0008  CODECOPY
+0009  PUSH2 0x277
+000c  PUSH1 0x0
+000e  RETURN
+      runtime:
+
+; Runtime code, offsets relative to its start
+0000  PUSH1 0x0
+0002  CALLDATALOAD
+0003  PUSH1 0xe0
+0005  SHR
+0006  DUP1
+0007  PUSH4 0x6fdde03
+000c  EQ
+000d  PUSH2 @name
+0010  JUMPI
+0011  DUP1
+0012  PUSH4 0x95d89b41
+0017  EQ
+0018  PUSH2 @symbol
+001b  JUMPI
+001c  DUP1
+001d  PUSH4 0x313ce567
+0022  EQ
+0023  PUSH2 @decimals
+0026  JUMPI
+0027  DUP1
+0028  PUSH4 0x18160ddd
+002d  EQ
+002e  PUSH2 @totalSupply
+0031  JUMPI
+0032  DUP1
+0033  PUSH4 0x70a08231
+0038  EQ
+0039  PUSH2 @balanceOf
+003c  JUMPI
+003d  DUP1
+003e  PUSH4 0xa9059cbb
+0043  EQ
+0044  PUSH2 @transfer
+0047  JUMPI
+0048  DUP1
+0049  PUSH4 0x40c10f19
+004e  EQ
+004f  PUSH2 @mint
+0052  JUMPI
+0053  PUSH1 0x0
+0055  DUP1
+0056  REVERT
+      name:
+0057  JUMPDEST
+0058  PUSH1 0x60
+005a  PUSH2 @name_data
+005d  PUSH1 0x0
+005f  CODECOPY
+0060  PUSH1 0x60
+0062  PUSH1 0x0
+0064  RETURN
+      symbol:
+0065  JUMPDEST
+0066  PUSH1 0x60
+0068  PUSH2 @symbol_data
+006b  PUSH1 0x0
+006d  CODECOPY
+006e  PUSH1 0x60
+0070  PUSH1 0x0
+0072  RETURN
+      decimals:
+0073  JUMPDEST
+0074  PUSH1 0x6
+0076  PUSH1 0x0
+0078  MSTORE
+0079  PUSH1 0x20
+007b  PUSH1 0x0
+007d  RETURN
+      totalSupply:
+007e  JUMPDEST
+007f  PUSH21 0x10000000000000000000000000000000000000000
+0095  SLOAD
+0096  PUSH1 0x0
+0098  MSTORE
+0099  PUSH1 0x20
+009b  PUSH1 0x0
+009d  RETURN
+      balanceOf:
+009e  JUMPDEST
+009f  PUSH1 0x4
+00a1  CALLDATALOAD
+00a2  PUSH20 0xffffffffffffffffffffffffffffffffffffffff
+00b7  AND
+00b8  SLOAD
+00b9  PUSH1 0x0
+00bb  MSTORE
+00bc  PUSH1 0x20
+00be  PUSH1 0x0
+00c0  RETURN
+      transfer:
+00c1  JUMPDEST
+00c2  PUSH1 0x24
+00c4  CALLDATALOAD
+00c5  CALLER
+00c6  SLOAD
+00c7  DUP2
+00c8  DUP2
+00c9  LT
+00ca  PUSH2 @revert
+00cd  JUMPI
+00ce  DUP2
+00cf  SWAP1
+00d0  SUB
+00d1  CALLER
+00d2  SSTORE
+00d3  PUSH1 0x4
+00d5  CALLDATALOAD
+00d6  PUSH20 0xffffffffffffffffffffffffffffffffffffffff
+00eb  AND
+00ec  DUP1
+00ed  SLOAD
+00ee  DUP3
+00ef  ADD
+00f0  DUP2
+00f1  SSTORE
+00f2  DUP2
+00f3  PUSH1 0x0
+00f5  MSTORE
+00f6  DUP1
+00f7  CALLER
+00f8  PUSH32 0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef
+0119  PUSH1 0x20
+011b  PUSH1 0x0
+011d  LOG3
+011e  PUSH1 0x1
+0120  PUSH1 0x0
+0122  MSTORE
+0123  PUSH1 0x20
+0125  PUSH1 0x0
+0127  RETURN
+      mint:
+0128  JUMPDEST
+0129  PUSH1 0x24
+012b  CALLDATALOAD
+012c  PUSH1 0x4
+012e  CALLDATALOAD
+012f  PUSH20 0xffffffffffffffffffffffffffffffffffffffff
+0144  AND
+0145  DUP1
+0146  SLOAD
+0147  DUP3
+0148  ADD
+0149  DUP2
+014a  SSTORE
+014b  PUSH21 0x10000000000000000000000000000000000000000
+0161  SLOAD
+0162  DUP3
+0163  ADD
+0164  PUSH21 0x10000000000000000000000000000000000000000
+017a  SSTORE
+017b  DUP2
+017c  PUSH1 0x0
+017e  MSTORE
+017f  DUP1
+0180  PUSH1 0x0
+0182  PUSH32 0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef
+01a3  PUSH1 0x20
+01a5  PUSH1 0x0
+01a7  LOG3
+01a8  PUSH1 0x1
+01aa  PUSH1 0x0
+01ac  MSTORE
+01ad  PUSH1 0x20
+01af  PUSH1 0x0
+01b1  RETURN
+      revert:
+01b2  JUMPDEST
+01b3  PUSH1 0x0
+01b5  DUP1
+01b6  REVERT
+      name_data:
+01b7  DATA 0x0000000000000000000000000000000000000000000000000000000000000020000000000000000000000000000000000000000000000000000000000000000a5465737420546f6b656e00000000000000000000000000000000000000000000
+      symbol_data:
+0217  DATA 0x000000000000000000000000000000000000000000000000000000000000002000000000000000000000000000000000000000000000000000000000000000045445535400000000000000000000000000000000000000000000000000000000
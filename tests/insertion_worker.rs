@@ -0,0 +1,179 @@
+//! The insertion worker on its own, fed batches straight through its channel
+use eth_indexer::error::{IndexerError, is_fatal};
+use eth_indexer::insertion_worker::{
+    InsertionSettings, RangeFetch, TransferBatch, WriteMode, run_insertion_worker,
+};
+use eth_indexer::progress::ProgressCounters;
+use eth_indexer::repository::{Database, TokenRepository};
+use eth_indexer::testutil::{
+    fresh_database, remove_database, synthetic_transfers, temp_database_path, token_address,
+};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+const BATCHES: u64 = 100;
+/// Ten transfers per block, ten blocks per batch
+const TRANSFERS_PER_BATCH: u64 = 100;
+
+/// The `index`th batch, covering blocks `10 * index + 1..=10 * index + 10`
+fn batch(index: u64) -> TransferBatch {
+    let first = index * TRANSFERS_PER_BATCH;
+    let end_block = (index + 1) * 10;
+    TransferBatch {
+        transfers: synthetic_transfers(first..first + TRANSFERS_PER_BATCH, 50),
+        to_block: end_block,
+        end_block,
+        replace_range: None,
+        finalize_range: None,
+        fetches: vec![RangeFetch {
+            from: end_block - 9,
+            to: end_block,
+            log_count: TRANSFERS_PER_BATCH,
+            rpc_url: "http://unused.example".to_string(),
+            elapsed: Duration::ZERO,
+        }],
+    }
+}
+
+/// Run the worker over `db` until `rx` is closed, reporting committed blocks
+/// to `last_processed_tx`
+async fn run(
+    db: Database,
+    rx: mpsc::Receiver<TransferBatch>,
+    last_processed_tx: watch::Sender<u64>,
+) {
+    try_run(db, rx, last_processed_tx).await.unwrap();
+}
+
+/// `run`, returning the error the worker stopped on
+async fn try_run(
+    db: Database,
+    rx: mpsc::Receiver<TransferBatch>,
+    last_processed_tx: watch::Sender<u64>,
+) -> anyhow::Result<()> {
+    run_insertion_worker(
+        db,
+        token_address(),
+        rx,
+        last_processed_tx,
+        Arc::new(ProgressCounters::new(0)),
+        None,
+        InsertionSettings {
+            multi_row_threshold: 1000,
+            indexing_log_retention: 0,
+            write_mode: WriteMode::Write,
+        },
+    )
+    .await
+}
+
+fn transfer_count(db: &Database) -> u64 {
+    db.conn
+        .query_row("SELECT COUNT(*) FROM transfers", [], |row| row.get(0))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn a_hundred_batches_go_through_one_connection() {
+    let path = temp_database_path("insertion-one-connection");
+    let db = fresh_database(&path).unwrap();
+    let (tx, rx) = mpsc::channel(1);
+    let (last_processed_tx, mut last_processed_rx) = watch::channel(0);
+    let worker = tokio::spawn(run(db.try_clone().unwrap(), rx, last_processed_tx));
+
+    // Each committed before the next is sent, so none are coalesced
+    for index in 0..BATCHES {
+        tx.send(batch(index)).await.unwrap();
+        last_processed_rx
+            .wait_for(|&block| block == (index + 1) * 10)
+            .await
+            .unwrap();
+    }
+    drop(tx);
+    worker.await.unwrap();
+
+    // The test's own connection and the one handed to the worker
+    assert_eq!(db.connections_opened(), 2);
+    assert_eq!(transfer_count(&db), BATCHES * TRANSFERS_PER_BATCH);
+
+    drop(db);
+    remove_database(&path);
+}
+
+#[tokio::test]
+async fn batches_queued_at_shutdown_are_committed_before_it_stops() {
+    let path = temp_database_path("insertion-drain");
+    let db = fresh_database(&path).unwrap();
+    let (tx, rx) = mpsc::channel(BATCHES as usize);
+    for index in 0..BATCHES {
+        tx.send(batch(index)).await.unwrap();
+    }
+    // The scanner dropping its sender is the worker's signal to stop
+    drop(tx);
+
+    let (last_processed_tx, last_processed_rx) = watch::channel(0);
+    run(db.try_clone().unwrap(), rx, last_processed_tx).await;
+    assert_eq!(*last_processed_rx.borrow(), BATCHES * 10);
+    assert_eq!(transfer_count(&db), BATCHES * TRANSFERS_PER_BATCH);
+    assert_eq!(
+        TokenRepository::new(&db.conn)
+            .get_last_processed_block(&token_address())
+            .unwrap(),
+        Some(BATCHES * 10)
+    );
+
+    drop(db);
+    remove_database(&path);
+}
+
+#[tokio::test]
+async fn a_balance_shortfall_stops_the_worker_and_is_recorded_once() {
+    let path = temp_database_path("insertion-shortfall");
+    let db = fresh_database(&path).unwrap();
+    let anomalies = || -> u64 {
+        db.conn
+            .query_row("SELECT COUNT(*) FROM balance_anomalies", [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    };
+
+    // Without the mints of batch 0, the first sender of batch 1 at block 11
+    // has nothing to send. Each run stops on it instead of going on.
+    for _ in 0..2 {
+        let (tx, rx) = mpsc::channel(2);
+        tx.send(batch(1)).await.unwrap();
+        tx.send(batch(2)).await.unwrap();
+        drop(tx);
+
+        let (last_processed_tx, last_processed_rx) = watch::channel(0);
+        let error = try_run(db.try_clone().unwrap(), rx, last_processed_tx)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                error.downcast_ref::<IndexerError>(),
+                Some(IndexerError::BalanceShortfall {
+                    block_number: 11,
+                    ..
+                })
+            ),
+            "{error:#}"
+        );
+        assert!(is_fatal(&error));
+        assert_eq!(*last_processed_rx.borrow(), 0);
+    }
+
+    assert_eq!(anomalies(), 1);
+    assert_eq!(transfer_count(&db), 0);
+    assert_eq!(
+        TokenRepository::new(&db.conn)
+            .get_last_processed_block(&token_address())
+            .unwrap(),
+        Some(0)
+    );
+
+    drop(db);
+    remove_database(&path);
+}
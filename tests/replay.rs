@@ -0,0 +1,68 @@
+//! Indexing from RPC responses recorded off a live chain
+mod common;
+
+use alloy_primitives::U256;
+use common::{MockChain, TOKEN, TempDatabase, holder, indexer_builder, open};
+use eth_indexer::repository::BalanceRepository;
+use eth_indexer::rpc::{ReplayRpc, RpcApi};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replays_a_recorded_sync_without_the_chain() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(40, holder(1), holder(2), 300)
+        .transfer(80, holder(2), holder(3), 100);
+    let provider = chain.provider().await;
+
+    let recorded = TempDatabase::new("record");
+    let fixtures = recorded.with_extension("fixtures");
+    let settings = format!("rpc_fixture_dir = {:?}\n", fixtures.display().to_string());
+    let indexer = indexer_builder(
+        &recorded,
+        &[&provider],
+        &format!("{settings}rpc_mode = \"record\""),
+    )
+    .once()
+    .build()
+    .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+    drop(provider);
+
+    let replayed = TempDatabase::new("replay");
+    // Fetched in other ranges than the recorded ones
+    let builder = indexer_builder(&replayed, &[], &format!("{settings}rpc_mode = \"replay\""));
+    let config_path = replayed.with_extension("toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        config.replace("batch_size = 10", "batch_size = 25"),
+    )
+    .unwrap();
+    let indexer = builder.once().build().unwrap();
+    let result = indexer.start().await.unwrap().wait().await;
+
+    let replay = ReplayRpc::load(&fixtures).unwrap();
+    let missing = replay.get_block_hash(1_000).await;
+    std::fs::remove_dir_all(&fixtures).unwrap();
+    result.unwrap();
+    assert!(
+        missing.is_err(),
+        "a request that wasn't recorded was served"
+    );
+
+    let db = open(&replayed);
+    let balances = BalanceRepository::new(&db.conn, &TOKEN);
+    assert_eq!(
+        balances.get_balance(&holder(1), true).unwrap().balance,
+        U256::from(700)
+    );
+    assert_eq!(
+        balances.get_balance(&holder(2), true).unwrap().balance,
+        U256::from(200)
+    );
+    assert_eq!(
+        balances.get_balance(&holder(3), true).unwrap().balance,
+        U256::from(100)
+    );
+}
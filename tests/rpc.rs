@@ -0,0 +1,388 @@
+//! The RPC client against mock providers: rate limits, header batches,
+//! transports, headers and timeouts
+mod common;
+
+use common::{MockChain, MockProvider, TOKEN};
+use eth_indexer::config::Config;
+use eth_indexer::error::{IndexerError, RpcError};
+use eth_indexer::rpc::RpcClient;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Config over `urls`, each a URL with any `|` options, with quick retries
+/// and no rate limit unless `settings` says otherwise
+fn config(name: &str, urls: &[&str], settings: &[(&str, &str)]) -> Config {
+    let mut values: BTreeMap<&str, String> = [
+        ("rpc_requests_per_second", "10000"),
+        ("rpc_max_retries", "2"),
+        ("rpc_retry_backoff_ms", "10"),
+        ("rpc_retry_max_delay_ms", "50"),
+        ("request_timeout_secs", "2"),
+        ("light_request_timeout_secs", "1"),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key, value.to_string()))
+    .collect();
+    values.insert("erc20_contract_address", format!("{TOKEN:?}"));
+    values.insert("json_rpc_urls", format!("{urls:?}"));
+    for (key, value) in settings {
+        values.insert(key, value.to_string());
+    }
+    let text: String = values
+        .iter()
+        .map(|(key, value)| match value.parse::<f64>() {
+            Ok(_) => format!("{key} = {value}\n"),
+            Err(_) if value.starts_with('[') => format!("{key} = {value}\n"),
+            Err(_) => format!("{key} = {value:?}\n"),
+        })
+        .collect();
+
+    let path = std::env::temp_dir().join(format!(
+        "eth-indexer-test-{}-rpc-{name}.toml",
+        std::process::id()
+    ));
+    std::fs::write(&path, text).unwrap();
+    let config = Config::from_file(&path);
+    let _ = std::fs::remove_file(&path);
+    config.unwrap()
+}
+
+async fn client(name: &str, providers: &[&MockProvider], settings: &[(&str, &str)]) -> RpcClient {
+    let urls: Vec<&str> = providers.iter().map(|p| p.url()).collect();
+    let config = config(name, &urls, settings);
+    RpcClient::new(&config.json_rpc_urls, &config)
+        .await
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn two_providers_at_five_rps_serve_about_ten_requests_a_second() {
+    let chain = MockChain::new(100, 90);
+    let (first, second) = (chain.provider().await, chain.provider().await);
+    let client = client(
+        "rate-limit",
+        &[&first, &second],
+        &[("rpc_requests_per_second", "5")],
+    )
+    .await;
+
+    // Each bucket starts with a second's worth, the other 20 take two seconds
+    let started = Instant::now();
+    let requests = (0..30).map(|_| client.get_latest_block());
+    for head in futures::future::join_all(requests).await {
+        assert_eq!(head.unwrap(), 100);
+    }
+    let elapsed = started.elapsed();
+
+    assert!(
+        elapsed > Duration::from_millis(1700) && elapsed < Duration::from_secs(3),
+        "30 requests took {elapsed:?}"
+    );
+    for provider in [&first, &second] {
+        let served = provider.requests("eth_blockNumber");
+        assert!(
+            (12..=18).contains(&served),
+            "{served} of 30 on one provider"
+        );
+    }
+}
+
+#[tokio::test]
+async fn header_batches_retry_only_the_calls_that_failed() {
+    let chain = MockChain::new(100, 90);
+    let provider = chain.provider().await;
+    let client = client("header-batch", &[&provider], &[]).await;
+
+    // The first three calls of the batch fail, the rest are answered
+    provider.fail_next("eth_getBlockByNumber", 3);
+    let blocks: Vec<u64> = (1..=10).collect();
+    let headers = client.get_block_headers_batch(&blocks).await.unwrap();
+
+    assert_eq!(headers.iter().map(|h| h.number).collect::<Vec<_>>(), blocks);
+    assert_eq!(provider.requests("eth_getBlockByNumber"), 13);
+    let batches = client
+        .stats_snapshot()
+        .into_iter()
+        .find(|s| s.method == "eth_getBlockByNumber (batch)")
+        .unwrap();
+    assert_eq!(batches.counters.requests, 13);
+    assert_eq!(batches.counters.retries, 3);
+    assert_eq!(batches.counters.failures, 3);
+}
+
+#[tokio::test]
+async fn header_batches_take_a_rate_limit_permit_per_call() {
+    let chain = MockChain::new(100, 90);
+    let provider = chain.provider().await;
+    let client = client(
+        "header-batch-rate-limit",
+        &[&provider],
+        &[("rpc_requests_per_second", "20")],
+    )
+    .await;
+
+    // The batch of 50 empties the bucket of 20 and owes 30 more, so the
+    // batch of 10 after it waits two seconds for its 10
+    let started = Instant::now();
+    let blocks: Vec<u64> = (1..=60).collect();
+    client.get_block_headers_batch(&blocks).await.unwrap();
+    let elapsed = started.elapsed();
+
+    assert!(
+        elapsed > Duration::from_millis(1800) && elapsed < Duration::from_secs(3),
+        "60 calls took {elapsed:?}"
+    );
+}
+
+#[tokio::test]
+async fn header_batches_give_up_on_calls_that_keep_failing() {
+    let chain = MockChain::new(100, 90);
+    let provider = chain.provider().await;
+    let client = client(
+        "header-batch-missing",
+        &[&provider],
+        &[("rpc_max_retries", "0")],
+    )
+    .await;
+
+    // One call fails and there are no retries left to send it again
+    provider.fail_next("eth_getBlockByNumber", 1);
+    let error = client
+        .get_block_headers_batch(&[1, 2, 3, 4])
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, IndexerError::Rpc(RpcError::BadResponse(_))),
+        "{error}"
+    );
+    let error = error.to_string();
+    assert!(
+        error.contains("1 block headers still missing after 1 batches"),
+        "{error}"
+    );
+    assert_eq!(provider.requests("eth_getBlockByNumber"), 4);
+}
+
+#[tokio::test]
+async fn a_missing_block_is_not_found_and_a_failing_provider_an_rpc_error() {
+    let chain = MockChain::new(100, 90);
+    let provider = chain.provider().await;
+    let client = client("error-variants", &[&provider], &[]).await;
+
+    // Past the head, the provider answers null on every attempt
+    let error = client.get_block_hash(200).await.unwrap_err();
+    assert!(matches!(error, IndexerError::NotFound(_)), "{error}");
+    assert_eq!(error.to_string(), "Block 200 not found");
+
+    // Every attempt is answered with a JSON-RPC error, whose code is kept
+    provider.fail_next("eth_blockNumber", 10);
+    let error = client.get_latest_block().await.unwrap_err();
+    assert!(
+        matches!(
+            &error,
+            IndexerError::Rpc(RpcError::ErrorResponse { code: -32000, message })
+                if message == "injected failure"
+        ),
+        "{error}"
+    );
+
+    provider.set_down(true);
+    let error = client.get_latest_block().await.unwrap_err();
+    assert!(
+        matches!(error, IndexerError::Rpc(RpcError::Transport(_))),
+        "{error}"
+    );
+    assert!(
+        error.to_string().starts_with("RPC request failed: "),
+        "{error}"
+    );
+}
+
+#[tokio::test]
+async fn each_url_scheme_gets_its_transport() {
+    // HTTP connects lazily, so no server is needed
+    let settings = config("scheme-http", &["http://127.0.0.1:1"], &[]);
+    assert!(
+        RpcClient::new(&settings.json_rpc_urls, &settings)
+            .await
+            .is_ok()
+    );
+
+    // A WebSocket or IPC provider connects up front, and says over what it failed
+    let settings = config("scheme-ws", &["ws://127.0.0.1:1"], &[]);
+    let error = RpcClient::new(&settings.json_rpc_urls, &settings)
+        .await
+        .err()
+        .unwrap();
+    assert!(format!("{error:#}").contains("over WebSocket"), "{error:#}");
+
+    let socket = std::env::temp_dir().join(format!("eth-indexer-{}.ipc", std::process::id()));
+    let _ = std::fs::remove_file(&socket);
+    let url = socket.to_str().unwrap();
+    let settings = config("scheme-ipc-missing", &[url], &[]);
+    let error = RpcClient::new(&settings.json_rpc_urls, &settings)
+        .await
+        .err()
+        .unwrap();
+    assert!(format!("{error:#}").contains("over IPC"), "{error:#}");
+
+    // A node on the socket answers every request with block 0x2a
+    let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = vec![0; 4096];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap_or(0);
+            if read == 0 {
+                return;
+            }
+            let request: serde_json::Value = serde_json::from_slice(&buffer[..read]).unwrap();
+            let response = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": "0x2a",
+            });
+            stream
+                .write_all(response.to_string().as_bytes())
+                .await
+                .unwrap();
+        }
+    });
+    let settings = config("scheme-ipc", &[url], &[]);
+    let client = RpcClient::new(&settings.json_rpc_urls, &settings)
+        .await
+        .unwrap();
+    assert_eq!(client.get_latest_block().await.unwrap(), 42);
+    server.abort();
+    let _ = std::fs::remove_file(&socket);
+
+    // Anything else is refused when the config is loaded
+    let path = std::env::temp_dir().join(format!(
+        "eth-indexer-test-{}-rpc-ftp.toml",
+        std::process::id()
+    ));
+    std::fs::write(
+        &path,
+        format!("erc20_contract_address = \"{TOKEN:?}\"\njson_rpc_url = \"ftp://node.example\"\n"),
+    )
+    .unwrap();
+    let error = Config::from_file(&path).err().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    assert!(error.contains("unsupported RPC URL scheme"), "{error}");
+}
+
+/// Log lines written while the guard lives, on this thread
+struct CapturedLogs {
+    lines: Arc<Mutex<Vec<u8>>>,
+    _guard: tracing::subscriber::DefaultGuard,
+}
+
+impl CapturedLogs {
+    fn start() -> Self {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let writer = lines.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || LogWriter(writer.clone()))
+            .finish();
+        Self {
+            lines,
+            _guard: tracing::subscriber::set_default(subscriber),
+        }
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8(self.lines.lock().unwrap().clone()).unwrap()
+    }
+}
+
+struct LogWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn configured_headers_reach_the_provider_but_not_the_logs() {
+    let logs = CapturedLogs::start();
+    let chain = MockChain::new(100, 90);
+    let provider = chain.provider().await;
+    let url = format!(
+        "{}|Authorization=Bearer s3cret-token|X-Api-Key=key-1234",
+        provider.url()
+    );
+    let settings = config("headers", &[&url], &[]);
+    tracing::info!("Loaded {:?}", settings);
+    let client = RpcClient::new(&settings.json_rpc_urls, &settings)
+        .await
+        .unwrap();
+
+    // One failure so the provider's URL is logged
+    provider.fail_next("eth_blockNumber", 1);
+    assert_eq!(client.get_latest_block().await.unwrap(), 100);
+
+    assert_eq!(
+        provider.last_header("authorization").as_deref(),
+        Some("Bearer s3cret-token")
+    );
+    assert_eq!(
+        provider.last_header("x-api-key").as_deref(),
+        Some("key-1234")
+    );
+
+    let text = logs.text();
+    assert!(text.contains(provider.url()), "{text}");
+    assert!(text.contains("Authorization: <redacted>"), "{text}");
+    assert!(!text.contains("s3cret-token"), "{text}");
+    assert!(!text.contains("key-1234"), "{text}");
+}
+
+#[tokio::test]
+async fn a_one_second_timeout_fires_against_a_hanging_provider() {
+    let chain = MockChain::new(100, 90);
+    let provider = chain.provider().await;
+    let client = client(
+        "timeout",
+        &[&provider],
+        &[("rpc_max_retries", "0"), ("request_timeout_secs", "1")],
+    )
+    .await;
+    provider.set_delay(Some(Duration::from_secs(30)));
+
+    // light_request_timeout_secs = 1 from the test defaults
+    let started = Instant::now();
+    let error = client.get_latest_block().await.unwrap_err();
+    let elapsed = started.elapsed();
+    assert!(
+        matches!(error, IndexerError::Rpc(RpcError::Timeout(timeout)) if timeout == Duration::from_secs(1)),
+        "{error}"
+    );
+    assert!(
+        error.to_string().contains("timeout after 1 seconds"),
+        "{error}"
+    );
+    assert!(
+        elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(3),
+        "{elapsed:?}"
+    );
+
+    let started = Instant::now();
+    let error = client.get_logs(1, 10, &[TOKEN], &[]).await.unwrap_err();
+    let elapsed = started.elapsed();
+    assert!(error.to_string().contains("timeout"), "{error}");
+    assert!(
+        elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(3),
+        "{elapsed:?}"
+    );
+}
@@ -0,0 +1,775 @@
+//! The scanning loop, reorg handling and finality against scripted chains
+mod common;
+
+use alloy_primitives::U256;
+use common::{
+    MockChain, TOKEN, TempDatabase, fast_indexer_builder, holder, indexer_builder, open, wait_until,
+};
+use eth_indexer::config::TokenConfig;
+use eth_indexer::error::IndexerError;
+use eth_indexer::repository::{
+    BalanceRepository, CheckpointRepository, Database, EventFilter, EventRepository,
+    ReorgRepository, ScannedRangeRepository, SnapshotDirection, TokenRepository,
+    TransferRepository,
+};
+use eth_indexer::rpc::RpcClient;
+use eth_indexer::supervisor::Supervisor;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::watch;
+
+const WAIT: Duration = Duration::from_secs(30);
+
+fn transfer_count(database: &Path, finalized_only: bool) -> usize {
+    let db = open(database);
+    TransferRepository::new(&db.conn, &TOKEN)
+        .query_transfers(None, None, None, finalized_only, 1_000, 0)
+        .unwrap()
+        .len()
+}
+
+fn balance(database: &Path, index: u8) -> U256 {
+    let db = open(database);
+    BalanceRepository::new(&db.conn, &TOKEN)
+        .get_balance(&holder(index), true)
+        .unwrap()
+        .balance
+}
+
+fn cursors(database: &Path) -> (Option<u64>, Option<u64>) {
+    let db = open(database);
+    let tokens = TokenRepository::new(&db.conn);
+    (
+        tokens.get_last_processed_block(&TOKEN).unwrap(),
+        tokens.get_last_processed_finalized_block(&TOKEN).unwrap(),
+    )
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn historical_sync_stores_transfers_and_finalized_balances() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(20, holder(1), holder(2), 300)
+        .transfer(20, holder(2), holder(3), 100)
+        .transfer(95, holder(1), holder(3), 50);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("historical-sync");
+
+    let indexer = indexer_builder(&database, &[&provider], "")
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+
+    assert_eq!(transfer_count(&database, false), 4);
+    // Block 95 is past the finalized block
+    assert_eq!(transfer_count(&database, true), 3);
+    assert_eq!(cursors(&database), (Some(100), Some(90)));
+    assert_eq!(balance(&database, 1), U256::from(700));
+    assert_eq!(balance(&database, 2), U256::from(200));
+    assert_eq!(balance(&database, 3), U256::from(100));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn catches_up_then_follows_new_blocks() {
+    let chain = MockChain::new(100, 90);
+    chain.mint(10, holder(1), 1_000);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("follow");
+
+    let indexer = indexer_builder(&database, &[&provider], "")
+        .build()
+        .unwrap();
+    let mut handle = indexer.start().await.unwrap();
+    assert_eq!(handle.wait_caught_up().await.unwrap(), 100);
+    assert_eq!(transfer_count(&database, false), 1);
+
+    chain
+        .transfer(104, holder(1), holder(2), 400)
+        .transfer(108, holder(2), holder(3), 150);
+    chain.advance(110, Some(108));
+
+    wait_until(WAIT, "blocks 101-110 to be indexed", || {
+        cursors(&database) == (Some(110), Some(108))
+    })
+    .await;
+    handle.shutdown().await.unwrap();
+
+    assert_eq!(transfer_count(&database, true), 3);
+    assert_eq!(balance(&database, 1), U256::from(600));
+    assert_eq!(balance(&database, 2), U256::from(250));
+    assert_eq!(balance(&database, 3), U256::from(150));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn three_block_reorg_before_finalization_is_replaced() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(10, holder(1), 1_000)
+        .transfer(99, holder(1), holder(2), 300);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("reorg");
+
+    let indexer = indexer_builder(&database, &[&provider], "")
+        .build()
+        .unwrap();
+    let mut handle = indexer.start().await.unwrap();
+    handle.wait_caught_up().await.unwrap();
+    assert_eq!(transfer_count(&database, false), 2);
+
+    // Blocks 98-100 are replaced: the transfer to 2 never happened, one to
+    // 3 did instead
+    chain.reorg(3);
+    chain.transfer(99, holder(1), holder(3), 70);
+    chain.advance(105, None);
+    wait_until(WAIT, "the scan to pass the reorg", || {
+        cursors(&database).0 == Some(105)
+    })
+    .await;
+
+    chain.advance(105, Some(105));
+    wait_until(WAIT, "blocks up to 105 to be finalized", || {
+        cursors(&database).1 == Some(105)
+    })
+    .await;
+    handle.shutdown().await.unwrap();
+
+    // Replaced at the head by the scan, not left for the finality pass
+    let reorgs = ReorgRepository::new(&open(&database).conn, &TOKEN)
+        .list(10)
+        .unwrap();
+    assert!(
+        reorgs
+            .iter()
+            .any(|r| r.block_number == 99 && r.transfers_deleted == 1 && r.transfers_inserted == 1),
+        "no reorg of block 99 recorded"
+    );
+    assert_eq!(transfer_count(&database, true), 2);
+    assert_eq!(balance(&database, 1), U256::from(930));
+    assert_eq!(balance(&database, 2), U256::ZERO);
+    assert_eq!(balance(&database, 3), U256::from(70));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_failing_reorg_refetch_is_retried_with_the_range() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(10, holder(1), 1_000)
+        .transfer(99, holder(1), holder(2), 300);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("reorg-refetch-retry");
+
+    let indexer = indexer_builder(&database, &[&provider], "")
+        .build()
+        .unwrap();
+    let mut handle = indexer.start().await.unwrap();
+    handle.wait_caught_up().await.unwrap();
+
+    // Blocks 98-100 are replaced. No hash below 100 was recorded, so the
+    // re-fetch starts after the finalized block 90, and all three requests of
+    // its first attempt fail.
+    chain.reorg(3);
+    chain
+        .transfer(99, holder(1), holder(3), 70)
+        .transfer(103, holder(3), holder(2), 20);
+    provider.fail_logs_from(91, 3);
+    chain.advance(105, None);
+    wait_until(WAIT, "the scan to pass the reorg", || {
+        cursors(&database).0 == Some(105)
+    })
+    .await;
+    assert_eq!(provider.errors(), 3);
+
+    chain.advance(105, Some(105));
+    wait_until(WAIT, "blocks up to 105 to be finalized", || {
+        cursors(&database).1 == Some(105)
+    })
+    .await;
+    handle.shutdown().await.unwrap();
+
+    // Replaced at the head by the retried range, not left for the finality pass
+    let reorgs = ReorgRepository::new(&open(&database).conn, &TOKEN)
+        .list(10)
+        .unwrap();
+    assert!(
+        reorgs
+            .iter()
+            .any(|r| r.block_number == 99 && r.transfers_deleted == 1 && r.transfers_inserted == 1),
+        "no reorg of block 99 recorded"
+    );
+    assert_eq!(transfer_count(&database, true), 3);
+    assert_eq!(balance(&database, 1), U256::from(930));
+    assert_eq!(balance(&database, 2), U256::from(20));
+    assert_eq!(balance(&database, 3), U256::from(50));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn finality_checks_only_the_headers_of_stored_blocks() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(10, holder(1), 1_000)
+        .transfer(95, holder(1), holder(2), 300);
+    let database = TempDatabase::new("finality-headers");
+
+    let provider = chain.provider().await;
+    let indexer = indexer_builder(&database, &[&provider], "")
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+    assert_eq!(cursors(&database), (Some(100), Some(90)));
+
+    // While the indexer was stopped blocks 91-100 were replaced: the transfer
+    // at 95 is gone and one at 93, where nothing was stored, came instead
+    chain.reorg(10);
+    chain.transfer(93, holder(1), holder(3), 70);
+    chain.advance(100, Some(100));
+
+    let provider = chain.provider().await;
+    let indexer = indexer_builder(&database, &[&provider], "")
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+
+    // Only block 95 had stored transfers, its changed hash has the logs
+    // re-fetched from the block after the finalized one
+    assert_eq!(provider.header_blocks(), vec![95]);
+    assert_eq!(provider.first_log_block(), Some(91));
+    assert_eq!(cursors(&database), (Some(100), Some(100)));
+    assert_eq!(transfer_count(&database, true), 2);
+    assert_eq!(balance(&database, 1), U256::from(930));
+    assert_eq!(balance(&database, 2), U256::ZERO);
+    assert_eq!(balance(&database, 3), U256::from(70));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ranges_over_the_result_limit_are_split() {
+    let chain = MockChain::new(100, 100);
+    chain.mint(1, holder(1), 1_000);
+    for block in 2..=9 {
+        chain.transfer(block, holder(1), holder(block as u8), 10);
+    }
+    chain.limit_logs_per_request(2);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("splitting");
+
+    let indexer = indexer_builder(&database, &[&provider], "")
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+
+    assert!(provider.errors() > 0, "no range went over the limit");
+    assert_eq!(transfer_count(&database, true), 9);
+    assert_eq!(balance(&database, 1), U256::from(920));
+    assert_eq!(balance(&database, 9), U256::from(10));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_over_to_a_working_provider() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(50, holder(1), holder(2), 250);
+    let broken = chain.provider().await;
+    let working = chain.provider().await;
+    broken.set_down(true);
+    // And the working one has a hiccup of its own
+    working.fail_next("eth_getLogs", 1);
+    let database = TempDatabase::new("failover");
+
+    let indexer = indexer_builder(&database, &[&broken, &working], "")
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+
+    assert!(broken.errors() > 0, "the broken provider was never tried");
+    assert!(working.requests("eth_getLogs") > 0);
+    assert_eq!(transfer_count(&database, true), 2);
+    assert_eq!(balance(&database, 2), U256::from(250));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_range_failing_past_its_retries_is_fetched_again() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(50, holder(1), holder(2), 250);
+    let provider = chain.provider().await;
+    // One range at a time, so the first one takes every failure: two
+    // attempts of three requests each fail, the third goes through
+    provider.fail_next("eth_getLogs", 3 + 3 + 1);
+    // And so does the first look at the chain head
+    provider.fail_next("eth_blockNumber", 3);
+    let database = TempDatabase::new("range-retry");
+
+    let indexer = indexer_builder(&database, &[&provider], "max_pending_requests = 1")
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+
+    assert_eq!(provider.errors(), 7 + 3);
+    assert_eq!(cursors(&database), (Some(100), Some(90)));
+    assert_eq!(transfer_count(&database, true), 2);
+    assert_eq!(balance(&database, 2), U256::from(250));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_range_failing_every_attempt_stops_the_scan() {
+    let chain = MockChain::new(100, 90);
+    chain.mint(5, holder(1), 1_000);
+    let provider = chain.provider().await;
+    provider.fail_next("eth_getLogs", 3 + 3);
+    let database = TempDatabase::new("range-give-up");
+
+    let indexer = indexer_builder(
+        &database,
+        &[&provider],
+        "max_pending_requests = 1\nrange_max_attempts = 2",
+    )
+    .once()
+    .build()
+    .unwrap();
+    let error = indexer.start().await.unwrap().wait().await.unwrap_err();
+
+    assert!(
+        format!("{error:#}").contains("injected failure"),
+        "{error:#}"
+    );
+    assert_eq!(provider.errors(), 6);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn queued_coverage_gaps_are_rescanned_on_start() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(20, holder(1), holder(2), 300)
+        .transfer(25, holder(2), holder(3), 100);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("coverage-repair");
+
+    let run_once = || async {
+        let indexer = indexer_builder(&database, &[&provider], "")
+            .once()
+            .build()
+            .unwrap();
+        indexer.start().await.unwrap().wait().await.unwrap();
+    };
+    run_once().await;
+
+    {
+        let db = open(&database);
+        let scanned = ScannedRangeRepository::new(&db.conn, &TOKEN);
+        assert_eq!(scanned.ranges().unwrap(), vec![(1, 100)]);
+    }
+
+    // Forget blocks 11 to 30 were scanned and queue them, as `query coverage
+    // --repair` would after a lost batch
+    {
+        let db = Database::new(database.to_str().unwrap()).unwrap();
+        db.conn.execute("DELETE FROM scanned_ranges", []).unwrap();
+        let scanned = ScannedRangeRepository::new(&db.conn, &TOKEN);
+        scanned.record(&[(1, 10), (31, 100)]).unwrap();
+        let gaps = scanned.gaps(1, 100).unwrap();
+        assert_eq!(gaps, vec![(11, 30)]);
+        scanned.queue_repairs(&gaps).unwrap();
+    }
+
+    let log_requests = provider.requests("eth_getLogs");
+    run_once().await;
+    assert!(provider.requests("eth_getLogs") > log_requests);
+
+    let db = open(&database);
+    let scanned = ScannedRangeRepository::new(&db.conn, &TOKEN);
+    assert_eq!(scanned.ranges().unwrap(), vec![(1, 100)]);
+    assert!(scanned.repairs().unwrap().is_empty());
+    // The rescan finds the stored transfers again without duplicating them
+    assert_eq!(transfer_count(&database, false), 3);
+    assert_eq!(balance(&database, 3), U256::from(100));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn parallel_segments_sync_the_same_history() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(20, holder(1), holder(2), 300)
+        .transfer(45, holder(2), holder(3), 100)
+        .transfer(70, holder(1), holder(3), 200)
+        .transfer(95, holder(3), holder(4), 50);
+    let first = chain.provider().await;
+    let second = chain.provider().await;
+    let database = TempDatabase::new("parallel-segments");
+
+    let indexer = indexer_builder(&database, &[&first, &second], "parallel_segments = 3")
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+
+    assert_eq!(transfer_count(&database, false), 5);
+    // Block 95 is past the finalized block, fetched after the segments
+    assert_eq!(transfer_count(&database, true), 4);
+    assert_eq!(cursors(&database), (Some(100), Some(90)));
+    assert_eq!(balance(&database, 1), U256::from(500));
+    assert_eq!(balance(&database, 2), U256::from(200));
+    assert_eq!(balance(&database, 3), U256::from(300));
+
+    let db = open(&database);
+    let scanned = ScannedRangeRepository::new(&db.conn, &TOKEN);
+    assert_eq!(scanned.ranges().unwrap(), vec![(1, 100)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn parallel_segments_resume_past_scanned_ranges() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(50, holder(1), holder(2), 100)
+        .transfer(70, holder(1), holder(3), 100)
+        .transfer(85, holder(1), holder(4), 100);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("parallel-resume");
+
+    let run_once = |extra: &'static str| {
+        let indexer = indexer_builder(&database, &[&provider], extra)
+            .once()
+            .build()
+            .unwrap();
+        async move { indexer.start().await.unwrap().wait().await.unwrap() }
+    };
+    run_once("").await;
+
+    // As if a segmented run stopped with its cursor at 40 after committing
+    // blocks 61-80 of a later segment, minus the transfer in them, so a
+    // rescan would show
+    {
+        let db = Database::new(database.to_str().unwrap()).unwrap();
+        TransferRepository::new(&db.conn, &TOKEN)
+            .rollback_to(40)
+            .unwrap();
+        ScannedRangeRepository::new(&db.conn, &TOKEN)
+            .record(&[(61, 80)])
+            .unwrap();
+    }
+
+    run_once("parallel_segments = 2").await;
+
+    assert_eq!(cursors(&database), (Some(100), Some(90)));
+    // Blocks 61-80 were not fetched again
+    assert_eq!(transfer_count(&database, false), 3);
+    assert_eq!(balance(&database, 2), U256::from(100));
+    assert_eq!(balance(&database, 4), U256::from(100));
+    let db = open(&database);
+    let scanned = ScannedRangeRepository::new(&db.conn, &TOKEN);
+    assert_eq!(scanned.ranges().unwrap(), vec![(1, 100)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn supervisor_keeps_tokens_running_past_a_failing_one() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(20, holder(1), holder(2), 300);
+    let provider = chain.provider().await;
+    let broken = chain.provider().await;
+    broken.set_down(true);
+    let healthy_db = TempDatabase::new("supervised-healthy");
+    let broken_db = TempDatabase::new("supervised-broken");
+
+    let base = indexer_builder(
+        &healthy_db,
+        &[&provider],
+        "token_restart_delay_secs = 0\ntoken_max_restarts = 1",
+    )
+    .once()
+    .build()
+    .unwrap()
+    .config()
+    .clone();
+    let token =
+        |database: &TempDatabase, provider: &common::MockProvider, symbol: &str| TokenConfig {
+            address: TOKEN,
+            database_url: format!("sqlite:{}", database.display()),
+            json_rpc_urls: vec![provider.url().to_string()],
+            symbol: Some(symbol.to_string()),
+        };
+    let supervisor = Supervisor::new(
+        base,
+        vec![
+            token(&healthy_db, &provider, "GOOD"),
+            token(&broken_db, &broken, "BAD"),
+        ],
+    );
+
+    let (_stop, shutdown) = watch::channel(false);
+    let error = supervisor.run(shutdown).await.unwrap_err().to_string();
+
+    assert!(error.starts_with("1 of 2 tokens failed"), "{error}");
+    assert!(error.contains("BAD: "), "{error}");
+    assert_eq!(transfer_count(&healthy_db, false), 2);
+    assert_eq!(cursors(&healthy_db), (Some(100), Some(90)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rpc_requests_are_counted_by_method_and_provider() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(50, holder(1), holder(2), 100);
+    let provider = chain.provider().await;
+    provider.fail_next("eth_getLogs", 1);
+    let database = TempDatabase::new("rpc-stats");
+
+    let indexer = indexer_builder(&database, &[&provider], "")
+        .once()
+        .build()
+        .unwrap();
+    let client = RpcClient::new(&indexer.config().json_rpc_urls, indexer.config())
+        .await
+        .unwrap();
+    indexer
+        .start_with_rpc(client.clone())
+        .await
+        .unwrap()
+        .wait()
+        .await
+        .unwrap();
+
+    let stats = client.stats_snapshot();
+    let logs = stats
+        .iter()
+        .find(|entry| entry.method == "eth_getLogs")
+        .unwrap();
+    assert_eq!(logs.provider, provider.url());
+    assert_eq!(
+        logs.counters.requests as usize,
+        provider.requests("eth_getLogs")
+    );
+    assert_eq!(logs.counters.failures, 1);
+    assert_eq!(logs.counters.retries, 1);
+    assert!(
+        stats
+            .iter()
+            .any(|entry| entry.method == "eth_chainId" && entry.counters.requests > 0)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn custom_event_logs_are_stored_with_their_fields() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(20, holder(1), holder(2), 300)
+        .transfer(95, holder(1), holder(3), 50);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("custom-event");
+
+    let indexer = indexer_builder(
+        &database,
+        &[&provider],
+        "event_signature = \"event Transfer(address indexed from, address indexed to, uint256 value)\"",
+    )
+    .once()
+    .build()
+    .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+
+    let db = open(&database);
+    let events = EventRepository::new(&db.conn, &TOKEN);
+    let all = events.get_events(&EventFilter::default(), 10).unwrap();
+    assert_eq!(all.len(), 3);
+    assert_eq!(all[0].block_number, 95);
+    assert!(!all[0].is_finalized);
+
+    let filter = EventFilter {
+        fields: vec![("to".to_string(), format!("{:?}", holder(2)))],
+        ..Default::default()
+    };
+    let matching = events.get_events(&filter, 10).unwrap();
+    assert_eq!(matching.len(), 1);
+    assert_eq!(matching[0].event.name, "Transfer");
+    let fields: serde_json::Value = serde_json::from_str(&matching[0].event.fields).unwrap();
+    assert_eq!(fields["from"], format!("{:?}", holder(1)));
+    assert_eq!(fields["value"], "300");
+
+    // The logs move nothing, no balances are kept
+    assert_eq!(balance(&database, 1), U256::ZERO);
+    assert_eq!(balance(&database, 2), U256::ZERO);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn balance_checkpoints_cover_finalized_blocks_and_go_with_a_rollback() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(20, holder(1), holder(2), 300)
+        .transfer(30, holder(2), holder(3), 100)
+        .transfer(60, holder(1), holder(3), 200)
+        .transfer(95, holder(3), holder(1), 10);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("balance-checkpoints");
+
+    let indexer = indexer_builder(&database, &[&provider], "balance_checkpoint_interval = 25")
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+
+    let db = open(&database);
+    let checkpoints = CheckpointRepository::new(&db.conn, &TOKEN);
+    // Block 100 is past the finalized block 90
+    let latest = checkpoints.nearest(100).unwrap().unwrap();
+    assert_eq!((latest.block_number, latest.holders), (75, 3));
+    assert!(checkpoints.missing(25, 1, 90).unwrap().is_empty());
+
+    let balances = BalanceRepository::new(&db.conn, &TOKEN);
+    let snapshot = balances.balances_at_block(97, true).unwrap();
+    assert_eq!(snapshot.direction, SnapshotDirection::FromCheckpoint(75));
+    let held: Vec<_> = snapshot
+        .holders
+        .iter()
+        .map(|holder| holder.balance.to::<u64>())
+        .collect();
+    assert_eq!(held, vec![510, 290, 200]);
+    assert_eq!(
+        balances.get_balance_at_block(&holder(3), 97).unwrap(),
+        U256::from(290)
+    );
+    assert_eq!(
+        balances.get_balance_at_block(&holder(2), 50).unwrap(),
+        U256::from(200)
+    );
+
+    // Checkpoints past the rollback block no longer hold
+    let writer = Database::new(database.to_str().unwrap()).unwrap();
+    TransferRepository::new(&writer.conn, &TOKEN)
+        .rollback_to(40)
+        .unwrap();
+    assert_eq!(checkpoints.nearest(100).unwrap().unwrap().block_number, 25);
+    assert_eq!(checkpoints.missing(25, 1, 90).unwrap(), vec![50, 75]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rollback_reverts_finalized_balances_and_the_scan_resumes_after_it() {
+    let chain = MockChain::new(100, 90);
+    chain
+        .mint(5, holder(1), 1_000)
+        .transfer(20, holder(1), holder(2), 300)
+        .transfer(60, holder(1), holder(3), 200)
+        .transfer(95, holder(3), holder(1), 10);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("rollback");
+
+    let indexer = fast_indexer_builder(&database, TOKEN, "")
+        .rpc_url(provider.url())
+        .start_block(3)
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+    assert_eq!(balance(&database, 1), U256::from(500));
+
+    let writer = Database::new(database.to_str().unwrap()).unwrap();
+    let transfers = TransferRepository::new(&writer.conn, &TOKEN);
+    let error = transfers.rollback_to(2).unwrap_err().to_string();
+    assert!(error.contains("below the deployment block 3"), "{error}");
+    assert_eq!(cursors(&database), (Some(100), Some(90)));
+
+    // Block 60 was finalized and comes back out, 95 never went in
+    let summary = transfers.rollback_to(40).unwrap();
+    assert_eq!(
+        (summary.transfers_deleted, summary.finalized_reverted),
+        (2, 1)
+    );
+    assert_eq!(cursors(&database), (Some(40), Some(40)));
+    assert_eq!(transfer_count(&database, false), 2);
+    assert_eq!(balance(&database, 1), U256::from(700));
+    assert_eq!(balance(&database, 3), U256::ZERO);
+
+    let provider = chain.provider().await;
+    let indexer = indexer_builder(&database, &[&provider], "")
+        .once()
+        .build()
+        .unwrap();
+    indexer.start().await.unwrap().wait().await.unwrap();
+
+    assert_eq!(provider.first_log_block(), Some(41));
+    assert_eq!(cursors(&database), (Some(100), Some(90)));
+    assert_eq!(transfer_count(&database, false), 4);
+    assert_eq!(balance(&database, 1), U256::from(500));
+    assert_eq!(balance(&database, 3), U256::from(200));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_balance_shortfall_at_finality_stops_the_run_and_is_recorded_once() {
+    // holder(1) sends what it never received, stored while unfinalized
+    let chain = MockChain::new(100, 0);
+    chain.transfer(20, holder(1), holder(2), 300);
+    let provider = chain.provider().await;
+    let database = TempDatabase::new("finality-shortfall");
+    let anomalies = || -> u64 {
+        open(&database)
+            .conn
+            .query_row("SELECT COUNT(*) FROM balance_anomalies", [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    };
+
+    let indexer = indexer_builder(&database, &[&provider], "")
+        .build()
+        .unwrap();
+    let mut handle = indexer.start().await.unwrap();
+    assert_eq!(handle.wait_caught_up().await.unwrap(), 100);
+    chain.advance(100, Some(90));
+
+    // The finality worker stops on it instead of retrying every tick
+    let error = tokio::time::timeout(WAIT, handle.wait())
+        .await
+        .expect("the shortfall should stop the run")
+        .unwrap_err();
+    assert!(
+        matches!(
+            error.downcast_ref::<IndexerError>(),
+            Some(IndexerError::BalanceShortfall {
+                block_number: 20,
+                ..
+            })
+        ),
+        "{error:#}"
+    );
+    assert_eq!(anomalies(), 1);
+    // Chunks of ten blocks, the one before the shortfall was saved
+    assert_eq!(cursors(&database), (Some(100), Some(10)));
+
+    // Restarting forever, the supervisor gives up on it after the first run
+    let config = indexer_builder(&database, &[&provider], "token_restart_delay_secs = 0")
+        .once()
+        .build()
+        .unwrap()
+        .config()
+        .clone();
+    let supervisor = Supervisor::new(
+        config,
+        vec![TokenConfig {
+            address: TOKEN,
+            database_url: format!("sqlite:{}", database.display()),
+            json_rpc_urls: vec![provider.url().to_string()],
+            symbol: Some("SHORT".to_string()),
+        }],
+    );
+    let (_stop, shutdown) = watch::channel(false);
+    let error = tokio::time::timeout(WAIT, supervisor.run(shutdown))
+        .await
+        .expect("a fatal failure shouldn't be restarted")
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("below zero at block 20"), "{error}");
+    assert_eq!(anomalies(), 1);
+}